@@ -0,0 +1,176 @@
+//! # Address Book
+//!
+//! A kernel-wide cache of "where is X and can we reach it", shared by every
+//! subsystem that dials out (P2P dialer, HTTP client, NTP client, ...).
+//!
+//! ## What it stores
+//! - **Names**: hostname and record type → resolved addresses, with the TTL
+//!   from the resolver (`dns` keeps its cache here).
+//! - **Peers**: P2P `NodeId` → the endpoint we last saw that peer on.
+//! - **Reachability**: endpoint → result of the last connection attempt.
+//!
+//! Every entry carries an expiry time (milliseconds since boot). Expired
+//! entries are never returned and are swept out lazily on insert, so callers
+//! don't need to run a maintenance task.
+//!
+//! ## Why share it?
+//! Without a shared cache each subsystem would re-resolve the same names and
+//! re-probe the same dead endpoints independently. Consulting the book first
+//! lets a subsystem skip a DNS round trip or a dial that just failed elsewhere.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use smoltcp::wire::IpEndpoint;
+use spin::Mutex;
+use crate::dns::{Address, RecordType};
+use crate::p2p_kademlia::NodeId;
+
+/// How long an observed peer endpoint is trusted without being seen again.
+pub const PEER_TTL_MS: u64 = 30 * 60 * 1000;
+/// How long a reachability result is cached.
+pub const REACHABILITY_TTL_MS: u64 = 60 * 1000;
+/// Maximum entries per table; the entry closest to expiry is evicted first.
+const MAX_ENTRIES: usize = 128;
+
+lazy_static! {
+    pub static ref ADDRESS_BOOK: Mutex<AddressBook> = Mutex::new(AddressBook::new());
+}
+
+/// A resolved name, as listed by `names`.
+#[derive(Debug, Clone)]
+pub struct NameEntry {
+    pub name: String,
+    pub record_type: RecordType,
+    pub addrs: Vec<Address>,
+    /// Uptime (ms) at which the entry expires.
+    pub expires_at: u64,
+}
+
+struct PeerEntry {
+    node_id: NodeId,
+    endpoint: IpEndpoint,
+    expires_at: u64,
+}
+
+struct ReachabilityEntry {
+    endpoint: IpEndpoint,
+    reachable: bool,
+    expires_at: u64,
+}
+
+pub struct AddressBook {
+    names: Vec<NameEntry>,
+    peers: Vec<PeerEntry>,
+    reachability: Vec<ReachabilityEntry>,
+}
+
+impl AddressBook {
+    pub const fn new() -> Self {
+        AddressBook {
+            names: Vec::new(),
+            peers: Vec::new(),
+            reachability: Vec::new(),
+        }
+    }
+
+    /// Cache the `record_type` addresses a name resolved to for `ttl_ms`.
+    pub fn insert_name(&mut self, name: &str, record_type: RecordType, addrs: &[Address], ttl_ms: u64, now: u64) {
+        self.expire(now);
+        let expires_at = now.saturating_add(ttl_ms);
+        if let Some(entry) = self.names.iter_mut().find(|e| e.name == name && e.record_type == record_type) {
+            entry.addrs = addrs.to_vec();
+            entry.expires_at = expires_at;
+            return;
+        }
+        if self.names.len() >= MAX_ENTRIES {
+            evict_soonest(&mut self.names, |e| e.expires_at);
+        }
+        self.names.push(NameEntry {
+            name: String::from(name),
+            record_type,
+            addrs: addrs.to_vec(),
+            expires_at,
+        });
+    }
+
+    /// Cached `record_type` addresses for `name`, if resolved recently enough.
+    pub fn lookup_name(&self, name: &str, record_type: RecordType, now: u64) -> Option<&[Address]> {
+        self.names
+            .iter()
+            .find(|e| e.name == name && e.record_type == record_type && e.expires_at > now)
+            .map(|e| e.addrs.as_slice())
+    }
+
+    /// Every unexpired name.
+    pub fn names(&self, now: u64) -> impl Iterator<Item = &NameEntry> {
+        self.names.iter().filter(move |e| e.expires_at > now)
+    }
+
+    /// Record the endpoint a peer was observed on (e.g., the source of an
+    /// inbound connection that completed the handshake).
+    pub fn record_peer(&mut self, node_id: NodeId, endpoint: IpEndpoint, now: u64) {
+        self.expire(now);
+        let expires_at = now.saturating_add(PEER_TTL_MS);
+        if let Some(entry) = self.peers.iter_mut().find(|e| e.node_id == node_id) {
+            entry.endpoint = endpoint;
+            entry.expires_at = expires_at;
+            return;
+        }
+        if self.peers.len() >= MAX_ENTRIES {
+            evict_soonest(&mut self.peers, |e| e.expires_at);
+        }
+        self.peers.push(PeerEntry { node_id, endpoint, expires_at });
+    }
+
+    /// Last known endpoint of a peer.
+    pub fn peer_endpoint(&self, node_id: &NodeId, now: u64) -> Option<IpEndpoint> {
+        self.peers
+            .iter()
+            .find(|e| e.node_id == *node_id && e.expires_at > now)
+            .map(|e| e.endpoint)
+    }
+
+    /// Record the outcome of a connection attempt to `endpoint`.
+    pub fn record_reachability(&mut self, endpoint: IpEndpoint, reachable: bool, now: u64) {
+        self.expire(now);
+        let expires_at = now.saturating_add(REACHABILITY_TTL_MS);
+        if let Some(entry) = self.reachability.iter_mut().find(|e| e.endpoint == endpoint) {
+            entry.reachable = reachable;
+            entry.expires_at = expires_at;
+            return;
+        }
+        if self.reachability.len() >= MAX_ENTRIES {
+            evict_soonest(&mut self.reachability, |e| e.expires_at);
+        }
+        self.reachability.push(ReachabilityEntry { endpoint, reachable, expires_at });
+    }
+
+    /// Cached reachability of `endpoint`: `Some(false)` means a recent
+    /// attempt failed and dialing again is probably pointless.
+    pub fn reachability(&self, endpoint: IpEndpoint, now: u64) -> Option<bool> {
+        self.reachability
+            .iter()
+            .find(|e| e.endpoint == endpoint && e.expires_at > now)
+            .map(|e| e.reachable)
+    }
+
+    /// Drop every entry whose expiry time has passed.
+    pub fn expire(&mut self, now: u64) {
+        self.names.retain(|e| e.expires_at > now);
+        self.peers.retain(|e| e.expires_at > now);
+        self.reachability.retain(|e| e.expires_at > now);
+    }
+}
+
+/// Remove the entry that would expire first to make room for a new one.
+fn evict_soonest<T>(entries: &mut Vec<T>, expires_at: impl Fn(&T) -> u64) {
+    if let Some(idx) = entries
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, e)| expires_at(e))
+        .map(|(i, _)| i)
+    {
+        entries.swap_remove(idx);
+    }
+}
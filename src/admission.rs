@@ -0,0 +1,144 @@
+//! # Spawn Admission Control
+//!
+//! Decides whether the node can afford to start another process *before*
+//! any memory is committed to it.
+//!
+//! ## Why?
+//! The kernel heap is a bump allocator that never frees, and it also feeds
+//! smoltcp's socket buffers and wasmi's compiled code. Letting a spawn run
+//! until the allocator returns null ends in a panic somewhere unrelated.
+//! Checking aggregate headroom up front turns overload into a clean,
+//! structured rejection the requester (local or remote) can act on.
+//!
+//! ## What is checked
+//! | Resource | Source | Reserve kept back |
+//! |:---|:---|:---|
+//! | Heap    | `heap_usage()`                 | `HEAP_RESERVE` bytes |
+//! | Frames  | `memory::free_dma_frames()`    | `FRAME_RESERVE` frames |
+//! | Tasks   | `executor::task_count()`       | up to `MAX_TASKS` (CPU headroom proxy) |
+//! | Sockets | `NetworkStack::socket_count()` | up to `net.max_sockets` |
+
+use core::fmt;
+use crate::net_stack::{self, NETWORK_STACK};
+use crate::{executor, memory};
+
+/// Heap bytes always kept free for the network stack and kernel bookkeeping.
+const HEAP_RESERVE: usize = 512 * 1024;
+/// DMA frames always kept free so the NIC can keep refilling its RX queue.
+pub const FRAME_RESERVE: usize = 512;
+/// Maximum number of live executor tasks. With a single core and no
+/// preemption, each task is a share of CPU time; past this, everyone starves.
+const MAX_TASKS: usize = 64;
+/// wasmi's compiled representation is several times the size of the bytecode.
+const COMPILE_OVERHEAD: usize = 8;
+/// Baseline heap cost of a process (store, instance, linker, host state).
+const PROCESS_BASE_HEAP: usize = 64 * 1024;
+
+/// What a spawn is expected to consume.
+#[derive(Debug, Clone, Copy)]
+pub struct ResourceRequest {
+    /// Heap bytes the process will allocate up front.
+    pub heap_bytes: usize,
+    /// DMA frames the process needs.
+    pub frames: usize,
+    /// Sockets the process needs.
+    pub sockets: usize,
+}
+
+impl ResourceRequest {
+    /// Estimate the cost of spawning a WASM module of the given size whose
+    /// linear memory may grow to `memory_limit` bytes.
+    ///
+    /// Linear memory lives on the kernel heap, so the whole cap is counted
+    /// now: a process admitted here can't later fail a `memory.grow` below
+    /// its limit because other spawns ate the headroom.
+    pub fn for_wasm(module_len: usize, memory_limit: usize) -> Self {
+        ResourceRequest {
+            heap_bytes: PROCESS_BASE_HEAP
+                .saturating_add(module_len.saturating_mul(COMPILE_OVERHEAD))
+                .saturating_add(memory_limit),
+            frames: 0,
+            sockets: 0,
+        }
+    }
+}
+
+/// The resource that could not be satisfied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    Heap,
+    Frames,
+    Tasks,
+    Sockets,
+}
+
+/// A snapshot of node-wide resource usage.
+#[derive(Debug, Clone, Copy)]
+pub struct Utilization {
+    pub heap_used: usize,
+    pub heap_total: usize,
+    pub frames_free: usize,
+    pub tasks: usize,
+    pub max_tasks: usize,
+    pub sockets: usize,
+    pub max_sockets: usize,
+}
+
+/// Structured rejection: which resource ran short, and the state of the node.
+#[derive(Debug, Clone, Copy)]
+pub struct InsufficientResources {
+    pub resource: Resource,
+    pub utilization: Utilization,
+}
+
+impl fmt::Display for InsufficientResources {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let u = &self.utilization;
+        match self.resource {
+            Resource::Heap => write!(f, "not enough heap ({} of {} bytes used)", u.heap_used, u.heap_total),
+            Resource::Frames => write!(f, "not enough DMA frames ({} free)", u.frames_free),
+            Resource::Tasks => write!(f, "too many tasks ({} of {})", u.tasks, u.max_tasks),
+            Resource::Sockets => write!(f, "too many sockets ({} of {})", u.sockets, u.max_sockets),
+        }
+    }
+}
+
+/// Take a snapshot of current resource usage.
+pub fn utilization() -> Utilization {
+    let (heap_used, heap_total) = crate::heap_usage();
+    let sockets = NETWORK_STACK
+        .lock()
+        .as_ref()
+        .map(|stack| stack.socket_count())
+        .unwrap_or(0);
+    Utilization {
+        heap_used,
+        heap_total,
+        frames_free: memory::free_dma_frames(),
+        tasks: executor::task_count(),
+        max_tasks: MAX_TASKS,
+        sockets,
+        max_sockets: net_stack::max_sockets(),
+    }
+}
+
+/// Admit or reject a spawn that needs `request`.
+pub fn admit(request: &ResourceRequest) -> Result<(), InsufficientResources> {
+    let utilization = utilization();
+    let reject = |resource| Err(InsufficientResources { resource, utilization });
+
+    let heap_free = utilization.heap_total.saturating_sub(utilization.heap_used);
+    if heap_free < request.heap_bytes.saturating_add(HEAP_RESERVE) {
+        return reject(Resource::Heap);
+    }
+    if utilization.frames_free < request.frames.saturating_add(FRAME_RESERVE) {
+        return reject(Resource::Frames);
+    }
+    if utilization.tasks >= utilization.max_tasks {
+        return reject(Resource::Tasks);
+    }
+    if utilization.sockets + request.sockets > utilization.max_sockets {
+        return reject(Resource::Sockets);
+    }
+    Ok(())
+}
@@ -0,0 +1,264 @@
+//! # VirtIO Memory Balloon
+//!
+//! Lets the host reclaim memory from this guest. The host writes a target
+//! page count into the device's config space; the driver *inflates* the
+//! balloon by handing that many frames to the host (which may then unmap
+//! them) and *deflates* it by taking frames back.
+//!
+//! ## Memory policy
+//! Inflating never eats into the frames admission control keeps in reserve
+//! (`admission::FRAME_RESERVE`). When free frames run short, registered
+//! caches are shrunk first (`memory::shrink_caches`); if that still isn't
+//! enough, the balloon stops short of the host's target rather than starve
+//! running workloads. The shortfall is logged and retried on the next poll.
+//!
+//! ## Device (legacy, PCI ID 0x1002)
+//! - Queue 0 (inflate) / queue 1 (deflate): buffers are arrays of `u32`
+//!   page frame numbers (4 KiB pages).
+//! - Config `+0` `num_pages`: target set by the host.
+//! - Config `+4` `actual`: pages currently in the balloon, set by us.
+//!
+//! We never touch a frame after it's been inflated, and always tell the host
+//! before reusing a deflated one, so no feature bits are negotiated.
+
+use alloc::vec::Vec;
+use core::ptr::NonNull;
+use core::sync::atomic::{fence, Ordering};
+use spin::Mutex;
+use virtio_drivers::transport::{DeviceStatus, Transport};
+use virtio_drivers::{BufferDirection, Hal};
+use x86_64::PhysAddr;
+use crate::admission;
+use crate::hal::VirtioHal;
+use crate::memory;
+use crate::network::{self, LegacyTransport};
+use crate::serial_println;
+
+const INFLATE_QUEUE: u16 = 0;
+const DEFLATE_QUEUE: u16 = 1;
+/// Frames moved per request (one PFN page holds far more; this bounds the
+/// time spent in a single `poll`).
+const BATCH: usize = 256;
+/// Config-space offsets.
+const CONFIG_NUM_PAGES: usize = 0;
+const CONFIG_ACTUAL: usize = 4;
+/// Spins to wait for the device to consume a buffer before giving up.
+const COMPLETION_SPINS: usize = 1_000_000;
+
+static BALLOON: Mutex<Option<Balloon>> = Mutex::new(None);
+
+/// A legacy split virtqueue carrying one request at a time.
+struct LegacyQueue {
+    index: u16,
+    size: u16,
+    base: NonNull<u8>,
+    avail_idx: u16,
+    last_used: u16,
+}
+
+// Safety: the queue memory is owned by this driver and only touched under `BALLOON`.
+unsafe impl Send for LegacyQueue {}
+
+impl LegacyQueue {
+    /// Allocate and register queue `index` with the device.
+    fn new(transport: &mut LegacyTransport, index: u16) -> Option<Self> {
+        let size = transport.max_queue_size(index) as u16;
+        if size == 0 {
+            return None;
+        }
+        let pages = Self::used_offset(size).div_ceil(4096) + (6 + 8 * size as usize).div_ceil(4096);
+        let (phys, base) = VirtioHal::dma_alloc(pages, BufferDirection::Both);
+        transport.queue_set(index, size as u32, phys, 0, 0);
+        Some(LegacyQueue { index, size, base, avail_idx: 0, last_used: 0 })
+    }
+
+    /// The used ring starts on the page after descriptors + avail ring.
+    fn used_offset(size: u16) -> usize {
+        (16 * size as usize + 6 + 2 * size as usize).next_multiple_of(4096)
+    }
+
+    /// Post a device-readable buffer and wait for the device to consume it.
+    fn submit(&mut self, transport: &mut LegacyTransport, buf_phys: usize, len: u32) -> bool {
+        let size = self.size as usize;
+        let base = self.base.as_ptr();
+        unsafe {
+            // Descriptor 0: addr u64, len u32, flags u16 (0 = device reads), next u16.
+            let desc = base as *mut u64;
+            desc.write_volatile(buf_phys as u64);
+            (base.add(8) as *mut u32).write_volatile(len);
+            (base.add(12) as *mut u16).write_volatile(0);
+            (base.add(14) as *mut u16).write_volatile(0);
+
+            // Avail ring: flags u16, idx u16, ring[size] u16.
+            let avail = base.add(16 * size);
+            let slot = (self.avail_idx as usize) % size;
+            (avail.add(4 + 2 * slot) as *mut u16).write_volatile(0);
+            fence(Ordering::SeqCst);
+            self.avail_idx = self.avail_idx.wrapping_add(1);
+            (avail.add(2) as *mut u16).write_volatile(self.avail_idx);
+            fence(Ordering::SeqCst);
+        }
+        transport.notify(self.index);
+
+        // Used ring: flags u16, idx u16, ...
+        let used_idx = unsafe { base.add(Self::used_offset(self.size) + 2) as *const u16 };
+        for _ in 0..COMPLETION_SPINS {
+            let idx = unsafe { used_idx.read_volatile() };
+            if idx != self.last_used {
+                self.last_used = idx;
+                return true;
+            }
+            core::hint::spin_loop();
+        }
+        false
+    }
+}
+
+struct Balloon {
+    transport: LegacyTransport,
+    inflate: LegacyQueue,
+    deflate: LegacyQueue,
+    /// One DMA page holding the PFN array of the current request.
+    pfn_phys: usize,
+    pfn_buf: NonNull<u32>,
+    /// Frames currently given to the host.
+    frames: Vec<PhysAddr>,
+}
+
+// Safety: see `LegacyQueue`; the PFN page is likewise owned by the driver.
+unsafe impl Send for Balloon {}
+
+impl Balloon {
+    fn target(&self) -> usize {
+        self.transport.read_config_space::<u32>(CONFIG_NUM_PAGES).unwrap_or(0) as usize
+    }
+
+    fn report_actual(&mut self) {
+        let _ = self
+            .transport
+            .write_config_space::<u32>(CONFIG_ACTUAL, self.frames.len() as u32);
+    }
+
+    /// Move up to `count` frames into the balloon, respecting the reserve.
+    fn inflate(&mut self, count: usize) -> usize {
+        let wanted = count.min(BATCH);
+        let mut available = memory::free_dma_frames().saturating_sub(admission::FRAME_RESERVE);
+        if available < wanted {
+            let freed = memory::shrink_caches(wanted - available);
+            available += freed;
+        }
+        let n = wanted.min(available);
+        if n < wanted {
+            serial_println!(
+                "[BALLOON] Holding back: host wants {} more pages, only {} above reserve.",
+                count,
+                n
+            );
+        }
+        if n == 0 {
+            return 0;
+        }
+
+        let mut batch = Vec::with_capacity(n);
+        for i in 0..n {
+            match memory::allocate_contiguous_frames(1) {
+                Some(frame) => {
+                    unsafe { self.pfn_buf.as_ptr().add(i).write_volatile((frame.as_u64() >> 12) as u32) };
+                    batch.push(frame);
+                }
+                None => break,
+            }
+        }
+        let pfn_phys = self.pfn_phys;
+        if !self.inflate.submit(&mut self.transport, pfn_phys, (batch.len() * 4) as u32) {
+            serial_println!("[BALLOON] Device did not complete inflate request.");
+            // The host may or may not have taken them; never reuse them.
+            return 0;
+        }
+        let moved = batch.len();
+        self.frames.extend(batch);
+        moved
+    }
+
+    /// Take up to `count` frames back from the host and free them.
+    fn deflate(&mut self, count: usize) -> usize {
+        let n = count.min(BATCH).min(self.frames.len());
+        let start = self.frames.len() - n;
+        for (i, frame) in self.frames[start..].iter().enumerate() {
+            unsafe { self.pfn_buf.as_ptr().add(i).write_volatile((frame.as_u64() >> 12) as u32) };
+        }
+        let pfn_phys = self.pfn_phys;
+        if !self.deflate.submit(&mut self.transport, pfn_phys, (n * 4) as u32) {
+            serial_println!("[BALLOON] Device did not complete deflate request.");
+            return 0;
+        }
+        // Tell-before-use: the host has acknowledged, the frames are ours again.
+        for frame in self.frames.drain(start..) {
+            memory::free_frame(frame);
+        }
+        n
+    }
+}
+
+/// Probe for a legacy virtio-balloon device and bring it up.
+pub fn init() {
+    let io_base = match network::find_legacy_device(0x1002) {
+        Some(io_base) => io_base,
+        None => {
+            serial_println!("[BALLOON] No VirtIO balloon device found.");
+            return;
+        }
+    };
+
+    let mut transport = LegacyTransport::new(io_base);
+    transport.set_status(DeviceStatus::empty());
+    transport.set_status(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER);
+    transport.write_driver_features(0);
+
+    let inflate = LegacyQueue::new(&mut transport, INFLATE_QUEUE);
+    let deflate = LegacyQueue::new(&mut transport, DEFLATE_QUEUE);
+    let (inflate, deflate) = match (inflate, deflate) {
+        (Some(i), Some(d)) => (i, d),
+        _ => {
+            serial_println!("[BALLOON] Device has no inflate/deflate queues.");
+            transport.set_status(DeviceStatus::FAILED);
+            return;
+        }
+    };
+    let (pfn_phys, pfn_buf) = VirtioHal::dma_alloc(1, BufferDirection::DriverToDevice);
+    transport.finish_init();
+
+    let mut balloon = Balloon {
+        transport,
+        inflate,
+        deflate,
+        pfn_phys,
+        pfn_buf: pfn_buf.cast(),
+        frames: Vec::new(),
+    };
+    balloon.report_actual();
+    serial_println!("[BALLOON] Driver initialized (target {} pages).", balloon.target());
+    *BALLOON.lock() = Some(balloon);
+}
+
+/// Move the balloon one batch toward the host's target. Call periodically.
+pub fn poll() {
+    let mut guard = BALLOON.lock();
+    let balloon = match guard.as_mut() {
+        Some(balloon) => balloon,
+        None => return,
+    };
+    let target = balloon.target();
+    let actual = balloon.frames.len();
+    let moved = if target > actual {
+        balloon.inflate(target - actual)
+    } else if target < actual {
+        balloon.deflate(actual - target)
+    } else {
+        0
+    };
+    if moved > 0 {
+        balloon.report_actual();
+        serial_println!("[BALLOON] {} pages held (target {}).", balloon.frames.len(), target);
+    }
+}
@@ -0,0 +1,405 @@
+//! # Block Device Layer
+//!
+//! A common interface for random-access storage, so filesystems and the
+//! log store don't care whether they sit on a whole disk, a partition, or
+//! a RAM disk.
+//!
+//! ## Naming
+//! Disks register as `diskN` (see `virtio_blk`). When a disk is registered its partition table
+//! is scanned (see `partition`) and every partition is registered as its own
+//! device, `diskNpM`, covering only that partition's blocks. Consumers
+//! should open a partition rather than the raw disk, so the kernel can share
+//! a disk with other operating systems or data.
+//!
+//! Guests reach devices by name through `env.blk_read` / `env.blk_write` /
+//! `env.blk_flush`, with a Device capability on `DEVICE_BLOCK`.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::capability::Capability;
+use crate::ipc::{IpcError, Message, Priority, IPC_MANAGER};
+use crate::partition::{self, PartitionKind};
+use crate::serial_println;
+
+/// Errors returned by block devices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The request extends past the end of the device.
+    OutOfRange,
+    /// The buffer length is not a multiple of the block size.
+    UnalignedBuffer,
+    /// The device reported an I/O error.
+    Io,
+    /// No device is registered under that name.
+    NoSuchDevice,
+    /// A device is already registered under that name.
+    AlreadyRegistered,
+    /// The device refuses writes.
+    ReadOnly,
+}
+
+/// A device addressed in fixed-size blocks.
+///
+/// Methods take `&self`; drivers do their own locking so a device can be
+/// shared (e.g., by a disk and the partitions carved out of it).
+///
+/// ## Ordering and durability
+/// - A write has *completed* when `write_blocks` returns `Ok`; requests
+///   complete in the order they were issued. Completed data may still sit
+///   in a volatile device cache.
+/// - `flush` returns only once every write that completed before it was
+///   called is on stable media (a write barrier).
+///
+/// A crash-consistent writer therefore writes data, calls `flush`, writes
+/// the commit record, and calls `flush` again.
+pub trait BlockDevice: Send + Sync {
+    /// Size of one block in bytes (usually 512).
+    fn block_size(&self) -> usize;
+    /// Number of blocks on the device.
+    fn block_count(&self) -> u64;
+    /// Read `buf.len() / block_size()` blocks starting at `lba`.
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+    /// Write `buf.len() / block_size()` blocks starting at `lba`.
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError>;
+    /// Make every completed write durable. There is no default, so a
+    /// driver with a write cache can't silently inherit a no-op.
+    fn flush(&self) -> Result<(), BlockError>;
+}
+
+/// Validate that a request of `len` bytes at `lba` fits on `dev`, returning
+/// the number of blocks it covers.
+pub fn check_request(dev: &dyn BlockDevice, lba: u64, len: usize) -> Result<u64, BlockError> {
+    if !len.is_multiple_of(dev.block_size()) {
+        return Err(BlockError::UnalignedBuffer);
+    }
+    let blocks = (len / dev.block_size()) as u64;
+    match lba.checked_add(blocks) {
+        Some(end) if end <= dev.block_count() => Ok(blocks),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+// ─── Registry ────────────────────────────────────────────────────────────────
+
+struct Registered {
+    name: String,
+    device: Arc<dyn BlockDevice>,
+    /// `None` for a whole disk.
+    partition: Option<PartitionKind>,
+}
+
+/// A registered device, as listed by `list`.
+#[derive(Debug, Clone)]
+pub struct BlockInfo {
+    pub name: String,
+    pub block_size: usize,
+    pub block_count: u64,
+    /// `None` for a whole disk.
+    pub partition: Option<PartitionKind>,
+}
+
+lazy_static! {
+    static ref BLOCK_DEVICES: Mutex<Vec<Registered>> = Mutex::new(Vec::new());
+}
+
+/// Register a device under `name` without scanning it.
+fn register(name: &str, device: Arc<dyn BlockDevice>, partition: Option<PartitionKind>) -> Result<(), BlockError> {
+    let mut devices = BLOCK_DEVICES.lock();
+    if devices.iter().any(|d| d.name == name) {
+        return Err(BlockError::AlreadyRegistered);
+    }
+    devices.push(Registered { name: String::from(name), device, partition });
+    Ok(())
+}
+
+/// Register a whole disk and each partition found on it.
+///
+/// The disk is wrapped in a health monitor (see below), so partitions share
+/// its statistics. Returns the number of partitions registered. A disk
+/// without a recognizable partition table is still registered, with 0
+/// partitions.
+pub fn register_disk(name: &str, device: Arc<dyn BlockDevice>) -> Result<usize, BlockError> {
+    let monitor = Arc::new(MonitoredDevice::new(name, device));
+    let device: Arc<dyn BlockDevice> = monitor.clone();
+    register(name, device.clone(), None)?;
+    MONITORS.lock().push(monitor);
+    let partitions = match partition::scan(&*device) {
+        Ok(partitions) => partitions,
+        Err(e) => {
+            serial_println!("[BLOCK] {}: no usable partition table ({:?})", name, e);
+            return Ok(0);
+        }
+    };
+
+    for part in &partitions {
+        let part_name = alloc::format!("{}p{}", name, part.index);
+        serial_println!(
+            "[BLOCK] {}: LBA {}..{} ({:?})",
+            part_name,
+            part.start_lba,
+            part.start_lba + part.block_count,
+            part.kind
+        );
+        let view = partition::PartitionDevice::new(device.clone(), part.start_lba, part.block_count);
+        register(&part_name, Arc::new(view), Some(part.kind.clone()))?;
+    }
+    Ok(partitions.len())
+}
+
+/// Look up a registered device by name.
+pub fn get(name: &str) -> Result<Arc<dyn BlockDevice>, BlockError> {
+    BLOCK_DEVICES
+        .lock()
+        .iter()
+        .find(|d| d.name == name)
+        .map(|d| d.device.clone())
+        .ok_or(BlockError::NoSuchDevice)
+}
+
+/// Flush every registered device (e.g., before shutdown).
+///
+/// Partitions forward to their disk, so a disk may be flushed more than
+/// once; flushing is idempotent. Returns the first error encountered, after
+/// attempting all devices.
+pub fn flush_all() -> Result<(), BlockError> {
+    let devices: Vec<Arc<dyn BlockDevice>> =
+        BLOCK_DEVICES.lock().iter().map(|d| d.device.clone()).collect();
+    let mut result = Ok(());
+    for device in devices {
+        if let Err(e) = device.flush() {
+            result = result.and(Err(e));
+        }
+    }
+    result
+}
+
+/// `flush_all` for the panic path: returns `None` without flushing if the
+/// device registry is locked (the panic may have happened while holding it).
+pub fn try_flush_all() -> Option<Result<(), BlockError>> {
+    drop(BLOCK_DEVICES.try_lock()?);
+    Some(flush_all())
+}
+
+/// Every registered device, disks before their partitions.
+pub fn list() -> Vec<BlockInfo> {
+    BLOCK_DEVICES
+        .lock()
+        .iter()
+        .map(|d| BlockInfo {
+            name: d.name.clone(),
+            block_size: d.device.block_size(),
+            block_count: d.device.block_count(),
+            partition: d.partition.clone(),
+        })
+        .collect()
+}
+
+// ─── Health Monitoring ───────────────────────────────────────────────────────
+//
+// Early warning for failing media: every disk registered with
+// `register_disk` counts operations, errors and retries, and keeps a latency
+// histogram. When a disk's error rate crosses `ERROR_RATE_WARN_PERMILLE`, a
+// `EVENT_BLOCK_DEGRADED` message goes to every endpoint subscribed with
+// `subscribe_health` (guests: `env.blk_subscribe`).
+
+/// Times a failed I/O is retried before the error is returned.
+const IO_RETRIES: u32 = 2;
+/// Error rate (per mille of operations) at which a device is degraded.
+const ERROR_RATE_WARN_PERMILLE: u64 = 10;
+/// Operations needed before the error rate is considered meaningful.
+const MIN_OPS_FOR_RATE: u64 = 100;
+/// Latency histogram buckets; bucket `i` counts operations that took
+/// fewer than `2^(i+1)` TSC cycles.
+const LATENCY_BUCKETS: usize = 40;
+
+/// Message label of the event published when a device becomes degraded.
+/// Data: `[errors, operations, retries]`.
+pub const EVENT_BLOCK_DEGRADED: u64 = 0x424C_4B01;
+
+/// Broadcast channel of health events, created by the first subscriber.
+static HEALTH_CHANNEL: Mutex<Option<Capability>> = Mutex::new(None);
+
+lazy_static! {
+    static ref MONITORS: Mutex<Vec<Arc<MonitoredDevice>>> = Mutex::new(Vec::new());
+}
+
+/// Deliver `EVENT_BLOCK_DEGRADED` messages to the endpoint named by
+/// `endpoint` (which needs READ).
+pub fn subscribe_health(endpoint: &Capability) -> Result<(), IpcError> {
+    let mut ipc = IPC_MANAGER.lock();
+    let channel = HEALTH_CHANNEL.lock().get_or_insert_with(|| ipc.create_broadcast()).clone();
+    ipc.subscribe(&channel, endpoint)
+}
+
+/// I/O statistics for one disk.
+#[derive(Debug, Clone)]
+pub struct BlockStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub flushes: u64,
+    /// Operations that failed even after retrying.
+    pub errors: u64,
+    /// Individual retries (a successful retry still counts here).
+    pub retries: u64,
+    /// Whether the device has crossed the error-rate threshold.
+    pub degraded: bool,
+    latency: [u64; LATENCY_BUCKETS],
+}
+
+impl BlockStats {
+    const fn new() -> Self {
+        BlockStats {
+            reads: 0,
+            writes: 0,
+            flushes: 0,
+            errors: 0,
+            retries: 0,
+            degraded: false,
+            latency: [0; LATENCY_BUCKETS],
+        }
+    }
+
+    /// Total operations issued.
+    pub fn operations(&self) -> u64 {
+        self.reads + self.writes + self.flushes
+    }
+
+    /// Failed operations per thousand.
+    pub fn error_rate_permille(&self) -> u64 {
+        self.errors * 1000 / self.operations().max(1)
+    }
+
+    /// Upper bound, in TSC cycles, of the given latency percentile
+    /// (e.g. `990` for p99). Returns 0 before any operation completed.
+    pub fn latency_percentile(&self, permille: u64) -> u64 {
+        let total: u64 = self.latency.iter().sum();
+        if total == 0 {
+            return 0;
+        }
+        let target = (total * permille).div_ceil(1000);
+        let mut seen = 0;
+        for (i, &count) in self.latency.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return 1u64 << (i + 1);
+            }
+        }
+        u64::MAX
+    }
+
+    fn record_latency(&mut self, cycles: u64) {
+        let bucket = (63 - cycles.max(1).leading_zeros()) as usize;
+        self.latency[bucket.min(LATENCY_BUCKETS - 1)] += 1;
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Op {
+    Read,
+    Write,
+    Flush,
+}
+
+/// Wraps a disk driver, retrying transient errors and recording statistics.
+struct MonitoredDevice {
+    name: String,
+    inner: Arc<dyn BlockDevice>,
+    stats: Mutex<BlockStats>,
+}
+
+impl MonitoredDevice {
+    fn new(name: &str, inner: Arc<dyn BlockDevice>) -> Self {
+        MonitoredDevice {
+            name: String::from(name),
+            inner,
+            stats: Mutex::new(BlockStats::new()),
+        }
+    }
+
+    /// Run one operation with retries, then update statistics.
+    fn run(&self, op: Op, mut io: impl FnMut() -> Result<(), BlockError>) -> Result<(), BlockError> {
+        let start = unsafe { core::arch::x86_64::_rdtsc() };
+        let mut retries = 0;
+        let result = loop {
+            match io() {
+                Err(BlockError::Io) if retries < IO_RETRIES => retries += 1,
+                result => break result,
+            }
+        };
+        let cycles = unsafe { core::arch::x86_64::_rdtsc() }.wrapping_sub(start);
+
+        let newly_degraded = {
+            let mut stats = self.stats.lock();
+            match op {
+                Op::Read => stats.reads += 1,
+                Op::Write => stats.writes += 1,
+                Op::Flush => stats.flushes += 1,
+            }
+            stats.retries += retries as u64;
+            if result.is_err() {
+                stats.errors += 1;
+            }
+            stats.record_latency(cycles);
+
+            let failing = stats.operations() >= MIN_OPS_FOR_RATE
+                && stats.error_rate_permille() >= ERROR_RATE_WARN_PERMILLE;
+            let newly = failing && !stats.degraded;
+            stats.degraded = failing;
+            newly.then(|| (stats.errors, stats.operations(), stats.retries))
+        };
+        if let Some((errors, ops, retries)) = newly_degraded {
+            self.report_degraded(errors, ops, retries);
+        }
+        result
+    }
+
+    fn report_degraded(&self, errors: u64, ops: u64, retries: u64) {
+        serial_println!(
+            "[BLOCK] WARNING: {} degraded: {} errors in {} ops ({} retries)",
+            self.name,
+            errors,
+            ops,
+            retries
+        );
+        if let Some(cap) = HEALTH_CHANNEL.lock().clone() {
+            let mut msg = Message::with_data2(EVENT_BLOCK_DEGRADED, errors, ops);
+            msg.data[2] = retries;
+            msg.length = 3;
+            let _ = IPC_MANAGER.lock().publish(&cap, msg.with_priority(Priority::High));
+        }
+    }
+}
+
+impl BlockDevice for MonitoredDevice {
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.inner.block_count()
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.run(Op::Read, || self.inner.read_blocks(lba, buf))
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.run(Op::Write, || self.inner.write_blocks(lba, buf))
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.run(Op::Flush, || self.inner.flush())
+    }
+}
+
+/// Snapshot of every monitored disk's statistics.
+pub fn stats() -> Vec<(String, BlockStats)> {
+    MONITORS
+        .lock()
+        .iter()
+        .map(|m| (m.name.clone(), m.stats.lock().clone()))
+        .collect()
+}
@@ -12,6 +12,10 @@
 //! | `EndpointCap`   | An IPC endpoint | Send, Receive |
 //! | `ThreadCap`     | A thread/process | Start, Stop, Configure |
 //! | `DeviceCap`     | A hardware device (e.g., serial port) | Read, Write |
+//! | `SocketCap`     | A TCP/UDP socket in the network stack | Connect, Listen, Send, Recv |
+//! | `SchedContextCap` | A CPU reservation (budget + period) | Execute (bind a task to it) |
+//! | `StorageCap`    | A key-value namespace (see `kv`) | Read, Write |
+//! | `SharedMemoryCap` | A shared region (see `shm`) | Read, Write |
 //!
 //! ## Why Capabilities?
 //! In traditional OS security (like Linux), access control is based on
@@ -25,6 +29,7 @@
 //! This is the initial skeleton. It defines the data structures and basic
 //! operations. The kernel will use this to control all resource access.
 
+use alloc::vec::Vec;
 use core::sync::atomic::{AtomicU64, Ordering};
 
 /// Unique identifier for a capability. Generated by the kernel and never reused.
@@ -59,10 +64,80 @@ pub enum CapabilityType {
     Thread,
     /// Access to a hardware device (for I/O operations).
     Device,
+    /// Access to a TCP/UDP socket owned by the network stack.
+    Socket,
+    /// Authority to run a task under a CPU reservation (budget per period).
+    SchedContext,
+    /// Access to a key-value namespace (`resource_id` is the namespace).
+    Storage,
+    /// Access to a shared memory region (`resource_id` is the region id).
+    SharedMemory,
     /// A "null" capability — placeholder for empty CSpace slots.
     Null,
 }
 
+impl CapabilityType {
+    /// Stable numeric code for this type, used when describing capabilities
+    /// to user space.
+    pub const fn code(self) -> u32 {
+        match self {
+            CapabilityType::Null => 0,
+            CapabilityType::Memory => 1,
+            CapabilityType::Endpoint => 2,
+            CapabilityType::Thread => 3,
+            CapabilityType::Device => 4,
+            CapabilityType::Socket => 5,
+            CapabilityType::SchedContext => 6,
+            CapabilityType::Storage => 7,
+            CapabilityType::SharedMemory => 8,
+        }
+    }
+
+    /// Inverse of [`CapabilityType::code`].
+    pub const fn from_code(code: u32) -> Option<Self> {
+        match code {
+            0 => Some(CapabilityType::Null),
+            1 => Some(CapabilityType::Memory),
+            2 => Some(CapabilityType::Endpoint),
+            3 => Some(CapabilityType::Thread),
+            4 => Some(CapabilityType::Device),
+            5 => Some(CapabilityType::Socket),
+            6 => Some(CapabilityType::SchedContext),
+            7 => Some(CapabilityType::Storage),
+            8 => Some(CapabilityType::SharedMemory),
+            _ => None,
+        }
+    }
+}
+
+/// `resource_id` of the `Device` capability for the kernel's monotonic clock.
+pub const DEVICE_CLOCK: u64 = 1;
+/// `resource_id` of the `Device` capability for the kernel's random number generator.
+pub const DEVICE_RNG: u64 = 2;
+/// `resource_id` of the `Device` capability for `/dev/null`.
+pub const DEVICE_NULL: u64 = 3;
+/// `resource_id` of the `Device` capability for `/dev/zero`.
+pub const DEVICE_ZERO: u64 = 4;
+/// `resource_id` of the `Device` capability for the kernel console.
+pub const DEVICE_CONSOLE: u64 = 5;
+/// `resource_id` of the `Device` capability for the first serial port.
+pub const DEVICE_SERIAL0: u64 = 6;
+/// `resource_id` of the `Device` capability for the second serial port.
+pub const DEVICE_SERIAL1: u64 = 7;
+/// `resource_id` of the `Device` capability for the network stack. Its
+/// CONNECT / LISTEN / SEND / RECV bits bound what sockets it may create.
+pub const DEVICE_NETWORK: u64 = 8;
+/// `resource_id` of the `Device` capability for network administration.
+/// WRITE lets its holder change the firewall rules.
+pub const DEVICE_NET_ADMIN: u64 = 9;
+/// `resource_id` of the `Device` capability for the block layer. READ and
+/// WRITE bound what its holder may do to every registered disk and
+/// partition (see `env.blk_read`).
+pub const DEVICE_BLOCK: u64 = 10;
+/// `resource_id` of the `Device` capability for `/dev/hvc0`, port 0 of the
+/// virtio-serial device.
+pub const DEVICE_VIRTIO_CONSOLE: u64 = 11;
+
 /// The permissions granted by a capability.
 ///
 /// Permissions are stored as a bitmask for efficient checking.
@@ -76,6 +151,13 @@ impl Permissions {
     pub const EXECUTE: Self = Permissions(1 << 2);
     pub const GRANT: Self = Permissions(1 << 3); // Can delegate this capability to others
 
+    // Socket-specific rights (only meaningful on `CapabilityType::Socket`).
+    pub const CONNECT: Self = Permissions(1 << 4); // Initiate outbound connections
+    pub const LISTEN: Self = Permissions(1 << 5);  // Bind and accept inbound connections
+    pub const SEND: Self = Permissions(1 << 6);    // Transmit data
+    pub const RECV: Self = Permissions(1 << 7);    // Receive data
+    pub const ALL: Self = Permissions(0b1111_1111);
+
     /// Combine two permission sets.
     pub const fn union(self, other: Self) -> Self {
         Permissions(self.0 | other.0)
@@ -88,7 +170,22 @@ impl Permissions {
 
     /// Returns a permission set with all permissions enabled.
     pub const fn all() -> Self {
-        Permissions(0b1111)
+        Permissions::ALL
+    }
+
+    /// Returns the raw permission bitmask.
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Build a permission set from a raw bitmask, dropping unknown bits.
+    pub const fn from_bits_truncate(bits: u32) -> Self {
+        Permissions(bits & Permissions::all().0)
+    }
+
+    /// Keep only the permissions present in both sets.
+    pub const fn intersection(self, other: Self) -> Self {
+        Permissions(self.0 & other.0)
     }
 }
 
@@ -105,6 +202,39 @@ pub struct Capability {
     pub resource_id: u64,
 }
 
+impl Capability {
+    /// Mint a fresh capability for a resource.
+    pub fn new(cap_type: CapabilityType, permissions: Permissions, resource_id: u64) -> Self {
+        Capability {
+            id: CapabilityId::new(),
+            cap_type,
+            permissions,
+            resource_id,
+        }
+    }
+
+    /// Derive a copy of this capability with (at most) the given permissions.
+    ///
+    /// This is how authority is delegated: the holder must have `GRANT`, and
+    /// the derived capability can never carry rights the original lacks.
+    /// The copy refers to the same resource, so revoking the resource
+    /// invalidates every derived capability at once.
+    pub fn derive(&self, permissions: Permissions) -> Option<Capability> {
+        if !self.permissions.contains(Permissions::GRANT) {
+            return None;
+        }
+        Some(Capability::new(
+            self.cap_type,
+            self.permissions.intersection(permissions),
+            self.resource_id,
+        ))
+    }
+}
+
+/// A CSpace shared between a running process and the kernel objects that
+/// manage it (process table, task context).
+pub type SharedCSpace = alloc::sync::Arc<spin::Mutex<CSpace>>;
+
 /// The Capability Space — a per-process table of capabilities.
 ///
 /// Each process (or "protection domain") has its own CSpace.
@@ -146,6 +276,32 @@ impl CSpace {
         None // CSpace is full
     }
 
+    /// Find the first slot holding a capability of `cap_type` with at least
+    /// the `required` permissions.
+    pub fn find(&self, cap_type: CapabilityType, required: Permissions) -> Option<usize> {
+        self.iter()
+            .find(|(_, cap)| cap.cap_type == cap_type && cap.permissions.contains(required))
+            .map(|(slot, _)| slot)
+    }
+
+    /// Check whether this CSpace holds a capability for a specific resource
+    /// with at least the `required` permissions.
+    pub fn holds(&self, cap_type: CapabilityType, resource_id: u64, required: Permissions) -> bool {
+        self.iter().any(|(_, cap)| {
+            cap.cap_type == cap_type
+                && cap.resource_id == resource_id
+                && cap.permissions.contains(required)
+        })
+    }
+
+    /// Iterate over all occupied slots as `(slot, capability)` pairs.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Capability)> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(|(i, slot)| slot.as_ref().map(|cap| (i, cap)))
+    }
+
     /// Look up a capability by slot index.
     pub fn get(&self, slot: usize) -> Option<&Capability> {
         self.slots.get(slot)?.as_ref()
@@ -166,6 +322,42 @@ impl CSpace {
         }
     }
 
+    /// Build the CSpace for a child process spawned by this CSpace's owner.
+    ///
+    /// The child gets its own Thread capability with the parent's
+    /// self-rights, plus a copy of every capability the parent may delegate
+    /// (holds `GRANT` on). Copies never carry `GRANT`, so authority can only
+    /// shrink down a process tree.
+    pub fn derive_child(&self) -> CSpace {
+        let mut child = CSpace::new();
+        for (_, cap) in self.iter() {
+            let derived = if cap.cap_type == CapabilityType::Thread && cap.resource_id == 0 {
+                Some(Capability::new(CapabilityType::Thread, cap.permissions, 0))
+            } else {
+                cap.derive(cap.permissions)
+            };
+            if let Some(mut derived) = derived {
+                derived.permissions = Permissions(derived.permissions.0 & !Permissions::GRANT.0);
+                child.insert(derived);
+            }
+        }
+        child
+    }
+
+    /// A fresh CSpace holding new capabilities with the same types, rights
+    /// and resources as this one's, in the same slots.
+    ///
+    /// Kernel-only: unlike `derive_child` this doesn't need `GRANT`. Used to
+    /// restart a process with the capabilities it was first given.
+    pub fn replicate(&self) -> CSpace {
+        let mut copy = CSpace::new();
+        for (slot, cap) in self.iter() {
+            copy.slots[slot] = Some(Capability::new(cap.cap_type, cap.permissions, cap.resource_id));
+            copy.count += 1;
+        }
+        copy
+    }
+
     /// Check if a slot holds a capability with the required permissions.
     ///
     /// This is the core access-control check. Every resource access in the
@@ -186,4 +378,91 @@ impl CSpace {
     pub fn is_empty(&self) -> bool {
         self.count == 0
     }
+
+    /// Serialize this CSpace for checkpointing or migration.
+    ///
+    /// Only the portable part of each capability is kept: its slot, type,
+    /// permissions and resource id. Capability IDs are node-local and are
+    /// re-minted on restore.
+    ///
+    /// Layout (little-endian):
+    /// `[version: u8][count: u16]` followed by `count` entries of
+    /// `[slot: u16][type: u32][permissions: u32][resource_id: u64]`.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(3 + self.count * SERIALIZED_ENTRY_SIZE);
+        out.push(SERIALIZATION_VERSION);
+        out.extend_from_slice(&(self.count as u16).to_le_bytes());
+        for (slot, cap) in self.iter() {
+            out.extend_from_slice(&(slot as u16).to_le_bytes());
+            out.extend_from_slice(&cap.cap_type.code().to_le_bytes());
+            out.extend_from_slice(&cap.permissions.bits().to_le_bytes());
+            out.extend_from_slice(&cap.resource_id.to_le_bytes());
+        }
+        out
+    }
+
+    /// Rebuild a CSpace from the output of [`CSpace::serialize`].
+    ///
+    /// Resource ids in a checkpoint refer to kernel objects on the node that
+    /// produced it, so every capability is passed through `relink`, which maps
+    /// `(type, old_resource_id)` to the id of the equivalent local object.
+    /// If `relink` returns `None` the whole restore fails: silently dropping
+    /// authority would leave the process in a state it was never in.
+    pub fn deserialize<F>(bytes: &[u8], mut relink: F) -> Result<CSpace, RestoreError>
+    where
+        F: FnMut(CapabilityType, u64) -> Option<u64>,
+    {
+        if bytes.len() < 3 {
+            return Err(RestoreError::Truncated);
+        }
+        if bytes[0] != SERIALIZATION_VERSION {
+            return Err(RestoreError::UnsupportedVersion(bytes[0]));
+        }
+        let count = u16::from_le_bytes([bytes[1], bytes[2]]) as usize;
+        if bytes.len() < 3 + count * SERIALIZED_ENTRY_SIZE {
+            return Err(RestoreError::Truncated);
+        }
+
+        let mut cspace = CSpace::new();
+        for entry in bytes[3..].chunks_exact(SERIALIZED_ENTRY_SIZE).take(count) {
+            let slot = u16::from_le_bytes([entry[0], entry[1]]) as usize;
+            let code = u32::from_le_bytes([entry[2], entry[3], entry[4], entry[5]]);
+            let bits = u32::from_le_bytes([entry[6], entry[7], entry[8], entry[9]]);
+            let mut id_bytes = [0u8; 8];
+            id_bytes.copy_from_slice(&entry[10..18]);
+            let old_resource_id = u64::from_le_bytes(id_bytes);
+
+            let cap_type = CapabilityType::from_code(code).ok_or(RestoreError::UnknownType(code))?;
+            if slot >= CSPACE_SIZE || cspace.slots[slot].is_some() {
+                return Err(RestoreError::InvalidSlot(slot));
+            }
+            let resource_id = relink(cap_type, old_resource_id)
+                .ok_or(RestoreError::Unresolved { cap_type, resource_id: old_resource_id })?;
+
+            let permissions = Permissions::from_bits_truncate(bits);
+            cspace.slots[slot] = Some(Capability::new(cap_type, permissions, resource_id));
+            cspace.count += 1;
+        }
+        Ok(cspace)
+    }
+}
+
+/// Format version written by [`CSpace::serialize`].
+const SERIALIZATION_VERSION: u8 = 1;
+/// Size in bytes of one serialized capability entry.
+const SERIALIZED_ENTRY_SIZE: usize = 18;
+
+/// Errors that can occur while restoring a serialized CSpace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestoreError {
+    /// The buffer ended before all entries were read.
+    Truncated,
+    /// The checkpoint was written by an incompatible format version.
+    UnsupportedVersion(u8),
+    /// An entry names a capability type this kernel doesn't know.
+    UnknownType(u32),
+    /// An entry's slot is out of range or used twice.
+    InvalidSlot(usize),
+    /// `relink` found no local object for this capability.
+    Unresolved { cap_type: CapabilityType, resource_id: u64 },
 }
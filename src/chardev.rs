@@ -0,0 +1,208 @@
+//! # Character Devices and `/dev`
+//!
+//! A byte-stream device layer: every device implements [`CharDevice`] and
+//! appears under `/dev` in the VFS. Opening a device goes through
+//! `Vfs::open`, which checks that the caller holds a `Device` capability
+//! on the device's ID with the requested permissions.
+//!
+//! ## Built-in devices
+//! | Path | Device ID | Behavior |
+//! |:---|:---|:---|
+//! | `/dev/null`    | `DEVICE_NULL`    | Reads return EOF, writes are discarded |
+//! | `/dev/zero`    | `DEVICE_ZERO`    | Reads return zeros, writes are discarded |
+//! | `/dev/random`  | `DEVICE_RNG`     | Reads return kernel RNG output |
+//! | `/dev/console` | `DEVICE_CONSOLE` | Kernel console (currently COM1) |
+//! | `/dev/ttyS0`   | `DEVICE_SERIAL0` | COM1, raw |
+//! | `/dev/ttyS1`   | `DEVICE_SERIAL1` | COM2, raw (guest agent fallback) |
+//!
+//! `virtio_console` adds `/dev/hvc0` (`DEVICE_VIRTIO_CONSOLE`) when the
+//! machine has a virtio-serial device.
+//!
+//! Reads never block: a device with no data ready returns 0 bytes.
+//! Future input devices register themselves with `register`.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::capability::{
+    Permissions, DEVICE_CONSOLE, DEVICE_NULL, DEVICE_RNG, DEVICE_SERIAL0, DEVICE_SERIAL1,
+    DEVICE_ZERO,
+};
+use crate::serial::{self, SerialLine};
+use crate::vfs::{FileSystem, ReadContext, VfsError};
+
+/// A device that transfers an unstructured stream of bytes.
+pub trait CharDevice: Send + Sync {
+    /// Read up to `buf.len()` bytes; returns how many were read (0 if none
+    /// are ready or the device is at EOF).
+    fn read(&self, buf: &mut [u8]) -> usize;
+    /// Write `data`; returns how many bytes the device accepted.
+    fn write(&self, data: &[u8]) -> usize;
+}
+
+struct DeviceEntry {
+    name: String,
+    id: u64,
+    device: Arc<dyn CharDevice>,
+}
+
+lazy_static! {
+    static ref DEVICES: Mutex<Vec<DeviceEntry>> = Mutex::new(Vec::new());
+}
+
+/// Register a device as `/dev/<name>`, guarded by `Device` capabilities
+/// whose `resource_id` is `id`.
+pub fn register(name: &str, id: u64, device: Arc<dyn CharDevice>) -> Result<(), VfsError> {
+    let mut devices = DEVICES.lock();
+    if devices.iter().any(|d| d.name == name) {
+        return Err(VfsError::AlreadyMounted);
+    }
+    devices.push(DeviceEntry { name: String::from(name), id, device });
+    Ok(())
+}
+
+/// Register the built-in devices. Call once, before mounting `DevFs`.
+pub fn init() {
+    let builtins: [(&str, u64, Arc<dyn CharDevice>); 6] = [
+        ("null", DEVICE_NULL, Arc::new(Null)),
+        ("zero", DEVICE_ZERO, Arc::new(Zero)),
+        ("random", DEVICE_RNG, Arc::new(Random)),
+        ("console", DEVICE_CONSOLE, Arc::new(Serial(SerialLine::Com1))),
+        ("ttyS0", DEVICE_SERIAL0, Arc::new(Serial(SerialLine::Com1))),
+        ("ttyS1", DEVICE_SERIAL1, Arc::new(Serial(SerialLine::Com2))),
+    ];
+    for (name, id, device) in builtins {
+        register(name, id, device).expect("Duplicate built-in device");
+    }
+}
+
+// ─── Open Devices ────────────────────────────────────────────────────────────
+
+/// A device opened through the VFS, with the permissions it was opened for.
+#[derive(Clone)]
+pub struct OpenDevice {
+    device: Arc<dyn CharDevice>,
+    permissions: Permissions,
+}
+
+impl OpenDevice {
+    /// Read from the device; fails unless it was opened with `READ`.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, VfsError> {
+        if !self.permissions.contains(Permissions::READ) {
+            return Err(VfsError::PermissionDenied);
+        }
+        Ok(self.device.read(buf))
+    }
+
+    /// Write to the device; fails unless it was opened with `WRITE`.
+    pub fn write(&self, data: &[u8]) -> Result<usize, VfsError> {
+        if !self.permissions.contains(Permissions::WRITE) {
+            return Err(VfsError::PermissionDenied);
+        }
+        Ok(self.device.write(data))
+    }
+}
+
+// ─── DevFs ───────────────────────────────────────────────────────────────────
+
+/// The `/dev` filesystem: a flat directory of registered devices.
+pub struct DevFs;
+
+impl FileSystem for DevFs {
+    fn read(&self, path: &str, _ctx: &ReadContext) -> Result<Vec<u8>, VfsError> {
+        match path {
+            "" => Err(VfsError::NotADirectory),
+            name if DEVICES.lock().iter().any(|d| d.name == name) => Err(VfsError::IsADevice),
+            _ => Err(VfsError::NotFound),
+        }
+    }
+
+    fn list(&self, path: &str) -> Result<Vec<String>, VfsError> {
+        if !path.is_empty() {
+            return match self.read(path, &ReadContext::KERNEL) {
+                Err(VfsError::NotFound) => Err(VfsError::NotFound),
+                _ => Err(VfsError::NotADirectory),
+            };
+        }
+        Ok(DEVICES.lock().iter().map(|d| d.name.clone()).collect())
+    }
+
+    fn open(&self, path: &str, permissions: Permissions, ctx: &ReadContext) -> Result<OpenDevice, VfsError> {
+        let devices = DEVICES.lock();
+        let entry = devices.iter().find(|d| d.name == path).ok_or(VfsError::NotFound)?;
+        if let Some(cspace) = ctx.cspace {
+            if !cspace.holds(crate::capability::CapabilityType::Device, entry.id, permissions) {
+                return Err(VfsError::PermissionDenied);
+            }
+        }
+        Ok(OpenDevice { device: entry.device.clone(), permissions })
+    }
+}
+
+// ─── Built-in Devices ────────────────────────────────────────────────────────
+
+struct Null;
+
+impl CharDevice for Null {
+    fn read(&self, _buf: &mut [u8]) -> usize {
+        0
+    }
+
+    fn write(&self, data: &[u8]) -> usize {
+        data.len()
+    }
+}
+
+struct Zero;
+
+impl CharDevice for Zero {
+    fn read(&self, buf: &mut [u8]) -> usize {
+        buf.fill(0);
+        buf.len()
+    }
+
+    fn write(&self, data: &[u8]) -> usize {
+        data.len()
+    }
+}
+
+struct Random;
+
+impl CharDevice for Random {
+    fn read(&self, buf: &mut [u8]) -> usize {
+        match getrandom::getrandom(buf) {
+            Ok(()) => buf.len(),
+            Err(_) => 0,
+        }
+    }
+
+    fn write(&self, data: &[u8]) -> usize {
+        // Accepted and dropped; the RNG has no entropy-mixing input yet.
+        data.len()
+    }
+}
+
+struct Serial(SerialLine);
+
+impl CharDevice for Serial {
+    fn read(&self, buf: &mut [u8]) -> usize {
+        let mut n = 0;
+        while n < buf.len() {
+            match serial::try_read_byte(self.0) {
+                Some(byte) => {
+                    buf[n] = byte;
+                    n += 1;
+                }
+                None => break,
+            }
+        }
+        n
+    }
+
+    fn write(&self, data: &[u8]) -> usize {
+        serial::write_bytes(self.0, data);
+        data.len()
+    }
+}
@@ -0,0 +1,54 @@
+//! # Kernel Command Line
+//!
+//! Boot-time settings as `key=value` words:
+//! ```text
+//! net=static,10.0.2.15/24,gw=10.0.2.2 p2p_bootstrap=10.0.2.2:40444
+//! ```
+//! The bootloader has no command line to pass, so it is the file `cmdline`
+//! in the initial ramdisk. Words are separated by spaces or newlines and
+//! `#` starts a comment; when a key appears twice the last word wins.
+//!
+//! ## Keys
+//! - `net=dhcp` (the default) asks DHCP for eth0's address, falling back
+//!   to QEMU's user-network address; `net=static,<addr>/<prefix>[,gw=<ip>]
+//!   [,dns=<ip>]` sets it instead. See `net_stack`.
+//! - `p2p_bootstrap=<host>:<port>[,<host>:<port>…]` lists peers the P2P
+//!   layer dials at boot, retrying each with backoff. Hosts may be names;
+//!   see `p2p`.
+//! - `arp=<ip>@<mac>[,<ip>@<mac>…]` adds static neighbor entries; see
+//!   `neighbor`.
+
+use spin::Once;
+use crate::{initrd, serial_println};
+
+/// Name of the file in the initrd holding the command line.
+pub const FILE: &str = "cmdline";
+
+static CMDLINE: Once<&'static str> = Once::new();
+
+/// Read the command line from the initrd. Call after `initrd::init` and
+/// before anything that reads it.
+pub fn init() {
+    let text = match initrd::find(FILE).map(core::str::from_utf8) {
+        Some(Ok(text)) => text,
+        Some(Err(_)) => {
+            serial_println!("[CMDLINE] Not valid UTF-8; ignoring it.");
+            ""
+        }
+        None => "",
+    };
+    CMDLINE.call_once(|| text);
+    for word in words() {
+        serial_println!("[CMDLINE] {}", word);
+    }
+}
+
+/// The value of `key`, if the command line sets it.
+pub fn get(key: &str) -> Option<&'static str> {
+    words().filter_map(|word| word.split_once('=')).filter(|(k, _)| *k == key).map(|(_, value)| value).last()
+}
+
+fn words() -> impl Iterator<Item = &'static str> {
+    let text: &'static str = CMDLINE.get().copied().unwrap_or("");
+    text.lines().flat_map(|line| line.split('#').next().unwrap_or("").split_whitespace())
+}
@@ -0,0 +1,289 @@
+//! # Static System Composition
+//!
+//! The initial system — which kernel objects exist at boot and which
+//! capabilities each boot process starts with — is declared once with the
+//! `system!` macro and checked by the compiler before the kernel is built.
+//!
+//! ```ignore
+//! system! {
+//!     pub const BOOT_SYSTEM {
+//!         object clock = Device(capability::DEVICE_CLOCK): [READ];
+//!         object control = Endpoint: [READ, WRITE, GRANT];
+//!
+//!         process root { control: [READ, WRITE, GRANT] }
+//!         process hello_world { self: [READ], clock: [READ] }
+//!     }
+//! }
+//! ```
+//!
+//! ## Objects
+//! Each `object` names a resource and the **ceiling** of rights any
+//! capability to it may carry:
+//! - `Memory(frame)`, `Device(id)` — existing resources, by id.
+//! - `Endpoint` — an IPC endpoint, created during `instantiate`.
+//!
+//! The name `self` is implicit: a Thread capability on the holding process.
+//!
+//! ## Compile-time checks
+//! `validate` runs in a `const` item, so a violation fails the build:
+//! - **No dangling grants**: every grant names a declared object (or `self`).
+//! - **Rights monotonicity**: a grant never carries rights outside its
+//!   object's ceiling, so every boot capability is derivable from the
+//!   object's master capability.
+//! - **Unique names** for objects and processes, and no process holding
+//!   more capabilities than fit in a CSpace.
+//!
+//! ## Bootstrapping
+//! `instantiate` creates the endpoints and mints one master capability per
+//! object (its ceiling plus `GRANT`). `BootImage::cspace_for` then derives a
+//! process's capabilities from the masters with `Capability::derive`, the
+//! same path as any later delegation. A declared process is a profile: it
+//! may be instantiated any number of times (e.g. once per service started
+//! from the initrd manifest). The masters never leave the `BootImage`.
+
+use alloc::vec::Vec;
+use crate::capability::{CSpace, Capability, CapabilityType, Permissions, CSPACE_SIZE};
+use crate::ipc::{IpcError, IPC_MANAGER};
+
+/// Name by which a grant refers to the holder's own Thread.
+pub const SELF: &str = "self";
+
+/// The resource behind a declared object.
+#[derive(Debug, Clone, Copy)]
+pub enum ObjectKind {
+    /// A memory frame (or, for frame 0, all of memory).
+    Memory(u64),
+    /// A device, by its `capability::DEVICE_*` id.
+    Device(u64),
+    /// An IPC endpoint created at boot.
+    Endpoint,
+}
+
+/// A kernel object and the most rights any capability to it may carry.
+#[derive(Debug, Clone, Copy)]
+pub struct ObjectDecl {
+    pub name: &'static str,
+    pub kind: ObjectKind,
+    pub ceiling: Permissions,
+}
+
+/// One capability a process starts with.
+#[derive(Debug, Clone, Copy)]
+pub struct GrantDecl {
+    /// An `ObjectDecl` name, or `SELF`.
+    pub object: &'static str,
+    pub rights: Permissions,
+}
+
+/// A boot process and its initial capabilities.
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessDecl {
+    pub name: &'static str,
+    pub grants: &'static [GrantDecl],
+}
+
+/// The complete initial system.
+#[derive(Debug, Clone, Copy)]
+pub struct SystemDecl {
+    pub objects: &'static [ObjectDecl],
+    pub processes: &'static [ProcessDecl],
+}
+
+/// Combine a list of rights; used by `system!`.
+pub const fn rights(list: &[Permissions]) -> Permissions {
+    let mut acc = Permissions::NONE;
+    let mut i = 0;
+    while i < list.len() {
+        acc = acc.union(list[i]);
+        i += 1;
+    }
+    acc
+}
+
+const fn str_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+impl SystemDecl {
+    /// Check the declaration for consistency. Panics on the first
+    /// violation; called from a `const` item by `system!`, so the panic is a
+    /// compile error.
+    pub const fn validate(&self) {
+        let mut i = 0;
+        while i < self.objects.len() {
+            let object = &self.objects[i];
+            if str_eq(object.name, SELF) {
+                panic!("system!: 'self' is reserved and cannot name an object");
+            }
+            let mut j = i + 1;
+            while j < self.objects.len() {
+                if str_eq(object.name, self.objects[j].name) {
+                    panic!("system!: duplicate object name");
+                }
+                j += 1;
+            }
+            i += 1;
+        }
+
+        let mut p = 0;
+        while p < self.processes.len() {
+            let process = &self.processes[p];
+            let mut q = p + 1;
+            while q < self.processes.len() {
+                if str_eq(process.name, self.processes[q].name) {
+                    panic!("system!: duplicate process name");
+                }
+                q += 1;
+            }
+            if process.grants.len() > CSPACE_SIZE {
+                panic!("system!: process holds more capabilities than fit in a CSpace");
+            }
+
+            let mut g = 0;
+            while g < process.grants.len() {
+                let grant = &process.grants[g];
+                if grant.rights.bits() == 0 {
+                    panic!("system!: grant with no rights");
+                }
+                if !str_eq(grant.object, SELF) {
+                    let ceiling = match self.object(grant.object) {
+                        Some(object) => object.ceiling,
+                        None => panic!("system!: grant refers to an undeclared object"),
+                    };
+                    if !ceiling.contains(grant.rights) {
+                        panic!("system!: grant exceeds the object's rights ceiling");
+                    }
+                }
+                g += 1;
+            }
+            p += 1;
+        }
+    }
+
+    /// Look up an object by name.
+    pub const fn object(&self, name: &str) -> Option<&ObjectDecl> {
+        let mut i = 0;
+        while i < self.objects.len() {
+            if str_eq(self.objects[i].name, name) {
+                return Some(&self.objects[i]);
+            }
+            i += 1;
+        }
+        None
+    }
+}
+
+/// Declare a `SystemDecl` constant and validate it at compile time.
+///
+/// Rights are listed by their `Permissions` constant names (`READ`,
+/// `GRANT`, `ALL`, ...). See the module documentation for the grammar.
+#[macro_export]
+macro_rules! system {
+    (
+        $(#[$attr:meta])*
+        $vis:vis const $name:ident {
+            $( object $obj:ident = $kind:ident $( ( $res:expr ) )? : [ $( $ceiling:ident ),* $(,)? ] ; )*
+            $( process $proc:ident { $( $target:ident : [ $( $right:ident ),* $(,)? ] ),* $(,)? } )*
+        }
+    ) => {
+        $(#[$attr])*
+        $vis const $name: $crate::composition::SystemDecl = $crate::composition::SystemDecl {
+            objects: &[ $(
+                $crate::composition::ObjectDecl {
+                    name: stringify!($obj),
+                    kind: $crate::composition::ObjectKind::$kind $( ($res) )?,
+                    ceiling: $crate::composition::rights(&[ $( $crate::capability::Permissions::$ceiling ),* ]),
+                },
+            )* ],
+            processes: &[ $(
+                $crate::composition::ProcessDecl {
+                    name: stringify!($proc),
+                    grants: &[ $(
+                        $crate::composition::GrantDecl {
+                            object: stringify!($target),
+                            rights: $crate::composition::rights(&[ $( $crate::capability::Permissions::$right ),* ]),
+                        },
+                    )* ],
+                },
+            )* ],
+        };
+        const _: () = $name.validate();
+    };
+}
+
+// ─── Bootstrapping ───────────────────────────────────────────────────────────
+
+/// Errors from `instantiate`. Only runtime resource exhaustion is possible;
+/// everything else was ruled out by `validate`.
+#[derive(Debug)]
+pub enum CompositionError {
+    /// Creating a declared endpoint failed.
+    Ipc(IpcError),
+}
+
+/// The live system built from a `SystemDecl`.
+pub struct BootImage {
+    system: &'static SystemDecl,
+    endpoints: Vec<(&'static str, usize)>,
+    masters: Vec<(&'static str, Capability)>,
+}
+
+impl BootImage {
+    /// Build a fresh CSpace holding the capabilities declared for `process`,
+    /// or `None` if no such process is declared.
+    pub fn cspace_for(&self, process: &str) -> Option<CSpace> {
+        let decl = self.system.processes.iter().find(|p| p.name == process)?;
+        let mut cspace = CSpace::new();
+        for grant in decl.grants {
+            let cap = if grant.object == SELF {
+                Capability::new(CapabilityType::Thread, grant.rights, 0)
+            } else {
+                self.masters
+                    .iter()
+                    .find(|(name, _)| *name == grant.object)
+                    .and_then(|(_, master)| master.derive(grant.rights))
+                    .expect("grant validated at compile time")
+            };
+            // `validate` bounds the grant count by CSPACE_SIZE.
+            cspace.insert(cap).expect("grant count validated at compile time");
+        }
+        Some(cspace)
+    }
+
+    /// IPC slot of the endpoint declared as `object`.
+    pub fn endpoint(&self, object: &str) -> Option<usize> {
+        self.endpoints.iter().find(|(name, _)| *name == object).map(|&(_, slot)| slot)
+    }
+}
+
+/// Create the declared objects and their master capabilities.
+pub fn instantiate(system: &'static SystemDecl) -> Result<BootImage, CompositionError> {
+    let mut endpoints = Vec::new();
+    let mut masters: Vec<(&'static str, Capability)> = Vec::with_capacity(system.objects.len());
+    for object in system.objects {
+        let ceiling = object.ceiling.union(Permissions::GRANT);
+        let master = match object.kind {
+            ObjectKind::Memory(frame) => Capability::new(CapabilityType::Memory, ceiling, frame),
+            ObjectKind::Device(id) => Capability::new(CapabilityType::Device, ceiling, id),
+            ObjectKind::Endpoint => {
+                let mut ipc = IPC_MANAGER.lock();
+                let slot = ipc.create_endpoint(0).map_err(CompositionError::Ipc)?;
+                endpoints.push((object.name, slot));
+                ipc.endpoint_capability(slot, ceiling).map_err(CompositionError::Ipc)?
+            }
+        };
+        masters.push((object.name, master));
+    }
+    Ok(BootImage { system, endpoints, masters })
+}
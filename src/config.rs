@@ -0,0 +1,121 @@
+//! # Kernel Configuration
+//!
+//! A registry of named integer tunables. Subsystems `register` their keys
+//! with a default, a valid range and an optional apply hook; the shell's
+//! `config` command and kernel code read and change them through `get` /
+//! `set`.
+//!
+//! ## Live application
+//! A key with a hook takes effect as soon as it is set: `set` stores the
+//! new value and then calls the hook (outside the registry lock, so hooks may
+//! read other keys). A key without a hook is read by its subsystem when it
+//! next needs it — e.g. a limit checked on every admission decision.
+//! Keys marked read-only report a value fixed at build time.
+//!
+//! Keys are dotted lowercase paths grouped by subsystem (`net.tcp.*`).
+//! Values are not persisted; every boot starts from the defaults.
+
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// Called with the new value after a key changes.
+pub type ApplyHook = fn(u64);
+
+/// Why a configuration change was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// No key with that name is registered.
+    UnknownKey,
+    /// The value is outside the key's `min..=max` range.
+    OutOfRange { min: u64, max: u64 },
+    /// The key reflects a build-time constant and cannot be changed.
+    ReadOnly,
+}
+
+/// Description and current value of one key.
+#[derive(Debug, Clone, Copy)]
+pub struct Tunable {
+    pub key: &'static str,
+    pub help: &'static str,
+    pub value: u64,
+    pub default: u64,
+    pub min: u64,
+    pub max: u64,
+    pub read_only: bool,
+    /// Whether changes take effect immediately (an apply hook is registered).
+    pub live: bool,
+}
+
+struct Entry {
+    tunable: Tunable,
+    hook: Option<ApplyHook>,
+}
+
+lazy_static! {
+    static ref REGISTRY: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+}
+
+/// Register a tunable. Registering an existing key again keeps its current
+/// value, so subsystems may re-register after a restart.
+pub fn register(key: &'static str, help: &'static str, default: u64, min: u64, max: u64, hook: Option<ApplyHook>) {
+    let mut registry = REGISTRY.lock();
+    if registry.iter().any(|e| e.tunable.key == key) {
+        return;
+    }
+    registry.push(Entry {
+        tunable: Tunable { key, help, value: default, default, min, max, read_only: false, live: hook.is_some() },
+        hook,
+    });
+}
+
+/// Register a read-only key reporting a build-time constant.
+pub fn register_fixed(key: &'static str, help: &'static str, value: u64) {
+    let mut registry = REGISTRY.lock();
+    if registry.iter().any(|e| e.tunable.key == key) {
+        return;
+    }
+    registry.push(Entry {
+        tunable: Tunable { key, help, value, default: value, min: value, max: value, read_only: true, live: false },
+        hook: None,
+    });
+}
+
+/// Current value of `key`, if registered.
+pub fn get(key: &str) -> Option<u64> {
+    REGISTRY.lock().iter().find(|e| e.tunable.key == key).map(|e| e.tunable.value)
+}
+
+/// Current value of `key`, or `fallback` if it isn't registered (yet).
+pub fn get_or(key: &str, fallback: u64) -> u64 {
+    get(key).unwrap_or(fallback)
+}
+
+/// Validate and store a new value, then apply it if the key is live.
+pub fn set(key: &str, value: u64) -> Result<(), ConfigError> {
+    let hook = {
+        let mut registry = REGISTRY.lock();
+        let entry = registry
+            .iter_mut()
+            .find(|e| e.tunable.key == key)
+            .ok_or(ConfigError::UnknownKey)?;
+        let t = &mut entry.tunable;
+        if t.read_only {
+            return Err(ConfigError::ReadOnly);
+        }
+        if value < t.min || value > t.max {
+            return Err(ConfigError::OutOfRange { min: t.min, max: t.max });
+        }
+        t.value = value;
+        entry.hook
+    };
+    if let Some(hook) = hook {
+        hook(value);
+    }
+    Ok(())
+}
+
+/// Every registered key, in registration order.
+pub fn list() -> Vec<Tunable> {
+    REGISTRY.lock().iter().map(|e| e.tunable).collect()
+}
@@ -0,0 +1,153 @@
+//! # WASM Process Debugger
+//!
+//! Shell commands for looking inside running WASM processes without adding
+//! `print_char` calls to the guest and recompiling it:
+//!
+//! | Command                        | Does                                       |
+//! |--------------------------------|--------------------------------------------|
+//! | `inspect <pid>`                | Status, fuel, memory use and capabilities  |
+//! | `pause <pid>` / `resume <pid>` | Stop it between slices / let it run again  |
+//! | `output <pid>`                 | The last `OUTPUT_LOG_LEN` bytes it printed |
+//! | `mem <pid> <addr> [len]`       | Hex dump of its linear memory              |
+//!
+//! `ps` lists the processes. Memory and output can be read while the
+//! process runs; pause it first for a consistent view across commands.
+
+use alloc::string::String;
+use crate::process::{self, Pid, ProcessStatus};
+use crate::wasm_runtime::OUTPUT_LOG_LEN;
+use crate::{serial_println, shell};
+
+/// Bytes `mem` dumps when no length is given.
+const DEFAULT_DUMP_LEN: usize = 64;
+/// Most bytes one `mem` command dumps.
+const MAX_DUMP_LEN: usize = 4096;
+/// Bytes per hex dump line.
+const DUMP_WIDTH: usize = 16;
+
+/// Register the debugger's shell commands. Call after `shell::init`.
+pub fn init() {
+    shell::register("inspect", "inspect <pid> — status, fuel, memory use and capabilities", cmd_inspect);
+    shell::register("pause", "pause <pid> — stop a process before its next slice", cmd_pause);
+    shell::register("resume", "resume <pid> — let a paused process run again", cmd_resume);
+    shell::register("output", "output <pid> — recent output of a process", cmd_output);
+    shell::register("mem", "mem <pid> <addr> [len] — hex dump of a process's linear memory", cmd_mem);
+}
+
+fn cmd_inspect(args: &[&str]) {
+    let Some(pid) = pid_arg(args, "inspect <pid>") else { return };
+    let Some(info) = process::get(pid) else {
+        serial_println!("No process {}.", pid);
+        return;
+    };
+    serial_println!("PID {} '{}' (parent {})", info.pid, info.name, info.parent);
+    match info.status {
+        ProcessStatus::Exited(code) => { serial_println!("  status:  exited({})", code); }
+        status => { serial_println!("  status:  {:?}", status); }
+    }
+    match info.fuel_budget {
+        Some(budget) => { serial_println!("  fuel:    {} of {}", info.fuel_used, budget); }
+        None => { serial_println!("  fuel:    {} (no budget)", info.fuel_used); }
+    }
+    match process::with_instance(pid, |p| p.memory_size()) {
+        Ok(size) => { serial_println!("  memory:  {} of {} KiB", size / 1024, info.memory_limit / 1024); }
+        Err(_) => { serial_println!("  memory:  released (limit {} KiB)", info.memory_limit / 1024); }
+    }
+    let cspace = info.cspace.lock();
+    serial_println!("  capabilities: {}", cspace.len());
+    for (slot, cap) in cspace.iter() {
+        serial_println!(
+            "    [{:>2}] {:?} {:?} resource {}",
+            slot, cap.cap_type, cap.permissions, cap.resource_id
+        );
+    }
+}
+
+fn cmd_pause(args: &[&str]) {
+    let Some(pid) = pid_arg(args, "pause <pid>") else { return };
+    match process::pause(pid) {
+        Ok(()) => { serial_println!("PID {} will pause before its next slice.", pid); }
+        Err(e) => { serial_println!("Cannot pause: {:?}", e); }
+    }
+}
+
+fn cmd_resume(args: &[&str]) {
+    let Some(pid) = pid_arg(args, "resume <pid>") else { return };
+    match process::resume(pid) {
+        Ok(()) => { serial_println!("PID {} resumed.", pid); }
+        Err(e) => { serial_println!("Cannot resume: {:?}", e); }
+    }
+}
+
+fn cmd_output(args: &[&str]) {
+    let Some(pid) = pid_arg(args, "output <pid>") else { return };
+    match process::output(pid) {
+        Ok(text) => {
+            serial_println!("── last {} bytes of PID {} output ──", OUTPUT_LOG_LEN, pid);
+            serial_println!("{}", text);
+        }
+        Err(e) => { serial_println!("Cannot read output: {:?}", e); }
+    }
+}
+
+fn cmd_mem(args: &[&str]) {
+    const USAGE: &str = "mem <pid> <addr> [len]";
+    let (pid, addr, len) = match args {
+        [pid, addr] => (pid.parse::<Pid>().ok(), parse_number(addr), Some(DEFAULT_DUMP_LEN)),
+        [pid, addr, len] => (pid.parse::<Pid>().ok(), parse_number(addr), parse_number(len)),
+        _ => (None, None, None),
+    };
+    let (Some(pid), Some(addr), Some(len)) = (pid, addr, len) else {
+        serial_println!("Usage: {}", USAGE);
+        return;
+    };
+    let len = len.min(MAX_DUMP_LEN);
+    let dumped = process::with_instance(pid, |p| {
+        p.with_memory(addr, len, |bytes| {
+            for (i, line) in bytes.chunks(DUMP_WIDTH).enumerate() {
+                serial_println!("{}", dump_line(addr + i * DUMP_WIDTH, line));
+            }
+        })
+    });
+    match dumped {
+        Ok(Some(())) => {}
+        Ok(None) => { serial_println!("{:#x}..{:#x} is outside PID {}'s memory.", addr, addr + len, pid); }
+        Err(e) => { serial_println!("Cannot read memory: {:?}", e); }
+    }
+}
+
+fn pid_arg(args: &[&str], usage: &str) -> Option<Pid> {
+    let pid = match args {
+        [pid] => pid.parse::<Pid>().ok(),
+        _ => None,
+    };
+    if pid.is_none() {
+        serial_println!("Usage: {}", usage);
+    }
+    pid
+}
+
+/// A decimal or `0x`-prefixed hexadecimal number.
+fn parse_number(text: &str) -> Option<usize> {
+    match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// `00001000  48 65 6c 6c 6f 00 ...  |Hello.|`
+fn dump_line(addr: usize, bytes: &[u8]) -> String {
+    use core::fmt::Write;
+    let mut line = String::new();
+    let _ = write!(line, "{:08x} ", addr);
+    for i in 0..DUMP_WIDTH {
+        match bytes.get(i) {
+            Some(b) => { let _ = write!(line, " {:02x}", b); }
+            None => line.push_str("   "),
+        }
+    }
+    line.push_str("  |");
+    line.extend(bytes.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }));
+    line.push('|');
+    line
+}
@@ -0,0 +1,361 @@
+//! # DNS Resolver
+//!
+//! Turns host names into addresses so the P2P layer can dial bootstrap
+//! nodes by name. A stub resolver: it asks a recursive server over UDP and
+//! trusts the answer.
+//!
+//! ## Servers
+//! Queries go to the servers set with `set_servers`, or else to the one
+//! DHCP offered (option 6). With the static fallback address there is no
+//! lease, so QEMU's built-in resolver (`FALLBACK_SERVER`) is used. Each
+//! server is tried in turn, `QUERY_TIMEOUT_MS` apiece, for `QUERY_ROUNDS`
+//! rounds.
+//!
+//! ## Cache
+//! Answers are cached in the shared address book (`addr_book`) for their
+//! TTL, clamped to `MIN_TTL_S`..`MAX_TTL_S`, so every subsystem that dials
+//! out sees them. Failures are not cached.
+//!
+//! ## Spoofing
+//! An off-path attacker who can guess a query can race the server with a
+//! forged answer and poison the cache. Each query therefore has a random
+//! ID and goes out from its own UDP socket on a random ephemeral port
+//! (sockets are pooled, so this costs no heap). An answer is accepted only
+//! if it comes from the server asked, to that port, with that ID, and
+//! echoes the question exactly.
+//!
+//! Lookups run one at a time; concurrent callers wait their turn, and then
+//! usually find the answer already cached.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
+use spin::Mutex;
+use crate::addr_book::ADDRESS_BOOK;
+use crate::capability::Capability;
+use crate::interrupts::uptime_ms;
+use crate::net_stack::{self, AddressSource, EPHEMERAL_PORT_FIRST};
+use crate::p2p::yield_now;
+use crate::p2p_transport::Deadline;
+
+/// QEMU user networking's resolver, used on the static fallback address.
+const FALLBACK_SERVER: Ipv4Address = Ipv4Address::new(10, 0, 2, 3);
+/// How long to wait for one server to answer.
+const QUERY_TIMEOUT_MS: u64 = 2_000;
+/// Passes over the server list before giving up.
+const QUERY_ROUNDS: usize = 2;
+/// Shortest time an answer is cached, whatever its TTL.
+const MIN_TTL_S: u32 = 5;
+/// Longest time an answer is cached, whatever its TTL.
+const MAX_TTL_S: u32 = 3_600;
+/// Longest name accepted, in presentation form.
+const MAX_NAME_LEN: usize = 253;
+/// Largest response read; servers truncate UDP answers to 512 bytes.
+const MAX_MESSAGE_LEN: usize = 512;
+
+const DNS_PORT: u16 = 53;
+const CLASS_IN: u16 = 1;
+const RCODE_NXDOMAIN: u16 = 3;
+
+/// Errors from lookups.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsError {
+    /// The name is empty, too long or has an empty or over-long label.
+    InvalidName,
+    /// No server is configured and there is no DHCP lease.
+    NoServers,
+    /// The name doesn't exist, or has no records of the requested type.
+    NotFound,
+    /// A server answered with an error other than "no such name".
+    ServerFailure,
+    /// No server answered in time.
+    Timeout,
+    /// The resolver socket couldn't be opened or used.
+    Network,
+}
+
+/// Record types the resolver asks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordType {
+    /// IPv4 address.
+    A,
+    /// IPv6 address.
+    Aaaa,
+}
+
+impl RecordType {
+    fn code(self) -> u16 {
+        match self {
+            RecordType::A => 1,
+            RecordType::Aaaa => 28,
+        }
+    }
+}
+
+/// A resolved address. The network stack is IPv4-only, so IPv6 addresses
+/// are returned as raw bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Address {
+    V4(Ipv4Address),
+    V6([u8; 16]),
+}
+
+lazy_static! {
+    static ref SERVERS: Mutex<Vec<Ipv4Address>> = Mutex::new(Vec::new());
+}
+
+/// Held while a query is in flight.
+static BUSY: AtomicBool = AtomicBool::new(false);
+
+/// Clears `BUSY` when dropped, even if the lookup is abandoned mid-query.
+struct BusyGuard;
+
+impl BusyGuard {
+    async fn acquire() -> BusyGuard {
+        while BUSY.swap(true, Ordering::Acquire) {
+            yield_now().await;
+        }
+        BusyGuard
+    }
+}
+
+impl Drop for BusyGuard {
+    fn drop(&mut self) {
+        BUSY.store(false, Ordering::Release);
+    }
+}
+
+/// A query's own socket, closed when dropped.
+struct QuerySocket(Capability);
+
+impl Drop for QuerySocket {
+    fn drop(&mut self) {
+        net_stack::udp_close(&self.0);
+    }
+}
+
+/// Use `servers` instead of the DHCP-provided server. An empty list goes
+/// back to DHCP.
+pub fn set_servers(servers: &[Ipv4Address]) {
+    *SERVERS.lock() = servers.to_vec();
+}
+
+/// The servers lookups currently go to, in order.
+pub fn servers() -> Vec<Ipv4Address> {
+    let configured = SERVERS.lock().clone();
+    if !configured.is_empty() {
+        return configured;
+    }
+    match net_stack::address_config() {
+        Some(config) => match (config.dns, config.source) {
+            (Some(dns), _) => alloc::vec![dns],
+            (None, AddressSource::Static) => alloc::vec![FALLBACK_SERVER],
+            (None, _) => Vec::new(),
+        },
+        None => Vec::new(),
+    }
+}
+
+/// The first IPv4 address of `name`. A dotted-quad literal is returned
+/// as is, without a query.
+pub async fn resolve(name: &str) -> Result<IpAddress, DnsError> {
+    if let Some(literal) = parse_ipv4(name) {
+        return Ok(IpAddress::Ipv4(literal));
+    }
+    let addresses = lookup(name, RecordType::A).await?;
+    addresses
+        .iter()
+        .find_map(|a| match a {
+            Address::V4(v4) => Some(IpAddress::Ipv4(*v4)),
+            Address::V6(_) => None,
+        })
+        .ok_or(DnsError::NotFound)
+}
+
+/// Every `record_type` address of `name`, from the cache or a server.
+pub async fn lookup(name: &str, record_type: RecordType) -> Result<Vec<Address>, DnsError> {
+    let name = normalize(name)?;
+    if let Some(addresses) = cached(&name, record_type) {
+        return Ok(addresses);
+    }
+
+    let busy = BusyGuard::acquire().await;
+    // Another caller may have filled the cache while we waited.
+    if let Some(addresses) = cached(&name, record_type) {
+        return Ok(addresses);
+    }
+    let result = query_servers(&name, record_type).await;
+    drop(busy);
+
+    let (addresses, ttl) = result?;
+    let ttl_ms = u64::from(ttl.clamp(MIN_TTL_S, MAX_TTL_S)) * 1000;
+    ADDRESS_BOOK.lock().insert_name(&name, record_type, &addresses, ttl_ms, uptime_ms());
+    Ok(addresses)
+}
+
+// ─── Cache ───────────────────────────────────────────────────────────────────
+
+fn cached(name: &str, record_type: RecordType) -> Option<Vec<Address>> {
+    ADDRESS_BOOK.lock().lookup_name(name, record_type, uptime_ms()).map(<[Address]>::to_vec)
+}
+
+// ─── Queries ─────────────────────────────────────────────────────────────────
+
+/// Ask each server in turn. Returns the addresses and the smallest TTL.
+async fn query_servers(name: &str, record_type: RecordType) -> Result<(Vec<Address>, u32), DnsError> {
+    let servers = servers();
+    if servers.is_empty() {
+        return Err(DnsError::NoServers);
+    }
+    let mut error = DnsError::Timeout;
+    for _ in 0..QUERY_ROUNDS {
+        for &server in &servers {
+            match query(server, name, record_type).await {
+                // A definite answer from any server settles it.
+                Ok(answer) => return Ok(answer),
+                Err(DnsError::NotFound) => return Err(DnsError::NotFound),
+                Err(e) => error = e,
+            }
+        }
+    }
+    Err(error)
+}
+
+async fn query(server: Ipv4Address, name: &str, record_type: RecordType) -> Result<(Vec<Address>, u32), DnsError> {
+    let id = random_u16();
+    let request = encode_query(id, name, record_type);
+    let port = EPHEMERAL_PORT_FIRST + random_u16() % (u16::MAX - EPHEMERAL_PORT_FIRST + 1);
+    let socket = QuerySocket(net_stack::udp_bind(port).map_err(|_| DnsError::Network)?);
+    let server = IpEndpoint::new(IpAddress::Ipv4(server), DNS_PORT);
+    let deadline = Some(uptime_ms() + QUERY_TIMEOUT_MS);
+    match (Deadline { inner: net_stack::udp_send_to(&socket.0, &request, server), deadline }).await {
+        Some(Ok(())) => {}
+        Some(Err(_)) => return Err(DnsError::Network),
+        None => return Err(DnsError::Timeout),
+    }
+
+    let mut buf = [0u8; MAX_MESSAGE_LEN];
+    loop {
+        match (Deadline { inner: net_stack::udp_recv_from(&socket.0, &mut buf), deadline }).await {
+            Some(Ok((received, from))) if from == server => {
+                if let Some(answer) = decode_response(&buf[..received], &request, record_type) {
+                    return answer;
+                }
+            }
+            Some(Ok(_)) => {}
+            Some(Err(_)) => return Err(DnsError::Network),
+            None => return Err(DnsError::Timeout),
+        }
+    }
+}
+
+fn random_u16() -> u16 {
+    let mut bytes = [0u8; 2];
+    crate::random::fill(&mut bytes);
+    u16::from_le_bytes(bytes)
+}
+
+// ─── Wire Format (RFC 1035) ──────────────────────────────────────────────────
+
+fn encode_query(id: u16, name: &str, record_type: RecordType) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(18 + name.len());
+    msg.extend_from_slice(&id.to_be_bytes());
+    msg.extend_from_slice(&0x0100u16.to_be_bytes()); // standard query, recursion desired
+    msg.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]); // one question
+    for label in name.split('.') {
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.push(0);
+    msg.extend_from_slice(&record_type.code().to_be_bytes());
+    msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+    msg
+}
+
+/// Decode the response to `request`, as `encode_query` built it. `None` if
+/// `msg` isn't one (wrong ID, not a response, a different question, or
+/// malformed); otherwise the answer or the server's error. CNAMEs are
+/// followed implicitly: every record of the requested type in the answer
+/// section counts.
+fn decode_response(msg: &[u8], request: &[u8], record_type: RecordType) -> Option<Result<(Vec<Address>, u32), DnsError>> {
+    let word = |at: usize| msg.get(at..at + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+    let flags = word(2)?;
+    if msg.get(..2)? != request.get(..2)? || flags & 0x8000 == 0 {
+        return None;
+    }
+    // The question must come back as asked; names compare case-blind.
+    let question = request.get(12..)?;
+    if word(4)? != 1 || !msg.get(12..12 + question.len())?.eq_ignore_ascii_case(question) {
+        return None;
+    }
+    match flags & 0x000F {
+        0 => {}
+        RCODE_NXDOMAIN => return Some(Err(DnsError::NotFound)),
+        _ => return Some(Err(DnsError::ServerFailure)),
+    }
+    let answers = word(6)?;
+
+    let mut at = 12 + question.len();
+    let mut addresses = Vec::new();
+    let mut ttl = u32::MAX;
+    for _ in 0..answers {
+        at = skip_name(msg, at)?;
+        let rtype = word(at)?;
+        let record_ttl = u32::from_be_bytes(msg.get(at + 4..at + 8)?.try_into().ok()?);
+        let len = usize::from(word(at + 8)?);
+        let data = msg.get(at + 10..at + 10 + len)?;
+        at += 10 + len;
+        if rtype != record_type.code() {
+            continue;
+        }
+        let address = match record_type {
+            RecordType::A => Address::V4(Ipv4Address::from_bytes(data.get(..4)?)),
+            RecordType::Aaaa => Address::V6(data.try_into().ok()?),
+        };
+        addresses.push(address);
+        ttl = ttl.min(record_ttl);
+    }
+    if addresses.is_empty() {
+        return Some(Err(DnsError::NotFound));
+    }
+    Some(Ok((addresses, ttl)))
+}
+
+/// Offset just past the (possibly compressed) name starting at `at`.
+fn skip_name(msg: &[u8], mut at: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(at)?;
+        match len {
+            0 => return Some(at + 1),
+            // A compression pointer ends the name.
+            l if l & 0xC0 == 0xC0 => return Some(at + 2),
+            l => at += 1 + usize::from(l),
+        }
+    }
+}
+
+// ─── Names ───────────────────────────────────────────────────────────────────
+
+/// Lower-case `name` and drop a trailing dot, checking label lengths.
+fn normalize(name: &str) -> Result<String, DnsError> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.is_empty() || name.len() > MAX_NAME_LEN || name.split('.').any(|l| l.is_empty() || l.len() > 63) {
+        return Err(DnsError::InvalidName);
+    }
+    Ok(name.to_ascii_lowercase())
+}
+
+/// A dotted-quad IPv4 address.
+pub fn parse_ipv4(text: &str) -> Option<Ipv4Address> {
+    let mut octets = [0u8; 4];
+    let mut parts = text.split('.');
+    for octet in octets.iter_mut() {
+        *octet = parts.next()?.parse().ok()?;
+    }
+    match parts.next() {
+        None => Some(Ipv4Address(octets)),
+        Some(_) => None,
+    }
+}
@@ -5,64 +5,451 @@ use core::future::Future;
 use core::pin::Pin;
 use core::task::{Context, Poll, Waker, RawWaker, RawWakerVTable};
 use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::capability::{Capability, CapabilityType, Permissions, SharedCSpace};
+
+/// Number of live tasks across the executor, readable without taking the
+/// executor lock (so code running *inside* a task can query it).
+static TASK_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn task_count() -> usize {
+    TASK_COUNT.load(Ordering::Relaxed)
+}
+
+/// Who a task is working for, carried with the task and inherited by every
+/// task it spawns.
+///
+/// While a task is being polled its context is the *current* context:
+/// code anywhere below it can call `current_context()` (or the narrower
+/// accessors) to attribute log lines, trace work across tasks, or check
+/// capabilities against the principal that started it.
+#[derive(Clone)]
+pub struct TaskContext {
+    /// Shared by a task and all its descendants.
+    pub trace_id: u64,
+    /// Owning process, or 0 for kernel work.
+    pub owner: u64,
+    /// Capabilities of the principal this work runs on behalf of, if any.
+    pub cspace: Option<SharedCSpace>,
+}
+
+static NEXT_TRACE_ID: AtomicU64 = AtomicU64::new(1);
+
+impl TaskContext {
+    /// A kernel-owned context starting a new trace.
+    pub fn kernel() -> Self {
+        TaskContext {
+            trace_id: NEXT_TRACE_ID.fetch_add(1, Ordering::Relaxed),
+            owner: 0,
+            cspace: None,
+        }
+    }
+
+    /// A new trace owned by `owner`, acting with `cspace`.
+    pub fn for_process(owner: u64, cspace: Option<SharedCSpace>) -> Self {
+        TaskContext { owner, cspace, ..Self::kernel() }
+    }
+}
+
+/// Context of the task currently being polled; `None` outside any task.
+static CURRENT: Mutex<Option<TaskContext>> = Mutex::new(None);
+
+/// Context of the running task, or `None` when called outside the executor.
+pub fn current_context() -> Option<TaskContext> {
+    CURRENT.lock().clone()
+}
+
+/// Trace ID of the running task (0 outside the executor); `klog!` tags
+/// its lines with it.
+pub fn current_trace_id() -> u64 {
+    CURRENT.lock().as_ref().map_or(0, |c| c.trace_id)
+}
+
+/// Owning process of the running task (0 for kernel work or outside the executor).
+pub fn current_owner() -> u64 {
+    CURRENT.lock().as_ref().map_or(0, |c| c.owner)
+}
 
 pub struct Task {
     future: Pin<Box<dyn Future<Output = ()> + Send + 'static>>,
+    context: TaskContext,
 }
 
 impl Task {
+    /// Wrap a future. Inside a running task the new task inherits that
+    /// task's context; elsewhere it gets a fresh kernel context.
     pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Task {
+        Task::with_context(future, current_context().unwrap_or_else(TaskContext::kernel))
+    }
+
+    /// Wrap a future with an explicit context (e.g. when starting work on
+    /// behalf of a new process).
+    pub fn with_context(future: impl Future<Output = ()> + Send + 'static, context: TaskContext) -> Task {
         Task {
             future: Box::pin(future),
+            context,
         }
     }
 
+    /// Poll with this task's context installed as the current one.
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
-        self.future.as_mut().poll(context)
+        let outer = CURRENT.lock().replace(self.context.clone());
+        let result = self.future.as_mut().poll(context);
+        *CURRENT.lock() = outer;
+        result
+    }
+}
+
+/// Tasks spawned from inside other tasks, picked up at the end of the
+/// current executor pass (tasks must not lock `EXECUTOR`).
+static PENDING_SPAWNS: Mutex<Vec<Task>> = Mutex::new(Vec::new());
+
+/// Queue a task from any context, including inside a running task. It
+/// starts on the next executor pass.
+pub fn submit(task: Task) {
+    TASK_COUNT.fetch_add(1, Ordering::Relaxed);
+    PENDING_SPAWNS.lock().push(task);
+}
+
+// ─── Timers ──────────────────────────────────────────────────────────────────
+
+/// A future that completes once `interrupts::uptime_ms()` reaches a deadline.
+///
+/// The executor polls every queued task on each pass, so this needs no
+/// timer queue: it just compares against the tick-driven clock, with 10 ms
+/// resolution (one timer tick).
+pub struct Sleep {
+    deadline_ms: u64,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if crate::interrupts::uptime_ms() >= self.deadline_ms {
+            Poll::Ready(())
+        } else {
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+/// Sleep until uptime reaches `deadline_ms`.
+pub fn sleep_until(deadline_ms: u64) -> Sleep {
+    Sleep { deadline_ms }
+}
+
+/// Sleep for at least `ms` milliseconds.
+pub fn sleep_ms(ms: u64) -> Sleep {
+    sleep_until(crate::interrupts::uptime_ms().saturating_add(ms))
+}
+
+/// A CPU reservation in the style of seL4 MCS scheduling contexts:
+/// a task bound to it may run for `budget_cycles` out of every
+/// `period_cycles` (TSC cycles), ahead of best-effort tasks.
+///
+/// Scheduling is cooperative, so this is a bound on waiting, not a hard
+/// share: nothing preempts a poll. A reserved task with budget left is
+/// polled before every best-effort poll, so it waits at most one of those
+/// (a WASM process yields after each fuel slice, kernel tasks at their next
+/// `await`). Its own polls are charged after they return; one that runs
+/// past the budget overdraws it and the task sits out the rest of the
+/// period.
+///
+/// `kernel_main` creates one for each service whose manifest line asks for
+/// a reservation and puts its capability in the service's CSpace; a process
+/// whose CSpace holds one with EXECUTE runs under it (see `process`).
+#[derive(Debug, Clone, Copy)]
+pub struct SchedContext {
+    pub budget_cycles: u64,
+    pub period_cycles: u64,
+}
+
+impl SchedContext {
+    /// A reservation of `budget_us` out of every `period_us` microseconds,
+    /// converted with `tsc_per_ms`.
+    pub fn from_micros(budget_us: u64, period_us: u64) -> Self {
+        let per_ms = tsc_per_ms();
+        SchedContext {
+            budget_cycles: budget_us.saturating_mul(per_ms) / 1000,
+            period_cycles: period_us.saturating_mul(per_ms) / 1000,
+        }
+    }
+
+    /// Share of the CPU this reservation claims, in permille.
+    fn utilization_permille(&self) -> u64 {
+        self.budget_cycles.saturating_mul(1000) / self.period_cycles.max(1)
+    }
+}
+
+/// Total CPU share that may be reserved, in permille. The rest is kept for
+/// best-effort work (network polling, WASM processes).
+const MAX_RESERVED_PERMILLE: u64 = 800;
+
+/// Errors from creating or binding scheduling contexts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedError {
+    /// The reservation would push total reserved CPU past `MAX_RESERVED_PERMILLE`.
+    Overcommitted,
+    /// Budget is zero or larger than the period.
+    InvalidReservation,
+    /// The capability is not a scheduling-context capability with EXECUTE,
+    /// or refers to a context that doesn't exist.
+    InvalidCapability,
+    /// Another task is already bound to this scheduling context.
+    AlreadyBound,
+}
+
+/// A task running under a reservation, with its replenishment state.
+struct ReservedTask {
+    task: Task,
+    context_id: u64,
+    /// Budget left in the current period.
+    remaining: u64,
+    /// TSC value at which the current period started.
+    period_start: u64,
+}
+
+struct SchedContextEntry {
+    id: u64,
+    context: SchedContext,
+    bound: bool,
+}
+
+struct SchedContexts {
+    entries: Vec<SchedContextEntry>,
+    next_id: u64,
+}
+
+/// Every scheduling context, outside `Executor` so tasks can bind one
+/// while spawning (they must not lock `EXECUTOR`).
+static SCHED_CONTEXTS: Mutex<SchedContexts> = Mutex::new(SchedContexts { entries: Vec::new(), next_id: 1 });
+
+/// Reserved tasks bound from inside other tasks, picked up with
+/// `PENDING_SPAWNS`.
+static PENDING_RESERVED: Mutex<Vec<ReservedTask>> = Mutex::new(Vec::new());
+
+/// Create a scheduling context and return a capability to it.
+///
+/// Fails if the reservation is malformed or would overcommit the CPU;
+/// a guarantee that can't be honored is worse than no guarantee.
+pub fn create_sched_context(context: SchedContext) -> Result<Capability, SchedError> {
+    if context.budget_cycles == 0 || context.budget_cycles > context.period_cycles {
+        return Err(SchedError::InvalidReservation);
+    }
+    let mut contexts = SCHED_CONTEXTS.lock();
+    let reserved: u64 = contexts.entries.iter().map(|e| e.context.utilization_permille()).sum();
+    if reserved + context.utilization_permille() > MAX_RESERVED_PERMILLE {
+        return Err(SchedError::Overcommitted);
+    }
+
+    let id = contexts.next_id;
+    contexts.next_id += 1;
+    contexts.entries.push(SchedContextEntry { id, context, bound: false });
+    Ok(Capability::new(CapabilityType::SchedContext, Permissions::all(), id))
+}
+
+/// A scheduling context claimed for one task, from `bind`. Hand it to
+/// `submit_reserved`; dropping it unused releases the context.
+pub struct Binding {
+    id: u64,
+}
+
+impl Drop for Binding {
+    fn drop(&mut self) {
+        release_sched_context(self.id);
+    }
+}
+
+/// Claim the scheduling context named by `cap` (which needs EXECUTE) for
+/// one task.
+pub fn bind(cap: &Capability) -> Result<Binding, SchedError> {
+    if cap.cap_type != CapabilityType::SchedContext || !cap.permissions.contains(Permissions::EXECUTE) {
+        return Err(SchedError::InvalidCapability);
+    }
+    let mut contexts = SCHED_CONTEXTS.lock();
+    let entry = contexts
+        .entries
+        .iter_mut()
+        .find(|e| e.id == cap.resource_id)
+        .ok_or(SchedError::InvalidCapability)?;
+    if entry.bound {
+        return Err(SchedError::AlreadyBound);
+    }
+    entry.bound = true;
+    Ok(Binding { id: entry.id })
+}
+
+/// Queue a task under the reservation it was bound to, from any context.
+/// Like `submit`, it starts on the next executor pass.
+pub fn submit_reserved(task: Task, binding: Binding) {
+    let context_id = binding.id;
+    core::mem::forget(binding);
+    let budget = SCHED_CONTEXTS
+        .lock()
+        .entries
+        .iter()
+        .find(|e| e.id == context_id)
+        .map_or(0, |e| e.context.budget_cycles);
+    TASK_COUNT.fetch_add(1, Ordering::Relaxed);
+    PENDING_RESERVED.lock().push(ReservedTask { task, context_id, remaining: budget, period_start: read_tsc() });
+}
+
+/// Let another task be bound to context `id`.
+fn release_sched_context(id: u64) {
+    if let Some(entry) = SCHED_CONTEXTS.lock().entries.iter_mut().find(|e| e.id == id) {
+        entry.bound = false;
     }
 }
 
 pub struct Executor {
     task_queue: VecDeque<Task>,
+    reserved: Vec<ReservedTask>,
 }
 
 impl Executor {
     pub fn new() -> Executor {
         Executor {
             task_queue: VecDeque::new(),
+            reserved: Vec::new(),
         }
     }
 
     pub fn spawn(&mut self, task: Task) {
+        TASK_COUNT.fetch_add(1, Ordering::Relaxed);
         self.task_queue.push_back(task)
     }
 
+    /// Poll every reserved task that still has budget in its period.
+    fn run_reserved_tasks(&mut self, context: &mut Context) {
+        let mut i = 0;
+        while i < self.reserved.len() {
+            let sched = {
+                let contexts = SCHED_CONTEXTS.lock();
+                match contexts.entries.iter().find(|e| e.id == self.reserved[i].context_id) {
+                    Some(entry) => entry.context,
+                    None => {
+                        i += 1;
+                        continue;
+                    }
+                }
+            };
+
+            let now = read_tsc();
+            let reserved = &mut self.reserved[i];
+            if now.wrapping_sub(reserved.period_start) >= sched.period_cycles {
+                reserved.period_start = now;
+                reserved.remaining = sched.budget_cycles;
+            }
+            if reserved.remaining == 0 {
+                i += 1;
+                continue;
+            }
+
+            let result = reserved.task.poll(context);
+            let spent = read_tsc().wrapping_sub(now);
+            reserved.remaining = reserved.remaining.saturating_sub(spent);
+
+            if result.is_ready() {
+                let done = self.reserved.swap_remove(i);
+                // Release the context so another task can be bound to it.
+                release_sched_context(done.context_id);
+                TASK_COUNT.fetch_sub(1, Ordering::Relaxed);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
     pub fn run_ready_tasks(&mut self) {
         let mut tasks_to_run = self.task_queue.len();
         let waker = dummy_waker();
         let mut context = Context::from_waker(&waker);
+
+        self.run_reserved_tasks(&mut context);
         
         while tasks_to_run > 0 {
             if let Some(mut task) = self.task_queue.pop_front() {
                 match task.poll(&mut context) {
                     Poll::Ready(()) => {
                         // task done
+                        TASK_COUNT.fetch_sub(1, Ordering::Relaxed);
                     }
                     Poll::Pending => {
                         self.task_queue.push_back(task);
                     }
                 }
+                // Reserved tasks wait at most one best-effort poll.
+                self.run_reserved_tasks(&mut context);
             }
             tasks_to_run -= 1;
         }
+
+        let spawned = core::mem::take(&mut *PENDING_SPAWNS.lock());
+        self.task_queue.extend(spawned);
+        let reserved = core::mem::take(&mut *PENDING_RESERVED.lock());
+        self.reserved.extend(reserved);
     }
     
+    /// Drop every task, running their destructors. Used at shutdown.
+    /// Returns how many were cancelled.
+    pub fn cancel_all(&mut self) -> usize {
+        let pending = core::mem::take(&mut *PENDING_SPAWNS.lock());
+        let pending_reserved = core::mem::take(&mut *PENDING_RESERVED.lock());
+        let n = self.task_queue.len() + self.reserved.len() + pending.len() + pending_reserved.len();
+        self.task_queue.clear();
+        self.reserved.clear();
+        drop(pending);
+        drop(pending_reserved);
+        for entry in &mut SCHED_CONTEXTS.lock().entries {
+            entry.bound = false;
+        }
+        TASK_COUNT.fetch_sub(n, Ordering::Relaxed);
+        n
+    }
+
     // Run one check pass
     pub fn poll(&mut self) {
         self.run_ready_tasks();
     }
 }
 
+/// Read the CPU timestamp counter, the time base for CPU reservations.
+fn read_tsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// TSC cycles per millisecond, measured on first use by spinning over
+/// `CALIBRATION_MS` of `uptime_ms` (so only as accurate as the timer).
+/// Needs the timer interrupt running.
+pub fn tsc_per_ms() -> u64 {
+    static CACHED: AtomicU64 = AtomicU64::new(0);
+    let cached = CACHED.load(Ordering::Relaxed);
+    if cached != 0 {
+        return cached;
+    }
+    // Start on a tick edge so the whole interval is measured.
+    let edge = crate::interrupts::uptime_ms();
+    while crate::interrupts::uptime_ms() == edge {
+        core::hint::spin_loop();
+    }
+    let start_ms = crate::interrupts::uptime_ms();
+    let start = read_tsc();
+    while crate::interrupts::uptime_ms() < start_ms + CALIBRATION_MS {
+        core::hint::spin_loop();
+    }
+    let elapsed_ms = crate::interrupts::uptime_ms() - start_ms;
+    let per_ms = (read_tsc().wrapping_sub(start) / elapsed_ms).max(1);
+    CACHED.store(per_ms, Ordering::Relaxed);
+    per_ms
+}
+
+/// How long `tsc_per_ms` measures for.
+const CALIBRATION_MS: u64 = 50;
+
 fn dummy_waker() -> Waker {
     static VTABLE: RawWakerVTable = RawWakerVTable::new(
         |_| RawWaker::new(core::ptr::null(), &VTABLE),
@@ -0,0 +1,234 @@
+//! # Ingress Firewall
+//!
+//! Rules checked against every IPv4 packet a NIC receives, in the driver
+//! before smoltcp sees it, so a denied packet never reaches a socket or
+//! the ICMP echo responder. Non-IPv4 frames (ARP) always pass.
+//!
+//! ## Rules
+//! A rule matches by protocol, destination (local) port range and source
+//! CIDR, and either allows or denies. The first matching rule decides;
+//! packets no rule matches get the `net.firewall.default` verdict (allow
+//! unless set to 0). A rule with a port range never matches ICMP, nor an
+//! IP fragment other than the first, since those carry no ports. Rules
+//! keep a hit count.
+//!
+//! With a deny-by-default policy, remember to allow DHCP replies (UDP
+//! port 68) or the interface never gets an address.
+//!
+//! ## Authority
+//! Processes change the rules through `env.firewall_*`, which requires a
+//! Device capability on `DEVICE_NET_ADMIN` with WRITE. The rules are
+//! readable by anyone in `/proc/firewall`. The console's `firewall`
+//! command acts with the kernel's authority.
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use smoltcp::wire::{
+    EthernetFrame, EthernetProtocol, IpProtocol, Ipv4Address, Ipv4Cidr, Ipv4Packet, TcpPacket, UdpPacket,
+};
+use spin::Mutex;
+use crate::config;
+
+/// Most rules the table holds.
+pub const MAX_RULES: usize = 64;
+
+/// What a matching rule does with a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+/// The transport a rule applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Any,
+    Tcp,
+    Udp,
+    Icmp,
+}
+
+/// One filter rule.
+#[derive(Debug, Clone, Copy)]
+pub struct Rule {
+    pub action: Action,
+    pub protocol: Protocol,
+    /// Inclusive range of destination ports; `None` for any packet.
+    pub ports: Option<(u16, u16)>,
+    /// Where the packet comes from; `0.0.0.0/0` for anywhere.
+    pub source: Ipv4Cidr,
+}
+
+/// Why a rule change was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirewallError {
+    /// The table already holds `MAX_RULES` rules.
+    TableFull,
+    /// No rule at that position.
+    NotFound,
+    /// The port range is empty.
+    InvalidRule,
+}
+
+/// The fields of a packet the rules look at.
+struct PacketInfo {
+    protocol: IpProtocol,
+    source: Ipv4Address,
+    /// Destination port; `None` for ICMP and non-first fragments.
+    port: Option<u16>,
+}
+
+/// `deny tcp 22 from 10.0.0.0/8`, `allow any * from 0.0.0.0/0`.
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let action = match self.action {
+            Action::Allow => "allow",
+            Action::Deny => "deny",
+        };
+        let protocol = match self.protocol {
+            Protocol::Any => "any",
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+            Protocol::Icmp => "icmp",
+        };
+        write!(f, "{} {} ", action, protocol)?;
+        match self.ports {
+            None => write!(f, "*")?,
+            Some((first, last)) if first == last => write!(f, "{}", first)?,
+            Some((first, last)) => write!(f, "{}-{}", first, last)?,
+        }
+        write!(f, " from {}", self.source)
+    }
+}
+
+impl Rule {
+    fn matches(&self, packet: &PacketInfo) -> bool {
+        let protocol = match self.protocol {
+            Protocol::Any => true,
+            Protocol::Tcp => packet.protocol == IpProtocol::Tcp,
+            Protocol::Udp => packet.protocol == IpProtocol::Udp,
+            Protocol::Icmp => packet.protocol == IpProtocol::Icmp,
+        };
+        let port = match (self.ports, packet.port) {
+            (None, _) => true,
+            (Some((first, last)), Some(port)) => (first..=last).contains(&port),
+            (Some(_), None) => false,
+        };
+        protocol && port && self.source.contains_addr(&packet.source)
+    }
+}
+
+lazy_static! {
+    /// Rules in evaluation order, with their hit counts.
+    static ref RULES: Mutex<Vec<(Rule, u64)>> = Mutex::new(Vec::new());
+}
+
+/// Whether no rules are installed, so `admits` can skip parsing.
+static EMPTY: AtomicBool = AtomicBool::new(true);
+static DEFAULT_ALLOW: AtomicBool = AtomicBool::new(true);
+static ALLOWED: AtomicU64 = AtomicU64::new(0);
+static DENIED: AtomicU64 = AtomicU64::new(0);
+
+/// Register `net.firewall.default`.
+pub fn init() {
+    config::register("net.firewall.default", "Verdict for packets no firewall rule matches (1 = allow, 0 = deny)", 1, 0, 1, Some(apply));
+}
+
+fn apply(value: u64) {
+    DEFAULT_ALLOW.store(value != 0, Ordering::Relaxed);
+}
+
+// ─── Filtering ───────────────────────────────────────────────────────────────
+
+/// Whether a received Ethernet frame may go on to the stack.
+pub fn admits(frame: &[u8]) -> bool {
+    let default = DEFAULT_ALLOW.load(Ordering::Relaxed);
+    if EMPTY.load(Ordering::Relaxed) && default {
+        return true;
+    }
+    let verdict = match parse(frame) {
+        Some(packet) => {
+            let mut rules = RULES.lock();
+            match rules.iter_mut().find(|(rule, _)| rule.matches(&packet)) {
+                Some((rule, hits)) => {
+                    *hits += 1;
+                    rule.action == Action::Allow
+                }
+                None => default,
+            }
+        }
+        // Not IPv4, or too short for smoltcp to accept either.
+        None => true,
+    };
+    let counter = if verdict { &ALLOWED } else { &DENIED };
+    counter.fetch_add(1, Ordering::Relaxed);
+    verdict
+}
+
+fn parse(frame: &[u8]) -> Option<PacketInfo> {
+    let frame = EthernetFrame::new_checked(frame).ok()?;
+    if frame.ethertype() != EthernetProtocol::Ipv4 {
+        return None;
+    }
+    let ip = Ipv4Packet::new_checked(frame.payload()).ok()?;
+    let protocol = ip.next_header();
+    let port = if ip.frag_offset() != 0 {
+        None
+    } else {
+        match protocol {
+            IpProtocol::Tcp => TcpPacket::new_checked(ip.payload()).ok().map(|tcp| tcp.dst_port()),
+            IpProtocol::Udp => UdpPacket::new_checked(ip.payload()).ok().map(|udp| udp.dst_port()),
+            _ => None,
+        }
+    };
+    Some(PacketInfo { protocol, source: ip.src_addr(), port })
+}
+
+// ─── Rule Table ──────────────────────────────────────────────────────────────
+
+/// Insert `rule` at `position` (appended if past the end). Returns where
+/// it went.
+pub fn insert(position: usize, rule: Rule) -> Result<usize, FirewallError> {
+    if let Some((first, last)) = rule.ports {
+        if first > last {
+            return Err(FirewallError::InvalidRule);
+        }
+    }
+    let mut rules = RULES.lock();
+    if rules.len() >= MAX_RULES {
+        return Err(FirewallError::TableFull);
+    }
+    let position = position.min(rules.len());
+    rules.insert(position, (rule, 0));
+    EMPTY.store(false, Ordering::Relaxed);
+    Ok(position)
+}
+
+/// Remove the rule at `position`.
+pub fn remove(position: usize) -> Result<Rule, FirewallError> {
+    let mut rules = RULES.lock();
+    if position >= rules.len() {
+        return Err(FirewallError::NotFound);
+    }
+    let (rule, _) = rules.remove(position);
+    EMPTY.store(rules.is_empty(), Ordering::Relaxed);
+    Ok(rule)
+}
+
+/// Remove every rule.
+pub fn clear() {
+    RULES.lock().clear();
+    EMPTY.store(true, Ordering::Relaxed);
+}
+
+/// The rules in evaluation order, with how many packets each decided.
+pub fn rules() -> Vec<(Rule, u64)> {
+    RULES.lock().clone()
+}
+
+/// Packets checked against the rules: (allowed, denied).
+pub fn counters() -> (u64, u64) {
+    (ALLOWED.load(Ordering::Relaxed), DENIED.load(Ordering::Relaxed))
+}
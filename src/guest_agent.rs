@@ -0,0 +1,316 @@
+//! # Guest Agent
+//!
+//! A control channel for host orchestration tools, speaking a subset of the
+//! QEMU guest agent (QGA) protocol so the same tooling can drive this kernel
+//! and ordinary Linux guests.
+//!
+//! ## Transport
+//! The agent runs over virtio-serial (`/dev/hvc0`, see `virtio_console`)
+//! when the machine has it:
+//! `-chardev socket,path=/tmp/qga.sock,server=on,wait=off,id=qga0 -device virtio-serial-pci -device virtconsole,chardev=qga0`.
+//! Only port 0 is driven, so it must be a `virtconsole`, not the named
+//! `virtserialport` libvirt sets up for QGA; host tools just connect to the
+//! chardev socket either way. vsock isn't supported.
+//!
+//! Without virtio-serial it falls back to COM2 (`/dev/ttyS1`):
+//! `-chardev socket,path=/tmp/qga.sock,server=on,wait=off,id=qga0 -serial null -serial chardev:qga0`
+//! (the first `-serial` is COM1, the console).
+//!
+//! ## Framing
+//! Requests are JSON objects, one per line:
+//! `{"execute": "<command>", "arguments": {...}}`. Replies are
+//! `{"return": ...}` or `{"error": {"class": ..., "desc": ...}}`, also one
+//! per line. A `0xFF` byte discards any partial request, as in QGA, so a
+//! client can resynchronize after `guest-sync-delimited`.
+//!
+//! ## Commands
+//! | Command | Arguments | Returns |
+//! |:---|:---|:---|
+//! | `guest-sync`, `guest-sync-delimited` | `id` | `id` (delimited: preceded by `0xFF`) |
+//! | `guest-ping` | — | `{}` |
+//! | `guest-info` | — | version and supported commands |
+//! | `guest-shutdown` | — | nothing; runs the orderly shutdown sequence |
+//! | `guest-exec-wasm` | `path`, optional `entry`, `arg`, `env` | `pid` |
+//! | `guest-exec-status` | `pid` | `exited`; once it has, `exitcode` and base64 `out-data` |
+//! | `guest-file-push` | `path`, `buf-b64` | bytes written |
+//!
+//! `guest-exec-wasm` and `guest-file-push` are extensions (QGA's own
+//! `guest-exec` / `guest-file-*` assume processes and file handles this
+//! kernel doesn't have). As with `guest-exec`, `guest-exec-wasm` starts the
+//! module as an ordinary process (`process::spawn`) and replies with its
+//! PID at once; the host polls `guest-exec-status`, which reaps the process
+//! once it reports it exited. Only processes the agent started can be
+//! polled. Modules run with the same minimal capabilities
+//! as the boot demo: a Thread capability on themselves, the clock and the RNG.
+//! As in `guest-exec`, `arg` is an array of argument strings and `env` an
+//! array of `KEY=VALUE` strings; modules read them with WASI `args_get` /
+//! `environ_get`.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::capability::{self, CSpace, Capability, CapabilityType, Permissions};
+use crate::chardev::OpenDevice;
+use crate::json::{self, Value};
+use crate::process::{Pid, ProcessError, ProcessStatus};
+use crate::vfs::{ReadContext, VFS};
+use crate::wasm_runtime::WasmError;
+use crate::{p2p, process, serial_println, shutdown, wasm_runtime};
+
+/// Devices the agent listens on, in order of preference.
+const AGENT_DEVICES: [&str; 2] = ["/dev/hvc0", "/dev/ttyS1"];
+/// Longest request line accepted; longer ones are dropped with an error.
+/// Sized for file pushes of a few tens of KiB after base64 expansion.
+const MAX_REQUEST: usize = 64 * 1024;
+/// Bytes read from the device per poll.
+const READ_CHUNK: usize = 256;
+/// QGA's resynchronization byte.
+const SYNC_BYTE: u8 = 0xFF;
+
+const COMMANDS: [&str; 8] = [
+    "guest-sync",
+    "guest-sync-delimited",
+    "guest-ping",
+    "guest-info",
+    "guest-shutdown",
+    "guest-exec-wasm",
+    "guest-exec-status",
+    "guest-file-push",
+];
+
+/// Processes started by `guest-exec-wasm` and not yet reaped.
+static EXECUTED: Mutex<Vec<Pid>> = Mutex::new(Vec::new());
+
+/// Run the agent. Spawn once on the executor; never returns unless no
+/// device can be opened.
+pub async fn agent_task() {
+    let rights = Permissions::READ.union(Permissions::WRITE);
+    let opened = AGENT_DEVICES
+        .iter()
+        .find_map(|path| VFS.lock().open(path, rights, &ReadContext::KERNEL).ok().map(|device| (*path, device)));
+    let Some((path, device)) = opened else {
+        serial_println!("[AGENT] Cannot open any of {:?}.", AGENT_DEVICES);
+        return;
+    };
+    serial_println!("[AGENT] Guest agent listening on {}.", path);
+
+    let mut line: Vec<u8> = Vec::new();
+    let mut overflowed = false;
+    let mut chunk = [0u8; READ_CHUNK];
+    loop {
+        let n = device.read(&mut chunk).unwrap_or(0);
+        if n == 0 {
+            p2p::yield_now().await;
+            continue;
+        }
+        for &byte in &chunk[..n] {
+            match byte {
+                SYNC_BYTE => {
+                    line.clear();
+                    overflowed = false;
+                }
+                b'\n' => {
+                    if overflowed {
+                        reply(&device, &error_reply("request too long"));
+                    } else if !line.is_empty() {
+                        handle_line(&device, &line);
+                    }
+                    line.clear();
+                    overflowed = false;
+                }
+                _ if overflowed => {}
+                _ if line.len() >= MAX_REQUEST => {
+                    overflowed = true;
+                    line.clear();
+                }
+                _ => line.push(byte),
+            }
+        }
+    }
+}
+
+fn reply(device: &OpenDevice, text: &str) {
+    let _ = device.write(text.as_bytes());
+    let _ = device.write(b"\n");
+}
+
+fn error_reply(desc: &str) -> String {
+    format!("{{\"error\":{{\"class\":\"GenericError\",\"desc\":\"{}\"}}}}", json::escape(desc))
+}
+
+/// Parse and execute one request line, writing the reply (if any).
+fn handle_line(device: &OpenDevice, line: &[u8]) {
+    let request = match core::str::from_utf8(line).ok().and_then(|s| json::parse(s).ok()) {
+        Some(request) => request,
+        None => return reply(device, &error_reply("malformed JSON")),
+    };
+    let command = match request.get("execute").and_then(Value::as_str) {
+        Some(command) => command,
+        None => return reply(device, &error_reply("missing 'execute'")),
+    };
+    let args = request.get("arguments");
+    let arg_str = |key: &str| args.and_then(|a| a.get(key)).and_then(Value::as_str);
+
+    let result = match command {
+        "guest-sync" | "guest-sync-delimited" => {
+            match args.and_then(|a| a.get("id")).and_then(Value::as_u64) {
+                Some(id) => {
+                    if command == "guest-sync-delimited" {
+                        let _ = device.write(&[SYNC_BYTE]);
+                    }
+                    Ok(format!("{}", id))
+                }
+                None => Err(String::from("'id' must be an integer")),
+            }
+        }
+        "guest-ping" => Ok(String::from("{}")),
+        "guest-info" => Ok(info()),
+        "guest-shutdown" => {
+            // Like QGA, no reply on success: the connection just goes away.
+            serial_println!("[AGENT] Shutdown requested by host.");
+            shutdown::request();
+            return;
+        }
+        "guest-exec-wasm" => match arg_str("path") {
+            Some(path) => {
+                let options = wasm_runtime::ProcessOptions {
+                    args: string_list(args.and_then(|a| a.get("arg"))),
+                    env: string_list(args.and_then(|a| a.get("env"))),
+                    ..Default::default()
+                };
+                exec_wasm(path, arg_str("entry").unwrap_or("main"), options)
+            }
+            None => Err(String::from("missing 'path'")),
+        },
+        "guest-exec-status" => match args.and_then(|a| a.get("pid")).and_then(Value::as_u64) {
+            Some(pid) => exec_status(pid),
+            None => Err(String::from("'pid' must be an integer")),
+        },
+        "guest-file-push" => match (arg_str("path"), arg_str("buf-b64")) {
+            (Some(path), Some(data)) => file_push(path, data),
+            _ => Err(String::from("missing 'path' or 'buf-b64'")),
+        },
+        _ => Err(format!("unknown command '{}'", command)),
+    };
+
+    match result {
+        Ok(value) => reply(device, &format!("{{\"return\":{}}}", value)),
+        Err(desc) => reply(device, &error_reply(&desc)),
+    }
+}
+
+// ─── Commands ────────────────────────────────────────────────────────────────
+
+fn info() -> String {
+    let commands: Vec<String> = COMMANDS
+        .iter()
+        .map(|name| format!("{{\"name\":\"{}\",\"enabled\":true,\"success-response\":{}}}", name, *name != "guest-shutdown"))
+        .collect();
+    format!(
+        "{{\"version\":\"{}\",\"supported_commands\":[{}]}}",
+        env!("CARGO_PKG_VERSION"),
+        commands.join(",")
+    )
+}
+
+/// The strings in an optional JSON array; other elements are skipped.
+fn string_list(value: Option<&Value>) -> Vec<String> {
+    value
+        .and_then(Value::as_array)
+        .map(|items| items.iter().filter_map(Value::as_str).map(String::from).collect())
+        .unwrap_or_default()
+}
+
+fn exec_wasm(path: &str, entry: &str, options: wasm_runtime::ProcessOptions) -> Result<String, String> {
+    let bytes = VFS
+        .lock()
+        .read(path, &ReadContext::KERNEL)
+        .map_err(|e| format!("cannot read {}: {:?}", path, e))?;
+
+    let mut cspace = CSpace::new();
+    let caps = [
+        Capability::new(CapabilityType::Thread, Permissions::READ, 0),
+        Capability::new(CapabilityType::Device, Permissions::READ, capability::DEVICE_CLOCK),
+        Capability::new(CapabilityType::Device, Permissions::READ, capability::DEVICE_RNG),
+    ];
+    for cap in caps {
+        cspace.insert(cap).ok_or_else(|| String::from("capability space full"))?;
+    }
+
+    let pid = match process::spawn(path, &bytes, entry, cspace, options) {
+        Ok(pid) => pid,
+        Err(ProcessError::Load(WasmError::InsufficientResources(e))) => return Err(format!("{}", e)),
+        Err(e) => return Err(format!("{:?}", e)),
+    };
+    EXECUTED.lock().push(pid);
+    serial_println!("[AGENT] Running {} as PID {}.", path, pid);
+    Ok(format!("{{\"pid\":{}}}", pid))
+}
+
+/// Report on a process `exec_wasm` started, reaping it if it has exited.
+fn exec_status(pid: Pid) -> Result<String, String> {
+    let mut executed = EXECUTED.lock();
+    let index = executed
+        .iter()
+        .position(|&p| p == pid)
+        .ok_or_else(|| format!("PID {} was not started by guest-exec-wasm", pid))?;
+    let info = process::get(pid).ok_or_else(|| format!("PID {}: no such process", pid))?;
+    let ProcessStatus::Exited(exitcode) = info.status else {
+        return Ok(String::from("{\"exited\":false}"));
+    };
+    let output = process::output(pid).unwrap_or_default();
+    let _ = process::reap(pid);
+    executed.swap_remove(index);
+    Ok(format!(
+        "{{\"exited\":true,\"exitcode\":{},\"out-data\":\"{}\"}}",
+        exitcode,
+        base64_encode(output.as_bytes())
+    ))
+}
+
+fn file_push(path: &str, data: &str) -> Result<String, String> {
+    let bytes = base64_decode(data).ok_or_else(|| String::from("invalid base64 in 'buf-b64'"))?;
+    VFS.lock()
+        .write(path, &bytes)
+        .map_err(|e| format!("cannot write {}: {:?}", path, e))?;
+    serial_println!("[AGENT] Pushed {} bytes to {}.", bytes.len(), path);
+    Ok(format!("{{\"count\":{}}}", bytes.len()))
+}
+
+// ─── Base64 ──────────────────────────────────────────────────────────────────
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for group in data.chunks(3) {
+        let b = [group[0], *group.get(1).unwrap_or(&0), *group.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= group.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i)) as usize & 63] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim_end_matches('=').as_bytes();
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for &c in text {
+        let v = BASE64_ALPHABET.iter().position(|&a| a == c)? as u32;
+        acc = acc << 6 | v;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
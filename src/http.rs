@@ -0,0 +1,516 @@
+//! # HTTP Server
+//!
+//! A small HTTP/1.1 server on `HTTP_PORT`, for kernel status pages and
+//! WASM module uploads.
+//!
+//! ## Routing
+//! Subsystems add handlers with `route`. A pattern ending in `/` matches
+//! every path under it and the handler finds the remainder in
+//! `Request::rest`; any other pattern matches one path exactly. The most
+//! specific match wins. A handler is a plain function from `Request` to
+//! `Response`, run on the connection's task, so it must not block.
+//!
+//! ## Connections
+//! The listener task takes connections the stack accepted on `HTTP_PORT`
+//! and gives each its own task. A connection serves one request and is
+//! closed (`Connection: close`); HTTP/1.1 responses are sent chunked, in
+//! `CHUNK_SIZE` pieces, HTTP/1.0 ones with a Content-Length. A request
+//! that doesn't arrive within `http.request_timeout_ms` gets 408.
+//!
+//! ## Request bodies
+//! Only routes added with `route_with_body` take a body; any other request
+//! with one gets 413. The request is routed as soon as its head is in, and
+//! the route's `BodyCheck` decides from the head and the Content-Length
+//! whether to read the body at all, so a refused upload costs nothing.
+//! Bodies need a Content-Length (chunked uploads get 411) and are capped
+//! by `http.max_body`.
+//!
+//! ## Buffers
+//! The kernel heap never frees, so request buffers go back to a spare
+//! list when a connection ends and the next connection reuses one. A
+//! buffer grows only as bytes arrive, never to a length the client merely
+//! announced.
+//!
+//! ## Built-in routes
+//! | Route | |
+//! |:---|:---|
+//! | `GET /` | The route table |
+//! | `GET /proc/` | Files under `/proc` (a listing for `/proc/`) |
+//! | `PUT /modules/<name>` | Store a WASM module as `/tmp/<name>`, if `http.allow_uploads` |
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use smoltcp::iface::SocketHandle;
+use spin::Mutex;
+use crate::admission::{self, ResourceRequest};
+use crate::executor::{self, Task};
+use crate::interrupts::uptime_ms;
+use crate::net_stack::{tcp_close, HTTP_PORT, NETWORK_STACK};
+use crate::p2p::yield_now;
+use crate::p2p_transport::{Deadline, TcpReadFuture, TcpWriteFuture};
+use crate::vfs::{ReadContext, VfsError, VFS};
+use crate::klog::Level;
+use crate::{config, klog, ramfs, serial_println, EXECUTOR};
+
+/// Longest request line plus headers accepted.
+const MAX_HEAD: usize = 8 * 1024;
+/// Body bytes per chunk of a chunked response.
+const CHUNK_SIZE: usize = 1024;
+/// Bytes read from the socket at a time.
+const READ_SIZE: usize = 1024;
+
+/// Request methods the server understands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+}
+
+impl Method {
+    fn parse(token: &str) -> Option<Self> {
+        match token {
+            "GET" => Some(Method::Get),
+            "HEAD" => Some(Method::Head),
+            "POST" => Some(Method::Post),
+            "PUT" => Some(Method::Put),
+            "DELETE" => Some(Method::Delete),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+        }
+    }
+}
+
+/// A parsed request, borrowing from the connection's buffer.
+pub struct Request<'a> {
+    pub method: Method,
+    /// The path without the query string, e.g. `/proc/meminfo`.
+    pub path: &'a str,
+    /// The part of `path` after the matched route's prefix (empty for an
+    /// exact route).
+    pub rest: &'a str,
+    pub body: &'a [u8],
+    /// Header lines, after the request line.
+    headers: &'a str,
+}
+
+impl<'a> Request<'a> {
+    /// The value of header `name` (case-insensitive), trimmed.
+    pub fn header(&self, name: &str) -> Option<&'a str> {
+        header(self.headers, name)
+    }
+}
+
+/// What a handler answers.
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// A 200 response with `body`.
+    pub fn ok(content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Response { status: 200, content_type, body: body.into() }
+    }
+
+    /// A plain-text response whose body is the status line's reason.
+    pub fn status(status: u16) -> Self {
+        Response { status, content_type: "text/plain", body: format!("{} {}\n", status, reason(status)).into_bytes() }
+    }
+}
+
+/// A request handler. Runs on the connection's task and must not block.
+pub type Handler = fn(&Request) -> Response;
+
+/// Decides from a request's head (`Request::body` is still empty) and its
+/// Content-Length whether to read the body. Fails with the status to
+/// answer instead.
+pub type BodyCheck = fn(&Request, usize) -> Result<(), u16>;
+
+struct Route {
+    method: Method,
+    pattern: &'static str,
+    handler: Handler,
+    /// Set for routes that take a body.
+    check: Option<BodyCheck>,
+}
+
+lazy_static! {
+    static ref ROUTES: Mutex<Vec<Route>> = Mutex::new(Vec::new());
+    static ref SPARE_BUFFERS: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+}
+
+/// Serve `method` requests for `pattern` with `handler`. A pattern ending
+/// in `/` covers every path under it. Replaces an earlier handler for the
+/// same method and pattern. Requests with a body are refused.
+pub fn route(method: Method, pattern: &'static str, handler: Handler) {
+    add_route(Route { method, pattern, handler, check: None });
+}
+
+/// Like `route`, for requests with a body: `check` runs once the head is
+/// in, and the body is read only if it passes.
+pub fn route_with_body(method: Method, pattern: &'static str, handler: Handler, check: BodyCheck) {
+    add_route(Route { method, pattern, handler, check: Some(check) });
+}
+
+fn add_route(route: Route) {
+    let mut routes = ROUTES.lock();
+    match routes.iter_mut().find(|r| r.method == route.method && r.pattern == route.pattern) {
+        Some(existing) => *existing = route,
+        None => routes.push(route),
+    }
+}
+
+/// Register the tunables and built-in routes and start the listener.
+pub fn init() {
+    config::register("http.max_body", "Largest request body accepted (bytes)", ramfs::MAX_FILE_SIZE as u64, 0, 16 * 1024 * 1024, None);
+    config::register("http.request_timeout_ms", "Answer 408 to a request not received within this long", 10_000, 100, 600_000, None);
+    config::register("http.allow_uploads", "Accept WASM modules on PUT /modules/<name> (1 = on)", 0, 0, 1, None);
+
+    route(Method::Get, "/", index);
+    route(Method::Get, "/proc/", proc_file);
+    route_with_body(Method::Put, "/modules/", upload_module, check_upload);
+
+    EXECUTOR.lock().spawn(Task::new(listen_task()));
+    serial_println!("[HTTP] Serving on port {}", HTTP_PORT);
+}
+
+/// Standard reason phrase for `status`.
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        413 => "Content Too Large",
+        415 => "Unsupported Media Type",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        507 => "Insufficient Storage",
+        _ => "",
+    }
+}
+
+// ─── Connections ─────────────────────────────────────────────────────────────
+
+async fn listen_task() {
+    loop {
+        let accepted = NETWORK_STACK.lock().as_mut().and_then(|stack| stack.accept(HTTP_PORT));
+        match accepted {
+            Some(handle) => executor::submit(Task::new(connection_task(handle))),
+            None => yield_now().await,
+        }
+    }
+}
+
+async fn connection_task(handle: SocketHandle) {
+    let mut buffer = SPARE_BUFFERS.lock().pop().unwrap_or_default();
+    let deadline = uptime_ms() + config::get_or("http.request_timeout_ms", 10_000);
+    let (response, head_only, chunked) = match serve(handle, &mut buffer, deadline).await {
+        Ok(answer) => answer,
+        Err(Some(status)) => (Response::status(status), false, false),
+        // The client went away; nobody to answer.
+        Err(None) => {
+            release(handle, buffer);
+            return;
+        }
+    };
+    let deadline = uptime_ms() + config::get_or("http.request_timeout_ms", 10_000);
+    if write_response(handle, &response, head_only, chunked, deadline).await.is_err() {
+        serial_println!("[HTTP] Client went away before the response was sent");
+    }
+    release(handle, buffer);
+}
+
+fn release(handle: SocketHandle, mut buffer: Vec<u8>) {
+    tcp_close(handle);
+    buffer.clear();
+    SPARE_BUFFERS.lock().push(buffer);
+}
+
+/// What routing a request head decided.
+enum Routing {
+    /// Read the body and run the handler.
+    Serve(Routed),
+    /// No route serves the request: answer with this 404 or 405, and the
+    /// method and whether the client speaks HTTP/1.1.
+    Answer(Response, Method, bool),
+}
+
+/// A routed request whose body hasn't been read yet.
+struct Routed {
+    method: Method,
+    http11: bool,
+    handler: Handler,
+    /// Length of the matched route pattern, the start of `Request::rest`.
+    prefix_len: usize,
+    body_len: usize,
+}
+
+/// Read one request into `buffer`, route it and run its handler. Returns
+/// the response, whether to leave its body out (HEAD) and whether to send
+/// it chunked (HTTP/1.1). Fails with the status to answer, or `None` if
+/// the connection closed.
+async fn serve(handle: SocketHandle, buffer: &mut Vec<u8>, deadline: u64) -> Result<(Response, bool, bool), Option<u16>> {
+    let head_len = read_head(handle, buffer, deadline).await?;
+    let routed = {
+        let head = core::str::from_utf8(&buffer[..head_len]).map_err(|_| Some(400))?;
+        match route_head(head).map_err(Some)? {
+            Routing::Serve(routed) => routed,
+            Routing::Answer(response, method, http11) => return Ok((response, method == Method::Head, http11)),
+        }
+    };
+
+    let total = head_len + routed.body_len;
+    while buffer.len() < total {
+        read_some(handle, buffer, total - buffer.len(), deadline).await?;
+    }
+
+    let head = core::str::from_utf8(&buffer[..head_len]).map_err(|_| Some(400))?;
+    let (request_line, headers) = head.split_once("\r\n").ok_or(Some(400))?;
+    let (method, path, _) = parse_request_line(request_line).map_err(Some)?;
+    let request = Request {
+        method,
+        path,
+        rest: &path[routed.prefix_len.min(path.len())..],
+        body: &buffer[head_len..total],
+        headers,
+    };
+    let response = (routed.handler)(&request);
+    klog!(klog::HTTP, Level::Info, "[HTTP] {} {} -> {}", request.method.as_str(), request.path, response.status);
+    Ok((response, routed.method == Method::Head, routed.http11))
+}
+
+/// Read a request's head into `buffer`, and perhaps the start of its body.
+/// Returns the head's length.
+async fn read_head(handle: SocketHandle, buffer: &mut Vec<u8>, deadline: u64) -> Result<usize, Option<u16>> {
+    loop {
+        if let Some(end) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            return Ok(end + 4);
+        }
+        if buffer.len() >= MAX_HEAD {
+            return Err(Some(431));
+        }
+        read_some(handle, buffer, MAX_HEAD - buffer.len(), deadline).await?;
+    }
+}
+
+/// Append up to `max` bytes from the socket to `buffer`.
+async fn read_some(handle: SocketHandle, buffer: &mut Vec<u8>, max: usize, deadline: u64) -> Result<(), Option<u16>> {
+    let mut chunk = [0u8; READ_SIZE];
+    let len = max.min(READ_SIZE);
+    match (Deadline { inner: TcpReadFuture { handle, buffer: &mut chunk[..len] }, deadline: Some(deadline) }).await {
+        Some(Ok(n)) => {
+            buffer.extend_from_slice(&chunk[..n]);
+            Ok(())
+        }
+        Some(Err(())) => Err(None),
+        None => Err(Some(408)),
+    }
+}
+
+/// Find the route for a request head and decide whether its body may be
+/// read, or fail with the status to answer.
+fn route_head(head: &str) -> Result<Routing, u16> {
+    let (request_line, headers) = head.split_once("\r\n").ok_or(400u16)?;
+    let (method, path, http11) = parse_request_line(request_line)?;
+
+    // HEAD is answered by the GET handler, without the body.
+    let lookup = if method == Method::Head { Method::Get } else { method };
+    let (handler, check, prefix_len) = {
+        let routes = ROUTES.lock();
+        let matches = |r: &&Route| {
+            r.pattern == path || (r.pattern.ends_with('/') && path.starts_with(r.pattern))
+        };
+        let best = routes.iter().filter(|r| r.method == lookup).filter(matches).max_by_key(|r| r.pattern.len());
+        match best {
+            Some(r) => (r.handler, r.check, r.pattern.len()),
+            None if routes.iter().any(|r| matches(&r)) => return Ok(Routing::Answer(Response::status(405), method, http11)),
+            None => return Ok(Routing::Answer(Response::status(404), method, http11)),
+        }
+    };
+
+    if header(headers, "Transfer-Encoding").is_some() {
+        return Err(411);
+    }
+    let body_len = match header(headers, "Content-Length") {
+        Some(len) => len.parse::<usize>().map_err(|_| 400u16)?,
+        None => 0,
+    };
+    match check {
+        None if body_len > 0 => return Err(413),
+        None => {}
+        Some(check) => {
+            if body_len as u64 > config::get_or("http.max_body", ramfs::MAX_FILE_SIZE as u64) {
+                return Err(413);
+            }
+            let rest = &path[prefix_len.min(path.len())..];
+            check(&Request { method, path, rest, body: &[], headers }, body_len)?;
+        }
+    }
+    Ok(Routing::Serve(Routed { method, http11, handler, prefix_len, body_len }))
+}
+
+/// Split a request line into its method, its path without the query
+/// string and whether the client speaks HTTP/1.1, or fail with the status
+/// to answer.
+fn parse_request_line(line: &str) -> Result<(Method, &str, bool), u16> {
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(400);
+    };
+    let http11 = match version {
+        "HTTP/1.1" => true,
+        "HTTP/1.0" => false,
+        _ => return Err(505),
+    };
+    let method = Method::parse(method).ok_or(501u16)?;
+    let path = target.split_once('?').map_or(target, |(path, _)| path);
+    if !path.starts_with('/') || path.split('/').any(|segment| segment == "..") {
+        return Err(400);
+    }
+    Ok((method, path, http11))
+}
+
+/// The value of header `name` among the `headers` lines.
+fn header<'a>(headers: &'a str, name: &str) -> Option<&'a str> {
+    headers.split("\r\n").find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+async fn write_response(
+    handle: SocketHandle,
+    response: &Response,
+    head_only: bool,
+    chunked: bool,
+    deadline: u64,
+) -> Result<(), ()> {
+    let framing = if chunked {
+        String::from("Transfer-Encoding: chunked")
+    } else {
+        format!("Content-Length: {}", response.body.len())
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\n{}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        framing
+    );
+    write_all(handle, head.as_bytes(), deadline).await?;
+    if head_only {
+        return Ok(());
+    }
+    if !chunked {
+        return write_all(handle, &response.body, deadline).await;
+    }
+    for chunk in response.body.chunks(CHUNK_SIZE) {
+        write_all(handle, format!("{:x}\r\n", chunk.len()).as_bytes(), deadline).await?;
+        write_all(handle, chunk, deadline).await?;
+        write_all(handle, b"\r\n", deadline).await?;
+    }
+    write_all(handle, b"0\r\n\r\n", deadline).await
+}
+
+async fn write_all(handle: SocketHandle, mut data: &[u8], deadline: u64) -> Result<(), ()> {
+    while !data.is_empty() {
+        match (Deadline { inner: TcpWriteFuture { handle, data }, deadline: Some(deadline) }).await {
+            Some(Ok(n)) => data = &data[n..],
+            Some(Err(())) | None => return Err(()),
+        }
+    }
+    Ok(())
+}
+
+// ─── Built-in Routes ─────────────────────────────────────────────────────────
+
+fn index(_request: &Request) -> Response {
+    let mut body = String::from("Routes:\n");
+    for route in ROUTES.lock().iter() {
+        body.push_str(&format!("  {:<6} {}\n", route.method.as_str(), route.pattern));
+    }
+    Response::ok("text/plain", body)
+}
+
+fn proc_file(request: &Request) -> Response {
+    if request.rest.is_empty() {
+        return match VFS.lock().list("/proc") {
+            Ok(names) => {
+                let mut body = String::new();
+                for name in names {
+                    body.push_str(&name);
+                    body.push('\n');
+                }
+                Response::ok("text/plain", body)
+            }
+            Err(_) => Response::status(404),
+        };
+    }
+    match VFS.lock().read(request.path, &ReadContext::KERNEL) {
+        Ok(data) => Response::ok("text/plain", data),
+        Err(VfsError::NotFound) => Response::status(404),
+        Err(_) => Response::status(403),
+    }
+}
+
+/// Store the body as `/tmp/<name>`, where spawn requests can find it.
+/// Everything about an upload that can be judged before its body is read.
+fn check_upload(request: &Request, body_len: usize) -> Result<(), u16> {
+    if config::get_or("http.allow_uploads", 0) == 0 {
+        return Err(403);
+    }
+    let name = request.rest;
+    let valid_name = !name.is_empty()
+        && name.len() <= 64
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'));
+    if !valid_name {
+        return Err(400);
+    }
+    let content_type = request.header("Content-Type").unwrap_or("application/wasm");
+    if !matches!(content_type, "application/wasm" | "application/octet-stream") {
+        return Err(415);
+    }
+    // The body is held twice: in the request buffer and in the stored file.
+    let heap_bytes = body_len.saturating_mul(2);
+    admission::admit(&ResourceRequest { heap_bytes, frames: 0, sockets: 0 }).map_err(|_| 503u16)
+}
+
+fn upload_module(request: &Request) -> Response {
+    let name = request.rest;
+    if !request.body.starts_with(b"\0asm") {
+        return Response::status(415);
+    }
+    match VFS.lock().write(&format!("/tmp/{}", name), request.body) {
+        Ok(()) => {
+            serial_println!("[HTTP] Stored module /tmp/{} ({} bytes)", name, request.body.len());
+            Response { status: 201, content_type: "text/plain", body: format!("/tmp/{}\n", name).into_bytes() }
+        }
+        Err(VfsError::NoSpace) => Response::status(507),
+        Err(_) => Response::status(500),
+    }
+}
@@ -0,0 +1,149 @@
+//! # ICMP Echo (Ping)
+//!
+//! The quickest connectivity check under QEMU/SLIRP: can the node reach a
+//! host, and can a host reach the node?
+//!
+//! ## Responder
+//! smoltcp's interface answers echo requests to its own address itself, so
+//! the node replies to pings as soon as it has an address (see
+//! `net_stack`'s address configuration).
+//!
+//! ## Client
+//! `NetworkStack` owns one ICMP socket bound to `PING_IDENT`. `send` emits
+//! an echo request and returns its sequence number; the stack's poll loop
+//! matches replies to outstanding requests (`collect_replies`) and `result`
+//! reports the round-trip time. `ping` wraps both for kernel tasks, and
+//! `env.ping_send` / `env.ping_result` expose them to WASM guests.
+//!
+//! Round-trip times have the timer's 10 ms resolution.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU16, Ordering};
+use lazy_static::lazy_static;
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::socket::icmp;
+use smoltcp::time::Duration;
+use smoltcp::wire::{IpAddress, Icmpv4Packet, Icmpv4Repr, Ipv4Address};
+use spin::Mutex;
+use crate::interrupts::uptime_ms;
+use crate::net_stack::NETWORK_STACK;
+use crate::p2p::yield_now;
+
+/// ICMP identifier of the kernel's echo requests.
+pub const PING_IDENT: u16 = 0x4b52;
+/// Requests awaiting a reply or collection at once.
+const MAX_OUTSTANDING: usize = 16;
+/// Payload carried by each echo request.
+const PING_PAYLOAD: &[u8] = b"kernel-ping";
+/// Results are kept this long after their deadline for `result` to collect.
+const RESULT_LINGER_MS: u64 = 10_000;
+
+/// Errors from ping operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PingError {
+    /// The network stack is down or the socket's buffer is full.
+    NoNetwork,
+    /// `MAX_OUTSTANDING` requests are already in flight.
+    TooManyOutstanding,
+    /// No reply arrived before the deadline.
+    TimedOut,
+    /// No outstanding request has this sequence number.
+    NotFound,
+}
+
+struct Outstanding {
+    seq: u16,
+    target: Ipv4Address,
+    sent_ms: u64,
+    deadline_ms: u64,
+    rtt_ms: Option<u64>,
+}
+
+lazy_static! {
+    static ref OUTSTANDING: Mutex<Vec<Outstanding>> = Mutex::new(Vec::new());
+}
+
+static NEXT_SEQ: AtomicU16 = AtomicU16::new(1);
+
+/// The stack's ICMP socket, bound to `PING_IDENT`.
+pub fn socket() -> icmp::Socket<'static> {
+    let buffer = || icmp::PacketBuffer::new(vec![icmp::PacketMetadata::EMPTY; 4], vec![0; 512]);
+    let mut socket = icmp::Socket::new(buffer(), buffer());
+    socket.bind(icmp::Endpoint::Ident(PING_IDENT)).expect("Failed to bind ICMP socket");
+    socket
+}
+
+/// Send an echo request to `target` and return its sequence number. A
+/// reply counts if it arrives within `timeout_ms`.
+pub fn send(target: Ipv4Address, timeout_ms: u64) -> Result<u16, PingError> {
+    let now = uptime_ms();
+    let mut outstanding = OUTSTANDING.lock();
+    outstanding.retain(|p| now < p.deadline_ms + RESULT_LINGER_MS);
+    if outstanding.len() >= MAX_OUTSTANDING {
+        return Err(PingError::TooManyOutstanding);
+    }
+
+    let seq = NEXT_SEQ.fetch_add(1, Ordering::Relaxed);
+    let repr = Icmpv4Repr::EchoRequest { ident: PING_IDENT, seq_no: seq, data: PING_PAYLOAD };
+    {
+        let mut stack = NETWORK_STACK.lock();
+        let stack = stack.as_mut().ok_or(PingError::NoNetwork)?;
+        let socket = stack.sockets.get_mut::<icmp::Socket>(stack.icmp_handle);
+        let buf = socket.send(repr.buffer_len(), IpAddress::Ipv4(target)).map_err(|_| PingError::NoNetwork)?;
+        repr.emit(&mut Icmpv4Packet::new_unchecked(buf), &ChecksumCapabilities::default());
+    }
+    outstanding.push(Outstanding { seq, target, sent_ms: now, deadline_ms: now + timeout_ms, rtt_ms: None });
+    Ok(seq)
+}
+
+/// The outcome of request `seq`: `None` while it is still waiting, else
+/// the round-trip time in milliseconds or why there is none. A finished
+/// request is forgotten once its result has been returned.
+pub fn result(seq: u16) -> Option<Result<u64, PingError>> {
+    let mut outstanding = OUTSTANDING.lock();
+    let idx = match outstanding.iter().position(|p| p.seq == seq) {
+        Some(idx) => idx,
+        None => return Some(Err(PingError::NotFound)),
+    };
+    let ping = &outstanding[idx];
+    let result = match ping.rtt_ms {
+        Some(rtt) => Ok(rtt),
+        None if uptime_ms() >= ping.deadline_ms => Err(PingError::TimedOut),
+        None => return None,
+    };
+    outstanding.swap_remove(idx);
+    Some(result)
+}
+
+/// Ping `target` and return the round-trip time.
+pub async fn ping(target: Ipv4Address, timeout_ms: u64) -> Result<Duration, PingError> {
+    let seq = send(target, timeout_ms)?;
+    loop {
+        if let Some(result) = result(seq) {
+            return result.map(Duration::from_millis);
+        }
+        yield_now().await;
+    }
+}
+
+/// Match echo replies waiting in `socket` to outstanding requests. Called
+/// by the network stack's poll loop.
+pub fn collect_replies(socket: &mut icmp::Socket) {
+    let now = uptime_ms();
+    while let Ok((data, source)) = socket.recv() {
+        let Ok(packet) = Icmpv4Packet::new_checked(data) else { continue };
+        let Ok(Icmpv4Repr::EchoReply { ident: PING_IDENT, seq_no, .. }) =
+            Icmpv4Repr::parse(&packet, &ChecksumCapabilities::default())
+        else {
+            continue;
+        };
+        let mut outstanding = OUTSTANDING.lock();
+        let ping = outstanding.iter_mut().find(|p| {
+            p.seq == seq_no && IpAddress::Ipv4(p.target) == source && p.rtt_ms.is_none() && now < p.deadline_ms
+        });
+        if let Some(ping) = ping {
+            ping.rtt_ms = Some(now - ping.sent_ms);
+        }
+    }
+}
@@ -0,0 +1,193 @@
+//! # Initial Ramdisk
+//!
+//! A read-only archive of WASM apps loaded alongside the kernel. The
+//! bootloader maps it into memory (`BootInfo::ramdisk_addr`); we index it
+//! once at boot, mount it at `/initrd` and hand out `&'static` slices of
+//! its files, so no module bytes are ever copied onto the heap.
+//!
+//! ## Format
+//! A POSIX ustar archive (`tar --format=ustar -cf initrd.tar ...`). Only
+//! regular files are indexed; directories, links and other entry types are
+//! skipped. A leading `./` is stripped from names.
+//!
+//! ## Manifest
+//! If the archive contains `manifest`, `kernel_main` starts the services it
+//! lists, one per line:
+//! ```text
+//! # name     module        [entry [memory_kib [budget_us/period_us]]]
+//! echo       echo.wasm
+//! logger     logger.wasm   run
+//! cache      cache.wasm    main   4096
+//! pacer      pacer.wasm    main   1024   2000/10000
+//! ```
+//! `entry` defaults to `main`, and the memory limit to `proc.memory_limit`.
+//! The last field gives the service a CPU reservation
+//! (`executor::SchedContext`): `budget_us` out of every `period_us`.
+//! Blank lines and `#` comments are ignored.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::serial_println;
+use crate::vfs::{FileSystem, ReadContext, VfsError};
+
+/// Name of the file listing the services to start at boot.
+pub const MANIFEST: &str = "manifest";
+
+const BLOCK_SIZE: usize = 512;
+
+lazy_static! {
+    static ref FILES: Mutex<Vec<(String, &'static [u8])>> = Mutex::new(Vec::new());
+}
+
+/// One service line from the manifest.
+#[derive(Debug)]
+pub struct ServiceSpec<'a> {
+    pub name: &'a str,
+    pub module: &'a str,
+    pub entry: &'a str,
+    /// Linear memory cap in bytes, if the line sets one.
+    pub memory_limit: Option<usize>,
+    /// CPU reservation as `(budget_us, period_us)`, if the line asks for one.
+    pub reservation: Option<(u64, u64)>,
+}
+
+/// Index the archive at `image`. Returns the number of files found.
+///
+/// A truncated or corrupt archive is indexed up to the first bad header.
+pub fn init(image: &'static [u8]) -> usize {
+    let mut files = FILES.lock();
+    let mut offset = 0;
+    while offset + BLOCK_SIZE <= image.len() {
+        let header = &image[offset..offset + BLOCK_SIZE];
+        // The archive ends with (at least) one all-zero block.
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        let size = match parse_octal(&header[124..136]) {
+            Some(size) => size,
+            None => {
+                serial_println!("[INITRD] Bad header at offset {}; stopping.", offset);
+                break;
+            }
+        };
+        let data_start = offset + BLOCK_SIZE;
+        if data_start + size > image.len() {
+            serial_println!("[INITRD] Truncated entry at offset {}; stopping.", offset);
+            break;
+        }
+
+        // '0' and NUL both mean "regular file".
+        if matches!(header[156], b'0' | 0) {
+            let mut name = String::new();
+            if &header[257..262] == b"ustar" {
+                let prefix = c_str(&header[345..500]);
+                if !prefix.is_empty() {
+                    name.push_str(prefix);
+                    name.push('/');
+                }
+            }
+            name.push_str(c_str(&header[0..100]));
+            let name = String::from(name.trim_start_matches("./"));
+            files.push((name, &image[data_start..data_start + size]));
+        }
+        offset = data_start + size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+    }
+    files.len()
+}
+
+/// Contents of the file called `name`, if the initrd has it.
+pub fn find(name: &str) -> Option<&'static [u8]> {
+    FILES.lock().iter().find(|(n, _)| n == name).map(|&(_, data)| data)
+}
+
+/// Parse the manifest, if any. Malformed lines are logged and skipped.
+pub fn services() -> Vec<ServiceSpec<'static>> {
+    let text = match find(MANIFEST).map(core::str::from_utf8) {
+        Some(Ok(text)) => text,
+        Some(Err(_)) => {
+            serial_println!("[INITRD] Manifest is not valid UTF-8; ignoring it.");
+            return Vec::new();
+        }
+        None => return Vec::new(),
+    };
+    let mut services = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let memory = |kib: &str| kib.parse::<usize>().ok().and_then(|kib| kib.checked_mul(1024));
+        let spec = match fields[..] {
+            [name, module] => Some(ServiceSpec { name, module, entry: "main", memory_limit: None, reservation: None }),
+            [name, module, entry] => Some(ServiceSpec { name, module, entry, memory_limit: None, reservation: None }),
+            [name, module, entry, kib] => memory(kib)
+                .map(|bytes| ServiceSpec { name, module, entry, memory_limit: Some(bytes), reservation: None }),
+            [name, module, entry, kib, reserve] => memory(kib).zip(parse_reservation(reserve)).map(|(bytes, reservation)| {
+                ServiceSpec { name, module, entry, memory_limit: Some(bytes), reservation: Some(reservation) }
+            }),
+            _ => None,
+        };
+        match spec {
+            Some(spec) => services.push(spec),
+            None => {
+                serial_println!(
+                    "[INITRD] Manifest line {}: expected 'name module [entry [memory_kib [budget_us/period_us]]]'.",
+                    n + 1
+                );
+            }
+        }
+    }
+    services
+}
+
+/// Parse a manifest reservation, `budget_us/period_us`.
+fn parse_reservation(field: &str) -> Option<(u64, u64)> {
+    let (budget, period) = field.split_once('/')?;
+    Some((budget.parse().ok()?, period.parse().ok()?))
+}
+
+/// A `FileSystem` view of the initrd, for mounting at `/initrd`.
+pub fn filesystem() -> Box<dyn FileSystem> {
+    Box::new(InitrdFs)
+}
+
+struct InitrdFs;
+
+impl FileSystem for InitrdFs {
+    fn read(&self, path: &str, _ctx: &ReadContext) -> Result<Vec<u8>, VfsError> {
+        if path.is_empty() {
+            return Err(VfsError::NotADirectory);
+        }
+        find(path).map(<[u8]>::to_vec).ok_or(VfsError::NotFound)
+    }
+
+    fn list(&self, path: &str) -> Result<Vec<String>, VfsError> {
+        if !path.is_empty() {
+            return Err(VfsError::NotADirectory);
+        }
+        Ok(FILES.lock().iter().map(|(name, _)| name.clone()).collect())
+    }
+}
+
+/// Parse a NUL/space-terminated octal field.
+fn parse_octal(field: &[u8]) -> Option<usize> {
+    let mut value = 0usize;
+    for &b in field.iter().skip_while(|&&b| b == b' ') {
+        match b {
+            b'0'..=b'7' => value = value.checked_mul(8)?.checked_add((b - b'0') as usize)?,
+            0 | b' ' => break,
+            _ => return None,
+        }
+    }
+    Some(value)
+}
+
+/// The text of a NUL-padded header field (empty if not UTF-8).
+fn c_str(field: &[u8]) -> &str {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).unwrap_or("")
+}
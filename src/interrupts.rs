@@ -3,6 +3,9 @@
 //! Configures the CPU's Interrupt Descriptor Table to handle:
 //! - **CPU Exceptions**: Breakpoints, Double Faults, Page Faults, etc.
 //! - **Hardware Interrupts**: Timer ticks, Keyboard input (via the 8259 PIC).
+//!   Devices claim their PIC line with `route_irq`, or an MSI vector with
+//!   `allocate_msi_vector`, and are told about each interrupt through a
+//!   `Notification`.
 //!
 //! ## How it works
 //! When the CPU encounters an exception or receives a hardware interrupt signal,
@@ -14,12 +17,14 @@
 //! automatically. This is a nightly Rust feature enabled via
 //! `#![feature(abi_x86_interrupt)]` in main.rs.
 
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 use x86_64::instructions::port::Port;
 use lazy_static::lazy_static;
+use crate::notification::Notification;
 use crate::serial_println;
 
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
 
 // 8259 PIC ports
 const PIC1_COMMAND: u16 = 0x20;
@@ -37,6 +42,25 @@ const TIMER_INTERRUPT: u8 = PIC1_OFFSET;
 
 pub static TICK_COUNTER: AtomicU64 = AtomicU64::new(0);
 
+/// PIC line cascading PIC2 into PIC1.
+const CASCADE_IRQ: u8 = 2;
+
+/// Notification signalled by each PIC line (see `route_irq`); null if none.
+static IRQ_ROUTES: [AtomicPtr<Notification>; 16] = [const { AtomicPtr::new(ptr::null_mut()) }; 16];
+
+/// First vector handed out for MSI/MSI-X, right after the PIC's.
+const MSI_VECTOR_BASE: u8 = PIC2_OFFSET + 8;
+/// Vectors available for MSI/MSI-X.
+const MSI_VECTORS: usize = 16;
+/// Local APIC spurious interrupt vector.
+const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// Notification and bits signalled by each MSI vector (see
+/// `allocate_msi_vector`).
+static MSI_ROUTES: [AtomicPtr<Notification>; MSI_VECTORS] = [const { AtomicPtr::new(ptr::null_mut()) }; MSI_VECTORS];
+static MSI_BITS: [AtomicU64; MSI_VECTORS] = [const { AtomicU64::new(0) }; MSI_VECTORS];
+static MSI_ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
 lazy_static! {
     /// The global IDT, initialized once at boot.
     ///
@@ -53,6 +77,13 @@ lazy_static! {
 
         // Hardware interrupt handlers
         idt[TIMER_INTERRUPT as usize].set_handler_fn(timer_interrupt_handler);
+        for (line, handler) in IRQ_HANDLERS.iter().enumerate() {
+            idt[PIC1_OFFSET as usize + 1 + line].set_handler_fn(*handler);
+        }
+        for (index, handler) in MSI_HANDLERS.iter().enumerate() {
+            idt[MSI_VECTOR_BASE as usize + index].set_handler_fn(*handler);
+        }
+        idt[SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
 
         idt
     };
@@ -191,12 +222,174 @@ extern "x86-interrupt" fn page_fault_handler(
 /// Fires ~100 times/second, waking the CPU from `hlt` to poll the network stack.
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
     TICK_COUNTER.fetch_add(1, Ordering::Relaxed);
+    // Interrupt arrival jitter against the TSC feeds the kernel RNG.
+    crate::random::add_interrupt_sample(unsafe { core::arch::x86_64::_rdtsc() });
     // Send End-Of-Interrupt to PIC1
     unsafe {
         Port::<u8>::new(PIC1_COMMAND).write(0x20);
     }
 }
 
+/// One handler per listed number, each calling `$dispatch` with it.
+macro_rules! irq_handlers {
+    ($dispatch:ident: $($n:literal),*) => {
+        [$({
+            extern "x86-interrupt" fn handler(_stack_frame: InterruptStackFrame) {
+                $dispatch($n);
+            }
+            handler as HandlerFunc
+        }),*]
+    };
+}
+
+/// Handlers for PIC lines 1-15.
+static IRQ_HANDLERS: [HandlerFunc; 15] = irq_handlers!(device_irq: 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15);
+/// Handlers for the MSI vectors, by index from `MSI_VECTOR_BASE`.
+static MSI_HANDLERS: [HandlerFunc; MSI_VECTORS] =
+    irq_handlers!(msi_irq: 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15);
+
+/// Signal the notification routed to `line` with bit `1 << line`, then
+/// acknowledge the interrupt. The device itself is serviced by whichever
+/// task waits on the notification.
+fn device_irq(line: u8) {
+    let notification = IRQ_ROUTES[line as usize].load(Ordering::Acquire);
+    if !notification.is_null() {
+        // Only ever set from a `&'static Notification` in `route_irq`.
+        unsafe { (*notification).signal(1 << line) };
+    }
+    unsafe {
+        if line >= 8 {
+            Port::<u8>::new(PIC2_COMMAND).write(0x20);
+        }
+        Port::<u8>::new(PIC1_COMMAND).write(0x20);
+    }
+}
+
+/// Signal `notification` on every interrupt on PIC line `line` and unmask
+/// the line. Returns false for the timer line, the cascade line, or a line
+/// that doesn't exist.
+///
+/// The line is edge-triggered: a device holding its (level) PCI interrupt
+/// raised interrupts again only after it has been acknowledged at the
+/// device, which is the waiting task's job.
+pub fn route_irq(line: u8, notification: &'static Notification) -> bool {
+    if line == 0 || line == CASCADE_IRQ || line > 15 {
+        return false;
+    }
+    IRQ_ROUTES[line as usize].store(notification as *const Notification as *mut Notification, Ordering::Release);
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        if line < 8 {
+            unmask(PIC1_DATA, line);
+        } else {
+            unmask(PIC1_DATA, CASCADE_IRQ);
+            unmask(PIC2_DATA, line - 8);
+        }
+    });
+    true
+}
+
+/// Clear bit `bit` in the interrupt mask register behind `data_port`.
+unsafe fn unmask(data_port: u16, bit: u8) {
+    let mut port = Port::<u8>::new(data_port);
+    let mask = port.read();
+    port.write(mask & !(1 << bit));
+}
+
+// ---------------------------------------------------------------------------
+// Local APIC and MSI
+// ---------------------------------------------------------------------------
+//
+// Message-signalled interrupts are delivered by the local APIC, not the PIC,
+// so the APIC must be software-enabled and acknowledges them itself. Its
+// local vector table is left as the firmware set it up (LINT0 in virtual
+// wire mode), which keeps the PIC's interrupts flowing through it.
+
+/// IA32_APIC_BASE MSR.
+const APIC_BASE_MSR: u32 = 0x1b;
+const LAPIC_ID: usize = 0x20;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SPURIOUS: usize = 0xf0;
+/// Spurious Interrupt Vector register bit 8: APIC software enable.
+const LAPIC_ENABLE: u32 = 1 << 8;
+/// MSI messages are writes into this physical window.
+const MSI_ADDRESS_BASE: u64 = 0xfee0_0000;
+
+/// Virtual address of the local APIC registers; 0 until `local_apic` maps them.
+static LAPIC_BASE: AtomicUsize = AtomicUsize::new(0);
+
+/// The local APIC's registers, software-enabled on first use.
+fn local_apic() -> usize {
+    let base = LAPIC_BASE.load(Ordering::Acquire);
+    if base != 0 {
+        return base;
+    }
+    use virtio_drivers::Hal;
+    let phys = unsafe { x86_64::registers::model_specific::Msr::new(APIC_BASE_MSR).read() } & 0xffff_f000;
+    let base = unsafe { crate::hal::VirtioHal::mmio_phys_to_virt(phys as usize, 4096) }.as_ptr() as usize;
+    unsafe {
+        let svr = (base + LAPIC_SPURIOUS) as *mut u32;
+        ptr::write_volatile(svr, ptr::read_volatile(svr) | LAPIC_ENABLE | SPURIOUS_VECTOR as u32);
+    }
+    LAPIC_BASE.store(base, Ordering::Release);
+    base
+}
+
+/// Give out an MSI/MSI-X vector that signals `bits` on `notification`.
+/// Asking again for the same pair returns the same vector. `None` once
+/// `MSI_VECTORS` are in use.
+pub fn allocate_msi_vector(notification: &'static Notification, bits: u64) -> Option<u8> {
+    local_apic();
+    let target = notification as *const Notification as *mut Notification;
+    let allocated = MSI_ALLOCATED.load(Ordering::Acquire);
+    let existing = (0..allocated.min(MSI_VECTORS)).find(|&i| {
+        MSI_ROUTES[i].load(Ordering::Acquire) == target && MSI_BITS[i].load(Ordering::Acquire) == bits
+    });
+    let index = match existing {
+        Some(index) => index,
+        None => {
+            let index = MSI_ALLOCATED.fetch_add(1, Ordering::AcqRel);
+            if index >= MSI_VECTORS {
+                return None;
+            }
+            MSI_BITS[index].store(bits, Ordering::Release);
+            MSI_ROUTES[index].store(target, Ordering::Release);
+            index
+        }
+    };
+    Some(MSI_VECTOR_BASE + index as u8)
+}
+
+/// Address and data of an MSI message raising `vector` on this CPU (fixed
+/// delivery, edge-triggered).
+pub fn msi_message(vector: u8) -> (u64, u32) {
+    let apic_id = unsafe { ptr::read_volatile((local_apic() + LAPIC_ID) as *const u32) } >> 24;
+    (MSI_ADDRESS_BASE | (apic_id as u64) << 12, vector as u32)
+}
+
+/// Signal the notification behind MSI vector `index` and acknowledge the
+/// interrupt at the local APIC.
+fn msi_irq(index: usize) {
+    let notification = MSI_ROUTES[index].load(Ordering::Acquire);
+    if !notification.is_null() {
+        // Only ever set from a `&'static Notification`.
+        unsafe { (*notification).signal(MSI_BITS[index].load(Ordering::Acquire)) };
+    }
+    let base = LAPIC_BASE.load(Ordering::Acquire);
+    unsafe { ptr::write_volatile((base + LAPIC_EOI) as *mut u32, 0) };
+}
+
+/// Spurious local APIC interrupt: nothing to do, and no EOI either.
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
+/// Timer interrupts since boot.
 pub fn get_ticks() -> u64 {
     TICK_COUNTER.load(Ordering::Relaxed)
 }
+
+/// Milliseconds since boot, derived from the tick counter.
+///
+/// COMPENSATION: The timer seems to run at ~10kHz instead of 100Hz in
+/// QEMU/HVF, so ticks are divided by 100 to get roughly real time.
+pub fn uptime_ms() -> u64 {
+    (get_ticks() / 100) * 10
+}
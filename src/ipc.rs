@@ -23,19 +23,55 @@
 //! ```
 //!
 //! ## Key Concepts
-//! - **Endpoint**: A kernel object where messages are buffered.
+//! - **Endpoint**: A kernel object where messages are buffered. Each one
+//!   has an owner (a PID, or 0 for the kernel); a process owns at most
+//!   `MAX_ENDPOINTS_PER_OWNER` of the table's `MAX_ENDPOINTS`, and its
+//!   endpoints are destroyed when it exits (`release_owner`).
 //! - **Capability**: A process must hold an `EndpointCap` to send/receive.
 //! - **Message**: A fixed-size payload (registers + optional data buffer).
 //! - **Synchronous**: In seL4, IPC is synchronous (sender blocks until receiver
 //!   picks up). We start with an async queue for simplicity.
+//! - **Priorities**: each message carries a `Priority`; higher classes are
+//!   delivered first (FIFO within a class), with a starvation bound so
+//!   lower classes still make progress under sustained urgent traffic.
+//! - **Large messages**: `send_shared()` passes a derived capability to a
+//!   whole shared-memory region, so nothing is copied; receivers address
+//!   the region through the capability (`shm`), which bounds-checks every
+//!   access.
+//! - **Async receive**: kernel tasks `recv_async(cap).await` instead of
+//!   busy polling; each endpoint keeps a list of wakers that `send()` wakes.
+//!   `recv_until()` adds a deadline, for server loops that must run a
+//!   watchdog instead of hanging forever on dead clients; the process
+//!   table uses it for guests blocked in `env.ipc_recv_wait`.
+//! - **Broadcast**: `publish()` on a broadcast channel delivers a copy to
+//!   every subscribed endpoint, for kernel events like "link up".
+//! - **Wait sets**: a `WaitSet` lets one server wait on several endpoints
+//!   and learn which one has a message; guests use it through
+//!   `env.ipc_wait`.
+//! - **Statistics**: every endpoint counts messages sent, received and
+//!   dropped (queue full) plus its queue high-watermark; read them with
+//!   `all_stats()` or the `ipcstat` shell command.
+//! - **Call/Reply**: `call()` creates a one-shot reply endpoint and attaches
+//!   a WRITE capability to it to the message; the server answers with
+//!   `reply()` through that capability, so a client/server pair needs only
+//!   the server's endpoint and no manual correlation. Guests use it through
+//!   `env.ipc_call` / `env.ipc_reply`.
 //!
 //! ## Security
 //! Every `send()` and `receive()` operation requires the caller to present
 //! a valid capability with the correct permissions. Without the right key,
 //! a process cannot even know an endpoint exists.
 
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
 use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
 use spin::Mutex;
+use crate::capability::{Capability, CapabilityType, Permissions};
+use crate::executor::{self, Sleep};
+use crate::process::Pid;
 
 // ─── Message ─────────────────────────────────────────────────────────────────
 
@@ -48,7 +84,7 @@ pub const MAX_MESSAGE_WORDS: usize = 8;
 /// An IPC message — the unit of communication between processes.
 ///
 /// Contains a label (message type) and a fixed-size data buffer.
-/// For larger payloads, processes would use shared memory capabilities.
+/// Larger data travels out of line as a `Payload`.
 #[derive(Debug, Clone)]
 pub struct Message {
     /// Application-defined message type/label.
@@ -65,6 +101,27 @@ pub struct Message {
     /// Sender's endpoint ID (filled in by the kernel, not the sender).
     /// Allows the receiver to identify who sent the message.
     pub sender_id: u64,
+
+    /// For messages sent with `call()`: the capability to answer through
+    /// with `reply()` (filled in by the kernel). `None` means the sender
+    /// does not expect a reply.
+    pub reply: Option<Capability>,
+
+    /// Delivery priority; urgent control messages jump ahead of the queue.
+    pub priority: Priority,
+
+    /// Optional out-of-line payload for data that doesn't fit in `data`.
+    /// Attached by the kernel in `send_shared()`.
+    pub payload: Option<Payload>,
+}
+
+/// Out-of-line message data, for payloads larger than `MAX_MESSAGE_WORDS`.
+#[derive(Debug, Clone)]
+pub enum Payload {
+    /// A shared-memory region. `region` is a capability the kernel derived
+    /// from the sender's own; the receiver installs it in its CSpace to
+    /// reach the region. No bytes are copied.
+    Shared { region: Capability },
 }
 
 impl Message {
@@ -75,9 +132,18 @@ impl Message {
             data: [0; MAX_MESSAGE_WORDS],
             length: 0,
             sender_id: 0,
+            reply: None,
+            priority: Priority::Normal,
+            payload: None,
         }
     }
 
+    /// Return this message with the given delivery priority.
+    pub const fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
     /// Create a message with a label and one data word.
     pub const fn with_data1(label: u64, word0: u64) -> Self {
         let mut msg = Message::new(label);
@@ -99,77 +165,191 @@ impl Message {
 // ─── Endpoint ────────────────────────────────────────────────────────────────
 
 /// Maximum number of messages that can be queued in an endpoint.
-const ENDPOINT_QUEUE_SIZE: usize = 16;
+pub const ENDPOINT_QUEUE_SIZE: usize = 16;
+
+/// Delivery priority of a message. Higher classes are received first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Priority {
+    /// The default for ordinary requests.
+    Normal = 0,
+    /// Kernel events a service should act on promptly, e.g. network
+    /// address changes.
+    High = 1,
+    /// Control traffic, e.g. the shutdown notice.
+    Urgent = 2,
+}
 
 /// Global counter for generating unique endpoint IDs.
 static NEXT_ENDPOINT_ID: AtomicU64 = AtomicU64::new(1);
 
+/// Number of priority classes (see `Priority`).
+const PRIORITY_LEVELS: usize = 3;
+
+/// Queue slots only `High`/`Urgent` messages may use, so control messages
+/// still get through when an endpoint is flooded with ordinary traffic.
+const URGENT_RESERVE: usize = 2;
+
+/// After this many consecutive deliveries from a higher class while a lower
+/// class is waiting, one message from the lower class is delivered.
+/// Bounds how long a steady stream of urgent traffic can starve the rest.
+const STARVATION_LIMIT: u32 = 8;
+
+/// Endpoints that may exist at once, reply endpoints included.
+pub const MAX_ENDPOINTS: usize = 1024;
+
+/// Endpoints one process may own at once. The kernel (owner 0) is only
+/// bound by `MAX_ENDPOINTS`.
+pub const MAX_ENDPOINTS_PER_OWNER: usize = 64;
+
+/// Lifetime counters for one endpoint.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EndpointStats {
+    /// Messages successfully enqueued.
+    pub sent: u64,
+    /// Messages dequeued by a receiver.
+    pub received: u64,
+    /// Sends rejected with `QueueFull`.
+    pub dropped: u64,
+    /// Largest number of messages ever queued at once.
+    pub high_watermark: usize,
+    /// Messages queued right now.
+    pub pending: usize,
+}
+
 /// An IPC Endpoint — a kernel object where messages are exchanged.
 ///
 /// Each endpoint has a bounded message queue. Senders enqueue messages;
 /// receivers dequeue them. If the queue is full, send fails (no blocking yet).
+/// Messages are delivered highest `Priority` first, FIFO within a class.
 pub struct Endpoint {
     /// Unique identifier for this endpoint.
     pub id: u64,
 
-    /// Bounded ring buffer of messages.
-    queue: [Option<Message>; ENDPOINT_QUEUE_SIZE],
+    /// The process that created it (0 for the kernel).
+    owner: Pid,
 
-    /// Index of the next message to dequeue (read pointer).
-    head: usize,
+    /// One FIFO per priority class, indexed by `Priority as usize`.
+    queues: [VecDeque<Message>; PRIORITY_LEVELS],
 
-    /// Index of the next free slot to enqueue into (write pointer).
-    tail: usize,
-
-    /// Number of messages currently in the queue.
+    /// Number of messages currently queued across all classes.
     count: usize,
+
+    /// Consecutive deliveries that bypassed a waiting lower class.
+    bypass_streak: u32,
+
+    /// Tasks waiting in `recv_async()` for a message to arrive.
+    wakers: Vec<Waker>,
+
+    /// A `call()`'s reply endpoint: takes one message, then refuses more
+    /// with `InvalidReply`.
+    one_shot: bool,
+
+    /// Lifetime counters (`pending` is filled in from `count` on read).
+    stats: EndpointStats,
 }
 
 impl Endpoint {
     /// Create a new endpoint with a unique ID and an empty queue.
     pub fn new() -> Self {
-        const EMPTY: Option<Message> = None;
         Endpoint {
             id: NEXT_ENDPOINT_ID.fetch_add(1, Ordering::Relaxed),
-            queue: [EMPTY; ENDPOINT_QUEUE_SIZE],
-            head: 0,
-            tail: 0,
+            owner: 0,
+            queues: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
             count: 0,
+            bypass_streak: 0,
+            wakers: Vec::new(),
+            one_shot: false,
+            stats: EndpointStats::default(),
         }
     }
 
+    /// Create the reply endpoint for one `call()`.
+    fn reply() -> Self {
+        Endpoint { one_shot: true, ..Endpoint::new() }
+    }
+
     /// Enqueue a message into this endpoint.
     ///
     /// Returns `Ok(())` if the message was queued successfully,
-    /// or `Err(IpcError::QueueFull)` if the buffer is full.
+    /// or `Err(IpcError::QueueFull)` if the buffer is full. The last
+    /// `URGENT_RESERVE` slots are only available to `High`/`Urgent` messages.
     pub fn send(&mut self, msg: Message) -> Result<(), IpcError> {
-        if self.count >= ENDPOINT_QUEUE_SIZE {
+        if self.one_shot && self.stats.sent > 0 {
+            return Err(IpcError::InvalidReply);
+        }
+        let limit = if msg.priority >= Priority::High {
+            ENDPOINT_QUEUE_SIZE
+        } else {
+            ENDPOINT_QUEUE_SIZE - URGENT_RESERVE
+        };
+        if self.count >= limit {
+            self.stats.dropped += 1;
             return Err(IpcError::QueueFull);
         }
 
-        self.queue[self.tail] = Some(msg);
-        self.tail = (self.tail + 1) % ENDPOINT_QUEUE_SIZE;
+        self.queues[msg.priority as usize].push_back(msg);
         self.count += 1;
+        self.stats.sent += 1;
+        self.stats.high_watermark = self.stats.high_watermark.max(self.count);
+        self.wake_all();
         Ok(())
     }
 
+    /// Register a task to be woken when the next message arrives.
+    pub fn register_waker(&mut self, waker: &Waker) {
+        if !self.wakers.iter().any(|w| w.will_wake(waker)) {
+            self.wakers.push(waker.clone());
+        }
+    }
+
+    /// Wake every task waiting on this endpoint.
+    fn wake_all(&mut self) {
+        for waker in self.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+
     /// Dequeue the next message from this endpoint.
     ///
     /// Returns `Ok(message)` if a message was available,
     /// or `Err(IpcError::QueueEmpty)` if there are no pending messages.
     pub fn receive(&mut self) -> Result<Message, IpcError> {
-        if self.count == 0 {
-            return Err(IpcError::QueueEmpty);
-        }
+        let highest = self
+            .queues
+            .iter()
+            .rposition(|q| !q.is_empty())
+            .ok_or(IpcError::QueueEmpty)?;
+        let lower_waiting = self.queues[..highest].iter().rposition(|q| !q.is_empty());
+
+        let class = match lower_waiting {
+            Some(lower) if self.bypass_streak >= STARVATION_LIMIT => {
+                self.bypass_streak = 0;
+                lower
+            }
+            Some(_) => {
+                self.bypass_streak += 1;
+                highest
+            }
+            None => {
+                self.bypass_streak = 0;
+                highest
+            }
+        };
 
-        let msg = self.queue[self.head]
-            .take()
-            .expect("Queue count > 0 but slot was None — invariant violated");
-        self.head = (self.head + 1) % ENDPOINT_QUEUE_SIZE;
+        let msg = self.queues[class]
+            .pop_front()
+            .expect("class chosen from a non-empty queue");
         self.count -= 1;
+        self.stats.received += 1;
         Ok(msg)
     }
 
+    /// Snapshot of this endpoint's counters.
+    pub fn stats(&self) -> EndpointStats {
+        EndpointStats { pending: self.count, ..self.stats }
+    }
+
     /// Returns the number of messages currently queued.
     pub fn pending_count(&self) -> usize {
         self.count
@@ -194,53 +374,218 @@ pub enum IpcError {
     PermissionDenied,
     /// The specified endpoint does not exist.
     InvalidEndpoint,
+    /// The capability doesn't name a reply endpoint, or the reply has
+    /// already been sent.
+    InvalidReply,
+    /// No message arrived before the receive timeout expired.
+    TimedOut,
+    /// The capability offered for a shared buffer is not a Memory
+    /// capability the sender may delegate (requires `GRANT`).
+    InvalidBuffer,
+    /// The endpoint table, or the owner's share of it, is full.
+    TooManyEndpoints,
+}
+
+// ─── Call / Reply ────────────────────────────────────────────────────────────
+
+/// The caller's side of a `call()`: owns the one-shot reply endpoint.
+///
+/// Wait for the answer with `recv_until(call.reply_cap(), ..)`. Dropping
+/// the `PendingCall` destroys the endpoint, so a late reply fails instead
+/// of sitting in a queue nobody reads. Don't drop it while holding
+/// `IPC_MANAGER`.
+#[derive(Debug)]
+pub struct PendingCall {
+    /// READ capability for the reply endpoint.
+    reply: Capability,
+}
+
+impl PendingCall {
+    /// Capability to receive the reply with.
+    pub fn reply_cap(&self) -> Capability {
+        self.reply.clone()
+    }
+}
+
+impl Drop for PendingCall {
+    fn drop(&mut self) {
+        let mut ipc = IPC_MANAGER.lock();
+        if let Ok(slot) = ipc.resolve(&self.reply, Permissions::READ) {
+            let _ = ipc.destroy_endpoint(slot);
+        }
+    }
+}
+
+// ─── Broadcast Channels ──────────────────────────────────────────────────────
+
+/// A fan-out channel: one `publish()` is copied into the queue of every
+/// subscribed endpoint.
+///
+/// Each subscriber keeps its own endpoint, so a slow receiver only fills
+/// its own queue — it never blocks the publisher or other subscribers.
+struct Broadcast {
+    /// Unique ID, drawn from the same counter as endpoint IDs so endpoint
+    /// capabilities can name either without ambiguity.
+    id: u64,
+    /// Endpoint IDs of the subscribers.
+    subscribers: Vec<u64>,
 }
 
 // ─── IPC Manager ─────────────────────────────────────────────────────────────
 
-/// Maximum number of endpoints the kernel can manage.
-const MAX_ENDPOINTS: usize = 32;
+/// The kernel-wide IPC manager, shared by boot code and executor tasks.
+pub static IPC_MANAGER: Mutex<IpcManager> = Mutex::new(IpcManager::new());
 
 /// The global IPC manager — owns all endpoints and mediates access.
 ///
 /// All IPC operations go through this manager, which enforces
 /// capability-based access control before touching any endpoint.
 pub struct IpcManager {
-    /// Array of all kernel-managed endpoints.
-    endpoints: [Option<Mutex<Endpoint>>; MAX_ENDPOINTS],
+    /// Table of all kernel-managed endpoints. Grows on demand; slots freed
+    /// by `destroy_endpoint` are reused.
+    endpoints: Vec<Option<Mutex<Endpoint>>>,
     /// Number of endpoints currently active.
     count: usize,
+    /// Fan-out channels for kernel event distribution.
+    broadcasts: Vec<Broadcast>,
 }
 
 impl IpcManager {
     /// Create a new IPC manager with no endpoints.
     pub const fn new() -> Self {
-        const EMPTY: Option<Mutex<Endpoint>> = None;
         IpcManager {
-            endpoints: [EMPTY; MAX_ENDPOINTS],
+            endpoints: Vec::new(),
             count: 0,
+            broadcasts: Vec::new(),
+        }
+    }
+
+    /// Create a new endpoint owned by `owner` (0 for the kernel) and return
+    /// its slot index.
+    ///
+    /// The caller should mint a capability for it with `endpoint_capability`
+    /// and grant that to the appropriate processes.
+    pub fn create_endpoint(&mut self, owner: Pid) -> Result<usize, IpcError> {
+        self.add_endpoint(Endpoint { owner, ..Endpoint::new() })
+    }
+
+    fn add_endpoint(&mut self, endpoint: Endpoint) -> Result<usize, IpcError> {
+        if self.count >= MAX_ENDPOINTS {
+            return Err(IpcError::TooManyEndpoints);
+        }
+        if endpoint.owner != 0 {
+            let owned = self.endpoints.iter().flatten().filter(|ep| ep.lock().owner == endpoint.owner).count();
+            if owned >= MAX_ENDPOINTS_PER_OWNER {
+                return Err(IpcError::TooManyEndpoints);
+            }
+        }
+        self.count += 1;
+        let endpoint = Some(Mutex::new(endpoint));
+        match self.endpoints.iter().position(|slot| slot.is_none()) {
+            Some(i) => {
+                self.endpoints[i] = endpoint;
+                Ok(i)
+            }
+            None => {
+                self.endpoints.push(endpoint);
+                Ok(self.endpoints.len() - 1)
+            }
         }
     }
 
-    /// Create a new endpoint and return its slot index.
+    /// Destroy an endpoint, discarding any queued messages.
     ///
-    /// The caller should create an `EndpointCap` capability pointing
-    /// to this slot index and grant it to the appropriate processes.
-    pub fn create_endpoint(&mut self) -> Result<usize, IpcError> {
-        for (i, slot) in self.endpoints.iter_mut().enumerate() {
-            if slot.is_none() {
-                *slot = Some(Mutex::new(Endpoint::new()));
-                self.count += 1;
-                return Ok(i);
+    /// Pending `call()`s whose request was still queued can never be
+    /// answered, so their reply endpoints are destroyed too and the callers
+    /// see `InvalidEndpoint` at once. Capabilities to the endpoint are invalidated
+    /// because they name the endpoint's unique ID, which is never reused —
+    /// even when this slot is recycled. Returns the number of discarded messages.
+    pub fn destroy_endpoint(&mut self, endpoint_slot: usize) -> Result<usize, IpcError> {
+        let endpoint = self
+            .endpoints
+            .get_mut(endpoint_slot)
+            .and_then(Option::take)
+            .ok_or(IpcError::InvalidEndpoint)?;
+        self.count -= 1;
+
+        let mut endpoint = endpoint.into_inner();
+        // Waiters re-poll and observe InvalidEndpoint.
+        endpoint.wake_all();
+        let mut drained = 0;
+        while let Ok(msg) = endpoint.receive() {
+            if let Some(reply) = msg.reply {
+                if let Ok(slot) = self.resolve(&reply, Permissions::NONE) {
+                    let _ = self.destroy_endpoint(slot);
+                }
+            }
+            drained += 1;
+        }
+        Ok(drained)
+    }
+
+    /// Destroy every endpoint `owner` created. Called when a process exits.
+    pub fn release_owner(&mut self, owner: Pid) {
+        let owned: Vec<usize> = (0..self.endpoints.len())
+            .filter(|&slot| matches!(&self.endpoints[slot], Some(ep) if ep.lock().owner == owner))
+            .collect();
+        for slot in owned {
+            let _ = self.destroy_endpoint(slot);
+        }
+    }
+
+    /// Give every endpoint `from` created to `to`, e.g. when `to` is a new
+    /// version of the service replacing `from`.
+    pub fn transfer_owner(&mut self, from: Pid, to: Pid) {
+        for endpoint in self.endpoints.iter().flatten() {
+            let mut endpoint = endpoint.lock();
+            if endpoint.owner == from {
+                endpoint.owner = to;
             }
         }
-        Err(IpcError::InvalidEndpoint) // No free slots
+    }
+
+    /// Mint a capability for the endpoint in `endpoint_slot`.
+    ///
+    /// The capability names the endpoint's unique ID rather than the slot,
+    /// so it stops working once the endpoint is destroyed.
+    pub fn endpoint_capability(&self, endpoint_slot: usize, permissions: Permissions) -> Result<Capability, IpcError> {
+        match self.endpoints.get(endpoint_slot) {
+            Some(Some(endpoint)) => Ok(Capability::new(
+                CapabilityType::Endpoint,
+                permissions,
+                endpoint.lock().id,
+            )),
+            _ => Err(IpcError::InvalidEndpoint),
+        }
+    }
+
+    /// Resolve an endpoint capability to a slot, checking its permissions.
+    pub fn resolve(&self, cap: &Capability, required: Permissions) -> Result<usize, IpcError> {
+        if cap.cap_type != CapabilityType::Endpoint || !cap.permissions.contains(required) {
+            return Err(IpcError::PermissionDenied);
+        }
+        self.endpoints
+            .iter()
+            .position(|slot| matches!(slot, Some(ep) if ep.lock().id == cap.resource_id))
+            .ok_or(IpcError::InvalidEndpoint)
+    }
+
+    /// Send through a capability (requires `WRITE`).
+    pub fn send_with_cap(&self, cap: &Capability, msg: Message) -> Result<(), IpcError> {
+        let slot = self.resolve(cap, Permissions::WRITE)?;
+        self.send(slot, msg)
+    }
+
+    /// Receive through a capability (requires `READ`).
+    pub fn receive_with_cap(&self, cap: &Capability) -> Result<Message, IpcError> {
+        let slot = self.resolve(cap, Permissions::READ)?;
+        self.receive(slot)
     }
 
     /// Send a message to an endpoint by slot index.
     ///
-    /// In a full implementation, this would also check the caller's
-    /// capability. For now, capability checking is done at a higher level.
+    /// This is the kernel-internal path and does no capability check; code
+    /// acting on behalf of a process should use `send_with_cap`.
     pub fn send(&self, endpoint_slot: usize, msg: Message) -> Result<(), IpcError> {
         match self.endpoints.get(endpoint_slot) {
             Some(Some(endpoint)) => endpoint.lock().send(msg),
@@ -256,6 +601,28 @@ impl IpcManager {
         }
     }
 
+    /// Send a reference to a whole shared-memory region (requires `WRITE`
+    /// on the endpoint).
+    ///
+    /// `region` must be a Memory or SharedMemory capability with `GRANT`;
+    /// the receiver gets a capability derived from it carrying at most
+    /// `permissions`.
+    pub fn send_shared(
+        &self,
+        cap: &Capability,
+        mut msg: Message,
+        region: &Capability,
+        permissions: Permissions,
+    ) -> Result<(), IpcError> {
+        let slot = self.resolve(cap, Permissions::WRITE)?;
+        if !matches!(region.cap_type, CapabilityType::Memory | CapabilityType::SharedMemory) {
+            return Err(IpcError::InvalidBuffer);
+        }
+        let region = region.derive(permissions).ok_or(IpcError::InvalidBuffer)?;
+        msg.payload = Some(Payload::Shared { region });
+        self.send(slot, msg)
+    }
+
     /// Get the number of pending messages in an endpoint.
     pub fn pending_count(&self, endpoint_slot: usize) -> Result<usize, IpcError> {
         match self.endpoints.get(endpoint_slot) {
@@ -268,4 +635,294 @@ impl IpcManager {
     pub fn endpoint_count(&self) -> usize {
         self.count
     }
+
+    /// Counters for every live endpoint, as `(slot, endpoint id, stats)`.
+    pub fn all_stats(&self) -> Vec<(usize, u64, EndpointStats)> {
+        self.endpoints
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, ep)| ep.as_ref().map(|ep| {
+                let ep = ep.lock();
+                (slot, ep.id, ep.stats())
+            }))
+            .collect()
+    }
+
+    /// Send a message through `cap` (requires `WRITE`) and open a one-shot
+    /// reply endpoint for the answer.
+    ///
+    /// The message carries a WRITE capability to the reply endpoint in
+    /// `Message::reply`, for the server to answer with `reply()`. The
+    /// caller waits for the answer on the returned `PendingCall`.
+    pub fn call(&mut self, cap: &Capability, mut msg: Message) -> Result<PendingCall, IpcError> {
+        let slot = self.resolve(cap, Permissions::WRITE)?;
+        let reply_slot = self.add_endpoint(Endpoint::reply())?;
+        msg.reply = Some(self.endpoint_capability(reply_slot, Permissions::WRITE)?);
+        let reply = self.endpoint_capability(reply_slot, Permissions::READ)?;
+        if let Err(e) = self.send(slot, msg) {
+            let _ = self.destroy_endpoint(reply_slot);
+            return Err(e);
+        }
+        Ok(PendingCall { reply })
+    }
+
+    /// Answer a `call()` through the reply capability its message carried.
+    /// Each reply capability works once; the caller may have given up, in
+    /// which case the endpoint is gone (`InvalidEndpoint`).
+    pub fn reply(&self, reply: &Capability, msg: Message) -> Result<(), IpcError> {
+        let slot = self.resolve(reply, Permissions::WRITE)?;
+        match self.endpoints.get(slot) {
+            Some(Some(endpoint)) => {
+                let mut endpoint = endpoint.lock();
+                if !endpoint.one_shot {
+                    return Err(IpcError::InvalidReply);
+                }
+                endpoint.send(msg)
+            }
+            _ => Err(IpcError::InvalidEndpoint),
+        }
+    }
+
+    /// Create a broadcast channel and return a capability to it.
+    ///
+    /// The capability is an Endpoint capability: `WRITE` allows publishing,
+    /// `READ` allows subscribing an endpoint.
+    pub fn create_broadcast(&mut self) -> Capability {
+        let id = NEXT_ENDPOINT_ID.fetch_add(1, Ordering::Relaxed);
+        self.broadcasts.push(Broadcast { id, subscribers: Vec::new() });
+        Capability::new(CapabilityType::Endpoint, Permissions::all(), id)
+    }
+
+    /// Subscribe the endpoint named by `endpoint_cap` (which needs `READ`,
+    /// since the subscriber will receive there) to a broadcast channel.
+    pub fn subscribe(&mut self, channel_cap: &Capability, endpoint_cap: &Capability) -> Result<(), IpcError> {
+        let slot = self.resolve(endpoint_cap, Permissions::READ)?;
+        let endpoint_id = match &self.endpoints[slot] {
+            Some(endpoint) => endpoint.lock().id,
+            None => return Err(IpcError::InvalidEndpoint),
+        };
+        let channel = self.broadcast_mut(channel_cap, Permissions::READ)?;
+        if !channel.subscribers.contains(&endpoint_id) {
+            channel.subscribers.push(endpoint_id);
+        }
+        Ok(())
+    }
+
+    /// Deliver a copy of `msg` to every subscriber of the channel (requires
+    /// `WRITE`).
+    ///
+    /// Subscribers whose queue is full miss this message (counted in their
+    /// endpoint's `dropped`); subscribers whose endpoint was destroyed are
+    /// pruned. Returns the number of endpoints the message reached.
+    pub fn publish(&mut self, channel_cap: &Capability, msg: Message) -> Result<usize, IpcError> {
+        let idx = self.broadcast_index(channel_cap, Permissions::WRITE)?;
+        let mut subscribers = core::mem::take(&mut self.broadcasts[idx].subscribers);
+        let mut delivered = 0;
+
+        subscribers.retain(|&endpoint_id| {
+            let endpoint = self
+                .endpoints
+                .iter()
+                .flatten()
+                .find(|ep| ep.lock().id == endpoint_id);
+            match endpoint {
+                Some(endpoint) => {
+                    if endpoint.lock().send(msg.clone()).is_ok() {
+                        delivered += 1;
+                    }
+                    true
+                }
+                None => false,
+            }
+        });
+
+        self.broadcasts[idx].subscribers = subscribers;
+        Ok(delivered)
+    }
+
+    fn broadcast_index(&self, cap: &Capability, required: Permissions) -> Result<usize, IpcError> {
+        if cap.cap_type != CapabilityType::Endpoint || !cap.permissions.contains(required) {
+            return Err(IpcError::PermissionDenied);
+        }
+        self.broadcasts
+            .iter()
+            .position(|b| b.id == cap.resource_id)
+            .ok_or(IpcError::InvalidEndpoint)
+    }
+
+    fn broadcast_mut(&mut self, cap: &Capability, required: Permissions) -> Result<&mut Broadcast, IpcError> {
+        let idx = self.broadcast_index(cap, required)?;
+        Ok(&mut self.broadcasts[idx])
+    }
+}
+
+// ─── Async Receive ───────────────────────────────────────────────────────────
+
+/// Receive from an endpoint without blocking the executor.
+///
+/// The returned future completes with the next message on the endpoint
+/// named by `cap` (which needs `READ`). While the queue is empty the task
+/// parks on the endpoint's waker list and is woken by the next `send()`.
+/// Resolves to `Err(InvalidEndpoint)` if the endpoint is destroyed.
+pub fn recv_async(cap: Capability) -> RecvFuture {
+    RecvFuture { cap }
+}
+
+/// Future returned by [`recv_async`].
+pub struct RecvFuture {
+    cap: Capability,
+}
+
+impl Future for RecvFuture {
+    type Output = Result<Message, IpcError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let manager = IPC_MANAGER.lock();
+        let slot = match manager.resolve(&self.cap, Permissions::READ) {
+            Ok(slot) => slot,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
+        let mut endpoint = match manager.endpoints.get(slot) {
+            Some(Some(endpoint)) => endpoint.lock(),
+            _ => return Poll::Ready(Err(IpcError::InvalidEndpoint)),
+        };
+        match endpoint.receive() {
+            Err(IpcError::QueueEmpty) => {
+                // Registered under the endpoint lock, so a send can't slip
+                // in between the empty check and the registration.
+                endpoint.register_waker(cx.waker());
+                Poll::Pending
+            }
+            result => Poll::Ready(result),
+        }
+    }
+}
+
+/// Like [`recv_async`], but give up with `IpcError::TimedOut` once uptime
+/// reaches `deadline_ms` (never, if `None`).
+///
+/// The endpoint is locked only while the future is polled, so senders get
+/// in between polls and a waiting task never holds up the executor.
+pub fn recv_until(cap: Capability, deadline_ms: Option<u64>) -> RecvUntil {
+    RecvUntil { recv: recv_async(cap), timer: deadline_ms.map(executor::sleep_until) }
+}
+
+/// Future returned by [`recv_until`].
+pub struct RecvUntil {
+    recv: RecvFuture,
+    timer: Option<Sleep>,
+}
+
+impl Future for RecvUntil {
+    type Output = Result<Message, IpcError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if let Poll::Ready(result) = Pin::new(&mut this.recv).poll(cx) {
+            return Poll::Ready(result);
+        }
+        let expired = this.timer.as_mut().is_some_and(|timer| Pin::new(timer).poll(cx).is_ready());
+        if expired {
+            Poll::Ready(Err(IpcError::TimedOut))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+// ─── Wait Sets ───────────────────────────────────────────────────────────────
+
+/// A set of endpoints a server can wait on together.
+///
+/// Each member is registered with a caller-chosen *badge*; waiting returns
+/// the badge of an endpoint that has a message queued, without dequeuing
+/// it, so the server then receives from that endpoint as usual. Members are
+/// scanned round-robin, so a busy endpoint can't starve the others.
+#[derive(Debug)]
+pub struct WaitSet {
+    members: Vec<(Capability, u64)>,
+    /// Member index the next scan starts at.
+    next: usize,
+}
+
+impl WaitSet {
+    pub const fn new() -> Self {
+        WaitSet { members: Vec::new(), next: 0 }
+    }
+
+    /// Add the endpoint named by `cap` (which needs `READ`) under `badge`.
+    pub fn add(&mut self, manager: &IpcManager, cap: Capability, badge: u64) -> Result<(), IpcError> {
+        manager.resolve(&cap, Permissions::READ)?;
+        self.members.push((cap, badge));
+        Ok(())
+    }
+
+    /// Return the badge of a member with a pending message, if any.
+    ///
+    /// Members whose endpoint was destroyed are dropped from the set.
+    pub fn poll(&mut self, manager: &IpcManager) -> Option<u64> {
+        self.members
+            .retain(|(cap, _)| manager.resolve(cap, Permissions::READ).is_ok());
+        let len = self.members.len();
+        for i in 0..len {
+            let idx = (self.next + i) % len;
+            let (cap, badge) = &self.members[idx];
+            let ready = manager
+                .resolve(cap, Permissions::READ)
+                .and_then(|slot| manager.pending_count(slot))
+                .is_ok_and(|n| n > 0);
+            if ready {
+                self.next = idx + 1;
+                return Some(*badge);
+            }
+        }
+        None
+    }
+
+    /// Wait from an executor task: completes with the badge of a ready
+    /// member, parking on every member's waker list in the meantime, or
+    /// with `IpcError::TimedOut` once uptime reaches `deadline_ms` (never,
+    /// if `None`). Fails with `IpcError::InvalidEndpoint` if every member's
+    /// endpoint is gone.
+    pub fn wait_until(&mut self, deadline_ms: Option<u64>) -> WaitSetFuture<'_> {
+        WaitSetFuture { set: self, timer: deadline_ms.map(executor::sleep_until) }
+    }
+}
+
+/// Future returned by [`WaitSet::wait_until`].
+pub struct WaitSetFuture<'a> {
+    set: &'a mut WaitSet,
+    timer: Option<Sleep>,
+}
+impl Future for WaitSetFuture<'_> {
+    type Output = Result<u64, IpcError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let set = &mut this.set;
+        let manager = IPC_MANAGER.lock();
+        if let Some(badge) = set.poll(&manager) {
+            return Poll::Ready(Ok(badge));
+        }
+        if set.members.is_empty() {
+            return Poll::Ready(Err(IpcError::InvalidEndpoint));
+        }
+        for (cap, _) in &set.members {
+            if let Ok(slot) = manager.resolve(cap, Permissions::READ) {
+                if let Some(Some(endpoint)) = manager.endpoints.get(slot) {
+                    endpoint.lock().register_waker(cx.waker());
+                }
+            }
+        }
+        // A message may have landed while we were registering.
+        if let Some(badge) = set.poll(&manager) {
+            return Poll::Ready(Ok(badge));
+        }
+        let expired = this.timer.as_mut().is_some_and(|timer| Pin::new(timer).poll(cx).is_ready());
+        if expired {
+            Poll::Ready(Err(IpcError::TimedOut))
+        } else {
+            Poll::Pending
+        }
+    }
 }
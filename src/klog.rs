@@ -0,0 +1,142 @@
+//! # Kernel Log Policy
+//!
+//! Levels and rate limits for chatty console output. The serial console
+//! runs at 115200 baud, so a line per packet caps the network at serial
+//! speed; output on hot paths goes through `klog!` instead of
+//! `serial_println!` so it can be turned down at runtime.
+//!
+//! ## Levels
+//! Each subsystem has a level (`log.<subsystem>`, 0 = off … 5 = trace), and
+//! a message is printed only at or below it:
+//!
+//! ```text
+//! config set log.net 5     # every frame sent and received
+//! config set log.net 0     # nothing from the network path
+//! ```
+//!
+//! ## Rate limit
+//! Each subsystem prints at most `log.rate_per_s` messages per second
+//! (0 = unlimited). The rest are dropped and counted, and the count is
+//! reported with the first message of the next second.
+//!
+//! ## Trace IDs
+//! A message logged from inside an executor task is prefixed with the
+//! task's trace ID (`[trace 7] …`), shared by every task it spawned, so
+//! the lines of one request or process can be picked out of the log.
+//!
+//! Boot messages and one-off events keep using `serial_println!`.
+
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use crate::interrupts::uptime_ms;
+use crate::{config, serial_println};
+
+/// Message severity, most severe first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+/// Default of `log.rate_per_s`.
+const DEFAULT_RATE_PER_S: u64 = 20;
+
+static RATE_PER_S: AtomicU64 = AtomicU64::new(DEFAULT_RATE_PER_S);
+
+/// A source of log messages with its own level and rate window.
+pub struct Subsystem {
+    name: &'static str,
+    /// Config key of the level.
+    key: &'static str,
+    default: Level,
+    level: AtomicU8,
+    /// Uptime (ms) at which the current one-second window began.
+    window_start: AtomicU64,
+    /// Messages printed in the current window.
+    printed: AtomicU64,
+    /// Messages dropped since the last report.
+    suppressed: AtomicU64,
+}
+
+impl Subsystem {
+    const fn new(name: &'static str, key: &'static str, default: Level) -> Self {
+        Subsystem {
+            name,
+            key,
+            default,
+            level: AtomicU8::new(default as u8),
+            window_start: AtomicU64::new(0),
+            printed: AtomicU64::new(0),
+            suppressed: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether a message at `level` should be printed now. Counts it
+    /// against the rate limit if so, and as suppressed if only the rate
+    /// limit stops it.
+    pub fn admit(&self, level: Level) -> bool {
+        if level as u8 > self.level.load(Ordering::Relaxed) {
+            return false;
+        }
+        let limit = RATE_PER_S.load(Ordering::Relaxed);
+        if limit == 0 {
+            return true;
+        }
+        let now = uptime_ms();
+        if now.saturating_sub(self.window_start.load(Ordering::Relaxed)) >= 1000 {
+            self.window_start.store(now, Ordering::Relaxed);
+            self.printed.store(0, Ordering::Relaxed);
+            let suppressed = self.suppressed.swap(0, Ordering::Relaxed);
+            if suppressed > 0 {
+                serial_println!("[LOG] {} {} messages suppressed", suppressed, self.name);
+            }
+        }
+        if self.printed.fetch_add(1, Ordering::Relaxed) < limit {
+            true
+        } else {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    }
+}
+
+/// The network path: NIC driver, interface polling, UDP echo.
+pub static NET: Subsystem = Subsystem::new("net", "log.net", Level::Info);
+/// The HTTP server's per-request lines.
+pub static HTTP: Subsystem = Subsystem::new("http", "log.http", Level::Info);
+
+static SUBSYSTEMS: &[&Subsystem] = &[&NET, &HTTP];
+
+/// Print a message through a subsystem's level and rate limit:
+/// `klog!(klog::NET, Level::Trace, "[NET RX] {} bytes", len)`.
+#[macro_export]
+macro_rules! klog {
+    ($subsystem:expr, $level:expr, $($arg:tt)*) => {
+        if $subsystem.admit($level) {
+            match $crate::executor::current_trace_id() {
+                0 => { $crate::serial_println!($($arg)*); }
+                trace => { $crate::serial_println!("[trace {}] {}", trace, format_args!($($arg)*)); }
+            }
+        }
+    };
+}
+
+/// Register the `log.*` tunables.
+pub fn init() {
+    config::register("log.rate_per_s", "Messages per second per log subsystem (0 = unlimited)", DEFAULT_RATE_PER_S, 0, 100_000, Some(apply));
+    for subsystem in SUBSYSTEMS {
+        config::register(subsystem.key, "Log level (0 = off, 1 = error … 5 = trace)", subsystem.default as u64, 0, 5, Some(apply));
+    }
+}
+
+/// Apply hook of every `log.*` key: reload them all.
+fn apply(_: u64) {
+    RATE_PER_S.store(config::get_or("log.rate_per_s", DEFAULT_RATE_PER_S), Ordering::Relaxed);
+    for subsystem in SUBSYSTEMS {
+        let level = config::get_or(subsystem.key, subsystem.default as u64);
+        subsystem.level.store(level as u8, Ordering::Relaxed);
+    }
+}
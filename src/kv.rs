@@ -0,0 +1,180 @@
+//! # Key-Value Store
+//!
+//! Small persistent state for WASM services (DHT routing tables, daemon
+//! configuration) that must survive the process being restarted.
+//!
+//! ## Namespaces
+//! Keys live in **namespaces**, one per service name. `open` returns the
+//! existing namespace for a name or creates it, so a service restarted
+//! under the same name sees the data its previous incarnation wrote.
+//!
+//! Access is capability-scoped: a `Storage` capability names one namespace
+//! (`resource_id`), with READ to get and WRITE to put or delete. A process
+//! can't name a namespace it holds no capability for.
+//!
+//! ## Quotas
+//! Each namespace is limited to `kv.max_keys` keys and `kv.max_bytes` of
+//! keys plus values, fixed when the namespace is created. Keys are at most
+//! `MAX_KEY_LEN` bytes and values at most `MAX_VALUE_LEN`.
+//!
+//! ## Backing
+//! Entries are held in kernel memory for now; a later revision writes them
+//! to the block device. The kernel heap never frees, so an overwrite reuses
+//! the old value's buffer when the new value fits in it.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::capability::{Capability, CapabilityType, Permissions};
+use crate::config;
+
+/// Longest key accepted.
+pub const MAX_KEY_LEN: usize = 128;
+/// Longest value accepted.
+pub const MAX_VALUE_LEN: usize = 4096;
+
+/// Identifies a namespace; the `resource_id` of its Storage capabilities.
+pub type Namespace = u64;
+
+/// Errors from store operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KvError {
+    /// The key is not in the namespace.
+    NotFound,
+    /// The key is empty or longer than `MAX_KEY_LEN`, or the value is longer
+    /// than `MAX_VALUE_LEN`.
+    TooLarge,
+    /// The write would take the namespace past its key or byte quota.
+    QuotaExceeded,
+    /// No namespace has this id.
+    NoSuchNamespace,
+}
+
+/// Usage of one namespace, as listed by `list`.
+#[derive(Debug, Clone)]
+pub struct NamespaceInfo {
+    pub id: Namespace,
+    pub name: String,
+    pub keys: usize,
+    pub bytes: usize,
+    pub max_keys: usize,
+    pub max_bytes: usize,
+}
+
+struct Bucket {
+    name: String,
+    entries: BTreeMap<Vec<u8>, Vec<u8>>,
+    /// Key plus value bytes currently stored.
+    bytes: usize,
+    max_keys: usize,
+    max_bytes: usize,
+}
+
+lazy_static! {
+    /// Namespace `n` is `STORE[n - 1]`; namespaces are never removed.
+    static ref STORE: Mutex<Vec<Bucket>> = Mutex::new(Vec::new());
+}
+
+/// Register the `kv.*` quota keys. Call once at boot.
+pub fn register_tunables() {
+    config::register("kv.max_keys", "Keys per key-value namespace", 256, 1, 65_536, None);
+    config::register(
+        "kv.max_bytes",
+        "Key and value bytes per key-value namespace",
+        64 * 1024,
+        1024,
+        16 * 1024 * 1024,
+        None,
+    );
+}
+
+/// The namespace called `name`, created with the current quotas if it
+/// doesn't exist yet.
+pub fn open(name: &str) -> Namespace {
+    let mut store = STORE.lock();
+    if let Some(idx) = store.iter().position(|b| b.name == name) {
+        return idx as Namespace + 1;
+    }
+    store.push(Bucket {
+        name: String::from(name),
+        entries: BTreeMap::new(),
+        bytes: 0,
+        max_keys: config::get_or("kv.max_keys", 256) as usize,
+        max_bytes: config::get_or("kv.max_bytes", 64 * 1024) as usize,
+    });
+    store.len() as Namespace
+}
+
+/// A Storage capability on `ns` carrying `permissions` (READ and/or WRITE).
+pub fn capability(ns: Namespace, permissions: Permissions) -> Capability {
+    Capability::new(CapabilityType::Storage, permissions, ns)
+}
+
+/// Store `value` under `key`, replacing any previous value.
+pub fn put(ns: Namespace, key: &[u8], value: &[u8]) -> Result<(), KvError> {
+    if key.is_empty() || key.len() > MAX_KEY_LEN || value.len() > MAX_VALUE_LEN {
+        return Err(KvError::TooLarge);
+    }
+    let mut store = STORE.lock();
+    let bucket = bucket(&mut store, ns)?;
+    match bucket.entries.get_mut(key) {
+        Some(old) => {
+            let bytes = bucket.bytes - old.len() + value.len();
+            if bytes > bucket.max_bytes {
+                return Err(KvError::QuotaExceeded);
+            }
+            old.clear();
+            old.extend_from_slice(value);
+            bucket.bytes = bytes;
+        }
+        None => {
+            let bytes = bucket.bytes + key.len() + value.len();
+            if bucket.entries.len() >= bucket.max_keys || bytes > bucket.max_bytes {
+                return Err(KvError::QuotaExceeded);
+            }
+            bucket.entries.insert(key.to_vec(), value.to_vec());
+            bucket.bytes = bytes;
+        }
+    }
+    Ok(())
+}
+
+/// Call `f` with the value stored under `key`. The store stays locked
+/// while `f` runs, so the value is borrowed rather than copied.
+pub fn get<R>(ns: Namespace, key: &[u8], f: impl FnOnce(&[u8]) -> R) -> Result<R, KvError> {
+    let mut store = STORE.lock();
+    let bucket = bucket(&mut store, ns)?;
+    bucket.entries.get(key).map(|value| f(value)).ok_or(KvError::NotFound)
+}
+
+/// Remove `key`.
+pub fn delete(ns: Namespace, key: &[u8]) -> Result<(), KvError> {
+    let mut store = STORE.lock();
+    let bucket = bucket(&mut store, ns)?;
+    let value = bucket.entries.remove(key).ok_or(KvError::NotFound)?;
+    bucket.bytes -= key.len() + value.len();
+    Ok(())
+}
+
+/// Usage of every namespace.
+pub fn list() -> Vec<NamespaceInfo> {
+    STORE
+        .lock()
+        .iter()
+        .enumerate()
+        .map(|(idx, b)| NamespaceInfo {
+            id: idx as Namespace + 1,
+            name: b.name.clone(),
+            keys: b.entries.len(),
+            bytes: b.bytes,
+            max_keys: b.max_keys,
+            max_bytes: b.max_bytes,
+        })
+        .collect()
+}
+
+fn bucket(store: &mut [Bucket], ns: Namespace) -> Result<&mut Bucket, KvError> {
+    (ns as usize).checked_sub(1).and_then(|idx| store.get_mut(idx)).ok_or(KvError::NoSuchNamespace)
+}
@@ -31,7 +31,7 @@ use core::sync::atomic::{AtomicUsize, Ordering};
 /// This allocates memory from a static buffer. It never frees memory.
 /// Sufficient for our boot-time WASM demo. A proper allocator
 /// (linked-list or slab) will replace this in a future phase.
-const HEAP_SIZE: usize = 4 * 1024 * 1024; // 4 MiB heap
+const HEAP_SIZE: usize = 8 * 1024 * 1024; // 8 MiB heap; HTTP and P2P sockets take 128 KiB each
 
 #[repr(align(4096))]
 struct AlignedHeap([u8; HEAP_SIZE]);
@@ -69,26 +69,85 @@ unsafe impl GlobalAlloc for BumpAllocator {
 #[global_allocator]
 static ALLOCATOR: BumpAllocator = BumpAllocator;
 
+/// Returns `(used, total)` bytes of the kernel heap.
+pub fn heap_usage() -> (usize, usize) {
+    (HEAP_POS.load(Ordering::Relaxed), HEAP_SIZE)
+}
+
 mod serial;
 mod interrupts;
 mod network;
+mod pci;
+mod virtio_modern;
 pub mod net_interface;
 pub mod net_stack;
+mod rtl8139;
 mod executor;
+mod notification;
 mod p2p;
 mod p2p_transport;
+mod p2p_proto;
+mod p2p_noise;
+mod p2p_sign;
+mod p2p_conn;
+mod p2p_pubsub;
 pub mod p2p_kademlia;
+mod p2p_rpc;
+mod p2p_store;
+mod p2p_blocks;
+mod p2p_maintenance;
+mod p2p_reputation;
+mod p2p_protocols;
+mod p2p_nat;
+mod p2p_relay;
+mod p2p_channel;
+mod p2p_metrics;
+mod addr_book;
+mod admission;
 mod random;
 mod ipc;
 mod memory;
 mod capability;
 mod wasm_runtime;
 mod hal;
+mod vfs;
+mod procfs;
+mod chardev;
+mod block;
+mod partition;
+mod virtio_blk;
+mod virtio_console;
+mod balloon;
+mod json;
+mod ramfs;
+mod guest_agent;
+mod shell;
+mod config;
+mod klog;
+mod process;
+mod shutdown;
+mod composition;
+mod initrd;
+mod cmdline;
+mod services;
+mod kv;
+mod supervisor;
+mod shm;
+mod upgrade;
+mod debugger;
+mod dns;
+mod icmp;
+mod socket_manager;
+mod tcp_monitor;
+mod tls;
+mod http;
+mod mdns;
+mod pcap;
+mod firewall;
+mod neighbor;
 
 use bootloader_api::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-use capability::{CSpace, Capability, CapabilityId, CapabilityType, Permissions};
-use ipc::{IpcManager, Message};
 use x86_64::instructions::port::Port;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -139,6 +198,36 @@ lazy_static::lazy_static! {
     pub static ref EXECUTOR: Mutex<Executor> = Mutex::new(Executor::new());
 }
 
+system! {
+    /// Objects and capabilities present at boot. Checked at compile time;
+    /// see `composition`.
+    const BOOT_SYSTEM {
+        object all_memory = Memory(0): [ALL];
+        object clock = Device(capability::DEVICE_CLOCK): [READ];
+        object rng = Device(capability::DEVICE_RNG): [READ];
+        object network = Device(capability::DEVICE_NETWORK): [CONNECT, LISTEN, SEND, RECV];
+        object net_admin = Device(capability::DEVICE_NET_ADMIN): [WRITE];
+        object disks = Device(capability::DEVICE_BLOCK): [READ, WRITE];
+        object control = Endpoint: [READ, WRITE, GRANT];
+
+        process root { all_memory: [ALL], control: [READ, WRITE, GRANT], net_admin: [WRITE], disks: [READ, WRITE] }
+        // The demo module may inspect its own CSpace via `env.cap_list`
+        // and read the kernel clock and RNG.
+        process hello_world { self: [READ], clock: [READ], rng: [READ] }
+        // Every service started from the initrd manifest; services may
+        // open sockets (`env.socket_*`) and start helpers (`env.spawn`).
+        process service {
+            self: [READ, EXECUTE],
+            clock: [READ],
+            rng: [READ],
+            network: [CONNECT, LISTEN, SEND, RECV],
+        }
+    }
+}
+
+/// Restarts per minute allowed to a crashing manifest service.
+const SERVICE_MAX_RESTARTS: u32 = 5;
+
 fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     // ── Banner ──────────────────────────────────────────────────────
     serial_println!("====================================");
@@ -167,68 +256,170 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
         panic!("[INIT] Failed to get physical memory offset from bootloader!");
     }
 
+    // ── Step 3b: Initial Ramdisk and Command Line ───────────────────
+    // The bootloader maps the ramdisk for us; it stays mapped for the
+    // kernel's lifetime, so its files can be handed out as 'static slices.
+    // It is indexed now, since the command line in it configures the
+    // network, and mounted with the other filesystems below.
+    let initrd_image = boot_info.ramdisk_addr.into_option().map(|addr| {
+        let image = unsafe { core::slice::from_raw_parts(addr as *const u8, boot_info.ramdisk_len as usize) };
+        (initrd::init(image), image.len())
+    });
+    cmdline::init();
+
     // ── Step 4: Initialize Networking ──
     serial_println!("[INIT] Initializing Networking...");
+    klog::init();
+    pcap::init();
+    firewall::init();
+    neighbor::init();
     network::init();
+    EXECUTOR.lock().spawn(Task::new(net_stack::network_task()));
     p2p::init();
+    http::init();
+    mdns::init();
     serial_println!("[INIT] Network initialization complete.");
 
-    // ── Step 5: Initialize Capability Space ─────────────────────────
-    serial_println!("[INIT] Initializing Capability Space (CSpace)...");
-    let mut cspace = CSpace::new();
-
-    // Create a Root capability
-    let root_cap = Capability {
-        id: CapabilityId::new(),
-        cap_type: CapabilityType::Memory,
-        permissions: Permissions::all(),
-        resource_id: 0, 
+    // Idle RX/TX buffers are the first thing to go when memory is needed.
+    memory::register_shrinker(net_interface::shrink_buffer_pool);
+    balloon::init();
+    virtio_blk::init();
+
+    // ── Step 5: Build the Initial System ───────────────────────────
+    // Capability spaces and endpoints come from `BOOT_SYSTEM`, which the
+    // compiler has already checked for dangling grants and excess rights.
+    serial_println!("[INIT] Instantiating initial system composition...");
+    let boot = match composition::instantiate(&BOOT_SYSTEM) {
+        Ok(boot) => boot,
+        Err(composition::CompositionError::Ipc(e)) => panic!("Failed to build initial system: {:?}", e),
     };
-    cspace.insert(root_cap).expect("Failed to insert root cap");
-    serial_println!("[INIT] CSpace: Root capability created.");
+    let root_cspace = boot.cspace_for("root").expect("BOOT_SYSTEM declares root");
+    serial_println!("[INIT] CSpace: root task holds {} capabilities.", root_cspace.len());
 
-    // ── Step 6: Initialize IPC Subsystem ────────────────────────────
-    let mut ipc_manager = IpcManager::new();
-    let ep_slot = ipc_manager.create_endpoint().expect("Failed to create endpoint");
+    // ── Step 6: IPC Subsystem ───────────────────────────────────────
+    let ep_slot = boot.endpoint("control").expect("BOOT_SYSTEM declares control");
     serial_println!("[INIT] IPC: Endpoint created at slot {}", ep_slot);
 
+    // ── Step 6b: Mount Virtual Filesystems ──────────────────────────
+    vfs::VFS
+        .lock()
+        .mount("/proc", alloc::boxed::Box::new(procfs::ProcFs))
+        .expect("Failed to mount /proc");
+    chardev::init();
+    virtio_console::init();
+    vfs::VFS
+        .lock()
+        .mount("/dev", alloc::boxed::Box::new(chardev::DevFs))
+        .expect("Failed to mount /dev");
+    vfs::VFS
+        .lock()
+        .mount("/tmp", alloc::boxed::Box::new(ramfs::RamFs::new()))
+        .expect("Failed to mount /tmp");
+    serial_println!("[INIT] VFS: /proc, /dev and /tmp mounted.");
+
+    // ── Step 6c: Initial Ramdisk ────────────────────────────────────
+    if let Some((files, len)) = initrd_image {
+        vfs::VFS
+            .lock()
+            .mount("/initrd", initrd::filesystem())
+            .expect("Failed to mount /initrd");
+        serial_println!("[INIT] Initrd: {} files ({} bytes) mounted at /initrd.", files, len);
+    } else {
+        serial_println!("[INIT] Initrd: none loaded.");
+    }
+
+    // Host orchestration channel on virtio-serial, or COM2 without one.
+    EXECUTOR.lock().spawn(Task::new(guest_agent::agent_task()));
+
+    // Debug shell on the console.
+    shell::init();
+    debugger::init();
+    EXECUTOR.lock().spawn(Task::new(shell::shell_task()));
+
     // ── Step 7: WASM Runtime Demo ───────────────────────────────────
     serial_println!("[WASM] ── Phase 2: Universal Execution Layer ──");
+    wasm_runtime::register_tunables();
+    kv::register_tunables();
     let wasm_bytes = wasm_runtime::hello_world_wasm();
     serial_println!("[WASM] Hello World module: {} bytes", wasm_bytes.len());
     
-    // Execute WASM
-    match wasm_runtime::execute_wasm("hello_world", wasm_bytes, "main") {
+    // Execute WASM with the capabilities BOOT_SYSTEM grants it.
+    let cspace = boot.cspace_for("hello_world").expect("BOOT_SYSTEM declares hello_world");
+    match wasm_runtime::execute_wasm("hello_world", wasm_bytes, "main", cspace, Default::default()) {
         Ok(state) => { serial_println!("[WASM] Process '{}' exited cleanly.", state.name); },
         Err(e) => { serial_println!("[WASM] Execution failed: {:?}", e); },
     }
 
-    // ── Final Step: Idle Loop with Network Polling ─────────────────
+    // ── Step 8: Services from the Initrd Manifest ───────────────────
+    // The kernel supervises them: a service that crashes is restarted, up
+    // to SERVICE_MAX_RESTARTS times a minute.
+    for service in initrd::services() {
+        let module = match initrd::find(service.module) {
+            Some(module) => module,
+            None => {
+                serial_println!("[INIT] Service '{}': no module '{}' in initrd.", service.name, service.module);
+                continue;
+            }
+        };
+        let mut cspace = boot.cspace_for("service").expect("BOOT_SYSTEM declares service");
+        // Each service keeps its key-value namespace across restarts.
+        let rights = capability::Permissions::READ.union(capability::Permissions::WRITE);
+        let storage = kv::capability(kv::open(service.name), rights);
+        if cspace.insert(storage).is_none() {
+            serial_println!("[INIT] Service '{}': CSpace full; no key-value storage.", service.name);
+        }
+        // Bind-only: without GRANT the reservation can't pass to children.
+        if let Some((budget_us, period_us)) = service.reservation {
+            match executor::create_sched_context(executor::SchedContext::from_micros(budget_us, period_us)) {
+                Ok(master) => {
+                    let cap = master.derive(capability::Permissions::EXECUTE).expect("a new context capability holds GRANT");
+                    if cspace.insert(cap).is_none() {
+                        serial_println!("[INIT] Service '{}': CSpace full; no CPU reservation.", service.name);
+                    }
+                }
+                Err(e) => {
+                    serial_println!("[INIT] Service '{}': CPU reservation refused: {:?}", service.name, e);
+                }
+            }
+        }
+        let spec = supervisor::ChildSpec {
+            name: alloc::string::String::from(service.name),
+            module,
+            entry: alloc::string::String::from(service.entry),
+            cspace,
+            options: wasm_runtime::ProcessOptions { memory_limit: service.memory_limit, ..Default::default() },
+            policy: supervisor::RestartPolicy::OnFailure,
+            max_restarts: SERVICE_MAX_RESTARTS,
+        };
+        if let Err(e) = supervisor::supervise(0, spec, None) {
+            serial_println!("[INIT] Service '{}' failed to start: {:?}", service.name, e);
+        }
+    }
+
+    // ── Final Step: Idle Loop ──────────────────────────────────────
     serial_println!();
     serial_println!("[SUCCESS] Kernel initialized successfully.");
-    serial_println!("[IDLE] Entering network polling loop...");
+    serial_println!("[IDLE] Entering idle loop...");
 
     loop {
-        // Halt CPU until next interrupt (Timer fires at 100Hz)
+        // Halt CPU until next interrupt (Timer fires at 100Hz, or a device
+        // such as the NIC signals)
         x86_64::instructions::hlt();
 
-        // Calculate time from ticks (100Hz = 10ms per tick)
-        // COMPENSATION: Timer seems to run at ~10kHz instead of 100Hz in QEMU/HVF?
-        // Divide by 100 to get roughly real time.
         let ticks = interrupts::get_ticks();
-        let time_ms = (ticks / 100) * 10;
-        
-        // Log heartbeat rarely (every 100*100 ticks = 1s maybe?)
-        if ticks % 10000 == 0 {
-             // serial_println!("[MAIN] Tick: {} Time: {}ms", ticks, time_ms);
-        }
 
-        // Poll the network stack
-        let timestamp = smoltcp::time::Instant::from_millis(time_ms as i64);
-        net_stack::poll_network(timestamp);
-        
-        // Poll the async executor
+        // Poll the async executor (including `net_stack::network_task`)
         EXECUTOR.lock().poll();
+
+        // A task (shell, guest agent) asked to power off.
+        if shutdown::requested() {
+            shutdown::run();
+        }
+
+        // Follow the host's balloon target (cheap when nothing changed).
+        if ticks.is_multiple_of(1000) {
+            balloon::poll();
+        }
     }
 }
 
@@ -241,5 +432,6 @@ fn panic(info: &PanicInfo) -> ! {
     serial_println!();
     serial_println!("!!! KERNEL PANIC !!!");
     serial_println!("{}", info);
+    shutdown::emergency();
     exit_qemu(QemuExitCode::Failed);
 }
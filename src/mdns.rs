@@ -0,0 +1,685 @@
+//! # mDNS Responder
+//!
+//! Advertises the kernel's P2P service on the local link with multicast DNS
+//! and DNS-SD (RFC 6762, RFC 6763), and learns about other kernels doing
+//! the same, so nodes on one LAN or QEMU socket network find each other
+//! without bootstrap addresses.
+//!
+//! ## Records
+//! Each node is the instance `kernel-<id>._kernel-p2p._tcp.local`, where
+//! `<id>` is the first four bytes of its node ID in hex, on the host
+//! `kernel-<id>.local`:
+//! - PTR `_kernel-p2p._tcp.local` → the instance
+//! - SRV instance → host, `P2P_PORT`
+//! - TXT instance → `node=<node ID hex>`, `peer=<peer ID>`
+//! - A host → the current address
+//!
+//! Names are not probed for conflicts; two nodes sharing a 32-bit ID
+//! prefix would also share a name.
+//!
+//! ## Announcing and answering
+//! The records are announced twice, a second apart, whenever the address
+//! changes, and again every `ANNOUNCE_INTERVAL_MS` so peers keep them well
+//! within their TTL. Queries for any of the names are answered on the
+//! multicast group. Known-answer suppression and response delays are not
+//! implemented; the traffic is small.
+//!
+//! ## Discovery
+//! Responses from other instances (announcements or answers to `discover`)
+//! are joined into `Peer`s: the SRV gives the port, the TXT the node ID and
+//! the A record the address. A new peer goes into the address book and the
+//! P2P routing table. A record with TTL 0 (a goodbye) forgets the peer.
+//!
+//! The heap never frees, so the hot path doesn't allocate: messages are
+//! built and parsed in fixed buffers, and only a newly seen peer costs heap.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
+use spin::Mutex;
+use crate::addr_book::ADDRESS_BOOK;
+use crate::capability::Capability;
+use crate::executor::{self, Task};
+use crate::interrupts::uptime_ms;
+use crate::net_stack::{self, SocketError, NETWORK_STACK, P2P_PORT};
+use crate::p2p::{yield_now, P2P_STATE};
+use crate::p2p_kademlia::{NodeId, PeerInfo, ID_SIZE};
+use crate::{serial_println, EXECUTOR};
+
+/// The mDNS IPv4 multicast group.
+pub const MDNS_GROUP: Ipv4Address = Ipv4Address::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+/// The service type the kernel advertises.
+const SERVICE: &str = "_kernel-p2p._tcp.local";
+/// DNS-SD's name for "every service type on the link".
+const SERVICES_META: &str = "_services._dns-sd._udp.local";
+/// TTL of the records we announce.
+const TTL_S: u32 = 120;
+/// Time between periodic announcements.
+const ANNOUNCE_INTERVAL_MS: u64 = 60_000;
+/// Gap between the two announcements after an address change.
+const ANNOUNCE_REPEAT_MS: u64 = 1_000;
+/// How long to wait for an address or a socket before trying again.
+const RETRY_MS: u64 = 1_000;
+/// Peers remembered; the one expiring first is evicted.
+const MAX_PEERS: usize = 32;
+/// Records of each type considered per message.
+const MAX_RECORDS: usize = 4;
+/// Largest message sent or read.
+const MAX_MESSAGE_LEN: usize = 1024;
+/// Longest name, in presentation form.
+const MAX_NAME_LEN: usize = 255;
+/// Longest peer ID accepted from a TXT record.
+const MAX_PEER_ID_LEN: usize = 64;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Top bit of a record's class: replaces cached records of this name and type.
+const CACHE_FLUSH: u16 = 0x8000;
+/// Top bit of a question's class: the asker prefers a unicast reply.
+const UNICAST_RESPONSE: u16 = 0x8000;
+/// Flags of a response: QR and AA.
+const FLAGS_RESPONSE: u16 = 0x8400;
+
+/// Another kernel found on the local link.
+#[derive(Debug, Clone)]
+pub struct Peer {
+    /// Instance name, without the service type.
+    pub instance: String,
+    pub node_id: NodeId,
+    pub peer_id: String,
+    /// Address and P2P port.
+    pub endpoint: IpEndpoint,
+    /// Uptime (ms) at which the records expire.
+    pub expires_ms: u64,
+}
+
+lazy_static! {
+    static ref PEERS: Mutex<Vec<Peer>> = Mutex::new(Vec::new());
+}
+
+/// Set by `discover`; the responder sends a query on its next pass.
+static QUERY_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Start the responder. Call after `p2p::init`, which creates the identity
+/// being advertised.
+pub fn init() {
+    EXECUTOR.lock().spawn(Task::new(responder_task()));
+}
+
+/// Every peer whose records haven't expired.
+pub fn peers() -> Vec<Peer> {
+    let now = uptime_ms();
+    PEERS.lock().iter().filter(|p| p.expires_ms > now).cloned().collect()
+}
+
+/// Ask the link for instances of the service, wait `wait_ms` for answers
+/// and return every known peer.
+pub async fn discover(wait_ms: u64) -> Vec<Peer> {
+    QUERY_PENDING.store(true, Ordering::Release);
+    executor::sleep_ms(wait_ms).await;
+    peers()
+}
+
+// ─── Responder ───────────────────────────────────────────────────────────────
+
+/// The names this node answers for.
+struct Identity {
+    node_id: NodeId,
+    peer_id: String,
+    /// `kernel-<id>._kernel-p2p._tcp.local`
+    instance: String,
+    /// `kernel-<id>.local`
+    host: String,
+}
+
+impl Identity {
+    fn load() -> Option<Identity> {
+        let state = P2P_STATE.lock();
+        let state = state.as_ref()?;
+        let label = crate::p2p::host_label(&state.node_id);
+        Some(Identity {
+            node_id: state.node_id,
+            peer_id: state.peer_id.clone(),
+            instance: format!("{}.{}", label, SERVICE),
+            host: format!("{}.local", label),
+        })
+    }
+}
+
+async fn responder_task() {
+    let Some(identity) = Identity::load() else {
+        serial_println!("[MDNS] No P2P identity to advertise");
+        return;
+    };
+    serial_println!("[MDNS] Advertising {}", identity.instance);
+
+    let mut socket: Option<Capability> = None;
+    // The address the records were last announced for.
+    let mut announced: Option<Ipv4Address> = None;
+    let mut repeats_left = 0;
+    let mut next_announce = 0;
+    let mut buf = [0u8; MAX_MESSAGE_LEN];
+    loop {
+        let address = net_stack::address_config().and_then(|c| c.address).map(|cidr| cidr.address());
+        let Some(address) = address else {
+            executor::sleep_ms(RETRY_MS).await;
+            continue;
+        };
+        if socket.is_none() {
+            socket = open_socket();
+            // A new socket means the stack restarted: announce afresh.
+            announced = None;
+        }
+        let Some(cap) = socket.clone() else {
+            executor::sleep_ms(RETRY_MS).await;
+            continue;
+        };
+
+        let now = uptime_ms();
+        if announced != Some(address) {
+            announced = Some(address);
+            repeats_left = 1;
+            next_announce = now;
+            // Also find the nodes that were here first.
+            QUERY_PENDING.store(true, Ordering::Release);
+        }
+        let mut result = Ok(());
+        if now >= next_announce {
+            result = send(&cap, announcement(&identity, address).bytes());
+            if repeats_left > 0 {
+                repeats_left -= 1;
+                next_announce = now + ANNOUNCE_REPEAT_MS;
+            } else {
+                next_announce = now + ANNOUNCE_INTERVAL_MS;
+            }
+        }
+        if result.is_ok() && QUERY_PENDING.swap(false, Ordering::AcqRel) {
+            result = send(&cap, query().bytes());
+        }
+        while result.is_ok() {
+            let received = match NETWORK_STACK.lock().as_mut() {
+                Some(stack) => stack.recv_from(&cap, &mut buf),
+                None => Err(SocketError::NotFound),
+            };
+            match received {
+                Ok(None) => break,
+                Ok(Some((n, _))) => {
+                    if let Some(reply) = handle(&identity, address, &buf[..n]) {
+                        result = send(&cap, reply.bytes());
+                    }
+                }
+                Err(e) => result = Err(e),
+            }
+        }
+        if result == Err(SocketError::NotFound) {
+            socket = None;
+        }
+        yield_now().await;
+    }
+}
+
+/// A UDP socket bound to the mDNS port; `None` while the stack is down
+/// or out of sockets.
+fn open_socket() -> Option<Capability> {
+    net_stack::udp_bind(MDNS_PORT).ok()
+}
+
+/// Send one message to the group. A full send buffer drops it; mDNS
+/// tolerates loss.
+fn send(cap: &Capability, msg: Option<&[u8]>) -> Result<(), SocketError> {
+    let Some(msg) = msg else { return Ok(()) };
+    let group = IpEndpoint::new(IpAddress::Ipv4(MDNS_GROUP), MDNS_PORT);
+    match NETWORK_STACK.lock().as_mut() {
+        Some(stack) => stack.send_to(cap, msg, group).map(|_| ()),
+        None => Err(SocketError::NotFound),
+    }
+}
+
+/// Act on a received message: the reply to a query about our names, if
+/// any. Responses from other nodes update the peer table.
+fn handle(identity: &Identity, address: Ipv4Address, msg: &[u8]) -> Option<Message> {
+    let flags = read_u16(msg, 2)?;
+    // Only standard queries and responses (opcode 0).
+    if flags & 0x7800 != 0 {
+        return None;
+    }
+    if flags & 0x8000 != 0 {
+        learn(identity, msg);
+        return None;
+    }
+
+    let mut answers = 0;
+    let mut at = 12;
+    for _ in 0..read_u16(msg, 4)? {
+        let (name, next) = read_name(msg, at)?;
+        let qtype = read_u16(msg, next)?;
+        let qclass = read_u16(msg, next + 2)? & !UNICAST_RESPONSE;
+        at = next + 4;
+        if qclass == CLASS_IN || qclass == TYPE_ANY {
+            answers |= matching_records(identity, &name, qtype);
+        }
+    }
+    if answers == 0 {
+        return None;
+    }
+    let mut additional = 0;
+    if answers & INSTANCE_PTR != 0 {
+        additional |= SRV | TXT | A;
+    }
+    if answers & SRV != 0 {
+        additional |= A;
+    }
+    let mut reply = Message::new(FLAGS_RESPONSE);
+    put_records(&mut reply, identity, address, answers, Section::Answer);
+    put_records(&mut reply, identity, address, additional & !answers, Section::Additional);
+    Some(reply)
+}
+
+// ─── Our Records ─────────────────────────────────────────────────────────────
+
+/// `_services._dns-sd._udp.local` PTR → the service type.
+const SERVICE_PTR: u8 = 1 << 0;
+/// Service type PTR → our instance.
+const INSTANCE_PTR: u8 = 1 << 1;
+const SRV: u8 = 1 << 2;
+const TXT: u8 = 1 << 3;
+const A: u8 = 1 << 4;
+
+/// Which of our records answer a question for `name` of type `qtype`.
+fn matching_records(identity: &Identity, name: &Name, qtype: u16) -> u8 {
+    let is = |rtype| qtype == rtype || qtype == TYPE_ANY;
+    if name.is(SERVICES_META) && is(TYPE_PTR) {
+        SERVICE_PTR
+    } else if name.is(SERVICE) && is(TYPE_PTR) {
+        INSTANCE_PTR
+    } else if name.is(&identity.instance) {
+        (if is(TYPE_SRV) { SRV } else { 0 }) | (if is(TYPE_TXT) { TXT } else { 0 })
+    } else if name.is(&identity.host) && is(TYPE_A) {
+        A
+    } else {
+        0
+    }
+}
+
+/// Every record, unsolicited.
+fn announcement(identity: &Identity, address: Ipv4Address) -> Message {
+    let mut msg = Message::new(FLAGS_RESPONSE);
+    put_records(&mut msg, identity, address, INSTANCE_PTR | SRV | TXT | A, Section::Answer);
+    msg
+}
+
+/// A question for every instance of the service.
+fn query() -> Message {
+    let mut msg = Message::new(0);
+    msg.put_name(SERVICE);
+    msg.put_u16(TYPE_PTR);
+    msg.put_u16(CLASS_IN);
+    msg.count(QD_COUNT);
+    msg
+}
+
+fn put_records(msg: &mut Message, identity: &Identity, address: Ipv4Address, records: u8, section: Section) {
+    let count = match section {
+        Section::Answer => AN_COUNT,
+        Section::Additional => AR_COUNT,
+    };
+    // PTR records are shared between nodes; the others are ours alone.
+    if records & SERVICE_PTR != 0 {
+        msg.record(count, SERVICES_META, TYPE_PTR, 0, |m| m.put_name(SERVICE));
+    }
+    if records & INSTANCE_PTR != 0 {
+        msg.record(count, SERVICE, TYPE_PTR, 0, |m| m.put_name(&identity.instance));
+    }
+    if records & SRV != 0 {
+        msg.record(count, &identity.instance, TYPE_SRV, CACHE_FLUSH, |m| {
+            m.put_u16(0); // priority
+            m.put_u16(0); // weight
+            m.put_u16(P2P_PORT);
+            m.put_name(&identity.host);
+        });
+    }
+    if records & TXT != 0 {
+        msg.record(count, &identity.instance, TYPE_TXT, CACHE_FLUSH, |m| {
+            let mut node = [0u8; 5 + 2 * ID_SIZE];
+            node[..5].copy_from_slice(b"node=");
+            for (i, byte) in identity.node_id.0.iter().enumerate() {
+                node[5 + 2 * i] = HEX[usize::from(byte >> 4)];
+                node[6 + 2 * i] = HEX[usize::from(byte & 0xF)];
+            }
+            m.put_string(&[&node]);
+            m.put_string(&[b"peer=", identity.peer_id.as_bytes()]);
+        });
+    }
+    if records & A != 0 {
+        msg.record(count, &identity.host, TYPE_A, CACHE_FLUSH, |m| m.put(address.as_bytes()));
+    }
+}
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+// ─── Peers ───────────────────────────────────────────────────────────────────
+
+/// The parts of other instances' records a message carried.
+struct Records {
+    /// Instance, target host, port, TTL.
+    srv: [Option<(Name, Name, u16, u32)>; MAX_RECORDS],
+    /// Instance, node ID, peer ID.
+    txt: [Option<(Name, NodeId, PeerId)>; MAX_RECORDS],
+    /// Host, address.
+    a: [Option<(Name, Ipv4Address)>; MAX_RECORDS],
+}
+
+struct PeerId {
+    bytes: [u8; MAX_PEER_ID_LEN],
+    len: usize,
+}
+
+/// Join the SRV, TXT and A records of a response into peers.
+fn learn(identity: &Identity, msg: &[u8]) -> Option<()> {
+    let mut records = Records { srv: Default::default(), txt: Default::default(), a: Default::default() };
+    let mut at = 12;
+    for _ in 0..read_u16(msg, 4)? {
+        at = read_name(msg, at)?.1 + 4;
+    }
+    let count = read_u16(msg, 6)? as usize + read_u16(msg, 8)? as usize + read_u16(msg, 10)? as usize;
+    for _ in 0..count {
+        let (name, next) = read_name(msg, at)?;
+        let rtype = read_u16(msg, next)?;
+        let ttl = u32::from_be_bytes(msg.get(next + 4..next + 8)?.try_into().ok()?);
+        let data_at = next + 10;
+        let data = msg.get(data_at..data_at + usize::from(read_u16(msg, next + 8)?))?;
+        at = data_at + data.len();
+        match rtype {
+            TYPE_SRV if name.is_instance() => {
+                let port = read_u16(data, 4)?;
+                let (host, _) = read_name(msg, data_at + 6)?;
+                insert(&mut records.srv, (name, host, port, ttl));
+            }
+            TYPE_TXT if name.is_instance() => {
+                if let Some((node_id, peer_id)) = parse_txt(data) {
+                    insert(&mut records.txt, (name, node_id, peer_id));
+                }
+            }
+            TYPE_A if data.len() == 4 => insert(&mut records.a, (name, Ipv4Address::from_bytes(data))),
+            _ => {}
+        }
+    }
+
+    let now = uptime_ms();
+    for (instance, host, port, ttl) in records.srv.iter().flatten() {
+        let Some((_, node_id, peer_id)) = records.txt.iter().flatten().find(|(name, ..)| name == instance) else {
+            continue;
+        };
+        if *node_id == identity.node_id {
+            continue;
+        }
+        if *ttl == 0 {
+            forget(node_id);
+            continue;
+        }
+        let Some((_, address)) = records.a.iter().flatten().find(|(name, _)| name == host) else {
+            continue;
+        };
+        let endpoint = IpEndpoint::new(IpAddress::Ipv4(*address), *port);
+        remember(instance, *node_id, peer_id.as_str()?, endpoint, now + u64::from(*ttl) * 1000);
+    }
+    Some(())
+}
+
+fn insert<T>(slots: &mut [Option<T>; MAX_RECORDS], record: T) {
+    if let Some(slot) = slots.iter_mut().find(|s| s.is_none()) {
+        *slot = Some(record);
+    }
+}
+
+/// The node and peer IDs from a TXT record's `node=` and `peer=` strings.
+fn parse_txt(data: &[u8]) -> Option<(NodeId, PeerId)> {
+    let mut node_id = None;
+    let mut peer_id = None;
+    let mut at = 0;
+    while at < data.len() {
+        let len = usize::from(data[at]);
+        let entry = data.get(at + 1..at + 1 + len)?;
+        at += 1 + len;
+        if let Some(hex) = entry.strip_prefix(b"node=") {
+            node_id = parse_node_id(hex);
+        } else if let Some(id) = entry.strip_prefix(b"peer=") {
+            if id.len() <= MAX_PEER_ID_LEN {
+                let mut bytes = [0u8; MAX_PEER_ID_LEN];
+                bytes[..id.len()].copy_from_slice(id);
+                peer_id = Some(PeerId { bytes, len: id.len() });
+            }
+        }
+    }
+    Some((node_id?, peer_id?))
+}
+
+fn parse_node_id(hex: &[u8]) -> Option<NodeId> {
+    if hex.len() != 2 * ID_SIZE {
+        return None;
+    }
+    let digit = |c: u8| (c as char).to_digit(16).map(|d| d as u8);
+    let mut id = [0u8; ID_SIZE];
+    for (byte, pair) in id.iter_mut().zip(hex.chunks(2)) {
+        *byte = digit(pair[0])? << 4 | digit(pair[1])?;
+    }
+    Some(NodeId(id))
+}
+
+impl PeerId {
+    fn as_str(&self) -> Option<&str> {
+        core::str::from_utf8(&self.bytes[..self.len]).ok()
+    }
+}
+
+/// Add or refresh a peer. Only a new one allocates.
+fn remember(instance: &Name, node_id: NodeId, peer_id: &str, endpoint: IpEndpoint, expires_ms: u64) {
+    let now = uptime_ms();
+    ADDRESS_BOOK.lock().record_peer(node_id, endpoint, now);
+    {
+        let mut peers = PEERS.lock();
+        if let Some(peer) = peers.iter_mut().find(|p| p.node_id == node_id) {
+            peer.endpoint = endpoint;
+            peer.expires_ms = expires_ms;
+            return;
+        }
+        if peers.len() >= MAX_PEERS {
+            if let Some(idx) = peers.iter().enumerate().min_by_key(|(_, p)| p.expires_ms).map(|(idx, _)| idx) {
+                peers.swap_remove(idx);
+            }
+        }
+        let label = instance.as_str().split('.').next().unwrap_or_default();
+        peers.push(Peer {
+            instance: String::from(label),
+            node_id,
+            peer_id: String::from(peer_id),
+            endpoint,
+            expires_ms,
+        });
+    }
+    serial_println!("[MDNS] Found {} at {}", peer_id, endpoint);
+    let last_seen = uptime_ms();
+    crate::p2p::add_peer(PeerInfo { node_id, peer_id_str: String::from(peer_id), endpoint: Some(endpoint), last_seen });
+}
+
+/// Drop a peer that said goodbye.
+fn forget(node_id: &NodeId) {
+    let mut peers = PEERS.lock();
+    if let Some(idx) = peers.iter().position(|p| p.node_id == *node_id) {
+        let peer = peers.swap_remove(idx);
+        serial_println!("[MDNS] {} left", peer.peer_id);
+    }
+}
+
+// ─── Wire Format (RFC 1035, RFC 6762) ────────────────────────────────────────
+
+const QD_COUNT: usize = 4;
+const AN_COUNT: usize = 6;
+const AR_COUNT: usize = 10;
+
+enum Section {
+    Answer,
+    Additional,
+}
+
+/// A message built in place. Anything that doesn't fit marks it as
+/// overflowed, and an overflowed message isn't sent.
+struct Message {
+    buf: [u8; MAX_MESSAGE_LEN],
+    len: usize,
+    overflow: bool,
+}
+
+impl Message {
+    fn new(flags: u16) -> Message {
+        let mut msg = Message { buf: [0; MAX_MESSAGE_LEN], len: 12, overflow: false };
+        msg.buf[2..4].copy_from_slice(&flags.to_be_bytes());
+        msg
+    }
+
+    fn bytes(&self) -> Option<&[u8]> {
+        (!self.overflow).then_some(&self.buf[..self.len])
+    }
+
+    fn put(&mut self, bytes: &[u8]) {
+        match self.buf.get_mut(self.len..self.len + bytes.len()) {
+            Some(dst) => {
+                dst.copy_from_slice(bytes);
+                self.len += bytes.len();
+            }
+            None => self.overflow = true,
+        }
+    }
+
+    fn put_u16(&mut self, value: u16) {
+        self.put(&value.to_be_bytes());
+    }
+
+    /// An uncompressed name.
+    fn put_name(&mut self, name: &str) {
+        for label in name.split('.') {
+            self.put(&[label.len() as u8]);
+            self.put(label.as_bytes());
+        }
+        self.put(&[0]);
+    }
+
+    /// One length-prefixed TXT string, concatenated from `parts`.
+    fn put_string(&mut self, parts: &[&[u8]]) {
+        let len: usize = parts.iter().map(|p| p.len()).sum();
+        self.put(&[len.min(255) as u8]);
+        for part in parts {
+            self.put(part);
+        }
+    }
+
+    /// Bump the header count at `offset`.
+    fn count(&mut self, offset: usize) {
+        let count = u16::from_be_bytes([self.buf[offset], self.buf[offset + 1]]) + 1;
+        self.buf[offset..offset + 2].copy_from_slice(&count.to_be_bytes());
+    }
+
+    /// A record whose data `data` writes, counted at `count`.
+    fn record(&mut self, count: usize, name: &str, rtype: u16, flags: u16, data: impl FnOnce(&mut Message)) {
+        self.put_name(name);
+        self.put_u16(rtype);
+        self.put_u16(CLASS_IN | flags);
+        self.put(&TTL_S.to_be_bytes());
+        let len_at = self.len;
+        self.put_u16(0);
+        data(self);
+        if !self.overflow {
+            let len = (self.len - len_at - 2) as u16;
+            self.buf[len_at..len_at + 2].copy_from_slice(&len.to_be_bytes());
+            self.count(count);
+        }
+    }
+}
+
+/// A name read from a message, lower-cased, in presentation form.
+struct Name {
+    bytes: [u8; MAX_NAME_LEN],
+    len: usize,
+}
+
+impl Default for Name {
+    fn default() -> Name {
+        Name { bytes: [0; MAX_NAME_LEN], len: 0 }
+    }
+}
+
+impl PartialEq for Name {
+    fn eq(&self, other: &Name) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Name {
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or_default()
+    }
+
+    fn is(&self, name: &str) -> bool {
+        self.as_str() == name
+    }
+
+    /// Whether this names an instance of our service type.
+    fn is_instance(&self) -> bool {
+        self.as_str()
+            .split_once('.')
+            .is_some_and(|(label, service)| !label.is_empty() && service == SERVICE)
+    }
+}
+
+fn read_u16(msg: &[u8], at: usize) -> Option<u16> {
+    msg.get(at..at + 2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+}
+
+/// The name at `at`, following compression pointers, and the offset just
+/// past it. Names with non-ASCII bytes or over `MAX_NAME_LEN` are rejected.
+fn read_name(msg: &[u8], mut at: usize) -> Option<(Name, usize)> {
+    let mut name = Name::default();
+    let mut end = None;
+    // Pointers must go backwards, which rules out loops.
+    let mut limit = at;
+    loop {
+        let len = *msg.get(at)?;
+        match len {
+            0 => break,
+            l if l & 0xC0 == 0xC0 => {
+                let target = usize::from(read_u16(msg, at)? & 0x3FFF);
+                if target >= limit {
+                    return None;
+                }
+                end.get_or_insert(at + 2);
+                limit = target;
+                at = target;
+            }
+            l if l & 0xC0 != 0 => return None,
+            l => {
+                let label = msg.get(at + 1..at + 1 + usize::from(l))?;
+                if name.len > 0 {
+                    *name.bytes.get_mut(name.len)? = b'.';
+                    name.len += 1;
+                }
+                let dst = name.bytes.get_mut(name.len..name.len + label.len())?;
+                if !label.is_ascii() {
+                    return None;
+                }
+                dst.copy_from_slice(label);
+                dst.make_ascii_lowercase();
+                name.len += label.len();
+                at += 1 + label.len();
+            }
+        }
+    }
+    Some((name, end.unwrap_or(at + 1)))
+}
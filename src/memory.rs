@@ -13,10 +13,15 @@
 //! physical memory are usable. We iterate through it and hand out frames
 //! one at a time. This is a simple "bump allocator" — fast but cannot
 //! reclaim freed frames. A bitmap or buddy allocator will replace this later.
+//!
+//! The DMA allocator additionally keeps a list of single frames returned
+//! with `free_frame`, and a set of *shrinkers* — caches that can give frames
+//! back when memory is needed elsewhere (e.g., by the balloon driver).
 
 use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
 use x86_64::structures::paging::{FrameAllocator, PhysFrame, Size4KiB, OffsetPageTable, PageTable};
 use x86_64::{PhysAddr, VirtAddr};
+use alloc::vec::Vec;
 use lazy_static::lazy_static;
 use spin::Mutex;
 
@@ -24,8 +29,16 @@ lazy_static! {
     static ref MEMORY_REGIONS: Mutex<Option<&'static MemoryRegions>> = Mutex::new(None);
     // Track where we are allocating DMA memory from (phys addr)
     static ref DMA_ALLOCATOR_STATE: Mutex<Option<PhysAddr>> = Mutex::new(None);
+    // Single frames handed back with `free_frame` (e.g., by a deflating balloon).
+    static ref RECLAIMED_FRAMES: Mutex<Vec<PhysAddr>> = Mutex::new(Vec::new());
+    // Callbacks that release cached frames under memory pressure.
+    static ref SHRINKERS: Mutex<Vec<Shrinker>> = Mutex::new(Vec::new());
 }
 
+/// A cache-shrinking callback: asked to free up to `wanted` frames (via
+/// `free_frame`), it returns how many it actually freed.
+pub type Shrinker = fn(wanted: usize) -> usize;
+
 pub fn init_regions(regions: &'static MemoryRegions) {
     *MEMORY_REGIONS.lock() = Some(regions);
 }
@@ -34,6 +47,13 @@ pub fn init_regions(regions: &'static MemoryRegions) {
 /// This implementation steals memory from the *end* of the largest usable region
 /// to avoid conflict with the main frame allocator (which starts from the beginning).
 pub fn allocate_contiguous_frames(pages: usize) -> Option<PhysAddr> {
+    // Single frames are served from the reclaimed list first.
+    if pages == 1 {
+        if let Some(frame) = RECLAIMED_FRAMES.lock().pop() {
+            return Some(frame);
+        }
+    }
+
     let mut state = DMA_ALLOCATOR_STATE.lock();
     
     // If not initialized, find the suitable region end
@@ -73,6 +93,56 @@ pub fn allocate_contiguous_frames(pages: usize) -> Option<PhysAddr> {
     None
 }
 
+/// Return a single 4 KiB frame obtained from `allocate_contiguous_frames`.
+///
+/// The frame is reused by later single-frame allocations; the caller must
+/// make sure no device still DMAs into it.
+pub fn free_frame(frame: PhysAddr) {
+    RECLAIMED_FRAMES.lock().push(frame);
+}
+
+/// Register a cache that can give frames back under memory pressure.
+pub fn register_shrinker(shrinker: Shrinker) {
+    SHRINKERS.lock().push(shrinker);
+}
+
+/// Ask registered caches to free up to `wanted` frames. Returns the number
+/// actually freed.
+pub fn shrink_caches(wanted: usize) -> usize {
+    // Copy the list so shrinkers may allocate or register without deadlocking.
+    let shrinkers: Vec<Shrinker> = SHRINKERS.lock().clone();
+    let mut freed = 0;
+    for shrinker in shrinkers {
+        if freed >= wanted {
+            break;
+        }
+        freed += shrinker(wanted - freed);
+    }
+    freed
+}
+
+/// Number of 4 KiB frames still available to `allocate_contiguous_frames`.
+///
+/// Returns 0 before `init_regions` has been called.
+pub fn free_dma_frames() -> usize {
+    let reclaimed = RECLAIMED_FRAMES.lock().len();
+    let state = DMA_ALLOCATOR_STATE.lock();
+    let regions = MEMORY_REGIONS.lock();
+    let region = match *regions {
+        Some(regions) => regions.iter()
+            .filter(|r| r.kind == MemoryRegionKind::Usable)
+            .max_by_key(|r| r.end - r.start),
+        None => None,
+    };
+    match region {
+        Some(region) => {
+            let end = state.map(|addr| addr.as_u64()).unwrap_or(region.end);
+            (end.saturating_sub(region.start) / 4096) as usize + reclaimed
+        }
+        None => reclaimed,
+    }
+}
+
 /// Initialize a new OffsetPageTable.
 ///
 /// This allows us to access arbitrary physical frames by adding `physical_memory_offset`
@@ -0,0 +1,277 @@
+//! # Neighbor Cache
+//!
+//! smoltcp keeps its ARP cache inside each `Interface`, out of reach, so
+//! this module keeps a view of it from the ARP traffic the NICs see, adds
+//! static entries, and reports addresses that never resolve.
+//!
+//! ## Learned entries
+//! The sender of every ARP packet a NIC receives is recorded, like smoltcp
+//! does for the ones it accepts, and forgotten after `ENTRY_LIFETIME_MS`,
+//! smoltcp's own lifetime. The table holds `MAX_ENTRIES`; a full table
+//! replaces its oldest learned entry.
+//!
+//! ## Static entries
+//! A static entry answers ARP requests for its address without any
+//! traffic: the driver catches the request on its way out and hands
+//! smoltcp a reply instead (`static_reply`), so smoltcp's cache is filled
+//! even when the neighbor never answers. They come from the shell's `arp
+//! add`, or `arp=<ip>@<mac>[,<ip>@<mac>…]` on the command line; QEMU's user
+//! network gateway is `arp=10.0.2.2@52:55:0a:00:02:02`.
+//!
+//! ## Resolution failures
+//! A request that sees no answer within `net.arp.resolve_timeout_ms` is a
+//! failure: it is logged, counted, and published as an
+//! `EVENT_NET_NEIGHBOR_FAILED` message (see `net_stack::subscribe_events`).
+//!
+//! When an interface gets an address the stack also announces it with a
+//! gratuitous ARP (`announcement`), so neighbors that cache it, SLIRP among
+//! them, can reach the node before it has sent them anything.
+
+use alloc::vec::Vec;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use smoltcp::wire::{
+    ArpOperation, ArpPacket, ArpRepr, EthernetAddress, EthernetFrame, EthernetProtocol, Ipv4Address,
+};
+use spin::Mutex;
+use crate::interrupts::uptime_ms;
+use crate::{cmdline, config, serial_println};
+
+/// Entries the table holds, static and learned.
+pub const MAX_ENTRIES: usize = 64;
+/// How long a learned entry lasts; smoltcp's `neighbor::ENTRY_LIFETIME`.
+const ENTRY_LIFETIME_MS: u64 = 60_000;
+/// Default of `net.arp.resolve_timeout_ms`.
+const DEFAULT_RESOLVE_TIMEOUT_MS: u64 = 3_000;
+/// Ethernet header plus an Ethernet/IPv4 ARP packet.
+pub const ARP_FRAME_LEN: usize = 14 + 28;
+
+/// How an entry got into the table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    Static,
+    /// Learned from ARP at this uptime (ms).
+    Learned(u64),
+}
+
+/// One IPv4 neighbor.
+#[derive(Debug, Clone, Copy)]
+pub struct Entry {
+    pub address: Ipv4Address,
+    pub mac: EthernetAddress,
+    /// The NIC it was seen by; the broadcast address for static entries,
+    /// which apply to every NIC.
+    pub nic: EthernetAddress,
+    pub origin: Origin,
+}
+
+/// Why a static entry was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NeighborError {
+    /// Every entry is static.
+    TableFull,
+    /// The hardware address isn't a unicast one.
+    InvalidAddress,
+    /// No static entry for that address.
+    NotFound,
+}
+
+/// An ARP request sent and not yet answered.
+struct Pending {
+    address: Ipv4Address,
+    nic: EthernetAddress,
+    since_ms: u64,
+}
+
+lazy_static! {
+    static ref ENTRIES: Mutex<Vec<Entry>> = Mutex::new(Vec::with_capacity(MAX_ENTRIES));
+    static ref PENDING: Mutex<Vec<Pending>> = Mutex::new(Vec::with_capacity(MAX_ENTRIES));
+}
+
+static FAILURES: AtomicU64 = AtomicU64::new(0);
+
+/// Add the static entries from the command line.
+pub fn init() {
+    let Some(value) = cmdline::get("arp") else { return };
+    for item in value.split(',') {
+        let parsed = item.split_once('@').and_then(|(ip, mac)| {
+            Some((crate::dns::parse_ipv4(ip)?, EthernetAddress::from_str(mac).ok()?))
+        });
+        match parsed.map(|(ip, mac)| add_static(ip, mac)) {
+            Some(Ok(())) => {}
+            _ => { serial_println!("[ARP] Ignoring arp={}; expected <ip>@<mac>.", item); }
+        }
+    }
+}
+
+// ─── Table ───────────────────────────────────────────────────────────────────
+
+/// Add or replace the static entry for `address`.
+pub fn add_static(address: Ipv4Address, mac: EthernetAddress) -> Result<(), NeighborError> {
+    if !mac.is_unicast() {
+        return Err(NeighborError::InvalidAddress);
+    }
+    let entry = Entry { address, mac, nic: EthernetAddress::BROADCAST, origin: Origin::Static };
+    let mut entries = ENTRIES.lock();
+    entries.retain(|e| e.address != address);
+    if entries.len() >= MAX_ENTRIES && !evict_learned(&mut entries) {
+        return Err(NeighborError::TableFull);
+    }
+    entries.push(entry);
+    Ok(())
+}
+
+/// Remove the static entry for `address`.
+pub fn remove_static(address: Ipv4Address) -> Result<(), NeighborError> {
+    let mut entries = ENTRIES.lock();
+    let idx = entries
+        .iter()
+        .position(|e| e.address == address && e.origin == Origin::Static)
+        .ok_or(NeighborError::NotFound)?;
+    entries.swap_remove(idx);
+    Ok(())
+}
+
+/// Every entry, static ones first, without expired learned ones.
+pub fn entries() -> Vec<Entry> {
+    let now = uptime_ms();
+    let mut entries = ENTRIES.lock();
+    entries.retain(|e| !expired(e, now));
+    let mut list = entries.clone();
+    list.sort_by_key(|e| (e.origin != Origin::Static, u32::from_be_bytes(e.address.0)));
+    list
+}
+
+/// Addresses that never resolved since boot.
+pub fn failures() -> u64 {
+    FAILURES.load(Ordering::Relaxed)
+}
+
+fn expired(entry: &Entry, now: u64) -> bool {
+    matches!(entry.origin, Origin::Learned(at) if now.saturating_sub(at) >= ENTRY_LIFETIME_MS)
+}
+
+/// Drop the oldest learned entry. Returns false if all are static.
+fn evict_learned(entries: &mut Vec<Entry>) -> bool {
+    let oldest = entries
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, e)| match e.origin {
+            Origin::Learned(at) => Some((idx, at)),
+            Origin::Static => None,
+        })
+        .min_by_key(|&(_, at)| at);
+    match oldest {
+        Some((idx, _)) => {
+            entries.swap_remove(idx);
+            true
+        }
+        None => false,
+    }
+}
+
+// ─── Driver Hooks ────────────────────────────────────────────────────────────
+
+fn parse(frame: &[u8]) -> Option<(ArpOperation, EthernetAddress, Ipv4Address, Ipv4Address)> {
+    let frame = EthernetFrame::new_checked(frame).ok()?;
+    if frame.ethertype() != EthernetProtocol::Arp {
+        return None;
+    }
+    match ArpRepr::parse(&ArpPacket::new_checked(frame.payload()).ok()?).ok()? {
+        ArpRepr::EthernetIpv4 { operation, source_hardware_addr, source_protocol_addr, target_protocol_addr, .. } => {
+            Some((operation, source_hardware_addr, source_protocol_addr, target_protocol_addr))
+        }
+        _ => None,
+    }
+}
+
+/// Learn from an ARP packet the NIC with address `nic` received.
+pub fn observe_rx(nic: EthernetAddress, frame: &[u8]) {
+    let Some((_, mac, address, _)) = parse(frame) else { return };
+    if !mac.is_unicast() || address.is_unspecified() {
+        return;
+    }
+    PENDING.lock().retain(|p| p.address != address);
+    let now = uptime_ms();
+    let mut entries = ENTRIES.lock();
+    match entries.iter_mut().find(|e| e.address == address) {
+        Some(entry) if entry.origin == Origin::Static => {}
+        Some(entry) => *entry = Entry { mac, nic, origin: Origin::Learned(now), ..*entry },
+        None => {
+            if entries.len() < MAX_ENTRIES || evict_learned(&mut entries) {
+                entries.push(Entry { address, mac, nic, origin: Origin::Learned(now) });
+            }
+        }
+    }
+}
+
+/// Look at an outgoing frame from the NIC with address `nic`. For an ARP
+/// request for a static entry, write the reply smoltcp should receive to
+/// `reply` and return true: the request must not be sent. Other requests
+/// are remembered until answered.
+pub fn static_reply(nic: EthernetAddress, frame: &[u8], reply: &mut [u8; ARP_FRAME_LEN]) -> bool {
+    let Some((ArpOperation::Request, requester, source, target)) = parse(frame) else { return false };
+    if source == target {
+        // Our own announcement.
+        return false;
+    }
+    let mac = ENTRIES.lock().iter().find(|e| e.address == target && e.origin == Origin::Static).map(|e| e.mac);
+    let Some(mac) = mac else {
+        let mut pending = PENDING.lock();
+        if !pending.iter().any(|p| p.address == target && p.nic == nic) && pending.len() < MAX_ENTRIES {
+            pending.push(Pending { address: target, nic, since_ms: uptime_ms() });
+        }
+        return false;
+    };
+    let answer = ArpRepr::EthernetIpv4 {
+        operation: ArpOperation::Reply,
+        source_hardware_addr: mac,
+        source_protocol_addr: target,
+        target_hardware_addr: requester,
+        target_protocol_addr: source,
+    };
+    emit(reply, requester, mac, &answer);
+    true
+}
+
+/// A gratuitous ARP announcing that `address` is at `mac`, for `mac`'s NIC
+/// to broadcast.
+pub fn announcement(mac: EthernetAddress, address: Ipv4Address, frame: &mut [u8; ARP_FRAME_LEN]) {
+    let announce = ArpRepr::EthernetIpv4 {
+        operation: ArpOperation::Request,
+        source_hardware_addr: mac,
+        source_protocol_addr: address,
+        target_hardware_addr: EthernetAddress([0; 6]),
+        target_protocol_addr: address,
+    };
+    emit(frame, EthernetAddress::BROADCAST, mac, &announce);
+}
+
+fn emit(buffer: &mut [u8; ARP_FRAME_LEN], dst: EthernetAddress, src: EthernetAddress, arp: &ArpRepr) {
+    let mut frame = EthernetFrame::new_unchecked(&mut buffer[..]);
+    frame.set_dst_addr(dst);
+    frame.set_src_addr(src);
+    frame.set_ethertype(EthernetProtocol::Arp);
+    arp.emit(&mut ArpPacket::new_unchecked(frame.payload_mut()));
+}
+
+/// Give up on requests from the NIC with address `nic` that went
+/// unanswered for `net.arp.resolve_timeout_ms`, calling `failed` with each
+/// address. Called on every poll.
+pub fn expire(nic: EthernetAddress, mut failed: impl FnMut(Ipv4Address)) {
+    let mut pending = PENDING.lock();
+    if pending.is_empty() {
+        return;
+    }
+    let now = uptime_ms();
+    let timeout = config::get_or("net.arp.resolve_timeout_ms", DEFAULT_RESOLVE_TIMEOUT_MS);
+    pending.retain(|p| {
+        if p.nic != nic || now.saturating_sub(p.since_ms) < timeout {
+            return true;
+        }
+        FAILURES.fetch_add(1, Ordering::Relaxed);
+        failed(p.address);
+        false
+    });
+}
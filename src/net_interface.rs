@@ -1,23 +1,351 @@
 use smoltcp::phy::{Device, DeviceCapabilities, Medium, RxToken, TxToken, Checksum};
 use smoltcp::time::Instant;
+use smoltcp::wire::EthernetAddress;
 use virtio_drivers::device::net::VirtIONetRaw;
 use virtio_drivers::Hal; // Import Hal trait to call dma_alloc
 use crate::hal::VirtioHal;
-use crate::network::LegacyTransport;
-use crate::serial_println;
+use crate::network::{VirtioLocation, VirtioTransport};
+use crate::rtl8139::{Rtl8139Device, Rtl8139RxToken, Rtl8139TxToken};
+use crate::{klog, serial_println};
+use alloc::boxed::Box;
 use alloc::vec::Vec;
 use spin::Mutex;
 use lazy_static::lazy_static;
 use core::ptr::NonNull;
 use core::slice;
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+use crate::klog::Level;
+use virtio_drivers::transport::Transport;
 
 lazy_static! {
     static ref BUFFER_POOL: Mutex<Vec<DmaBuffer>> = Mutex::new(Vec::new());
+    /// Buffers that frames spanning several RX buffers are copied into.
+    static ref MERGE_POOL: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
 }
 
+const PAGE_SIZE: usize = 4096;
 const RX_BUFFER_PAGES: usize = 1; // 4096 bytes
 const QUEUE_SIZE: usize = 256;
 const VIRTIO_HEADER_LEN: usize = 10; // Legacy Header (no MRG_RXBUF)
+/// A modern (VERSION_1) device, or one with MRG_RXBUF, has the 2-byte
+/// `num_buffers` field as well.
+const VIRTIO_MERGEABLE_HEADER_LEN: usize = 12;
+/// TX descriptors pending this long without a single completion mean the device is wedged.
+pub(crate) const WEDGE_TIMEOUT_MS: i64 = 5000;
+
+// ─── Statistics ──────────────────────────────────────────────────────────────
+//
+// Kept in statics rather than in the device so they add up across resets,
+// and shared by the drivers.
+
+pub(crate) static RX_PACKETS: AtomicU64 = AtomicU64::new(0);
+pub(crate) static RX_BYTES: AtomicU64 = AtomicU64::new(0);
+pub(crate) static RX_DROPPED: AtomicU64 = AtomicU64::new(0);
+pub(crate) static TX_PACKETS: AtomicU64 = AtomicU64::new(0);
+pub(crate) static TX_BYTES: AtomicU64 = AtomicU64::new(0);
+pub(crate) static TX_DROPPED: AtomicU64 = AtomicU64::new(0);
+pub(crate) static TX_QUEUE_FULL: AtomicU64 = AtomicU64::new(0);
+
+/// Frame counters of all NICs since boot. Byte counts are Ethernet frames
+/// without the virtio-net header.
+#[derive(Debug, Clone, Copy)]
+pub struct InterfaceStats {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    /// Received frames lost to driver errors.
+    pub rx_dropped: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    /// Frames the device refused to queue.
+    pub tx_dropped: u64,
+    /// Times a frame was ready while every TX descriptor was in flight.
+    pub tx_queue_full: u64,
+}
+
+pub fn interface_stats() -> InterfaceStats {
+    InterfaceStats {
+        rx_packets: RX_PACKETS.load(Ordering::Relaxed),
+        rx_bytes: RX_BYTES.load(Ordering::Relaxed),
+        rx_dropped: RX_DROPPED.load(Ordering::Relaxed),
+        tx_packets: TX_PACKETS.load(Ordering::Relaxed),
+        tx_bytes: TX_BYTES.load(Ordering::Relaxed),
+        tx_dropped: TX_DROPPED.load(Ordering::Relaxed),
+        tx_queue_full: TX_QUEUE_FULL.load(Ordering::Relaxed),
+    }
+}
+
+// ─── Checksum Offload ────────────────────────────────────────────────────────
+//
+// With VIRTIO_NET_F_CSUM the device fills in TCP and UDP checksums: smoltcp
+// leaves them zero, and the driver marks the frame NEEDS_CSUM with where the
+// checksum goes, seeded with the pseudo-header sum as the spec requires.
+//
+// With VIRTIO_NET_F_GUEST_CSUM the device may deliver frames whose checksum
+// is still partial (e.g. from another guest on the same host); the driver
+// completes them before smoltcp sees them. smoltcp still verifies every
+// received checksum, since it can't skip the ones the device marks valid.
+//
+// Segmentation offload (TSO/GSO) is not used: smoltcp never builds segments
+// larger than the MTU, and large receives would need bigger RX buffers.
+
+const VIRTIO_NET_F_CSUM: u64 = 1 << 0;
+const VIRTIO_NET_F_GUEST_CSUM: u64 = 1 << 1;
+
+/// `flags` bit of the virtio-net header: the checksum at `csum_start` +
+/// `csum_offset` is partial.
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+const ETHERNET_HEADER_LEN: usize = 14;
+const IP_PROTOCOL_TCP: u8 = 6;
+const IP_PROTOCOL_UDP: u8 = 17;
+
+/// One's-complement sum of `data` as big-endian words, added to `sum`.
+fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        sum += u32::from(u16::from_be_bytes([word[0], word[1]]));
+    }
+    if let [last] = words.remainder() {
+        sum += u32::from(*last) << 8;
+    }
+    sum
+}
+
+fn checksum_fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// Ask the device to checksum an outgoing TCP or UDP frame: fill in the
+/// virtio-net `header` and seed the checksum field of `frame`. Other frames,
+/// and IPv4 fragments, are left alone; smoltcp doesn't fragment.
+fn offload_checksum(header: &mut [u8], frame: &mut [u8]) {
+    let Some(ip) = frame.get(ETHERNET_HEADER_LEN..) else { return };
+    if frame[12..14] != [0x08, 0x00] || ip.len() < 20 {
+        return;
+    }
+    let ihl = usize::from(ip[0] & 0x0F) * 4;
+    let total_len = usize::from(u16::from_be_bytes([ip[2], ip[3]]));
+    let checksum_offset = match ip[9] {
+        IP_PROTOCOL_TCP => 16,
+        IP_PROTOCOL_UDP => 6,
+        _ => return,
+    };
+    if ihl < 20 || total_len > ip.len() || total_len < ihl + checksum_offset + 2 {
+        return;
+    }
+    // Pseudo-header: addresses, protocol, L4 length.
+    let mut sum = checksum_add(0, &ip[12..20]);
+    sum += u32::from(ip[9]);
+    sum += (total_len - ihl) as u32;
+    let start = ETHERNET_HEADER_LEN + ihl;
+    let field = start + checksum_offset;
+    frame[field..field + 2].copy_from_slice(&checksum_fold(sum).to_be_bytes());
+
+    header[0] = VIRTIO_NET_HDR_F_NEEDS_CSUM;
+    header[6..8].copy_from_slice(&(start as u16).to_le_bytes());
+    header[8..10].copy_from_slice(&(checksum_offset as u16).to_le_bytes());
+}
+
+/// Finish the partial checksum of a received frame the device marked
+/// NEEDS_CSUM, so smoltcp's verification passes.
+fn complete_checksum(header: &[u8], frame: &mut [u8]) {
+    if header[0] & VIRTIO_NET_HDR_F_NEEDS_CSUM == 0 {
+        return;
+    }
+    let start = usize::from(u16::from_le_bytes([header[6], header[7]]));
+    let field = start + usize::from(u16::from_le_bytes([header[8], header[9]]));
+    if field + 2 > frame.len() {
+        return;
+    }
+    // The field holds the pseudo-header sum, so it is part of the sum.
+    let checksum = !checksum_fold(checksum_add(0, &frame[start..]));
+    frame[field..field + 2].copy_from_slice(&checksum.to_be_bytes());
+}
+
+// ─── MTU and Mergeable Buffers ───────────────────────────────────────────────
+//
+// The MTU comes from `net.mtu`, capped by what the device allows: the `mtu`
+// in its config space with VIRTIO_NET_F_MTU, else the Ethernet default.
+//
+// RX buffers are one page. With VIRTIO_NET_F_MRG_RXBUF the device may spread
+// a frame over several of them, saying how many in the header's
+// `num_buffers`; the driver copies them into one `MERGE_POOL` buffer. That
+// allows jumbo frames. Without it a frame must fit one page, which caps the
+// MTU a little below 4096.
+
+/// VIRTIO_NET_F_MTU: the device config has a maximum MTU.
+pub const VIRTIO_NET_F_MTU: u64 = 1 << 3;
+const VIRTIO_NET_F_MRG_RXBUF: u64 = 1 << 15;
+/// Features the transport adds to what virtio-drivers asks for.
+pub const DRIVER_FEATURES: u64 = VIRTIO_NET_F_CSUM
+    | VIRTIO_NET_F_GUEST_CSUM
+    | VIRTIO_NET_F_MTU
+    | VIRTIO_NET_F_MRG_RXBUF
+    | VIRTIO_NET_F_CTRL_VQ
+    | VIRTIO_NET_F_CTRL_RX;
+
+/// IP MTU without VIRTIO_NET_F_MTU, and the default of `net.mtu`.
+pub const DEFAULT_MTU: usize = 1500;
+/// Smallest MTU an IPv4 host must support.
+pub const MIN_MTU: usize = 576;
+/// Largest MTU accepted (jumbo frames).
+pub const MAX_MTU: usize = 9000;
+
+// ─── Control Queue ───────────────────────────────────────────────────────────
+//
+// With VIRTIO_NET_F_CTRL_VQ the device has a third virtqueue (index 2, with
+// a single queue pair) for commands, and with VIRTIO_NET_F_CTRL_RX those
+// include the receive filter: promiscuous mode, all-multicast, and a table
+// of extra MAC addresses. virtio-drivers sets up only RX and TX, so the
+// transport sets this one up before DRIVER_OK (`ControlQueue::new`).
+//
+// A command is three descriptors: class and command, the command's data,
+// and a byte the device writes its answer to. Commands are rare and run
+// one at a time, so the driver reuses descriptors 0-2 and spins until the
+// device has used them; the queue never interrupts.
+//
+// QEMU starts a device with a control queue in promiscuous mode, so the
+// stack turns it off (`net_stack`). Without CTRL_RX the device delivers
+// every frame and the filter calls fail with `Unsupported`.
+
+pub const VIRTIO_NET_F_CTRL_VQ: u64 = 1 << 17;
+const VIRTIO_NET_F_CTRL_RX: u64 = 1 << 18;
+
+const CONTROL_QUEUE_INDEX: u16 = 2;
+const VIRTIO_NET_CTRL_RX: u8 = 0;
+const VIRTIO_NET_CTRL_RX_PROMISC: u8 = 0;
+const VIRTIO_NET_CTRL_RX_ALLMULTI: u8 = 1;
+const VIRTIO_NET_CTRL_MAC: u8 = 1;
+const VIRTIO_NET_CTRL_MAC_TABLE_SET: u8 = 0;
+const VIRTIO_NET_OK: u8 = 0;
+/// Multicast addresses `set_multicast_filter` puts in the MAC table;
+/// beyond that it accepts all multicast.
+pub const MAX_MULTICAST_FILTER: usize = 32;
+/// Give up on a command the device hasn't answered after this long.
+const CONTROL_TIMEOUT_MS: u64 = 1000;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+/// Offsets in the command page: the answer, then the data.
+const COMMAND_ACK_OFFSET: usize = 8;
+const COMMAND_DATA_OFFSET: usize = 16;
+
+/// Why a receive filter change failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlError {
+    /// No control queue, or no VIRTIO_NET_F_CTRL_RX.
+    Unsupported,
+    /// The device answered VIRTIO_NET_ERR.
+    Rejected,
+    /// The device didn't answer within `CONTROL_TIMEOUT_MS`.
+    Timeout,
+}
+
+/// The control virtqueue, in the legacy layout (descriptors, available
+/// ring, then the used ring on the next page boundary) that both
+/// transports accept.
+pub struct ControlQueue {
+    ring: DmaBuffer,
+    /// Header, answer and data of the command in flight.
+    command: DmaBuffer,
+    size: u16,
+    avail_offset: usize,
+    used_offset: usize,
+    /// Next index of the available ring.
+    avail_idx: u16,
+    /// Used ring index seen after the last command.
+    used_idx: u16,
+}
+
+impl ControlQueue {
+    /// Set up queue 2 on a device being initialized. `None` if it has no
+    /// such queue or there is no memory for it.
+    pub fn new(transport: &mut impl Transport) -> Option<Self> {
+        let size = transport.max_queue_size(CONTROL_QUEUE_INDEX) as usize;
+        if size == 0 {
+            return None;
+        }
+        let avail_offset = 16 * size;
+        let used_offset = (avail_offset + 6 + 2 * size).next_multiple_of(PAGE_SIZE);
+        let pages = (used_offset + 6 + 8 * size).div_ceil(PAGE_SIZE);
+        let queue = ControlQueue {
+            ring: DmaBuffer::new(pages)?,
+            command: DmaBuffer::new(1)?,
+            size: size as u16,
+            avail_offset,
+            used_offset,
+            avail_idx: 0,
+            used_idx: 0,
+        };
+        let command = queue.command.phys as u64;
+        queue.write_descriptor(0, command, 2, VIRTQ_DESC_F_NEXT, 1);
+        queue.write_descriptor(1, command + COMMAND_DATA_OFFSET as u64, 0, VIRTQ_DESC_F_NEXT, 2);
+        queue.write_descriptor(2, command + COMMAND_ACK_OFFSET as u64, 1, VIRTQ_DESC_F_WRITE, 0);
+        queue.write(avail_offset, VIRTQ_AVAIL_F_NO_INTERRUPT);
+        let phys = queue.ring.phys;
+        transport.queue_set(CONTROL_QUEUE_INDEX, size as u32, phys, phys + avail_offset, phys + used_offset);
+        serial_println!("[NET] Control queue: {} entries", size);
+        Some(queue)
+    }
+
+    /// Run one command and wait for the device's answer.
+    fn command(
+        &mut self,
+        transport: &mut impl Transport,
+        class: u8,
+        command: u8,
+        data: &[u8],
+    ) -> Result<(), ControlError> {
+        let page = self.command.as_mut_slice();
+        page[0] = class;
+        page[1] = command;
+        page[COMMAND_ACK_OFFSET] = !VIRTIO_NET_OK;
+        page[COMMAND_DATA_OFFSET..COMMAND_DATA_OFFSET + data.len()].copy_from_slice(data);
+        // Length of descriptor 1, the data.
+        self.write(16 + 8, data.len() as u32);
+
+        let slot = usize::from(self.avail_idx % self.size);
+        self.write(self.avail_offset + 4 + 2 * slot, 0u16);
+        fence(Ordering::SeqCst);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        self.write(self.avail_offset + 2, self.avail_idx);
+        fence(Ordering::SeqCst);
+        transport.notify(CONTROL_QUEUE_INDEX);
+
+        let deadline = crate::interrupts::uptime_ms() + CONTROL_TIMEOUT_MS;
+        while self.read::<u16>(self.used_offset + 2) == self.used_idx {
+            if crate::interrupts::uptime_ms() > deadline {
+                return Err(ControlError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+        self.used_idx = self.used_idx.wrapping_add(1);
+        fence(Ordering::SeqCst);
+        match self.command.as_mut_slice()[COMMAND_ACK_OFFSET] {
+            VIRTIO_NET_OK => Ok(()),
+            _ => Err(ControlError::Rejected),
+        }
+    }
+
+    fn write_descriptor(&self, index: usize, addr: u64, len: u32, flags: u16, next: u16) {
+        let offset = 16 * index;
+        self.write(offset, addr);
+        self.write(offset + 8, len);
+        self.write(offset + 12, flags);
+        self.write(offset + 14, next);
+    }
+
+    fn write<T>(&self, offset: usize, value: T) {
+        unsafe { core::ptr::write_volatile(self.ring.ptr.as_ptr().add(offset) as *mut T, value) }
+    }
+
+    fn read<T>(&self, offset: usize) -> T {
+        unsafe { core::ptr::read_volatile(self.ring.ptr.as_ptr().add(offset) as *const T) }
+    }
+}
 
 /// A physically contiguous buffer allocated via HAL DMA.
 pub struct DmaBuffer {
@@ -49,20 +377,124 @@ impl DmaBuffer {
     pub fn as_mut_slice(&mut self) -> &mut [u8] {
         unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
     }
+
+    /// Physical address of the first byte, for the device.
+    pub fn phys_addr(&self) -> usize {
+        self.phys
+    }
+
+    /// Size in bytes.
+    pub fn size(&self) -> usize {
+        self.len
+    }
+}
+
+/// A pooled buffer of at least `pages` pages, or a new one. `None` when
+/// out of DMA memory.
+pub(crate) fn pooled_buffer(pages: usize) -> Option<DmaBuffer> {
+    let pooled = {
+        let mut pool = BUFFER_POOL.lock();
+        pool.iter().position(|buf| buf.pages >= pages).map(|idx| pool.swap_remove(idx))
+    };
+    pooled.or_else(|| DmaBuffer::new(pages))
 }
 
-// We rely on BUFFER_POOL to recycle. If dropped without returning, we leak (dma_dealloc is no-op).
+/// Hand a buffer the device no longer owns back to the pool.
+pub(crate) fn release_buffer(buffer: DmaBuffer) {
+    BUFFER_POOL.lock().push(buffer);
+}
+
+fn take_buffer(pages: usize) -> DmaBuffer {
+    pooled_buffer(pages).expect("DMA buffer allocation failed")
+}
+
+// We rely on BUFFER_POOL to recycle. A buffer that is dropped hands its
+// frames back to the frame allocator, so only drop buffers the device no
+// longer owns.
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        for page in 0..self.pages {
+            crate::memory::free_frame(x86_64::PhysAddr::new((self.phys + page * 4096) as u64));
+        }
+    }
+}
+
+/// Shrinker for the idle buffer pool: frees up to `wanted` frames.
+pub fn shrink_buffer_pool(wanted: usize) -> usize {
+    let mut pool = BUFFER_POOL.lock();
+    let mut freed = 0;
+    while freed < wanted {
+        match pool.pop() {
+            Some(buf) => freed += buf.pages,
+            None => break,
+        }
+    }
+    freed
+}
+
+// ─── Driver Taps ─────────────────────────────────────────────────────────────
+//
+// What every NIC driver does with the frames it moves, besides counting them.
+
+/// Show a received frame from the NIC with address `nic` to the capture
+/// and the neighbor cache. Returns whether the firewall lets it on to
+/// smoltcp.
+pub(crate) fn receive_tap(nic: EthernetAddress, frame: &[u8]) -> bool {
+    crate::pcap::capture(frame);
+    crate::neighbor::observe_rx(nic, frame);
+    crate::firewall::admits(frame)
+}
+
+/// Show a frame about to be sent to the capture and the TCP monitor.
+pub(crate) fn transmit_tap(frame: &[u8]) {
+    crate::pcap::capture(frame);
+    crate::tcp_monitor::observe(frame);
+}
 
 /// smoltcp Device implementation wrapping VirtIONetRaw (Non-blocking)
 pub struct VirtioNetDevice {
-    inner: VirtIONetRaw<VirtioHal, LegacyTransport, QUEUE_SIZE>,
+    inner: VirtIONetRaw<VirtioHal, VirtioTransport, QUEUE_SIZE>,
     // buffers[i] holds the buffer for the descriptor with token `i`
     rx_buffers: Vec<Option<DmaBuffer>>,
     tx_buffers: Vec<Option<DmaBuffer>>,
+    // Where the device sits, kept so it can be reset.
+    location: VirtioLocation,
+    // Bytes of virtio-net header in front of every packet.
+    header_len: usize,
+    // Last time the device showed signs of life (TX completion, RX packet, or idle).
+    last_progress: Instant,
+    // The device checksums TCP/UDP frames we send (VIRTIO_NET_F_CSUM).
+    tx_checksum_offload: bool,
+    // The device may hand us partial checksums (VIRTIO_NET_F_GUEST_CSUM).
+    rx_partial_checksums: bool,
+    // A frame may span several RX buffers (VIRTIO_NET_F_MRG_RXBUF).
+    mergeable_rx: bool,
+    // Largest MTU the device and the RX buffers allow.
+    max_mtu: usize,
+    // Current IP MTU.
+    mtu: usize,
+    // The control queue, if the device has one and it still answers.
+    control: Option<ControlQueue>,
+    // The device filters received frames (VIRTIO_NET_F_CTRL_RX).
+    rx_filter: bool,
+    // The device's MAC address.
+    mac: EthernetAddress,
+    // Frames for smoltcp that never crossed the wire (see `neighbor`),
+    // virtio-net header first.
+    injected: Vec<Vec<u8>>,
 }
 
 impl VirtioNetDevice {
-    pub fn new(mut inner: VirtIONetRaw<VirtioHal, LegacyTransport, QUEUE_SIZE>) -> Self {
+    /// `features` are the feature bits negotiated with the device,
+    /// `device_mtu` the MTU in its config if it has one, and `control` its
+    /// control queue if one was set up.
+    pub fn new(
+        mut inner: VirtIONetRaw<VirtioHal, VirtioTransport, QUEUE_SIZE>,
+        location: VirtioLocation,
+        features: u64,
+        device_mtu: Option<u16>,
+        control: Option<ControlQueue>,
+    ) -> Self {
         // Allocate storage for tokens
         let mut rx_buffers = Vec::with_capacity(QUEUE_SIZE);
         let mut tx_buffers = Vec::with_capacity(QUEUE_SIZE);
@@ -83,6 +515,8 @@ impl VirtioNetDevice {
                                  rx_buffers[token as usize] = Some(buf);
                              } else {
                                  serial_println!("[NET] Error: RX token {} out of bounds", token);
+                                 // The device owns it now; leak rather than free.
+                                 core::mem::forget(buf);
                              }
                         }
                         Err(e) => {
@@ -95,14 +529,208 @@ impl VirtioNetDevice {
             }
         }
 
-        Self { inner, rx_buffers, tx_buffers }
+        let mergeable_rx = features & VIRTIO_NET_F_MRG_RXBUF != 0;
+        let header_len =
+            if location.is_modern() || mergeable_rx { VIRTIO_MERGEABLE_HEADER_LEN } else { VIRTIO_HEADER_LEN };
+        let device_max = device_mtu.map_or(DEFAULT_MTU, usize::from);
+        let buffer_max = if mergeable_rx { MAX_MTU } else { PAGE_SIZE - header_len - ETHERNET_HEADER_LEN };
+        let tx_checksum_offload = features & VIRTIO_NET_F_CSUM != 0;
+        let rx_partial_checksums = features & VIRTIO_NET_F_GUEST_CSUM != 0;
+        serial_println!(
+            "[NET] Checksum offload: TX {}, RX {}",
+            if tx_checksum_offload { "on" } else { "off" },
+            if rx_partial_checksums { "on" } else { "off" }
+        );
+        let mac = EthernetAddress(inner.mac_address());
+        let mut device = Self {
+            inner,
+            rx_buffers,
+            tx_buffers,
+            location,
+            header_len,
+            last_progress: Instant::ZERO,
+            tx_checksum_offload,
+            rx_partial_checksums,
+            mergeable_rx,
+            max_mtu: device_max.min(buffer_max).max(MIN_MTU),
+            mtu: DEFAULT_MTU,
+            rx_filter: control.is_some() && features & VIRTIO_NET_F_CTRL_RX != 0,
+            control,
+            mac,
+            injected: Vec::new(),
+        };
+        device.set_mtu(crate::config::get_or("net.mtu", DEFAULT_MTU as u64) as usize);
+        device
+    }
+
+    pub fn location(&self) -> VirtioLocation {
+        self.location
+    }
+
+    /// Current IP MTU.
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// Use `requested` as the IP MTU, or the device's maximum if it is larger.
+    /// Returns the MTU now in use.
+    pub fn set_mtu(&mut self, requested: usize) -> usize {
+        self.mtu = requested.clamp(MIN_MTU, self.max_mtu);
+        serial_println!(
+            "[NET] MTU {} (device allows {}, mergeable RX buffers {})",
+            self.mtu, self.max_mtu, if self.mergeable_rx { "on" } else { "off" }
+        );
+        self.mtu
+    }
+
+    /// Have the next `receive` return `frame`, as if it had arrived.
+    fn inject(&mut self, frame: &[u8]) {
+        let mut data = MERGE_POOL.lock().pop().unwrap_or_default();
+        data.clear();
+        data.resize(self.header_len, 0);
+        data.extend_from_slice(frame);
+        self.injected.push(data);
+    }
+
+    /// Number of TX buffers handed to the device and not yet completed.
+    pub fn tx_in_flight(&self) -> usize {
+        self.tx_buffers.iter().filter(|s| s.is_some()).count()
+    }
+
+    /// True if transmissions have been pending for longer than
+    /// `WEDGE_TIMEOUT_MS` without the device completing any of them.
+    pub fn is_wedged(&self, now: Instant) -> bool {
+        self.tx_in_flight() > 0
+            && now.total_millis() - self.last_progress.total_millis() > WEDGE_TIMEOUT_MS
+    }
+
+    /// Take back the RX buffer of `token` with how much of it the device
+    /// filled, header included. `None` (counted as a drop) on driver errors.
+    fn complete_rx(&mut self, token: u16) -> Option<(DmaBuffer, usize)> {
+        let Some(mut buffer) = self.rx_buffers.get_mut(token as usize).and_then(Option::take) else {
+            klog!(klog::NET, Level::Error, "[NET ERROR] RX Token {} has no buffer calling poll_receive", token);
+            RX_DROPPED.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        match unsafe { self.inner.receive_complete(token, buffer.as_mut_slice()) } {
+            // virtio-drivers assumes the 10-byte header and subtracts it;
+            // added back, it is the used length whatever the header size.
+            Ok((hdr, pkt_len)) => Some((buffer, hdr + pkt_len)),
+            Err(e) => {
+                klog!(klog::NET, Level::Error, "[NET] RX complete error: {:?}", e);
+                RX_DROPPED.fetch_add(1, Ordering::Relaxed);
+                // Return buffer to pool
+                BUFFER_POOL.lock().push(buffer);
+                None
+            }
+        }
+    }
+
+    /// Reclaim completed TX buffers and update the progress timestamp.
+    fn poll_tx_completions(&mut self, now: Instant) {
+        unsafe {
+            while let Some(token) = self.inner.poll_transmit() {
+                if (token as usize) < QUEUE_SIZE {
+                    if let Some(mut buf) = self.tx_buffers[token as usize].take() {
+                        self.inner.transmit_complete(token, buf.as_mut_slice()).ok();
+                        BUFFER_POOL.lock().push(buf);
+                    }
+                }
+                self.last_progress = now;
+            }
+        }
+        if self.tx_in_flight() == 0 {
+            // Nothing outstanding, so there is nothing to be stuck on.
+            self.last_progress = now;
+        }
+    }
+
+    /// Stop the device and return every in-flight DMA buffer to the pool.
+    ///
+    /// The device is unusable afterwards; drop it and probe the NIC again.
+    pub fn reset(&mut self) {
+        crate::network::reset_device(self.location);
+
+        let mut pool = BUFFER_POOL.lock();
+        for slot in self.rx_buffers.iter_mut().chain(self.tx_buffers.iter_mut()) {
+            if let Some(buf) = slot.take() {
+                pool.push(buf);
+            }
+        }
+        if let Some(control) = self.control.take() {
+            pool.push(control.ring);
+            pool.push(control.command);
+        }
+    }
+
+    /// Whether the device can filter received frames; without it every
+    /// frame on the link is delivered, as if promiscuous.
+    pub fn has_rx_filter(&self) -> bool {
+        self.rx_filter
+    }
+
+    /// Deliver every frame on the link, not only those for this NIC.
+    pub fn set_promiscuous(&mut self, on: bool) -> Result<(), ControlError> {
+        self.control(VIRTIO_NET_CTRL_RX, VIRTIO_NET_CTRL_RX_PROMISC, &[on as u8])
+    }
+
+    /// Deliver multicast frames only for `groups` (Ethernet addresses), or
+    /// every multicast frame if there are more than `MAX_MULTICAST_FILTER`.
+    pub fn set_multicast_filter(&mut self, groups: &[[u8; 6]]) -> Result<(), ControlError> {
+        if groups.len() > MAX_MULTICAST_FILTER {
+            return self.control(VIRTIO_NET_CTRL_RX, VIRTIO_NET_CTRL_RX_ALLMULTI, &[1]);
+        }
+        // No extra unicast addresses, then the multicast ones; counts are
+        // little-endian.
+        let mut table = [0u8; 8 + 6 * MAX_MULTICAST_FILTER];
+        table[4..8].copy_from_slice(&(groups.len() as u32).to_le_bytes());
+        for (entry, group) in table[8..].chunks_exact_mut(6).zip(groups) {
+            entry.copy_from_slice(group);
+        }
+        self.control(VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_TABLE_SET, &table[..8 + 6 * groups.len()])?;
+        self.control(VIRTIO_NET_CTRL_RX, VIRTIO_NET_CTRL_RX_ALLMULTI, &[0])
+    }
+
+    /// Run an RX filter command on the control queue. A device that stops
+    /// answering loses its control queue.
+    fn control(&mut self, class: u8, command: u8, data: &[u8]) -> Result<(), ControlError> {
+        let Some(queue) = self.control.as_mut().filter(|_| self.rx_filter) else {
+            return Err(ControlError::Unsupported);
+        };
+        let result = queue.command(&mut self.location.transport(), class, command, data);
+        if result == Err(ControlError::Timeout) {
+            serial_println!("[NET] Control queue timed out; RX filtering off until the device is reset.");
+            self.rx_filter = false;
+            // The device may still write the buffers; never reuse them.
+            core::mem::forget(self.control.take());
+        }
+        result
+    }
+}
+
+/// A received frame, virtio-net header first.
+enum RxFrame {
+    /// Still in its RX buffer, which the device filled this far.
+    Single(DmaBuffer, usize),
+    /// Copied together from several RX buffers.
+    Merged(Vec<u8>),
+    Empty,
+}
+
+impl RxFrame {
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        match self {
+            RxFrame::Single(buffer, used) => &mut buffer.as_mut_slice()[..*used],
+            RxFrame::Merged(data) => data,
+            RxFrame::Empty => &mut [],
+        }
     }
 }
 
 /// RX token for receiving packets wrapped in a safe container
 pub struct VirtioRxTokenSafe {
-    buffer: Option<DmaBuffer>,
-    len: usize,
+    frame: RxFrame,
+    header_len: usize,
 }
 
 impl RxToken for VirtioRxTokenSafe {
@@ -110,19 +738,18 @@ impl RxToken for VirtioRxTokenSafe {
     where
         F: FnOnce(&mut [u8]) -> R,
     {
-        if let Some(buf) = self.buffer.as_mut() {
-             // Skip VirtIO Header
-             f(&mut buf.as_mut_slice()[VIRTIO_HEADER_LEN..self.len])
-        } else {
-             f(&mut [])
-        }
+        // Skip VirtIO Header
+        let header_len = self.header_len;
+        f(self.frame.as_mut_slice().get_mut(header_len..).unwrap_or_default())
     }
 }
 
 impl Drop for VirtioRxTokenSafe {
     fn drop(&mut self) {
-        if let Some(buf) = self.buffer.take() {
-            BUFFER_POOL.lock().push(buf);
+        match core::mem::replace(&mut self.frame, RxFrame::Empty) {
+            RxFrame::Single(buffer, _) => BUFFER_POOL.lock().push(buffer),
+            RxFrame::Merged(data) => MERGE_POOL.lock().push(data),
+            RxFrame::Empty => {}
         }
     }
 }
@@ -137,20 +764,29 @@ impl<'a> TxToken for VirtioTxToken<'a> {
     where
         F: FnOnce(&mut [u8]) -> R,
     {
-        // Try reuse from pool or alloc new
-        let mut buffer = BUFFER_POOL.lock().pop().or_else(|| DmaBuffer::new(RX_BUFFER_PAGES)).expect("TX Alloc failed");
+        let header_len = self.device.header_len;
+        // Try reuse from pool or alloc new; jumbo frames need more than a page.
+        let mut buffer = take_buffer((header_len + len).div_ceil(PAGE_SIZE));
         
         // Zero header
-        unsafe { core::ptr::write_bytes(buffer.as_mut_slice().as_mut_ptr(), 0, VIRTIO_HEADER_LEN); }
+        unsafe { core::ptr::write_bytes(buffer.as_mut_slice().as_mut_ptr(), 0, header_len); }
 
         // Write packet data
-        let result = f(&mut buffer.as_mut_slice()[VIRTIO_HEADER_LEN..VIRTIO_HEADER_LEN + len]);
+        let result = f(&mut buffer.as_mut_slice()[header_len..header_len + len]);
+        let mut reply = [0; crate::neighbor::ARP_FRAME_LEN];
+        let frame = &buffer.as_mut_slice()[header_len..header_len + len];
+        if crate::neighbor::static_reply(self.device.mac, frame, &mut reply) {
+            // A static neighbor: answer smoltcp's ARP request ourselves.
+            self.device.inject(&reply);
+            BUFFER_POOL.lock().push(buffer);
+            return result;
+        }
         let data = buffer.as_mut_slice();
-        let eth_type = ((data[VIRTIO_HEADER_LEN + 12] as u16) << 8) | (data[VIRTIO_HEADER_LEN + 13] as u16);
-        // serial_println!("[NET TX] {} bytes, EthType: 0x{:04x}", len, eth_type);
+        let eth_type = ((data[header_len + 12] as u16) << 8) | (data[header_len + 13] as u16);
+        klog!(klog::NET, Level::Trace, "[NET TX] {} bytes, EthType: 0x{:04x}", len, eth_type);
 
         // Checksum patch for IPv4
-        let pkt_start = VIRTIO_HEADER_LEN;
+        let pkt_start = header_len;
         if buffer.len > pkt_start + 34 { // Min size for Eth+IP
             let data = buffer.as_mut_slice();
             // Check EtherType (IPv4 = 0x0800) at offset 12
@@ -188,23 +824,37 @@ impl<'a> TxToken for VirtioTxToken<'a> {
             }
         }
 
+        if self.device.tx_checksum_offload {
+            let (header, frame) = buffer.as_mut_slice().split_at_mut(header_len);
+            offload_checksum(header, &mut frame[..len]);
+        }
+        transmit_tap(&buffer.as_mut_slice()[header_len..header_len + len]);
+
         unsafe {
             // Transmit Header + Packet
-            match self.device.inner.transmit_begin(&mut buffer.as_mut_slice()[..VIRTIO_HEADER_LEN + len]) {
+            match self.device.inner.transmit_begin(&mut buffer.as_mut_slice()[..header_len + len]) {
                 Ok(token) => {
                     if (token as usize) < QUEUE_SIZE {
-                        if self.device.tx_buffers[token as usize].is_some() {
+                        TX_PACKETS.fetch_add(1, Ordering::Relaxed);
+                        TX_BYTES.fetch_add(len as u64, Ordering::Relaxed);
+                        if let Some(old) = self.device.tx_buffers[token as usize].replace(buffer) {
                            serial_println!("[NET TX] Warning: Overwriting active TX buffer at {}", token); 
+                           // The device may still own it; leak rather than free.
+                           core::mem::forget(old);
                         }
-                        self.device.tx_buffers[token as usize] = Some(buffer);
                     } else {
                         serial_println!("[NET TX] Error: TX token {} out of bounds", token);
+                        TX_DROPPED.fetch_add(1, Ordering::Relaxed);
                         // Return to pool if invalid token
                         BUFFER_POOL.lock().push(buffer);
                     }
                 }
                 Err(e) => {
-                    serial_println!("[NET TX] Transmit failed: {:?}", e);
+                    klog!(klog::NET, Level::Warn, "[NET TX] Transmit failed: {:?}", e);
+                    if e == virtio_drivers::Error::QueueFull {
+                        TX_QUEUE_FULL.fetch_add(1, Ordering::Relaxed);
+                    }
+                    TX_DROPPED.fetch_add(1, Ordering::Relaxed);
                     BUFFER_POOL.lock().push(buffer);
                 }
             }
@@ -218,7 +868,7 @@ impl Device for VirtioNetDevice {
     type RxToken<'a> = VirtioRxTokenSafe;
     type TxToken<'a> = VirtioTxToken<'a>;
 
-    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+    fn receive(&mut self, timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
         // Acknowledge interrupts (clears ISR) - essential for some devices/backends even in polling mode
         // self.inner.ack_interrupt(); // Wait, confirm if exposed. 
         // virtio-drivers 0.10 VirtIONetRaw usually exposes it.
@@ -227,16 +877,7 @@ impl Device for VirtioNetDevice {
         self.inner.ack_interrupt();
 
         // 1. Poll TX completions (free up buffers)
-        unsafe {
-            while let Some(token) = self.inner.poll_transmit() {
-                if (token as usize) < QUEUE_SIZE {
-                     if let Some(mut buf) = self.tx_buffers[token as usize].take() {
-                          self.inner.transmit_complete(token, buf.as_mut_slice()).ok();
-                          BUFFER_POOL.lock().push(buf);
-                     }
-                }
-            }
-        }
+        self.poll_tx_completions(timestamp);
 
         // 2. Replenish RX buffers
         loop {
@@ -265,66 +906,78 @@ impl Device for VirtioNetDevice {
                     break;
                 }
                 Err(e) => {
-                    serial_println!("[NET ERROR] receive_begin failed: {:?}", e);
+                    klog!(klog::NET, Level::Error, "[NET ERROR] receive_begin failed: {:?}", e);
                     BUFFER_POOL.lock().push(buf);
                     break;
                 }
             }
         }
 
-        // 3. Poll RX
-        unsafe {
-            match self.inner.poll_receive() {
-                Some(token) => {
-                    if (token as usize) < QUEUE_SIZE && self.rx_buffers[token as usize].is_some() {
-                        let mut buffer = self.rx_buffers[token as usize].take().unwrap();
-                        match self.inner.receive_complete(token, buffer.as_mut_slice()) {
-                            Ok((_hdr, pkt_len)) => {
-                                let eth_type = ((buffer.as_mut_slice()[VIRTIO_HEADER_LEN + 12] as u16) << 8) | (buffer.as_mut_slice()[VIRTIO_HEADER_LEN + 13] as u16);
-                                serial_println!("[NET RX] {} bytes, EthType: 0x{:04x}", pkt_len, eth_type);
-                                
-                                let rx_token = VirtioRxTokenSafe {
-                                    buffer: Some(buffer), // Pass ownership
-                                    len: pkt_len + VIRTIO_HEADER_LEN, // heuristic: pkt_len seems to be data len only in this env
-                                };
-                                let tx_token = VirtioTxToken { 
-                                    device: self, 
-                                };
-                                return Some((rx_token, tx_token)); 
-                            }
-                            Err(e) => {
-                                serial_println!("[NET] RX complete error: {:?}", e);
-                                // Return buffer to pool
-                                BUFFER_POOL.lock().push(buffer);
-                            }
-                        }
-                    } else {
-                         serial_println!("[NET ERROR] RX Token {} has no buffer calling poll_receive", token);
+        // 3. Poll RX, skipping frames the firewall turns away
+        if let Some(data) = self.injected.pop() {
+            let rx_token = VirtioRxTokenSafe { frame: RxFrame::Merged(data), header_len: self.header_len };
+            return Some((rx_token, VirtioTxToken { device: self }));
+        }
+        loop {
+            let token = self.inner.poll_receive()?;
+            let (mut first, used) = self.complete_rx(token)?;
+            self.last_progress = timestamp;
+            let header_len = self.header_len;
+            let num_buffers = if self.mergeable_rx {
+                let header = &first.as_mut_slice()[..header_len];
+                u16::from_le_bytes([header[10], header[11]]).max(1)
+            } else {
+                1
+            };
+            let mut rx_token = VirtioRxTokenSafe { frame: RxFrame::Single(first, used), header_len };
+            if num_buffers > 1 {
+                // The rest of the frame is in the next buffers the device used.
+                let mut data = MERGE_POOL.lock().pop().unwrap_or_default();
+                data.clear();
+                data.extend_from_slice(rx_token.frame.as_mut_slice());
+                rx_token.frame = RxFrame::Merged(data);
+                for _ in 1..num_buffers {
+                    let next = self.inner.poll_receive().and_then(|token| self.complete_rx(token));
+                    let Some((mut buffer, used)) = next else {
+                        klog!(klog::NET, Level::Error, "[NET ERROR] Frame spans {} buffers; fewer arrived", num_buffers);
+                        RX_DROPPED.fetch_add(1, Ordering::Relaxed);
+                        return None;
+                    };
+                    if let RxFrame::Merged(data) = &mut rx_token.frame {
+                        data.extend_from_slice(&buffer.as_mut_slice()[..used]);
                     }
+                    BUFFER_POOL.lock().push(buffer);
                 }
-                None => {}
             }
-        }
 
-        None
+            let data = rx_token.frame.as_mut_slice();
+            if data.len() < header_len + ETHERNET_HEADER_LEN {
+                RX_DROPPED.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+            let (header, frame) = data.split_at_mut(header_len);
+            let eth_type = u16::from_be_bytes([frame[12], frame[13]]);
+            RX_PACKETS.fetch_add(1, Ordering::Relaxed);
+            RX_BYTES.fetch_add(frame.len() as u64, Ordering::Relaxed);
+            klog!(klog::NET, Level::Trace, "[NET RX] {} bytes, EthType: 0x{:04x}", frame.len(), eth_type);
+            if self.rx_partial_checksums {
+                complete_checksum(header, frame);
+            }
+            if !receive_tap(self.mac, frame) {
+                continue;
+            }
+            let tx_token = VirtioTxToken { device: self };
+            return Some((rx_token, tx_token));
+        }
     }
 
-    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+    fn transmit(&mut self, timestamp: Instant) -> Option<Self::TxToken<'_>> {
         // Poll TX descriptors to free space
-        unsafe {
-             while let Some(token) = self.inner.poll_transmit() {
-                if (token as usize) < QUEUE_SIZE {
-                    if let Some(mut buf) = self.tx_buffers[token as usize].take() { 
-                        self.inner.transmit_complete(token, buf.as_mut_slice()).ok();
-                        BUFFER_POOL.lock().push(buf);
-                    }
-                }
-             }
-        }
+        self.poll_tx_completions(timestamp);
 
         // Check flight limit
-        let used = self.tx_buffers.iter().filter(|s| s.is_some()).count();
-        if used >= QUEUE_SIZE {
+        if self.tx_in_flight() >= QUEUE_SIZE {
+            TX_QUEUE_FULL.fetch_add(1, Ordering::Relaxed);
             return None;
         }
 
@@ -333,13 +986,139 @@ impl Device for VirtioNetDevice {
 
     fn capabilities(&self) -> DeviceCapabilities {
         let mut caps = DeviceCapabilities::default();
-        caps.max_transmission_unit = 1500;
+        // smoltcp counts the Ethernet header in the MTU.
+        caps.max_transmission_unit = self.mtu + ETHERNET_HEADER_LEN;
         caps.max_burst_size = Some(1);
         caps.medium = Medium::Ethernet;
         caps.checksum.ipv4 = Checksum::Both;
-        caps.checksum.tcp = Checksum::Both;
-        caps.checksum.udp = Checksum::Both;
+        // Received checksums are always verified (see "Checksum Offload").
+        let l4 = if self.tx_checksum_offload { Checksum::Rx } else { Checksum::Both };
+        caps.checksum.tcp = l4;
+        caps.checksum.udp = l4;
         caps.checksum.icmpv4 = Checksum::Both;
         caps
     }
 }
+
+// ─── NIC Devices ─────────────────────────────────────────────────────────────
+//
+// The stack holds each NIC as a `NetDevice`, whichever driver runs it.
+
+/// A NIC, as the stack sees it. The virtio device is boxed: its queues
+/// dwarf the RTL8139's few registers.
+pub enum NetDevice {
+    Virtio(Box<VirtioNetDevice>),
+    Rtl8139(Rtl8139Device),
+}
+
+macro_rules! dispatch {
+    ($self:expr, $d:ident => $call:expr) => {
+        match $self {
+            NetDevice::Virtio($d) => $call,
+            NetDevice::Rtl8139($d) => $call,
+        }
+    };
+}
+
+impl NetDevice {
+    pub fn mtu(&self) -> usize { dispatch!(self, d => d.mtu()) }
+    pub fn set_mtu(&mut self, requested: usize) -> usize { dispatch!(self, d => d.set_mtu(requested)) }
+    pub fn tx_in_flight(&self) -> usize { dispatch!(self, d => d.tx_in_flight()) }
+    pub fn is_wedged(&self, now: Instant) -> bool { dispatch!(self, d => d.is_wedged(now)) }
+    pub fn set_promiscuous(&mut self, on: bool) -> Result<(), ControlError> {
+        dispatch!(self, d => d.set_promiscuous(on))
+    }
+    pub fn set_multicast_filter(&mut self, groups: &[[u8; 6]]) -> Result<(), ControlError> {
+        dispatch!(self, d => d.set_multicast_filter(groups))
+    }
+    pub fn reset(&mut self) { dispatch!(self, d => d.reset()) }
+
+    /// Whether the NIC can filter received frames; without it every frame
+    /// on the link is delivered, as if promiscuous.
+    pub fn has_rx_filter(&self) -> bool {
+        match self {
+            NetDevice::Virtio(device) => device.has_rx_filter(),
+            NetDevice::Rtl8139(_) => true,
+        }
+    }
+
+    /// Reset a wedged NIC and bring it back up in place. Returns false if
+    /// it didn't come back.
+    pub fn recover(&mut self) -> bool {
+        match self {
+            NetDevice::Virtio(device) => {
+                let location = device.location();
+                device.reset();
+                match crate::network::init_device(location) {
+                    Some((new, _mac)) => {
+                        **device = new;
+                        true
+                    }
+                    None => false,
+                }
+            }
+            NetDevice::Rtl8139(device) => device.restart(),
+        }
+    }
+}
+
+pub enum NetRxToken {
+    Virtio(VirtioRxTokenSafe),
+    Rtl8139(Rtl8139RxToken),
+}
+
+pub enum NetTxToken<'a> {
+    Virtio(VirtioTxToken<'a>),
+    Rtl8139(Rtl8139TxToken<'a>),
+}
+
+impl RxToken for NetRxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        match self {
+            NetRxToken::Virtio(token) => token.consume(f),
+            NetRxToken::Rtl8139(token) => token.consume(f),
+        }
+    }
+}
+
+impl<'a> TxToken for NetTxToken<'a> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        match self {
+            NetTxToken::Virtio(token) => token.consume(len, f),
+            NetTxToken::Rtl8139(token) => token.consume(len, f),
+        }
+    }
+}
+
+impl Device for NetDevice {
+    type RxToken<'a> = NetRxToken;
+    type TxToken<'a> = NetTxToken<'a>;
+
+    fn receive(&mut self, timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        match self {
+            NetDevice::Virtio(device) => {
+                device.receive(timestamp).map(|(rx, tx)| (NetRxToken::Virtio(rx), NetTxToken::Virtio(tx)))
+            }
+            NetDevice::Rtl8139(device) => {
+                device.receive(timestamp).map(|(rx, tx)| (NetRxToken::Rtl8139(rx), NetTxToken::Rtl8139(tx)))
+            }
+        }
+    }
+
+    fn transmit(&mut self, timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        match self {
+            NetDevice::Virtio(device) => device.transmit(timestamp).map(NetTxToken::Virtio),
+            NetDevice::Rtl8139(device) => device.transmit(timestamp).map(NetTxToken::Rtl8139),
+        }
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        dispatch!(self, d => d.capabilities())
+    }
+}
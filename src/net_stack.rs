@@ -1,58 +1,624 @@
 use smoltcp::iface::{Config, Interface, SocketSet, SocketHandle};
+use smoltcp::phy::{Device, TxToken};
 use smoltcp::socket::dhcpv4;
 use smoltcp::socket::udp::{self, PacketMetadata as UdpPacketMetadata, Socket as UdpSocket};
-use smoltcp::socket::tcp::{self, Socket as TcpSocket, SocketBuffer as TcpSocketBuffer};
-use smoltcp::time::Instant;
-use smoltcp::wire::{EthernetAddress, HardwareAddress, IpCidr};
-use crate::net_interface::VirtioNetDevice;
-use crate::serial_println;
+use smoltcp::socket::tcp::{self, Socket as TcpSocket};
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{DhcpOption, EthernetAddress, HardwareAddress, IpAddress, IpCidr, IpEndpoint, IpListenEndpoint, Ipv4Address, Ipv4Cidr};
+use crate::net_interface::{self, InterfaceStats, NetDevice, DEFAULT_MTU, MAX_MTU, MIN_MTU};
+use crate::capability::{Capability, CapabilityType, Permissions};
+use crate::config;
+use crate::interrupts;
+use crate::ipc::{IpcError, Message, Priority, IPC_MANAGER};
+use crate::klog::Level;
+use crate::neighbor;
+use crate::notification::Notification;
+use crate::p2p_transport::Deadline;
+use crate::{klog, serial_println};
+use crate::socket_manager::{socket_heap, SocketManager, MAX_TCP_BUFFER, MIN_TCP_BUFFER};
 use spin::Mutex;
 use lazy_static::lazy_static;
+use alloc::string::String;
 use alloc::vec::Vec;
 use alloc::vec;
+use core::future::{poll_fn, Future};
+use core::pin::Pin;
 use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
 
 lazy_static! {
     pub static ref NETWORK_STACK: Mutex<Option<NetworkStack>> = Mutex::new(None);
 }
 
+/// Default soft limit on open sockets (tunable as `net.max_sockets`).
+/// Admission control refuses new work that would need sockets beyond it.
+pub const MAX_SOCKETS: usize = 32;
+
+/// Port of the HTTP server (`http`).
+pub const HTTP_PORT: u16 = 80;
+/// Port the P2P layer accepts peers on.
+pub const P2P_PORT: u16 = 40444;
+/// Default of `net.tcp.service_buffer`.
+pub const SERVICE_TCP_BUFFER: usize = 64 * 1024;
+
+/// Current soft limit on open sockets.
+pub fn max_sockets() -> usize {
+    config::get_or("net.max_sockets", MAX_SOCKETS as u64) as usize
+}
+
+// ─── Tunables ────────────────────────────────────────────────────────────────
+//
+// TCP and DHCP keys apply live to every socket in the stack, except the
+// buffer sizes, which apply to sockets taken from then on. The ARP cache
+// size and TCP RTO bounds are compile-time constants inside smoltcp and are
+// exposed read-only so they at least show up next to the rest.
+
+/// Register the network keys with the config subsystem. Idempotent.
+fn register_tunables() {
+    config::register("net.max_sockets", "Soft limit on open sockets", MAX_SOCKETS as u64, 4, 256, None);
+    config::register("net.tcp.timeout_ms", "Abort a TCP connection idle this long (0 = never)", 0, 0, 600_000, Some(apply_tcp_tunables));
+    config::register("net.tcp.keepalive_ms", "TCP keep-alive interval (0 = off)", 0, 0, 3_600_000, Some(apply_tcp_tunables));
+    config::register("net.tcp.ack_delay_ms", "Delayed-ACK timeout (0 = ACK immediately)", 10, 0, 500, Some(apply_tcp_tunables));
+    config::register("net.tcp.nagle", "Nagle's algorithm (1 = on)", 1, 0, 1, Some(apply_tcp_tunables));
+    config::register("net.tcp.buffer", "TCP buffer bytes per direction of sockets that don't ask for a size", MIN_TCP_BUFFER as u64, MIN_TCP_BUFFER as u64, MAX_TCP_BUFFER as u64, None);
+    config::register("net.tcp.service_buffer", "TCP buffer bytes per direction of HTTP and P2P connections", SERVICE_TCP_BUFFER as u64, MIN_TCP_BUFFER as u64, MAX_TCP_BUFFER as u64, Some(apply_service_buffer));
+    config::register("net.mtu", "IP MTU, capped by what the NIC allows", DEFAULT_MTU as u64, MIN_MTU as u64, MAX_MTU as u64, Some(apply_mtu));
+    config::register("net.poll_fallback_ms", "Poll the interface at least this often without NIC interrupts", 10, 1, 1_000, None);
+    config::register("net.tcp.backlog", "Listening sockets per kernel service port (HTTP, P2P)", 4, 1, 16, None);
+    config::register("net.tcp.connect_timeout_ms", "Give up on an outbound connection after this long", 10_000, 500, 120_000, None);
+    config::register("net.tls.handshake_timeout_ms", "Give up on a TLS handshake after this long", 10_000, 500, 120_000, None);
+    config::register("net.tls.allow_unpinned", "Connect to TLS hosts without a pinned key, unauthenticated (1 = on)", 0, 0, 1, None);
+    config::register_fixed("net.tcp.rto_min_ms", "Minimum retransmission timeout (smoltcp)", 10);
+    config::register_fixed("net.tcp.rto_max_ms", "Maximum retransmission timeout (smoltcp)", 10_000);
+    config::register("net.dhcp.discover_timeout_ms", "Interval between DHCP DISCOVERs", 10_000, 1_000, 120_000, Some(apply_dhcp_tunables));
+    config::register("net.dhcp.request_timeout_ms", "Initial DHCP REQUEST timeout (doubles every 2 tries)", 5_000, 500, 60_000, Some(apply_dhcp_tunables));
+    config::register("net.dhcp.request_retries", "DHCP REQUESTs before restarting discovery", 5, 1, 20, Some(apply_dhcp_tunables));
+    config::register("net.dhcp.fallback_ms", "Wait this long for a lease before using the static address", 15_000, 0, 600_000, None);
+    config::register("net.dhcp.max_lease_s", "Treat DHCP leases as at most this long, to renew sooner (0 = as offered)", 0, 0, 604_800, Some(apply_dhcp_tunables));
+    config::register("net.dhcp.renew_retry_ms", "Shortest wait before repeating an unanswered DHCP renewal", 60_000, 1_000, 3_600_000, Some(apply_dhcp_tunables));
+    config::register_fixed("net.arp.cache_size", "Neighbor cache entries (smoltcp build feature)", 4);
+    config::register("net.arp.resolve_timeout_ms", "Report an address as unreachable when ARP gets no answer for this long", 3_000, 500, 60_000, None);
+}
+
+/// Apply the `net.tcp.*` keys to one socket. Call on every new TCP socket.
+pub fn configure_tcp(socket: &mut TcpSocket) {
+    let ms = |key, default| match config::get_or(key, default) {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    };
+    socket.set_timeout(ms("net.tcp.timeout_ms", 0));
+    socket.set_keep_alive(ms("net.tcp.keepalive_ms", 0));
+    socket.set_ack_delay(ms("net.tcp.ack_delay_ms", 10));
+    socket.set_nagle_enabled(config::get_or("net.tcp.nagle", 1) != 0);
+}
+
+fn configure_dhcp(socket: &mut dhcpv4::Socket) {
+    let mut retry = socket.get_retry_config();
+    retry.discover_timeout = Duration::from_millis(config::get_or("net.dhcp.discover_timeout_ms", 10_000));
+    retry.initial_request_timeout = Duration::from_millis(config::get_or("net.dhcp.request_timeout_ms", 5_000));
+    retry.request_retries = config::get_or("net.dhcp.request_retries", 5) as u16;
+    retry.min_renew_timeout = Duration::from_millis(config::get_or("net.dhcp.renew_retry_ms", 60_000));
+    socket.set_retry_config(retry);
+    let max_lease = match config::get_or("net.dhcp.max_lease_s", 0) {
+        0 => None,
+        s => Some(Duration::from_secs(s)),
+    };
+    socket.set_max_lease_duration(max_lease);
+    if let Some(options) = HOSTNAME_OPTION.get() {
+        socket.set_outgoing_options(options);
+    }
+}
+
+/// TCP buffer size per direction of sockets that don't ask for one.
+pub fn tcp_buffer() -> usize {
+    config::get_or("net.tcp.buffer", MIN_TCP_BUFFER as u64) as usize
+}
+
+/// TCP buffer size per direction of the HTTP server's and P2P layer's
+/// connections, both accepted and dialed.
+pub fn service_tcp_buffer() -> usize {
+    config::get_or("net.tcp.service_buffer", SERVICE_TCP_BUFFER as u64) as usize
+}
+
+fn apply_service_buffer(value: u64) {
+    if let Some(stack) = NETWORK_STACK.lock().as_mut() {
+        stack.socket_manager.set_listen_buffer(HTTP_PORT, value as usize);
+        stack.socket_manager.set_listen_buffer(P2P_PORT, value as usize);
+    }
+}
+
+fn apply_tcp_tunables(_value: u64) {
+    if let Some(stack) = NETWORK_STACK.lock().as_mut() {
+        for (_, socket) in stack.sockets.iter_mut() {
+            if let smoltcp::socket::Socket::Tcp(s) = socket {
+                configure_tcp(s);
+            }
+        }
+    }
+}
+
+fn apply_mtu(value: u64) {
+    if let Some(stack) = NETWORK_STACK.lock().as_mut() {
+        for interface in &mut stack.interfaces {
+            interface.device.set_mtu(value as usize);
+        }
+    }
+}
+
+/// Reprogram every NIC's receive filter; `pcap` calls this when capture
+/// is turned on or off.
+pub fn apply_rx_filters() {
+    if let Some(stack) = NETWORK_STACK.lock().as_mut() {
+        for interface in &mut stack.interfaces {
+            interface.apply_rx_filter();
+        }
+    }
+}
+
+fn apply_dhcp_tunables(_value: u64) {
+    if let Some(stack) = NETWORK_STACK.lock().as_mut() {
+        for interface in &mut stack.interfaces {
+            configure_dhcp(&mut interface.dhcp);
+        }
+    }
+}
+
+// ─── Interfaces ──────────────────────────────────────────────────────────────
+//
+// Every NIC gets its own smoltcp `Interface`, with its own address, DHCP
+// client and routes, named `eth0`, `eth1`, … in PCI scan order. All of them
+// share the stack's socket set, so a socket isn't tied to a NIC unless a
+// process binds it to one (`bind_interface`).
+//
+// Routing is by destination, as on a host with one routing table: every
+// interface reaches its own subnet, and only eth0 installs its gateway as
+// the default route. smoltcp sends a socket's packets through the first
+// interface polled that has a route for them, so the other interfaces are
+// polled before eth0. Multicast needs no route and leaves through the
+// first interface polled.
+//
+// smoltcp hands DHCP replies to the first DHCP socket in a set, so each
+// interface keeps its DHCP client to itself, and the stack swaps it into
+// the set's single DHCP slot while that interface is polled.
+
+/// Multicast groups every interface joins, so smoltcp answers IGMP queries
+/// for them and the NIC's receive filter lets them in.
+const MULTICAST_GROUPS: [Ipv4Address; 1] = [crate::mdns::MDNS_GROUP];
+
+/// One NIC and the smoltcp interface on top of it.
+struct NetInterface {
+    iface: Interface,
+    device: NetDevice,
+    mac: EthernetAddress,
+    /// This interface's DHCP client, parked while it isn't being polled.
+    dhcp: dhcpv4::Socket<'static>,
+    /// Current IPv4 configuration (see "Address Configuration").
+    address: AddressConfig,
+    /// When the interface last became unconfigured; set on the first poll.
+    unconfigured_since: Option<Instant>,
+    /// Whether leases from `dhcp` configure the interface; not with an
+    /// address from the command line.
+    use_dhcp: bool,
+}
+
+impl NetInterface {
+    fn new(mut device: NetDevice, mac: [u8; 6]) -> Self {
+        let mac = EthernetAddress(mac);
+        let config = Config::new(HardwareAddress::Ethernet(mac));
+        // No address until DHCP (or the fallback timer) provides one.
+        let mut iface = Interface::new(config, &mut device, Instant::ZERO);
+        // 224.0.0.x groups are link-local: switches flood them, so it
+        // doesn't matter that the IGMP report can't go out without an
+        // address yet.
+        for group in MULTICAST_GROUPS {
+            if iface.join_multicast_group(&mut device, group, Instant::ZERO).is_err() {
+                serial_println!("[NET STACK] Could not join multicast group {}", group);
+            }
+        }
+        let mut dhcp = dhcpv4::Socket::new();
+        configure_dhcp(&mut dhcp);
+        let mut interface = NetInterface {
+            iface,
+            device,
+            mac,
+            dhcp,
+            address: AddressConfig::UNCONFIGURED,
+            unconfigured_since: None,
+            use_dhcp: true,
+        };
+        interface.apply_rx_filter();
+        interface
+    }
+
+    /// Program the NIC's receive filter: promiscuous while packets are
+    /// captured (see `pcap`), else only frames for its MAC address,
+    /// broadcasts and `MULTICAST_GROUPS`.
+    fn apply_rx_filter(&mut self) {
+        if !self.device.has_rx_filter() {
+            return;
+        }
+        let groups = MULTICAST_GROUPS.map(|group| {
+            // RFC 1112: 01:00:5e and the low 23 bits of the group.
+            let octets = group.as_bytes();
+            [0x01, 0x00, 0x5e, octets[1] & 0x7f, octets[2], octets[3]]
+        });
+        let promiscuous = crate::pcap::is_enabled();
+        let result = self.device.set_multicast_filter(&groups).and_then(|()| self.device.set_promiscuous(promiscuous));
+        match result {
+            Ok(()) => {
+                serial_println!("[NET STACK] RX filter: promiscuous {}", if promiscuous { "on" } else { "off" });
+            }
+            Err(e) => { serial_println!("[NET STACK] Could not set the RX filter: {:?}", e); }
+        }
+    }
+
+    /// Make `config` the interface's only address, and its gateway the
+    /// default route if the interface carries it (eth0). Returns whether
+    /// the address changed.
+    fn apply_address(&mut self, config: AddressConfig, default_route: bool) -> bool {
+        self.iface.update_ip_addrs(|addrs| {
+            addrs.clear();
+            if let Some(address) = config.address {
+                addrs.push(IpCidr::Ipv4(address)).ok();
+            }
+        });
+        match config.gateway {
+            Some(gateway) if default_route => { self.iface.routes_mut().add_default_ipv4_route(gateway).ok(); }
+            _ => { self.iface.routes_mut().remove_default_ipv4_route(); }
+        }
+        let changed = self.address.address != config.address;
+        self.address = config;
+        if changed {
+            self.announce();
+        }
+        changed
+    }
+
+    /// Broadcast a gratuitous ARP for the interface's address, if it has
+    /// one (see `neighbor`).
+    fn announce(&mut self) {
+        let Some(cidr) = self.address.address else { return };
+        let mut frame = [0; neighbor::ARP_FRAME_LEN];
+        neighbor::announcement(self.mac, cidr.address(), &mut frame);
+        if let Some(token) = self.device.transmit(Instant::from_millis(interrupts::uptime_ms() as i64)) {
+            token.consume(frame.len(), |buf| buf.copy_from_slice(&frame));
+        }
+    }
+
+    /// Whether `addr` is on one of this interface's subnets.
+    fn on_link(&self, addr: IpAddress) -> bool {
+        self.iface.ip_addrs().iter().any(|cidr| cidr.contains_addr(&addr))
+    }
+}
+
+/// An interface as listed by `interfaces`.
+#[derive(Debug, Clone, Copy)]
+pub struct InterfaceInfo {
+    /// Position in PCI scan order; the interface is `eth<index>`.
+    pub index: usize,
+    pub mac: EthernetAddress,
+    pub mtu: usize,
+    pub address: AddressConfig,
+}
+
+/// Every interface of the stack, eth0 first; empty without a network stack.
+pub fn interfaces() -> Vec<InterfaceInfo> {
+    match NETWORK_STACK.lock().as_ref() {
+        Some(stack) => stack
+            .interfaces
+            .iter()
+            .enumerate()
+            .map(|(index, interface)| InterfaceInfo {
+                index,
+                mac: interface.mac,
+                mtu: interface.device.mtu(),
+                address: interface.address,
+            })
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+// ─── Address Configuration ───────────────────────────────────────────────────
+//
+// Each interface starts without an address and asks DHCP for one. If no lease
+// arrives within `net.dhcp.fallback_ms`, the static QEMU user-network address
+// is applied so the node is reachable anyway; DHCP keeps trying and replaces
+// it once a lease arrives. Losing a lease makes the interface unconfigured
+// again and restarts the fallback timer. Only eth0 falls back: QEMU gives
+// every user-network NIC the same subnet, so the other interfaces wait for
+// DHCP.
+//
+// `net=static,<addr>/<prefix>[,gw=<ip>][,dns=<ip>]` on the kernel command
+// line gives eth0 its address at boot instead. smoltcp can't switch a DHCP
+// client off, so eth0's still asks, but its leases are ignored.
+
+// smoltcp's DHCP client renews the lease at T1 (unicast to its server) and
+// rebinds at T2 (broadcast), and starts over when the lease expires or a
+// server NAKs a renewal; the stack applies whatever comes of it, including
+// an ACK that moves the interface to another address. A FORCERENEW is not
+// honoured, as RFC 6704 requires it to be authenticated. Requests carry the
+// node's host name (option 12) once `set_hostname` has been called.
+//
+// Every change of an interface's address is published as an
+// `EVENT_NET_ADDRESS` message to the endpoints subscribed with
+// `subscribe_events`, so services bound to the old address can rebind.
+// Events go out at `Priority::High`, ahead of ordinary requests.
+
+/// Message label of the event published when an interface's address
+/// changes. Data: `[interface index, address, prefix length]`, with the
+/// address as a big-endian integer (0 once the interface is unconfigured).
+pub const EVENT_NET_ADDRESS: u64 = 0x4E45_5401;
+/// Message label of the event published when ARP gets no answer for an
+/// address (see `neighbor`). Data: `[interface index, address]`.
+pub const EVENT_NET_NEIGHBOR_FAILED: u64 = 0x4E45_5402;
+/// DHCP option carrying the client's host name.
+const DHCP_OPTION_HOSTNAME: u8 = 12;
+
+/// Host name sent to DHCP servers, set once by `set_hostname`.
+static HOSTNAME: spin::Once<String> = spin::Once::new();
+static HOSTNAME_OPTION: spin::Once<[DhcpOption<'static>; 1]> = spin::Once::new();
+/// Broadcast channel of the `EVENT_NET_*` messages, created by the first
+/// subscriber.
+static EVENT_CHANNEL: Mutex<Option<Capability>> = Mutex::new(None);
+
+/// Send `name` as the host name in every DHCP request from now on. Only
+/// the first call counts; the P2P layer makes it with the node's name.
+pub fn set_hostname(name: String) {
+    let name = HOSTNAME.call_once(|| name);
+    let options = HOSTNAME_OPTION.call_once(|| [DhcpOption { kind: DHCP_OPTION_HOSTNAME, data: name.as_bytes() }]);
+    if let Some(stack) = NETWORK_STACK.lock().as_mut() {
+        for interface in &mut stack.interfaces {
+            interface.dhcp.set_outgoing_options(options);
+        }
+    }
+}
+
+/// The host name sent to DHCP servers, once set.
+pub fn hostname() -> Option<&'static str> {
+    HOSTNAME.get().map(String::as_str)
+}
+
+/// Deliver `EVENT_NET_ADDRESS` and `EVENT_NET_NEIGHBOR_FAILED` messages to
+/// the endpoint named by `endpoint` (which needs READ).
+pub fn subscribe_events(endpoint: &Capability) -> Result<(), IpcError> {
+    let mut ipc = IPC_MANAGER.lock();
+    let channel = EVENT_CHANNEL.lock().get_or_insert_with(|| ipc.create_broadcast()).clone();
+    ipc.subscribe(&channel, endpoint)
+}
+
+fn publish_event(msg: Message) {
+    let Some(channel) = EVENT_CHANNEL.lock().clone() else { return };
+    let _ = IPC_MANAGER.lock().publish(&channel, msg.with_priority(Priority::High));
+}
+
+fn publish_address(index: usize, address: Option<Ipv4Cidr>) {
+    let (addr, prefix_len) = address.map_or((0, 0), |cidr| (u32::from_be_bytes(cidr.address().0), cidr.prefix_len()));
+    let mut msg = Message::with_data2(EVENT_NET_ADDRESS, index as u64, u64::from(addr));
+    msg.data[2] = u64::from(prefix_len);
+    msg.length = 3;
+    publish_event(msg);
+}
+
+/// eth0's address from the `net=` boot option; `None` for DHCP.
+fn boot_address() -> Option<AddressConfig> {
+    let value = crate::cmdline::get("net")?;
+    if value == "dhcp" {
+        return None;
+    }
+    let config = parse_static(value);
+    if config.is_none() {
+        serial_println!(
+            "[NET STACK] Ignoring net={}; expected dhcp or static,<addr>/<prefix>[,gw=<ip>][,dns=<ip>].",
+            value
+        );
+    }
+    config
+}
+
+fn parse_static(value: &str) -> Option<AddressConfig> {
+    let mut fields = value.split(',');
+    if fields.next()? != "static" {
+        return None;
+    }
+    let (address, prefix_len) = fields.next()?.split_once('/')?;
+    let prefix_len = prefix_len.parse().ok().filter(|&len| len <= 32)?;
+    let mut config = AddressConfig {
+        source: AddressSource::Static,
+        address: Some(Ipv4Cidr::new(crate::dns::parse_ipv4(address)?, prefix_len)),
+        gateway: None,
+        dns: None,
+    };
+    for field in fields {
+        match field.split_once('=')? {
+            ("gw", ip) => config.gateway = Some(crate::dns::parse_ipv4(ip)?),
+            ("dns", ip) => config.dns = Some(crate::dns::parse_ipv4(ip)?),
+            _ => return None,
+        }
+    }
+    Some(config)
+}
+
+/// Address used when DHCP doesn't answer (QEMU user networking).
+const FALLBACK_ADDRESS: Ipv4Cidr = Ipv4Cidr::new(Ipv4Address::new(10, 0, 2, 15), 24);
+/// Gateway used with `FALLBACK_ADDRESS`.
+const FALLBACK_GATEWAY: Ipv4Address = Ipv4Address::new(10, 0, 2, 2);
+
+/// Where an interface's current address came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSource {
+    /// No address yet; waiting for DHCP.
+    Unconfigured,
+    /// Leased from a DHCP server.
+    Dhcp,
+    /// Set on the command line, or the fallback after DHCP timed out.
+    Static,
+}
+
+/// An interface's IPv4 configuration, as returned by `address_config`.
+#[derive(Debug, Clone, Copy)]
+pub struct AddressConfig {
+    pub source: AddressSource,
+    pub address: Option<Ipv4Cidr>,
+    pub gateway: Option<Ipv4Address>,
+    /// First DNS server offered by DHCP.
+    pub dns: Option<Ipv4Address>,
+}
+
+impl AddressConfig {
+    const UNCONFIGURED: AddressConfig =
+        AddressConfig { source: AddressSource::Unconfigured, address: None, gateway: None, dns: None };
+}
+
+// ─── Process Sockets ─────────────────────────────────────────────────────────
+
+/// First local port handed out to outgoing connections (IANA dynamic range).
+pub const EPHEMERAL_PORT_FIRST: u16 = 49152;
+
+/// Transport of a process socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketKind {
+    Tcp,
+    Udp,
+}
+
+/// Why a socket operation on behalf of a process failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketError {
+    /// The capability names no live process socket.
+    NotFound,
+    /// The capability lacks the permission for this operation.
+    PermissionDenied,
+    /// The socket is in the wrong state (e.g. `send` before `connect`).
+    InvalidState,
+    /// The peer closed the connection.
+    Closed,
+    /// No interface with that index, or it has no address yet.
+    NoInterface,
+    /// The datagram is larger than the socket's send buffer.
+    TooLarge,
+}
+
+struct ProcessSocket {
+    /// Resource id of the socket's capabilities.
+    id: u64,
+    kind: SocketKind,
+    /// Default destination of a UDP socket, set by `connect`.
+    peer: Option<IpEndpoint>,
+    /// Interface chosen with `bind_interface`.
+    interface: Option<usize>,
+}
+
+static WEDGES_DETECTED: AtomicU64 = AtomicU64::new(0);
+static RESETS_SUCCEEDED: AtomicU64 = AtomicU64::new(0);
+static RESETS_FAILED: AtomicU64 = AtomicU64::new(0);
+
+/// Counters for automatic recovery of wedged network devices.
+#[derive(Debug, Clone, Copy)]
+pub struct RecoveryStats {
+    /// Times the device was found with TX descriptors stuck.
+    pub wedges_detected: u64,
+    /// Device resets after which the driver came back up.
+    pub resets_succeeded: u64,
+    /// Device resets after which the driver failed to initialize.
+    pub resets_failed: u64,
+}
+
+pub fn recovery_stats() -> RecoveryStats {
+    RecoveryStats {
+        wedges_detected: WEDGES_DETECTED.load(Ordering::Relaxed),
+        resets_succeeded: RESETS_SUCCEEDED.load(Ordering::Relaxed),
+        resets_failed: RESETS_FAILED.load(Ordering::Relaxed),
+    }
+}
+
+/// Traffic through one socket since it was last handed out. Counted where
+/// data enters and leaves the socket's buffers, so bytes are payload.
+///
+/// The TCP fields after `tx_full` are filled in by `net_stats`, from the
+/// socket and `tcp_monitor`; they are zero for UDP.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SocketStats {
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    /// Sends that found the socket's buffer full and queued nothing.
+    pub tx_full: u64,
+    /// Buffer bytes per direction.
+    pub buffer: usize,
+    /// Free receive buffer: the window advertised to the peer.
+    pub rx_window: usize,
+    /// Bytes not yet sent or not yet acknowledged.
+    pub tx_queued: usize,
+    /// Segments sent again.
+    pub retransmits: u64,
+    /// Segments sent advertising a zero window.
+    pub zero_windows: u64,
+}
+
+/// Interface and per-socket counters, from `net_stats`.
+#[derive(Debug, Clone)]
+pub struct NetStats {
+    pub interface: InterfaceStats,
+    /// Sockets in use that have seen traffic.
+    pub sockets: Vec<(SocketHandle, SocketStats)>,
+}
+
+/// Interface counters, plus per-socket counters when the stack is up.
+pub fn net_stats() -> NetStats {
+    let sockets = match NETWORK_STACK.lock().as_ref() {
+        Some(stack) => stack
+            .socket_stats
+            .iter()
+            .filter(|&&(handle, _)| !stack.socket_idle(handle))
+            .map(|&(handle, stats)| (handle, stack.tcp_snapshot(handle, stats)))
+            .collect(),
+        None => Vec::new(),
+    };
+    NetStats { interface: net_interface::interface_stats(), sockets }
+}
+
 pub struct NetworkStack {
-    pub iface: Interface,
-    pub device: VirtioNetDevice,
+    /// eth0 first (see "Interfaces"); never empty.
+    interfaces: Vec<NetInterface>,
     pub sockets: SocketSet<'static>,
+    /// The DHCP slot: holds the client of the interface being polled.
     pub dhcp_handle: SocketHandle,
     pub udp_handle: SocketHandle,
-    pub tcp_handle: SocketHandle,
-    pub p2p_handle: SocketHandle,
+    /// Echo requests sent by `icmp::send`.
+    pub icmp_handle: SocketHandle,
+    /// Sockets handed out as capabilities: (resource id, handle).
+    socket_caps: Vec<(u64, SocketHandle)>,
+    next_socket_id: u64,
+    /// Sockets created on behalf of processes (see `open_socket`).
+    process_sockets: Vec<ProcessSocket>,
+    /// Sockets added at runtime (see `create_socket`).
+    socket_manager: SocketManager,
+    next_local_port: u16,
+    /// Counters of sockets that have seen traffic (see `SocketStats`).
+    socket_stats: Vec<(SocketHandle, SocketStats)>,
 }
 
 impl NetworkStack {
-    pub fn new(mut device: VirtioNetDevice, mac: [u8; 6]) -> Self {
-        serial_println!("[NET STACK] Creating interface with MAC: {:02x?}", mac);
-
-        // Create interface configuration
-        let ethernet_addr = EthernetAddress(mac);
-        let hw_addr = HardwareAddress::Ethernet(ethernet_addr);
-        let config = Config::new(hw_addr);
-
-        // Create interface (needs mutable ref to device)
-        let mut iface = Interface::new(config, &mut device, Instant::ZERO);
-        
-        // Static IP Configuration (10.0.2.15)
-        iface.update_ip_addrs(|addrs| {
-            addrs.push(IpCidr::new(smoltcp::wire::IpAddress::v4(10, 0, 2, 15), 24)).ok();
-        });
-        iface.routes_mut().add_default_ipv4_route(smoltcp::wire::Ipv4Address::new(10, 0, 2, 2)).ok();
+    /// A stack with `device` as eth0.
+    pub fn new(device: NetDevice, mac: [u8; 6]) -> Self {
+        serial_println!("[NET STACK] Creating interface eth0 with MAC: {:02x?}", mac);
 
         // Create socket set
         let mut sockets = SocketSet::new(Vec::new());
 
-        // 1. DHCP Socket (Optional, kept for testing)
-        let dhcp_socket = dhcpv4::Socket::new();
-        let dhcp_handle = sockets.add(dhcp_socket);
-        /* 
-        let dhcp_handle = SocketHandle::default();
-        */
+        register_tunables();
+        let mut interface = NetInterface::new(device, mac);
+        if let Some(config) = boot_address() {
+            serial_println!(
+                "[NET STACK] eth0 static address {:?} via {:?}, DNS {:?}",
+                config.address, config.gateway, config.dns
+            );
+            interface.apply_address(config, true);
+            interface.use_dhcp = false;
+        }
+
+        // 1. DHCP Slot (see "Interfaces")
+        let dhcp_handle = sockets.add(dhcpv4::Socket::new());
 
         // 2. UDP Echo Socket (Port 6969)
         let udp_rx_buffer = udp::PacketBuffer::new(
@@ -67,195 +633,963 @@ impl NetworkStack {
         udp_socket.bind(6969).expect("Failed to bind UDP socket");
         let udp_handle = sockets.add(udp_socket);
 
-        // 3. TCP Echo Socket (Port 80)
-        let tcp_rx_buffer = TcpSocketBuffer::new(vec![0; 1024]);
-        let tcp_tx_buffer = TcpSocketBuffer::new(vec![0; 1024]);
-        let mut tcp_socket = TcpSocket::new(tcp_rx_buffer, tcp_tx_buffer);
-        tcp_socket.listen(80).expect("Failed to listen on TCP socket");
-        let tcp_handle = sockets.add(tcp_socket);
+        // 3. HTTP, P2P and Packet Capture Listeners (Ports 80, 40444, 5555);
+        //    the sockets are added by the first poll
+        let mut socket_manager = SocketManager::new();
+        socket_manager.listen(HTTP_PORT, service_tcp_buffer());
+        socket_manager.listen(P2P_PORT, service_tcp_buffer());
+        socket_manager.listen(crate::pcap::PCAP_PORT, tcp_buffer());
 
-        // 4. P2P Socket (Port 40444)
-        let mut p2p_rx_buffer = tcp::SocketBuffer::new(vec![0; 4096]);
-        let mut p2p_tx_buffer = tcp::SocketBuffer::new(vec![0; 4096]);
-        let mut p2p_socket = TcpSocket::new(p2p_rx_buffer, p2p_tx_buffer);
-        p2p_socket.listen(40444).expect("Failed to listen on P2P port");
-        let p2p_handle = sockets.add(p2p_socket);
+        // 4. ICMP Socket (ping client; the interface answers pings itself)
+        let icmp_handle = sockets.add(crate::icmp::socket());
 
         serial_println!("[NET STACK] Interface created.");
-        serial_println!("[NET STACK] Services: DHCP, UDP Echo (6969), TCP Echo (80), P2P (40444)");
+        serial_println!(
+            "[NET STACK] Services: DHCP, UDP Echo (6969), HTTP ({}), P2P ({}), pcap ({}), ICMP Echo",
+            HTTP_PORT, P2P_PORT, crate::pcap::PCAP_PORT
+        );
 
         Self {
-            iface,
-            device,
+            interfaces: vec![interface],
             sockets,
             dhcp_handle,
             udp_handle,
-            tcp_handle,
-            p2p_handle,
+            icmp_handle,
+            socket_caps: Vec::new(),
+            next_socket_id: 1,
+            process_sockets: Vec::new(),
+            socket_manager,
+            next_local_port: EPHEMERAL_PORT_FIRST,
+            socket_stats: Vec::new(),
+        }
+    }
+
+    /// Add another NIC as the next `eth<n>`. Returns its index.
+    pub fn add_interface(&mut self, device: NetDevice, mac: [u8; 6]) -> usize {
+        let index = self.interfaces.len();
+        serial_println!("[NET STACK] Creating interface eth{} with MAC: {:02x?}", index, mac);
+        self.interfaces.push(NetInterface::new(device, mac));
+        index
+    }
+
+    /// Run `f` on interface `index` with its DHCP client in the slot.
+    fn with_interface<R>(
+        &mut self,
+        index: usize,
+        f: impl FnOnce(&mut NetInterface, &mut SocketSet<'static>) -> R,
+    ) -> R {
+        let interface = &mut self.interfaces[index];
+        let slot = self.sockets.get_mut::<dhcpv4::Socket>(self.dhcp_handle);
+        core::mem::swap(slot, &mut interface.dhcp);
+        let result = f(interface, &mut self.sockets);
+        let slot = self.sockets.get_mut::<dhcpv4::Socket>(self.dhcp_handle);
+        core::mem::swap(slot, &mut interface.dhcp);
+        result
+    }
+
+    /// Poll every interface once, eth0 last (see "Interfaces").
+    fn poll_interfaces(&mut self, timestamp: Instant) {
+        for index in (0..self.interfaces.len()).rev() {
+            self.with_interface(index, |interface, sockets| {
+                interface.iface.poll(timestamp, &mut interface.device, sockets);
+            });
+        }
+    }
+
+    /// How soon any interface wants to be polled again.
+    fn poll_delay(&mut self, timestamp: Instant) -> Option<Duration> {
+        (0..self.interfaces.len())
+            .filter_map(|index| {
+                self.with_interface(index, |interface, sockets| interface.iface.poll_delay(timestamp, sockets))
+            })
+            .min()
+    }
+
+    /// The interface a packet to `addr` leaves through: the first one, in
+    /// poll order, with `addr` on its subnet, else eth0.
+    fn route(&self, addr: IpAddress) -> usize {
+        (1..self.interfaces.len()).rev().find(|&index| self.interfaces[index].on_link(addr)).unwrap_or(0)
+    }
+
+    /// Wrap a socket in a capability so it can be handed to a process.
+    ///
+    /// The capability's `resource_id` is a stack-assigned socket id, not the
+    /// smoltcp handle, so it stays meaningful only while the socket is
+    /// registered here.
+    pub fn socket_capability(&mut self, handle: SocketHandle, permissions: Permissions) -> Capability {
+        let id = match self.socket_caps.iter().find(|(_, h)| *h == handle) {
+            Some((id, _)) => *id,
+            None => {
+                let id = self.next_socket_id;
+                self.next_socket_id += 1;
+                self.socket_caps.push((id, handle));
+                id
+            }
+        };
+        Capability::new(CapabilityType::Socket, permissions, id)
+    }
+
+    /// Resolve a socket capability back into a handle.
+    ///
+    /// Returns `None` if the capability is not a socket capability, lacks the
+    /// `required` permissions, or refers to a socket that has been revoked.
+    pub fn resolve_socket(&self, cap: &Capability, required: Permissions) -> Option<SocketHandle> {
+        if cap.cap_type != CapabilityType::Socket || !cap.permissions.contains(required) {
+            return None;
         }
+        self.socket_caps
+            .iter()
+            .find(|(id, _)| *id == cap.resource_id)
+            .map(|(_, handle)| *handle)
+    }
+
+    /// Revoke a socket: every capability (and derived copy) referring to it
+    /// stops resolving. Returns the handle so the caller can close the socket.
+    pub fn revoke_socket(&mut self, socket_id: u64) -> Option<SocketHandle> {
+        let idx = self.socket_caps.iter().position(|(id, _)| *id == socket_id)?;
+        Some(self.socket_caps.swap_remove(idx).1)
     }
 
     pub fn poll(&mut self, timestamp: Instant) {
         static POLL_COUNT: AtomicU64 = AtomicU64::new(0);
         let count = POLL_COUNT.fetch_add(1, Ordering::Relaxed);
 
-        if count % 500 == 0 {
-             if let Some(cidr) = self.iface.ip_addrs().first() {
-                 serial_println!("[NET STACK] Poll #{}: IP: {} Time: {}ms", count, cidr, timestamp.total_millis());
+        if count.is_multiple_of(500) {
+             if let Some(cidr) = self.interfaces[0].iface.ip_addrs().first() {
+                 klog!(klog::NET, Level::Debug, "[NET STACK] Poll #{}: IP: {} Time: {}ms", count, cidr, timestamp.total_millis());
              } else {
-                 serial_println!("[NET STACK] Poll #{}: No IP. Time: {}ms", count, timestamp.total_millis());
+                 klog!(klog::NET, Level::Debug, "[NET STACK] Poll #{}: No IP. Time: {}ms", count, timestamp.total_millis());
              }
         }
 
-        // 1. Handle DHCP
-        let socket = self.sockets.get_mut::<dhcpv4::Socket>(self.dhcp_handle);
-        let event = socket.poll();
-        if event.is_some() {
-             serial_println!("[NET STACK] DHCP Event: {:?}", event);
+        // 1. Address configuration
+        for index in 0..self.interfaces.len() {
+            self.poll_address(index, timestamp);
         }
-        match event {
-            Some(dhcpv4::Event::Configured(config)) => {
-                serial_println!("[NET STACK] DHCP configuration received:");
-                serial_println!("  IP Address: {}", config.address);
-                if let Some(router) = config.router {
-                    serial_println!("  Gateway: {}", router);
-                }
 
-                // Update interface IP addresses
-                self.iface.update_ip_addrs(|addrs| {
-                    addrs.clear();
-                    addrs.push(IpCidr::Ipv4(config.address)).ok();
-                });
+        self.socket_manager.reap(&mut self.sockets);
+        let room = max_sockets().saturating_sub(self.socket_count());
+        let backlog = config::get_or("net.tcp.backlog", 4) as usize;
+        self.socket_manager.fill_listeners(&mut self.sockets, backlog, room);
 
-                // Set default route via router
-                if let Some(router) = config.router {
-                    self.iface.routes_mut().add_default_ipv4_route(router).ok();
-                }
-            }
-            Some(dhcpv4::Event::Deconfigured) => {
-                serial_println!("[NET STACK] DHCP lease lost. Setting fallback IP 10.0.2.15");
-                self.iface.update_ip_addrs(|addrs| {
-                    addrs.clear();
-                    addrs.push(IpCidr::new(smoltcp::wire::IpAddress::v4(10, 0, 2, 15), 24)).ok();
-                });
-                self.iface.routes_mut().add_default_ipv4_route(smoltcp::wire::Ipv4Address::new(10, 0, 2, 2)).ok();
-            }
-            None => {}
-        }
-        
-        /*
-        */
-
-        // Poll the interface
-        let changed = self.iface.poll(timestamp, &mut self.device, &mut self.sockets);
+        // Poll the interfaces
+        self.poll_interfaces(timestamp);
 
-        // Log significant changes (optional, reduced verbosity)
-        if changed && count < 50 {
-            // serial_println!("[NET STACK] poll #{}: interface state changed!", count);
-        }
+        // 2. Match ping replies
+        crate::icmp::collect_replies(self.sockets.get_mut::<smoltcp::socket::icmp::Socket>(self.icmp_handle));
 
-        // 2. Handle UDP Echo
+        // 3. Handle UDP Echo
         let socket = self.sockets.get_mut::<UdpSocket>(self.udp_handle);
         if socket.can_recv() {
             let mut buf = [0u8; 1500];
             match socket.recv_slice(&mut buf) {
                 Ok((len, endpoint)) => {
-                    serial_println!("[UDP] Recv {} bytes from {}", len, endpoint);
+                    klog!(klog::NET, Level::Debug, "[UDP] Recv {} bytes from {}", len, endpoint);
                     // Echo back
-                    if socket.can_send() {
-                         socket.send_slice(&buf[..len], endpoint).ok();
-                    }
+                    let echoed = socket.can_send() && socket.send_slice(&buf[..len], endpoint).is_ok();
+                    self.record_recv(self.udp_handle, len);
+                    self.record_send(self.udp_handle, len, if echoed { len } else { 0 });
                 }
                 Err(_) => {}
             }
         }
 
-        // 3. Handle TCP Echo
-        let socket = self.sockets.get_mut::<TcpSocket>(self.tcp_handle);
-        if socket.is_active() && !socket.is_open() {
-             // connection closed, re-listen
-             // actually smoltcp tcp socket stays in state, we might need to check if we need to listen again?
-             // listen() puts it in Listen state. If it was Active (connected) and then remote closed, it goes to CloseWait/LastAck/Closed.
-             // We need to re-listen if it's Closed.
-             // For now, simple echo:
-        }
-        
-        if socket.may_recv() {
-            // We can read data
-            // Since we want to echo, we can just pipe recv to send?
-            // But we need a buffer or loop.
-            // Let's inspect the recv buffer.
-            
-            // Note: simple echo using recv_slice/send_slice
-            // We need to dequeue data to free buffer space
-            // socket.recv(|data| {
-            //     if data.len() > 0 {
-            //         serial_println!("[TCP] Recv {} bytes", data.len());
-            //          // send queue might be full, so we can't always echo all.
-            //          // For this simple demo, assume we can echo.
-            //          let len = data.len();
-            //          (len, Try to send data) -- HARD to do zero copy echo in one closure.
-            //     }
-            //     (0, ())
-            // });
-            
-            // easier: peek, try send, if sent -> data received.
-            // But TcpSocket doesn't allow easy "peek and remove conditionally on send".
-            // We'll allocate a temp buffer.
-            let mut buf = [0u8; 1024];
-            match socket.recv_slice(&mut buf) {
-                Ok(len) if len > 0 => {
-                     serial_println!("[TCP] Recv {} bytes", len);
-                     if socket.may_send() {
-                         match socket.send_slice(&buf[..len]) {
-                             Ok(_) => {},
-                             Err(e) => { serial_println!("[TCP] Echo failed: {:?}", e); },
-                         }
-                     }
+        // 4. Neighbors that never answered ARP
+        for (index, interface) in self.interfaces.iter().enumerate() {
+            neighbor::expire(interface.mac, |addr| {
+                serial_println!("[NET STACK] eth{}: no ARP answer from {}", index, addr);
+                let addr = u64::from(u32::from_be_bytes(addr.0));
+                publish_event(Message::with_data2(EVENT_NET_NEIGHBOR_FAILED, index as u64, addr));
+            });
+        }
+
+        // 5. Recover from wedged devices
+        for index in 0..self.interfaces.len() {
+            if self.interfaces[index].device.is_wedged(timestamp) {
+                self.recover_device(index);
+            }
+        }
+    }
+
+    /// Reset a wedged NIC and re-initialize its queues in place.
+    ///
+    /// The interface and socket set are kept, so established TCP connections
+    /// survive the reset: whatever was lost in the dead queues is simply
+    /// retransmitted by smoltcp.
+    fn recover_device(&mut self, index: usize) {
+        WEDGES_DETECTED.fetch_add(1, Ordering::Relaxed);
+        let interface = &mut self.interfaces[index];
+        serial_println!(
+            "[NET STACK] eth{} wedged ({} TX descriptors stuck), resetting...",
+            index, interface.device.tx_in_flight()
+        );
+
+        if interface.device.recover() {
+            interface.apply_rx_filter();
+            RESETS_SUCCEEDED.fetch_add(1, Ordering::Relaxed);
+            serial_println!("[NET STACK] Device recovered.");
+        } else {
+            RESETS_FAILED.fetch_add(1, Ordering::Relaxed);
+            serial_println!("[NET STACK] Device recovery failed; use net_stack::restart() to retry.");
+        }
+    }
+
+    // ─── Runtime Sockets ─────────────────────────────────────────────
+
+    /// Add a TCP or UDP socket for a kernel task, reusing an idle one when
+    /// possible; a TCP socket gets `buffer` bytes per direction (see
+    /// `socket_manager::tcp_buffer_size`). Hand it back with `destroy_socket`.
+    ///
+    /// Callers should pass the request through admission control first
+    /// (`socket_manager::socket_heap` of heap, one socket).
+    pub fn create_socket(&mut self, kind: SocketKind, buffer: usize) -> SocketHandle {
+        let handle = self.socket_manager.allocate(&mut self.sockets, kind, buffer);
+        self.reset_stats(handle);
+        handle
+    }
+
+    /// Close a socket from `create_socket` and reclaim it for reuse. A TCP
+    /// connection finishes its FIN exchange first; `abort` it beforehand to
+    /// reset it instead.
+    pub fn destroy_socket(&mut self, handle: SocketHandle, kind: SocketKind) {
+        self.socket_manager.release(&mut self.sockets, handle, kind);
+    }
+
+    /// Take a connection accepted on a kernel service port (`HTTP_PORT`,
+    /// `P2P_PORT`). Hand it back with `destroy_socket` or `tcp_close`.
+    pub fn accept(&mut self, port: u16) -> Option<SocketHandle> {
+        let handle = self.socket_manager.accept(&mut self.sockets, port)?;
+        self.reset_stats(handle);
+        Some(handle)
+    }
+
+    // ─── Socket Statistics ───────────────────────────────────────────
+
+    /// Count `bytes` read from `handle`'s receive buffer.
+    pub fn record_recv(&mut self, handle: SocketHandle, bytes: usize) {
+        if bytes > 0 {
+            self.stats_mut(handle).rx_bytes += bytes as u64;
+        }
+    }
+
+    /// Count a send of `wanted` bytes to `handle` of which `sent` were queued.
+    pub fn record_send(&mut self, handle: SocketHandle, wanted: usize, sent: usize) {
+        let stats = self.stats_mut(handle);
+        stats.tx_bytes += sent as u64;
+        if sent == 0 && wanted > 0 {
+            stats.tx_full += 1;
+        }
+    }
+
+    /// `stats` with the TCP fields filled in, if `handle` is a TCP socket.
+    fn tcp_snapshot(&self, handle: SocketHandle, mut stats: SocketStats) -> SocketStats {
+        let tcp = self.sockets.iter().find_map(|(h, socket)| match socket {
+            smoltcp::socket::Socket::Tcp(tcp) if h == handle => Some(tcp),
+            _ => None,
+        });
+        if let Some(socket) = tcp {
+            stats.buffer = socket.recv_capacity();
+            stats.rx_window = socket.recv_capacity() - socket.recv_queue();
+            stats.tx_queued = socket.send_queue();
+            let flow = socket.local_endpoint().zip(socket.remote_endpoint());
+            if let Some((retransmits, zero_windows)) =
+                flow.and_then(|(local, remote)| crate::tcp_monitor::flow(local.port, remote))
+            {
+                stats.retransmits = retransmits;
+                stats.zero_windows = zero_windows;
+            }
+        }
+        stats
+    }
+
+    fn stats_mut(&mut self, handle: SocketHandle) -> &mut SocketStats {
+        let idx = match self.socket_stats.iter().position(|&(h, _)| h == handle) {
+            Some(idx) => idx,
+            None => {
+                self.socket_stats.push((handle, SocketStats::default()));
+                self.socket_stats.len() - 1
+            }
+        };
+        &mut self.socket_stats[idx].1
+    }
+
+    /// Start a reused socket's counters from zero. The entry is kept, so
+    /// the table never grows past the number of sockets.
+    fn reset_stats(&mut self, handle: SocketHandle) {
+        if let Some((_, stats)) = self.socket_stats.iter_mut().find(|(h, _)| *h == handle) {
+            *stats = SocketStats::default();
+        }
+    }
+
+    /// Whether `handle` is a pooled socket waiting to be reused.
+    pub fn socket_idle(&self, handle: SocketHandle) -> bool {
+        self.socket_manager.is_idle(handle)
+    }
+
+    // ─── Process Sockets ─────────────────────────────────────────────
+
+    /// Create a socket for a process and return a capability for it with
+    /// `permissions` (a subset of CONNECT, LISTEN, SEND, RECV). A TCP
+    /// socket has `net.tcp.buffer` bytes of buffer until `set_buffer_size`.
+    ///
+    /// Callers should pass the request through admission control first.
+    pub fn open_socket(&mut self, kind: SocketKind, permissions: Permissions) -> Capability {
+        let handle = self.create_socket(kind, tcp_buffer());
+        let cap = self.socket_capability(handle, permissions);
+        self.process_sockets.push(ProcessSocket { id: cap.resource_id, kind, peer: None, interface: None });
+        cap
+    }
+
+    /// Connect a TCP socket, or set the default destination of a UDP
+    /// socket (binding it to an ephemeral port first). Requires CONNECT.
+    pub fn connect(&mut self, cap: &Capability, remote: IpEndpoint) -> Result<(), SocketError> {
+        let (handle, kind) = self.process_socket(cap, Permissions::CONNECT)?;
+        let port = self.ephemeral_port();
+        let local = self.local_endpoint(cap, port)?;
+        match kind {
+            SocketKind::Tcp => {
+                let index = self.socket_interface(cap).unwrap_or_else(|| self.route(remote.addr));
+                let cx = self.interfaces[index].iface.context();
+                self.sockets
+                    .get_mut::<TcpSocket>(handle)
+                    .connect(cx, remote, local)
+                    .map_err(|_| SocketError::InvalidState)
+            }
+            SocketKind::Udp => {
+                let socket = self.sockets.get_mut::<UdpSocket>(handle);
+                if !socket.is_open() {
+                    socket.bind(local).map_err(|_| SocketError::InvalidState)?;
                 }
-                _ => {}
+                if let Some(entry) = self.process_sockets.iter_mut().find(|s| s.id == cap.resource_id) {
+                    entry.peer = Some(remote);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Listen for TCP connections on `port`, or bind a UDP socket to it.
+    /// Requires LISTEN. A listening TCP socket serves one connection.
+    pub fn listen(&mut self, cap: &Capability, port: u16) -> Result<(), SocketError> {
+        let (handle, kind) = self.process_socket(cap, Permissions::LISTEN)?;
+        let local = self.local_endpoint(cap, port)?;
+        let result = match kind {
+            SocketKind::Tcp => self.sockets.get_mut::<TcpSocket>(handle).listen(local).map_err(|_| ()),
+            SocketKind::Udp => self.sockets.get_mut::<UdpSocket>(handle).bind(local).map_err(|_| ()),
+        };
+        result.map_err(|_| SocketError::InvalidState)
+    }
+
+    /// Tie a process socket to interface `index` (`eth<index>`): `listen`
+    /// and `connect` then use that interface's address, so the socket only
+    /// sees traffic sent to that address. Call before either; they fail
+    /// with `NoInterface` while the interface has no address. Needs no
+    /// permission beyond holding the socket.
+    pub fn bind_interface(&mut self, cap: &Capability, index: usize) -> Result<(), SocketError> {
+        self.process_socket(cap, Permissions::NONE)?;
+        if index >= self.interfaces.len() {
+            return Err(SocketError::NoInterface);
+        }
+        if let Some(entry) = self.process_sockets.iter_mut().find(|s| s.id == cap.resource_id) {
+            entry.interface = Some(index);
+        }
+        Ok(())
+    }
+
+    /// Give a TCP process socket `bytes` of buffer per direction, rounded
+    /// as `socket_manager::tcp_buffer_size` says, and return the size it
+    /// got. Only before `connect` or `listen`; needs no permission beyond
+    /// holding the socket. Callers should pass a larger buffer through
+    /// admission control first.
+    pub fn set_buffer_size(&mut self, cap: &Capability, bytes: usize) -> Result<usize, SocketError> {
+        let (handle, kind) = self.process_socket(cap, Permissions::NONE)?;
+        if kind != SocketKind::Tcp || self.sockets.get::<TcpSocket>(handle).state() != tcp::State::Closed {
+            return Err(SocketError::InvalidState);
+        }
+        let size = crate::socket_manager::tcp_buffer_size(bytes);
+        if self.sockets.get::<TcpSocket>(handle).recv_capacity() != size {
+            // Buffers can't be resized, so swap in a socket with the new size.
+            let replacement = self.create_socket(SocketKind::Tcp, size);
+            if let Some(entry) = self.socket_caps.iter_mut().find(|(id, _)| *id == cap.resource_id) {
+                entry.1 = replacement;
             }
-        } else if socket.state() == tcp::State::Closed {
-            // If closed, listen again
-            socket.listen(80).ok();
-        }
-
-        // 4. Handle P2P Socket (Debug State)
-        let socket = self.sockets.get_mut::<TcpSocket>(self.p2p_handle);
-        let p2p_state = socket.state();
-        if p2p_state != tcp::State::Listen && p2p_state != tcp::State::Closed {
-             serial_println!("[NET STACK] P2P Socket State: {:?}", p2p_state);
-        }
-
-        // 5. Periodic Heartbeat to Gateway (helps SLIRP find us)
-        static LAST_HEARTBEAT: AtomicU64 = AtomicU64::new(0);
-        let now_ms = timestamp.total_millis() as u64;
-        let last = LAST_HEARTBEAT.load(Ordering::Relaxed);
-        if now_ms > last && now_ms - last > 5000 {
-            LAST_HEARTBEAT.store(now_ms, Ordering::Relaxed);
-            
-            // Only send if we have an IP
-            if self.iface.ip_addrs().first().is_some() {
-                let socket = self.sockets.get_mut::<UdpSocket>(self.udp_handle);
-                if socket.can_send() {
-                    let gateway = smoltcp::wire::IpEndpoint::new(smoltcp::wire::IpAddress::v4(10, 0, 2, 2), 12345);
-                    serial_println!("[NET STACK] Sending Heartbeat to gateway 10.0.2.2...");
-                    socket.send_slice(b"PING", gateway).ok();
+            self.destroy_socket(handle, SocketKind::Tcp);
+        }
+        Ok(size)
+    }
+
+    /// The interface a process socket was bound to, if any.
+    fn socket_interface(&self, cap: &Capability) -> Option<usize> {
+        self.process_sockets.iter().find(|s| s.id == cap.resource_id).and_then(|s| s.interface)
+    }
+
+    /// Where a process socket listens or sends from: `port` on its
+    /// interface's address, or on every address if it has none.
+    fn local_endpoint(&self, cap: &Capability, port: u16) -> Result<IpListenEndpoint, SocketError> {
+        let addr = match self.socket_interface(cap) {
+            Some(index) => {
+                let address = self.interfaces[index].address.address.ok_or(SocketError::NoInterface)?;
+                Some(IpAddress::Ipv4(address.address()))
+            }
+            None => None,
+        };
+        Ok(IpListenEndpoint { addr, port })
+    }
+
+    /// Queue `data` for transmission without blocking. Returns the number
+    /// of bytes accepted (0 if the buffer is full). Requires SEND.
+    ///
+    /// A UDP socket sends one datagram to the peer set by `connect`.
+    pub fn send(&mut self, cap: &Capability, data: &[u8]) -> Result<usize, SocketError> {
+        let (handle, kind) = self.process_socket(cap, Permissions::SEND)?;
+        let sent = match kind {
+            SocketKind::Tcp => {
+                let socket = self.sockets.get_mut::<TcpSocket>(handle);
+                if !socket.may_send() {
+                    return Err(if socket.is_open() { SocketError::InvalidState } else { SocketError::Closed });
+                }
+                socket.send_slice(data).map_err(|_| SocketError::Closed)?
+            }
+            SocketKind::Udp => {
+                let peer = self
+                    .process_sockets
+                    .iter()
+                    .find(|s| s.id == cap.resource_id)
+                    .and_then(|s| s.peer)
+                    .ok_or(SocketError::InvalidState)?;
+                if self.queue_datagram(handle, data, peer)? { data.len() } else { 0 }
+            }
+        };
+        self.record_send(handle, data.len(), sent);
+        Ok(sent)
+    }
+
+    /// Queue one datagram to `remote` on a UDP socket without blocking,
+    /// binding the socket to an ephemeral port first if it isn't bound.
+    /// Returns whether it was queued (`false` if the send buffer is full).
+    /// Requires SEND.
+    pub fn send_to(&mut self, cap: &Capability, data: &[u8], remote: IpEndpoint) -> Result<bool, SocketError> {
+        let (handle, kind) = self.process_socket(cap, Permissions::SEND)?;
+        if kind != SocketKind::Udp {
+            return Err(SocketError::InvalidState);
+        }
+        if !self.sockets.get::<UdpSocket>(handle).is_open() {
+            let port = self.ephemeral_port();
+            let local = self.local_endpoint(cap, port)?;
+            self.sockets.get_mut::<UdpSocket>(handle).bind(local).map_err(|_| SocketError::InvalidState)?;
+        }
+        let queued = self.queue_datagram(handle, data, remote)?;
+        self.record_send(handle, data.len(), if queued { data.len() } else { 0 });
+        Ok(queued)
+    }
+
+    fn queue_datagram(&mut self, handle: SocketHandle, data: &[u8], remote: IpEndpoint) -> Result<bool, SocketError> {
+        let socket = self.sockets.get_mut::<UdpSocket>(handle);
+        if data.len() > socket.payload_send_capacity() {
+            return Err(SocketError::TooLarge);
+        }
+        match socket.send_slice(data, remote) {
+            Ok(()) => Ok(true),
+            Err(udp::SendError::BufferFull) => Ok(false),
+            Err(udp::SendError::Unaddressable) => Err(SocketError::InvalidState),
+        }
+    }
+
+    /// Receive into `buf` without blocking. Returns the number of bytes read
+    /// (0 if nothing is ready), or `Closed` once a TCP peer has closed and
+    /// everything it sent has been read. Requires RECV.
+    ///
+    /// A UDP socket returns one datagram per call, truncated to `buf`.
+    pub fn recv(&mut self, cap: &Capability, buf: &mut [u8]) -> Result<usize, SocketError> {
+        let (handle, kind) = self.process_socket(cap, Permissions::RECV)?;
+        let received = match kind {
+            SocketKind::Tcp => {
+                let socket = self.sockets.get_mut::<TcpSocket>(handle);
+                match socket.recv_slice(buf) {
+                    Ok(n) => n,
+                    Err(tcp::RecvError::Finished) => return Err(SocketError::Closed),
+                    Err(tcp::RecvError::InvalidState) => return Err(SocketError::InvalidState),
                 }
             }
+            SocketKind::Udp => self.take_datagram(handle, buf).map_or(0, |(n, _)| n),
+        };
+        self.record_recv(handle, received);
+        Ok(received)
+    }
+
+    /// Receive one datagram from a UDP socket into `buf`, truncated to
+    /// fit, without blocking. Returns its length and sender, or `None` if
+    /// nothing is ready. Requires RECV.
+    pub fn recv_from(&mut self, cap: &Capability, buf: &mut [u8]) -> Result<Option<(usize, IpEndpoint)>, SocketError> {
+        let (handle, kind) = self.process_socket(cap, Permissions::RECV)?;
+        if kind != SocketKind::Udp {
+            return Err(SocketError::InvalidState);
+        }
+        let received = self.take_datagram(handle, buf);
+        if let Some((n, _)) = received {
+            self.record_recv(handle, n);
+        }
+        Ok(received)
+    }
+
+    fn take_datagram(&mut self, handle: SocketHandle, buf: &mut [u8]) -> Option<(usize, IpEndpoint)> {
+        let (data, meta) = self.sockets.get_mut::<UdpSocket>(handle).recv().ok()?;
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        Some((n, meta.endpoint))
+    }
+
+    /// Close a process socket and revoke every capability to it. TCP
+    /// sockets finish their FIN exchange in the background.
+    pub fn close_socket(&mut self, socket_id: u64) -> Result<(), SocketError> {
+        let idx = self
+            .process_sockets
+            .iter()
+            .position(|s| s.id == socket_id)
+            .ok_or(SocketError::NotFound)?;
+        let entry = self.process_sockets.swap_remove(idx);
+        let handle = self.revoke_socket(socket_id).ok_or(SocketError::NotFound)?;
+        self.destroy_socket(handle, entry.kind);
+        Ok(())
+    }
+
+    /// Resolve a capability to a process socket holding `required`.
+    fn process_socket(&self, cap: &Capability, required: Permissions) -> Result<(SocketHandle, SocketKind), SocketError> {
+        let entry = self
+            .process_sockets
+            .iter()
+            .find(|s| s.id == cap.resource_id)
+            .ok_or(SocketError::NotFound)?;
+        let handle = self.resolve_socket(cap, required).ok_or(SocketError::PermissionDenied)?;
+        Ok((handle, entry.kind))
+    }
+
+    fn ephemeral_port(&mut self) -> u16 {
+        let port = self.next_local_port;
+        self.next_local_port = if port == u16::MAX { EPHEMERAL_PORT_FIRST } else { port + 1 };
+        port
+    }
+
+    /// eth0's current IPv4 configuration.
+    pub fn address_config(&self) -> AddressConfig {
+        self.interfaces[0].address
+    }
+
+    /// Advance interface `index`'s address state machine: apply DHCP
+    /// events, and fall back to the static address once DHCP has been
+    /// silent for too long.
+    fn poll_address(&mut self, index: usize, timestamp: Instant) {
+        let interface = &mut self.interfaces[index];
+        if !interface.use_dhcp {
+            return;
+        }
+        // `Some(None)` for a lost lease.
+        let lease = match interface.dhcp.poll() {
+            Some(dhcpv4::Event::Configured(lease)) => Some(Some(AddressConfig {
+                source: AddressSource::Dhcp,
+                address: Some(lease.address),
+                gateway: lease.router,
+                dns: lease.dns_servers.first().copied(),
+            })),
+            Some(dhcpv4::Event::Deconfigured) => Some(None),
+            None => None,
+        };
+        let mut changed = false;
+        match lease {
+            Some(Some(lease)) => {
+                serial_println!(
+                    "[NET STACK] eth{} DHCP lease: {:?} via {:?}, DNS {:?}",
+                    index, lease.address, lease.gateway, lease.dns
+                );
+                changed = interface.apply_address(lease, index == 0);
+                interface.unconfigured_since = None;
+            }
+            Some(None) if interface.address.source == AddressSource::Dhcp => {
+                serial_println!("[NET STACK] eth{} DHCP lease lost; interface unconfigured.", index);
+                changed = interface.apply_address(AddressConfig::UNCONFIGURED, index == 0);
+                interface.unconfigured_since = Some(timestamp);
+            }
+            _ => {}
+        }
+
+        if index == 0 && interface.address.source == AddressSource::Unconfigured {
+            let since = *interface.unconfigured_since.get_or_insert(timestamp);
+            let fallback = Duration::from_millis(config::get_or("net.dhcp.fallback_ms", 15_000));
+            if timestamp - since >= fallback {
+                serial_println!(
+                    "[NET STACK] No DHCP lease after {} ms; using static {}.",
+                    fallback.total_millis(), FALLBACK_ADDRESS
+                );
+                changed = interface.apply_address(AddressConfig {
+                    source: AddressSource::Static,
+                    address: Some(FALLBACK_ADDRESS),
+                    gateway: Some(FALLBACK_GATEWAY),
+                    dns: None,
+                }, true);
+            }
+        }
+        if changed {
+            publish_address(index, interface.address.address);
         }
     }
 
+    /// Number of sockets in use. Idle pooled sockets don't count.
+    pub fn socket_count(&self) -> usize {
+        self.sockets.iter().count() - self.socket_manager.idle_count()
+    }
+
+    /// Returns true while any TCP socket still has a connection to wind down.
+    fn has_open_tcp(&self) -> bool {
+        self.sockets.iter().any(|(_, socket)| match socket {
+            smoltcp::socket::Socket::Tcp(s) => {
+                s.state() != tcp::State::Closed && s.state() != tcp::State::TimeWait
+            }
+            _ => false,
+        })
+    }
+
     #[allow(dead_code)]
     pub fn get_ip(&self) -> Option<smoltcp::wire::Ipv4Address> {
-        self.iface.ipv4_addr()
+        self.interfaces[0].iface.ipv4_addr()
+    }
+}
+
+/// eth0's IPv4 configuration, or `None` without a network stack.
+pub fn address_config() -> Option<AddressConfig> {
+    NETWORK_STACK.lock().as_ref().map(|stack| stack.address_config())
+}
+
+/// Bring up a NIC found by `network::init`: the first one creates the
+/// stack as eth0, later ones are added to it.
+pub fn add_interface(device: NetDevice, mac: [u8; 6]) {
+    let mut stack = NETWORK_STACK.lock();
+    match stack.as_mut() {
+        Some(stack) => { stack.add_interface(device, mac); }
+        None => {
+            *stack = Some(NetworkStack::new(device, mac));
+            serial_println!("[NET STACK] Network stack initialized");
+        }
+    }
+}
+
+/// Close every process socket `cspace` holds a capability for. Call when
+/// the owning process exits.
+pub fn close_process_sockets(cspace: &crate::capability::CSpace) {
+    let ids: Vec<u64> = cspace
+        .iter()
+        .filter(|(_, cap)| cap.cap_type == CapabilityType::Socket)
+        .map(|(_, cap)| cap.resource_id)
+        .collect();
+    if ids.is_empty() {
+        return;
+    }
+    for &id in &ids {
+        crate::tls::end_session(id);
     }
+    if let Some(stack) = NETWORK_STACK.lock().as_mut() {
+        for id in ids {
+            // Sockets the kernel shared with the process aren't process
+            // sockets and are left alone.
+            let _ = stack.close_socket(id);
+        }
+    }
+}
+
+// ─── Outbound Connections ────────────────────────────────────────────────────
+//
+// Kernel clients (the P2P dialer, NTP or HTTP clients) open connections with
+// `tcp_connect` and get a plain socket handle back, like the stack's own
+// listening sockets, to use with `FramedConnection` or directly. They hand
+// it back with `tcp_close` when done.
+
+/// Why `tcp_connect` or `udp_bind` failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectError {
+    /// The network stack is down.
+    NoNetwork,
+    /// Admission control refused another socket.
+    NoResources,
+    /// The peer reset the connection (nothing listening on the port).
+    Refused,
+    /// No answer within `net.tcp.connect_timeout_ms`.
+    TimedOut,
 }
 
-pub fn init(device: VirtioNetDevice, mac: [u8; 6]) {
-    let stack = NetworkStack::new(device, mac);
-    *NETWORK_STACK.lock() = Some(stack);
-    serial_println!("[NET STACK] Network stack initialized");
+/// Connect to `port` on `remote`. Completes once the handshake does, with
+/// the handle of the established socket; the socket is reclaimed on failure.
+///
+/// Between checks the task yields, so the executor keeps polling the
+/// interface (which drives the SYN retransmissions) and other tasks.
+pub async fn tcp_connect(remote: smoltcp::wire::IpAddress, port: u16) -> Result<SocketHandle, ConnectError> {
+    tcp_connect_buffered(remote, port, tcp_buffer()).await
+}
+
+/// `tcp_connect` with `buffer` bytes of socket buffer per direction.
+pub async fn tcp_connect_buffered(
+    remote: smoltcp::wire::IpAddress,
+    port: u16,
+    buffer: usize,
+) -> Result<SocketHandle, ConnectError> {
+    tcp_connect_from(None, remote, port, buffer).await
+}
+
+/// `tcp_connect_buffered` from `local_port` rather than an ephemeral port,
+/// if given. A port something listens on may be used too: hole punching
+/// dials from the listening port so the NAT's mapping for it lets the
+/// peer's own dial in.
+pub async fn tcp_connect_from(
+    local_port: Option<u16>,
+    remote: smoltcp::wire::IpAddress,
+    port: u16,
+    buffer: usize,
+) -> Result<SocketHandle, ConnectError> {
+    crate::admission::admit(&crate::admission::ResourceRequest {
+        heap_bytes: socket_heap(SocketKind::Tcp, buffer),
+        frames: 0,
+        sockets: 1,
+    })
+    .map_err(|_| ConnectError::NoResources)?;
+    let handle = NETWORK_STACK
+        .lock()
+        .as_mut()
+        .ok_or(ConnectError::NoNetwork)?
+        .start_connect(IpEndpoint::new(remote, port), local_port, buffer)?;
+
+    let deadline = interrupts::uptime_ms() + config::get_or("net.tcp.connect_timeout_ms", 10_000);
+    loop {
+        let outcome = {
+            let mut stack = NETWORK_STACK.lock();
+            // A restart dropped the socket along with the old stack.
+            let stack = stack.as_mut().ok_or(ConnectError::NoNetwork)?;
+            let state = stack.sockets.get::<TcpSocket>(handle).state();
+            match state {
+                tcp::State::Established => Some(Ok(handle)),
+                tcp::State::SynSent | tcp::State::SynReceived if interrupts::uptime_ms() < deadline => None,
+                tcp::State::SynSent | tcp::State::SynReceived => Some(Err(ConnectError::TimedOut)),
+                _ => Some(Err(ConnectError::Refused)),
+            }
+        };
+        match outcome {
+            Some(Ok(handle)) => return Ok(handle),
+            Some(Err(e)) => {
+                if let Some(stack) = NETWORK_STACK.lock().as_mut() {
+                    stack.sockets.get_mut::<TcpSocket>(handle).abort();
+                    stack.destroy_socket(handle, SocketKind::Tcp);
+                }
+                return Err(e);
+            }
+            None => crate::p2p::yield_now().await,
+        }
+    }
+}
+
+/// Close a connection opened with `tcp_connect` or taken with
+/// `NetworkStack::accept`. The FIN exchange finishes in the background and
+/// the socket is then reused.
+pub fn tcp_close(handle: SocketHandle) {
+    if let Some(stack) = NETWORK_STACK.lock().as_mut() {
+        stack.destroy_socket(handle, SocketKind::Tcp);
+    }
+}
+
+impl NetworkStack {
+    /// Take a client TCP socket and send its SYN, from `local_port` or an
+    /// ephemeral port.
+    fn start_connect(
+        &mut self,
+        remote: IpEndpoint,
+        local_port: Option<u16>,
+        buffer: usize,
+    ) -> Result<SocketHandle, ConnectError> {
+        let handle = self.create_socket(SocketKind::Tcp, buffer);
+        let local_port = local_port.unwrap_or_else(|| self.ephemeral_port());
+        let index = self.route(remote.addr);
+        let cx = self.interfaces[index].iface.context();
+        let socket = self.sockets.get_mut::<TcpSocket>(handle);
+        if socket.connect(cx, remote, local_port).is_err() {
+            self.destroy_socket(handle, SocketKind::Tcp);
+            return Err(ConnectError::NoNetwork);
+        }
+        Ok(handle)
+    }
+}
+
+// ─── Kernel UDP Sockets ──────────────────────────────────────────────────────
+//
+// Kernel clients (the DNS resolver, mDNS) take a UDP socket with `udp_bind`
+// and use it through `udp_send_to` / `udp_recv_from`, futures that wait for
+// room in the send buffer or for a datagram; wrap them in a `Deadline` to
+// give up. The socket is a process socket the kernel holds the capability
+// to, so guests' `env.socket_send_to` / `env.socket_recv_from` share the
+// code. Every operation fails with `NotFound` once the stack has restarted;
+// bind a new socket then. Hand it back with `udp_close`.
+
+/// Take a UDP socket bound to `port` on every interface, or to an
+/// ephemeral port if `port` is 0.
+pub fn udp_bind(port: u16) -> Result<Capability, ConnectError> {
+    crate::admission::admit(&crate::admission::ResourceRequest {
+        heap_bytes: socket_heap(SocketKind::Udp, 0),
+        frames: 0,
+        sockets: 1,
+    })
+    .map_err(|_| ConnectError::NoResources)?;
+    let mut stack = NETWORK_STACK.lock();
+    let stack = stack.as_mut().ok_or(ConnectError::NoNetwork)?;
+    let rights = Permissions::LISTEN.union(Permissions::SEND).union(Permissions::RECV);
+    let cap = stack.open_socket(SocketKind::Udp, rights);
+    let port = if port == 0 { stack.ephemeral_port() } else { port };
+    if stack.listen(&cap, port).is_err() {
+        stack.close_socket(cap.resource_id).ok();
+        return Err(ConnectError::NoNetwork);
+    }
+    Ok(cap)
+}
+
+/// Send one datagram to `remote` once there is room for it.
+pub fn udp_send_to<'a>(cap: &'a Capability, data: &'a [u8], remote: IpEndpoint) -> UdpSendTo<'a> {
+    UdpSendTo { cap, data, remote }
+}
+
+/// Wait for a datagram and read it into `buf`, truncated to fit. Resolves
+/// to its length and sender.
+pub fn udp_recv_from<'a>(cap: &'a Capability, buf: &'a mut [u8]) -> UdpRecvFrom<'a> {
+    UdpRecvFrom { cap, buf }
+}
+
+/// Close a socket from `udp_bind`.
+pub fn udp_close(cap: &Capability) {
+    if let Some(stack) = NETWORK_STACK.lock().as_mut() {
+        stack.close_socket(cap.resource_id).ok();
+    }
+}
+
+/// Future of `udp_send_to`.
+pub struct UdpSendTo<'a> {
+    cap: &'a Capability,
+    data: &'a [u8],
+    remote: IpEndpoint,
+}
+
+impl Future for UdpSendTo<'_> {
+    type Output = Result<(), SocketError>;
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let sent = match NETWORK_STACK.lock().as_mut() {
+            Some(stack) => stack.send_to(self.cap, self.data, self.remote),
+            None => Err(SocketError::NotFound),
+        };
+        match sent {
+            Ok(true) => Poll::Ready(Ok(())),
+            Ok(false) => Poll::Pending,
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+}
+
+/// Future of `udp_recv_from`.
+pub struct UdpRecvFrom<'a> {
+    cap: &'a Capability,
+    buf: &'a mut [u8],
+}
+
+impl Future for UdpRecvFrom<'_> {
+    type Output = Result<(usize, IpEndpoint), SocketError>;
+
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let received = match NETWORK_STACK.lock().as_mut() {
+            Some(stack) => stack.recv_from(this.cap, this.buf),
+            None => Err(SocketError::NotFound),
+        };
+        match received {
+            Ok(Some(datagram)) => Poll::Ready(Ok(datagram)),
+            Ok(None) => Poll::Pending,
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+}
+
+/// How long `shutdown` waits for TCP peers to finish the FIN exchange.
+const SHUTDOWN_LINGER_MS: u64 = 500;
+
+/// Tear down the network stack.
+///
+/// TCP and UDP sockets are closed gracefully (FIN for TCP) and given
+/// `SHUTDOWN_LINGER_MS` to drain; TCP connections still open after that are
+/// aborted with an RST. The virtio devices are then reset and their DMA buffers
+/// are returned to the pool. Socket capabilities handed out earlier stop
+/// resolving because the stack that issued them is gone.
+///
+/// The linger is awaited, polling the detached stack on each executor
+/// pass, so other tasks keep running meanwhile.
+pub async fn shutdown() {
+    let stack = NETWORK_STACK.lock().take();
+    let mut stack = match stack {
+        Some(stack) => stack,
+        None => return,
+    };
+    serial_println!("[NET STACK] Shutting down...");
+
+    for (_, socket) in stack.sockets.iter_mut() {
+        match socket {
+            smoltcp::socket::Socket::Tcp(s) => s.close(),
+            smoltcp::socket::Socket::Udp(s) => s.close(),
+            _ => {}
+        }
+    }
+
+    let deadline = Some(interrupts::uptime_ms() + SHUTDOWN_LINGER_MS);
+    let drained = poll_fn(|_| {
+        stack.poll_interfaces(Instant::from_millis(interrupts::uptime_ms() as i64));
+        if stack.has_open_tcp() { Poll::Pending } else { Poll::Ready(()) }
+    });
+    if (Deadline { inner: drained, deadline }).await.is_none() {
+        serial_println!("[NET STACK] Linger expired, aborting remaining TCP connections.");
+        for (_, socket) in stack.sockets.iter_mut() {
+            if let smoltcp::socket::Socket::Tcp(s) = socket {
+                s.abort();
+            }
+        }
+        // One more poll so the RSTs actually hit the wire.
+        stack.poll_interfaces(Instant::from_millis(interrupts::uptime_ms() as i64));
+    }
+
+    for interface in &mut stack.interfaces {
+        interface.device.reset();
+    }
+    serial_println!("[NET STACK] Network stack shut down.");
+}
+
+/// Shut the stack down and bring it back up by re-probing the NICs.
+pub async fn restart() {
+    shutdown().await;
+    crate::network::init();
+}
+
+/// Signalled by every NIC's interrupts (routed in `network::init`): bit
+/// `1 << line` for a PIC line, or `IRQ_RX` / `IRQ_TX` for MSI-X.
+pub static NIC_IRQ: Notification = Notification::new();
+/// `NIC_IRQ` bit of the receive queue's MSI-X vector.
+pub const IRQ_RX: u64 = 1 << 16;
+/// `NIC_IRQ` bit of the transmit queue's MSI-X vector.
+pub const IRQ_TX: u64 = 1 << 17;
+
+/// Drive the network stack. The interface is polled as soon as the NIC
+/// raises an interrupt, when smoltcp has a timer or queued data due, and at
+/// least every `net.poll_fallback_ms` otherwise, so a device without a
+/// usable interrupt line still works, just with more latency.
+pub async fn network_task() {
+    loop {
+        let now = interrupts::uptime_ms();
+        let mut deadline = now + config::get_or("net.poll_fallback_ms", 10);
+        if let Some(stack) = NETWORK_STACK.lock().as_mut() {
+            if let Some(delay) = stack.poll_delay(Instant::from_millis(now as i64)) {
+                deadline = deadline.min(now + delay.total_millis());
+            }
+        }
+        NIC_IRQ.wait_until(deadline).await;
+        poll_network(Instant::from_millis(interrupts::uptime_ms() as i64));
+    }
 }
 
 pub fn poll_network(timestamp: Instant) {
@@ -264,7 +1598,7 @@ pub fn poll_network(timestamp: Instant) {
         stack.poll(timestamp);
     } else {
         static ONCE: AtomicU64 = AtomicU64::new(0);
-        if ONCE.fetch_add(1, Ordering::Relaxed) % 1000 == 0 {
+        if ONCE.fetch_add(1, Ordering::Relaxed).is_multiple_of(1000) {
              serial_println!("[NET ERROR] poll_network called but NETWORK_STACK is None!");
         }
     }
@@ -1,114 +1,311 @@
 use x86_64::instructions::port::{Port, PortRead, PortWrite};
 use virtio_drivers::{device::net::{VirtIONet, VirtIONetRaw}, transport::{Transport, DeviceType, DeviceStatus}, Error};
 use crate::hal::VirtioHal;
+use crate::interrupts;
+use crate::net_interface::{self, ControlQueue};
+use crate::net_stack::{self, NIC_IRQ};
+use crate::pci::{self, pci_read, pci_write_16, verify_device, MsiX, PciFunction};
 use crate::serial_println;
+use crate::virtio_modern::{self, ModernLayout, ModernTransport};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::mem::size_of;
+use core::sync::atomic::{AtomicU64, Ordering};
 use zerocopy::{FromBytes, IntoBytes, Immutable};
 use bitflags::Flags;
+use spin::Mutex;
 
 pub fn init() {
-    serial_println!("[NET] Scanning PCI bus for VirtIO Network device...");
-    
-    // Simple PCI scan
+    serial_println!("[NET] Scanning PCI bus for VirtIO Network devices...");
+
+    // 0x1000 is the transitional network device, 0x1041 the modern-only one.
+    let devices = find_devices(0x1000, 0x1041);
+    if devices.is_empty() {
+        serial_println!("[NET] No VirtIO Network device found.");
+    }
+    for (mut location, function) in devices {
+        serial_println!(
+            "[NET] Detected {} VirtIO Network Device.",
+            if location.is_modern() { "Modern" } else { "Legacy" }
+        );
+        route_interrupts(&mut location, function);
+        if let Some((device, mac)) = init_device(location) {
+            net_stack::add_interface(net_interface::NetDevice::Virtio(Box::new(device)), mac);
+        }
+    }
+    // Emulators without virtio usually offer an RTL8139 instead.
+    crate::rtl8139::init();
+}
+
+/// Wire a NIC's interrupts to `NIC_IRQ`: one MSI-X vector per queue on a
+/// modern device, else a single MSI vector, else its INTx PIC line. Must
+/// run before `init_device`,
+/// which tells the device which MSI-X entry each queue uses. Without any
+/// interrupt the network task falls back to polling.
+///
+/// A legacy device never gets MSI-X: with MSI-X enabled its legacy register
+/// block grows and moves the device config `LegacyTransport` expects at a
+/// fixed offset.
+fn route_interrupts(location: &mut VirtioLocation, function: PciFunction) {
+    if let VirtioLocation::Modern(layout) = location {
+        let msix = MsiX::probe(function).filter(|msix| msix.len() >= 2);
+        let vectors = interrupts::allocate_msi_vector(&NIC_IRQ, net_stack::IRQ_RX)
+            .zip(interrupts::allocate_msi_vector(&NIC_IRQ, net_stack::IRQ_TX));
+        if let (Some(mut msix), Some((rx, tx))) = (msix, vectors) {
+            // Table entries follow queue indices: 0 is RX, 1 is TX.
+            msix.set_vector(0, rx);
+            msix.set_vector(1, tx);
+            msix.enable();
+            layout.use_msix_per_queue();
+            serial_println!("[NET] MSI-X interrupts: RX vector {}, TX vector {}.", rx, tx);
+            return;
+        }
+    }
+    if let Some(vector) = interrupts::allocate_msi_vector(&NIC_IRQ, net_stack::IRQ_RX | net_stack::IRQ_TX) {
+        if pci::enable_msi(function, vector) {
+            serial_println!("[NET] MSI interrupts: vector {}.", vector);
+            return;
+        }
+    }
+    match function.interrupt_line() {
+        Some(line) if interrupts::route_irq(line, &NIC_IRQ) => {
+            serial_println!("[NET] Interrupts on IRQ {}.", line);
+        }
+        _ => { serial_println!("[NET] No usable interrupt; polling only."); }
+    }
+}
+
+/// How to reach a VirtIO PCI device.
+#[derive(Debug, Clone, Copy)]
+pub enum VirtioLocation {
+    /// Legacy interface: registers in I/O space at this base (BAR0).
+    Legacy(u16),
+    /// Modern (1.0+) interface: structures in memory BARs.
+    Modern(ModernLayout),
+}
+
+impl VirtioLocation {
+    pub fn is_modern(self) -> bool {
+        matches!(self, VirtioLocation::Modern(_))
+    }
+
+    /// A fresh transport to the device. Cheap: nothing is probed again.
+    pub fn transport(self) -> VirtioTransport {
+        match self {
+            VirtioLocation::Legacy(io_base) => VirtioTransport::Legacy(LegacyTransport::new(io_base)),
+            VirtioLocation::Modern(layout) => VirtioTransport::Modern(ModernTransport::new(layout)),
+        }
+    }
+}
+
+/// Find every VirtIO PCI device (vendor 0x1af4) with the transitional
+/// `legacy_id` or the modern-only `modern_id`, in bus order, enable I/O,
+/// memory and bus mastering on each, and say how to reach them.
+///
+/// The modern interface is used whenever the device offers it; otherwise
+/// a transitional device falls back to the legacy interface in BAR0.
+pub(crate) fn find_devices(legacy_id: u16, modern_id: u16) -> Vec<(VirtioLocation, PciFunction)> {
+    let mut found = Vec::new();
     for bus in 0..255 {
         for device in 0..32 {
-            if let Some(header) = unsafe { verify_device(bus, device) } {
-                // Check if it's a network device (Device ID 0x1000 for legacy, Vendor ID 0x1af4)
-                if header.device_id == 0x1000 && header.vendor_id == 0x1af4 {
-                    serial_println!("[NET] Found VirtIO device at {:02x}:{:02x}, Vendor ID: 0x{:04x}, Device ID: 0x{:04x}", 
-                        bus, device, header.vendor_id, header.device_id);
-                    serial_println!("[NET] Detected Legacy VirtIO Network Device.");
-                    
-                    // Read BAR0 to get I/O base
+            let header = match unsafe { verify_device(bus, device) } {
+                Some(header) => header,
+                None => continue,
+            };
+            if header.vendor_id != 0x1af4 || (header.device_id != legacy_id && header.device_id != modern_id) {
+                continue;
+            }
+            serial_println!("[PCI] Found VirtIO device at {:02x}:{:02x}, Vendor ID: 0x{:04x}, Device ID: 0x{:04x}",
+                bus, device, header.vendor_id, header.device_id);
+
+            let function = PciFunction { bus, slot: device };
+            let location = match virtio_modern::probe(function, header.device_id) {
+                Some(layout) => VirtioLocation::Modern(layout),
+                None if header.device_id == legacy_id => {
                     let bar0 = unsafe { pci_read(bus, device, 0, 0x10) };
-                    // If bit 0 is set, it's I/O.
-                    if bar0 & 1 == 1 {
-                        let io_base = (bar0 & !0x3) as u16;
-                        serial_println!("[NET] I/O Base: 0x{:04x}", io_base);
-                        
-                        // IMPORTANT: Enable Bus Master (bit 2) in Command Register (Offset 4)
-                        // IMPORTANT: Enable Bus Master (bit 2) and Memory Space (bit 1)
-                        // Command Register is 16 bits at offset 4.
-                        let command_reg = unsafe { pci_read(bus, device, 0, 0x04) } as u16;
-                        let new_command = command_reg | 0x7; // Bit 0 (IO), Bit 1 (Mem), Bit 2 (Bus Master)
-                        unsafe { pci_write_16(bus, device, 0, 0x04, new_command) };
-                        serial_println!("[NET] PCI Bus Master + Mem Enabled");
-
-                        let transport = LegacyTransport::new(io_base);
-
-                        // Initialize VirtIONetRaw with 256 queue size (Legacy default)
-                        match VirtIONetRaw::<VirtioHal, LegacyTransport, 256>::new(transport) {
-                            Ok(net) => {
-                                serial_println!("[NET] VirtIO Network Driver Initialized!");
-                                let mac = net.mac_address();
-                                serial_println!("[NET] MAC Address: {:02x?}", mac);
-
-                                let device = crate::net_interface::VirtioNetDevice::new(net);
-                                
-                                // PROBE: Check if queues are active using a fresh transport handle
-                                let mut probe_transport = LegacyTransport::new(io_base);
-                                let rx_active = probe_transport.queue_used(0);
-                                let tx_active = probe_transport.queue_used(1);
-                                serial_println!("[NET] Queue PFN Probe: RX={}, TX={}", rx_active, tx_active);
-
-                                crate::net_stack::init(device, mac);
-                            }
-                            Err(e) => {
-                                serial_println!("[NET] Failed to initialize VirtioNet: {:?}", e);
-                            }
-                        }
-                        return; // Found and initialized
-                    } else {
-                        serial_println!("[NET] BAR0 is not I/O space. Legacy VirtIO requires I/O.");
+                    if bar0 & 1 != 1 {
+                        serial_println!("[PCI] BAR0 is not I/O space. Legacy VirtIO requires I/O.");
+                        continue;
                     }
+                    VirtioLocation::Legacy((bar0 & !0x3) as u16)
                 }
+                None => {
+                    serial_println!("[PCI] Modern VirtIO device has no usable capabilities.");
+                    continue;
+                }
+            };
+
+            let command_reg = unsafe { pci_read(bus, device, 0, 0x04) } as u16;
+            unsafe { pci_write_16(bus, device, 0, 0x04, command_reg | 0x7) };
+            serial_println!("[PCI] Bus Master + Mem Enabled");
+            found.push((location, function));
+        }
+    }
+    found
+}
+
+/// Find the first legacy VirtIO PCI device (vendor 0x1af4) with `device_id`,
+/// enable I/O, memory and bus mastering on it, and return its I/O base.
+///
+/// Legacy VirtIO requires BAR0 to be I/O space; a device whose BAR0 is not
+/// is skipped.
+pub(crate) fn find_legacy_device(device_id: u16) -> Option<u16> {
+    for bus in 0..255 {
+        for device in 0..32 {
+            let header = match unsafe { verify_device(bus, device) } {
+                Some(header) => header,
+                None => continue,
+            };
+            if header.device_id != device_id || header.vendor_id != 0x1af4 {
+                continue;
+            }
+            serial_println!("[PCI] Found VirtIO device at {:02x}:{:02x}, Vendor ID: 0x{:04x}, Device ID: 0x{:04x}",
+                bus, device, header.vendor_id, header.device_id);
+
+            // Read BAR0 to get I/O base. If bit 0 is set, it's I/O.
+            let bar0 = unsafe { pci_read(bus, device, 0, 0x10) };
+            if bar0 & 1 != 1 {
+                serial_println!("[PCI] BAR0 is not I/O space. Legacy VirtIO requires I/O.");
+                continue;
             }
+            let io_base = (bar0 & !0x3) as u16;
+            serial_println!("[PCI] I/O Base: 0x{:04x}", io_base);
+
+            // IMPORTANT: Enable Bus Master (bit 2) and Memory Space (bit 1)
+            // Command Register is 16 bits at offset 4.
+            let command_reg = unsafe { pci_read(bus, device, 0, 0x04) } as u16;
+            let new_command = command_reg | 0x7; // Bit 0 (IO), Bit 1 (Mem), Bit 2 (Bus Master)
+            unsafe { pci_write_16(bus, device, 0, 0x04, new_command) };
+            serial_println!("[PCI] Bus Master + Mem Enabled");
+            return Some(io_base);
         }
     }
-    serial_println!("[NET] No VirtIO Network device found.");
+    None
 }
 
-// Minimal PCI helpers
-#[derive(Debug, Clone, Copy)]
-struct PciHeader {
-    vendor_id: u16,
-    device_id: u16,
+/// Bring up the VirtIO network driver on the device at `location`.
+///
+/// Used both at boot and when recovering a wedged device. Returns the
+/// smoltcp-facing device together with its MAC address.
+pub fn init_device(location: VirtioLocation) -> Option<(crate::net_interface::VirtioNetDevice, [u8; 6])> {
+    let transport = location.transport();
+
+    // Initialize VirtIONetRaw with 256 queue size (Legacy default)
+    match VirtIONetRaw::<VirtioHal, VirtioTransport, 256>::new(transport) {
+        Ok(mut net) => {
+            serial_println!("[NET] VirtIO Network Driver Initialized!");
+            // Interrupts only wake the network task; it still drains the
+            // queues itself.
+            net.enable_interrupts();
+            let mac = net.mac_address();
+            serial_println!("[NET] MAC Address: {:02x?}", mac);
+
+            let features = NEGOTIATED_FEATURES.load(Ordering::Relaxed);
+            let device_mtu = if features & net_interface::VIRTIO_NET_F_MTU != 0 {
+                location.transport().read_config_space::<u16>(CONFIG_MTU).ok()
+            } else {
+                None
+            };
+            let control = CONTROL_QUEUE.lock().take();
+            let device = crate::net_interface::VirtioNetDevice::new(net, location, features, device_mtu, control);
+
+            // PROBE: Check if queues are active using a fresh transport handle
+            let mut probe_transport = location.transport();
+            let rx_active = probe_transport.queue_used(0);
+            let tx_active = probe_transport.queue_used(1);
+            serial_println!("[NET] Queue Probe: RX={}, TX={}", rx_active, tx_active);
+
+            Some((device, mac))
+        }
+        Err(e) => {
+            serial_println!("[NET] Failed to initialize VirtioNet: {:?}", e);
+            None
+        }
+    }
 }
 
-unsafe fn pci_read(bus: u8, slot: u8, func: u8, offset: u8) -> u32 {
-    let address = 0x80000000 | ((bus as u32) << 16) | ((slot as u32) << 11) | ((func as u32) << 8) | ((offset as u32) & 0xfc);
-    let mut command_port = Port::<u32>::new(0xCF8);
-    let mut data_port = Port::<u32>::new(0xCFC);
-    command_port.write(address);
-    data_port.read()
+/// Reset the VirtIO device at `location`.
+///
+/// Writing 0 to the status register stops the device from touching queue
+/// memory, so its DMA buffers can safely be reclaimed afterwards. A modern
+/// device has finished resetting once the register reads back 0.
+pub fn reset_device(location: VirtioLocation) {
+    let mut transport = location.transport();
+    transport.set_status(DeviceStatus::empty());
+    while !transport.get_status().is_empty() {
+        core::hint::spin_loop();
+    }
 }
 
-unsafe fn pci_write(bus: u8, slot: u8, func: u8, offset: u8, value: u32) {
-    let address = 0x80000000 | ((bus as u32) << 16) | ((slot as u32) << 11) | ((func as u32) << 8) | ((offset as u32) & 0xfc);
-    let mut command_port = Port::<u32>::new(0xCF8);
-    let mut data_port = Port::<u32>::new(0xCFC);
-    command_port.write(address);
-    data_port.write(value);
+/// Offset of `mtu` in the virtio-net device config, after `mac`, `status`
+/// and `max_virtqueue_pairs`.
+const CONFIG_MTU: usize = 10;
+
+/// Feature bits negotiated by the last `begin_init`, for the driver to
+/// pick up once `VirtIONetRaw::new` has consumed the transport.
+static NEGOTIATED_FEATURES: AtomicU64 = AtomicU64::new(0);
+/// Control queue set up by the last `finish_init`, handed over the same way.
+static CONTROL_QUEUE: Mutex<Option<ControlQueue>> = Mutex::new(None);
+
+/// Either VirtIO PCI transport, so drivers need not care which one a device
+/// uses.
+pub enum VirtioTransport {
+    Legacy(LegacyTransport),
+    Modern(ModernTransport),
 }
 
-unsafe fn pci_write_16(bus: u8, slot: u8, func: u8, offset: u8, value: u16) {
-    let address = 0x80000000 | ((bus as u32) << 16) | ((slot as u32) << 11) | ((func as u32) << 8) | ((offset as u32) & 0xfc);
-    let mut command_port = Port::<u32>::new(0xCF8);
-    // Data port for 16-bit access depends on offset alignment (0xCFC + (offset & 2))
-    let mut data_port = Port::<u16>::new(0xCFC + (offset as u16 & 2));
-    command_port.write(address);
-    data_port.write(value);
+/// Forward a `Transport` call to whichever transport is inside.
+macro_rules! dispatch {
+    ($self:expr, $t:ident => $call:expr) => {
+        match $self {
+            VirtioTransport::Legacy($t) => $call,
+            VirtioTransport::Modern($t) => $call,
+        }
+    };
 }
 
-unsafe fn verify_device(bus: u8, slot: u8) -> Option<PciHeader> {
-    let id = pci_read(bus, slot, 0, 0);
-    if id == 0xFFFFFFFF {
-        return None;
+impl Transport for VirtioTransport {
+    fn device_type(&self) -> DeviceType { dispatch!(self, t => t.device_type()) }
+    fn read_device_features(&mut self) -> u64 { dispatch!(self, t => t.read_device_features()) }
+    fn write_driver_features(&mut self, features: u64) { dispatch!(self, t => t.write_driver_features(features)) }
+    fn begin_init<F: Flags<Bits = u64> + core::ops::BitAnd<Output = F> + core::fmt::Debug>(
+        &mut self,
+        supported_features: F,
+    ) -> F {
+        // virtio-drivers doesn't ask for checksum offload, the MTU or
+        // mergeable buffers; the driver handles those itself (see
+        // `net_interface`).
+        let supported = supported_features.union(F::from_bits_truncate(net_interface::DRIVER_FEATURES));
+        let negotiated = dispatch!(self, t => t.begin_init(supported));
+        NEGOTIATED_FEATURES.store(negotiated.bits(), Ordering::Relaxed);
+        negotiated
+    }
+    fn finish_init(&mut self) {
+        // virtio-drivers sets up only the RX and TX queues, and the control
+        // queue must be ready before DRIVER_OK as well.
+        let control = NEGOTIATED_FEATURES.load(Ordering::Relaxed) & net_interface::VIRTIO_NET_F_CTRL_VQ != 0;
+        *CONTROL_QUEUE.lock() = if control { ControlQueue::new(self) } else { None };
+        dispatch!(self, t => t.finish_init())
+    }
+    fn max_queue_size(&mut self, queue: u16) -> u32 { dispatch!(self, t => t.max_queue_size(queue)) }
+    fn notify(&mut self, queue: u16) { dispatch!(self, t => t.notify(queue)) }
+    fn get_status(&self) -> DeviceStatus { dispatch!(self, t => t.get_status()) }
+    fn set_status(&mut self, status: DeviceStatus) { dispatch!(self, t => t.set_status(status)) }
+    fn set_guest_page_size(&mut self, size: u32) { dispatch!(self, t => t.set_guest_page_size(size)) }
+    fn requires_legacy_layout(&self) -> bool { dispatch!(self, t => t.requires_legacy_layout()) }
+    fn queue_set(&mut self, queue: u16, size: u32, descriptors: usize, driver_area: usize, device_area: usize) {
+        dispatch!(self, t => t.queue_set(queue, size, descriptors, driver_area, device_area))
+    }
+    fn queue_unset(&mut self, queue: u16) { dispatch!(self, t => t.queue_unset(queue)) }
+    fn queue_used(&mut self, queue: u16) -> bool { dispatch!(self, t => t.queue_used(queue)) }
+    fn ack_interrupt(&mut self) -> bool { dispatch!(self, t => t.ack_interrupt()) }
+    fn read_config_generation(&self) -> u32 { dispatch!(self, t => t.read_config_generation()) }
+    fn read_config_space<T: FromBytes + IntoBytes>(&self, offset: usize) -> Result<T, Error> {
+        dispatch!(self, t => t.read_config_space(offset))
+    }
+    fn write_config_space<T: IntoBytes + Immutable>(&mut self, offset: usize, value: T) -> Result<(), Error> {
+        dispatch!(self, t => t.write_config_space(offset, value))
     }
-    Some(PciHeader {
-        vendor_id: (id & 0xFFFF) as u16,
-        device_id: ((id >> 16) & 0xFFFF) as u16,
-    })
 }
 
 // Legacy Transport Implementation
@@ -259,7 +456,10 @@ impl Transport for LegacyTransport {
     }
 
     fn queue_unset(&mut self, _queue: u16) {
-        // Not easily supported in Legacy
+        // Intentionally a no-op. The driver calls this when it is dropped,
+        // which during recovery happens *after* a replacement driver has set
+        // up fresh queues on the same I/O base; writing PFN 0 here would tear
+        // those down. Queues are released by the device reset instead.
     }
 
     fn queue_used(&mut self, queue: u16) -> bool {
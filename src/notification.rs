@@ -0,0 +1,67 @@
+//! # Notifications
+//!
+//! A word of signal bits, in the style of seL4 notification objects.
+//! `signal` ORs bits in without locking or blocking, so interrupt handlers
+//! can use it (see `interrupts::route_irq`); a task waits for any bit with
+//! `wait().await`, which returns the pending bits and clears them.
+//!
+//! Like `executor::Sleep`, a waiting task is simply checked again on every
+//! executor pass. An interrupt also ends the idle loop's `hlt`, so the pass
+//! that sees the signal runs right away rather than on the next tick.
+
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+
+pub struct Notification {
+    bits: AtomicU64,
+}
+
+impl Notification {
+    pub const fn new() -> Self {
+        Notification { bits: AtomicU64::new(0) }
+    }
+
+    /// Set `bits`. Safe to call from an interrupt handler.
+    pub fn signal(&self, bits: u64) {
+        self.bits.fetch_or(bits, Ordering::AcqRel);
+    }
+
+    /// Take the pending bits without waiting (0 if none).
+    pub fn poll(&self) -> u64 {
+        self.bits.swap(0, Ordering::AcqRel)
+    }
+
+    /// Wait until any bit is set.
+    pub fn wait(&self) -> Wait<'_> {
+        Wait { notification: self, deadline_ms: None }
+    }
+
+    /// Wait until any bit is set or uptime reaches `deadline_ms`, whichever
+    /// comes first. Returns 0 on timeout.
+    pub fn wait_until(&self, deadline_ms: u64) -> Wait<'_> {
+        Wait { notification: self, deadline_ms: Some(deadline_ms) }
+    }
+}
+
+/// Future returned by `Notification::wait` and `wait_until`.
+pub struct Wait<'a> {
+    notification: &'a Notification,
+    deadline_ms: Option<u64>,
+}
+
+impl Future for Wait<'_> {
+    type Output = u64;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u64> {
+        let bits = self.notification.poll();
+        let expired = self.deadline_ms.is_some_and(|deadline| crate::interrupts::uptime_ms() >= deadline);
+        if bits != 0 || expired {
+            Poll::Ready(bits)
+        } else {
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
@@ -1,11 +1,21 @@
 use crate::serial_println;
-use crate::p2p_transport;
-use crate::p2p_kademlia::{self, NodeId, RoutingTable, PeerInfo};
+use crate::p2p_noise::{self, LocalKeys, Role, SecureConnection};
+use crate::p2p_transport::{Deadline, FramedConnection};
+use crate::p2p_kademlia::{self, AddOutcome, NodeId, RoutingTable, PeerInfo};
+use crate::p2p_conn::{self, Direction};
+use crate::p2p_protocols::{self, Negotiated};
+use crate::p2p_proto::{self, Writer};
+use crate::p2p_rpc;
+use crate::p2p_reputation::{self, Offense, Subject};
 use crate::EXECUTOR;
-use crate::executor::Task;
-use crate::net_stack::NETWORK_STACK;
-use ed25519_dalek::SigningKey;
+use crate::executor::{self, Task};
+use crate::net_stack::{tcp_close, NETWORK_STACK, P2P_PORT};
+use crate::addr_book::ADDRESS_BOOK;
+use crate::kv;
+use ed25519_dalek::{SigningKey, VerifyingKey};
 use alloc::vec::Vec;
+use core::pin::pin;
+use smoltcp::wire::{IpEndpoint, Ipv4Address};
 use alloc::string::String;
 use spin::Mutex;
 use lazy_static::lazy_static;
@@ -14,58 +24,189 @@ pub struct P2PState {
     pub peer_id: String,
     pub node_id: NodeId,
     pub routing_table: RoutingTable,
+    pub keys: LocalKeys,
 }
 
 lazy_static! {
     pub static ref P2P_STATE: Mutex<Option<P2PState>> = Mutex::new(None);
 }
 
-pub fn init() {
-    serial_println!("[P2P] Initializing P2P Stack (Modified Kademlia)...");
-    
-    // 1. Generate Identity
-    serial_println!("[P2P] Step 1: Getting Randomness...");
-    let mut key_bytes = [0u8; 32];
-    getrandom::getrandom(&mut key_bytes).expect("RNG failed");
-    
-    serial_println!("[P2P] Step 2: Generating Keypair...");
-    let signing_key = SigningKey::from_bytes(&key_bytes);
-    let verifying_key = signing_key.verifying_key();
-    
-    // PeerId derivation
-    serial_println!("[P2P] Step 3: Deriving PeerID...");
+/// The node's host name: `kernel-` and the first four bytes of its node ID
+/// (the hash of its peer ID) in hex. Sent to DHCP servers and announced
+/// over mDNS.
+pub fn host_label(node_id: &NodeId) -> String {
+    let id = &node_id.0;
+    alloc::format!("kernel-{:02x}{:02x}{:02x}{:02x}", id[0], id[1], id[2], id[3])
+}
+
+/// The peer ID and node ID of the node whose identity key is `key`. The
+/// peer ID is the base58 multihash of the protobuf-encoded key, and the
+/// node ID the SHA-256 of that multihash.
+pub fn identity_of(key: &VerifyingKey) -> (String, NodeId) {
     let mut pub_key_proto = Vec::with_capacity(36);
     pub_key_proto.push(0x08); pub_key_proto.push(0x01);
     pub_key_proto.push(0x12); pub_key_proto.push(0x20);
-    pub_key_proto.extend_from_slice(verifying_key.as_bytes());
+    pub_key_proto.extend_from_slice(key.as_bytes());
     
     let mut multihash = Vec::with_capacity(2 + 36);
     multihash.push(0x00); multihash.push(36);
     multihash.extend_from_slice(&pub_key_proto);
     
-    let peer_id_str = bs58::encode(&multihash).into_string();
+    (bs58::encode(&multihash).into_string(), NodeId::from_data(&multihash))
+}
+
+pub fn init() {
+    serial_println!("[P2P] Initializing P2P Stack (Modified Kademlia)...");
+    crate::p2p_transport::register_tunables();
+    crate::p2p_store::register_tunables();
+    crate::p2p_blocks::register_tunables();
+    crate::p2p_conn::register_tunables();
+    crate::p2p_maintenance::register_tunables();
+    crate::p2p_reputation::register_tunables();
+    crate::p2p_nat::register_tunables();
+    crate::p2p_relay::register_tunables();
+    
+    // 1. Load the identity, or generate one on first boot
+    serial_println!("[P2P] Step 1: Loading Identity...");
+    let saved = kv::open(STATE_NAMESPACE);
+    let signing_key = load_identity(saved);
+    let verifying_key = signing_key.verifying_key();
+    crate::p2p_sign::start_boot(count_boot(saved));
     
-    // Generate NodeID (SHA256 of PeerID/PublicKey)
-    serial_println!("[P2P] Step 4: Generating NodeID (SHA256)...");
-    let node_id = NodeId::from_data(&multihash);
+    serial_println!("[P2P] Step 3: Deriving PeerID and NodeID...");
+    let (peer_id_str, node_id) = identity_of(&verifying_key);
 
     serial_println!("[P2P] Identity: {:?} NodeId: {:?}", peer_id_str, node_id);
+    crate::net_stack::set_hostname(host_label(&node_id));
     
-    serial_println!("[P2P] Step 5: Initializing Global State...");
+    serial_println!("[P2P] Step 4: Initializing Global State...");
     *P2P_STATE.lock() = Some(P2PState { 
         peer_id: peer_id_str,
         node_id,
         routing_table: RoutingTable::new(node_id),
+        keys: LocalKeys::new(signing_key),
     });
     serial_println!("[P2P] State initialized.");
+    let known = load_peers(saved);
+    let rejoin = !known.is_empty() && crate::cmdline::get("p2p_bootstrap").is_none();
+    serial_println!("[P2P] Loaded {} known peers.", known.len());
+    for peer in known {
+        add_peer(peer);
+    }
+    crate::shutdown::register_flush_hook("P2P peers", save_peers);
     
     // 2. Spawn P2P Listener Task
-    serial_println!("[P2P] Step 6: Spawning Listener...");
+    serial_println!("[P2P] Step 5: Spawning Listener...");
     EXECUTOR.lock().spawn(Task::new(p2p_listen_task()));
+    crate::p2p_pubsub::start();
+    crate::p2p_maintenance::start();
+
+    // 3. Dial the bootstrap peers from the command line
+    if let Some(peers) = crate::cmdline::get("p2p_bootstrap") {
+        start_bootstrap(peers);
+    } else if rejoin {
+        EXECUTOR.lock().spawn(Task::new(async {
+            while crate::net_stack::address_config().and_then(|config| config.address).is_none() {
+                executor::sleep_ms(ADDRESS_POLL_MS).await;
+            }
+            self_lookup().await;
+        }));
+    }
+}
+
+// ─── Saved State ─────────────────────────────────────────────────────────────
+//
+// The identity and the peers the node knew are kept in the kernel's
+// key-value store, so the node keeps its peer ID and finds its way back
+// into the network as long as the store keeps them (across reboots, once
+// the store is written to the block device).
+
+/// Key-value namespace holding the saved state.
+const STATE_NAMESPACE: &str = "kernel.p2p";
+const IDENTITY_KEY: &[u8] = b"identity";
+const BOOTS_KEY: &[u8] = b"boots";
+const PEERS_KEY: &[u8] = b"peers";
+
+/// The saved signing key, or a new one, saved for next time.
+fn load_identity(ns: kv::Namespace) -> SigningKey {
+    if let Ok(Some(secret)) = kv::get(ns, IDENTITY_KEY, |value| <[u8; 32]>::try_from(value).ok()) {
+        return SigningKey::from_bytes(&secret);
+    }
+    serial_println!("[P2P] No saved identity; generating one.");
+    let mut secret = [0u8; 32];
+    getrandom::getrandom(&mut secret).expect("RNG failed");
+    if let Err(e) = kv::put(ns, IDENTITY_KEY, &secret) {
+        serial_println!("[P2P] Couldn't save the identity: {:?}", e);
+    }
+    SigningKey::from_bytes(&secret)
+}
+
+/// How many boots used the saved identity before this one; counts this
+/// one.
+fn count_boot(ns: kv::Namespace) -> u64 {
+    let boots = kv::get(ns, BOOTS_KEY, |value| value.try_into().ok().map(u64::from_le_bytes))
+        .ok()
+        .flatten()
+        .unwrap_or(0);
+    if let Err(e) = kv::put(ns, BOOTS_KEY, &(boots + 1).to_le_bytes()) {
+        serial_println!("[P2P] Couldn't save the boot count: {:?}", e);
+    }
+    boots
+}
+
+/// Save the routing table's peers that have an endpoint, most recently
+/// seen first, as many as fit in one value: per peer its node ID, IPv4
+/// address, port (u16 LE), and peer ID length (u8) and bytes. Runs from
+/// maintenance and once more at shutdown, as a flush hook.
+pub fn save_peers() {
+    let mut peers: Vec<PeerInfo> = match P2P_STATE.lock().as_ref() {
+        Some(state) => state.routing_table.buckets.iter().flat_map(|b| b.peers.iter().cloned()).collect(),
+        None => return,
+    };
+    peers.sort_by_key(|peer| core::cmp::Reverse(peer.last_seen));
+    let mut value = Vec::with_capacity(kv::MAX_VALUE_LEN);
+    for peer in &peers {
+        let (Some(endpoint), Ok(id_len)) = (peer.endpoint, u8::try_from(peer.peer_id_str.len())) else { continue };
+        if value.len() + 32 + 4 + 2 + 1 + usize::from(id_len) > kv::MAX_VALUE_LEN {
+            break;
+        }
+        let smoltcp::wire::IpAddress::Ipv4(addr) = endpoint.addr;
+        value.extend_from_slice(&peer.node_id.0);
+        value.extend_from_slice(addr.as_bytes());
+        value.extend_from_slice(&endpoint.port.to_le_bytes());
+        value.push(id_len);
+        value.extend_from_slice(peer.peer_id_str.as_bytes());
+    }
+    if let Err(e) = kv::put(kv::open(STATE_NAMESPACE), PEERS_KEY, &value) {
+        serial_println!("[P2P] Couldn't save the known peers: {:?}", e);
+    }
+}
+
+/// The peers `save_peers` saved.
+fn load_peers(ns: kv::Namespace) -> Vec<PeerInfo> {
+    let mut peers = Vec::new();
+    let _ = kv::get(ns, PEERS_KEY, |mut value| {
+        while let Some((entry, rest)) = value.split_at_checked(32 + 4 + 2 + 1) {
+            let id_len = usize::from(entry[38]);
+            let Some((peer_id, rest)) = rest.split_at_checked(id_len) else { break };
+            let Ok(peer_id) = core::str::from_utf8(peer_id) else { break };
+            let addr = Ipv4Address::from_bytes(&entry[32..36]);
+            let port = u16::from_le_bytes([entry[36], entry[37]]);
+            peers.push(PeerInfo {
+                node_id: NodeId::new(entry[..32].try_into().unwrap()),
+                peer_id_str: String::from(peer_id),
+                endpoint: Some(IpEndpoint::new(addr.into(), port)),
+                last_seen: 0,
+            });
+            value = rest;
+        }
+    });
+    peers
 }
 
 use core::task::{Context, Poll};
 use core::future::Future;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 struct YieldNow {
     yielded: bool,
@@ -92,91 +233,311 @@ pub fn yield_now() -> impl Future<Output = ()> {
 
 async fn p2p_listen_task() {
     serial_println!("[P2P] Starting listener task...");
-    
+
     loop {
-        // serial_println!("[P2P] Listener loop tick");
-        let mut handle_opt = None;
-        {
-            let mut stack = NETWORK_STACK.lock();
-            if let Some(ref mut stack_inner) = *stack {
-                let socket = stack_inner.sockets.get_mut::<smoltcp::socket::tcp::Socket>(stack_inner.p2p_handle);
-                
-                let state = socket.state();
-                if state == smoltcp::socket::tcp::State::Established || state == smoltcp::socket::tcp::State::CloseWait {
-                     serial_println!("[P2P] Socket active! State: {:?}", state);
-                     handle_opt = Some(stack_inner.p2p_handle);
-                } else if state == smoltcp::socket::tcp::State::Closed {
-                    // serial_println!("[P2P] Socket closed, re-listening...");
-                    socket.listen(40444).ok();
-                }
-            }
+        // Each peer gets its own task, so one slow handshake doesn't hold
+        // up the next peer.
+        let accepted = NETWORK_STACK.lock().as_mut().and_then(|stack| stack.accept(P2P_PORT));
+        match accepted {
+            Some(handle) => executor::submit(Task::new(p2p_connection_task(handle))),
+            None => yield_now().await,
         }
-        
-        if let Some(handle) = handle_opt {
-            serial_println!("[P2P] New connection detected! Exchanging handshakes...");
-            match handshake(handle).await {
-                Ok(_) => { serial_println!("[P2P] Handshake success!"); }
-                Err(_) => { serial_println!("[P2P] Handshake failed or connection closed."); }
-            }
-            // After handshake, close or keep open. For now, we simple echo/close.
-            {
-                let mut stack = NETWORK_STACK.lock();
-                if let Some(ref mut stack_inner) = *stack {
-                    let socket = stack_inner.sockets.get_mut::<smoltcp::socket::tcp::Socket>(stack_inner.p2p_handle);
-                    socket.close();
-                }
+    }
+}
+
+/// Times each bootstrap peer is dialed before giving up on it.
+const BOOTSTRAP_ATTEMPTS: u32 = 6;
+/// Wait after the first failed dial of a bootstrap peer. It doubles after
+/// each later failure, up to `BOOTSTRAP_MAX_BACKOFF_MS`.
+const BOOTSTRAP_BACKOFF_MS: u64 = 1_000;
+const BOOTSTRAP_MAX_BACKOFF_MS: u64 = 30_000;
+/// Wait between checks for an address before dialing.
+const ADDRESS_POLL_MS: u64 = 1_000;
+
+/// Bootstrap peers from the boot option still being dialed; the last one
+/// to finish starts the self-lookup.
+static BOOTSTRAP_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Dial each `host:port` in `peers` (comma-separated, from the
+/// `p2p_bootstrap` boot option), each in its own task so a dead peer
+/// doesn't hold up the rest, then join the network with a self-lookup.
+fn start_bootstrap(peers: &'static str) {
+    let peers = || peers.split(',').map(str::trim).filter(|peer| !peer.is_empty());
+    BOOTSTRAP_PENDING.store(peers().count(), Ordering::Relaxed);
+    for peer in peers() {
+        EXECUTOR.lock().spawn(Task::new(async move {
+            bootstrap_peer(peer).await;
+            if BOOTSTRAP_PENDING.fetch_sub(1, Ordering::Relaxed) == 1 {
+                self_lookup().await;
             }
+        }));
+    }
+}
+
+/// Handshake with `peer` (`host:port`) so it lands in the routing table,
+/// retrying with backoff. Waits for an address first. Returns whether a
+/// handshake succeeded.
+pub async fn bootstrap_peer(peer: &str) -> bool {
+    let Some((host, port)) = peer.rsplit_once(':').and_then(|(host, port)| Some((host, port.parse().ok()?))) else {
+        serial_println!("[P2P] Bootstrap peer '{}' is not host:port; skipping it.", peer);
+        return false;
+    };
+    while crate::net_stack::address_config().and_then(|config| config.address).is_none() {
+        executor::sleep_ms(ADDRESS_POLL_MS).await;
+    }
+    let mut backoff = BOOTSTRAP_BACKOFF_MS;
+    for attempt in 1..=BOOTSTRAP_ATTEMPTS {
+        if dial(host, port).await.is_ok() {
+            return true;
+        }
+        if attempt < BOOTSTRAP_ATTEMPTS {
+            // Up to a quarter extra, so peers that failed together don't
+            // retry together.
+            let mut jitter = [0; 2];
+            crate::random::fill(&mut jitter);
+            let wait = backoff + u64::from(u16::from_le_bytes(jitter)) % (backoff / 4 + 1);
+            serial_println!("[P2P] Retrying bootstrap peer {} in {} ms.", peer, wait);
+            executor::sleep_ms(wait).await;
+            backoff = (backoff * 2).min(BOOTSTRAP_MAX_BACKOFF_MS);
         }
-        
-        // Yield proper
-        yield_now().await;
     }
+    serial_println!("[P2P] Giving up on bootstrap peer {} after {} attempts.", peer, BOOTSTRAP_ATTEMPTS);
+    false
 }
 
-async fn handshake(handle: smoltcp::iface::SocketHandle) -> Result<(), ()> {
-    // 1. Send our PeerID and NodeID
-    let (my_peer_id, my_node_id) = {
+/// Join: look ourselves up, which fills the routing table with the peers
+/// closest to us.
+async fn self_lookup() {
+    let Some(local_id) = P2P_STATE.lock().as_ref().map(|state| state.node_id) else { return };
+    let found = p2p_rpc::lookup(local_id).await;
+    serial_println!("[P2P] Self-lookup found {} peers.", found.len());
+}
+
+/// Connect to a peer and exchange handshakes.
+async fn dial(host: &str, port: u16) -> Result<(), ()> {
+    let addr = crate::dns::resolve(host).await.map_err(|e| {
+        serial_println!("[P2P] Cannot resolve {}: {:?}", host, e);
+    })?;
+    let buffer = crate::net_stack::service_tcp_buffer();
+    crate::p2p_metrics::dial_attempted();
+    let handle = crate::net_stack::tcp_connect_buffered(addr, port, buffer).await.map_err(|e| {
+        serial_println!("[P2P] Dialing {}:{} failed: {:?}", addr, port, e);
+    })?;
+    serial_println!("[P2P] Connected to {}:{}. Exchanging handshakes...", addr, port);
+    match handshake(FramedConnection::new(handle), Role::Initiator).await {
+        Ok((conn, peer, protocols)) => {
+            crate::p2p_metrics::dial_succeeded();
+            p2p_conn::adopt(conn, handle, peer, Direction::Outbound, protocols);
+            Ok(())
+        }
+        Err(()) => {
+            tcp_close(handle);
+            Err(())
+        }
+    }
+}
+
+async fn p2p_connection_task(handle: smoltcp::iface::SocketHandle) {
+    serial_println!("[P2P] New connection detected! Exchanging handshakes...");
+    match handshake(FramedConnection::new(handle), Role::Responder).await {
+        Ok((conn, peer, protocols)) => {
+            serial_println!("[P2P] Handshake success!");
+            p2p_conn::adopt(conn, handle, peer, Direction::Inbound, protocols);
+        }
+        Err(_) => {
+            serial_println!("[P2P] Handshake failed or connection closed.");
+            tcp_close(handle);
+        }
+    }
+}
+
+/// Longest a peer may take over its handshake, whichever side dialed,
+/// and over a relayed connection's handshake (see `p2p_conn`).
+pub const HANDSHAKE_TIMEOUT_MS: u64 = 5_000;
+
+/// Secure a new connection (see `p2p_noise`), exchange identities and
+/// protocol lists over it (see `p2p_protocols`) and add the peer to the
+/// routing table and the address book. Returns the secured connection,
+/// the peer's node ID and the protocols both sides speak.
+///
+/// The whole exchange must finish within `HANDSHAKE_TIMEOUT_MS`: waiting
+/// for a frame never times out by itself, so a peer that connects and
+/// stays silent would otherwise hold the socket and this task for good.
+pub(crate) async fn handshake(
+    conn: FramedConnection,
+    role: Role,
+) -> Result<(SecureConnection, NodeId, Negotiated), ()> {
+    let deadline = Some(crate::interrupts::uptime_ms() + HANDSHAKE_TIMEOUT_MS);
+    let result = match (Deadline { inner: pin!(secure(conn, role)), deadline }).await {
+        Some(result) => result,
+        None => {
+            serial_println!("[P2P] The handshake timed out.");
+            Err(())
+        }
+    };
+    crate::p2p_metrics::handshake(result.is_ok());
+    result
+}
+
+/// The steps of `handshake`, which counts how they end.
+async fn secure(conn: FramedConnection, role: Role) -> Result<(SecureConnection, NodeId, Negotiated), ()> {
+    // 1. Send our PeerID, NodeID and the addresses we can be dialed on
+    let (my_peer_id, my_node_id, keys) = {
         let state = P2P_STATE.lock();
         let s = state.as_ref().unwrap();
-        (s.peer_id.clone(), s.node_id.clone())
+        (s.peer_id.clone(), s.node_id.clone(), s.keys.clone())
     };
+    // The interface's address, and the one peers see if it differs
+    // (behind NAT, see `p2p_nat`).
+    let mut my_addresses: Vec<IpEndpoint> = crate::net_stack::address_config()
+        .and_then(|config| config.address)
+        .map(|cidr| IpEndpoint::new(cidr.address().into(), P2P_PORT))
+        .into_iter()
+        .collect();
+    if let Some(external) = crate::p2p_nat::external_address().filter(|external| !my_addresses.contains(external)) {
+        my_addresses.push(external);
+    }
     
-    // Serialization: [PeerID Len (4)] [PeerID Bytes] [NodeID (32)]
-    let peer_id_bytes: &[u8] = my_peer_id.as_bytes();
-    let mut payload = Vec::with_capacity(4 + peer_id_bytes.len() + 32);
-    payload.extend_from_slice(&(peer_id_bytes.len() as u32).to_le_bytes());
-    payload.extend_from_slice(peer_id_bytes);
-    payload.extend_from_slice(&my_node_id.0);
-    
-    p2p_transport::send_framed(handle, &payload).await?;
-    serial_println!("[P2P] Sent Identity (PeerID + NodeID)");
-    
-    // 2. Recv their Identity
-    let payload = p2p_transport::recv_framed(handle).await?;
-    if payload.len() < 36 { return Err(()); } // Min 4(len) + 0(id) + 32(node)
-    
-    let len = u32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]]) as usize;
-    if payload.len() < 4 + len + 32 { return Err(()); }
-    
-    let remote_peer_id = String::from_utf8_lossy(&payload[4..4+len]).into_owned();
-    let mut node_id_bytes = [0u8; 32];
-    node_id_bytes.copy_from_slice(&payload[4+len..4+len+32]);
-    let remote_node_id = NodeId::new(node_id_bytes);
-    
+    // An `Identity` message (see `p2p_proto`). The port is where other
+    // peers can dial us.
+    let mut identity = Writer::with_capacity(my_peer_id.len() + 48 + 12 * my_addresses.len());
+    identity.string(1, &my_peer_id).bytes(2, &my_node_id.0).uint(3, u64::from(P2P_PORT));
+    for endpoint in &my_addresses {
+        identity.bytes(4, &p2p_proto::encode_endpoint(endpoint));
+    }
+    let payload = identity.finish();
+
+    // 2. Run the Noise handshake, which carries both identities. A banned
+    // address gets no handshake; a failed one counts against the address.
+    let handle = conn.handle();
+    let observed = {
+        let stack = NETWORK_STACK.lock();
+        stack
+            .as_ref()
+            .and_then(|s| s.sockets.get::<smoltcp::socket::tcp::Socket>(handle).remote_endpoint())
+    };
+    let remote_addr = observed.map(|endpoint| Subject::Address(endpoint.addr));
+    if remote_addr.is_some_and(p2p_reputation::is_banned) {
+        serial_println!("[P2P] {:?} is banned; refusing the handshake.", observed);
+        return Err(());
+    }
+    let failed = || {
+        if let Some(addr) = remote_addr {
+            p2p_reputation::penalize(addr, Offense::HandshakeFailed);
+        }
+    };
+    let (mut conn, remote) = p2p_noise::handshake(conn, role, &keys, &payload).await.map_err(|e| {
+        serial_println!("[P2P] Secure handshake failed: {:?}", e);
+        failed();
+    })?;
+    let Some(Identity { peer_id: remote_peer_id, node_id: remote_node_id, listen_port, claimed }) =
+        decode_identity(&remote.payload)
+    else {
+        serial_println!("[P2P] The peer's identity is malformed.");
+        failed();
+        return Err(());
+    };
+
+    // The IDs must be the ones the key that signed the handshake hashes to
+    if identity_of(&remote.identity) != (remote_peer_id.clone(), remote_node_id) {
+        serial_println!("[P2P] Peer {} claims IDs its key doesn't hash to; rejecting it.", remote_peer_id);
+        failed();
+        return Err(());
+    }
+    if p2p_reputation::is_banned(Subject::Peer(remote_node_id)) {
+        serial_println!("[P2P] {:?} is banned; closing the connection.", remote_node_id);
+        return Err(());
+    }
+    let protocols = p2p_protocols::negotiate(&mut conn).await.inspect_err(|_| failed())?;
     serial_println!("[P2P] Handshake verified. Remote PeerID: {} NodeID: {:?}", remote_peer_id, remote_node_id);
-    
-    // 3. Add to Routing Table
-    {
-        let mut state_lock = P2P_STATE.lock();
-        if let Some(state) = state_lock.as_mut() {
-            let peer_info = PeerInfo {
-                node_id: remote_node_id,
-                peer_id_str: remote_peer_id,
-            };
-            state.routing_table.add_peer(peer_info);
-            serial_println!("[P2P] Added peer to Kademlia Routing Table.");
+
+    // 3. Work out where the peer can be dialed: the address we observed it
+    // on, with the port it listens on. A claimed address that differs is
+    // likely behind NAT or spoofed, so the observed one wins.
+    let endpoint = observed.map(|mut endpoint| {
+        endpoint.port = listen_port.unwrap_or(endpoint.port);
+        if let Some(claimed) = claimed.filter(|claimed| claimed.addr != endpoint.addr) {
+            serial_println!(
+                "[P2P] {:?} claims {} but connected from {}; using the observed address.",
+                remote_node_id, claimed, endpoint.addr
+            );
         }
+        endpoint
+    });
+
+    // 4. Add to Routing Table and the address book
+    let last_seen = crate::interrupts::uptime_ms();
+    add_peer(PeerInfo { node_id: remote_node_id, peer_id_str: remote_peer_id, endpoint, last_seen });
+    if let Some(endpoint) = endpoint {
+        ADDRESS_BOOK
+            .lock()
+            .record_peer(remote_node_id, endpoint, crate::interrupts::uptime_ms());
     }
     
-    Ok(())
+    Ok((conn, remote_node_id, protocols))
+}
+
+/// What a peer says about itself in the handshake:
+/// ```text
+/// message Identity {
+///   string            peer_id     = 1;
+///   bytes             node_id     = 2;  // 32 bytes
+///   uint32            listen_port = 3;
+///   repeated Endpoint addresses   = 4;  // see `p2p_proto`
+/// }
+/// ```
+struct Identity {
+    peer_id: String,
+    node_id: NodeId,
+    listen_port: Option<u16>,
+    /// The first address the peer claims.
+    claimed: Option<IpEndpoint>,
+}
+
+/// An `Identity` message. `None` if it is malformed or lacks an ID.
+/// Addresses that aren't IPv4 are skipped.
+fn decode_identity(message: &[u8]) -> Option<Identity> {
+    let (mut peer_id, mut node_id, mut listen_port, mut claimed) = (None, None, None, None);
+    for field in p2p_proto::fields(message) {
+        match field? {
+            (1, value) => peer_id = Some(String::from(value.str()?)),
+            (2, value) => node_id = Some(NodeId::new(value.array()?)),
+            (3, value) => listen_port = Some(u16::try_from(value.uint()?).ok()?),
+            (4, value) => claimed = claimed.or(p2p_proto::decode_endpoint(value.bytes()?)),
+            _ => {}
+        }
+    }
+    Some(Identity { peer_id: peer_id?, node_id: node_id?, listen_port, claimed })
+}
+
+/// Add `peer` to the routing table. When its bucket is full, ping the
+/// bucket's least-recently-seen peer and replace that one only if it
+/// doesn't answer.
+pub(crate) fn add_peer(peer: PeerInfo) {
+    let node_id = peer.node_id;
+    let outcome = match P2P_STATE.lock().as_mut() {
+        Some(state) => state.routing_table.add_peer(peer),
+        None => return,
+    };
+    match outcome {
+        AddOutcome::Added => {
+            serial_println!("[P2P] Added {:?} to the routing table.", node_id);
+            executor::submit(Task::new(crate::p2p_maintenance::hand_over(node_id)));
+        }
+        AddOutcome::Full { oldest } => executor::submit(Task::new(ping_oldest(oldest))),
+        AddOutcome::Refreshed | AddOutcome::Pending => {}
+    }
+}
+
+async fn ping_oldest(oldest: NodeId) {
+    let known = P2P_STATE.lock().as_ref().and_then(|state| state.routing_table.peer(&oldest)?.endpoint);
+    let endpoint = ADDRESS_BOOK.lock().peer_endpoint(&oldest, crate::interrupts::uptime_ms()).or(known);
+    let answered = match endpoint {
+        Some(endpoint) => p2p_rpc::ping(p2p_rpc::Contact { node_id: oldest, endpoint }).await.is_ok(),
+        None => false,
+    };
+    let evicted = P2P_STATE.lock().as_mut().is_some_and(|state| state.routing_table.resolve_ping(&oldest, answered));
+    if evicted {
+        serial_println!("[P2P] Evicted {:?}, which didn't answer a ping.", oldest);
+    }
 }
@@ -0,0 +1,176 @@
+//! # Content-Addressed Block Store
+//!
+//! Blocks of bytes named by their SHA-256 (as a `NodeId`, like DHT keys),
+//! so a block fetched from any peer can be checked against its name. Peers
+//! fetch blocks from each other with WANT_BLOCK (see `p2p_rpc`).
+//!
+//! Data longer than a block is stored as an *object*: its
+//! `MAX_BLOCK_LEN`-byte chunks, each a block, and a manifest block listing
+//! them, whose hash names the object. A manifest is
+//! `[MANIFEST_MAGIC (8)][length: u64 LE][chunk hash (32)]...`.
+//!
+//! ## Pinning and eviction
+//! Blocks put here are pinned and stay until removed; blocks fetched from
+//! peers are a cache. The store holds at most `p2p.blocks.max_bytes`; a
+//! block that doesn't fit evicts the least recently used cached blocks,
+//! and fails with `Full` if that isn't enough.
+//!
+//! ## Memory
+//! The kernel heap never frees, so the buffers of evicted and removed
+//! blocks are kept and reused for later blocks that fit in them.
+
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::config;
+use crate::interrupts::uptime_ms;
+use crate::p2p_kademlia::{NodeId, ID_SIZE};
+
+/// Largest block, and the chunk size of objects.
+pub const MAX_BLOCK_LEN: usize = 64 * 1024;
+/// Largest object.
+pub const MAX_OBJECT_LEN: usize = 16 * 1024 * 1024;
+/// Default of `p2p.blocks.max_bytes`.
+const DEFAULT_MAX_BYTES: u64 = 4 * 1024 * 1024;
+const MANIFEST_MAGIC: &[u8; 8] = b"blkobj\x00\x01";
+const MANIFEST_HEADER_LEN: usize = 16;
+
+/// Why a block or object couldn't be stored or fetched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// Longer than `MAX_BLOCK_LEN`, or an object longer than
+    /// `MAX_OBJECT_LEN`.
+    TooLarge,
+    /// Evicting every cached block wouldn't make room.
+    Full,
+    /// Neither this node nor any peer asked has the block.
+    Missing(NodeId),
+    /// The block isn't an object manifest.
+    NotAnObject,
+}
+
+struct Block {
+    hash: NodeId,
+    data: Vec<u8>,
+    pinned: bool,
+    last_used: u64,
+}
+
+/// A stored block, as listed by `blocks`.
+#[derive(Debug, Clone, Copy)]
+pub struct BlockInfo {
+    pub hash: NodeId,
+    pub len: usize,
+    pub pinned: bool,
+}
+
+lazy_static! {
+    static ref BLOCKS: Mutex<Vec<Block>> = Mutex::new(Vec::new());
+    /// Buffers of blocks that were evicted or removed.
+    static ref SPARE: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+}
+
+/// Register the `p2p.blocks.max_bytes` key.
+pub fn register_tunables() {
+    config::register("p2p.blocks.max_bytes", "Bytes of content-addressed blocks this node keeps", DEFAULT_MAX_BYTES, 64 * 1024, 64 * 1024 * 1024, None);
+}
+
+/// Store and pin `data` as one block. Returns its hash.
+pub fn put(data: &[u8]) -> Result<NodeId, BlockError> {
+    let hash = NodeId::from_data(data);
+    insert(hash, data, true)?;
+    Ok(hash)
+}
+
+/// Keep `data`, fetched from a peer, as the cached block `hash`. The
+/// caller has checked the hash.
+pub fn cache(hash: NodeId, data: &[u8]) -> Result<(), BlockError> {
+    insert(hash, data, false)
+}
+
+/// Store and pin `data` as an object. Returns its manifest's hash.
+pub fn put_object(data: &[u8]) -> Result<NodeId, BlockError> {
+    if data.len() > MAX_OBJECT_LEN {
+        return Err(BlockError::TooLarge);
+    }
+    let chunks = data.chunks(MAX_BLOCK_LEN);
+    let mut manifest = Vec::with_capacity(MANIFEST_HEADER_LEN + chunks.len() * ID_SIZE);
+    manifest.extend_from_slice(MANIFEST_MAGIC);
+    manifest.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    for chunk in chunks {
+        manifest.extend_from_slice(&put(chunk)?.0);
+    }
+    put(&manifest)
+}
+
+/// The object length and chunk hashes `block` lists, if it is a manifest.
+pub fn parse_manifest(block: &[u8]) -> Result<(usize, Vec<NodeId>), BlockError> {
+    let (header, hashes) = block.split_at_checked(MANIFEST_HEADER_LEN).ok_or(BlockError::NotAnObject)?;
+    if &header[..8] != MANIFEST_MAGIC || hashes.len() % ID_SIZE != 0 {
+        return Err(BlockError::NotAnObject);
+    }
+    let len = u64::from_le_bytes(header[8..].try_into().unwrap());
+    if len > MAX_OBJECT_LEN as u64 || (len as usize).div_ceil(MAX_BLOCK_LEN) != hashes.len() / ID_SIZE {
+        return Err(BlockError::NotAnObject);
+    }
+    let chunks = hashes.chunks_exact(ID_SIZE).map(|hash| NodeId::new(hash.try_into().unwrap())).collect();
+    Ok((len as usize, chunks))
+}
+
+/// The block named `hash`, if this node has it.
+pub fn get(hash: &NodeId) -> Option<Vec<u8>> {
+    let mut blocks = BLOCKS.lock();
+    let block = blocks.iter_mut().find(|b| b.hash == *hash)?;
+    block.last_used = uptime_ms();
+    Some(block.data.clone())
+}
+
+/// Drop the block named `hash`, pinned or not. False if it isn't here.
+pub fn remove(hash: &NodeId) -> bool {
+    let mut blocks = BLOCKS.lock();
+    let Some(idx) = blocks.iter().position(|b| b.hash == *hash) else { return false };
+    SPARE.lock().push(blocks.swap_remove(idx).data);
+    true
+}
+
+/// Every block held, without the data.
+pub fn blocks() -> Vec<BlockInfo> {
+    BLOCKS.lock().iter().map(|b| BlockInfo { hash: b.hash, len: b.data.len(), pinned: b.pinned }).collect()
+}
+
+fn insert(hash: NodeId, data: &[u8], pinned: bool) -> Result<(), BlockError> {
+    if data.len() > MAX_BLOCK_LEN {
+        return Err(BlockError::TooLarge);
+    }
+    let now = uptime_ms();
+    let mut blocks = BLOCKS.lock();
+    if let Some(block) = blocks.iter_mut().find(|b| b.hash == hash) {
+        block.pinned |= pinned;
+        block.last_used = now;
+        return Ok(());
+    }
+    let max = config::get_or("p2p.blocks.max_bytes", DEFAULT_MAX_BYTES) as usize;
+    let mut used: usize = blocks.iter().map(|b| b.data.len()).sum();
+    let cached: usize = blocks.iter().filter(|b| !b.pinned).map(|b| b.data.len()).sum();
+    if used - cached + data.len() > max {
+        return Err(BlockError::Full);
+    }
+    let mut spare = SPARE.lock();
+    while used + data.len() > max {
+        let Some((idx, _)) = blocks.iter().enumerate().filter(|(_, b)| !b.pinned).min_by_key(|(_, b)| b.last_used)
+        else {
+            break;
+        };
+        let evicted = blocks.swap_remove(idx);
+        used -= evicted.data.len();
+        spare.push(evicted.data);
+    }
+    let mut buffer = match spare.iter().position(|buffer| buffer.capacity() >= data.len()) {
+        Some(idx) => spare.swap_remove(idx),
+        None => Vec::with_capacity(data.len()),
+    };
+    buffer.clear();
+    buffer.extend_from_slice(data);
+    blocks.push(Block { hash, data: buffer, pinned, last_used: now });
+    Ok(())
+}
@@ -0,0 +1,446 @@
+//! # Channels
+//!
+//! Byte streams between processes on different nodes. A process listens
+//! under a service name; a process on another node opens a channel to that
+//! node and service, and both ends then read and write it like a socket
+//! (see `env.channel_open` and friends in `wasm_runtime`).
+//!
+//! Channels run over the connections `p2p_conn` keeps open, under their
+//! own protocol (see `p2p_protocols`), and any number of them share one
+//! connection. So a channel is encrypted end to end by the Noise session
+//! between the two nodes, whether the connection has its own socket or
+//! runs through a relay's circuit (see `p2p_relay`), and every message on
+//! it is sealed with the sender's signature (see `p2p_sign`): the far end
+//! of a channel is always the node it names, and a relay in between can't
+//! read it.
+//!
+//! ## Wire format
+//! Every message is a protobuf `Channel` (see `p2p_proto`):
+//!
+//! ```text
+//! message Channel {
+//!   uint32 type    = 1;  // the kind, below
+//!   uint32 id      = 2;  // chosen by the side that opened the channel
+//!   bool   opener  = 3;  // the sender is the side that opened it
+//!   bytes  payload = 4;
+//! }
+//! ```
+//!
+//! | type | message | payload          |
+//! |------|---------|------------------|
+//! | 0x01 | OPEN    | the service name |
+//! | 0x02 | ACCEPT  |                  |
+//! | 0x03 | REFUSE  |                  |
+//! | 0x04 | DATA    | bytes            |
+//! | 0x05 | CLOSE   |                  |
+//!
+//! OPEN gets ACCEPT if a process listens under the service and fewer than
+//! `MAX_PENDING` channels wait for it to accept them, else REFUSE. An OPEN
+//! that gets neither within `OPEN_TIMEOUT_MS` fails. Either end closes the
+//! channel with CLOSE, and it closes by itself when the last connection to
+//! its peer does. Messages for a channel this node doesn't know are
+//! ignored: they may have crossed a CLOSE.
+//!
+//! ## Limits
+//! - One DATA carries at most `MAX_CHUNK` bytes.
+//! - At most `MAX_BUFFERED` bytes wait to be read on a channel. DATA past
+//!   that closes it: a reader that far behind won't catch up.
+//! - A write that finds the connection's queue full (`p2p_conn::MAX_QUEUED`)
+//!   takes nothing, and the writer tries again later.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use core::task::Poll;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::executor::{self, Task};
+use crate::interrupts::uptime_ms;
+use crate::p2p::P2P_STATE;
+use crate::p2p_conn;
+use crate::p2p_kademlia::NodeId;
+use crate::p2p_protocols::Protocol;
+use crate::p2p_proto::{self, Writer};
+use crate::p2p_rpc::{self, Contact, RpcError};
+use crate::p2p_transport::Deadline;
+use crate::serial_println;
+
+/// Longest service name.
+pub const MAX_SERVICE_LEN: usize = 64;
+/// Most bytes one DATA carries.
+pub const MAX_CHUNK: usize = 16 * 1024;
+/// Most unread bytes kept for a channel.
+const MAX_BUFFERED: usize = 64 * 1024;
+/// Opened channels waiting for their listener to accept them.
+const MAX_PENDING: usize = 8;
+/// Longest an OPEN may go unanswered, connecting included.
+const OPEN_TIMEOUT_MS: u64 = 10_000;
+
+const KIND_OPEN: u32 = 0x01;
+const KIND_ACCEPT: u32 = 0x02;
+const KIND_REFUSE: u32 = 0x03;
+const KIND_DATA: u32 = 0x04;
+const KIND_CLOSE: u32 = 0x05;
+
+static NEXT_HANDLE: AtomicU64 = AtomicU64::new(1);
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+/// Why a channel couldn't be opened or used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelError {
+    /// The service name is empty or longer than `MAX_SERVICE_LEN`.
+    BadService,
+    /// A process on this node listens under the service already.
+    InUse,
+    /// The P2P stack isn't up.
+    NotRunning,
+    /// The peer couldn't be reached, or didn't answer the OPEN in time.
+    Unreachable,
+    /// Nothing listens under the service on the peer, or its queue is full.
+    Refused,
+    /// The other end closed the channel, or its connection went away.
+    Closed,
+    /// This end fell more than `MAX_BUFFERED` bytes behind.
+    Overrun,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// OPEN sent, or about to be; no answer yet.
+    Opening,
+    Open,
+    Closed(ChannelError),
+}
+
+/// One end of a channel.
+struct Entry {
+    handle: u64,
+    peer: NodeId,
+    /// The channel's id on the wire.
+    id: u32,
+    /// This node sent the OPEN.
+    opener: bool,
+    state: State,
+    /// Received and not yet read.
+    inbound: VecDeque<u8>,
+}
+
+/// A service processes on this node listen under.
+struct Service {
+    handle: u64,
+    name: String,
+    /// Channels opened to it and not yet accepted, oldest first.
+    pending: VecDeque<u64>,
+}
+
+#[derive(Default)]
+struct Channels {
+    entries: Vec<Entry>,
+    services: Vec<Service>,
+}
+
+impl Channels {
+    fn entry(&mut self, handle: u64) -> Option<&mut Entry> {
+        self.entries.iter_mut().find(|e| e.handle == handle)
+    }
+
+    /// The end of the channel `peer` calls `id`; `opener` is whether
+    /// `peer` opened it.
+    fn by_wire(&mut self, peer: NodeId, id: u32, opener: bool) -> Option<&mut Entry> {
+        self.entries.iter_mut().find(|e| e.peer == peer && e.id == id && e.opener != opener)
+    }
+}
+
+lazy_static! {
+    static ref CHANNELS: Mutex<Channels> = Mutex::new(Channels::default());
+}
+
+// ─── Listening ───────────────────────────────────────────────────────────────
+
+/// A service this node accepts channels for. Dropping it stops listening
+/// and closes the channels it hadn't accepted yet.
+pub struct Listener {
+    handle: u64,
+}
+
+impl Listener {
+    /// The oldest channel opened to the service, if any.
+    pub fn accept(&self) -> Option<Channel> {
+        let handle = CHANNELS.lock().services.iter_mut().find(|s| s.handle == self.handle)?.pending.pop_front()?;
+        Some(Channel { handle })
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        let pending = {
+            let mut channels = CHANNELS.lock();
+            let Some(idx) = channels.services.iter().position(|s| s.handle == self.handle) else { return };
+            channels.services.swap_remove(idx).pending
+        };
+        for handle in pending {
+            drop(Channel { handle });
+        }
+    }
+}
+
+/// Accept channels opened to `service` on this node.
+pub fn listen(service: &str) -> Result<Listener, ChannelError> {
+    check_service(service)?;
+    let mut channels = CHANNELS.lock();
+    if channels.services.iter().any(|s| s.name == service) {
+        return Err(ChannelError::InUse);
+    }
+    let handle = NEXT_HANDLE.fetch_add(1, Ordering::Relaxed);
+    channels.services.push(Service { handle, name: String::from(service), pending: VecDeque::new() });
+    Ok(Listener { handle })
+}
+
+// ─── Opening ─────────────────────────────────────────────────────────────────
+
+/// One end of a channel. Dropping it closes the channel.
+pub struct Channel {
+    handle: u64,
+}
+
+impl Channel {
+    /// Queue up to `MAX_CHUNK` bytes of `data` for the other end. Returns
+    /// how many were taken: 0 while the channel is still opening or the
+    /// connection's queue is full.
+    pub fn send(&self, data: &[u8]) -> Result<usize, ChannelError> {
+        let (peer, id, opener) = {
+            let mut channels = CHANNELS.lock();
+            let entry = channels.entry(self.handle).ok_or(ChannelError::Closed)?;
+            match entry.state {
+                State::Opening => return Ok(0),
+                State::Open => (entry.peer, entry.id, entry.opener),
+                State::Closed(reason) => return Err(reason),
+            }
+        };
+        let data = &data[..data.len().min(MAX_CHUNK)];
+        match p2p_conn::send_message(&peer, Protocol::Channel, &encode(KIND_DATA, id, opener, data)) {
+            Ok(()) => Ok(data.len()),
+            Err(RpcError::Busy) => Ok(0),
+            Err(_) => {
+                close_entry(self.handle, ChannelError::Closed);
+                Err(ChannelError::Closed)
+            }
+        }
+    }
+
+    /// Read what has arrived into `buf`. Returns how many bytes were read:
+    /// 0 if nothing has. Once the channel has closed and everything that
+    /// arrived before has been read, fails with why it closed.
+    pub fn recv(&self, buf: &mut [u8]) -> Result<usize, ChannelError> {
+        let mut channels = CHANNELS.lock();
+        let entry = channels.entry(self.handle).ok_or(ChannelError::Closed)?;
+        if entry.inbound.is_empty() {
+            if let State::Closed(reason) = entry.state {
+                return Err(reason);
+            }
+            return Ok(0);
+        }
+        let n = buf.len().min(entry.inbound.len());
+        for (byte, slot) in entry.inbound.drain(..n).zip(buf.iter_mut()) {
+            *slot = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        let Some(entry) = ({
+            let mut channels = CHANNELS.lock();
+            let idx = channels.entries.iter().position(|e| e.handle == self.handle);
+            idx.map(|idx| channels.entries.swap_remove(idx))
+        }) else {
+            return;
+        };
+        if matches!(entry.state, State::Opening | State::Open) {
+            let _ = p2p_conn::send_message(&entry.peer, Protocol::Channel, &encode(KIND_CLOSE, entry.id, entry.opener, &[]));
+        }
+    }
+}
+
+/// Open a channel to `service` on `peer`, connecting to `peer` first if
+/// needed. Returns at once; the channel reads and writes nothing until the
+/// peer accepts it, and fails with `Unreachable` or `Refused` if it doesn't.
+pub fn open(peer: NodeId, service: &str) -> Result<Channel, ChannelError> {
+    check_service(service)?;
+    if P2P_STATE.lock().is_none() {
+        return Err(ChannelError::NotRunning);
+    }
+    let handle = NEXT_HANDLE.fetch_add(1, Ordering::Relaxed);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    CHANNELS.lock().entries.push(Entry {
+        handle,
+        peer,
+        id,
+        opener: true,
+        state: State::Opening,
+        inbound: VecDeque::new(),
+    });
+    let open = encode(KIND_OPEN, id, true, service.as_bytes());
+    executor::submit(Task::new(async move {
+        let deadline = uptime_ms() + OPEN_TIMEOUT_MS;
+        let sent = match reach(peer).await {
+            Ok(()) => p2p_conn::send_message(&peer, Protocol::Channel, &open),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = sent {
+            serial_println!("[P2P] Couldn't open a channel to {:?}: {:?}", peer, e);
+            close_entry(handle, ChannelError::Unreachable);
+            return;
+        }
+        let answered = poll_fn(|_| match CHANNELS.lock().entry(handle) {
+            Some(entry) if entry.state == State::Opening => Poll::Pending,
+            _ => Poll::Ready(()),
+        });
+        if (Deadline { inner: answered, deadline: Some(deadline) }).await.is_none() {
+            close_entry(handle, ChannelError::Unreachable);
+        }
+    }));
+    Ok(Channel { handle })
+}
+
+/// Make sure a connection to `peer` is open: dial its known endpoint, or
+/// look it up in the DHT first if this node has none.
+async fn reach(peer: NodeId) -> Result<(), RpcError> {
+    if p2p_conn::is_connected(&peer) {
+        return Ok(());
+    }
+    let endpoint = match p2p_rpc::peer_endpoint(&peer, uptime_ms()) {
+        Some(endpoint) => endpoint,
+        None => {
+            let found = p2p_rpc::lookup(peer).await.into_iter().find(|contact| contact.node_id == peer);
+            found.ok_or(RpcError::NotConnected)?.endpoint
+        }
+    };
+    p2p_conn::connect(Contact { node_id: peer, endpoint }).await
+}
+
+fn check_service(service: &str) -> Result<(), ChannelError> {
+    if service.is_empty() || service.len() > MAX_SERVICE_LEN {
+        return Err(ChannelError::BadService);
+    }
+    Ok(())
+}
+
+/// Mark the channel closed for `reason`, keeping what it received for its
+/// reader.
+fn close_entry(handle: u64, reason: ChannelError) {
+    if let Some(entry) = CHANNELS.lock().entry(handle) {
+        if !matches!(entry.state, State::Closed(_)) {
+            entry.state = State::Closed(reason);
+        }
+    }
+}
+
+// ─── Wire ────────────────────────────────────────────────────────────────────
+
+fn encode(kind: u32, id: u32, opener: bool, payload: &[u8]) -> Vec<u8> {
+    let mut message = Writer::with_capacity(16 + payload.len());
+    message.uint(1, u64::from(kind)).uint(2, u64::from(id)).bool(3, opener);
+    if !payload.is_empty() {
+        message.bytes(4, payload);
+    }
+    message.finish()
+}
+
+/// A `Channel` message: its type, id, opener flag and payload.
+fn decode(message: &[u8]) -> Option<(u32, u32, bool, &[u8])> {
+    let (mut kind, mut id, mut opener, mut payload) = (None, None, false, &[][..]);
+    for field in p2p_proto::fields(message) {
+        match field? {
+            (1, value) => kind = Some(value.u32()?),
+            (2, value) => id = Some(value.u32()?),
+            (3, value) => opener = value.bool()?,
+            (4, value) => payload = value.bytes()?,
+            _ => {}
+        }
+    }
+    Some((kind?, id?, opener, payload))
+}
+
+/// Handle a channel message from `peer`. False if it is malformed.
+pub(crate) fn handle(message: &[u8], peer: NodeId) -> bool {
+    let Some((kind, id, opener, payload)) = decode(message) else { return false };
+    match kind {
+        KIND_OPEN if opener => {
+            let Some(service) = core::str::from_utf8(payload).ok().filter(|s| check_service(s).is_ok()) else {
+                return false;
+            };
+            let kind = if accept(peer, id, service) { KIND_ACCEPT } else { KIND_REFUSE };
+            let _ = p2p_conn::send_message(&peer, Protocol::Channel, &encode(kind, id, false, &[]));
+        }
+        KIND_ACCEPT | KIND_REFUSE if !opener => {
+            if let Some(entry) = CHANNELS.lock().by_wire(peer, id, opener) {
+                if entry.state == State::Opening {
+                    entry.state = if kind == KIND_ACCEPT { State::Open } else { State::Closed(ChannelError::Refused) };
+                }
+            }
+        }
+        KIND_DATA => {
+            let mut channels = CHANNELS.lock();
+            let Some(entry) = channels.by_wire(peer, id, opener) else { return true };
+            if entry.state != State::Open {
+                return true;
+            }
+            if entry.inbound.len() + payload.len() > MAX_BUFFERED {
+                serial_println!("[P2P] Channel {} from {:?} overran its buffer; closing it.", id, peer);
+                entry.state = State::Closed(ChannelError::Overrun);
+                drop(channels);
+                let _ = p2p_conn::send_message(&peer, Protocol::Channel, &encode(KIND_CLOSE, id, !opener, &[]));
+                return true;
+            }
+            entry.inbound.extend(payload);
+        }
+        KIND_CLOSE => {
+            if let Some(entry) = CHANNELS.lock().by_wire(peer, id, opener) {
+                entry.state = State::Closed(ChannelError::Closed);
+            }
+        }
+        _ => return false,
+    }
+    true
+}
+
+/// `peer` opened channel `id` to `service`: queue it for the service's
+/// listener. False if nothing listens under it or its queue is full.
+fn accept(peer: NodeId, id: u32, service: &str) -> bool {
+    let mut channels = CHANNELS.lock();
+    if channels.by_wire(peer, id, true).is_some() {
+        return false;
+    }
+    let handle = NEXT_HANDLE.fetch_add(1, Ordering::Relaxed);
+    let Some(listener) = channels.services.iter_mut().find(|s| s.name == service) else { return false };
+    if listener.pending.len() >= MAX_PENDING {
+        return false;
+    }
+    listener.pending.push_back(handle);
+    channels.entries.push(Entry {
+        handle,
+        peer,
+        id,
+        opener: false,
+        state: State::Open,
+        inbound: VecDeque::new(),
+    });
+    true
+}
+
+/// The last connection to `peer` has closed: close the channels to it.
+pub fn peer_disconnected(peer: NodeId) {
+    for entry in CHANNELS.lock().entries.iter_mut().filter(|e| e.peer == peer) {
+        if !matches!(entry.state, State::Closed(_)) {
+            entry.state = State::Closed(if entry.state == State::Opening {
+                ChannelError::Unreachable
+            } else {
+                ChannelError::Closed
+            });
+        }
+    }
+}
@@ -0,0 +1,629 @@
+//! # P2P Connection Manager
+//!
+//! Keeps secured connections to peers open and shares them between RPCs,
+//! instead of dialing and handshaking for every request. Each connection
+//! has its own TCP socket and its own task, which sends what is queued for
+//! the peer and handles what arrives: requests are answered by `p2p_rpc`,
+//! replies are handed to the request waiting for them. Both sides may
+//! send requests on the same connection, whoever dialed. Pubsub messages
+//! (see `p2p_pubsub`) share the connections too; they get no reply. Each
+//! message is tagged with its protocol, from those the handshake
+//! negotiated (see `p2p_protocols`).
+//!
+//! A connection may also run through a relay's circuit (see `p2p_relay`)
+//! instead of its own socket. The two ends run a Noise handshake over the
+//! circuit first, within `p2p::HANDSHAKE_TIMEOUT_MS`, and check the key
+//! it proves against the one the circuit was opened with. Messages are
+//! then sealed the same way, encrypted end to end with that session and
+//! carried in the relay protocol's messages; everything else is shared.
+//!
+//! ## Limits
+//! - At most `p2p.max_connections` connections are kept. Adding one past
+//!   that closes the least recently active, preferring one with no request
+//!   in flight.
+//! - A connection with nothing in flight for `p2p.idle_timeout_ms` is
+//!   closed.
+//! - At most `MAX_QUEUED` messages wait to be sent to one connection; a
+//!   request past that fails at once rather than queueing behind a peer
+//!   that isn't keeping up.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::future::{poll_fn, Future};
+use core::pin::pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::Poll;
+use ed25519_dalek::VerifyingKey;
+use lazy_static::lazy_static;
+use smoltcp::iface::SocketHandle;
+use spin::Mutex;
+use crate::addr_book::ADDRESS_BOOK;
+use crate::executor::{self, Task};
+use crate::interrupts::uptime_ms;
+use crate::net_stack::{self, tcp_close, ConnectError, P2P_PORT};
+use crate::p2p::{self, P2P_STATE};
+use crate::p2p_channel;
+use crate::p2p_kademlia::NodeId;
+use crate::p2p_metrics;
+use crate::p2p_nat;
+use crate::p2p_noise::{self, FrameLink, LocalKeys, Role, SecureConnection, Session};
+use crate::p2p_protocols::{self, Negotiated, Protocol};
+use crate::p2p_pubsub;
+use crate::p2p_relay;
+use crate::p2p_reputation::{self, Offense, Subject};
+use crate::p2p_rpc::{self, Contact, Incoming, RpcError};
+use crate::p2p_sign;
+use crate::p2p_transport::{Deadline, FrameError, FramedConnection};
+use crate::{config, serial_println};
+
+/// Messages waiting to be sent on one connection.
+pub const MAX_QUEUED: usize = 16;
+/// Default of `p2p.max_connections`.
+const DEFAULT_MAX_CONNECTIONS: u64 = 32;
+/// Default of `p2p.idle_timeout_ms`.
+const DEFAULT_IDLE_TIMEOUT_MS: u64 = 60_000;
+
+static NEXT_CONNECTION: AtomicU64 = AtomicU64::new(1);
+
+/// Which side opened a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// How a connection's messages travel.
+enum Link {
+    /// Over its own socket, run by `run`.
+    Direct,
+    /// Through circuit `circuit` of the relay `relay`, run by
+    /// `run_relayed`. `inbox` holds frames from the circuit, still
+    /// encrypted, for the task to take.
+    Relayed { relay: NodeId, circuit: u32, inbox: VecDeque<Vec<u8>> },
+}
+
+struct Connection {
+    id: u64,
+    peer: NodeId,
+    direction: Direction,
+    link: Link,
+    protocols: Negotiated,
+    /// Tagged messages to seal and send, oldest first.
+    outbox: VecDeque<Vec<u8>>,
+    last_active: u64,
+    /// Set to make the connection's task close it.
+    closing: bool,
+}
+
+/// A request waiting for its reply.
+struct Pending {
+    connection: u64,
+    request: u32,
+    reply: Option<Vec<u8>>,
+    /// The connection closed before the reply came.
+    failed: bool,
+}
+
+/// An open connection, as listed by `connections`.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub peer: NodeId,
+    pub direction: Direction,
+    /// The relay the connection runs through, if it isn't direct.
+    pub relay: Option<NodeId>,
+    /// The protocols both sides speak.
+    pub protocols: Vec<Protocol>,
+    pub queued: usize,
+    pub in_flight: usize,
+    /// Milliseconds since anything was sent or received.
+    pub idle_ms: u64,
+}
+
+lazy_static! {
+    static ref CONNECTIONS: Mutex<Vec<Connection>> = Mutex::new(Vec::new());
+    static ref PENDING: Mutex<Vec<Pending>> = Mutex::new(Vec::new());
+}
+
+/// Register the `p2p.max_connections` and `p2p.idle_timeout_ms` keys.
+pub fn register_tunables() {
+    config::register("p2p.max_connections", "P2P connections kept open at once", DEFAULT_MAX_CONNECTIONS, 1, 256, None);
+    config::register("p2p.idle_timeout_ms", "Close a P2P connection with nothing in flight for this long", DEFAULT_IDLE_TIMEOUT_MS, 1_000, 3_600_000, None);
+}
+
+/// Every open connection.
+pub fn connections() -> Vec<ConnectionInfo> {
+    let now = uptime_ms();
+    let pending = PENDING.lock();
+    CONNECTIONS
+        .lock()
+        .iter()
+        .map(|c| ConnectionInfo {
+            peer: c.peer,
+            direction: c.direction,
+            relay: match c.link {
+                Link::Direct => None,
+                Link::Relayed { relay, .. } => Some(relay),
+            },
+            protocols: c.protocols.protocols(),
+            queued: c.outbox.len(),
+            in_flight: pending.iter().filter(|p| p.connection == c.id).count(),
+            idle_ms: now.saturating_sub(c.last_active),
+        })
+        .collect()
+}
+
+// ─── Opening ─────────────────────────────────────────────────────────────────
+
+/// Make sure there is a connection to `contact`, dialing it if not. A dial
+/// that times out, as dials to a peer behind NAT do, is retried as a hole
+/// punch (see `p2p_nat`), and failing that through a relay's circuit (see
+/// `p2p_relay`).
+pub async fn connect(contact: Contact) -> Result<(), RpcError> {
+    match dial(contact, None).await {
+        Err(e @ RpcError::Connect(ConnectError::TimedOut)) => {
+            if p2p_nat::hole_punching() && p2p_nat::connect_via_relay(contact).await.is_ok() {
+                return Ok(());
+            }
+            p2p_relay::connect_via_any(contact.node_id).await.map_err(|_| e)
+        }
+        result => result,
+    }
+}
+
+/// Dial `contact` from the P2P port, for a hole punch.
+pub async fn punch(contact: Contact) -> Result<(), RpcError> {
+    dial(contact, Some(P2P_PORT)).await
+}
+
+/// Whether a connection to `peer` is open.
+pub fn is_connected(peer: &NodeId) -> bool {
+    CONNECTIONS.lock().iter().any(|c| c.peer == *peer && !c.closing)
+}
+
+async fn dial(contact: Contact, local_port: Option<u16>) -> Result<(), RpcError> {
+    if is_connected(&contact.node_id) {
+        return Ok(());
+    }
+    if p2p_reputation::is_banned(Subject::Peer(contact.node_id)) {
+        return Err(RpcError::Banned);
+    }
+    let endpoint = contact.endpoint;
+    let buffer = net_stack::service_tcp_buffer();
+    p2p_metrics::dial_attempted();
+    // `tcp_connect` times out by itself (`net.tcp.connect_timeout_ms`).
+    let connected = net_stack::tcp_connect_from(local_port, endpoint.addr, endpoint.port, buffer).await;
+    ADDRESS_BOOK.lock().record_reachability(endpoint, connected.is_ok(), uptime_ms());
+    let handle = connected.map_err(RpcError::Connect)?;
+
+    let (conn, peer, protocols) = match p2p::handshake(FramedConnection::new(handle), Role::Initiator).await {
+        Ok(secured) => secured,
+        Err(()) => {
+            tcp_close(handle);
+            return Err(RpcError::Handshake);
+        }
+    };
+    if peer != contact.node_id {
+        drop(conn);
+        tcp_close(handle);
+        return Err(RpcError::WrongNode);
+    }
+    p2p_metrics::dial_succeeded();
+    adopt(conn, handle, peer, Direction::Outbound, protocols);
+    Ok(())
+}
+
+/// Keep a connection that finished its handshake with `peer`, which
+/// negotiated `protocols`: register it and start the task that runs it.
+/// The task closes `handle` when done.
+pub fn adopt(conn: SecureConnection, handle: SocketHandle, peer: NodeId, direction: Direction, protocols: Negotiated) {
+    let identify = direction == Direction::Outbound && protocols.speaks(Protocol::Identify);
+    let id = register(peer, direction, Link::Direct, protocols);
+    executor::submit(Task::new(run(conn, handle, peer, id)));
+    if identify {
+        executor::submit(Task::new(p2p_nat::learn_address(peer)));
+    }
+}
+
+/// Keep a connection to `peer`, whose key is `identity`, through circuit
+/// `circuit` of `relay`, and start the task that runs it.
+pub fn adopt_relayed(
+    peer: NodeId,
+    identity: VerifyingKey,
+    relay: NodeId,
+    circuit: u32,
+    direction: Direction,
+    protocols: Negotiated,
+) {
+    let id = register(peer, direction, Link::Relayed { relay, circuit, inbox: VecDeque::new() }, protocols);
+    let role = if direction == Direction::Outbound { Role::Initiator } else { Role::Responder };
+    executor::submit(Task::new(run_relayed(peer, identity, relay, circuit, id, role)));
+}
+
+/// Add a connection, closing the least recently active one if that makes
+/// too many, and tell pubsub about the peer. Returns the connection's id.
+fn register(peer: NodeId, direction: Direction, link: Link, protocols: Negotiated) -> u64 {
+    let id = NEXT_CONNECTION.fetch_add(1, Ordering::Relaxed);
+    let now = uptime_ms();
+    let gossip = {
+        let mut connections = CONNECTIONS.lock();
+        let max = config::get_or("p2p.max_connections", DEFAULT_MAX_CONNECTIONS) as usize;
+        let open = connections.iter().filter(|c| !c.closing).count();
+        if open >= max {
+            let pending = PENDING.lock();
+            let victim = connections
+                .iter_mut()
+                .filter(|c| !c.closing)
+                .min_by_key(|c| (pending.iter().any(|p| p.connection == c.id), c.last_active));
+            if let Some(victim) = victim {
+                serial_println!("[P2P] At {} connections; closing the one to {:?}.", max, victim.peer);
+                victim.closing = true;
+            }
+        }
+        let gossip = protocols.speaks(Protocol::Gossipsub);
+        let outbox = VecDeque::new();
+        connections.push(Connection { id, peer, direction, link, protocols, outbox, last_active: now, closing: false });
+        gossip
+    };
+    if gossip {
+        p2p_pubsub::peer_connected(peer);
+    }
+    id
+}
+
+/// Close every connection to `peer`.
+pub fn disconnect(peer: &NodeId) {
+    for conn in CONNECTIONS.lock().iter_mut().filter(|c| c.peer == *peer) {
+        conn.closing = true;
+    }
+}
+
+// ─── Requests ────────────────────────────────────────────────────────────────
+
+/// A request sent on a connection, waiting for its reply. Dropping it
+/// forgets the request; a reply that comes later is discarded.
+pub struct ReplySlot {
+    connection: u64,
+    request: u32,
+}
+
+impl ReplySlot {
+    /// The reply, or `None` if the connection closed first.
+    pub async fn wait(&self) -> Option<Vec<u8>> {
+        poll_fn(|_| {
+            let mut pending = PENDING.lock();
+            let Some(slot) = pending.iter_mut().find(|p| p.connection == self.connection && p.request == self.request)
+            else {
+                return Poll::Ready(None);
+            };
+            if slot.failed {
+                return Poll::Ready(None);
+            }
+            match slot.reply.take() {
+                Some(reply) => Poll::Ready(Some(reply)),
+                None => Poll::Pending,
+            }
+        })
+        .await
+    }
+}
+
+impl Drop for ReplySlot {
+    fn drop(&mut self) {
+        PENDING.lock().retain(|p| !(p.connection == self.connection && p.request == self.request));
+    }
+}
+
+/// Queue `message`, request `request` of `protocol`, on the connection to
+/// `peer`. Fails with `Busy` if its queue is full, `NotConnected` if there
+/// is no connection and `Unsupported` if it didn't negotiate `protocol`.
+pub fn send_request(peer: &NodeId, protocol: Protocol, request: u32, message: Vec<u8>) -> Result<ReplySlot, RpcError> {
+    let mut connections = CONNECTIONS.lock();
+    let connection = enqueue(&mut connections, peer, protocol, &message)?;
+    PENDING.lock().push(Pending { connection, request, reply: None, failed: false });
+    Ok(ReplySlot { connection, request })
+}
+
+/// Queue `message` of `protocol`, which gets no reply, on the connection
+/// to `peer`. Fails as `send_request` does.
+pub fn send_message(peer: &NodeId, protocol: Protocol, message: &[u8]) -> Result<(), RpcError> {
+    enqueue(&mut CONNECTIONS.lock(), peer, protocol, message).map(|_| ())
+}
+
+/// Queue `message` on the connection to `peer`; returns the connection's id.
+fn enqueue(connections: &mut [Connection], peer: &NodeId, protocol: Protocol, message: &[u8]) -> Result<u64, RpcError> {
+    let conn = connections.iter_mut().find(|c| c.peer == *peer && !c.closing).ok_or(RpcError::NotConnected)?;
+    if conn.outbox.len() >= MAX_QUEUED {
+        return Err(RpcError::Busy);
+    }
+    conn.outbox.push_back(conn.protocols.tag(protocol, message).ok_or(RpcError::Unsupported)?);
+    conn.last_active = uptime_ms();
+    Ok(conn.id)
+}
+
+// ─── Running ─────────────────────────────────────────────────────────────────
+
+enum Event {
+    Frame(Result<Vec<u8>, FrameError>),
+    /// Something was queued to send.
+    Outgoing,
+    /// Evicted, or idle for too long.
+    Close,
+}
+
+/// Serve a connection until it closes, fails, idles out or is evicted.
+async fn run(mut conn: SecureConnection, handle: SocketHandle, peer: NodeId, id: u64) {
+    let signer = P2P_STATE.lock().as_ref().map(|state| (state.node_id, state.keys.identity.clone()));
+    if let Some((local_id, identity)) = signer {
+        'serve: loop {
+            while let Some(message) = next_outgoing(id) {
+                let sealed = p2p_sign::seal(&message, &peer, &identity);
+                if conn.send(&sealed).await.is_err() {
+                    break 'serve;
+                }
+                p2p_metrics::bytes_out(peer, sealed.len());
+            }
+            let event = {
+                let mut recv = pin!(conn.recv());
+                // `recv` is cancellation-safe, so leaving it to send loses
+                // nothing.
+                poll_fn(|cx| match recv.as_mut().poll(cx) {
+                    Poll::Ready(result) => Poll::Ready(Event::Frame(result)),
+                    Poll::Pending => check(id).map_or(Poll::Pending, Poll::Ready),
+                })
+                .await
+            };
+            let frame = match event {
+                Event::Frame(Ok(frame)) => frame,
+                Event::Frame(Err(_)) | Event::Close => break,
+                Event::Outgoing => continue,
+            };
+            if !receive(id, peer, conn.remote_identity(), &local_id, &frame) {
+                break;
+            }
+        }
+    }
+    closed(id, peer);
+    drop(conn);
+    tcp_close(handle);
+}
+
+/// Secure a relayed connection with `peer`, whose key is `identity`, then
+/// serve it until it closes, idles out or is evicted, or its circuit
+/// closes. Frames arrive through `receive_relayed`.
+async fn run_relayed(peer: NodeId, identity: VerifyingKey, relay: NodeId, circuit: u32, id: u64, role: Role) {
+    let keys = P2P_STATE.lock().as_ref().map(|state| (state.node_id, state.keys.clone()));
+    if let Some((local_id, keys)) = keys {
+        let mut link = CircuitLink { id, relay, circuit };
+        let deadline = Some(uptime_ms() + p2p::HANDSHAKE_TIMEOUT_MS);
+        let secured = Deadline { inner: pin!(p2p_noise::handshake_session(&mut link, role, &keys, &[])), deadline }.await;
+        match secured {
+            Some(Ok((session, remote))) if remote.identity == identity => {
+                serve_relayed(session, peer, &identity, relay, circuit, id, &local_id, &keys).await;
+            }
+            Some(Ok(_)) => {
+                serial_println!("[P2P] {:?} proved a different key over {:?}'s circuit; closing it.", peer, relay);
+                p2p_reputation::penalize(Subject::Peer(peer), Offense::BadSignature);
+            }
+            Some(Err(e)) => {
+                serial_println!("[P2P] Handshake with {:?} through {:?} failed: {:?}", peer, relay, e);
+            }
+            None => {
+                serial_println!("[P2P] Handshake with {:?} through {:?} timed out.", peer, relay);
+            }
+        }
+    }
+    p2p_relay::close(&relay, circuit);
+    closed(id, peer);
+}
+
+/// Run a secured relayed connection until it should close.
+#[allow(clippy::too_many_arguments)]
+async fn serve_relayed(
+    mut session: Session,
+    peer: NodeId,
+    identity: &VerifyingKey,
+    relay: NodeId,
+    circuit: u32,
+    id: u64,
+    local_id: &NodeId,
+    keys: &LocalKeys,
+) {
+    loop {
+        while let Some(message) = next_outgoing(id) {
+            let frame = session.encrypt(&p2p_sign::seal(&message, &peer, &keys.identity));
+            if frame.len() > p2p_relay::MAX_RELAYED_LEN {
+                serial_println!("[P2P] Dropped a {}-byte message to {:?}: too big to relay.", frame.len(), peer);
+                continue;
+            }
+            match p2p_relay::send_frame(&relay, circuit, &frame) {
+                Ok(()) => p2p_metrics::bytes_out(peer, frame.len()),
+                Err(RpcError::NotConnected) => return,
+                Err(_) => {}
+            }
+        }
+        let event = poll_fn(|_| match next_incoming(id) {
+            Some(frame) => Poll::Ready(Event::Frame(Ok(frame))),
+            None => check(id).map_or(Poll::Pending, Poll::Ready),
+        })
+        .await;
+        let frame = match event {
+            Event::Frame(Ok(frame)) => frame,
+            Event::Frame(Err(_)) | Event::Close => return,
+            Event::Outgoing => continue,
+        };
+        let Ok(sealed) = session.decrypt(&frame) else {
+            serial_println!("[P2P] A frame from {:?} through {:?} failed to decrypt; closing the connection.", peer, relay);
+            return;
+        };
+        if !receive(id, peer, identity, local_id, &sealed) {
+            return;
+        }
+    }
+}
+
+/// Circuit `circuit` of `relay` as connection `id` sees it, for the
+/// handshake: frames go out through the relay and come in through
+/// `receive_relayed`.
+struct CircuitLink {
+    id: u64,
+    relay: NodeId,
+    circuit: u32,
+}
+
+impl FrameLink for CircuitLink {
+    async fn send(&mut self, data: &[u8]) -> Result<(), FrameError> {
+        p2p_relay::send_frame(&self.relay, self.circuit, data).map_err(|_| FrameError::Closed)
+    }
+
+    async fn recv(&mut self) -> Result<Vec<u8>, FrameError> {
+        poll_fn(|_| match next_incoming(self.id) {
+            Some(frame) => Poll::Ready(Ok(frame)),
+            None => match check(self.id) {
+                Some(Event::Close) => Poll::Ready(Err(FrameError::Closed)),
+                // Messages queued meanwhile wait for the handshake.
+                _ => Poll::Pending,
+            },
+        })
+        .await
+    }
+}
+
+/// Queue a frame that came through circuit `circuit` of `relay` for the
+/// connection running through it. False if there is none. Frames past
+/// `MAX_QUEUED` are dropped, as a relay drops frames over its cap.
+pub fn receive_relayed(relay: &NodeId, circuit: u32, frame: &[u8]) -> bool {
+    let mut connections = CONNECTIONS.lock();
+    let inbox = connections.iter_mut().find_map(|c| match &mut c.link {
+        Link::Relayed { relay: r, circuit: cid, inbox } if r == relay && *cid == circuit && !c.closing => Some(inbox),
+        _ => None,
+    });
+    let Some(inbox) = inbox else { return false };
+    if inbox.len() < MAX_QUEUED {
+        inbox.push_back(frame.to_vec());
+    }
+    true
+}
+
+/// The oldest frame waiting for relayed connection `id`.
+fn next_incoming(id: u64) -> Option<Vec<u8>> {
+    match &mut CONNECTIONS.lock().iter_mut().find(|c| c.id == id)?.link {
+        Link::Relayed { inbox, .. } => inbox.pop_front(),
+        Link::Direct => None,
+    }
+}
+
+/// Close the connection through circuit `circuit` of `relay`, if any.
+pub fn close_relayed(relay: &NodeId, circuit: u32) {
+    let mut connections = CONNECTIONS.lock();
+    for conn in connections.iter_mut() {
+        if matches!(conn.link, Link::Relayed { relay: r, circuit: cid, .. } if r == *relay && cid == circuit) {
+            conn.closing = true;
+        }
+    }
+}
+
+/// Handle `frame`, which came from `peer`, whose key is `identity`, on
+/// connection `id`. False if the connection should close.
+fn receive(id: u64, peer: NodeId, identity: &VerifyingKey, local_id: &NodeId, frame: &[u8]) -> bool {
+    touch(id);
+    p2p_metrics::bytes_in(peer, frame.len());
+    if p2p_reputation::count_message(peer) {
+        return false;
+    }
+    let message = match p2p_sign::open(frame, &peer, identity, local_id) {
+        Ok(message) => message,
+        Err(e) => {
+            serial_println!("[P2P] Rejected a message from {:?}: {:?}; closing the connection.", peer, e);
+            p2p_reputation::penalize(Subject::Peer(peer), Offense::BadSignature);
+            return false;
+        }
+    };
+    let Some((protocol, message)) = p2p_protocols::untag(message) else {
+        serial_println!("[P2P] Message from {:?} under an unknown protocol; closing the connection.", peer);
+        p2p_reputation::penalize(Subject::Peer(peer), Offense::InvalidMessage);
+        return false;
+    };
+    match p2p_rpc::dispatch(protocol, message, peer, identity.to_bytes()) {
+        Incoming::Answer(reply) => queue(id, protocol, &reply),
+        Incoming::Reply(request) => deliver(id, request, message.to_vec()),
+        Incoming::Handled => {}
+        Incoming::Invalid => {
+            serial_println!("[P2P] Unexpected message from {:?}; closing the connection.", peer);
+            p2p_reputation::penalize(Subject::Peer(peer), Offense::InvalidMessage);
+            return false;
+        }
+    }
+    true
+}
+
+/// Forget connection `id` to `peer`, which has stopped. Fails its pending
+/// requests; if it was the last connection to `peer`, tells pubsub, the
+/// relay and the channels, and closes the connections relayed through
+/// `peer`.
+fn closed(id: u64, peer: NodeId) {
+    let last = {
+        let mut connections = CONNECTIONS.lock();
+        connections.retain(|c| c.id != id);
+        let last = !connections.iter().any(|c| c.peer == peer);
+        if last {
+            for conn in connections.iter_mut() {
+                if matches!(conn.link, Link::Relayed { relay, .. } if relay == peer) {
+                    conn.closing = true;
+                }
+            }
+        }
+        last
+    };
+    if last {
+        p2p_pubsub::peer_disconnected(peer);
+        p2p_relay::peer_disconnected(peer);
+        p2p_channel::peer_disconnected(peer);
+    }
+    for pending in PENDING.lock().iter_mut().filter(|p| p.connection == id) {
+        pending.failed = true;
+    }
+}
+
+fn next_outgoing(id: u64) -> Option<Vec<u8>> {
+    CONNECTIONS.lock().iter_mut().find(|c| c.id == id)?.outbox.pop_front()
+}
+
+/// What the connection should do besides waiting for a frame, if anything.
+fn check(id: u64) -> Option<Event> {
+    let connections = CONNECTIONS.lock();
+    let Some(conn) = connections.iter().find(|c| c.id == id) else { return Some(Event::Close) };
+    if conn.closing {
+        return Some(Event::Close);
+    }
+    if !conn.outbox.is_empty() {
+        return Some(Event::Outgoing);
+    }
+    let idle = uptime_ms().saturating_sub(conn.last_active);
+    if idle > config::get_or("p2p.idle_timeout_ms", DEFAULT_IDLE_TIMEOUT_MS)
+        && !PENDING.lock().iter().any(|p| p.connection == id)
+    {
+        serial_println!("[P2P] Connection to {:?} idle for {} ms; closing it.", conn.peer, idle);
+        return Some(Event::Close);
+    }
+    None
+}
+
+fn touch(id: u64) {
+    if let Some(conn) = CONNECTIONS.lock().iter_mut().find(|c| c.id == id) {
+        conn.last_active = uptime_ms();
+    }
+}
+
+fn queue(id: u64, protocol: Protocol, message: &[u8]) {
+    if let Some(conn) = CONNECTIONS.lock().iter_mut().find(|c| c.id == id) {
+        if let Some(tagged) = conn.protocols.tag(protocol, message) {
+            conn.outbox.push_back(tagged);
+        }
+    }
+}
+
+fn deliver(id: u64, request: u32, reply: Vec<u8>) {
+    if let Some(slot) = PENDING.lock().iter_mut().find(|p| p.connection == id && p.request == request) {
+        slot.reply = Some(reply);
+    }
+}
@@ -3,6 +3,7 @@ use alloc::string::String;
 use alloc::format;
 use sha2::{Sha256, Digest};
 use core::fmt;
+use smoltcp::wire::IpEndpoint;
 
 // Kademlia Configuration
 pub const K_BUCKET_SIZE: usize = 20;
@@ -61,33 +62,87 @@ impl fmt::Debug for NodeId {
 pub struct PeerInfo {
     pub node_id: NodeId,
     pub peer_id_str: String,
-    // Add socket addr later if needed
+    /// Where the peer can be dialed, as of its last handshake or mDNS
+    /// announcement.
+    pub endpoint: Option<IpEndpoint>,
+    /// Uptime (ms) the peer was last heard from.
+    pub last_seen: u64,
 }
 
 pub struct KBucket {
     pub peers: Vec<PeerInfo>,
+    /// The newest peer that found the bucket full, waiting on the ping of
+    /// the least-recently-seen one.
+    pub candidate: Option<PeerInfo>,
+    /// Uptime (ms) of the last lookup in the bucket's range or the last
+    /// peer added to it, whichever is later.
+    pub last_refreshed: u64,
+}
+
+/// What `KBucket::add` did with a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddOutcome {
+    Added,
+    /// The peer was known; it is now the most recently seen.
+    Refreshed,
+    /// The bucket is full. Ping `oldest` and pass the answer to
+    /// `resolve_ping`; the peer takes its place if it doesn't answer.
+    Full { oldest: NodeId },
+    /// The bucket is full and already pinging its oldest peer; the peer
+    /// replaced the waiting candidate.
+    Pending,
 }
 
 impl KBucket {
     pub fn new() -> Self {
         KBucket {
             peers: Vec::with_capacity(K_BUCKET_SIZE),
+            candidate: None,
+            last_refreshed: 0,
         }
     }
 
-    pub fn add(&mut self, peer: PeerInfo) -> bool {
+    pub fn add(&mut self, peer: PeerInfo) -> AddOutcome {
+        self.last_refreshed = self.last_refreshed.max(peer.last_seen);
         if let Some(idx) = self.peers.iter().position(|p| p.node_id == peer.node_id) {
             // Move to tail (most recently seen)
             self.peers.remove(idx);
             self.peers.push(peer);
-            true
+            AddOutcome::Refreshed
         } else if self.peers.len() < K_BUCKET_SIZE {
             self.peers.push(peer);
-            true
+            AddOutcome::Added
+        } else if self.candidate.replace(peer).is_some() {
+            AddOutcome::Pending
         } else {
-            // Bucket full - ideally disable/ping least recently seen.
-            // For now, minimal implementation: drop new peer
-            false
+            // Long-lived peers tend to stay up, so the oldest keeps its
+            // slot unless it fails a ping.
+            AddOutcome::Full { oldest: self.peers[0].node_id }
+        }
+    }
+
+    /// Settle the ping of `oldest` that `add` asked for: keep it if it
+    /// answered, else replace it with the candidate. Returns whether it
+    /// was evicted.
+    pub fn resolve_ping(&mut self, oldest: &NodeId, answered: bool) -> bool {
+        let candidate = self.candidate.take();
+        let Some(idx) = self.peers.iter().position(|p| p.node_id == *oldest) else {
+            // Gone already; the candidate may have the slot.
+            if let Some(candidate) = candidate.filter(|_| self.peers.len() < K_BUCKET_SIZE) {
+                self.peers.push(candidate);
+            }
+            return false;
+        };
+        let peer = self.peers.remove(idx);
+        match candidate {
+            Some(candidate) if !answered => {
+                self.peers.push(candidate);
+                true
+            }
+            _ => {
+                self.peers.push(peer);
+                false
+            }
         }
     }
 }
@@ -109,13 +164,66 @@ impl RoutingTable {
         }
     }
 
-    pub fn add_peer(&mut self, peer: PeerInfo) {
+    pub fn add_peer(&mut self, peer: PeerInfo) -> AddOutcome {
         let dist = self.local_id.distance(&peer.node_id);
         let bucket_idx = self.get_bucket_index(&dist);
-        
-        if let Some(bucket) = self.buckets.get_mut(bucket_idx) {
-            bucket.add(peer);
+        self.buckets[bucket_idx].add(peer)
+    }
+
+    pub fn peer(&self, node_id: &NodeId) -> Option<&PeerInfo> {
+        let bucket_idx = self.get_bucket_index(&self.local_id.distance(node_id));
+        self.buckets[bucket_idx].peers.iter().find(|p| p.node_id == *node_id)
+    }
+
+    /// Drop `node_id` from the table, letting a waiting candidate have its
+    /// slot. Returns whether it was there.
+    pub fn remove(&mut self, node_id: &NodeId) -> bool {
+        let bucket_idx = self.get_bucket_index(&self.local_id.distance(node_id));
+        let bucket = &mut self.buckets[bucket_idx];
+        let Some(idx) = bucket.peers.iter().position(|p| p.node_id == *node_id) else { return false };
+        bucket.peers.remove(idx);
+        if let Some(candidate) = bucket.candidate.take() {
+            bucket.peers.push(candidate);
         }
+        true
+    }
+
+    /// Note that `node_id` was heard from at `now`.
+    pub fn touch(&mut self, node_id: &NodeId, now: u64) {
+        let bucket_idx = self.get_bucket_index(&self.local_id.distance(node_id));
+        let bucket = &mut self.buckets[bucket_idx];
+        if let Some(peer) = bucket.peers.iter_mut().find(|p| p.node_id == *node_id) {
+            peer.last_seen = peer.last_seen.max(now);
+        }
+    }
+
+    /// Buckets not refreshed since `before`, up to the deepest one holding
+    /// a peer; deeper ones are empty because no such peers exist.
+    pub fn stale_buckets(&self, before: u64) -> Vec<usize> {
+        let Some(deepest) = self.buckets.iter().rposition(|b| !b.peers.is_empty()) else { return Vec::new() };
+        (0..=deepest).filter(|&idx| self.buckets[idx].last_refreshed < before).collect()
+    }
+
+    /// A node ID that falls in bucket `idx`: the local ID with bit `idx`
+    /// flipped and the bits after it taken from `random`.
+    pub fn id_in_bucket(&self, idx: usize, random: [u8; ID_SIZE]) -> NodeId {
+        let mut distance = random;
+        for bit in 0..=idx.min(ID_SIZE * 8 - 1) {
+            let mask = 0x80 >> (bit % 8);
+            if bit == idx {
+                distance[bit / 8] |= mask;
+            } else {
+                distance[bit / 8] &= !mask;
+            }
+        }
+        self.local_id.distance(&NodeId(distance))
+    }
+
+    /// Settle a ping asked for by `AddOutcome::Full`; see
+    /// `KBucket::resolve_ping`.
+    pub fn resolve_ping(&mut self, oldest: &NodeId, answered: bool) -> bool {
+        let bucket_idx = self.get_bucket_index(&self.local_id.distance(oldest));
+        self.buckets[bucket_idx].resolve_ping(oldest, answered)
     }
     
     fn get_bucket_index(&self, distance: &NodeId) -> usize {
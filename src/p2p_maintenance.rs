@@ -0,0 +1,176 @@
+//! # Routing Table Maintenance
+//!
+//! A task that keeps the routing table and this node's records useful over
+//! a long uptime. Every `ROUND_MS` it:
+//!
+//! 1. Marks peers with an open connection (see `p2p_conn`) as seen.
+//! 2. Pings each peer not heard from in `p2p.peer_timeout_ms` and drops
+//!    it from the routing table if it doesn't answer.
+//! 3. Refreshes each bucket no lookup or new peer has touched in
+//!    `p2p.refresh_interval_ms`, by looking up a random ID in its range.
+//!    The lookup meets the peers in that range, which land in the bucket.
+//! 4. Saves the routing table's peers (see `p2p::save_peers`).
+//! 5. Drops the records and provider entries whose TTL has run out.
+//! 6. Every `p2p.republish_interval_ms`, sends the records this node
+//!    holds, its own and replicas alike, to the nodes now closest to their
+//!    keys, with the TTL they have left, so they outlive the peers they
+//!    were first stored on. Records a STORE brought in since the last
+//!    round are skipped (see `p2p_store`).
+//!
+//! Besides the rounds, `hand_over` gives a peer that just joined the
+//! routing table the records it is now among the closest nodes for.
+
+use alloc::vec::Vec;
+use crate::executor::{self, Task};
+use crate::interrupts::uptime_ms;
+use crate::p2p::{self, P2P_STATE};
+use crate::p2p_conn;
+use crate::addr_book::ADDRESS_BOOK;
+use crate::p2p_kademlia::{NodeId, ID_SIZE, K_BUCKET_SIZE};
+use crate::p2p_rpc::{self, Contact, RpcError};
+use crate::p2p_store;
+use crate::{config, serial_println};
+
+/// Time between maintenance rounds.
+const ROUND_MS: u64 = 60_000;
+/// Default of `p2p.peer_timeout_ms`.
+const DEFAULT_PEER_TIMEOUT_MS: u64 = 15 * 60 * 1000;
+/// Default of `p2p.refresh_interval_ms`.
+const DEFAULT_REFRESH_INTERVAL_MS: u64 = 60 * 60 * 1000;
+/// Default of `p2p.republish_interval_ms`.
+const DEFAULT_REPUBLISH_INTERVAL_MS: u64 = 60 * 60 * 1000;
+
+/// Register the `p2p.peer_timeout_ms`, `p2p.refresh_interval_ms` and
+/// `p2p.republish_interval_ms` keys.
+pub fn register_tunables() {
+    config::register("p2p.peer_timeout_ms", "Ping a routing-table peer not heard from for this long", DEFAULT_PEER_TIMEOUT_MS, 60_000, 24 * 3_600_000, None);
+    config::register("p2p.refresh_interval_ms", "Refresh a k-bucket no lookup has touched for this long", DEFAULT_REFRESH_INTERVAL_MS, 60_000, 24 * 3_600_000, None);
+    config::register("p2p.republish_interval_ms", "Time between replicating the DHT records this node holds", DEFAULT_REPUBLISH_INTERVAL_MS, 60_000, 24 * 3_600_000, None);
+}
+
+/// Start the maintenance task.
+pub fn start() {
+    executor::submit(Task::new(maintenance_task()));
+}
+
+async fn maintenance_task() {
+    let mut last_republish = uptime_ms();
+    loop {
+        executor::sleep_ms(ROUND_MS).await;
+        expire_peers().await;
+        refresh_buckets().await;
+        p2p::save_peers();
+        let now = uptime_ms();
+        let (records, providers) = p2p_store::expire_all(now);
+        if records + providers > 0 {
+            serial_println!("[P2P] Expired {} records and {} provider entries.", records, providers);
+        }
+        let interval = config::get_or("p2p.republish_interval_ms", DEFAULT_REPUBLISH_INTERVAL_MS);
+        if now - last_republish >= interval {
+            last_republish = now;
+            replicate(now - interval).await;
+        }
+    }
+}
+
+/// Ping the peers not heard from lately and drop those that don't answer.
+async fn expire_peers() {
+    let now = uptime_ms();
+    let connected: Vec<_> = p2p_conn::connections().iter().map(|conn| conn.peer).collect();
+    let timeout = config::get_or("p2p.peer_timeout_ms", DEFAULT_PEER_TIMEOUT_MS);
+    let quiet: Vec<Contact> = {
+        let mut state = P2P_STATE.lock();
+        let Some(state) = state.as_mut() else { return };
+        for peer in &connected {
+            state.routing_table.touch(peer, now);
+        }
+        state
+            .routing_table
+            .buckets
+            .iter()
+            .flat_map(|bucket| bucket.peers.iter())
+            .filter(|peer| now.saturating_sub(peer.last_seen) > timeout)
+            .filter_map(|peer| Some(Contact { node_id: peer.node_id, endpoint: peer.endpoint? }))
+            .collect()
+    };
+    for contact in quiet {
+        // Answering means a handshake, which marks the peer seen again.
+        if p2p_rpc::ping(contact).await.is_err() {
+            let removed = P2P_STATE.lock().as_mut().is_some_and(|state| state.routing_table.remove(&contact.node_id));
+            if removed {
+                serial_println!("[P2P] Dropped {:?}, which stopped answering.", contact.node_id);
+            }
+        }
+    }
+}
+
+/// Look up a random ID in each stale bucket's range.
+async fn refresh_buckets() {
+    let before = uptime_ms().saturating_sub(config::get_or("p2p.refresh_interval_ms", DEFAULT_REFRESH_INTERVAL_MS));
+    let stale = match P2P_STATE.lock().as_ref() {
+        Some(state) => state.routing_table.stale_buckets(before),
+        None => return,
+    };
+    for idx in stale {
+        let mut random = [0u8; ID_SIZE];
+        crate::random::fill(&mut random);
+        let Some(target) = P2P_STATE.lock().as_ref().map(|state| state.routing_table.id_in_bucket(idx, random)) else {
+            return;
+        };
+        let found = p2p_rpc::lookup(target).await;
+        if let Some(state) = P2P_STATE.lock().as_mut() {
+            state.routing_table.buckets[idx].last_refreshed = uptime_ms();
+        }
+        serial_println!("[P2P] Refreshed bucket {}: {} peers found.", idx, found.len());
+    }
+}
+
+/// Send the records held here, and not stored since `before`, to the
+/// nodes now closest to their keys.
+async fn replicate(before: u64) {
+    for (key, value, ttl_ms, provenance) in p2p_store::due_for_replication(before, uptime_ms()) {
+        let stored = p2p_rpc::replicate(key, &value, provenance, ttl_ms).await;
+        serial_println!("[P2P] Replicated {:?} to {} peers.", key, stored);
+    }
+}
+
+/// Store on `peer`, which just joined the routing table, each record held
+/// here that both it and this node are among the `K_BUCKET_SIZE` closest
+/// to. The other closest nodes have the record already, or get it from
+/// their own hand-over.
+pub async fn hand_over(peer: NodeId) {
+    let now = uptime_ms();
+    let known = P2P_STATE.lock().as_ref().and_then(|state| state.routing_table.peer(&peer)?.endpoint);
+    let Some(endpoint) = ADDRESS_BOOK.lock().peer_endpoint(&peer, now).or(known) else { return };
+    let contact = Contact { node_id: peer, endpoint };
+    for (key, value, ttl_ms, provenance) in p2p_store::held(now) {
+        if !among_closest(key, peer) {
+            continue;
+        }
+        match p2p_rpc::store(contact, key, &value, provenance, ttl_ms).await {
+            Ok(()) => { serial_println!("[P2P] Handed {:?} over to {:?}.", key, peer); }
+            // A refusal is about this record (size, quota, signature), not
+            // the peer, so the rest may still be welcome.
+            Err(RpcError::Refused(reason)) => {
+                serial_println!("[P2P] {:?} refused {:?}: {:?}", peer, key, reason);
+            }
+            Err(e) => {
+                serial_println!("[P2P] Handing {:?} over to {:?} failed: {:?}", key, peer, e);
+                return;
+            }
+        }
+    }
+}
+
+/// Whether `peer` and this node are both among the `K_BUCKET_SIZE` nodes
+/// closest to `key` that this node knows.
+fn among_closest(key: NodeId, peer: NodeId) -> bool {
+    let state = P2P_STATE.lock();
+    let Some(state) = state.as_ref() else { return false };
+    let mut closest: Vec<NodeId> =
+        state.routing_table.find_closest(&key, K_BUCKET_SIZE).iter().map(|p| p.node_id).collect();
+    closest.push(state.node_id);
+    closest.sort_by_key(|id| id.distance(&key));
+    closest.truncate(K_BUCKET_SIZE);
+    closest.contains(&peer) && closest.contains(&state.node_id)
+}
@@ -0,0 +1,140 @@
+//! # P2P Metrics
+//!
+//! Counters the P2P stack keeps since boot, for `/proc/p2p` and the
+//! `p2pstat` shell command:
+//!
+//! - Dials: TCP connections opened to peers, hole punches included, and
+//!   how many of them finished their handshake.
+//! - Handshakes: secure handshakes in either direction, and how many
+//!   failed.
+//! - RPCs: Kademlia, block and identify messages sent and received, by
+//!   type (see `p2p_rpc`).
+//! - Traffic: bytes of sealed messages sent to and received from each
+//!   peer, over direct and relayed connections alike. Up to `MAX_PEERS`
+//!   peers are tracked; past that, the one with the least traffic is
+//!   forgotten.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::p2p_kademlia::NodeId;
+
+/// Peers whose traffic is tracked at once.
+const MAX_PEERS: usize = 128;
+
+static DIALS: AtomicU64 = AtomicU64::new(0);
+static DIALS_SUCCEEDED: AtomicU64 = AtomicU64::new(0);
+static HANDSHAKES: AtomicU64 = AtomicU64::new(0);
+static HANDSHAKES_FAILED: AtomicU64 = AtomicU64::new(0);
+
+/// Messages of one RPC type.
+#[derive(Debug, Clone, Copy)]
+pub struct RpcCount {
+    pub name: &'static str,
+    pub sent: u64,
+    pub received: u64,
+}
+
+/// Bytes exchanged with one peer.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerTraffic {
+    pub peer: NodeId,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+/// The counters at one moment, as `snapshot` returns them.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    pub dials: u64,
+    pub dials_succeeded: u64,
+    pub handshakes: u64,
+    pub handshakes_failed: u64,
+    /// In the order the types were first seen.
+    pub rpcs: Vec<RpcCount>,
+    /// Most traffic first.
+    pub peers: Vec<PeerTraffic>,
+}
+
+lazy_static! {
+    static ref RPCS: Mutex<Vec<RpcCount>> = Mutex::new(Vec::new());
+    static ref TRAFFIC: Mutex<Vec<PeerTraffic>> = Mutex::new(Vec::new());
+}
+
+pub fn dial_attempted() {
+    DIALS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// A dial finished its handshake.
+pub fn dial_succeeded() {
+    DIALS_SUCCEEDED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn handshake(succeeded: bool) {
+    HANDSHAKES.fetch_add(1, Ordering::Relaxed);
+    if !succeeded {
+        HANDSHAKES_FAILED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn rpc_sent(name: &'static str) {
+    rpc(name, |count| count.sent += 1);
+}
+
+pub fn rpc_received(name: &'static str) {
+    rpc(name, |count| count.received += 1);
+}
+
+pub fn bytes_in(peer: NodeId, bytes: usize) {
+    traffic(peer, |t| t.bytes_in += bytes as u64);
+}
+
+pub fn bytes_out(peer: NodeId, bytes: usize) {
+    traffic(peer, |t| t.bytes_out += bytes as u64);
+}
+
+pub fn snapshot() -> Metrics {
+    let mut peers = TRAFFIC.lock().clone();
+    peers.sort_by_key(|t| core::cmp::Reverse(t.bytes_in + t.bytes_out));
+    Metrics {
+        dials: DIALS.load(Ordering::Relaxed),
+        dials_succeeded: DIALS_SUCCEEDED.load(Ordering::Relaxed),
+        handshakes: HANDSHAKES.load(Ordering::Relaxed),
+        handshakes_failed: HANDSHAKES_FAILED.load(Ordering::Relaxed),
+        rpcs: RPCS.lock().clone(),
+        peers,
+    }
+}
+
+/// Apply `update` to the count for RPC type `name`, added if it is new.
+fn rpc(name: &'static str, update: impl FnOnce(&mut RpcCount)) {
+    let mut rpcs = RPCS.lock();
+    let idx = match rpcs.iter().position(|count| count.name == name) {
+        Some(idx) => idx,
+        None => {
+            rpcs.push(RpcCount { name, sent: 0, received: 0 });
+            rpcs.len() - 1
+        }
+    };
+    update(&mut rpcs[idx]);
+}
+
+/// Apply `update` to `peer`'s traffic, tracking it from now if it wasn't.
+fn traffic(peer: NodeId, update: impl FnOnce(&mut PeerTraffic)) {
+    let mut traffic = TRAFFIC.lock();
+    let idx = match traffic.iter().position(|t| t.peer == peer) {
+        Some(idx) => idx,
+        None => {
+            if traffic.len() >= MAX_PEERS {
+                let quietest = traffic.iter().enumerate().min_by_key(|(_, t)| t.bytes_in + t.bytes_out);
+                if let Some((idx, _)) = quietest {
+                    traffic.swap_remove(idx);
+                }
+            }
+            traffic.push(PeerTraffic { peer, bytes_in: 0, bytes_out: 0 });
+            traffic.len() - 1
+        }
+    };
+    update(&mut traffic[idx]);
+}
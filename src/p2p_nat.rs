@@ -0,0 +1,284 @@
+//! # NAT Traversal
+//!
+//! Helps nodes behind NAT find out where they can be reached and connect
+//! to each other.
+//!
+//! ## Observed addresses
+//! After dialing a peer, this node asks it with IDENTIFY (see `p2p_rpc`)
+//! where it sees this node from. Once `CONFIRMATIONS` peers report the
+//! same endpoint it is taken as this node's external address, and the
+//! handshake advertises it. An external address other than the
+//! interface's own means the node is behind NAT.
+//!
+//! ## Hole punching
+//! Two nodes behind NAT can't dial each other: each NAT drops the other's
+//! SYN, having no mapping for it. A peer both are connected to relays the
+//! introduction, so both dial at about the same time, each from its P2P
+//! port: each dial opens a mapping in its own NAT that lets the other's
+//! SYN in, and it is answered by the P2P listener. The exchange is its
+//! own protocol (see `p2p_protocols`); each message is `[kind: u8][0: u32
+//! LE][body]`:
+//!
+//! | kind | message     | body                                          |
+//! |------|-------------|-----------------------------------------------|
+//! | 0x20 | CONNECT     | target `NodeId` (32)                          |
+//! | 0x21 | INTRO       | peer `NodeId` (32), IPv4 (4), port (u16 LE)   |
+//! | 0x22 | UNREACHABLE | target `NodeId` (32)                          |
+//!
+//! A node that wants the target sends CONNECT to a relay. If the relay is
+//! connected to the target and knows both endpoints, it sends each side an
+//! INTRO naming the other and where the relay sees it from; otherwise it
+//! answers UNREACHABLE. A node that gets an INTRO dials the peer in it, at
+//! most `MAX_PUNCHES` at a time.
+//!
+//! `p2p_conn::connect` falls back to a hole punch when a dial times out,
+//! unless `p2p.nat.hole_punching` is 0, trying up to `MAX_RELAYS` of the
+//! connected peers as relays.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
+use spin::Mutex;
+use crate::addr_book::ADDRESS_BOOK;
+use crate::executor::{self, Task};
+use crate::interrupts::uptime_ms;
+use crate::p2p_conn;
+use crate::p2p_kademlia::{NodeId, ID_SIZE};
+use crate::p2p_protocols::Protocol;
+use crate::p2p_rpc::{self, Contact, RpcError};
+use crate::{config, serial_println};
+
+/// Peers that must report the same endpoint before it counts as this
+/// node's external address.
+pub const CONFIRMATIONS: usize = 2;
+/// Reports remembered, one per peer; the oldest goes first.
+const MAX_OBSERVATIONS: usize = 16;
+/// Hole punches running at once on behalf of INTROs.
+const MAX_PUNCHES: usize = 4;
+/// Connected peers tried as relays for one hole punch.
+const MAX_RELAYS: usize = 3;
+/// Longest a relay may take to introduce, and a punch to connect.
+const PUNCH_TIMEOUT_MS: u64 = 5_000;
+/// Wait between checks for a punched connection.
+const PUNCH_POLL_MS: u64 = 100;
+
+const KIND_CONNECT: u8 = 0x20;
+const KIND_INTRO: u8 = 0x21;
+const KIND_UNREACHABLE: u8 = 0x22;
+const HEADER_LEN: usize = 5;
+
+/// Whether this node can be dialed from outside, as far as it knows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reachability {
+    /// Too few peers have reported an address.
+    Unknown,
+    /// Peers see the interface's own address.
+    Public,
+    /// Peers see another address: this node is behind NAT.
+    BehindNat,
+}
+
+/// A peer's report of where it sees this node from.
+#[derive(Debug, Clone, Copy)]
+pub struct Observation {
+    pub reporter: NodeId,
+    pub endpoint: IpEndpoint,
+    pub at: u64,
+}
+
+/// A CONNECT sent to a relay, waiting for its answer.
+struct Attempt {
+    target: NodeId,
+    relay: NodeId,
+    /// Set by UNREACHABLE.
+    unreachable: bool,
+}
+
+lazy_static! {
+    static ref OBSERVATIONS: Mutex<Vec<Observation>> = Mutex::new(Vec::new());
+    static ref ATTEMPTS: Mutex<Vec<Attempt>> = Mutex::new(Vec::new());
+}
+
+/// Hole punches running for INTROs.
+static PUNCHES: AtomicUsize = AtomicUsize::new(0);
+
+/// Register the `p2p.nat.hole_punching` key.
+pub fn register_tunables() {
+    config::register("p2p.nat.hole_punching", "Retry timed-out P2P dials as hole punches through a relay (0 = off)", 1, 0, 1, None);
+}
+
+/// Whether timed-out dials are retried as hole punches.
+pub fn hole_punching() -> bool {
+    config::get_or("p2p.nat.hole_punching", 1) != 0
+}
+
+// ─── Observed Addresses ──────────────────────────────────────────────────────
+
+/// Ask `peer` where it sees this node from, and remember the answer.
+pub async fn learn_address(peer: NodeId) {
+    let endpoint = match p2p_rpc::identify(peer).await {
+        Ok(identity) => identity.observed,
+        Err(e) => {
+            serial_println!("[P2P] IDENTIFY to {:?} failed: {:?}", peer, e);
+            return;
+        }
+    };
+    let Some(endpoint) = endpoint else { return };
+    let mut observations = OBSERVATIONS.lock();
+    observations.retain(|o| o.reporter != peer);
+    if observations.len() >= MAX_OBSERVATIONS {
+        observations.remove(0);
+    }
+    observations.push(Observation { reporter: peer, endpoint, at: uptime_ms() });
+}
+
+/// What peers have reported, oldest first.
+pub fn observations() -> Vec<Observation> {
+    OBSERVATIONS.lock().clone()
+}
+
+/// The endpoint most peers report, if at least `CONFIRMATIONS` do.
+pub fn external_address() -> Option<IpEndpoint> {
+    let observations = OBSERVATIONS.lock();
+    observations
+        .iter()
+        .map(|o| (o.endpoint, observations.iter().filter(|other| other.endpoint == o.endpoint).count()))
+        .filter(|(_, reports)| *reports >= CONFIRMATIONS)
+        .max_by_key(|(_, reports)| *reports)
+        .map(|(endpoint, _)| endpoint)
+}
+
+pub fn reachability() -> Reachability {
+    let Some(external) = external_address() else { return Reachability::Unknown };
+    let local = crate::net_stack::address_config().and_then(|config| config.address);
+    if local.is_some_and(|cidr| IpAddress::Ipv4(cidr.address()) == external.addr) {
+        Reachability::Public
+    } else {
+        Reachability::BehindNat
+    }
+}
+
+// ─── Hole Punching ───────────────────────────────────────────────────────────
+
+/// Connect to `contact` by a hole punch, with each connected peer in turn
+/// as the relay, up to `MAX_RELAYS`.
+pub async fn connect_via_relay(contact: Contact) -> Result<(), RpcError> {
+    let relays: Vec<NodeId> = p2p_conn::connections()
+        .iter()
+        .filter(|conn| conn.peer != contact.node_id && conn.protocols.contains(&Protocol::HolePunch))
+        .map(|conn| conn.peer)
+        .take(MAX_RELAYS)
+        .collect();
+    let mut result = Err(RpcError::NotConnected);
+    for relay in relays {
+        result = connect_via(contact.node_id, relay).await;
+        if result.is_ok() {
+            break;
+        }
+    }
+    result
+}
+
+/// Ask `relay` to introduce this node and `target`, and wait for the
+/// connection the punch opens.
+pub async fn connect_via(target: NodeId, relay: NodeId) -> Result<(), RpcError> {
+    let mut request = header(KIND_CONNECT, ID_SIZE);
+    request.extend_from_slice(&target.0);
+    ATTEMPTS.lock().push(Attempt { target, relay, unreachable: false });
+    let sent = p2p_conn::send_message(&relay, Protocol::HolePunch, &request);
+    let deadline = uptime_ms() + PUNCH_TIMEOUT_MS * 2;
+    let result = match sent {
+        Ok(()) => loop {
+            if p2p_conn::is_connected(&target) {
+                break Ok(());
+            }
+            let unreachable = ATTEMPTS.lock().iter().any(|a| a.target == target && a.relay == relay && a.unreachable);
+            if unreachable {
+                break Err(RpcError::NotConnected);
+            }
+            if uptime_ms() >= deadline {
+                break Err(RpcError::Timeout);
+            }
+            executor::sleep_ms(PUNCH_POLL_MS).await;
+        },
+        Err(e) => Err(e),
+    };
+    ATTEMPTS.lock().retain(|a| !(a.target == target && a.relay == relay));
+    result
+}
+
+/// Handle a hole-punching `message` from `peer`. False if it was
+/// malformed, and the connection should close.
+pub(crate) fn handle(message: &[u8], peer: NodeId) -> bool {
+    let Some(body) = message.get(HEADER_LEN..) else { return false };
+    let Some(node_id) = body.first_chunk::<ID_SIZE>().map(|id| NodeId::new(*id)) else { return false };
+    match message[0] {
+        KIND_CONNECT => relay(peer, node_id),
+        KIND_UNREACHABLE => {
+            for attempt in ATTEMPTS.lock().iter_mut().filter(|a| a.target == node_id && a.relay == peer) {
+                attempt.unreachable = true;
+            }
+        }
+        KIND_INTRO => {
+            let Some(endpoint) = body[ID_SIZE..].first_chunk::<6>() else { return false };
+            let port = u16::from_le_bytes([endpoint[4], endpoint[5]]);
+            let endpoint = IpEndpoint::new(Ipv4Address::from_bytes(&endpoint[..4]).into(), port);
+            introduced(Contact { node_id, endpoint }, peer);
+        }
+        _ => return false,
+    }
+    true
+}
+
+/// Introduce `from` and `target` to each other, if both can be.
+fn relay(from: NodeId, target: NodeId) {
+    let now = uptime_ms();
+    let endpoints = {
+        let book = ADDRESS_BOOK.lock();
+        book.peer_endpoint(&from, now).zip(book.peer_endpoint(&target, now))
+    };
+    if let Some((from_endpoint, target_endpoint)) = endpoints.filter(|_| p2p_conn::is_connected(&target)) {
+        let sent = p2p_conn::send_message(&target, Protocol::HolePunch, &intro(from, from_endpoint));
+        if sent.is_ok() {
+            serial_println!("[P2P] Introducing {:?} and {:?} for a hole punch.", from, target);
+            let _ = p2p_conn::send_message(&from, Protocol::HolePunch, &intro(target, target_endpoint));
+            return;
+        }
+    }
+    let mut reply = header(KIND_UNREACHABLE, ID_SIZE);
+    reply.extend_from_slice(&target.0);
+    let _ = p2p_conn::send_message(&from, Protocol::HolePunch, &reply);
+}
+
+/// `relay` introduced `contact`: dial it from the P2P port.
+fn introduced(contact: Contact, relay: NodeId) {
+    let asked = ATTEMPTS.lock().iter().any(|a| a.target == contact.node_id && a.relay == relay);
+    if !asked && PUNCHES.fetch_add(1, Ordering::Relaxed) >= MAX_PUNCHES {
+        PUNCHES.fetch_sub(1, Ordering::Relaxed);
+        return;
+    }
+    executor::submit(Task::new(async move {
+        let result = p2p_conn::punch(contact).await;
+        serial_println!("[P2P] Hole punch to {:?} at {}: {:?}", contact.node_id, contact.endpoint, result);
+        if !asked {
+            PUNCHES.fetch_sub(1, Ordering::Relaxed);
+        }
+    }));
+}
+
+fn intro(peer: NodeId, endpoint: IpEndpoint) -> Vec<u8> {
+    let IpAddress::Ipv4(addr) = endpoint.addr;
+    let mut frame = header(KIND_INTRO, ID_SIZE + 6);
+    frame.extend_from_slice(&peer.0);
+    frame.extend_from_slice(addr.as_bytes());
+    frame.extend_from_slice(&endpoint.port.to_le_bytes());
+    frame
+}
+
+fn header(kind: u8, body_len: usize) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + body_len);
+    frame.push(kind);
+    frame.extend_from_slice(&0u32.to_le_bytes());
+    frame
+}
@@ -0,0 +1,463 @@
+//! # P2P Secure Channel
+//!
+//! Every P2P connection runs `Noise_XX_25519_ChaChaPoly_SHA256` over the
+//! framed transport before anything else, so peers are authenticated and
+//! what they say to each other is confidential.
+//!
+//! ## Handshake
+//! One Noise message per frame:
+//! ```text
+//! -> e
+//! <- e, ee, s, es, payload
+//! -> s, se, payload
+//! ```
+//! `s` is an X25519 key made fresh at boot. The node's long-term identity
+//! is its Ed25519 key, so each payload is a protobuf message (see
+//! `p2p_proto`) carrying that public key and its signature over
+//! `STATIC_KEY_CONTEXT` followed by the static key:
+//! ```text
+//! message HandshakePayload {
+//!   bytes identity_key = 1;  // Ed25519, 32 bytes
+//!   bytes identity_sig = 2;  // 64 bytes
+//!   bytes data         = 3;
+//! }
+//! ```
+//! A peer that can't sign for the key it negotiated with fails the
+//! handshake. `data` belongs to the caller (`p2p` sends its identity
+//! there), arriving encrypted and bound to the handshake.
+//!
+//! ## Transport
+//! After the handshake each frame is one ChaCha20-Poly1305 message with an
+//! incrementing nonce, at most `MAX_MESSAGE_LEN` bytes like any Noise
+//! message. A frame that fails to decrypt means the stream can't be
+//! trusted any more, and `recv` reports it as `FrameError::Corrupt`.
+//!
+//! ## Relay circuits
+//! The handshake runs over any `FrameLink`, not just TCP: the two ends of
+//! a relay circuit (see `p2p_relay`) run it through the relay with
+//! `handshake_session`. A relay drops frames over its rate cap, so a
+//! `Session` sends each frame's nonce in front of it,
+//! `[nonce: u64 LE][ciphertext][tag]`, and accepts any nonce above the
+//! highest it has accepted: a lost frame doesn't desynchronize the ends,
+//! and a replayed one is refused.
+
+use alloc::vec::Vec;
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, Tag};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+use crate::p2p_proto::{self, Writer};
+use crate::p2p_transport::{FrameError, FramedConnection};
+use crate::serial_println;
+
+const PROTOCOL_NAME: &[u8; 32] = b"Noise_XX_25519_ChaChaPoly_SHA256";
+/// Mixed into the handshake hash, so only peers speaking this version
+/// agree on the keys.
+const PROLOGUE: &[u8] = b"kernel-p2p/2";
+/// What an identity key signs, followed by the static key it vouches for.
+const STATIC_KEY_CONTEXT: &[u8] = b"noise-p2p-static-key:";
+/// Longest Noise message, ciphertext and tag included.
+pub const MAX_MESSAGE_LEN: usize = 65535;
+const DH_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+const NONCE_LEN: usize = 8;
+
+/// Which side of the handshake this node is: the one that dialed
+/// initiates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Initiator,
+    Responder,
+}
+
+/// Why a handshake failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoiseError {
+    Frame(FrameError),
+    /// A message was too short or its keys were unusable.
+    Malformed,
+    /// A message failed to decrypt.
+    Decrypt,
+    /// The peer's identity key didn't sign its static key.
+    BadSignature,
+}
+
+impl From<FrameError> for NoiseError {
+    fn from(e: FrameError) -> Self {
+        NoiseError::Frame(e)
+    }
+}
+
+/// This node's keys for the handshake.
+#[derive(Clone)]
+pub struct LocalKeys {
+    pub identity: SigningKey,
+    static_key: StaticSecret,
+    /// `identity`'s signature over the static key, made once at boot.
+    static_signature: Signature,
+}
+
+impl LocalKeys {
+    /// Make a static key and sign it with `identity`.
+    pub fn new(identity: SigningKey) -> Self {
+        let mut secret = [0; DH_LEN];
+        crate::random::fill(&mut secret);
+        let static_key = StaticSecret::from(secret);
+        let static_signature = identity.sign(&signed_static_key(&PublicKey::from(&static_key)));
+        LocalKeys { identity, static_key, static_signature }
+    }
+}
+
+/// What the handshake learned about the peer.
+pub struct RemotePeer {
+    /// The Ed25519 key that signed the peer's static key.
+    pub identity: VerifyingKey,
+    /// The caller's part of the peer's payload.
+    pub payload: Vec<u8>,
+}
+
+fn signed_static_key(static_key: &PublicKey) -> Vec<u8> {
+    let mut message = Vec::with_capacity(STATIC_KEY_CONTEXT.len() + DH_LEN);
+    message.extend_from_slice(STATIC_KEY_CONTEXT);
+    message.extend_from_slice(static_key.as_bytes());
+    message
+}
+
+// ─── Noise State ─────────────────────────────────────────────────────────────
+
+struct CipherState {
+    cipher: ChaCha20Poly1305,
+    nonce: u64,
+}
+
+impl CipherState {
+    fn new(key: &[u8; 32]) -> Self {
+        CipherState { cipher: ChaCha20Poly1305::new(Key::from_slice(key)), nonce: 0 }
+    }
+
+    /// Use `nonce` for the next message; only `Session` skips ahead.
+    fn set_nonce(&mut self, nonce: u64) {
+        self.nonce = nonce;
+    }
+
+    fn next_nonce(&mut self) -> [u8; 12] {
+        let mut nonce = [0; 12];
+        nonce[4..].copy_from_slice(&self.nonce.to_le_bytes());
+        self.nonce += 1;
+        nonce
+    }
+
+    /// Append `plaintext`, encrypted, and its tag to `out`.
+    fn encrypt(&mut self, ad: &[u8], plaintext: &[u8], out: &mut Vec<u8>) {
+        let start = out.len();
+        out.extend_from_slice(plaintext);
+        let nonce = self.next_nonce();
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(Nonce::from_slice(&nonce), ad, &mut out[start..])
+            .expect("Noise messages are far below the AEAD limit");
+        out.extend_from_slice(&tag);
+    }
+
+    fn decrypt(&mut self, ad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, NoiseError> {
+        let split = ciphertext.len().checked_sub(TAG_LEN).ok_or(NoiseError::Malformed)?;
+        let (body, tag) = ciphertext.split_at(split);
+        let mut plaintext = body.to_vec();
+        let nonce = self.next_nonce();
+        self.cipher
+            .decrypt_in_place_detached(Nonce::from_slice(&nonce), ad, &mut plaintext, Tag::from_slice(tag))
+            .map_err(|_| NoiseError::Decrypt)?;
+        Ok(plaintext)
+    }
+}
+
+struct SymmetricState {
+    ck: [u8; 32],
+    h: [u8; 32],
+    cipher: Option<CipherState>,
+}
+
+impl SymmetricState {
+    fn new() -> Self {
+        // The protocol name is exactly one hash long, so it is used as is.
+        let mut state = SymmetricState { ck: *PROTOCOL_NAME, h: *PROTOCOL_NAME, cipher: None };
+        state.mix_hash(PROLOGUE);
+        state
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        self.h = Sha256::new().chain_update(self.h).chain_update(data).finalize().into();
+    }
+
+    fn mix_key(&mut self, input: &[u8; 32]) {
+        let (ck, key) = hkdf(&self.ck, input);
+        self.ck = ck;
+        self.cipher = Some(CipherState::new(&key));
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8], out: &mut Vec<u8>) {
+        let start = out.len();
+        match &mut self.cipher {
+            Some(cipher) => cipher.encrypt(&self.h, plaintext, out),
+            None => out.extend_from_slice(plaintext),
+        }
+        self.mix_hash(&out[start..]);
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, NoiseError> {
+        let plaintext = match &mut self.cipher {
+            Some(cipher) => cipher.decrypt(&self.h, ciphertext)?,
+            None => ciphertext.to_vec(),
+        };
+        self.mix_hash(ciphertext);
+        Ok(plaintext)
+    }
+
+    /// The initiator's sending and receiving ciphers.
+    fn split(&self) -> (CipherState, CipherState) {
+        let (first, second) = hkdf(&self.ck, &[]);
+        (CipherState::new(&first), CipherState::new(&second))
+    }
+}
+
+/// Noise's two-output HKDF, which is RFC 5869 HKDF with the chaining key
+/// as salt and no info.
+fn hkdf(ck: &[u8; 32], input: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let mut okm = [0; 64];
+    Hkdf::<Sha256>::new(Some(ck), input).expand(&[], &mut okm).expect("64 bytes is a valid HKDF-SHA256 length");
+    let (first, second) = okm.split_at(32);
+    (first.try_into().unwrap(), second.try_into().unwrap())
+}
+
+fn dh(secret: &StaticSecret, public: &PublicKey) -> Result<[u8; 32], NoiseError> {
+    let shared = secret.diffie_hellman(public);
+    // A low-order public key would make the secret predictable.
+    if !shared.was_contributory() {
+        return Err(NoiseError::Malformed);
+    }
+    Ok(shared.to_bytes())
+}
+
+fn public_key(bytes: &[u8]) -> Result<PublicKey, NoiseError> {
+    let bytes: [u8; DH_LEN] = bytes.try_into().map_err(|_| NoiseError::Malformed)?;
+    Ok(PublicKey::from(bytes))
+}
+
+// ─── Handshake ───────────────────────────────────────────────────────────────
+
+/// Carries the handshake's messages, one per frame.
+pub trait FrameLink {
+    async fn send(&mut self, data: &[u8]) -> Result<(), FrameError>;
+    async fn recv(&mut self) -> Result<Vec<u8>, FrameError>;
+}
+
+impl FrameLink for FramedConnection {
+    async fn send(&mut self, data: &[u8]) -> Result<(), FrameError> {
+        FramedConnection::send(self, data).await
+    }
+
+    async fn recv(&mut self) -> Result<Vec<u8>, FrameError> {
+        FramedConnection::recv(self).await
+    }
+}
+
+/// Run the handshake on `conn` as `role`, sending `payload` to the peer.
+/// The connection isn't usable for anything else if this fails.
+pub async fn handshake(
+    mut conn: FramedConnection,
+    role: Role,
+    keys: &LocalKeys,
+    payload: &[u8],
+) -> Result<(SecureConnection, RemotePeer), NoiseError> {
+    let (tx, rx, remote) = exchange(&mut conn, role, keys, payload).await?;
+    Ok((SecureConnection { conn, tx, rx, remote_identity: remote.identity }, remote))
+}
+
+/// Run the handshake over `link`, which may lose frames once it is done,
+/// and return a `Session` to encrypt them with.
+pub async fn handshake_session<L: FrameLink>(
+    link: &mut L,
+    role: Role,
+    keys: &LocalKeys,
+    payload: &[u8],
+) -> Result<(Session, RemotePeer), NoiseError> {
+    let (tx, rx, remote) = exchange(link, role, keys, payload).await?;
+    Ok((Session { tx, rx, highest_rx: None }, remote))
+}
+
+/// The handshake itself. Returns the sending and receiving ciphers and
+/// what the peer proved.
+async fn exchange<L: FrameLink>(
+    conn: &mut L,
+    role: Role,
+    keys: &LocalKeys,
+    payload: &[u8],
+) -> Result<(CipherState, CipherState, RemotePeer), NoiseError> {
+    let mut state = SymmetricState::new();
+    let mut secret = [0; DH_LEN];
+    crate::random::fill(&mut secret);
+    let ephemeral = StaticSecret::from(secret);
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    let static_public = PublicKey::from(&keys.static_key);
+
+    let signed_payload = Writer::with_capacity(104 + payload.len())
+        .bytes(1, keys.identity.verifying_key().as_bytes())
+        .bytes(2, &keys.static_signature.to_bytes())
+        .bytes(3, payload)
+        .finish();
+
+    let (remote_static, remote_payload, tx, rx) = match role {
+        Role::Initiator => {
+            // -> e
+            let mut message = Vec::with_capacity(DH_LEN);
+            message.extend_from_slice(ephemeral_public.as_bytes());
+            state.mix_hash(ephemeral_public.as_bytes());
+            state.encrypt_and_hash(&[], &mut message);
+            conn.send(&message).await?;
+
+            // <- e, ee, s, es
+            let message = conn.recv().await?;
+            let remote_ephemeral = public_key(message.get(..DH_LEN).ok_or(NoiseError::Malformed)?)?;
+            state.mix_hash(remote_ephemeral.as_bytes());
+            state.mix_key(&dh(&ephemeral, &remote_ephemeral)?);
+            let encrypted_static = message.get(DH_LEN..2 * DH_LEN + TAG_LEN).ok_or(NoiseError::Malformed)?;
+            let remote_static = public_key(&state.decrypt_and_hash(encrypted_static)?)?;
+            state.mix_key(&dh(&ephemeral, &remote_static)?);
+            let remote_payload = state.decrypt_and_hash(&message[2 * DH_LEN + TAG_LEN..])?;
+
+            // -> s, se
+            let mut message = Vec::with_capacity(DH_LEN + TAG_LEN + signed_payload.len() + TAG_LEN);
+            state.encrypt_and_hash(static_public.as_bytes(), &mut message);
+            state.mix_key(&dh(&keys.static_key, &remote_ephemeral)?);
+            state.encrypt_and_hash(&signed_payload, &mut message);
+            conn.send(&message).await?;
+
+            let (tx, rx) = state.split();
+            (remote_static, remote_payload, tx, rx)
+        }
+        Role::Responder => {
+            // -> e
+            let message = conn.recv().await?;
+            let remote_ephemeral = public_key(message.get(..DH_LEN).ok_or(NoiseError::Malformed)?)?;
+            state.mix_hash(remote_ephemeral.as_bytes());
+            state.decrypt_and_hash(&message[DH_LEN..])?;
+
+            // <- e, ee, s, es
+            let mut message = Vec::with_capacity(2 * DH_LEN + TAG_LEN + signed_payload.len() + TAG_LEN);
+            message.extend_from_slice(ephemeral_public.as_bytes());
+            state.mix_hash(ephemeral_public.as_bytes());
+            state.mix_key(&dh(&ephemeral, &remote_ephemeral)?);
+            state.encrypt_and_hash(static_public.as_bytes(), &mut message);
+            state.mix_key(&dh(&keys.static_key, &remote_ephemeral)?);
+            state.encrypt_and_hash(&signed_payload, &mut message);
+            conn.send(&message).await?;
+
+            // -> s, se
+            let message = conn.recv().await?;
+            let encrypted_static = message.get(..DH_LEN + TAG_LEN).ok_or(NoiseError::Malformed)?;
+            let remote_static = public_key(&state.decrypt_and_hash(encrypted_static)?)?;
+            state.mix_key(&dh(&ephemeral, &remote_static)?);
+            let remote_payload = state.decrypt_and_hash(&message[DH_LEN + TAG_LEN..])?;
+
+            let (rx, tx) = state.split();
+            (remote_static, remote_payload, tx, rx)
+        }
+    };
+
+    let remote = verify_payload(&remote_static, remote_payload)?;
+    Ok((tx, rx, remote))
+}
+
+/// Check that the identity key in `payload` signed `remote_static`, and
+/// take out the caller's part.
+fn verify_payload(remote_static: &PublicKey, payload: Vec<u8>) -> Result<RemotePeer, NoiseError> {
+    let (mut key, mut signature, mut data) = (None, None, Vec::new());
+    for field in p2p_proto::fields(&payload) {
+        match field.ok_or(NoiseError::Malformed)? {
+            (1, value) => key = value.array::<32>(),
+            (2, value) => signature = value.array::<64>(),
+            (3, value) => data = value.bytes().ok_or(NoiseError::Malformed)?.to_vec(),
+            _ => {}
+        }
+    }
+    let (Some(key), Some(signature)) = (key, signature) else { return Err(NoiseError::Malformed) };
+    let identity = VerifyingKey::from_bytes(&key).map_err(|_| NoiseError::Malformed)?;
+    let signature = Signature::from_bytes(&signature);
+    identity.verify(&signed_static_key(remote_static), &signature).map_err(|_| NoiseError::BadSignature)?;
+    Ok(RemotePeer { identity, payload: data })
+}
+
+// ─── Transport ───────────────────────────────────────────────────────────────
+
+/// A framed connection whose frames are encrypted with the keys from a
+/// finished handshake.
+///
+/// Like `FramedConnection`, `send` and `recv` are cancellation-safe: a
+/// frame is encrypted and queued whole before the first await, and
+/// decrypted only once it has fully arrived.
+pub struct SecureConnection {
+    conn: FramedConnection,
+    tx: CipherState,
+    rx: CipherState,
+    remote_identity: VerifyingKey,
+}
+
+impl SecureConnection {
+    /// The peer's Ed25519 identity, as proven in the handshake.
+    pub fn remote_identity(&self) -> &VerifyingKey {
+        &self.remote_identity
+    }
+
+    /// Encrypt and send one frame.
+    pub async fn send(&mut self, data: &[u8]) -> Result<(), FrameError> {
+        if data.len() + TAG_LEN > MAX_MESSAGE_LEN {
+            return Err(FrameError::Oversized(data.len()));
+        }
+        let mut frame = Vec::with_capacity(data.len() + TAG_LEN);
+        self.tx.encrypt(&[], data, &mut frame);
+        self.conn.send(&frame).await
+    }
+
+    /// Receive and decrypt one frame.
+    pub async fn recv(&mut self) -> Result<Vec<u8>, FrameError> {
+        let frame = self.conn.recv().await?;
+        self.rx.decrypt(&[], &frame).map_err(|_| {
+            serial_println!("[P2P] A frame failed to decrypt; the connection can't be trusted.");
+            FrameError::Corrupt
+        })
+    }
+}
+
+/// The keys from a handshake over a link that may lose frames (see the
+/// module documentation). The caller sends and receives the frames.
+pub struct Session {
+    tx: CipherState,
+    rx: CipherState,
+    /// Nonce of the last frame accepted.
+    highest_rx: Option<u64>,
+}
+
+impl Session {
+    /// Encrypt one frame, nonce in front.
+    pub fn encrypt(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(NONCE_LEN + data.len() + TAG_LEN);
+        frame.extend_from_slice(&self.tx.nonce.to_le_bytes());
+        self.tx.encrypt(&[], data, &mut frame);
+        frame
+    }
+
+    /// Decrypt one frame. Fails if it was forged, corrupted or replayed.
+    pub fn decrypt(&mut self, frame: &[u8]) -> Result<Vec<u8>, FrameError> {
+        let (nonce, ciphertext) = frame.split_first_chunk::<NONCE_LEN>().ok_or(FrameError::Corrupt)?;
+        let nonce = u64::from_le_bytes(*nonce);
+        if self.highest_rx.is_some_and(|highest| nonce <= highest) {
+            return Err(FrameError::Corrupt);
+        }
+        self.rx.set_nonce(nonce);
+        let plaintext = self.rx.decrypt(&[], ciphertext).map_err(|_| FrameError::Corrupt)?;
+        self.highest_rx = Some(nonce);
+        Ok(plaintext)
+    }
+}
@@ -0,0 +1,236 @@
+//! # Protobuf Encoding
+//!
+//! A minimal codec for the protobuf wire format, which the handshake,
+//! Kademlia and gossip messages are written in so their schemas can grow
+//! without breaking older peers. Each field is a key, `(number << 3) |
+//! wire type` as a varint, then its value:
+//!
+//! | wire type | value                                 |
+//! |-----------|---------------------------------------|
+//! | 0         | varint                                |
+//! | 1         | 8 bytes, little-endian                |
+//! | 2         | varint length, then that many bytes   |
+//! | 5         | 4 bytes, little-endian                |
+//!
+//! Readers skip fields they don't know, so a later version can add fields
+//! freely; it must not reuse a number or change a field's type. Nested
+//! messages, strings and bytes are all wire type 2. Groups (types 3 and 4)
+//! are long deprecated and not supported.
+//!
+//! Varints are little-endian base 128: seven bits per byte, the high bit
+//! set on every byte but the last. Frames on the wire are prefixed with
+//! their length the same way (see `p2p_transport`).
+
+use alloc::vec::Vec;
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
+
+/// Longest varint, for a `u64`.
+pub const MAX_VARINT_LEN: usize = 10;
+
+const WIRE_VARINT: u8 = 0;
+const WIRE_FIXED64: u8 = 1;
+const WIRE_BYTES: u8 = 2;
+const WIRE_FIXED32: u8 = 5;
+
+/// Append `value` to `buf` as a varint.
+pub fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// The varint at the start of `bytes`, and what follows it. `None` if it
+/// is cut short or longer than `MAX_VARINT_LEN`.
+pub fn varint(bytes: &[u8]) -> Option<(u64, &[u8])> {
+    let mut value = 0u64;
+    for (i, &byte) in bytes.iter().enumerate().take(MAX_VARINT_LEN) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, &bytes[i + 1..]));
+        }
+    }
+    None
+}
+
+/// Builds a message field by field.
+#[derive(Default)]
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn with_capacity(capacity: usize) -> Writer {
+        Writer { buf: Vec::with_capacity(capacity) }
+    }
+
+    pub fn uint(&mut self, field: u32, value: u64) -> &mut Writer {
+        self.key(field, WIRE_VARINT);
+        put_varint(&mut self.buf, value);
+        self
+    }
+
+    pub fn bool(&mut self, field: u32, value: bool) -> &mut Writer {
+        self.uint(field, u64::from(value))
+    }
+
+    /// A `fixed64` field, for values that are usually large.
+    pub fn fixed64(&mut self, field: u32, value: u64) -> &mut Writer {
+        self.key(field, WIRE_FIXED64);
+        self.buf.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    /// A bytes, string or nested message field.
+    pub fn bytes(&mut self, field: u32, value: &[u8]) -> &mut Writer {
+        self.key(field, WIRE_BYTES);
+        put_varint(&mut self.buf, value.len() as u64);
+        self.buf.extend_from_slice(value);
+        self
+    }
+
+    pub fn string(&mut self, field: u32, value: &str) -> &mut Writer {
+        self.bytes(field, value.as_bytes())
+    }
+
+    pub fn finish(&mut self) -> Vec<u8> {
+        core::mem::take(&mut self.buf)
+    }
+
+    fn key(&mut self, field: u32, wire: u8) {
+        put_varint(&mut self.buf, u64::from(field) << 3 | u64::from(wire));
+    }
+}
+
+/// A field's value as read off the wire.
+#[derive(Debug, Clone, Copy)]
+pub enum Value<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    /// A 4-byte value; no field here has that type, so it is only skipped.
+    Fixed32,
+}
+
+impl<'a> Value<'a> {
+    pub fn uint(self) -> Option<u64> {
+        match self {
+            Value::Varint(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// A varint that must fit a `u32`.
+    pub fn u32(self) -> Option<u32> {
+        self.uint()?.try_into().ok()
+    }
+
+    pub fn bool(self) -> Option<bool> {
+        self.uint().map(|value| value != 0)
+    }
+
+    pub fn fixed64(self) -> Option<u64> {
+        match self {
+            Value::Fixed64(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn bytes(self) -> Option<&'a [u8]> {
+        match self {
+            Value::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// Bytes of exactly `N`, like a key or a node ID.
+    pub fn array<const N: usize>(self) -> Option<[u8; N]> {
+        self.bytes()?.try_into().ok()
+    }
+
+    pub fn str(self) -> Option<&'a str> {
+        core::str::from_utf8(self.bytes()?).ok()
+    }
+}
+
+/// The fields of `message`, in wire order. Yields `None`, and then stops,
+/// at the first field that doesn't parse.
+pub fn fields(message: &[u8]) -> Fields<'_> {
+    Fields { rest: message }
+}
+
+pub struct Fields<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = Option<(u32, Value<'a>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rest.is_empty() {
+            return None;
+        }
+        let field = self.field();
+        if field.is_none() {
+            self.rest = &[];
+        }
+        Some(field)
+    }
+}
+
+impl<'a> Fields<'a> {
+    fn field(&mut self) -> Option<(u32, Value<'a>)> {
+        let (key, rest) = varint(self.rest)?;
+        let field = u32::try_from(key >> 3).ok().filter(|field| *field != 0)?;
+        let (value, rest) = match key as u8 & 7 {
+            WIRE_VARINT => {
+                let (value, rest) = varint(rest)?;
+                (Value::Varint(value), rest)
+            }
+            WIRE_FIXED64 => {
+                let (value, rest) = rest.split_first_chunk::<8>()?;
+                (Value::Fixed64(u64::from_le_bytes(*value)), rest)
+            }
+            WIRE_BYTES => {
+                let (len, rest) = varint(rest)?;
+                let (value, rest) = rest.split_at_checked(usize::try_from(len).ok()?)?;
+                (Value::Bytes(value), rest)
+            }
+            WIRE_FIXED32 => {
+                let (_, rest) = rest.split_first_chunk::<4>()?;
+                (Value::Fixed32, rest)
+            }
+            _ => return None,
+        };
+        self.rest = rest;
+        Some((field, value))
+    }
+}
+
+// ─── Shared Messages ─────────────────────────────────────────────────────────
+//
+// message Endpoint {
+//   bytes  ip   = 1;  // IPv4, 4 bytes
+//   uint32 port = 2;
+// }
+
+/// `endpoint` as an `Endpoint` message.
+pub fn encode_endpoint(endpoint: &IpEndpoint) -> Vec<u8> {
+    let IpAddress::Ipv4(addr) = endpoint.addr;
+    Writer::with_capacity(10).bytes(1, addr.as_bytes()).uint(2, u64::from(endpoint.port)).finish()
+}
+
+/// An `Endpoint` message. `None` if it is malformed or its address isn't
+/// IPv4.
+pub fn decode_endpoint(message: &[u8]) -> Option<IpEndpoint> {
+    let (mut addr, mut port) = (None, 0);
+    for field in fields(message) {
+        match field? {
+            (1, value) => addr = Some(Ipv4Address::from_bytes(&value.array::<4>()?)),
+            (2, value) => port = u16::try_from(value.uint()?).ok()?,
+            _ => {}
+        }
+    }
+    Some(IpEndpoint::new(addr?.into(), port))
+}
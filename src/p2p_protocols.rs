@@ -0,0 +1,153 @@
+//! # Protocol Negotiation
+//!
+//! Lets several sub-protocols share one P2P connection, multistream-select
+//! style. Right after the handshake each side sends the protocols it
+//! speaks, by name, in its order of preference, as a protobuf message
+//! (see `p2p_proto`):
+//!
+//! ```text
+//! message Protocols {
+//!   repeated string names = 1;
+//! }
+//! ```
+//!
+//! A name either side doesn't know is skipped, and a connection with no
+//! protocol in common is closed. After that every message starts with a
+//! one-byte tag naming its protocol: the protocol's position in the
+//! *receiving* side's list. So each side reads tags against its own list
+//! and needs nothing agreed in advance, and a later version can add or
+//! drop a protocol without breaking peers that don't know it.
+
+use alloc::vec::Vec;
+use crate::p2p_noise::SecureConnection;
+use crate::p2p_proto::{self, Writer};
+use crate::serial_println;
+
+/// Most protocols read from a peer's list.
+const MAX_PROTOCOLS: usize = 32;
+
+/// A sub-protocol spoken over P2P connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Who the peer is and where it sees us from.
+    Identify,
+    /// DHT lookups, records and providers (see `p2p_rpc`).
+    Kad,
+    /// Publish/subscribe (see `p2p_pubsub`).
+    Gossipsub,
+    /// Block exchange (see `p2p_blocks`).
+    Blocks,
+    /// Hole-punch introductions (see `p2p_nat`).
+    HolePunch,
+    /// Circuits through a relay (see `p2p_relay`).
+    Relay,
+    /// Streams between processes (see `p2p_channel`).
+    Channel,
+}
+
+/// The protocols this node speaks, in the order it lists them.
+const SUPPORTED: [Protocol; 7] = [
+    Protocol::Kad,
+    Protocol::Gossipsub,
+    Protocol::Blocks,
+    Protocol::Identify,
+    Protocol::HolePunch,
+    Protocol::Relay,
+    Protocol::Channel,
+];
+
+impl Protocol {
+    pub fn name(self) -> &'static str {
+        match self {
+            Protocol::Identify => "/kernel/identify/1.0.0",
+            Protocol::Kad => "/kernel/kad/1.0.0",
+            Protocol::Gossipsub => "/kernel/gossipsub/1.0.0",
+            Protocol::Blocks => "/kernel/blocks/1.0.0",
+            Protocol::HolePunch => "/kernel/holepunch/1.0.0",
+            Protocol::Relay => "/kernel/relay/1.0.0",
+            Protocol::Channel => "/kernel/channel/1.0.0",
+        }
+    }
+
+    fn from_name(name: &[u8]) -> Option<Protocol> {
+        SUPPORTED.into_iter().find(|protocol| protocol.name().as_bytes() == name)
+    }
+}
+
+/// What a connection's peer listed, in its order.
+#[derive(Debug, Clone)]
+pub struct Negotiated {
+    /// `None` for the names this node doesn't know.
+    remote: Vec<Option<Protocol>>,
+}
+
+impl Negotiated {
+    /// Whether both sides speak `protocol`.
+    pub fn speaks(&self, protocol: Protocol) -> bool {
+        self.remote.contains(&Some(protocol))
+    }
+
+    /// The protocols both sides speak, in this node's order.
+    pub fn protocols(&self) -> Vec<Protocol> {
+        SUPPORTED.into_iter().filter(|protocol| self.speaks(*protocol)).collect()
+    }
+
+    /// `message` with the tag the peer reads as `protocol`; `None` if the
+    /// peer doesn't speak it.
+    pub fn tag(&self, protocol: Protocol, message: &[u8]) -> Option<Vec<u8>> {
+        let tag = self.remote.iter().position(|remote| *remote == Some(protocol))?;
+        let mut tagged = Vec::with_capacity(1 + message.len());
+        tagged.push(tag as u8);
+        tagged.extend_from_slice(message);
+        Some(tagged)
+    }
+}
+
+/// The protocol an incoming `message` is tagged with, and the message
+/// without the tag.
+pub fn untag(message: &[u8]) -> Option<(Protocol, &[u8])> {
+    let (&tag, rest) = message.split_first()?;
+    Some((*SUPPORTED.get(usize::from(tag))?, rest))
+}
+
+/// Exchange protocol lists with the peer on a freshly secured `conn`.
+pub async fn negotiate(conn: &mut SecureConnection) -> Result<Negotiated, ()> {
+    conn.send(&local_list()).await.map_err(|e| {
+        serial_println!("[P2P] Couldn't send the protocol list: {:?}", e);
+    })?;
+    let list = conn.recv().await.map_err(|e| {
+        serial_println!("[P2P] No protocol list from the peer: {:?}", e);
+    })?;
+    let Some(negotiated) = parse_list(&list) else {
+        serial_println!("[P2P] The peer's protocol list is malformed.");
+        return Err(());
+    };
+    if negotiated.protocols().is_empty() {
+        serial_println!("[P2P] The peer speaks no protocol this node does.");
+        return Err(());
+    }
+    Ok(negotiated)
+}
+
+/// This node's protocol list, as `negotiate` sends it.
+pub fn local_list() -> Vec<u8> {
+    let mut list = Writer::with_capacity(SUPPORTED.len() * 26);
+    for protocol in SUPPORTED {
+        list.string(1, protocol.name());
+    }
+    list.finish()
+}
+
+/// A peer's protocol list, as `negotiate` receives it.
+pub fn parse_list(list: &[u8]) -> Option<Negotiated> {
+    let mut remote = Vec::new();
+    for field in p2p_proto::fields(list) {
+        if let (1, name) = field? {
+            if remote.len() == MAX_PROTOCOLS {
+                return None;
+            }
+            remote.push(Protocol::from_name(name.bytes()?));
+        }
+    }
+    Some(Negotiated { remote })
+}
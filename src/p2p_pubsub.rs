@@ -0,0 +1,697 @@
+//! # Publish/Subscribe
+//!
+//! GossipSub-style topics over the connections `p2p_conn` keeps open. A
+//! node tells every peer it is connected to which topics it subscribes to,
+//! and keeps, for each of its own topics, a *mesh*: a handful of connected
+//! peers subscribed to the topic that it forwards the topic's messages to.
+//! A message travels mesh by mesh; each node passes it on once and
+//! remembers its ID so it drops the copies that come back.
+//!
+//! ## Wire format
+//! Pubsub messages are the gossipsub protocol (see `p2p_protocols`), each
+//! a protobuf `Rpc` (see `p2p_proto`). None of them gets a reply.
+//!
+//! ```text
+//! message Rpc {
+//!   repeated SubOpts subscriptions = 1;
+//!   repeated Publish publish       = 2;
+//!   repeated string  graft         = 3;  // topics
+//!   repeated string  prune         = 4;  // topics
+//! }
+//! message SubOpts {
+//!   bool   subscribe = 1;
+//!   string topic     = 2;
+//! }
+//! message Publish {
+//!   bytes   publisher = 1;  // Ed25519 key, 32 bytes
+//!   fixed64 sequence  = 2;
+//!   bytes   signature = 3;  // 64 bytes
+//!   string  topic     = 4;
+//!   bytes   data      = 5;
+//! }
+//! ```
+//!
+//! Subscriptions go to a peer when a connection to it opens, listing all
+//! of this node's topics, and to every peer whenever this node joins or
+//! leaves a topic. A graft asks a peer to put the sender in its mesh for a
+//! topic and a prune takes it out; a graft for a topic the receiver isn't
+//! subscribed to is answered with a prune. This node sends one part per
+//! `Rpc`, but handles any mix.
+//!
+//! ## Messages
+//! A message's ID is the SHA-256 of its publisher's key and sequence
+//! number. The publisher signs it (see `p2p_sign`), so the nodes passing
+//! it on can't alter it; a bad signature ends the connection it came on.
+//! A message whose ID was seen in the last `SEEN_TTL_MS` is dropped.
+//! Otherwise it is queued for this node's subscriptions to the topic and
+//! forwarded to the topic's mesh, leaving out the peer it came from.
+//!
+//! Publishing to a topic this node subscribes to sends to its mesh. For
+//! any other topic the node keeps a *fanout* instead: up to `MESH_D`
+//! subscribed peers, picked on the first publish and forgotten after
+//! `FANOUT_TTL_MS` without one. Messages published here are also queued
+//! for local subscriptions, so processes on one node can use a topic to
+//! talk to each other.
+//!
+//! ## Mesh maintenance
+//! Every `HEARTBEAT_MS`, a mesh with fewer than `MESH_D_LOW` peers (peers
+//! disconnected or pruned it) grafts more, up to `MESH_D`, and one with
+//! more than `MESH_D_HIGH` (peers grafted onto it) prunes back to
+//! `MESH_D`, picking at random. Only connected peers take part; pubsub
+//! dials nobody.
+//!
+//! ## Subscriptions
+//! `subscribe` returns a `Subscription` whose queue holds up to
+//! `MAX_QUEUED` messages; past that the oldest is dropped. Dropping the
+//! subscription unsubscribes, and the node leaves the topic with its last
+//! subscription.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::Poll;
+use ed25519_dalek::VerifyingKey;
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+use spin::Mutex;
+use crate::executor::{self, Task};
+use crate::interrupts::uptime_ms;
+use crate::p2p::{self, P2P_STATE};
+use crate::p2p_conn;
+use crate::p2p_kademlia::NodeId;
+use crate::p2p_protocols::Protocol;
+use crate::p2p_proto::{self, Writer};
+use crate::p2p_sign::{self, SIGNATURE_LEN};
+
+/// Peers a mesh aims for.
+pub const MESH_D: usize = 6;
+/// A mesh with fewer peers than this grafts more.
+pub const MESH_D_LOW: usize = 4;
+/// A mesh with more peers than this prunes some.
+pub const MESH_D_HIGH: usize = 12;
+/// Longest topic name, in bytes.
+pub const MAX_TOPIC_LEN: usize = 64;
+/// Topics this node may subscribe to at once.
+pub const MAX_TOPICS: usize = 64;
+/// Largest message, in bytes.
+pub const MAX_DATA_LEN: usize = 64 * 1024;
+/// Messages waiting in one subscription.
+pub const MAX_QUEUED: usize = 64;
+/// Topics remembered per peer.
+const MAX_PEER_TOPICS: usize = 256;
+const HEARTBEAT_MS: u64 = 1_000;
+const FANOUT_TTL_MS: u64 = 60_000;
+const SEEN_TTL_MS: u64 = 120_000;
+/// Message IDs remembered at most.
+const MAX_SEEN: usize = 4096;
+
+const FIELD_SUBSCRIPTIONS: u32 = 1;
+const FIELD_PUBLISH: u32 = 2;
+const FIELD_GRAFT: u32 = 3;
+const FIELD_PRUNE: u32 = 4;
+
+static NEXT_SUBSCRIPTION: AtomicU64 = AtomicU64::new(1);
+
+/// Why a subscribe or publish failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PubsubError {
+    /// The topic is empty or longer than `MAX_TOPIC_LEN`.
+    BadTopic,
+    /// The data is longer than `MAX_DATA_LEN`.
+    TooLarge,
+    /// This node already subscribes to `MAX_TOPICS` topics.
+    TooManyTopics,
+    /// The P2P stack isn't up.
+    NotRunning,
+}
+
+/// A message queued for a subscription.
+#[derive(Debug, Clone)]
+pub struct PubsubMessage {
+    pub topic: String,
+    /// The node that published it.
+    pub from: NodeId,
+    pub data: Vec<u8>,
+}
+
+/// A topic this node subscribes to, as listed by `topics`.
+#[derive(Debug, Clone)]
+pub struct TopicInfo {
+    pub topic: String,
+    pub subscriptions: usize,
+    pub mesh: usize,
+    /// Connected peers subscribed to the topic.
+    pub peers: usize,
+}
+
+struct Mesh {
+    topic: String,
+    peers: Vec<NodeId>,
+}
+
+struct Fanout {
+    topic: String,
+    peers: Vec<NodeId>,
+    last_published: u64,
+}
+
+struct Subscriber {
+    id: u64,
+    topic: String,
+    queue: VecDeque<PubsubMessage>,
+}
+
+/// A connected peer and the topics it subscribes to.
+struct Peer {
+    node_id: NodeId,
+    topics: Vec<String>,
+}
+
+#[derive(Default)]
+struct PubSub {
+    peers: Vec<Peer>,
+    meshes: Vec<Mesh>,
+    fanouts: Vec<Fanout>,
+    subscribers: Vec<Subscriber>,
+    /// IDs of recent messages and when they were first seen, oldest first.
+    seen: VecDeque<([u8; 32], u64)>,
+}
+
+lazy_static! {
+    static ref PUBSUB: Mutex<PubSub> = Mutex::new(PubSub::default());
+}
+
+/// Frames to send and their peers, gathered under the lock and sent after.
+type Outgoing = Vec<(NodeId, Vec<u8>)>;
+
+/// Start the heartbeat that maintains the meshes.
+pub fn start() {
+    executor::submit(Task::new(heartbeat_task()));
+}
+
+/// Every topic this node subscribes to.
+pub fn topics() -> Vec<TopicInfo> {
+    let pubsub = PUBSUB.lock();
+    pubsub
+        .meshes
+        .iter()
+        .map(|mesh| TopicInfo {
+            topic: mesh.topic.clone(),
+            subscriptions: pubsub.subscribers.iter().filter(|s| s.topic == mesh.topic).count(),
+            mesh: mesh.peers.len(),
+            peers: pubsub.candidates(&mesh.topic, &[]).len(),
+        })
+        .collect()
+}
+
+// ─── Subscribing and Publishing ──────────────────────────────────────────────
+
+/// A subscription to a topic. Dropping it unsubscribes.
+pub struct Subscription {
+    id: u64,
+}
+
+impl Subscription {
+    /// The oldest queued message, if any.
+    pub fn try_recv(&self) -> Option<PubsubMessage> {
+        PUBSUB.lock().subscribers.iter_mut().find(|s| s.id == self.id)?.queue.pop_front()
+    }
+
+    /// Wait for the next message.
+    pub async fn recv(&self) -> PubsubMessage {
+        poll_fn(|_| self.try_recv().map_or(Poll::Pending, Poll::Ready)).await
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let outgoing = {
+            let mut pubsub = PUBSUB.lock();
+            let Some(idx) = pubsub.subscribers.iter().position(|s| s.id == self.id) else { return };
+            let topic = pubsub.subscribers.swap_remove(idx).topic;
+            if pubsub.subscribers.iter().any(|s| s.topic == topic) {
+                return;
+            }
+            pubsub.leave(&topic)
+        };
+        send(outgoing);
+    }
+}
+
+/// Subscribe to `topic`, joining it if this is the node's first
+/// subscription to it.
+pub fn subscribe(topic: &str) -> Result<Subscription, PubsubError> {
+    check_topic(topic)?;
+    let id = NEXT_SUBSCRIPTION.fetch_add(1, Ordering::Relaxed);
+    let outgoing = {
+        let mut pubsub = PUBSUB.lock();
+        let join = !pubsub.subscribed(topic);
+        if join && pubsub.meshes.len() >= MAX_TOPICS {
+            return Err(PubsubError::TooManyTopics);
+        }
+        pubsub.subscribers.push(Subscriber { id, topic: String::from(topic), queue: VecDeque::new() });
+        if join { pubsub.join(topic) } else { Outgoing::new() }
+    };
+    send(outgoing);
+    Ok(Subscription { id })
+}
+
+/// Publish `data` on `topic`. Returns how many peers it was sent to.
+pub fn publish(topic: &str, data: &[u8]) -> Result<usize, PubsubError> {
+    check_topic(topic)?;
+    if data.len() > MAX_DATA_LEN {
+        return Err(PubsubError::TooLarge);
+    }
+    let (node_id, identity) = P2P_STATE
+        .lock()
+        .as_ref()
+        .map(|state| (state.node_id, state.keys.identity.clone()))
+        .ok_or(PubsubError::NotRunning)?;
+    let sequence = p2p_sign::next_sequence();
+    let publisher = identity.verifying_key().to_bytes();
+    let signature = p2p_sign::sign_gossip(sequence, topic, data, &identity);
+
+    let frame = Publish { publisher, sequence, signature, topic, data }.encode();
+
+    let now = uptime_ms();
+    let peers = {
+        let mut pubsub = PUBSUB.lock();
+        pubsub.mark_seen(message_id(&publisher, sequence), now);
+        pubsub.deliver(&PubsubMessage { topic: String::from(topic), from: node_id, data: data.to_vec() });
+        pubsub.publish_peers(topic, now)
+    };
+    Ok(peers.iter().filter(|peer| p2p_conn::send_message(peer, Protocol::Gossipsub, &frame).is_ok()).count())
+}
+
+fn check_topic(topic: &str) -> Result<(), PubsubError> {
+    if topic.is_empty() || topic.len() > MAX_TOPIC_LEN {
+        return Err(PubsubError::BadTopic);
+    }
+    Ok(())
+}
+
+fn message_id(publisher: &[u8; 32], sequence: u64) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(publisher);
+    hasher.update(sequence.to_le_bytes());
+    hasher.finalize().into()
+}
+
+// ─── Peers ───────────────────────────────────────────────────────────────────
+
+/// A connection to `peer` opened: tell it which topics this node is in.
+pub(crate) fn peer_connected(peer: NodeId) {
+    let frame = {
+        let mut pubsub = PUBSUB.lock();
+        if !pubsub.peers.iter().any(|p| p.node_id == peer) {
+            pubsub.peers.push(Peer { node_id: peer, topics: Vec::new() });
+        }
+        if pubsub.meshes.is_empty() {
+            return;
+        }
+        subscriptions(pubsub.meshes.iter().map(|mesh| (mesh.topic.as_str(), true)))
+    };
+    send(alloc::vec![(peer, frame)]);
+}
+
+/// The last connection to `peer` closed: forget it.
+pub(crate) fn peer_disconnected(peer: NodeId) {
+    let mut pubsub = PUBSUB.lock();
+    pubsub.peers.retain(|p| p.node_id != peer);
+    for mesh in pubsub.meshes.iter_mut() {
+        mesh.peers.retain(|p| *p != peer);
+    }
+    for fanout in pubsub.fanouts.iter_mut() {
+        fanout.peers.retain(|p| *p != peer);
+    }
+}
+
+/// Handle a pubsub `message` from `peer`. False if it was malformed or
+/// a signature doesn't check out, and the connection should close.
+pub(crate) fn handle(message: &[u8], peer: NodeId) -> bool {
+    let Some(rpc) = Rpc::decode(message) else { return false };
+    if !rpc.publish.iter().all(|publish| on_publish(publish, peer)) {
+        return false;
+    }
+    let outgoing = {
+        let mut pubsub = PUBSUB.lock();
+        pubsub.on_subscriptions(&rpc.subscriptions, peer);
+        let mut outgoing = Outgoing::new();
+        for topic in rpc.graft {
+            outgoing.extend(pubsub.on_graft(topic, peer));
+        }
+        for topic in rpc.prune {
+            outgoing.extend(pubsub.on_prune(topic, peer));
+        }
+        outgoing
+    };
+    send(outgoing);
+    true
+}
+
+/// Check a published message, then queue it locally and pass it on if it
+/// is new.
+fn on_publish(publish: &Publish, peer: NodeId) -> bool {
+    let Publish { publisher, sequence, signature, topic, data } = *publish;
+    let id = message_id(&publisher, sequence);
+    // Copies arrive from every mesh peer; only the first is worth a
+    // signature check.
+    if PUBSUB.lock().seen.iter().any(|(seen, _)| *seen == id) {
+        return true;
+    }
+    if data.len() > MAX_DATA_LEN || !p2p_sign::verify_gossip(sequence, topic, data, &publisher, &signature) {
+        return false;
+    }
+    let Ok(key) = VerifyingKey::from_bytes(&publisher) else { return false };
+    let from = p2p::identity_of(&key).1;
+
+    let peers = {
+        let mut pubsub = PUBSUB.lock();
+        if !pubsub.mark_seen(id, uptime_ms()) {
+            return true;
+        }
+        pubsub.deliver(&PubsubMessage { topic: String::from(topic), from, data: data.to_vec() });
+        match pubsub.meshes.iter().find(|mesh| mesh.topic == topic) {
+            Some(mesh) => mesh.peers.iter().copied().filter(|p| *p != peer && *p != from).collect(),
+            None => Vec::new(),
+        }
+    };
+    let frame = publish.encode();
+    for forward in peers {
+        let _ = p2p_conn::send_message(&forward, Protocol::Gossipsub, &frame);
+    }
+    true
+}
+
+// ─── State ───────────────────────────────────────────────────────────────────
+
+impl PubSub {
+    fn subscribed(&self, topic: &str) -> bool {
+        self.meshes.iter().any(|mesh| mesh.topic == topic)
+    }
+
+    /// Connected peers subscribed to `topic`, other than `exclude`.
+    fn candidates(&self, topic: &str, exclude: &[NodeId]) -> Vec<NodeId> {
+        self.peers
+            .iter()
+            .filter(|p| p.topics.iter().any(|t| t == topic) && !exclude.contains(&p.node_id))
+            .map(|p| p.node_id)
+            .collect()
+    }
+
+    /// Remember message `id`; false if it was seen already.
+    fn mark_seen(&mut self, id: [u8; 32], now: u64) -> bool {
+        self.expire_seen(now);
+        if self.seen.iter().any(|(seen, _)| *seen == id) {
+            return false;
+        }
+        if self.seen.len() >= MAX_SEEN {
+            self.seen.pop_front();
+        }
+        self.seen.push_back((id, now));
+        true
+    }
+
+    fn expire_seen(&mut self, now: u64) {
+        while self.seen.front().is_some_and(|(_, at)| now.saturating_sub(*at) > SEEN_TTL_MS) {
+            self.seen.pop_front();
+        }
+    }
+
+    /// Queue `message` for every subscription to its topic.
+    fn deliver(&mut self, message: &PubsubMessage) {
+        for subscriber in self.subscribers.iter_mut().filter(|s| s.topic == message.topic) {
+            if subscriber.queue.len() >= MAX_QUEUED {
+                subscriber.queue.pop_front();
+            }
+            subscriber.queue.push_back(message.clone());
+        }
+    }
+
+    /// Where to send a message published here on `topic`: the mesh, or
+    /// else the topic's fanout, made or topped up as needed.
+    fn publish_peers(&mut self, topic: &str, now: u64) -> Vec<NodeId> {
+        if let Some(mesh) = self.meshes.iter().find(|mesh| mesh.topic == topic) {
+            return mesh.peers.clone();
+        }
+        let idx = match self.fanouts.iter().position(|f| f.topic == topic) {
+            Some(idx) => idx,
+            None => {
+                self.fanouts.push(Fanout { topic: String::from(topic), peers: Vec::new(), last_published: now });
+                self.fanouts.len() - 1
+            }
+        };
+        let have = self.fanouts[idx].peers.len();
+        if have < MESH_D {
+            let more = pick(self.candidates(topic, &self.fanouts[idx].peers), MESH_D - have);
+            self.fanouts[idx].peers.extend(more);
+        }
+        let fanout = &mut self.fanouts[idx];
+        fanout.last_published = now;
+        fanout.peers.clone()
+    }
+
+    /// Join `topic`: build its mesh, starting from its fanout, graft the
+    /// peers in it and tell everyone.
+    fn join(&mut self, topic: &str) -> Outgoing {
+        let mut peers = match self.fanouts.iter().position(|f| f.topic == topic) {
+            Some(idx) => self.fanouts.swap_remove(idx).peers,
+            None => Vec::new(),
+        };
+        if peers.len() < MESH_D {
+            let more = pick(self.candidates(topic, &peers), MESH_D - peers.len());
+            peers.extend(more);
+        }
+        let mut outgoing: Outgoing = peers.iter().map(|peer| (*peer, topic_frame(FIELD_GRAFT, topic))).collect();
+        self.announce(topic, true, &mut outgoing);
+        self.meshes.push(Mesh { topic: String::from(topic), peers });
+        outgoing
+    }
+
+    /// Leave `topic`: prune its mesh and tell everyone.
+    fn leave(&mut self, topic: &str) -> Outgoing {
+        let mut outgoing = Outgoing::new();
+        if let Some(idx) = self.meshes.iter().position(|mesh| mesh.topic == topic) {
+            let mesh = self.meshes.swap_remove(idx);
+            outgoing.extend(mesh.peers.iter().map(|peer| (*peer, topic_frame(FIELD_PRUNE, topic))));
+        }
+        self.announce(topic, false, &mut outgoing);
+        outgoing
+    }
+
+    /// Tell every connected peer this node joined or left `topic`.
+    fn announce(&self, topic: &str, subscribed: bool, outgoing: &mut Outgoing) {
+        let frame = subscriptions(core::iter::once((topic, subscribed)));
+        outgoing.extend(self.peers.iter().map(|p| (p.node_id, frame.clone())));
+    }
+
+    fn on_subscriptions(&mut self, subscriptions: &[(&str, bool)], peer: NodeId) {
+        if subscriptions.is_empty() {
+            return;
+        }
+        let idx = match self.peers.iter().position(|p| p.node_id == peer) {
+            Some(idx) => idx,
+            None => {
+                self.peers.push(Peer { node_id: peer, topics: Vec::new() });
+                self.peers.len() - 1
+            }
+        };
+        for &(topic, subscribed) in subscriptions {
+            let topics = &mut self.peers[idx].topics;
+            let known = topics.iter().position(|t| t == topic);
+            match (subscribed, known) {
+                (true, None) if topics.len() < MAX_PEER_TOPICS => topics.push(String::from(topic)),
+                (false, Some(known)) => {
+                    topics.swap_remove(known);
+                    for mesh in self.meshes.iter_mut().filter(|mesh| mesh.topic == topic) {
+                        mesh.peers.retain(|p| *p != peer);
+                    }
+                    for fanout in self.fanouts.iter_mut().filter(|f| f.topic == topic) {
+                        fanout.peers.retain(|p| *p != peer);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn on_graft(&mut self, topic: &str, peer: NodeId) -> Outgoing {
+        let Some(mesh) = self.meshes.iter_mut().find(|mesh| mesh.topic == topic) else {
+            return alloc::vec![(peer, topic_frame(FIELD_PRUNE, topic))];
+        };
+        if !mesh.peers.contains(&peer) {
+            mesh.peers.push(peer);
+        }
+        // A peer grafting a topic subscribes to it, whether or not its
+        // SUBSCRIPTIONS said so yet.
+        if let Some(p) = self.peers.iter_mut().find(|p| p.node_id == peer) {
+            if !p.topics.iter().any(|t| t == topic) && p.topics.len() < MAX_PEER_TOPICS {
+                p.topics.push(String::from(topic));
+            }
+        }
+        Outgoing::new()
+    }
+
+    fn on_prune(&mut self, topic: &str, peer: NodeId) -> Outgoing {
+        for mesh in self.meshes.iter_mut().filter(|mesh| mesh.topic == topic) {
+            mesh.peers.retain(|p| *p != peer);
+        }
+        Outgoing::new()
+    }
+
+    /// Bring each mesh back between `MESH_D_LOW` and `MESH_D_HIGH` peers
+    /// and forget stale fanouts and message IDs.
+    fn heartbeat(&mut self, now: u64) -> Outgoing {
+        let mut outgoing = Outgoing::new();
+        for idx in 0..self.meshes.len() {
+            let have = self.meshes[idx].peers.len();
+            if have < MESH_D_LOW {
+                let topic = &self.meshes[idx].topic;
+                let more = pick(self.candidates(topic, &self.meshes[idx].peers), MESH_D - have);
+                outgoing.extend(more.iter().map(|peer| (*peer, topic_frame(FIELD_GRAFT, topic))));
+                self.meshes[idx].peers.extend(more);
+            } else if have > MESH_D_HIGH {
+                let mesh = &mut self.meshes[idx];
+                let keep = pick(mesh.peers.clone(), MESH_D);
+                for peer in mesh.peers.iter().filter(|p| !keep.contains(p)) {
+                    outgoing.push((*peer, topic_frame(FIELD_PRUNE, &mesh.topic)));
+                }
+                mesh.peers = keep;
+            }
+        }
+        self.fanouts.retain(|f| now.saturating_sub(f.last_published) <= FANOUT_TTL_MS);
+        self.expire_seen(now);
+        outgoing
+    }
+}
+
+async fn heartbeat_task() {
+    loop {
+        executor::sleep_ms(HEARTBEAT_MS).await;
+        let outgoing = PUBSUB.lock().heartbeat(uptime_ms());
+        send(outgoing);
+    }
+}
+
+/// Up to `n` of `peers`, picked at random.
+fn pick(mut peers: Vec<NodeId>, n: usize) -> Vec<NodeId> {
+    let n = n.min(peers.len());
+    for i in 0..n {
+        let mut random = [0u8; 4];
+        crate::random::fill(&mut random);
+        let j = i + u32::from_le_bytes(random) as usize % (peers.len() - i);
+        peers.swap(i, j);
+    }
+    peers.truncate(n);
+    peers
+}
+
+fn send(outgoing: Outgoing) {
+    for (peer, frame) in outgoing {
+        // Gossip is best effort: a peer whose queue is full misses it.
+        let _ = p2p_conn::send_message(&peer, Protocol::Gossipsub, &frame);
+    }
+}
+
+// ─── Encoding ────────────────────────────────────────────────────────────────
+
+/// A gossipsub `Rpc` as read.
+#[derive(Default)]
+struct Rpc<'a> {
+    /// Topics, and whether the sender joined or left them.
+    subscriptions: Vec<(&'a str, bool)>,
+    publish: Vec<Publish<'a>>,
+    graft: Vec<&'a str>,
+    prune: Vec<&'a str>,
+}
+
+impl<'a> Rpc<'a> {
+    /// `None` if it is malformed, or names a topic `check_topic` rejects.
+    fn decode(message: &'a [u8]) -> Option<Rpc<'a>> {
+        let mut rpc = Rpc::default();
+        for field in p2p_proto::fields(message) {
+            match field? {
+                (FIELD_SUBSCRIPTIONS, value) => rpc.subscriptions.push(decode_subscription(value.bytes()?)?),
+                (FIELD_PUBLISH, value) => rpc.publish.push(Publish::decode(value.bytes()?)?),
+                (FIELD_GRAFT, value) => rpc.graft.push(topic(value.str()?)?),
+                (FIELD_PRUNE, value) => rpc.prune.push(topic(value.str()?)?),
+                _ => {}
+            }
+        }
+        Some(rpc)
+    }
+}
+
+/// A signed message on a topic.
+#[derive(Clone, Copy)]
+struct Publish<'a> {
+    publisher: [u8; 32],
+    sequence: u64,
+    signature: [u8; SIGNATURE_LEN],
+    topic: &'a str,
+    data: &'a [u8],
+}
+
+impl<'a> Publish<'a> {
+    /// An `Rpc` carrying just this message.
+    fn encode(&self) -> Vec<u8> {
+        let publish = Writer::with_capacity(112 + self.topic.len() + self.data.len())
+            .bytes(1, &self.publisher)
+            .fixed64(2, self.sequence)
+            .bytes(3, &self.signature)
+            .string(4, self.topic)
+            .bytes(5, self.data)
+            .finish();
+        Writer::with_capacity(4 + publish.len()).bytes(FIELD_PUBLISH, &publish).finish()
+    }
+
+    fn decode(message: &'a [u8]) -> Option<Publish<'a>> {
+        let (mut publisher, mut sequence, mut signature, mut topic_name, mut data) = (None, None, None, None, &[][..]);
+        for field in p2p_proto::fields(message) {
+            match field? {
+                (1, value) => publisher = Some(value.array()?),
+                (2, value) => sequence = Some(value.fixed64()?),
+                (3, value) => signature = Some(value.array()?),
+                (4, value) => topic_name = Some(topic(value.str()?)?),
+                (5, value) => data = value.bytes()?,
+                _ => {}
+            }
+        }
+        Some(Publish { publisher: publisher?, sequence: sequence?, signature: signature?, topic: topic_name?, data })
+    }
+}
+
+/// `topic`, if `check_topic` accepts it.
+fn topic(topic: &str) -> Option<&str> {
+    check_topic(topic).ok().map(|()| topic)
+}
+
+/// A `SubOpts` message.
+fn decode_subscription(message: &[u8]) -> Option<(&str, bool)> {
+    let (mut subscribe, mut topic_name) = (false, None);
+    for field in p2p_proto::fields(message) {
+        match field? {
+            (1, value) => subscribe = value.bool()?,
+            (2, value) => topic_name = Some(topic(value.str()?)?),
+            _ => {}
+        }
+    }
+    Some((topic_name?, subscribe))
+}
+
+/// An `Rpc` grafting or pruning (`field`) `topic`.
+fn topic_frame(field: u32, topic: &str) -> Vec<u8> {
+    Writer::with_capacity(2 + topic.len()).string(field, topic).finish()
+}
+
+/// An `Rpc` listing subscriptions; `topics` holds at most `MAX_TOPICS`.
+fn subscriptions<'a>(topics: impl ExactSizeIterator<Item = (&'a str, bool)>) -> Vec<u8> {
+    let mut rpc = Writer::with_capacity(topics.len() * (8 + MAX_TOPIC_LEN));
+    for (topic, subscribed) in topics {
+        let opts = Writer::with_capacity(4 + topic.len()).bool(1, subscribed).string(2, topic).finish();
+        rpc.bytes(FIELD_SUBSCRIPTIONS, &opts);
+    }
+    rpc.finish()
+}
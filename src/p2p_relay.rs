@@ -0,0 +1,584 @@
+//! # Circuit Relay
+//!
+//! Lets two peers that can't reach each other, even by a hole punch (see
+//! `p2p_nat`), talk through a third node that both can reach. The relay
+//! opens a circuit between them and forwards each side's frames to the
+//! other; the two ends run a connection over it like any other (see
+//! `p2p_conn`).
+//!
+//! Relaying is off unless turned on with `set_enabled`, which guests can
+//! only do holding a Device capability on `DEVICE_NET_ADMIN` with WRITE.
+//! A relay keeps at most `p2p.relay.max_circuits` circuits, and forwards
+//! at most `p2p.relay.circuit_bytes_per_sec` each way on each; frames over
+//! that are dropped.
+//!
+//! The two ends run a Noise XX handshake over the circuit (see
+//! `p2p_noise` and `p2p_conn`) and encrypt every frame end to end with the
+//! resulting session, on top of each hop's own encryption and
+//! `p2p_sign`'s seal. The relay can't read, forge or replay frames, and a
+//! relay that swaps in its own key fails the handshake's check against the
+//! identity keys exchanged when the circuit opened. It still sees who
+//! talks to whom, when and how much, and it can drop, delay or cut off
+//! frames.
+//!
+//! ## Wire format
+//! Each message is `[kind: u8][0: u32 LE][body]`. The HOP messages go
+//! between the node asking for a circuit, or using one, and the relay; the
+//! STOP messages between the relay and the other end.
+//!
+//! | kind | message      | body                                                      |
+//! |------|--------------|-----------------------------------------------------------|
+//! | 0x30 | HOP_CONNECT  | target `NodeId` (32), identity key (32), protocol list    |
+//! | 0x31 | HOP_STATUS   | target (32), status (u8), then if 0: circuit (u32 LE), the target's key (32) and list |
+//! | 0x32 | STOP_CONNECT | circuit (u32 LE), origin `NodeId` (32), its key (32) and list |
+//! | 0x33 | STOP_REPLY   | circuit (u32 LE), status (u8), then if 0: key (32), list  |
+//! | 0x34 | HOP_DATA     | circuit (u32 LE), frame                                   |
+//! | 0x35 | STOP_DATA    | circuit (u32 LE), frame                                   |
+//! | 0x36 | HOP_CLOSE    | circuit (u32 LE)                                          |
+//! | 0x37 | STOP_CLOSE   | circuit (u32 LE)                                          |
+//!
+//! Protocol lists are laid out as `p2p_protocols` sends them, and take the
+//! place of negotiation. Each end checks that the other's key hashes to
+//! its node ID. A circuit closes when either end sends HOP_CLOSE, or
+//! either end's connection to the relay closes.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use ed25519_dalek::VerifyingKey;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::executor;
+use crate::interrupts::uptime_ms;
+use crate::p2p::{self, P2P_STATE};
+use crate::p2p_conn::{self, Direction};
+use crate::p2p_kademlia::{NodeId, ID_SIZE};
+use crate::p2p_noise::MAX_MESSAGE_LEN;
+use crate::p2p_protocols::{self, Protocol};
+use crate::p2p_reputation::{self, Subject};
+use crate::p2p_rpc::RpcError;
+use crate::{config, serial_println};
+
+/// Largest frame carried over a circuit, end-to-end encryption included,
+/// leaving room for the relay message around it, sealed and encrypted for
+/// the hop.
+pub const MAX_RELAYED_LEN: usize = MAX_MESSAGE_LEN - 256;
+/// Default of `p2p.relay.max_circuits`.
+const DEFAULT_MAX_CIRCUITS: u64 = 16;
+/// Default of `p2p.relay.circuit_bytes_per_sec`.
+const DEFAULT_CIRCUIT_BYTES_PER_SEC: u64 = 32 * 1024;
+/// Longest a relay and the target may take to open a circuit.
+const RELAY_TIMEOUT_MS: u64 = 5_000;
+/// Wait between checks for a relay's answer.
+const RELAY_POLL_MS: u64 = 50;
+/// Connected peers tried as relays for one connection.
+const MAX_RELAYS: usize = 3;
+
+const KIND_HOP_CONNECT: u8 = 0x30;
+const KIND_HOP_STATUS: u8 = 0x31;
+const KIND_STOP_CONNECT: u8 = 0x32;
+const KIND_STOP_REPLY: u8 = 0x33;
+const KIND_HOP_DATA: u8 = 0x34;
+const KIND_STOP_DATA: u8 = 0x35;
+const KIND_HOP_CLOSE: u8 = 0x36;
+const KIND_STOP_CLOSE: u8 = 0x37;
+const HEADER_LEN: usize = 5;
+const KEY_LEN: usize = 32;
+
+const STATUS_OK: u8 = 0;
+
+/// Why a relay didn't open a circuit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayStatus {
+    /// The node doesn't relay.
+    Disabled,
+    /// The relay has `p2p.relay.max_circuits` open.
+    NoRoom,
+    /// The relay isn't connected to the target.
+    Unreachable,
+    /// The target turned the circuit down.
+    Refused,
+}
+
+impl RelayStatus {
+    fn code(self) -> u8 {
+        match self {
+            RelayStatus::Disabled => 1,
+            RelayStatus::NoRoom => 2,
+            RelayStatus::Unreachable => 3,
+            RelayStatus::Refused => 4,
+        }
+    }
+
+    fn from_code(code: u8) -> RelayStatus {
+        match code {
+            1 => RelayStatus::Disabled,
+            2 => RelayStatus::NoRoom,
+            3 => RelayStatus::Unreachable,
+            _ => RelayStatus::Refused,
+        }
+    }
+}
+
+/// Bytes one end of a circuit may still send, refilled over time.
+struct Allowance {
+    bytes: u64,
+    refilled_at: u64,
+}
+
+impl Allowance {
+    fn new(now: u64) -> Allowance {
+        Allowance { bytes: burst(), refilled_at: now }
+    }
+
+    /// Take `len` bytes if there are that many.
+    fn take(&mut self, len: usize, now: u64) -> bool {
+        let rate = config::get_or("p2p.relay.circuit_bytes_per_sec", DEFAULT_CIRCUIT_BYTES_PER_SEC);
+        let refill = now.saturating_sub(self.refilled_at) * rate / 1000;
+        self.bytes = (self.bytes + refill).min(burst());
+        self.refilled_at = now;
+        let len = len as u64;
+        if self.bytes < len {
+            return false;
+        }
+        self.bytes -= len;
+        true
+    }
+}
+
+/// Most bytes an end may send at once: a second's worth, and at least one
+/// frame of the largest size.
+fn burst() -> u64 {
+    config::get_or("p2p.relay.circuit_bytes_per_sec", DEFAULT_CIRCUIT_BYTES_PER_SEC).max(MAX_RELAYED_LEN as u64)
+}
+
+/// A circuit this node relays.
+struct Circuit {
+    id: u32,
+    origin: NodeId,
+    target: NodeId,
+    /// Set once the target accepts.
+    open: bool,
+    opened_at: u64,
+    from_origin: Allowance,
+    from_target: Allowance,
+    forwarded: u64,
+    dropped: u64,
+}
+
+/// A circuit this node relays, as listed by `circuits`.
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitInfo {
+    pub id: u32,
+    pub origin: NodeId,
+    pub target: NodeId,
+    pub open: bool,
+    pub age_ms: u64,
+    /// Bytes forwarded, both ways.
+    pub forwarded: u64,
+    /// Frames dropped for going over the cap.
+    pub dropped: u64,
+}
+
+/// A HOP_CONNECT sent to a relay, waiting for its answer.
+struct Attempt {
+    target: NodeId,
+    relay: NodeId,
+    outcome: Option<Result<(), RelayStatus>>,
+}
+
+lazy_static! {
+    static ref CIRCUITS: Mutex<Vec<Circuit>> = Mutex::new(Vec::new());
+    static ref ATTEMPTS: Mutex<Vec<Attempt>> = Mutex::new(Vec::new());
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static NEXT_CIRCUIT: AtomicU32 = AtomicU32::new(1);
+
+/// Register the `p2p.relay.*` keys.
+pub fn register_tunables() {
+    config::register("p2p.relay.max_circuits", "Circuits this node relays at once, when relaying is on", DEFAULT_MAX_CIRCUITS, 1, 1024, None);
+    config::register("p2p.relay.circuit_bytes_per_sec", "Bytes a relayed circuit forwards per second each way", DEFAULT_CIRCUIT_BYTES_PER_SEC, 1024, 16 * 1024 * 1024, None);
+}
+
+/// Turn relaying for other peers on or off. Turning it off closes the
+/// circuits this node relays.
+pub fn set_enabled(on: bool) {
+    ENABLED.store(on, Ordering::Relaxed);
+    if !on {
+        let circuits: Vec<Circuit> = core::mem::take(&mut *CIRCUITS.lock());
+        for circuit in circuits {
+            let _ = send(&circuit.origin, KIND_STOP_CLOSE, circuit.id, &[]);
+            let _ = send(&circuit.target, KIND_STOP_CLOSE, circuit.id, &[]);
+        }
+    }
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// The circuits this node relays.
+pub fn circuits() -> Vec<CircuitInfo> {
+    let now = uptime_ms();
+    CIRCUITS
+        .lock()
+        .iter()
+        .map(|c| CircuitInfo {
+            id: c.id,
+            origin: c.origin,
+            target: c.target,
+            open: c.open,
+            age_ms: now.saturating_sub(c.opened_at),
+            forwarded: c.forwarded,
+            dropped: c.dropped,
+        })
+        .collect()
+}
+
+// ─── Using a Relay ───────────────────────────────────────────────────────────
+
+/// Connect to `target` through a circuit, with each connected peer in turn
+/// as the relay, up to `MAX_RELAYS`.
+pub async fn connect_via_any(target: NodeId) -> Result<(), RpcError> {
+    let relays: Vec<NodeId> = p2p_conn::connections()
+        .iter()
+        .filter(|conn| conn.peer != target && conn.relay.is_none() && conn.protocols.contains(&Protocol::Relay))
+        .map(|conn| conn.peer)
+        .take(MAX_RELAYS)
+        .collect();
+    let mut result = Err(RpcError::NotConnected);
+    for relay in relays {
+        result = connect_via(target, relay).await;
+        match result {
+            Ok(()) => break,
+            Err(RpcError::Relay(status)) => {
+                serial_println!("[P2P] {:?} opened no circuit to {:?}: {:?}", relay, target, status);
+            }
+            Err(_) => {}
+        }
+    }
+    result
+}
+
+/// Ask `relay` for a circuit to `target`, and wait for it to open.
+pub async fn connect_via(target: NodeId, relay: NodeId) -> Result<(), RpcError> {
+    if p2p_reputation::is_banned(Subject::Peer(target)) {
+        return Err(RpcError::Banned);
+    }
+    let Some(key) = local_key() else { return Err(RpcError::NotConnected) };
+    let mut body = Vec::with_capacity(ID_SIZE + KEY_LEN + 128);
+    body.extend_from_slice(&target.0);
+    body.extend_from_slice(key.as_bytes());
+    body.extend_from_slice(&p2p_protocols::local_list());
+    ATTEMPTS.lock().push(Attempt { target, relay, outcome: None });
+    let sent = p2p_conn::send_message(&relay, Protocol::Relay, &frame(KIND_HOP_CONNECT, &body));
+    let deadline = uptime_ms() + RELAY_TIMEOUT_MS;
+    let result = match sent {
+        Ok(()) => loop {
+            let outcome = ATTEMPTS.lock().iter().find(|a| a.target == target && a.relay == relay).map(|a| a.outcome);
+            match outcome.flatten() {
+                Some(outcome) => break outcome.map_err(RpcError::Relay),
+                None if uptime_ms() >= deadline => break Err(RpcError::Timeout),
+                None => executor::sleep_ms(RELAY_POLL_MS).await,
+            }
+        },
+        Err(e) => Err(e),
+    };
+    ATTEMPTS.lock().retain(|a| !(a.target == target && a.relay == relay));
+    result
+}
+
+/// Send `frame` over circuit `circuit` of `relay`.
+pub fn send_frame(relay: &NodeId, circuit: u32, frame: &[u8]) -> Result<(), RpcError> {
+    send(relay, KIND_HOP_DATA, circuit, frame)
+}
+
+/// Tell `relay` that this end of circuit `circuit` is done with it.
+pub fn close(relay: &NodeId, circuit: u32) {
+    let _ = send(relay, KIND_HOP_CLOSE, circuit, &[]);
+}
+
+/// The connection to `peer` has closed: close the circuits it was an end
+/// of, and give up on circuits asked of it.
+pub fn peer_disconnected(peer: NodeId) {
+    let closed: Vec<(u32, NodeId)> = {
+        let mut circuits = CIRCUITS.lock();
+        let closed = circuits
+            .iter()
+            .filter(|c| c.origin == peer || c.target == peer)
+            .map(|c| (c.id, if c.origin == peer { c.target } else { c.origin }))
+            .collect();
+        circuits.retain(|c| c.origin != peer && c.target != peer);
+        closed
+    };
+    for (circuit, other) in closed {
+        let _ = send(&other, KIND_STOP_CLOSE, circuit, &[]);
+    }
+    for attempt in ATTEMPTS.lock().iter_mut().filter(|a| a.relay == peer && a.outcome.is_none()) {
+        attempt.outcome = Some(Err(RelayStatus::Unreachable));
+    }
+}
+
+// ─── Messages ────────────────────────────────────────────────────────────────
+
+/// Handle a relay `message` from `peer`. False if it was malformed, and
+/// the connection should close.
+pub(crate) fn handle(message: &[u8], peer: NodeId) -> bool {
+    let Some(body) = message.get(HEADER_LEN..) else { return false };
+    let handled = match message[0] {
+        KIND_HOP_CONNECT => hop_connect(peer, body),
+        KIND_HOP_STATUS => hop_status(peer, body),
+        KIND_STOP_CONNECT => stop_connect(peer, body),
+        KIND_STOP_REPLY => stop_reply(peer, body),
+        KIND_HOP_DATA => hop_data(peer, body),
+        KIND_STOP_DATA => split_circuit(body).map(|(circuit, frame)| {
+            if !p2p_conn::receive_relayed(&peer, circuit, frame) {
+                close(&peer, circuit);
+            }
+        }),
+        KIND_HOP_CLOSE => split_circuit(body).map(|(circuit, _)| hop_close(peer, circuit)),
+        KIND_STOP_CLOSE => split_circuit(body).map(|(circuit, _)| p2p_conn::close_relayed(&peer, circuit)),
+        _ => None,
+    };
+    handled.is_some()
+}
+
+/// `origin` asks for a circuit to the target.
+fn hop_connect(origin: NodeId, body: &[u8]) -> Option<()> {
+    let (target, rest) = split_id(body)?;
+    let (key, list) = split_key(rest, &origin)?;
+    p2p_protocols::parse_list(list)?;
+    if let Err(status) = open_circuit(origin, target, &key, list) {
+        let mut reply = target.0.to_vec();
+        reply.push(status.code());
+        let _ = p2p_conn::send_message(&origin, Protocol::Relay, &frame(KIND_HOP_STATUS, &reply));
+    }
+    Some(())
+}
+
+/// Start a circuit from `origin`, whose key is `key` and protocol list
+/// `list`, to `target`, and ask `target` to accept it.
+fn open_circuit(origin: NodeId, target: NodeId, key: &VerifyingKey, list: &[u8]) -> Result<(), RelayStatus> {
+    if !enabled() {
+        return Err(RelayStatus::Disabled);
+    }
+    let reachable = p2p_conn::connections()
+        .iter()
+        .any(|conn| conn.peer == target && conn.relay.is_none() && conn.protocols.contains(&Protocol::Relay));
+    if target == origin || !reachable {
+        return Err(RelayStatus::Unreachable);
+    }
+    let now = uptime_ms();
+    let id = {
+        let mut circuits = CIRCUITS.lock();
+        circuits.retain(|c| c.open || now.saturating_sub(c.opened_at) < RELAY_TIMEOUT_MS);
+        if circuits.len() as u64 >= config::get_or("p2p.relay.max_circuits", DEFAULT_MAX_CIRCUITS) {
+            return Err(RelayStatus::NoRoom);
+        }
+        let id = NEXT_CIRCUIT.fetch_add(1, Ordering::Relaxed);
+        circuits.push(Circuit {
+            id,
+            origin,
+            target,
+            open: false,
+            opened_at: now,
+            from_origin: Allowance::new(now),
+            from_target: Allowance::new(now),
+            forwarded: 0,
+            dropped: 0,
+        });
+        id
+    };
+    let mut body = Vec::with_capacity(ID_SIZE + KEY_LEN + list.len());
+    body.extend_from_slice(&origin.0);
+    body.extend_from_slice(key.as_bytes());
+    body.extend_from_slice(list);
+    if send(&target, KIND_STOP_CONNECT, id, &body).is_err() {
+        CIRCUITS.lock().retain(|c| c.id != id);
+        return Err(RelayStatus::Unreachable);
+    }
+    Ok(())
+}
+
+/// The target of circuit `circuit` answered whether it accepts it.
+fn stop_reply(target: NodeId, body: &[u8]) -> Option<()> {
+    let (circuit, rest) = split_circuit(body)?;
+    let (&status, rest) = rest.split_first()?;
+    let accepted = if status == STATUS_OK {
+        let (key, list) = split_key(rest, &target)?;
+        p2p_protocols::parse_list(list)?;
+        Some((key, list))
+    } else {
+        None
+    };
+    let origin = {
+        let mut circuits = CIRCUITS.lock();
+        let idx = circuits.iter().position(|c| c.id == circuit && c.target == target && !c.open)?;
+        if accepted.is_some() {
+            circuits[idx].open = true;
+            circuits[idx].origin
+        } else {
+            circuits.swap_remove(idx).origin
+        }
+    };
+    let mut reply = target.0.to_vec();
+    match accepted {
+        Some((key, list)) => {
+            serial_println!("[P2P] Relaying circuit {} between {:?} and {:?}.", circuit, origin, target);
+            reply.push(STATUS_OK);
+            reply.extend_from_slice(&circuit.to_le_bytes());
+            reply.extend_from_slice(key.as_bytes());
+            reply.extend_from_slice(list);
+        }
+        None => reply.push(status),
+    }
+    if p2p_conn::send_message(&origin, Protocol::Relay, &frame(KIND_HOP_STATUS, &reply)).is_err() {
+        hop_close(origin, circuit);
+    }
+    Some(())
+}
+
+/// `relay` answered a HOP_CONNECT.
+fn hop_status(relay: NodeId, body: &[u8]) -> Option<()> {
+    let (target, rest) = split_id(body)?;
+    let (&status, rest) = rest.split_first()?;
+    let opened = if status == STATUS_OK {
+        let (circuit, rest) = split_circuit(rest)?;
+        let (key, list) = split_key(rest, &target)?;
+        Some((circuit, key, p2p_protocols::parse_list(list)?))
+    } else {
+        None
+    };
+    let asked = ATTEMPTS.lock().iter().any(|a| a.target == target && a.relay == relay && a.outcome.is_none());
+    let outcome = match opened {
+        Some((circuit, _, _)) if !asked => {
+            close(&relay, circuit);
+            return Some(());
+        }
+        None if !asked => return Some(()),
+        Some((circuit, key, protocols)) => {
+            if protocols.protocols().is_empty() {
+                close(&relay, circuit);
+                Err(RelayStatus::Refused)
+            } else {
+                p2p_conn::adopt_relayed(target, key, relay, circuit, Direction::Outbound, protocols);
+                Ok(())
+            }
+        }
+        None => Err(RelayStatus::from_code(status)),
+    };
+    for attempt in ATTEMPTS.lock().iter_mut().filter(|a| a.target == target && a.relay == relay) {
+        attempt.outcome = Some(outcome);
+    }
+    Some(())
+}
+
+/// `relay` asks this node to accept circuit `circuit` from another peer.
+fn stop_connect(relay: NodeId, body: &[u8]) -> Option<()> {
+    let (circuit, rest) = split_circuit(body)?;
+    let (origin, rest) = split_id(rest)?;
+    let (key, list) = split_key(rest, &origin)?;
+    let protocols = p2p_protocols::parse_list(list)?;
+    let local = local_key();
+    let mut reply = Vec::with_capacity(1 + KEY_LEN + 128);
+    match local {
+        Some(local)
+            if !p2p_reputation::is_banned(Subject::Peer(origin))
+                && !p2p_conn::is_connected(&origin)
+                && !protocols.protocols().is_empty() =>
+        {
+            p2p_conn::adopt_relayed(origin, key, relay, circuit, Direction::Inbound, protocols);
+            serial_println!("[P2P] Accepted a connection from {:?} through {:?}.", origin, relay);
+            reply.push(STATUS_OK);
+            reply.extend_from_slice(local.as_bytes());
+            reply.extend_from_slice(&p2p_protocols::local_list());
+        }
+        _ => reply.push(RelayStatus::Refused.code()),
+    }
+    let _ = send(&relay, KIND_STOP_REPLY, circuit, &reply);
+    Some(())
+}
+
+/// Forward `frame` from `from`, one end of circuit `circuit`, to the
+/// other, if the circuit's cap allows.
+fn hop_data(from: NodeId, body: &[u8]) -> Option<()> {
+    let (circuit, data) = split_circuit(body)?;
+    if data.len() > MAX_RELAYED_LEN {
+        return None;
+    }
+    let now = uptime_ms();
+    let to = {
+        let mut circuits = CIRCUITS.lock();
+        let Some(c) = circuits.iter_mut().find(|c| c.id == circuit && c.open && (c.origin == from || c.target == from))
+        else {
+            drop(circuits);
+            let _ = send(&from, KIND_STOP_CLOSE, circuit, &[]);
+            return Some(());
+        };
+        let (allowance, to) =
+            if c.origin == from { (&mut c.from_origin, c.target) } else { (&mut c.from_target, c.origin) };
+        if !allowance.take(data.len(), now) {
+            c.dropped += 1;
+            return Some(());
+        }
+        c.forwarded += data.len() as u64;
+        to
+    };
+    if let Err(RpcError::NotConnected) = send(&to, KIND_STOP_DATA, circuit, data) {
+        hop_close(to, circuit);
+    }
+    Some(())
+}
+
+/// `from`, one end of circuit `circuit`, is done with it: close it, and
+/// tell the other end.
+fn hop_close(from: NodeId, circuit: u32) {
+    let other = {
+        let mut circuits = CIRCUITS.lock();
+        let Some(idx) = circuits.iter().position(|c| c.id == circuit && (c.origin == from || c.target == from)) else {
+            return;
+        };
+        let c = circuits.swap_remove(idx);
+        if c.origin == from { c.target } else { c.origin }
+    };
+    let _ = send(&other, KIND_STOP_CLOSE, circuit, &[]);
+}
+
+// ─── Encoding ────────────────────────────────────────────────────────────────
+
+fn local_key() -> Option<VerifyingKey> {
+    P2P_STATE.lock().as_ref().map(|state| state.keys.identity.verifying_key())
+}
+
+/// Send `peer` a message of `kind` about circuit `circuit`.
+fn send(peer: &NodeId, kind: u8, circuit: u32, body: &[u8]) -> Result<(), RpcError> {
+    let mut message = Vec::with_capacity(4 + body.len());
+    message.extend_from_slice(&circuit.to_le_bytes());
+    message.extend_from_slice(body);
+    p2p_conn::send_message(peer, Protocol::Relay, &frame(kind, &message))
+}
+
+fn frame(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + body.len());
+    frame.push(kind);
+    frame.extend_from_slice(&0u32.to_le_bytes());
+    frame.extend_from_slice(body);
+    frame
+}
+
+fn split_id(body: &[u8]) -> Option<(NodeId, &[u8])> {
+    let (id, rest) = body.split_first_chunk::<ID_SIZE>()?;
+    Some((NodeId::new(*id), rest))
+}
+
+fn split_circuit(body: &[u8]) -> Option<(u32, &[u8])> {
+    let (circuit, rest) = body.split_first_chunk::<4>()?;
+    Some((u32::from_le_bytes(*circuit), rest))
+}
+
+/// An identity key, which must be `owner`'s, and what follows it.
+fn split_key<'a>(body: &'a [u8], owner: &NodeId) -> Option<(VerifyingKey, &'a [u8])> {
+    let (key, rest) = body.split_first_chunk::<KEY_LEN>()?;
+    let key = VerifyingKey::from_bytes(key).ok()?;
+    (p2p::identity_of(&key).1 == *owner).then_some((key, rest))
+}
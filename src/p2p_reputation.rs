@@ -0,0 +1,264 @@
+//! # Peer Reputation
+//!
+//! Scores peers by how they behave and bans the ones that misbehave, so a
+//! broken or hostile peer can't keep reconnecting and wasting the node's
+//! time. Each offense adds penalty points to its subject: the peer (by
+//! node ID) for what it sends over a secured connection, the address for
+//! failed handshakes, since those happen before the peer has proved who it
+//! is. Points are forgiven over time, one per `p2p.reputation.forgive_ms`.
+//!
+//! A subject whose points reach `p2p.reputation.ban_threshold` is banned
+//! for `p2p.reputation.ban_ms`: its connections are closed, it is dropped
+//! from the routing table, and handshakes with it are refused either way.
+//! Its points are cleared when the ban ends.
+//!
+//! Excessive traffic is a peer sending more than
+//! `p2p.reputation.max_messages_per_sec` messages within a second.
+
+use alloc::vec::Vec;
+use core::fmt;
+use lazy_static::lazy_static;
+use smoltcp::wire::IpAddress;
+use spin::Mutex;
+use crate::interrupts::uptime_ms;
+use crate::p2p::P2P_STATE;
+use crate::p2p_conn;
+use crate::p2p_kademlia::NodeId;
+use crate::{config, serial_println};
+
+/// Subjects tracked at once; past that, the one with the fewest points
+/// that isn't banned is forgotten.
+const MAX_TRACKED: usize = 256;
+/// Default of `p2p.reputation.ban_threshold`.
+const DEFAULT_BAN_THRESHOLD: u64 = 100;
+/// Default of `p2p.reputation.ban_ms`.
+const DEFAULT_BAN_MS: u64 = 10 * 60 * 1000;
+/// Default of `p2p.reputation.forgive_ms`.
+const DEFAULT_FORGIVE_MS: u64 = 6_000;
+/// Default of `p2p.reputation.max_messages_per_sec`.
+const DEFAULT_MAX_MESSAGES_PER_SEC: u64 = 200;
+/// Length of the window messages are counted over.
+const RATE_WINDOW_MS: u64 = 1_000;
+
+/// Something a peer did wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offense {
+    /// A message that didn't decode or wasn't expected.
+    InvalidMessage,
+    /// A message whose signature or sequence number didn't check out.
+    BadSignature,
+    /// A handshake that failed or claimed IDs its key doesn't hash to.
+    HandshakeFailed,
+    /// More than `p2p.reputation.max_messages_per_sec` messages in a second.
+    ExcessiveTraffic,
+}
+
+impl Offense {
+    const ALL: [Offense; 4] =
+        [Offense::InvalidMessage, Offense::BadSignature, Offense::HandshakeFailed, Offense::ExcessiveTraffic];
+
+    fn penalty(self) -> u64 {
+        match self {
+            Offense::InvalidMessage => 20,
+            Offense::BadSignature => 50,
+            Offense::HandshakeFailed => 10,
+            Offense::ExcessiveTraffic => 25,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Who an offense is held against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subject {
+    Peer(NodeId),
+    Address(IpAddress),
+}
+
+impl fmt::Display for Subject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Subject::Peer(node_id) => write!(f, "{:?}", node_id),
+            Subject::Address(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+struct Standing {
+    subject: Subject,
+    points: u64,
+    /// When `points` were last reduced for time passing.
+    forgiven_at: u64,
+    banned_until: Option<u64>,
+    offenses: [u32; Offense::ALL.len()],
+    /// Start of the current rate window, and messages counted in it.
+    window_start: u64,
+    window_messages: u64,
+}
+
+impl Standing {
+    /// Forgive the points earned back since the last call, and lift an
+    /// expired ban.
+    fn settle(&mut self, now: u64, forgive_ms: u64) {
+        if self.banned_until.is_some_and(|until| now >= until) {
+            self.banned_until = None;
+            self.points = 0;
+            self.forgiven_at = now;
+        }
+        let forgiven = now.saturating_sub(self.forgiven_at) / forgive_ms.max(1);
+        if forgiven > 0 {
+            self.points = self.points.saturating_sub(forgiven);
+            self.forgiven_at += forgiven * forgive_ms.max(1);
+        }
+    }
+}
+
+/// A tracked subject, as listed by `standings`.
+#[derive(Debug, Clone, Copy)]
+pub struct StandingInfo {
+    pub subject: Subject,
+    pub points: u64,
+    /// Time left on the ban, if banned.
+    pub banned_ms: Option<u64>,
+    /// Offenses recorded, counted per kind.
+    pub offenses: [(Offense, u32); Offense::ALL.len()],
+}
+
+lazy_static! {
+    static ref STANDINGS: Mutex<Vec<Standing>> = Mutex::new(Vec::new());
+}
+
+/// Register the `p2p.reputation.*` keys.
+pub fn register_tunables() {
+    config::register("p2p.reputation.ban_threshold", "Penalty points that get a peer or address banned", DEFAULT_BAN_THRESHOLD, 10, 10_000, None);
+    config::register("p2p.reputation.ban_ms", "How long a ban lasts", DEFAULT_BAN_MS, 1_000, 7 * 24 * 3_600_000, None);
+    config::register("p2p.reputation.forgive_ms", "Time to forgive one penalty point", DEFAULT_FORGIVE_MS, 100, 3_600_000, None);
+    config::register("p2p.reputation.max_messages_per_sec", "Messages a peer may send per second before it is penalized", DEFAULT_MAX_MESSAGES_PER_SEC, 10, 100_000, None);
+}
+
+/// Hold `offense` against `subject`, banning it if that brings it to the
+/// threshold. Returns whether `subject` is banned.
+pub fn penalize(subject: Subject, offense: Offense) -> bool {
+    let now = uptime_ms();
+    let newly_banned = {
+        let mut standings = STANDINGS.lock();
+        let standing = standing(&mut standings, subject, now);
+        standing.offenses[offense.index()] = standing.offenses[offense.index()].saturating_add(1);
+        if standing.banned_until.is_some() {
+            return true;
+        }
+        standing.points += offense.penalty();
+        if standing.points < config::get_or("p2p.reputation.ban_threshold", DEFAULT_BAN_THRESHOLD) {
+            return false;
+        }
+        standing.banned_until = Some(now + config::get_or("p2p.reputation.ban_ms", DEFAULT_BAN_MS));
+        true
+    };
+    if newly_banned {
+        serial_println!("[P2P] Banned {} after {:?}.", subject, offense);
+        if let Subject::Peer(node_id) = subject {
+            p2p_conn::disconnect(&node_id);
+            if let Some(state) = P2P_STATE.lock().as_mut() {
+                state.routing_table.remove(&node_id);
+            }
+        }
+    }
+    newly_banned
+}
+
+/// Count a message from `peer` against its rate limit, penalizing it once
+/// per window it goes over. Returns whether `peer` is banned.
+pub fn count_message(peer: NodeId) -> bool {
+    let now = uptime_ms();
+    let over = {
+        let mut standings = STANDINGS.lock();
+        let standing = standing(&mut standings, Subject::Peer(peer), now);
+        if now.saturating_sub(standing.window_start) >= RATE_WINDOW_MS {
+            standing.window_start = now;
+            standing.window_messages = 0;
+        }
+        standing.window_messages += 1;
+        let max = config::get_or("p2p.reputation.max_messages_per_sec", DEFAULT_MAX_MESSAGES_PER_SEC);
+        if standing.window_messages != max + 1 {
+            return standing.banned_until.is_some();
+        }
+        true
+    };
+    over && penalize(Subject::Peer(peer), Offense::ExcessiveTraffic)
+}
+
+/// Whether `subject` is banned now.
+pub fn is_banned(subject: Subject) -> bool {
+    let now = uptime_ms();
+    let forgive_ms = config::get_or("p2p.reputation.forgive_ms", DEFAULT_FORGIVE_MS);
+    let mut standings = STANDINGS.lock();
+    let Some(standing) = standings.iter_mut().find(|s| s.subject == subject) else { return false };
+    standing.settle(now, forgive_ms);
+    standing.banned_until.is_some()
+}
+
+/// Lift `subject`'s ban and forget its points. False if it isn't tracked.
+pub fn unban(subject: Subject) -> bool {
+    let mut standings = STANDINGS.lock();
+    let before = standings.len();
+    standings.retain(|s| s.subject != subject);
+    standings.len() != before
+}
+
+/// Every tracked subject, banned ones first, then by points.
+pub fn standings() -> Vec<StandingInfo> {
+    let now = uptime_ms();
+    let forgive_ms = config::get_or("p2p.reputation.forgive_ms", DEFAULT_FORGIVE_MS);
+    let mut standings = STANDINGS.lock();
+    let mut list: Vec<StandingInfo> = standings
+        .iter_mut()
+        .map(|s| {
+            s.settle(now, forgive_ms);
+            StandingInfo {
+                subject: s.subject,
+                points: s.points,
+                banned_ms: s.banned_until.map(|until| until - now),
+                offenses: Offense::ALL.map(|offense| (offense, s.offenses[offense.index()])),
+            }
+        })
+        .collect();
+    list.sort_by_key(|s| (s.banned_ms.is_none(), core::cmp::Reverse(s.points)));
+    list
+}
+
+/// `subject`'s standing, settled to `now`; tracked from now if it wasn't.
+fn standing(standings: &mut Vec<Standing>, subject: Subject, now: u64) -> &mut Standing {
+    let forgive_ms = config::get_or("p2p.reputation.forgive_ms", DEFAULT_FORGIVE_MS);
+    let idx = match standings.iter().position(|s| s.subject == subject) {
+        Some(idx) => idx,
+        None => {
+            if standings.len() >= MAX_TRACKED {
+                let forgotten = standings
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, s)| s.banned_until.is_none())
+                    .min_by_key(|(_, s)| s.points)
+                    .map(|(idx, _)| idx);
+                if let Some(idx) = forgotten {
+                    standings.swap_remove(idx);
+                }
+            }
+            standings.push(Standing {
+                subject,
+                points: 0,
+                forgiven_at: now,
+                banned_until: None,
+                offenses: [0; Offense::ALL.len()],
+                window_start: now,
+                window_messages: 0,
+            });
+            standings.len() - 1
+        }
+    };
+    let standing = &mut standings[idx];
+    standing.settle(now, forgive_ms);
+    standing
+}
@@ -0,0 +1,939 @@
+//! # Kademlia RPCs
+//!
+//! Requests peers answer over the connections `p2p_conn` keeps open, and
+//! the node lookup and record publishing built on them. Either side of a
+//! connection may ask; a reply carries the id of its request, so several
+//! can be in flight on one connection.
+//!
+//! ## Wire format
+//! Every message is one frame holding a protobuf `Message` (see
+//! `p2p_proto`), behind the tag of its protocol (see `p2p_protocols`), and
+//! sealed by `p2p_sign` with the sender's signature and a sequence number
+//! the receiver checks for replays. WANT_BLOCK and BLOCK are the blocks
+//! protocol, IDENTIFY and IDENTIFIED the identify protocol, and the rest
+//! kad.
+//!
+//! ```text
+//! message Message {
+//!   uint32        type      = 1;   // the kind, below
+//!   uint32        id        = 2;   // request id
+//!   bytes         key       = 3;   // 32 bytes
+//!   uint32        ttl_secs  = 4;
+//!   bytes         signature = 5;   // record signature, 64 bytes
+//!   bytes         value     = 6;
+//!   repeated Peer closer    = 7;
+//!   repeated Peer providers = 8;
+//!   bytes         publisher = 9;   // publisher key, 32 bytes
+//!   uint32        status    = 10;  // 0, or the `StoreError` code
+//!   Endpoint      observed  = 11;  // see `p2p_proto`
+//!   string        agent     = 12;
+//! }
+//! message Peer {
+//!   bytes    id       = 1;  // `NodeId`, 32 bytes
+//!   Endpoint endpoint = 2;
+//! }
+//! ```
+//!
+//! | type | message       | fields                                        |
+//! |------|---------------|-----------------------------------------------|
+//! | 0x01 | FIND_NODE     | key: the target                               |
+//! | 0x02 | NODES         | closer                                        |
+//! | 0x03 | STORE         | key, ttl_secs, signature, value, publisher    |
+//! | 0x04 | STORED        | status                                        |
+//! | 0x05 | FIND_VALUE    | key                                           |
+//! | 0x06 | VALUE         | ttl_secs: seconds left, publisher, signature, value |
+//! | 0x07 | PING          |                                               |
+//! | 0x08 | PONG          |                                               |
+//! | 0x09 | WANT_BLOCK    | key: the block hash                           |
+//! | 0x0a | BLOCK         | value: the block, if found                    |
+//! | 0x0b | PROVIDE       | key, ttl_secs                                 |
+//! | 0x0c | GET_PROVIDERS | key                                           |
+//! | 0x0d | PROVIDERS     | providers, closer                             |
+//! | 0x0e | IDENTIFY      |                                               |
+//! | 0x0f | IDENTIFIED    | observed, if known; agent                     |
+//!
+//! A field a message doesn't list is ignored, as are unknown fields. A
+//! message missing a field it needs is malformed.
+//!
+//! A reply carries the id of its request. NODES answers FIND_NODE with up
+//! to `K_BUCKET_SIZE` contacts from the routing table closest to the
+//! target, leaving out the asker and peers whose endpoint isn't in the
+//! address book. STORE puts the record in `p2p_store` on behalf of its
+//! publisher, if the publisher signed it; a publisher other than the
+//! asker makes it a replica the asker passed on, and none means the
+//! asker. FIND_VALUE gets VALUE if the record is here, else NODES as for
+//! FIND_NODE. PING gets PONG; `p2p` pings the least-recently-seen peer of
+//! a full k-bucket before giving its slot away. WANT_BLOCK gets BLOCK with
+//! the block from `p2p_blocks` if this node has it. PROVIDE records the
+//! asker as a provider of the key in `p2p_store`, at its address-book
+//! endpoint, and gets STORED. GET_PROVIDERS gets PROVIDERS: the providers
+//! known for the key and, as for FIND_NODE, the contacts closest to it.
+//! IDENTIFY gets IDENTIFIED: this node's agent string and the endpoint it
+//! has for the asker, which is where the asker can be dialed from here.
+//! Gossipsub messages belong to `p2p_pubsub`, hole-punching ones to
+//! `p2p_nat` and channel ones to `p2p_channel`. Any other message, or one
+//! under the wrong protocol, ends the connection.
+//!
+//! ## Lookups
+//! `lookup` walks toward a target. It starts from the closest contacts in
+//! the routing table, asks the `ALPHA` closest it hasn't asked yet at once,
+//! merges what they answer into its shortlist, and repeats until the
+//! `K_BUCKET_SIZE` closest contacts that haven't failed have all answered.
+//! `get` walks the same way with FIND_VALUE and stops at the first VALUE
+//! whose record signature checks out;
+//! `publish` looks the key up and sends STORE to the nodes it found.
+//! `provide` does the same with PROVIDE, and `find_providers` walks with
+//! GET_PROVIDERS until an answer lists providers.
+//!
+//! ## Blocks
+//! `fetch_block` asks the peers this node is connected to for a block,
+//! `ALPHA` at a time, until one sends a block whose hash matches; it is
+//! cached in `p2p_blocks`. If none has it, it asks the key's providers,
+//! dialing them. `fetch_object` fetches an object's manifest and
+//! then its chunks.
+//!
+//! A contact without an open connection is dialed and handshaken with
+//! first, so every peer that answers also lands in the routing table. Dial
+//! failures go to the address book, and contacts it knows to be
+//! unreachable are skipped.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::future::{poll_fn, Future};
+use core::pin::{pin, Pin};
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::task::Poll;
+use smoltcp::wire::IpEndpoint;
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use crate::addr_book::ADDRESS_BOOK;
+use crate::interrupts::uptime_ms;
+use crate::net_stack::ConnectError;
+use crate::p2p_blocks::{self, BlockError};
+use crate::p2p_channel;
+use crate::p2p::{self, P2P_STATE};
+use crate::p2p_conn;
+use crate::p2p_kademlia::{NodeId, ID_SIZE, K_BUCKET_SIZE};
+use crate::p2p_metrics;
+use crate::p2p_nat;
+use crate::p2p_protocols::Protocol;
+use crate::p2p_proto::{self, Writer};
+use crate::p2p_pubsub;
+use crate::p2p_relay::{self, RelayStatus};
+use crate::p2p_sign::{self, SIGNATURE_LEN};
+use crate::p2p_store::{self, Provenance, StoreError};
+use crate::p2p_transport::Deadline;
+use crate::serial_println;
+
+/// Requests a lookup has in flight at once.
+pub const ALPHA: usize = 3;
+/// Give up on a connected peer that hasn't answered after this long.
+const RPC_TIMEOUT_MS: u64 = 5_000;
+/// Contacts a lookup keeps in its shortlist.
+const MAX_SHORTLIST: usize = 4 * K_BUCKET_SIZE;
+
+const KIND_FIND_NODE: u8 = 0x01;
+const KIND_NODES: u8 = 0x02;
+const KIND_STORE: u8 = 0x03;
+const KIND_STORED: u8 = 0x04;
+const KIND_FIND_VALUE: u8 = 0x05;
+const KIND_VALUE: u8 = 0x06;
+const KIND_PING: u8 = 0x07;
+const KIND_PONG: u8 = 0x08;
+const KIND_WANT_BLOCK: u8 = 0x09;
+const KIND_BLOCK: u8 = 0x0a;
+const KIND_PROVIDE: u8 = 0x0b;
+const KIND_GET_PROVIDERS: u8 = 0x0c;
+const KIND_PROVIDERS: u8 = 0x0d;
+const KIND_IDENTIFY: u8 = 0x0e;
+const KIND_IDENTIFIED: u8 = 0x0f;
+
+/// What IDENTIFIED says this node runs.
+const AGENT: &str = concat!("kernel/", env!("CARGO_PKG_VERSION"));
+
+static NEXT_REQUEST: AtomicU32 = AtomicU32::new(1);
+
+/// A peer and where to dial it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Contact {
+    pub node_id: NodeId,
+    pub endpoint: IpEndpoint,
+}
+
+/// Why a request failed.
+#[derive(Debug, Clone, Copy)]
+pub enum RpcError {
+    Connect(ConnectError),
+    Handshake,
+    /// The connection closed before the reply came.
+    Closed,
+    /// The answer wasn't a reply to the request.
+    Malformed,
+    /// The peer said it is a different node than the contact.
+    WrongNode,
+    /// No answer within `RPC_TIMEOUT_MS`.
+    Timeout,
+    /// The peer didn't store the record.
+    Refused(StoreError),
+    /// No connection to the peer is open.
+    NotConnected,
+    /// Too many messages are queued for the peer already.
+    Busy,
+    /// The peer is banned for misbehaving (see `p2p_reputation`).
+    Banned,
+    /// The connection didn't negotiate the request's protocol.
+    Unsupported,
+    /// A relay didn't open a circuit (see `p2p_relay`).
+    Relay(RelayStatus),
+}
+
+enum Message {
+    FindNode { id: u32, target: NodeId },
+    Nodes { id: u32, contacts: Vec<Contact> },
+    Store {
+        id: u32,
+        key: NodeId,
+        ttl_secs: u32,
+        signature: [u8; SIGNATURE_LEN],
+        value: Vec<u8>,
+        publisher: Option<[u8; 32]>,
+    },
+    Stored { id: u32, result: Result<(), StoreError> },
+    FindValue { id: u32, key: NodeId },
+    Value { id: u32, ttl_secs: u32, provenance: Provenance, value: Vec<u8> },
+    Ping { id: u32 },
+    Pong { id: u32 },
+    WantBlock { id: u32, hash: NodeId },
+    Block { id: u32, data: Option<Vec<u8>> },
+    Provide { id: u32, key: NodeId, ttl_secs: u32 },
+    GetProviders { id: u32, key: NodeId },
+    Providers { id: u32, providers: Vec<Contact>, contacts: Vec<Contact> },
+    Identify { id: u32 },
+    Identified { id: u32, observed: Option<IpEndpoint>, agent: String },
+}
+
+impl Message {
+    fn id(&self) -> u32 {
+        match *self {
+            Message::FindNode { id, .. }
+            | Message::Nodes { id, .. }
+            | Message::Store { id, .. }
+            | Message::Stored { id, .. }
+            | Message::FindValue { id, .. }
+            | Message::Value { id, .. }
+            | Message::Ping { id }
+            | Message::Pong { id }
+            | Message::WantBlock { id, .. }
+            | Message::Block { id, .. }
+            | Message::Provide { id, .. }
+            | Message::GetProviders { id, .. }
+            | Message::Providers { id, .. }
+            | Message::Identify { id }
+            | Message::Identified { id, .. } => id,
+        }
+    }
+
+    /// The message's type, as the doc table names it.
+    fn name(&self) -> &'static str {
+        match self {
+            Message::FindNode { .. } => "FIND_NODE",
+            Message::Nodes { .. } => "NODES",
+            Message::Store { .. } => "STORE",
+            Message::Stored { .. } => "STORED",
+            Message::FindValue { .. } => "FIND_VALUE",
+            Message::Value { .. } => "VALUE",
+            Message::Ping { .. } => "PING",
+            Message::Pong { .. } => "PONG",
+            Message::WantBlock { .. } => "WANT_BLOCK",
+            Message::Block { .. } => "BLOCK",
+            Message::Provide { .. } => "PROVIDE",
+            Message::GetProviders { .. } => "GET_PROVIDERS",
+            Message::Providers { .. } => "PROVIDERS",
+            Message::Identify { .. } => "IDENTIFY",
+            Message::Identified { .. } => "IDENTIFIED",
+        }
+    }
+
+    fn protocol(&self) -> Protocol {
+        match self {
+            Message::WantBlock { .. } | Message::Block { .. } => Protocol::Blocks,
+            Message::Identify { .. } | Message::Identified { .. } => Protocol::Identify,
+            _ => Protocol::Kad,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let kind = match self {
+            Message::FindNode { .. } => KIND_FIND_NODE,
+            Message::Nodes { .. } => KIND_NODES,
+            Message::Store { .. } => KIND_STORE,
+            Message::Stored { .. } => KIND_STORED,
+            Message::FindValue { .. } => KIND_FIND_VALUE,
+            Message::Value { .. } => KIND_VALUE,
+            Message::Ping { .. } => KIND_PING,
+            Message::Pong { .. } => KIND_PONG,
+            Message::WantBlock { .. } => KIND_WANT_BLOCK,
+            Message::Block { .. } => KIND_BLOCK,
+            Message::Provide { .. } => KIND_PROVIDE,
+            Message::GetProviders { .. } => KIND_GET_PROVIDERS,
+            Message::Providers { .. } => KIND_PROVIDERS,
+            Message::Identify { .. } => KIND_IDENTIFY,
+            Message::Identified { .. } => KIND_IDENTIFIED,
+        };
+        let mut message = Writer::with_capacity(16 + ID_SIZE);
+        message.uint(1, u64::from(kind)).uint(2, u64::from(self.id()));
+        match self {
+            Message::FindNode { target: key, .. }
+            | Message::FindValue { key, .. }
+            | Message::WantBlock { hash: key, .. }
+            | Message::GetProviders { key, .. } => {
+                message.bytes(3, &key.0);
+            }
+            Message::Nodes { contacts, .. } => push_contacts(&mut message, 7, contacts),
+            Message::Providers { providers, contacts, .. } => {
+                push_contacts(&mut message, 8, providers);
+                push_contacts(&mut message, 7, contacts);
+            }
+            Message::Provide { key, ttl_secs, .. } => {
+                message.bytes(3, &key.0).uint(4, u64::from(*ttl_secs));
+            }
+            Message::Store { key, ttl_secs, signature, value, publisher, .. } => {
+                message.bytes(3, &key.0).uint(4, u64::from(*ttl_secs)).bytes(5, signature).bytes(6, value);
+                if let Some(publisher) = publisher {
+                    message.bytes(9, publisher);
+                }
+            }
+            Message::Stored { result, .. } => {
+                message.uint(10, u64::from(result.err().map_or(0, StoreError::code)));
+            }
+            Message::Value { ttl_secs, provenance, value, .. } => {
+                message
+                    .uint(4, u64::from(*ttl_secs))
+                    .bytes(9, &provenance.identity)
+                    .bytes(5, &provenance.signature)
+                    .bytes(6, value);
+            }
+            Message::Block { data, .. } => {
+                if let Some(data) = data {
+                    message.bytes(6, data);
+                }
+            }
+            Message::Identified { observed, agent, .. } => {
+                if let Some(observed) = observed {
+                    message.bytes(11, &p2p_proto::encode_endpoint(observed));
+                }
+                message.string(12, agent);
+            }
+            Message::Ping { .. } | Message::Pong { .. } | Message::Identify { .. } => {}
+        }
+        message.finish()
+    }
+
+    fn decode(message: &[u8]) -> Option<Message> {
+        let mut fields = Fields::default();
+        for field in p2p_proto::fields(message) {
+            match field? {
+                (1, value) => fields.kind = Some(value.u32()?),
+                (2, value) => fields.id = Some(value.u32()?),
+                (3, value) => fields.key = Some(NodeId::new(value.array()?)),
+                (4, value) => fields.ttl_secs = Some(value.u32()?),
+                (5, value) => fields.signature = Some(value.array()?),
+                (6, value) => fields.value = Some(value.bytes()?.to_vec()),
+                (7, value) => fields.closer.push(decode_contact(value.bytes()?)?),
+                (8, value) => fields.providers.push(decode_contact(value.bytes()?)?),
+                (9, value) => fields.publisher = Some(value.array()?),
+                (10, value) => fields.status = value.u32()?,
+                (11, value) => fields.observed = Some(p2p_proto::decode_endpoint(value.bytes()?)?),
+                (12, value) => fields.agent = String::from(value.str()?),
+                _ => {}
+            }
+        }
+        let id = fields.id?;
+        match u8::try_from(fields.kind?).ok()? {
+            KIND_FIND_NODE => Some(Message::FindNode { id, target: fields.key? }),
+            KIND_FIND_VALUE => Some(Message::FindValue { id, key: fields.key? }),
+            KIND_WANT_BLOCK => Some(Message::WantBlock { id, hash: fields.key? }),
+            KIND_BLOCK => Some(Message::Block { id, data: fields.value }),
+            KIND_NODES => Some(Message::Nodes { id, contacts: fields.closer }),
+            KIND_PROVIDERS => Some(Message::Providers { id, providers: fields.providers, contacts: fields.closer }),
+            KIND_PROVIDE => Some(Message::Provide { id, key: fields.key?, ttl_secs: fields.ttl_secs? }),
+            KIND_GET_PROVIDERS => Some(Message::GetProviders { id, key: fields.key? }),
+            KIND_STORE => Some(Message::Store {
+                id,
+                key: fields.key?,
+                ttl_secs: fields.ttl_secs?,
+                signature: fields.signature?,
+                value: fields.value?,
+                publisher: fields.publisher,
+            }),
+            KIND_STORED => {
+                let result = match u8::try_from(fields.status).ok()? {
+                    0 => Ok(()),
+                    code => Err(StoreError::from_code(code)?),
+                };
+                Some(Message::Stored { id, result })
+            }
+            KIND_VALUE => {
+                let provenance = Provenance { identity: fields.publisher?, signature: fields.signature? };
+                Some(Message::Value { id, ttl_secs: fields.ttl_secs?, provenance, value: fields.value? })
+            }
+            KIND_PING => Some(Message::Ping { id }),
+            KIND_PONG => Some(Message::Pong { id }),
+            KIND_IDENTIFY => Some(Message::Identify { id }),
+            KIND_IDENTIFIED => Some(Message::Identified { id, observed: fields.observed, agent: fields.agent }),
+            _ => None,
+        }
+    }
+}
+
+/// The fields of a `Message` as read, before they are checked against its
+/// type.
+#[derive(Default)]
+struct Fields {
+    kind: Option<u32>,
+    id: Option<u32>,
+    key: Option<NodeId>,
+    ttl_secs: Option<u32>,
+    signature: Option<[u8; SIGNATURE_LEN]>,
+    value: Option<Vec<u8>>,
+    closer: Vec<Contact>,
+    providers: Vec<Contact>,
+    publisher: Option<[u8; 32]>,
+    status: u32,
+    observed: Option<IpEndpoint>,
+    agent: String,
+}
+
+/// Append `contacts` as `Peer` messages in `field`.
+fn push_contacts(message: &mut Writer, field: u32, contacts: &[Contact]) {
+    for contact in contacts {
+        let peer = Writer::with_capacity(48)
+            .bytes(1, &contact.node_id.0)
+            .bytes(2, &p2p_proto::encode_endpoint(&contact.endpoint))
+            .finish();
+        message.bytes(field, &peer);
+    }
+}
+
+/// A `Peer` message.
+fn decode_contact(message: &[u8]) -> Option<Contact> {
+    let (mut node_id, mut endpoint) = (None, None);
+    for field in p2p_proto::fields(message) {
+        match field? {
+            (1, value) => node_id = Some(NodeId::new(value.array()?)),
+            (2, value) => endpoint = Some(p2p_proto::decode_endpoint(value.bytes()?)?),
+            _ => {}
+        }
+    }
+    Some(Contact { node_id: node_id?, endpoint: endpoint? })
+}
+
+fn next_id() -> u32 {
+    NEXT_REQUEST.fetch_add(1, Ordering::Relaxed)
+}
+
+fn local_id() -> Option<NodeId> {
+    P2P_STATE.lock().as_ref().map(|state| state.node_id)
+}
+
+/// This node's ID and the identity key it signs with.
+fn local_signer() -> Option<(NodeId, SigningKey)> {
+    P2P_STATE.lock().as_ref().map(|state| (state.node_id, state.keys.identity.clone()))
+}
+
+/// Where to dial `node_id`: its address-book endpoint, else the one in
+/// the routing table.
+pub(crate) fn peer_endpoint(node_id: &NodeId, now: u64) -> Option<IpEndpoint> {
+    if let Some(endpoint) = ADDRESS_BOOK.lock().peer_endpoint(node_id, now) {
+        return Some(endpoint);
+    }
+    P2P_STATE.lock().as_ref()?.routing_table.peer(node_id)?.endpoint
+}
+
+/// The contacts closest to `target` from the routing table that have a
+/// known endpoint, without `exclude`. The address book's endpoint is
+/// fresher than the routing table's, so it wins.
+fn closest_contacts(target: &NodeId, exclude: &NodeId) -> Vec<Contact> {
+    let peers = match P2P_STATE.lock().as_ref() {
+        Some(state) => state.routing_table.find_closest(target, usize::MAX),
+        None => return Vec::new(),
+    };
+    let now = uptime_ms();
+    let book = ADDRESS_BOOK.lock();
+    peers
+        .iter()
+        .filter(|peer| peer.node_id != *exclude)
+        .filter_map(|peer| {
+            let endpoint = book.peer_endpoint(&peer.node_id, now).or(peer.endpoint)?;
+            Some(Contact { node_id: peer.node_id, endpoint })
+        })
+        .take(K_BUCKET_SIZE)
+        .collect()
+}
+
+// ─── Serving ─────────────────────────────────────────────────────────────────
+
+/// What `p2p_conn` should do with a message a peer sent.
+pub(crate) enum Incoming {
+    /// A request; send this reply.
+    Answer(Vec<u8>),
+    /// The reply to this node's request with this id.
+    Reply(u32),
+    /// A pubsub, hole-punching, relay or channel message, which
+    /// `p2p_pubsub`, `p2p_nat`, `p2p_relay` or `p2p_channel` took care of.
+    Handled,
+    /// Neither; the connection should close.
+    Invalid,
+}
+
+/// Sort out `message` from `peer`, whose identity key is `publisher`, and
+/// answer it if it is a request.
+pub(crate) fn dispatch(protocol: Protocol, message: &[u8], peer: NodeId, publisher: [u8; 32]) -> Incoming {
+    let handled = match protocol {
+        Protocol::Gossipsub => Some(p2p_pubsub::handle(message, peer)),
+        Protocol::HolePunch => Some(p2p_nat::handle(message, peer)),
+        Protocol::Relay => Some(p2p_relay::handle(message, peer)),
+        Protocol::Channel => Some(p2p_channel::handle(message, peer)),
+        _ => None,
+    };
+    if let Some(handled) = handled {
+        return if handled { Incoming::Handled } else { Incoming::Invalid };
+    }
+    let message = Message::decode(message).filter(|message| message.protocol() == protocol);
+    if let Some(message) = &message {
+        p2p_metrics::rpc_received(message.name());
+    }
+    match message {
+        Some(
+            reply @ (Message::Nodes { .. }
+            | Message::Stored { .. }
+            | Message::Value { .. }
+            | Message::Pong { .. }
+            | Message::Block { .. }
+            | Message::Providers { .. }
+            | Message::Identified { .. }),
+        ) => Incoming::Reply(reply.id()),
+        Some(request) => match answer(request, peer, publisher) {
+            Some(reply) => {
+                p2p_metrics::rpc_sent(reply.name());
+                Incoming::Answer(reply.encode())
+            }
+            None => Incoming::Invalid,
+        },
+        None => Incoming::Invalid,
+    }
+}
+
+/// The reply to `request` from `peer`, whose identity key is `publisher`.
+fn answer(request: Message, peer: NodeId, publisher: [u8; 32]) -> Option<Message> {
+    let now = uptime_ms();
+    match request {
+        Message::FindNode { id, target } => Some(Message::Nodes { id, contacts: closest_contacts(&target, &peer) }),
+        Message::Store { id, key, ttl_secs, signature, value, publisher: origin } => {
+            let ttl_ms = u64::from(ttl_secs) * 1000;
+            let result = match origin.filter(|origin| *origin != publisher) {
+                None => {
+                    let provenance = Provenance { identity: publisher, signature };
+                    p2p_store::put(key, &value, peer, provenance, ttl_ms, now)
+                }
+                Some(origin) => match VerifyingKey::from_bytes(&origin) {
+                    Ok(origin_key) => {
+                        let provenance = Provenance { identity: origin, signature };
+                        p2p_store::put_replica(key, &value, p2p::identity_of(&origin_key).1, provenance, ttl_ms, now)
+                    }
+                    Err(_) => Err(StoreError::BadSignature),
+                },
+            };
+            if let Err(e) = result {
+                serial_println!("[P2P] Refused a record from {:?}: {:?}", peer, e);
+            }
+            Some(Message::Stored { id, result })
+        }
+        Message::FindValue { id, key } => Some(match p2p_store::get(&key, now) {
+            Some((value, ttl_ms, provenance)) => {
+                Message::Value { id, ttl_secs: (ttl_ms / 1000) as u32, provenance, value }
+            }
+            None => Message::Nodes { id, contacts: closest_contacts(&key, &peer) },
+        }),
+        Message::Ping { id } => Some(Message::Pong { id }),
+        Message::WantBlock { id, hash } => Some(Message::Block { id, data: p2p_blocks::get(&hash) }),
+        Message::Provide { id, key, ttl_secs } => {
+            let endpoint = peer_endpoint(&peer, now);
+            let result = endpoint.ok_or(StoreError::NoAddress).and_then(|endpoint| {
+                p2p_store::add_provider(key, peer, endpoint, u64::from(ttl_secs) * 1000, now)
+            });
+            if let Err(e) = result {
+                serial_println!("[P2P] Refused {:?} as a provider: {:?}", peer, e);
+            }
+            Some(Message::Stored { id, result })
+        }
+        Message::GetProviders { id, key } => {
+            let providers = p2p_store::providers(&key, now)
+                .into_iter()
+                .filter(|(node_id, _)| *node_id != peer)
+                .map(|(node_id, endpoint)| Contact { node_id, endpoint })
+                .collect();
+            Some(Message::Providers { id, providers, contacts: closest_contacts(&key, &peer) })
+        }
+        Message::Identify { id } => {
+            Some(Message::Identified { id, observed: peer_endpoint(&peer, now), agent: String::from(AGENT) })
+        }
+        _ => None,
+    }
+}
+
+// ─── Requests ────────────────────────────────────────────────────────────────
+
+/// Send `request` to `contact`, connecting first if needed, and return
+/// its reply.
+async fn call(contact: Contact, request: Message) -> Result<Message, RpcError> {
+    p2p_conn::connect(contact).await?;
+    send(contact.node_id, request).await
+}
+
+/// Send `request` on the open connection to `peer` and return its reply.
+async fn send(peer: NodeId, request: Message) -> Result<Message, RpcError> {
+    let slot = p2p_conn::send_request(&peer, request.protocol(), request.id(), request.encode())?;
+    p2p_metrics::rpc_sent(request.name());
+    let deadline = Some(uptime_ms() + RPC_TIMEOUT_MS);
+    let reply = match (Deadline { inner: pin!(slot.wait()), deadline }).await {
+        Some(Some(reply)) => reply,
+        Some(None) => return Err(RpcError::Closed),
+        None => return Err(RpcError::Timeout),
+    };
+    match Message::decode(&reply) {
+        Some(reply) if reply.id() == request.id() => Ok(reply),
+        _ => Err(RpcError::Malformed),
+    }
+}
+
+/// Ask `contact` to hold `value` under `key` for `ttl_ms`, as published
+/// and signed by `provenance`'s publisher.
+pub async fn store(
+    contact: Contact,
+    key: NodeId,
+    value: &[u8],
+    provenance: Provenance,
+    ttl_ms: u64,
+) -> Result<(), RpcError> {
+    let ttl_secs = (ttl_ms / 1000).min(u64::from(u32::MAX)) as u32;
+    let Provenance { identity, signature } = provenance;
+    let request =
+        Message::Store { id: next_id(), key, ttl_secs, signature, value: value.to_vec(), publisher: Some(identity) };
+    match call(contact, request).await? {
+        Message::Stored { result, .. } => result.map_err(RpcError::Refused),
+        _ => Err(RpcError::Malformed),
+    }
+}
+
+/// Check that `contact` is up and still has its node ID.
+pub async fn ping(contact: Contact) -> Result<(), RpcError> {
+    match call(contact, Message::Ping { id: next_id() }).await? {
+        Message::Pong { .. } => Ok(()),
+        _ => Err(RpcError::Malformed),
+    }
+}
+
+/// What a peer says about itself and about this node.
+#[derive(Debug, Clone)]
+pub struct PeerIdentity {
+    /// The software the peer runs.
+    pub agent: String,
+    /// Where the peer would dial this node.
+    pub observed: Option<IpEndpoint>,
+}
+
+/// Ask `peer`, over its connection or at its known endpoint, what it runs
+/// and where it sees this node from.
+pub async fn identify(peer: NodeId) -> Result<PeerIdentity, RpcError> {
+    let endpoint = peer_endpoint(&peer, uptime_ms()).ok_or(RpcError::NotConnected)?;
+    match call(Contact { node_id: peer, endpoint }, Message::Identify { id: next_id() }).await? {
+        Message::Identified { observed, agent, .. } => Ok(PeerIdentity { agent, observed }),
+        _ => Err(RpcError::Malformed),
+    }
+}
+
+// ─── Lookups ─────────────────────────────────────────────────────────────────
+
+/// What a walk asks the nodes on its way for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Want {
+    Nodes,
+    Value,
+    Providers,
+}
+
+/// What a walk found.
+#[derive(Default)]
+struct Walk {
+    /// The closest contacts that answered, closest first.
+    closest: Vec<Contact>,
+    value: Option<Vec<u8>>,
+    providers: Vec<Contact>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Query {
+    NotAsked,
+    Answered,
+    Failed,
+}
+
+/// Find the `K_BUCKET_SIZE` peers closest to `target` that answer, closest
+/// first.
+pub async fn lookup(target: NodeId) -> Vec<Contact> {
+    walk(target, Want::Nodes).await.closest
+}
+
+/// The value stored under `key`: this node's record, else the first one a
+/// FIND_VALUE walk toward the key turns up.
+pub async fn get(key: NodeId) -> Option<Vec<u8>> {
+    if let Some((value, _, _)) = p2p_store::get(&key, uptime_ms()) {
+        return Some(value);
+    }
+    walk(key, Want::Value).await.value
+}
+
+/// Store `value` under `key` here and on the `K_BUCKET_SIZE` nodes closest
+/// to it, for `ttl_ms`. Returns how many peers took it. Before P2P is up
+/// there is no node ID to publish under, which fails as `Full`.
+pub async fn publish(key: NodeId, value: &[u8], ttl_ms: u64) -> Result<usize, StoreError> {
+    let (local_id, identity) = local_signer().ok_or(StoreError::Full)?;
+    let signature = p2p_sign::sign_record(&key, value, &identity);
+    let provenance = Provenance { identity: identity.verifying_key().to_bytes(), signature };
+    p2p_store::put(key, value, local_id, provenance, ttl_ms, uptime_ms())?;
+    Ok(replicate(key, value, provenance, ttl_ms).await)
+}
+
+/// Send a record, signed by `provenance`'s publisher, to the
+/// `K_BUCKET_SIZE` nodes closest to its key. Returns how many took it.
+pub async fn replicate(key: NodeId, value: &[u8], provenance: Provenance, ttl_ms: u64) -> usize {
+    let closest = lookup(key).await;
+    let mut stored = 0;
+    for batch in closest.chunks(ALPHA) {
+        let replies = query_all(batch, |contact| store(contact, key, value, provenance, ttl_ms)).await;
+        for (contact, reply) in batch.iter().zip(replies) {
+            match reply {
+                Ok(()) => stored += 1,
+                Err(e) => { serial_println!("[P2P] STORE to {:?} failed: {:?}", contact.node_id, e); }
+            }
+        }
+    }
+    stored
+}
+
+/// Announce that this node provides `key`, to the `K_BUCKET_SIZE` nodes
+/// closest to it, for `ttl_ms`. Returns how many peers took it.
+pub async fn provide(key: NodeId, ttl_ms: u64) -> usize {
+    let ttl_secs = (ttl_ms / 1000).min(u64::from(u32::MAX)) as u32;
+    let closest = lookup(key).await;
+    let mut provided = 0;
+    for batch in closest.chunks(ALPHA) {
+        let provide = |contact| call(contact, Message::Provide { id: next_id(), key, ttl_secs });
+        let replies = query_all(batch, provide).await;
+        for (contact, reply) in batch.iter().zip(replies) {
+            match reply {
+                Ok(Message::Stored { result: Ok(()), .. }) => provided += 1,
+                Ok(Message::Stored { result: Err(e), .. }) => {
+                    serial_println!("[P2P] {:?} refused PROVIDE: {:?}", contact.node_id, e);
+                }
+                Ok(_) => { serial_println!("[P2P] {:?} answered PROVIDE oddly.", contact.node_id); }
+                Err(e) => { serial_println!("[P2P] PROVIDE to {:?} failed: {:?}", contact.node_id, e); }
+            }
+        }
+    }
+    provided
+}
+
+/// Nodes that provide `key`: those this node knows of, else those a
+/// GET_PROVIDERS walk toward the key turns up.
+pub async fn find_providers(key: NodeId) -> Vec<Contact> {
+    let known: Vec<Contact> = p2p_store::providers(&key, uptime_ms())
+        .into_iter()
+        .map(|(node_id, endpoint)| Contact { node_id, endpoint })
+        .collect();
+    if !known.is_empty() {
+        return known;
+    }
+    walk(key, Want::Providers).await.providers
+}
+
+/// Walk toward `target` with FIND_NODE, FIND_VALUE or GET_PROVIDERS,
+/// depending on what is wanted. A value or providers end the walk early.
+async fn walk(target: NodeId, want: Want) -> Walk {
+    let Some(local_id) = local_id() else { return Walk::default() };
+    let mut shortlist: Vec<(Contact, Query)> =
+        closest_contacts(&target, &local_id).into_iter().map(|contact| (contact, Query::NotAsked)).collect();
+    let request = |contact| {
+        let id = next_id();
+        let request = match want {
+            Want::Nodes => Message::FindNode { id, target },
+            Want::Value => Message::FindValue { id, key: target },
+            Want::Providers => Message::GetProviders { id, key: target },
+        };
+        call(contact, request)
+    };
+    let mut providers: Vec<Contact> = Vec::new();
+    loop {
+        shortlist.sort_by_key(|(contact, _)| contact.node_id.distance(&target));
+        shortlist.truncate(MAX_SHORTLIST);
+        let batch: Vec<Contact> = shortlist
+            .iter()
+            .filter(|(_, query)| *query != Query::Failed)
+            .take(K_BUCKET_SIZE)
+            .filter(|(_, query)| *query == Query::NotAsked)
+            .take(ALPHA)
+            .map(|(contact, _)| *contact)
+            .collect();
+        if batch.is_empty() {
+            break;
+        }
+        let replies = query_all(&batch, request).await;
+        for (asked, reply) in batch.iter().zip(replies) {
+            let outcome = match reply {
+                Ok(Message::Value { provenance, value, .. }) if want == Want::Value => {
+                    if p2p_sign::verify_record(&target, &value, &provenance.identity, &provenance.signature) {
+                        return Walk { value: Some(value), ..Walk::default() };
+                    }
+                    serial_println!("[P2P] {:?} sent a record with a bad signature.", asked.node_id);
+                    Query::Failed
+                }
+                Ok(Message::Providers { providers: found, contacts, .. }) if want == Want::Providers => {
+                    for provider in found {
+                        if !providers.iter().any(|p| p.node_id == provider.node_id) {
+                            providers.push(provider);
+                        }
+                    }
+                    merge(&mut shortlist, contacts, &local_id);
+                    Query::Answered
+                }
+                Ok(Message::Nodes { contacts, .. }) => {
+                    merge(&mut shortlist, contacts, &local_id);
+                    Query::Answered
+                }
+                Ok(_) => Query::Failed,
+                Err(e) => {
+                    serial_println!("[P2P] Request to {:?} at {} failed: {:?}", asked.node_id, asked.endpoint, e);
+                    Query::Failed
+                }
+            };
+            if let Some(entry) = shortlist.iter_mut().find(|(c, _)| c.node_id == asked.node_id) {
+                entry.1 = outcome;
+            }
+        }
+        if !providers.is_empty() {
+            break;
+        }
+    }
+    let closest = shortlist
+        .into_iter()
+        .filter(|(_, query)| *query == Query::Answered)
+        .take(K_BUCKET_SIZE)
+        .map(|(contact, _)| contact)
+        .collect();
+    Walk { closest, value: None, providers }
+}
+
+/// Add the `contacts` a node answered with to a walk's shortlist, unless
+/// known already or this node.
+fn merge(shortlist: &mut Vec<(Contact, Query)>, contacts: Vec<Contact>, local_id: &NodeId) {
+    let now = uptime_ms();
+    let book = ADDRESS_BOOK.lock();
+    for contact in contacts {
+        let known = shortlist.iter().any(|(c, _)| c.node_id == contact.node_id);
+        if !known && contact.node_id != *local_id {
+            // Skip a dial that just failed elsewhere.
+            let query = match book.reachability(contact.endpoint, now) {
+                Some(false) => Query::Failed,
+                _ => Query::NotAsked,
+            };
+            shortlist.push((contact, query));
+        }
+    }
+}
+
+/// Run `request` against each of `targets` (at most `ALPHA`) at once.
+/// The replies are in the same order.
+async fn query_all<T: Copy, F: Future>(targets: &[T], request: impl Fn(T) -> F) -> Vec<F::Output> {
+    let mut queries: [Option<F>; ALPHA] = core::array::from_fn(|i| targets.get(i).map(|&t| request(t)));
+    let mut replies: [Option<F::Output>; ALPHA] = Default::default();
+    poll_fn(|cx| {
+        let mut pending = false;
+        for (query, reply) in queries.iter_mut().zip(replies.iter_mut()) {
+            let Some(future) = query.as_mut() else { continue };
+            // Safety: `queries` lives in this future's state, which is
+            // pinned, and is never moved; a finished query is dropped in
+            // place.
+            match unsafe { Pin::new_unchecked(future) }.poll(cx) {
+                Poll::Ready(result) => {
+                    *reply = Some(result);
+                    *query = None;
+                }
+                Poll::Pending => pending = true,
+            }
+        }
+        if pending { Poll::Pending } else { Poll::Ready(()) }
+    })
+    .await;
+    replies.into_iter().flatten().collect()
+}
+
+// ─── Blocks ──────────────────────────────────────────────────────────────────
+
+/// The block named `hash`: this node's copy, else the first matching one
+/// a connected peer or, failing those, a provider sends, which is then
+/// cached.
+pub async fn fetch_block(hash: NodeId) -> Result<Vec<u8>, BlockError> {
+    if let Some(data) = p2p_blocks::get(&hash) {
+        return Ok(data);
+    }
+    let peers: Vec<NodeId> = p2p_conn::connections().iter().map(|conn| conn.peer).collect();
+    for batch in peers.chunks(ALPHA) {
+        let replies = query_all(batch, |peer| send(peer, Message::WantBlock { id: next_id(), hash })).await;
+        for (peer, reply) in batch.iter().zip(replies) {
+            if let Some(data) = accept_block(hash, peer, reply) {
+                return Ok(data);
+            }
+        }
+    }
+    let local_id = local_id();
+    let providers: Vec<Contact> = find_providers(hash)
+        .await
+        .into_iter()
+        .filter(|contact| Some(contact.node_id) != local_id && !peers.contains(&contact.node_id))
+        .collect();
+    for batch in providers.chunks(ALPHA) {
+        let replies = query_all(batch, |contact| call(contact, Message::WantBlock { id: next_id(), hash })).await;
+        for (contact, reply) in batch.iter().zip(replies) {
+            if let Some(data) = accept_block(hash, &contact.node_id, reply) {
+                return Ok(data);
+            }
+        }
+    }
+    Err(BlockError::Missing(hash))
+}
+
+/// The block in `reply` from `peer`, cached, if it is the one named `hash`.
+fn accept_block(hash: NodeId, peer: &NodeId, reply: Result<Message, RpcError>) -> Option<Vec<u8>> {
+    let Ok(Message::Block { data: Some(data), .. }) = reply else { return None };
+    if NodeId::from_data(&data) != hash {
+        serial_println!("[P2P] {:?} sent a block that doesn't match {:?}.", peer, hash);
+        return None;
+    }
+    if let Err(e) = p2p_blocks::cache(hash, &data) {
+        serial_println!("[P2P] Not caching block {:?}: {:?}", hash, e);
+    }
+    Some(data)
+}
+
+/// The object whose manifest is `root`, fetched block by block.
+pub async fn fetch_object(root: NodeId) -> Result<Vec<u8>, BlockError> {
+    let (len, chunks) = p2p_blocks::parse_manifest(&fetch_block(root).await?)?;
+    let mut object = Vec::with_capacity(len);
+    for chunk in chunks {
+        object.extend_from_slice(&fetch_block(chunk).await?);
+    }
+    if object.len() != len {
+        return Err(BlockError::NotAnObject);
+    }
+    Ok(object)
+}
@@ -0,0 +1,214 @@
+//! # Signed P2P Messages
+//!
+//! The Noise channel proves who is on the other end of a connection; this
+//! module makes each RPC and each DHT record carry its own proof, so a
+//! message can't be replayed and a record can't be altered by the nodes
+//! that pass it on.
+//!
+//! ## Messages
+//! Every RPC frame is sealed as `[sequence: u64 LE][message][signature: 64]`.
+//! The sender's Ed25519 identity signs `MESSAGE_CONTEXT`, the recipient's
+//! node ID, the sequence number and the message, so a frame is only good
+//! for the peer it was sent to. Sequence numbers come from one counter,
+//! which each boot starts past every number an earlier boot could have
+//! used (see `start_boot`), so they never repeat under the identity the
+//! boots share.
+//!
+//! ## Replay protection
+//! The receiver keeps a window per sender: the highest sequence number
+//! seen and which of the `WINDOW` before it have arrived. Requests run on
+//! parallel connections, so frames may arrive a little out of order, but
+//! each number is accepted once, and anything older than the window not
+//! at all. At most `MAX_WINDOWS` senders are tracked; the least recently
+//! heard from is forgotten first.
+//!
+//! ## Records
+//! A DHT record is signed by its publisher over `RECORD_CONTEXT`, its key
+//! and its value. Nodes holding it keep the signature and the publisher's
+//! key and hand both out with the value, so whoever fetches it can check
+//! it came from the publisher unchanged.
+//!
+//! ## Gossip
+//! A pubsub message is signed by its publisher over `GOSSIP_CONTEXT`, its
+//! sequence number, topic and data, and passes through other nodes
+//! untouched like a record does.
+
+use alloc::vec::Vec;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::interrupts::uptime_ms;
+use crate::p2p_kademlia::NodeId;
+
+const MESSAGE_CONTEXT: &[u8] = b"p2p-rpc:";
+const RECORD_CONTEXT: &[u8] = b"p2p-record:";
+const GOSSIP_CONTEXT: &[u8] = b"p2p-gossip:";
+const SEQUENCE_LEN: usize = 8;
+pub const SIGNATURE_LEN: usize = 64;
+/// Sequence numbers behind the highest one that are still accepted.
+const WINDOW: u64 = 64;
+/// Senders whose replay windows are kept.
+const MAX_WINDOWS: usize = 256;
+/// Sequence numbers one boot may use, as a power of two.
+const BOOT_SEQUENCE_BITS: u32 = 40;
+
+static NEXT_SEQUENCE: AtomicU64 = AtomicU64::new(1);
+
+/// Why a sealed message was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignError {
+    /// Too short to hold a sequence number and a signature.
+    Malformed,
+    /// The signature doesn't check out with the sender's identity.
+    BadSignature,
+    /// The sequence number was seen before, or is too old to tell.
+    Replayed,
+}
+
+struct ReplayWindow {
+    sender: NodeId,
+    highest: u64,
+    /// Bit `n` set: `highest - n` has arrived.
+    seen: u64,
+    last_used: u64,
+}
+
+impl ReplayWindow {
+    /// Record `sequence`; false if it was already seen or is too old.
+    fn accept(&mut self, sequence: u64) -> bool {
+        if sequence > self.highest {
+            let shift = sequence - self.highest;
+            self.seen = if shift >= WINDOW { 1 } else { (self.seen << shift) | 1 };
+            self.highest = sequence;
+            return true;
+        }
+        let age = self.highest - sequence;
+        if age >= WINDOW || self.seen & (1 << age) != 0 {
+            return false;
+        }
+        self.seen |= 1 << age;
+        true
+    }
+}
+
+lazy_static! {
+    static ref WINDOWS: Mutex<Vec<ReplayWindow>> = Mutex::new(Vec::new());
+}
+
+/// Start this boot's sequence numbers after those of the `boot` boots
+/// before it under the same identity.
+pub fn start_boot(boot: u64) {
+    NEXT_SEQUENCE.store((boot << BOOT_SEQUENCE_BITS) + 1, Ordering::Relaxed);
+}
+
+/// A sequence number not used before under this node's identity.
+pub fn next_sequence() -> u64 {
+    NEXT_SEQUENCE.fetch_add(1, Ordering::Relaxed)
+}
+
+fn signed_message(recipient: &NodeId, sequence: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut signed = Vec::with_capacity(MESSAGE_CONTEXT.len() + recipient.0.len() + SEQUENCE_LEN + message.len());
+    signed.extend_from_slice(MESSAGE_CONTEXT);
+    signed.extend_from_slice(&recipient.0);
+    signed.extend_from_slice(sequence);
+    signed.extend_from_slice(message);
+    signed
+}
+
+/// Sign `message` for `recipient` with the next sequence number.
+pub fn seal(message: &[u8], recipient: &NodeId, identity: &SigningKey) -> Vec<u8> {
+    let sequence = next_sequence().to_le_bytes();
+    let signature = identity.sign(&signed_message(recipient, &sequence, message));
+    let mut frame = Vec::with_capacity(SEQUENCE_LEN + message.len() + SIGNATURE_LEN);
+    frame.extend_from_slice(&sequence);
+    frame.extend_from_slice(message);
+    frame.extend_from_slice(&signature.to_bytes());
+    frame
+}
+
+/// Check a frame `sender` (whose identity is `identity`) sealed for
+/// `recipient`, and that it isn't a replay. Returns the message.
+pub fn open<'a>(
+    frame: &'a [u8],
+    sender: &NodeId,
+    identity: &VerifyingKey,
+    recipient: &NodeId,
+) -> Result<&'a [u8], SignError> {
+    if frame.len() < SEQUENCE_LEN + SIGNATURE_LEN {
+        return Err(SignError::Malformed);
+    }
+    let (sequence, rest) = frame.split_at(SEQUENCE_LEN);
+    let (message, signature) = rest.split_at(rest.len() - SIGNATURE_LEN);
+    let signature = Signature::from_bytes(signature.try_into().unwrap());
+    identity
+        .verify(&signed_message(recipient, sequence, message), &signature)
+        .map_err(|_| SignError::BadSignature)?;
+
+    let sequence = u64::from_le_bytes(sequence.try_into().unwrap());
+    let now = uptime_ms();
+    let mut windows = WINDOWS.lock();
+    let window = match windows.iter().position(|w| w.sender == *sender) {
+        Some(idx) => &mut windows[idx],
+        None => {
+            if windows.len() >= MAX_WINDOWS {
+                if let Some(idx) = windows.iter().enumerate().min_by_key(|(_, w)| w.last_used).map(|(idx, _)| idx) {
+                    windows.swap_remove(idx);
+                }
+            }
+            windows.push(ReplayWindow { sender: *sender, highest: 0, seen: 0, last_used: now });
+            windows.last_mut().unwrap()
+        }
+    };
+    window.last_used = now;
+    if !window.accept(sequence) {
+        return Err(SignError::Replayed);
+    }
+    Ok(message)
+}
+
+fn signed_record(key: &NodeId, value: &[u8]) -> Vec<u8> {
+    let mut signed = Vec::with_capacity(RECORD_CONTEXT.len() + key.0.len() + value.len());
+    signed.extend_from_slice(RECORD_CONTEXT);
+    signed.extend_from_slice(&key.0);
+    signed.extend_from_slice(value);
+    signed
+}
+
+/// The publisher's signature over the record `key` → `value`.
+pub fn sign_record(key: &NodeId, value: &[u8], identity: &SigningKey) -> [u8; SIGNATURE_LEN] {
+    identity.sign(&signed_record(key, value)).to_bytes()
+}
+
+/// Whether `publisher` signed the record `key` → `value`.
+pub fn verify_record(key: &NodeId, value: &[u8], publisher: &[u8; 32], signature: &[u8; SIGNATURE_LEN]) -> bool {
+    let Ok(publisher) = VerifyingKey::from_bytes(publisher) else { return false };
+    publisher.verify(&signed_record(key, value), &Signature::from_bytes(signature)).is_ok()
+}
+
+fn signed_gossip(sequence: u64, topic: &str, data: &[u8]) -> Vec<u8> {
+    let mut signed = Vec::with_capacity(GOSSIP_CONTEXT.len() + SEQUENCE_LEN + 1 + topic.len() + data.len());
+    signed.extend_from_slice(GOSSIP_CONTEXT);
+    signed.extend_from_slice(&sequence.to_le_bytes());
+    signed.push(topic.len() as u8);
+    signed.extend_from_slice(topic.as_bytes());
+    signed.extend_from_slice(data);
+    signed
+}
+
+/// The publisher's signature over pubsub message `sequence` on `topic`.
+pub fn sign_gossip(sequence: u64, topic: &str, data: &[u8], identity: &SigningKey) -> [u8; SIGNATURE_LEN] {
+    identity.sign(&signed_gossip(sequence, topic, data)).to_bytes()
+}
+
+/// Whether `publisher` signed pubsub message `sequence` on `topic`.
+pub fn verify_gossip(
+    sequence: u64,
+    topic: &str,
+    data: &[u8],
+    publisher: &[u8; 32],
+    signature: &[u8; SIGNATURE_LEN],
+) -> bool {
+    let Ok(publisher) = VerifyingKey::from_bytes(publisher) else { return false };
+    publisher.verify(&signed_gossip(sequence, topic, data), &Signature::from_bytes(signature)).is_ok()
+}
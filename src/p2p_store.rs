@@ -0,0 +1,365 @@
+//! # DHT Record Store
+//!
+//! The records this node holds for the DHT: values peers published to it
+//! with STORE, and the ones it published itself, until they expire. A key
+//! is a `NodeId`-sized hash (usually `NodeId::from_data` of a name), and
+//! a record lives on the `K_BUCKET_SIZE` nodes whose IDs are closest to it
+//! (see `p2p_rpc`). Publishers republish before the TTL runs out if they
+//! want a record kept; past it, the record is gone from every node.
+//!
+//! One record per key: a later STORE replaces it, whoever published it.
+//! Each record keeps its publisher's signature (see `p2p_sign`), which is
+//! checked before it is stored and handed out with the value.
+//!
+//! ## Replication
+//! Every node holding a record passes it on to the nodes closest to its
+//! key (see `p2p_maintenance`), with the TTL it has left, so the record
+//! outlives the nodes it was first stored on, its publisher included.
+//! Such a replica never replaces a record that expires later, which would
+//! be a newer one from the publisher. A record stored here within the last
+//! replication interval was sent to the other closest nodes too, so it
+//! isn't passed on again in the next round.
+//!
+//! ## Providers
+//! Besides records, the store keeps provider entries: which nodes said,
+//! with PROVIDE, that they hold the content with a given hash (see
+//! `p2p_blocks`). A key has up to `MAX_KEY_PROVIDERS` of them, the one
+//! expiring first giving way to a new one, and they expire like records.
+//!
+//! ## Quotas
+//! Values are at most `MAX_VALUE_LEN` bytes and live at most `MAX_TTL_MS`.
+//! Each publisher may hold `p2p.store.max_records` records and
+//! `p2p.store.max_bytes` bytes of values, and the store holds at most
+//! `MAX_RECORDS` in all. Records published locally count against this
+//! node's own ID. Provider entries count against the providing node's
+//! record quota, and the store holds at most `MAX_PROVIDERS` of them.
+//!
+//! ## Memory
+//! The kernel heap never frees, so the buffers of replaced and expired
+//! values are kept and reused for later values that fit in them.
+
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use smoltcp::wire::IpEndpoint;
+use spin::Mutex;
+use crate::config;
+use crate::p2p_kademlia::NodeId;
+use crate::p2p_sign::{self, SIGNATURE_LEN};
+
+/// Longest value accepted.
+pub const MAX_VALUE_LEN: usize = 4096;
+/// Longest a record lives; longer TTLs are cut to this.
+pub const MAX_TTL_MS: u64 = 24 * 60 * 60 * 1000;
+/// Records the store holds from all publishers together.
+pub const MAX_RECORDS: usize = 1024;
+/// Provider entries the store holds for all keys together.
+pub const MAX_PROVIDERS: usize = 1024;
+/// Provider entries kept per key.
+pub const MAX_KEY_PROVIDERS: usize = 20;
+/// Default of `p2p.store.max_records`.
+const DEFAULT_PEER_RECORDS: u64 = 64;
+/// Default of `p2p.store.max_bytes`.
+const DEFAULT_PEER_BYTES: u64 = 64 * 1024;
+
+/// Why a record wasn't stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreError {
+    /// The value is longer than `MAX_VALUE_LEN`.
+    TooLarge,
+    /// The publisher is at its record or byte quota.
+    QuotaExceeded,
+    /// The store holds `MAX_RECORDS`.
+    Full,
+    /// The publisher's signature doesn't match the record.
+    BadSignature,
+    /// The would-be provider's address isn't known, so nobody could be
+    /// sent to it.
+    NoAddress,
+}
+
+impl StoreError {
+    /// Code of the error in a STORED reply; 0 means stored.
+    pub fn code(self) -> u8 {
+        match self {
+            StoreError::TooLarge => 1,
+            StoreError::QuotaExceeded => 2,
+            StoreError::Full => 3,
+            StoreError::BadSignature => 4,
+            StoreError::NoAddress => 5,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(StoreError::TooLarge),
+            2 => Some(StoreError::QuotaExceeded),
+            3 => Some(StoreError::Full),
+            4 => Some(StoreError::BadSignature),
+            5 => Some(StoreError::NoAddress),
+            _ => None,
+        }
+    }
+}
+
+/// Who signed a record, and the signature.
+#[derive(Debug, Clone, Copy)]
+pub struct Provenance {
+    /// The publisher's Ed25519 identity key.
+    pub identity: [u8; 32],
+    pub signature: [u8; SIGNATURE_LEN],
+}
+
+struct Record {
+    key: NodeId,
+    value: Vec<u8>,
+    publisher: NodeId,
+    provenance: Provenance,
+    expires_at: u64,
+    /// When a STORE or this node's replication last sent the record out.
+    stored_at: u64,
+}
+
+/// A stored record, as listed by `records`.
+#[derive(Debug, Clone, Copy)]
+pub struct RecordInfo {
+    pub key: NodeId,
+    pub publisher: NodeId,
+    pub len: usize,
+    /// Milliseconds until it expires.
+    pub ttl_ms: u64,
+}
+
+/// A node that holds the content with hash `key`.
+struct Provider {
+    key: NodeId,
+    node_id: NodeId,
+    endpoint: IpEndpoint,
+    expires_at: u64,
+}
+
+lazy_static! {
+    static ref RECORDS: Mutex<Vec<Record>> = Mutex::new(Vec::new());
+    static ref PROVIDERS: Mutex<Vec<Provider>> = Mutex::new(Vec::new());
+    /// Buffers of values that were replaced or expired.
+    static ref SPARE: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+}
+
+/// Register the `p2p.store.*` quota keys.
+pub fn register_tunables() {
+    config::register("p2p.store.max_records", "DHT records one publisher may store on this node", DEFAULT_PEER_RECORDS, 1, MAX_RECORDS as u64, None);
+    config::register("p2p.store.max_bytes", "DHT value bytes one publisher may store on this node", DEFAULT_PEER_BYTES, 1024, 1024 * 1024, None);
+}
+
+/// Store `value` under `key` for `ttl_ms` (at most `MAX_TTL_MS`), replacing
+/// any record for the key, on behalf of `publisher`, who signed it.
+pub fn put(
+    key: NodeId,
+    value: &[u8],
+    publisher: NodeId,
+    provenance: Provenance,
+    ttl_ms: u64,
+    now: u64,
+) -> Result<(), StoreError> {
+    insert(key, value, publisher, provenance, ttl_ms, now, false)
+}
+
+/// Like `put`, for a copy of `publisher`'s record another node replicated
+/// here. A record for the key that expires later is kept instead.
+pub fn put_replica(
+    key: NodeId,
+    value: &[u8],
+    publisher: NodeId,
+    provenance: Provenance,
+    ttl_ms: u64,
+    now: u64,
+) -> Result<(), StoreError> {
+    insert(key, value, publisher, provenance, ttl_ms, now, true)
+}
+
+fn insert(
+    key: NodeId,
+    value: &[u8],
+    publisher: NodeId,
+    provenance: Provenance,
+    ttl_ms: u64,
+    now: u64,
+    replica: bool,
+) -> Result<(), StoreError> {
+    if value.len() > MAX_VALUE_LEN {
+        return Err(StoreError::TooLarge);
+    }
+    if !p2p_sign::verify_record(&key, value, &provenance.identity, &provenance.signature) {
+        return Err(StoreError::BadSignature);
+    }
+    let expires_at = now.saturating_add(ttl_ms.min(MAX_TTL_MS));
+    let mut records = RECORDS.lock();
+    expire(&mut records, now);
+    let existing = records.iter().position(|r| r.key == key);
+    if let Some(record) = existing.map(|idx| &mut records[idx]).filter(|r| replica && r.expires_at >= expires_at) {
+        // Held already, or a newer version is: the sender passed it on to
+        // the other closest nodes as well.
+        record.stored_at = now;
+        return Ok(());
+    }
+
+    // The publisher's usage without the record being replaced.
+    let (mut count, mut bytes) = (0, 0);
+    for (idx, record) in records.iter().enumerate() {
+        if record.publisher == publisher && Some(idx) != existing {
+            count += 1;
+            bytes += record.value.len();
+        }
+    }
+    count += PROVIDERS.lock().iter().filter(|p| p.node_id == publisher && p.expires_at > now).count();
+    let max_records = config::get_or("p2p.store.max_records", DEFAULT_PEER_RECORDS) as usize;
+    let max_bytes = config::get_or("p2p.store.max_bytes", DEFAULT_PEER_BYTES) as usize;
+    if count + 1 > max_records || bytes + value.len() > max_bytes {
+        return Err(StoreError::QuotaExceeded);
+    }
+
+    match existing {
+        Some(idx) => {
+            let record = &mut records[idx];
+            if record.value.capacity() < value.len() {
+                let old = core::mem::replace(&mut record.value, spare_buffer(value.len()));
+                SPARE.lock().push(old);
+            }
+            record.value.clear();
+            record.value.extend_from_slice(value);
+            record.publisher = publisher;
+            record.provenance = provenance;
+            record.expires_at = expires_at;
+            record.stored_at = now;
+        }
+        None => {
+            if records.len() >= MAX_RECORDS {
+                return Err(StoreError::Full);
+            }
+            let mut buffer = spare_buffer(value.len());
+            buffer.extend_from_slice(value);
+            records.push(Record { key, value: buffer, publisher, provenance, expires_at, stored_at: now });
+        }
+    }
+    Ok(())
+}
+
+/// The value stored under `key`, the milliseconds it has left and who
+/// signed it.
+pub fn get(key: &NodeId, now: u64) -> Option<(Vec<u8>, u64, Provenance)> {
+    let mut records = RECORDS.lock();
+    expire(&mut records, now);
+    records.iter().find(|r| r.key == *key).map(|r| (r.value.clone(), r.expires_at - now, r.provenance))
+}
+
+/// The records not sent out since `before`: key, value, milliseconds left
+/// and signature of each. They count as sent out now.
+pub fn due_for_replication(before: u64, now: u64) -> Vec<(NodeId, Vec<u8>, u64, Provenance)> {
+    let mut records = RECORDS.lock();
+    expire(&mut records, now);
+    records
+        .iter_mut()
+        .filter(|r| r.stored_at <= before)
+        .map(|r| {
+            r.stored_at = now;
+            (r.key, r.value.clone(), r.expires_at - now, r.provenance)
+        })
+        .collect()
+}
+
+/// Every record held: key, value, milliseconds left and signature of each.
+pub fn held(now: u64) -> Vec<(NodeId, Vec<u8>, u64, Provenance)> {
+    let mut records = RECORDS.lock();
+    expire(&mut records, now);
+    records.iter().map(|r| (r.key, r.value.clone(), r.expires_at - now, r.provenance)).collect()
+}
+
+/// Drop the records and provider entries whose TTL ran out. Returns how
+/// many of each went.
+pub fn expire_all(now: u64) -> (usize, usize) {
+    let mut records = RECORDS.lock();
+    let before = records.len();
+    expire(&mut records, now);
+    let expired = before - records.len();
+    let mut providers = PROVIDERS.lock();
+    let before = providers.len();
+    providers.retain(|p| p.expires_at > now);
+    (expired, before - providers.len())
+}
+
+/// Every record held, without the values.
+pub fn records(now: u64) -> Vec<RecordInfo> {
+    let mut records = RECORDS.lock();
+    expire(&mut records, now);
+    records
+        .iter()
+        .map(|r| RecordInfo { key: r.key, publisher: r.publisher, len: r.value.len(), ttl_ms: r.expires_at - now })
+        .collect()
+}
+
+/// Record that `node_id`, dialed at `endpoint`, provides `key` for the
+/// next `ttl_ms` (at most `MAX_TTL_MS`).
+pub fn add_provider(
+    key: NodeId,
+    node_id: NodeId,
+    endpoint: IpEndpoint,
+    ttl_ms: u64,
+    now: u64,
+) -> Result<(), StoreError> {
+    let expires_at = now.saturating_add(ttl_ms.min(MAX_TTL_MS));
+    let records = RECORDS.lock().iter().filter(|r| r.publisher == node_id && r.expires_at > now).count();
+    let mut providers = PROVIDERS.lock();
+    providers.retain(|p| p.expires_at > now);
+    if let Some(known) = providers.iter_mut().find(|p| p.key == key && p.node_id == node_id) {
+        known.endpoint = endpoint;
+        known.expires_at = expires_at;
+        return Ok(());
+    }
+    let provided = providers.iter().filter(|p| p.node_id == node_id).count();
+    if records + provided + 1 > config::get_or("p2p.store.max_records", DEFAULT_PEER_RECORDS) as usize {
+        return Err(StoreError::QuotaExceeded);
+    }
+    let for_key = providers.iter().filter(|p| p.key == key).count();
+    if for_key >= MAX_KEY_PROVIDERS {
+        let soonest = providers.iter().enumerate().filter(|(_, p)| p.key == key).min_by_key(|(_, p)| p.expires_at);
+        if let Some((idx, _)) = soonest {
+            providers.swap_remove(idx);
+        }
+    } else if providers.len() >= MAX_PROVIDERS {
+        return Err(StoreError::Full);
+    }
+    providers.push(Provider { key, node_id, endpoint, expires_at });
+    Ok(())
+}
+
+/// The nodes known to provide `key`, and where to dial them.
+pub fn providers(key: &NodeId, now: u64) -> Vec<(NodeId, IpEndpoint)> {
+    let mut providers = PROVIDERS.lock();
+    providers.retain(|p| p.expires_at > now);
+    providers.iter().filter(|p| p.key == *key).map(|p| (p.node_id, p.endpoint)).collect()
+}
+
+/// Drop expired records, keeping their buffers.
+fn expire(records: &mut Vec<Record>, now: u64) {
+    let mut spare = SPARE.lock();
+    let mut idx = 0;
+    while idx < records.len() {
+        if records[idx].expires_at <= now {
+            spare.push(records.swap_remove(idx).value);
+        } else {
+            idx += 1;
+        }
+    }
+}
+
+/// An empty buffer that holds at least `len` bytes, reused if possible.
+fn spare_buffer(len: usize) -> Vec<u8> {
+    let mut spare = SPARE.lock();
+    match spare.iter().position(|buffer| buffer.capacity() >= len) {
+        Some(idx) => {
+            let mut buffer = spare.swap_remove(idx);
+            buffer.clear();
+            buffer
+        }
+        None => Vec::with_capacity(len),
+    }
+}
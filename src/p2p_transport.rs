@@ -4,6 +4,9 @@ use core::future::Future;
 use core::pin::Pin;
 use core::task::{Context, Poll};
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use smoltcp::iface::SocketHandle;
+use crate::{config, interrupts, p2p_proto, serial_println};
 
 pub struct TcpReadFuture<'a> {
     pub handle: smoltcp::iface::SocketHandle,
@@ -19,7 +22,10 @@ impl<'a> Future for TcpReadFuture<'a> {
             let socket = stack_inner.sockets.get_mut::<tcp::Socket>(self.handle);
             if socket.can_recv() {
                 match socket.recv_slice(&mut self.buffer) {
-                    Ok(n) if n > 0 => Poll::Ready(Ok(n)),
+                    Ok(n) if n > 0 => {
+                        stack_inner.record_recv(self.handle, n);
+                        Poll::Ready(Ok(n))
+                    }
                     Ok(_) => Poll::Pending, // Non-blocking, keep polling
                     Err(_) => Poll::Ready(Err(())),
                 }
@@ -48,7 +54,10 @@ impl<'a> Future for TcpWriteFuture<'a> {
             let socket = stack_inner.sockets.get_mut::<tcp::Socket>(self.handle);
             if socket.can_send() {
                 match socket.send_slice(self.data) {
-                    Ok(n) if n > 0 => Poll::Ready(Ok(n)),
+                    Ok(n) if n > 0 => {
+                        stack_inner.record_send(self.handle, self.data.len(), n);
+                        Poll::Ready(Ok(n))
+                    }
                     Ok(_) => Poll::Pending,
                     Err(_) => Poll::Ready(Err(())),
                 }
@@ -61,55 +70,249 @@ impl<'a> Future for TcpWriteFuture<'a> {
     }
 }
 
-/// Helper for length-prefixed framing (simple P2P transport)
-pub async fn send_framed(handle: smoltcp::iface::SocketHandle, data: &[u8]) -> Result<(), ()> {
-    // 1. Send Length (u32 little endian)
-    let len = data.len() as u32;
-    let len_bytes = len.to_le_bytes();
-    
-    let mut sent = 0;
-    while sent < 4 {
-        match (TcpWriteFuture { handle, data: &len_bytes[sent..] }).await {
-            Ok(n) => sent += n,
-            Err(_) => return Err(()),
+// ─── Length-Prefixed Framing ─────────────────────────────────────────────────
+//
+// Frames are `[len: unsigned varint][len bytes]`, the length written like a
+// protobuf varint (see `p2p_proto`). All framing state lives in the
+// `FramedConnection`, never in a future's locals, so every operation is
+// cancellation-safe: dropping a `send`/`recv` future (timeout, task
+// cancelled) loses nothing, and the next call picks up exactly where the
+// dropped one stopped.
+//
+// ## Desynchronization
+// A length prefix carries no sync marker, so once a stream is misaligned
+// every later "length" is garbage. The receiver treats two things as a sign
+// of that: a length above `p2p.max_frame_len` (or a prefix longer than
+// `MAX_PREFIX_LEN`), and a frame that stops
+// making progress for `p2p.frame_timeout_ms` after it started. Either way
+// the connection is reset (RST), the event is logged and counted in
+// `framing_stats()`, and `recv` returns the reason. A fresh connection is
+// the only reliable way back into sync.
+
+/// Hard upper bound on `p2p.max_frame_len`.
+pub const MAX_FRAME_LEN: usize = 1024 * 1024;
+/// Default for `p2p.max_frame_len`; no current message comes close.
+const DEFAULT_MAX_FRAME_LEN: usize = 256 * 1024;
+/// Default for `p2p.frame_timeout_ms`.
+const DEFAULT_FRAME_TIMEOUT_MS: u64 = 5_000;
+/// Longest length prefix: enough for any length up to `MAX_FRAME_LEN`.
+const MAX_PREFIX_LEN: usize = 3;
+
+static OVERSIZED_FRAMES: AtomicU64 = AtomicU64::new(0);
+static FRAME_TIMEOUTS: AtomicU64 = AtomicU64::new(0);
+
+/// Connections reset because their framing went out of sync.
+#[derive(Debug, Clone, Copy)]
+pub struct FramingStats {
+    /// Frames announcing a length above `p2p.max_frame_len`.
+    pub oversized: u64,
+    /// Frames that stalled mid-way for longer than `p2p.frame_timeout_ms`.
+    pub timeouts: u64,
+}
+
+pub fn framing_stats() -> FramingStats {
+    FramingStats {
+        oversized: OVERSIZED_FRAMES.load(Ordering::Relaxed),
+        timeouts: FRAME_TIMEOUTS.load(Ordering::Relaxed),
+    }
+}
+
+/// Register the framing keys with the config subsystem. Idempotent.
+pub fn register_tunables() {
+    config::register("p2p.max_frame_len", "Largest P2P frame accepted (bytes)", DEFAULT_MAX_FRAME_LEN as u64, 1024, MAX_FRAME_LEN as u64, None);
+    config::register("p2p.frame_timeout_ms", "Reset a connection whose frame stalls this long", DEFAULT_FRAME_TIMEOUT_MS, 100, 600_000, None);
+}
+
+/// Why a framed operation failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// The socket closed or errored.
+    Closed,
+    /// A frame length exceeded `p2p.max_frame_len` (the connection was reset).
+    Oversized(usize),
+    /// A frame stalled mid-way (the connection was reset).
+    Timeout,
+    /// A frame failed to decrypt on a `SecureConnection`.
+    Corrupt,
+}
+
+/// Where the receiver is within the current frame.
+enum RxState {
+    /// Reading the varint length prefix, a byte at a time; `have` bytes
+    /// so far.
+    Header { buf: [u8; MAX_PREFIX_LEN], have: usize },
+    /// Reading a body of `body.len()` bytes; `have` so far.
+    Body { body: Vec<u8>, have: usize },
+}
+
+/// A TCP socket carrying length-prefixed frames.
+///
+/// Dropping a connection with a frame half-sent or half-received would leave
+/// the peer parsing garbage, so the drop guard aborts the socket (RST) in
+/// that case. Must not be dropped while `NETWORK_STACK` is locked.
+pub struct FramedConnection {
+    handle: SocketHandle,
+    rx: RxState,
+    /// Uptime (ms) when the current incoming frame's first byte arrived.
+    rx_started: Option<u64>,
+    /// Encoded outgoing frames the socket hasn't accepted yet.
+    tx: Vec<u8>,
+    tx_sent: usize,
+}
+
+impl FramedConnection {
+    pub fn new(handle: SocketHandle) -> Self {
+        FramedConnection {
+            handle,
+            rx: RxState::Header { buf: [0; MAX_PREFIX_LEN], have: 0 },
+            rx_started: None,
+            tx: Vec::new(),
+            tx_sent: 0,
+        }
+    }
+
+    pub fn handle(&self) -> SocketHandle {
+        self.handle
+    }
+
+    /// True when no frame is partially sent or received.
+    pub fn is_idle(&self) -> bool {
+        self.rx_started.is_none() && self.tx_sent == self.tx.len()
+    }
+
+    /// Send one frame.
+    ///
+    /// The whole frame is queued on the connection before the first await,
+    /// so a dropped `send` still delivers it on the next `send` or `flush`.
+    pub async fn send(&mut self, data: &[u8]) -> Result<(), FrameError> {
+        if data.len() > MAX_FRAME_LEN {
+            return Err(FrameError::Oversized(data.len()));
         }
+        p2p_proto::put_varint(&mut self.tx, data.len() as u64);
+        self.tx.extend_from_slice(data);
+        self.flush().await
     }
-    
-    // 2. Send Data
-    let mut sent = 0;
-    while sent < data.len() {
-        match (TcpWriteFuture { handle, data: &data[sent..] }).await {
-            Ok(n) => sent += n,
-            Err(_) => return Err(()),
+
+    /// Push queued outgoing bytes into the socket until none are left.
+    pub async fn flush(&mut self) -> Result<(), FrameError> {
+        while self.tx_sent < self.tx.len() {
+            let n = (TcpWriteFuture { handle: self.handle, data: &self.tx[self.tx_sent..] })
+                .await
+                .map_err(|_| FrameError::Closed)?;
+            self.tx_sent += n;
+        }
+        self.tx.clear();
+        self.tx_sent = 0;
+        Ok(())
+    }
+
+    /// Receive one frame.
+    ///
+    /// Progress is recorded after every read, so a dropped `recv` leaves the
+    /// partial frame on the connection for the next call to finish. Waiting
+    /// for a frame to *start* never times out; only a stalled frame does.
+    /// Callers bound the wait: `p2p::handshake` gives up after a deadline,
+    /// and the connection manager closes established connections idle for
+    /// `p2p.idle_timeout_ms`.
+    pub async fn recv(&mut self) -> Result<Vec<u8>, FrameError> {
+        loop {
+            let handle = self.handle;
+            let deadline = self
+                .rx_started
+                .map(|t| t + config::get_or("p2p.frame_timeout_ms", DEFAULT_FRAME_TIMEOUT_MS));
+            let buffer = match &mut self.rx {
+                RxState::Header { buf, have } => &mut buf[*have..*have + 1],
+                RxState::Body { body, have } => &mut body[*have..],
+            };
+            let n = if buffer.is_empty() {
+                0 // zero-length body
+            } else {
+                match (Deadline { inner: TcpReadFuture { handle, buffer }, deadline }).await {
+                    Some(Ok(n)) => n,
+                    Some(Err(())) => return Err(FrameError::Closed),
+                    None => return Err(self.desync(FrameError::Timeout)),
+                }
+            };
+            self.rx_started.get_or_insert_with(interrupts::uptime_ms);
+
+            match &mut self.rx {
+                RxState::Header { buf, have } => {
+                    *have += n;
+                    // A prefix still going after `MAX_PREFIX_LEN` bytes is
+                    // for a length past any limit.
+                    let len = match p2p_proto::varint(&buf[..*have]) {
+                        Some((len, _)) => usize::try_from(len).unwrap_or(usize::MAX),
+                        None if *have < MAX_PREFIX_LEN => continue,
+                        None => usize::MAX,
+                    };
+                    if len > config::get_or("p2p.max_frame_len", DEFAULT_MAX_FRAME_LEN as u64) as usize {
+                        return Err(self.desync(FrameError::Oversized(len)));
+                    }
+                    self.rx = RxState::Body { body: alloc::vec![0; len], have: 0 };
+                }
+                RxState::Body { body, have } => {
+                    *have += n;
+                    if *have == body.len() {
+                        let frame = core::mem::take(body);
+                        self.rx = RxState::Header { buf: [0; MAX_PREFIX_LEN], have: 0 };
+                        self.rx_started = None;
+                        return Ok(frame);
+                    }
+                }
+            }
         }
     }
-    
-    Ok(())
+
+    /// Reset the connection after a desynchronization and record why.
+    fn desync(&mut self, reason: FrameError) -> FrameError {
+        match reason {
+            FrameError::Oversized(len) => {
+                OVERSIZED_FRAMES.fetch_add(1, Ordering::Relaxed);
+                serial_println!("[P2P] Frame length {} exceeds limit; stream out of sync, resetting connection.", len);
+            }
+            _ => {
+                FRAME_TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+                serial_println!("[P2P] Frame stalled mid-way; resetting connection.");
+            }
+        }
+        if let Some(stack) = NETWORK_STACK.lock().as_mut() {
+            stack.sockets.get_mut::<tcp::Socket>(self.handle).abort();
+        }
+        self.rx = RxState::Header { buf: [0; MAX_PREFIX_LEN], have: 0 };
+        self.rx_started = None;
+        self.tx.clear();
+        self.tx_sent = 0;
+        reason
+    }
 }
 
-pub async fn recv_framed(handle: smoltcp::iface::SocketHandle) -> Result<Vec<u8>, ()> {
-    // 1. Read Length
-    let mut len_bytes = [0u8; 4];
-    let mut read = 0;
-    while read < 4 {
-        match (TcpReadFuture { handle, buffer: &mut len_bytes[read..] }).await {
-            Ok(n) => read += n,
-            Err(_) => return Err(()),
+impl Drop for FramedConnection {
+    fn drop(&mut self) {
+        if self.is_idle() {
+            return;
+        }
+        if let Some(stack) = NETWORK_STACK.lock().as_mut() {
+            stack.sockets.get_mut::<tcp::Socket>(self.handle).abort();
         }
     }
-    let len = u32::from_le_bytes(len_bytes) as usize;
-    if len > 1024 * 1024 { return Err(()); } // Sanity check 1MB
-    
-    // 2. Read Data
-    let mut buffer = Vec::with_capacity(len);
-    buffer.resize(len, 0);
-    let mut read = 0;
-    while read < len {
-        match (TcpReadFuture { handle, buffer: &mut buffer[read..] }).await {
-            Ok(n) => read += n,
-            Err(_) => return Err(()),
+}
+
+/// Resolves to `None` once `deadline` (uptime ms) passes before `inner` completes.
+pub(crate) struct Deadline<F> {
+    pub(crate) inner: F,
+    pub(crate) deadline: Option<u64>,
+}
+
+impl<F: Future + Unpin> Future for Deadline<F> {
+    type Output = Option<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Poll::Ready(out) = Pin::new(&mut self.inner).poll(cx) {
+            return Poll::Ready(Some(out));
+        }
+        match self.deadline {
+            Some(deadline) if interrupts::uptime_ms() >= deadline => Poll::Ready(None),
+            _ => Poll::Pending,
         }
     }
-    
-    Ok(buffer)
 }
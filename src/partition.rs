@@ -0,0 +1,272 @@
+//! # Partition Tables (MBR and GPT)
+//!
+//! Reads the partition table at the start of a disk and describes each
+//! partition, so the block layer can expose partitions as separate devices.
+//!
+//! ## Detection order
+//! 1. Read LBA 0. Without the `0x55AA` boot signature there is no table.
+//! 2. If any MBR entry has type `0xEE` (protective MBR), the disk is GPT:
+//!    read the header at LBA 1, verify both CRC32s, and read the entry array.
+//! 3. Otherwise use the four primary MBR entries. Extended partitions
+//!    (types `0x05`, `0x0F`) are skipped; logical volumes aren't supported.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::block::{check_request, BlockDevice, BlockError};
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_TABLE_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_TYPE_PROTECTIVE: u8 = 0xEE;
+const MBR_TYPE_EXTENDED: [u8; 2] = [0x05, 0x0F];
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// Upper bound on the entry array we're willing to read (the spec's
+/// minimum is 128 entries of 128 bytes).
+const GPT_MAX_ENTRY_BYTES: usize = 64 * 1024;
+
+/// Why a partition table couldn't be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionError {
+    /// LBA 0 has no boot signature.
+    NoTable,
+    /// The GPT header is missing or malformed.
+    BadGptHeader,
+    /// A GPT CRC32 didn't match; the table is corrupt.
+    ChecksumMismatch,
+    /// A partition lies (partly) outside the disk.
+    OutOfRange,
+    /// Blocks are smaller than the 512-byte sector the tables assume.
+    UnsupportedBlockSize,
+    /// Reading the disk failed.
+    Io(BlockError),
+}
+
+impl From<BlockError> for PartitionError {
+    fn from(e: BlockError) -> Self {
+        PartitionError::Io(e)
+    }
+}
+
+/// What kind of table a partition came from, with its type identifier.
+#[derive(Debug, Clone)]
+pub enum PartitionKind {
+    Mbr { type_byte: u8 },
+    Gpt { type_guid: [u8; 16], name: String },
+}
+
+impl PartitionKind {
+    /// One-line description for listings, e.g. `mbr 0x83` or
+    /// `gpt 0fc63daf-8483-4772-8e79-3d69d8477de4 "root"`.
+    pub fn describe(&self) -> String {
+        match self {
+            PartitionKind::Mbr { type_byte } => format!("mbr 0x{:02x}", type_byte),
+            PartitionKind::Gpt { type_guid: g, name } => format!(
+                "gpt {:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x} {:?}",
+                le_u32(&g[0..4]),
+                u16::from_le_bytes([g[4], g[5]]),
+                u16::from_le_bytes([g[6], g[7]]),
+                g[8], g[9], g[10], g[11], g[12], g[13], g[14], g[15],
+                name
+            ),
+        }
+    }
+}
+
+/// One partition, in units of the disk's blocks.
+#[derive(Debug, Clone)]
+pub struct Partition {
+    /// 1-based position in the table (used for the `diskNpM` name).
+    pub index: usize,
+    pub start_lba: u64,
+    pub block_count: u64,
+    pub kind: PartitionKind,
+}
+
+/// Read the partition table on `dev`.
+pub fn scan(dev: &dyn BlockDevice) -> Result<Vec<Partition>, PartitionError> {
+    let bs = dev.block_size();
+    if bs < 512 {
+        return Err(PartitionError::UnsupportedBlockSize);
+    }
+    let mut sector = vec![0u8; bs];
+    dev.read_blocks(0, &mut sector)?;
+    if sector[510..512] != MBR_SIGNATURE {
+        return Err(PartitionError::NoTable);
+    }
+
+    let entries: Vec<&[u8]> = (0..4)
+        .map(|i| {
+            let off = MBR_TABLE_OFFSET + i * MBR_ENTRY_SIZE;
+            &sector[off..off + MBR_ENTRY_SIZE]
+        })
+        .collect();
+    if entries.iter().any(|e| e[4] == MBR_TYPE_PROTECTIVE) {
+        return scan_gpt(dev);
+    }
+
+    let mut partitions = Vec::new();
+    for (i, entry) in entries.iter().enumerate() {
+        let type_byte = entry[4];
+        if type_byte == 0 || MBR_TYPE_EXTENDED.contains(&type_byte) {
+            continue;
+        }
+        let start_lba = le_u32(&entry[8..12]) as u64;
+        let block_count = le_u32(&entry[12..16]) as u64;
+        if block_count == 0 {
+            continue;
+        }
+        push_checked(dev, &mut partitions, Partition {
+            index: i + 1,
+            start_lba,
+            block_count,
+            kind: PartitionKind::Mbr { type_byte },
+        })?;
+    }
+    Ok(partitions)
+}
+
+fn scan_gpt(dev: &dyn BlockDevice) -> Result<Vec<Partition>, PartitionError> {
+    let bs = dev.block_size();
+    let mut header = vec![0u8; bs];
+    dev.read_blocks(1, &mut header)?;
+    if &header[0..8] != GPT_SIGNATURE {
+        return Err(PartitionError::BadGptHeader);
+    }
+
+    let header_size = le_u32(&header[12..16]) as usize;
+    if header_size < 92 || header_size > bs {
+        return Err(PartitionError::BadGptHeader);
+    }
+    let header_crc = le_u32(&header[16..20]);
+    let mut check = header[..header_size].to_vec();
+    check[16..20].fill(0);
+    if crc32(&check) != header_crc {
+        return Err(PartitionError::ChecksumMismatch);
+    }
+
+    let entries_lba = le_u64(&header[72..80]);
+    let entry_count = le_u32(&header[80..84]) as usize;
+    let entry_size = le_u32(&header[84..88]) as usize;
+    let entries_crc = le_u32(&header[88..92]);
+    if entry_size < 128 || entry_count.saturating_mul(entry_size) > GPT_MAX_ENTRY_BYTES {
+        return Err(PartitionError::BadGptHeader);
+    }
+
+    let array_len = entry_count * entry_size;
+    let mut array = vec![0u8; array_len.div_ceil(bs) * bs];
+    dev.read_blocks(entries_lba, &mut array)?;
+    if crc32(&array[..array_len]) != entries_crc {
+        return Err(PartitionError::ChecksumMismatch);
+    }
+
+    let mut partitions = Vec::new();
+    for i in 0..entry_count {
+        let entry = &array[i * entry_size..(i + 1) * entry_size];
+        let mut type_guid = [0u8; 16];
+        type_guid.copy_from_slice(&entry[0..16]);
+        if type_guid == [0u8; 16] {
+            continue;
+        }
+        let first = le_u64(&entry[32..40]);
+        let last = le_u64(&entry[40..48]);
+        if last < first {
+            return Err(PartitionError::OutOfRange);
+        }
+        push_checked(dev, &mut partitions, Partition {
+            index: i + 1,
+            start_lba: first,
+            block_count: last - first + 1,
+            kind: PartitionKind::Gpt { type_guid, name: utf16_name(&entry[56..128]) },
+        })?;
+    }
+    Ok(partitions)
+}
+
+/// Add `part` unless it extends past the end of the disk.
+fn push_checked(dev: &dyn BlockDevice, out: &mut Vec<Partition>, part: Partition) -> Result<(), PartitionError> {
+    match part.start_lba.checked_add(part.block_count) {
+        Some(end) if end <= dev.block_count() => {
+            out.push(part);
+            Ok(())
+        }
+        _ => Err(PartitionError::OutOfRange),
+    }
+}
+
+/// Decode a NUL-padded UTF-16LE partition name.
+fn utf16_name(raw: &[u8]) -> String {
+    let units = raw
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|&u| u != 0);
+    char::decode_utf16(units)
+        .map(|r| r.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+fn le_u32(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+fn le_u64(b: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&b[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// CRC-32 (IEEE 802.3, reflected), as used by GPT.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+// ─── Partition Device ────────────────────────────────────────────────────────
+
+/// A window onto a range of blocks of a parent device.
+pub struct PartitionDevice {
+    parent: Arc<dyn BlockDevice>,
+    start_lba: u64,
+    block_count: u64,
+}
+
+impl PartitionDevice {
+    pub fn new(parent: Arc<dyn BlockDevice>, start_lba: u64, block_count: u64) -> Self {
+        PartitionDevice { parent, start_lba, block_count }
+    }
+}
+
+impl BlockDevice for PartitionDevice {
+    fn block_size(&self) -> usize {
+        self.parent.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, lba, buf.len())?;
+        self.parent.read_blocks(self.start_lba + lba, buf)
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self, lba, buf.len())?;
+        self.parent.write_blocks(self.start_lba + lba, buf)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        // The cache belongs to the whole disk; there's no partition-scoped flush.
+        self.parent.flush()
+    }
+}
@@ -0,0 +1,208 @@
+//! # Packet Capture
+//!
+//! A tap in the NIC drivers copies every frame sent and received into a
+//! ring buffer in pcap format, so link-level trouble (SLIRP, DHCP) can be
+//! looked at in Wireshark instead of guessed at from log lines. Capture is
+//! off by default and toggled at runtime with `net.pcap` or the shell's
+//! `pcap on` / `pcap off`. While it is on, NICs that filter received
+//! frames are put in promiscuous mode, so traffic between other hosts on
+//! the link is captured too (smoltcp still ignores it).
+//!
+//! ## Getting the capture out
+//! Either way the reader gets a complete pcap file: the capture as it was
+//! when the dump started.
+//!
+//! - `pcap dump` writes it raw to the second serial port, so run QEMU with
+//!   `-serial stdio -serial file:capture.pcap`. COM2 is also
+//!   `/dev/ttyS1` and, on machines without virtio-serial, the guest agent's
+//!   channel, so don't dump over it while either is in use.
+//! - Connecting to `PCAP_PORT` streams it and closes the connection:
+//!   `nc <address> 5555 > capture.pcap` (with a `hostfwd` under SLIRP).
+//!
+//! ## Ring buffer
+//! The ring holds `RING_BYTES` of records and is allocated the first time
+//! capture is turned on; the heap never frees, so it is then kept. When it
+//! is full the oldest records make room and are counted as dropped. Frames
+//! are cut to `net.pcap.snaplen` bytes.
+//!
+//! Timestamps are uptime (there is no wall clock), so captures start at
+//! the epoch. Frames sent with checksum offload carry the partial checksum
+//! the device completes, which Wireshark flags as wrong.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use smoltcp::iface::SocketHandle;
+use spin::Mutex;
+use crate::executor::Task;
+use crate::interrupts::uptime_ms;
+use crate::net_stack::{self, tcp_close, NETWORK_STACK};
+use crate::p2p::yield_now;
+use crate::p2p_transport::{Deadline, TcpWriteFuture};
+use crate::serial::{self, SerialLine};
+use crate::{config, EXECUTOR};
+
+/// Connecting to this port streams the capture.
+pub const PCAP_PORT: u16 = 5555;
+/// Bytes of records the ring holds.
+const RING_BYTES: usize = 256 * 1024;
+/// pcap record header: seconds, microseconds, captured and original length.
+const RECORD_HEADER_LEN: usize = 16;
+/// Default of `net.pcap.snaplen`: a whole frame at the default MTU.
+const DEFAULT_SNAPLEN: u64 = 1514;
+/// Bytes written to the serial port at a time; interrupts are off meanwhile.
+const SERIAL_CHUNK: usize = 64;
+/// Stop streaming to a client that hasn't read everything after this long.
+const STREAM_TIMEOUT_MS: u64 = 30_000;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SNAPLEN: AtomicU64 = AtomicU64::new(DEFAULT_SNAPLEN);
+static CAPTURED: AtomicU64 = AtomicU64::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// pcap records, oldest first; `None` until capture is first turned on.
+    static ref RING: Mutex<Option<VecDeque<u8>>> = Mutex::new(None);
+    /// Buffers of earlier dumps, reused by the next one.
+    static ref SPARE_FILES: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+}
+
+/// Register the `net.pcap*` tunables and serve `PCAP_PORT`.
+pub fn init() {
+    config::register("net.pcap", "Capture every frame into the pcap ring (1 = on)", 0, 0, 1, Some(apply));
+    config::register("net.pcap.snaplen", "Bytes of each frame kept in the pcap ring", DEFAULT_SNAPLEN, 64, 65_535, Some(apply));
+    EXECUTOR.lock().spawn(Task::new(listen_task()));
+}
+
+/// Apply hook of both keys: reload them.
+fn apply(_: u64) {
+    SNAPLEN.store(config::get_or("net.pcap.snaplen", DEFAULT_SNAPLEN), Ordering::Relaxed);
+    let on = config::get_or("net.pcap", 0) != 0;
+    if on {
+        RING.lock().get_or_insert_with(|| VecDeque::with_capacity(RING_BYTES));
+    }
+    if ENABLED.swap(on, Ordering::Relaxed) != on {
+        net_stack::apply_rx_filters();
+    }
+}
+
+/// Whether capture is on.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// ─── Capture ─────────────────────────────────────────────────────────────────
+
+/// Record `frame` (Ethernet, without the virtio-net header) if capture is on.
+pub fn capture(frame: &[u8]) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let kept = frame.len().min(SNAPLEN.load(Ordering::Relaxed) as usize);
+    let mut ring = RING.lock();
+    let Some(ring) = ring.as_mut() else { return };
+    while ring.len() + RECORD_HEADER_LEN + kept > RING_BYTES {
+        // Drop the oldest record.
+        let len = u32::from_le_bytes([ring[8], ring[9], ring[10], ring[11]]) as usize;
+        ring.drain(..RECORD_HEADER_LEN + len);
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+    let now = uptime_ms();
+    let header = [(now / 1000) as u32, (now % 1000 * 1000) as u32, kept as u32, frame.len() as u32];
+    for field in header {
+        ring.extend(field.to_le_bytes());
+    }
+    ring.extend(&frame[..kept]);
+    CAPTURED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Empty the ring and zero the counters.
+pub fn clear() {
+    if let Some(ring) = RING.lock().as_mut() {
+        ring.clear();
+    }
+    CAPTURED.store(0, Ordering::Relaxed);
+    DROPPED.store(0, Ordering::Relaxed);
+}
+
+/// State of the capture, from `stats`.
+#[derive(Debug, Clone, Copy)]
+pub struct CaptureStats {
+    pub enabled: bool,
+    /// Bytes of records in the ring.
+    pub buffered: usize,
+    /// Frames captured since the last `clear`.
+    pub captured: u64,
+    /// Of those, records pushed out of the full ring.
+    pub dropped: u64,
+}
+
+pub fn stats() -> CaptureStats {
+    CaptureStats {
+        enabled: ENABLED.load(Ordering::Relaxed),
+        buffered: RING.lock().as_ref().map_or(0, |ring| ring.len()),
+        captured: CAPTURED.load(Ordering::Relaxed),
+        dropped: DROPPED.load(Ordering::Relaxed),
+    }
+}
+
+// ─── Dumps ───────────────────────────────────────────────────────────────────
+
+/// The ring as a pcap file, in a buffer to hand back with `release`.
+fn snapshot() -> Vec<u8> {
+    let mut file = SPARE_FILES.lock().pop().unwrap_or_default();
+    file.clear();
+    // Magic, version 2.4, UTC, timestamp accuracy, snaplen, Ethernet.
+    for field in [0xa1b2_c3d4u32, 0x0004_0002, 0, 0, 65_535, 1] {
+        file.extend_from_slice(&field.to_le_bytes());
+    }
+    if let Some(ring) = RING.lock().as_ref() {
+        let (front, back) = ring.as_slices();
+        file.extend_from_slice(front);
+        file.extend_from_slice(back);
+    }
+    file
+}
+
+fn release(file: Vec<u8>) {
+    SPARE_FILES.lock().push(file);
+}
+
+/// Write the capture to the second serial port. Returns its size.
+pub async fn dump_serial() -> usize {
+    let file = snapshot();
+    for chunk in file.chunks(SERIAL_CHUNK) {
+        serial::write_bytes(SerialLine::Com2, chunk);
+        yield_now().await;
+    }
+    let len = file.len();
+    release(file);
+    len
+}
+
+/// Serve connections on `PCAP_PORT` one at a time.
+async fn listen_task() {
+    loop {
+        let accepted = NETWORK_STACK.lock().as_mut().and_then(|stack| stack.accept(PCAP_PORT));
+        match accepted {
+            Some(handle) => stream(handle).await,
+            None => yield_now().await,
+        }
+    }
+}
+
+/// Write the capture to a connection and close it.
+async fn stream(handle: SocketHandle) {
+    let file = snapshot();
+    let deadline = uptime_ms() + STREAM_TIMEOUT_MS;
+    let mut data = &file[..];
+    while !data.is_empty() {
+        match (Deadline { inner: TcpWriteFuture { handle, data }, deadline: Some(deadline) }).await {
+            Some(Ok(n)) => data = &data[n..],
+            Some(Err(())) | None => break,
+        }
+    }
+    tcp_close(handle);
+    release(file);
+}
@@ -0,0 +1,253 @@
+//! # PCI
+//!
+//! Configuration space access through I/O ports 0xCF8/0xCFC, the
+//! capability list, and message-signalled interrupts. Only function 0 of
+//! each slot is looked at.
+//!
+//! ## MSI and MSI-X
+//! Instead of asserting a (shared, level-triggered) PIC line, a device with
+//! MSI or MSI-X writes a message to the local APIC, which raises the vector
+//! encoded in it. `interrupts::allocate_msi_vector` hands out a vector wired
+//! to a `Notification`; `enable_msi` points a device's single MSI message at
+//! one, and `MsiX` programs individual table entries, e.g. one per queue.
+//! Enabling either turns the device's INTx line off.
+
+use x86_64::instructions::port::Port;
+use virtio_drivers::Hal;
+use crate::hal::VirtioHal;
+use crate::interrupts;
+
+/// Capability ID of MSI.
+const CAP_ID_MSI: u8 = 0x05;
+/// Capability ID of vendor-specific capabilities.
+pub(crate) const CAP_ID_VENDOR: u8 = 0x09;
+/// Capability ID of MSI-X.
+const CAP_ID_MSIX: u8 = 0x11;
+/// Capability list entries walked at most, in case the list loops.
+const MAX_CAPABILITIES: usize = 48;
+
+/// Command register bit that disables the INTx line.
+const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+/// Function 0 of a PCI slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciFunction {
+    pub bus: u8,
+    pub slot: u8,
+}
+
+// ─── Configuration Space ─────────────────────────────────────────────────────
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PciHeader {
+    pub vendor_id: u16,
+    pub device_id: u16,
+}
+
+pub(crate) unsafe fn pci_read(bus: u8, slot: u8, func: u8, offset: u8) -> u32 {
+    let address = 0x80000000 | ((bus as u32) << 16) | ((slot as u32) << 11) | ((func as u32) << 8) | ((offset as u32) & 0xfc);
+    let mut command_port = Port::<u32>::new(0xCF8);
+    let mut data_port = Port::<u32>::new(0xCFC);
+    command_port.write(address);
+    data_port.read()
+}
+
+pub(crate) unsafe fn pci_write(bus: u8, slot: u8, func: u8, offset: u8, value: u32) {
+    let address = 0x80000000 | ((bus as u32) << 16) | ((slot as u32) << 11) | ((func as u32) << 8) | ((offset as u32) & 0xfc);
+    let mut command_port = Port::<u32>::new(0xCF8);
+    let mut data_port = Port::<u32>::new(0xCFC);
+    command_port.write(address);
+    data_port.write(value);
+}
+
+pub(crate) unsafe fn pci_write_16(bus: u8, slot: u8, func: u8, offset: u8, value: u16) {
+    let address = 0x80000000 | ((bus as u32) << 16) | ((slot as u32) << 11) | ((func as u32) << 8) | ((offset as u32) & 0xfc);
+    let mut command_port = Port::<u32>::new(0xCF8);
+    // Data port for 16-bit access depends on offset alignment (0xCFC + (offset & 2))
+    let mut data_port = Port::<u16>::new(0xCFC + (offset as u16 & 2));
+    command_port.write(address);
+    data_port.write(value);
+}
+
+/// Physical base address of memory BAR `bar` of `bus:slot.0`, or `None`
+/// for an I/O BAR or a BAR index out of range.
+pub(crate) fn read_pci_bar(bus: u8, slot: u8, bar: u8) -> Option<u64> {
+    if bar > 5 {
+        return None;
+    }
+    let offset = 0x10 + bar * 4;
+    let low = unsafe { pci_read(bus, slot, 0, offset) };
+    if low & 1 != 0 {
+        return None;
+    }
+    // Type bits 2:1 = 0b10: a 64-bit BAR, high half in the next register.
+    let high = if (low >> 1) & 0x3 == 0x2 && bar < 5 { unsafe { pci_read(bus, slot, 0, offset + 4) } } else { 0 };
+    Some(((high as u64) << 32) | (low & !0xf) as u64)
+}
+
+pub(crate) unsafe fn verify_device(bus: u8, slot: u8) -> Option<PciHeader> {
+    let id = pci_read(bus, slot, 0, 0);
+    if id == 0xFFFFFFFF {
+        return None;
+    }
+    Some(PciHeader {
+        vendor_id: (id & 0xFFFF) as u16,
+        device_id: ((id >> 16) & 0xFFFF) as u16,
+    })
+}
+
+impl PciFunction {
+    fn read(self, offset: u8) -> u32 {
+        unsafe { pci_read(self.bus, self.slot, 0, offset) }
+    }
+
+    fn write(self, offset: u8, value: u32) {
+        unsafe { pci_write(self.bus, self.slot, 0, offset, value) }
+    }
+
+    fn write_16(self, offset: u8, value: u16) {
+        unsafe { pci_write_16(self.bus, self.slot, 0, offset, value) }
+    }
+
+    /// Physical base address of memory BAR `bar`.
+    pub fn bar(self, bar: u8) -> Option<u64> {
+        read_pci_bar(self.bus, self.slot, bar)
+    }
+
+    /// The PIC line the firmware wired the INTx pin to, if any (Interrupt
+    /// Line register; 0xff means not connected).
+    pub fn interrupt_line(self) -> Option<u8> {
+        match self.read(0x3c) as u8 {
+            0xff => None,
+            line => Some(line),
+        }
+    }
+
+    /// `(id, offset)` of each entry in the capability list.
+    pub fn capabilities(self) -> Capabilities {
+        // Status register bit 4: the capability list is present.
+        let present = (self.read(0x04) >> 16) & (1 << 4) != 0;
+        let next = if present { (self.read(0x34) & 0xfc) as u8 } else { 0 };
+        Capabilities { function: self, next, remaining: MAX_CAPABILITIES }
+    }
+
+    /// Offset of the first capability with `id`.
+    pub fn find_capability(self, id: u8) -> Option<u8> {
+        self.capabilities().find(|&(cap_id, _)| cap_id == id).map(|(_, offset)| offset)
+    }
+
+    /// Read dword `index` of the capability at `offset` (0 is its header).
+    pub fn capability_dword(self, offset: u8, index: u8) -> u32 {
+        self.read(offset + index * 4)
+    }
+
+    fn disable_intx(self) {
+        let command = self.read(0x04) as u16;
+        self.write_16(0x04, command | COMMAND_INTX_DISABLE);
+    }
+}
+
+/// Iterator over a function's capability list; see `PciFunction::capabilities`.
+pub struct Capabilities {
+    function: PciFunction,
+    next: u8,
+    remaining: usize,
+}
+
+impl Iterator for Capabilities {
+    type Item = (u8, u8);
+
+    fn next(&mut self) -> Option<(u8, u8)> {
+        if self.next == 0 || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let offset = self.next;
+        let header = self.function.read(offset);
+        self.next = (header >> 8) as u8 & 0xfc;
+        Some((header as u8, offset))
+    }
+}
+
+// ─── MSI ─────────────────────────────────────────────────────────────────────
+
+/// Point the function's MSI message at `vector` (from
+/// `interrupts::allocate_msi_vector`) and enable it. Returns false if the
+/// function has no MSI capability.
+pub fn enable_msi(function: PciFunction, vector: u8) -> bool {
+    let Some(cap) = function.find_capability(CAP_ID_MSI) else { return false };
+    let control = (function.read(cap) >> 16) as u16;
+    let (address, data) = interrupts::msi_message(vector);
+    function.write(cap + 4, address as u32);
+    // Bit 7: 64-bit address, which moves the data register up.
+    let data_offset = if control & (1 << 7) != 0 {
+        function.write(cap + 8, (address >> 32) as u32);
+        cap + 12
+    } else {
+        cap + 8
+    };
+    function.write_16(data_offset, data as u16);
+    // One message (Multiple Message Enable = 0), enabled.
+    function.write_16(cap + 2, (control & !(0x7 << 4)) | 1);
+    function.disable_intx();
+    true
+}
+
+// ─── MSI-X ───────────────────────────────────────────────────────────────────
+
+/// Bytes per MSI-X table entry: address (64-bit), data, vector control.
+const MSIX_ENTRY_SIZE: usize = 16;
+
+/// A function's MSI-X table.
+pub struct MsiX {
+    function: PciFunction,
+    cap: u8,
+    table: usize,
+    entries: u16,
+}
+
+impl MsiX {
+    /// The function's MSI-X table, if it has one in a memory BAR.
+    pub fn probe(function: PciFunction) -> Option<MsiX> {
+        let cap = function.find_capability(CAP_ID_MSIX)?;
+        let control = (function.read(cap) >> 16) as u16;
+        let entries = (control & 0x7ff) + 1;
+        // Table Offset/BIR: the low 3 bits name the BAR.
+        let table_reg = function.read(cap + 4);
+        let base = function.bar((table_reg & 0x7) as u8)?;
+        let phys = (base + (table_reg & !0x7) as u64) as usize;
+        let size = entries as usize * MSIX_ENTRY_SIZE;
+        let table = unsafe { VirtioHal::mmio_phys_to_virt(phys, size) }.as_ptr() as usize;
+        Some(MsiX { function, cap, table, entries })
+    }
+
+    /// Number of table entries.
+    pub fn len(&self) -> u16 {
+        self.entries
+    }
+
+    /// Point table entry `entry` at `vector` and unmask it. Returns false
+    /// if there is no such entry.
+    pub fn set_vector(&mut self, entry: u16, vector: u8) -> bool {
+        if entry >= self.entries {
+            return false;
+        }
+        let (address, data) = interrupts::msi_message(vector);
+        let slot = (self.table + entry as usize * MSIX_ENTRY_SIZE) as *mut u32;
+        unsafe {
+            core::ptr::write_volatile(slot, address as u32);
+            core::ptr::write_volatile(slot.add(1), (address >> 32) as u32);
+            core::ptr::write_volatile(slot.add(2), data);
+            core::ptr::write_volatile(slot.add(3), 0);
+        }
+        true
+    }
+
+    /// Turn MSI-X on and INTx off. Entries not set stay masked.
+    pub fn enable(&mut self) {
+        let control = (self.function.read(self.cap) >> 16) as u16;
+        // Bit 15: MSI-X Enable; bit 14: Function Mask.
+        self.function.write_16(self.cap + 2, (control | (1 << 15)) & !(1 << 14));
+        self.function.disable_intx();
+    }
+}
@@ -0,0 +1,458 @@
+//! # Process Table
+//!
+//! Every WASM process started with `spawn` gets a PID and an entry here that
+//! outlives the process itself, so its exit status can be collected later.
+//!
+//! ## Lifecycle
+//! ```text
+//!            Paused
+//!              ▲ │
+//!              │ ▼
+//!   spawn ──► Running ◄──► Blocked
+//!                │            │
+//!                └────┬───────┘
+//!                     ▼
+//!                  Exited(code) ──► reap
+//! ```
+//! - **Running**: scheduled on the executor, one fuel slice per poll.
+//! - **Blocked**: waiting inside a host call (e.g. `env.sleep_ms`,
+//!   `env.ipc_recv_wait`); not consuming slices.
+//! - **Paused**: stopped by `pause` (the shell's debugger) between slices
+//!   until `resume`. Its memory and output stay inspectable through
+//!   `with_instance`.
+//! - **Exited**: the entry point returned (code 0), the guest called
+//!   `env.exit(code)`, it trapped (`EXIT_TRAPPED`), it used up its fuel
+//!   budget (`EXIT_QUOTA`) or it was killed (`EXIT_KILLED`). The entry
+//!   stays until `reap` so a supervisor can read the code; `wait` does
+//!   both.
+//!
+//! `kill` and `pause` are asynchronous: they take effect before the
+//! process's next slice. A killed paused process exits.
+//!
+//! ## Reservations
+//! A process whose CSpace holds a SchedContext capability with EXECUTE is
+//! bound to that CPU reservation for its lifetime (`executor::bind`); the
+//! spawn fails if another process holds it. Without one it runs
+//! best-effort.
+//!
+//! ## Children
+//! A process started by another (`spawn_child`, used by `env.spawn`)
+//! records its parent, and its exit is announced with a `CHILD_EXITED`
+//! message on an endpoint the parent receives from.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::{poll_fn, Future};
+use core::pin::Pin;
+use core::task::Poll;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::capability::{CSpace, Capability, CapabilityType, Permissions, SharedCSpace};
+use crate::ipc::{self, Message, IPC_MANAGER};
+use crate::executor::{self, SchedError, Task, TaskContext};
+use crate::serial_println;
+use crate::wasm_runtime::{ProcessOptions, SliceResult, Wait, WasmError, WasmProcess};
+
+/// Process identifier. PIDs are never reused; 0 means "the kernel".
+pub type Pid = u64;
+
+/// Exit code recorded when a guest traps (including running out of fuel).
+pub const EXIT_TRAPPED: i32 = -1;
+/// Exit code recorded when a process is killed.
+pub const EXIT_KILLED: i32 = -9;
+/// Exit code recorded when a process exhausts its fuel budget.
+pub const EXIT_QUOTA: i32 = -24;
+
+/// IPC label of the message sent to a parent when a child exits:
+/// `data[0]` is the child's PID, `data[1]` its exit code (sign-extended).
+pub const CHILD_EXITED: u64 = 0xC41D;
+
+/// Most entries (live and exited-but-unreaped) the table holds.
+const MAX_PROCESSES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessStatus {
+    Running,
+    Blocked,
+    Paused,
+    Exited(i32),
+}
+
+/// Errors from process table operations.
+#[derive(Debug)]
+pub enum ProcessError {
+    /// No process with that PID.
+    NoSuchProcess,
+    /// The process has already exited.
+    AlreadyExited,
+    /// The process is still running (e.g. `reap` on a live process).
+    StillRunning,
+    /// The table has no free entries; reap some exited processes.
+    TableFull,
+    /// Loading the module failed.
+    Load(WasmError),
+    /// The process's scheduling context can't be bound.
+    Sched(SchedError),
+}
+
+/// A snapshot of one process table entry.
+#[derive(Clone)]
+pub struct ProcessInfo {
+    pub pid: Pid,
+    /// The process that spawned this one, or 0 if the kernel did.
+    pub parent: Pid,
+    pub name: String,
+    pub status: ProcessStatus,
+    /// Fuel consumed so far (see `wasm_runtime::FuelQuota`).
+    pub fuel_used: u64,
+    /// Lifetime fuel budget, or `None` for unlimited.
+    pub fuel_budget: Option<u64>,
+    /// Cap on the process's linear memory, in bytes.
+    pub memory_limit: usize,
+    /// The process's capabilities, shared with its running instance.
+    pub cspace: SharedCSpace,
+}
+
+struct Entry {
+    info: ProcessInfo,
+    kill_requested: bool,
+    pause_requested: bool,
+    /// The running instance, shared with its task; `None` once exited.
+    instance: Option<Arc<Mutex<WasmProcess>>>,
+    /// What the process printed last, kept from its instance on exit.
+    final_output: String,
+    /// Endpoint (WRITE) to announce this process's exit on.
+    exit_notify: Option<Capability>,
+}
+
+struct ProcessTable {
+    entries: Vec<Entry>,
+    next_pid: Pid,
+}
+
+lazy_static! {
+    static ref PROCESSES: Mutex<ProcessTable> = Mutex::new(ProcessTable { entries: Vec::new(), next_pid: 1 });
+}
+
+/// Load a module and start running `entry_point` as a new process.
+///
+/// Returns once the process is scheduled; it starts on the next executor
+/// pass. Safe to call from inside a task.
+///
+/// `options` sets the memory limit, arguments and environment.
+pub fn spawn(
+    name: &str,
+    wasm_bytes: &[u8],
+    entry_point: &str,
+    cspace: CSpace,
+    options: ProcessOptions,
+) -> Result<Pid, ProcessError> {
+    spawn_child(0, name, wasm_bytes, entry_point, cspace, options, None)
+}
+
+/// Start a process on behalf of `parent`. When it exits, a `CHILD_EXITED`
+/// message is sent through `exit_notify` (an Endpoint capability with
+/// WRITE), if given.
+pub fn spawn_child(
+    parent: Pid,
+    name: &str,
+    wasm_bytes: &[u8],
+    entry_point: &str,
+    cspace: CSpace,
+    options: ProcessOptions,
+    exit_notify: Option<Capability>,
+) -> Result<Pid, ProcessError> {
+    // Dropped on any early return below, which releases the reservation.
+    let binding = match cspace.find(CapabilityType::SchedContext, Permissions::EXECUTE).and_then(|slot| cspace.get(slot)) {
+        Some(cap) => Some(executor::bind(cap).map_err(ProcessError::Sched)?),
+        None => None,
+    };
+    let cspace: SharedCSpace = Arc::new(Mutex::new(cspace));
+    let pid = {
+        let mut table = PROCESSES.lock();
+        if table.entries.len() >= MAX_PROCESSES {
+            return Err(ProcessError::TableFull);
+        }
+        let pid = table.next_pid;
+        table.next_pid += 1;
+        pid
+    };
+
+    let process = WasmProcess::new(name, wasm_bytes, entry_point, cspace.clone(), options)
+        .map_err(ProcessError::Load)?;
+    let fuel_budget = process.quota().total;
+    let memory_limit = process.memory_limit();
+    let process = Arc::new(Mutex::new(process));
+    PROCESSES.lock().entries.push(Entry {
+        info: ProcessInfo {
+            pid,
+            parent,
+            name: String::from(name),
+            status: ProcessStatus::Running,
+            fuel_used: 0,
+            fuel_budget,
+            memory_limit,
+            cspace: cspace.clone(),
+        },
+        kill_requested: false,
+        pause_requested: false,
+        instance: Some(process.clone()),
+        final_output: String::new(),
+        exit_notify,
+    });
+
+    let context = TaskContext::for_process(pid, Some(cspace));
+    let task = Task::with_context(run(pid, process), context);
+    match binding {
+        Some(binding) => executor::submit_reserved(task, binding),
+        None => executor::submit(task),
+    }
+    serial_println!("[PROC] Spawned '{}' as PID {}.", name, pid);
+    Ok(pid)
+}
+
+/// Ask a process to stop. It exits with `EXIT_KILLED` before its next slice.
+pub fn kill(pid: Pid) -> Result<(), ProcessError> {
+    let mut table = PROCESSES.lock();
+    let entry = table.entries.iter_mut().find(|e| e.info.pid == pid).ok_or(ProcessError::NoSuchProcess)?;
+    if let ProcessStatus::Exited(_) = entry.info.status {
+        return Err(ProcessError::AlreadyExited);
+    }
+    entry.kill_requested = true;
+    Ok(())
+}
+
+/// Stop a process before its next slice until `resume`.
+pub fn pause(pid: Pid) -> Result<(), ProcessError> {
+    set_pause_requested(pid, true)
+}
+
+/// Let a paused process run again.
+pub fn resume(pid: Pid) -> Result<(), ProcessError> {
+    set_pause_requested(pid, false)
+}
+
+fn set_pause_requested(pid: Pid, paused: bool) -> Result<(), ProcessError> {
+    let mut table = PROCESSES.lock();
+    let entry = table.entries.iter_mut().find(|e| e.info.pid == pid).ok_or(ProcessError::NoSuchProcess)?;
+    if let ProcessStatus::Exited(_) = entry.info.status {
+        return Err(ProcessError::AlreadyExited);
+    }
+    entry.pause_requested = paused;
+    Ok(())
+}
+
+/// Call `f` with a live process's instance, e.g. to read its memory or
+/// output. Between slices the instance is idle, so this doesn't wait.
+pub fn with_instance<R>(pid: Pid, f: impl FnOnce(&WasmProcess) -> R) -> Result<R, ProcessError> {
+    let instance = {
+        let table = PROCESSES.lock();
+        let entry = table.entries.iter().find(|e| e.info.pid == pid).ok_or(ProcessError::NoSuchProcess)?;
+        entry.instance.clone().ok_or(ProcessError::AlreadyExited)?
+    };
+    let process = instance.lock();
+    Ok(f(&process))
+}
+
+/// The last `OUTPUT_LOG_LEN` bytes a process printed, live or exited (until
+/// it is reaped).
+pub fn output(pid: Pid) -> Result<String, ProcessError> {
+    let instance = {
+        let table = PROCESSES.lock();
+        let entry = table.entries.iter().find(|e| e.info.pid == pid).ok_or(ProcessError::NoSuchProcess)?;
+        match &entry.instance {
+            Some(instance) => instance.clone(),
+            None => return Ok(entry.final_output.clone()),
+        }
+    };
+    let output = instance.lock().output().contents();
+    Ok(output)
+}
+
+/// Announce `to`'s exit instead of `from`'s on the endpoint `from` was
+/// spawned with. Used when `to` replaces `from`.
+pub fn transfer_exit_notify(from: Pid, to: Pid) {
+    let mut table = PROCESSES.lock();
+    let cap = table.entries.iter_mut().find(|e| e.info.pid == from).and_then(|e| e.exit_notify.take());
+    if let Some(entry) = table.entries.iter_mut().find(|e| e.info.pid == to) {
+        entry.exit_notify = cap;
+    }
+}
+
+/// Change a process's lifetime fuel budget (`None` for unlimited). A
+/// budget below what it has already used stops it before its next slice.
+pub fn set_fuel_budget(pid: Pid, budget: Option<u64>) -> Result<(), ProcessError> {
+    let mut table = PROCESSES.lock();
+    let entry = table.entries.iter_mut().find(|e| e.info.pid == pid).ok_or(ProcessError::NoSuchProcess)?;
+    if let ProcessStatus::Exited(_) = entry.info.status {
+        return Err(ProcessError::AlreadyExited);
+    }
+    entry.info.fuel_budget = budget;
+    Ok(())
+}
+
+/// Snapshot of one process.
+pub fn get(pid: Pid) -> Option<ProcessInfo> {
+    PROCESSES.lock().entries.iter().find(|e| e.info.pid == pid).map(|e| e.info.clone())
+}
+
+/// Snapshot of every entry, live and exited.
+pub fn list() -> Vec<ProcessInfo> {
+    PROCESSES.lock().entries.iter().map(|e| e.info.clone()).collect()
+}
+
+/// Remove an exited process from the table and return its exit code.
+pub fn reap(pid: Pid) -> Result<i32, ProcessError> {
+    let mut table = PROCESSES.lock();
+    let idx = table.entries.iter().position(|e| e.info.pid == pid).ok_or(ProcessError::NoSuchProcess)?;
+    match table.entries[idx].info.status {
+        ProcessStatus::Exited(code) => {
+            table.entries.swap_remove(idx);
+            Ok(code)
+        }
+        _ => Err(ProcessError::StillRunning),
+    }
+}
+
+/// Wait for a process to exit, then reap it and return its exit code.
+///
+/// For supervisors running as executor tasks. Only one waiter should wait
+/// on a given PID; the others see `NoSuchProcess` once it is reaped.
+pub async fn wait(pid: Pid) -> Result<i32, ProcessError> {
+    exited(pid).await?;
+    reap(pid)
+}
+
+/// Wait for a process to exit and return its exit code, leaving the entry
+/// (and its `output`) in the table until `reap`.
+pub async fn exited(pid: Pid) -> Result<i32, ProcessError> {
+    loop {
+        match get(pid).map(|info| info.status) {
+            None => return Err(ProcessError::NoSuchProcess),
+            Some(ProcessStatus::Exited(code)) => return Ok(code),
+            Some(_) => crate::p2p::yield_now().await,
+        }
+    }
+}
+
+/// Mark a process as blocked in (or returning from) a host call.
+pub fn set_blocked(pid: Pid, blocked: bool) {
+    set_status(pid, if blocked { ProcessStatus::Blocked } else { ProcessStatus::Running });
+}
+
+fn update(pid: Pid, f: impl FnOnce(&mut Entry)) {
+    if let Some(entry) = PROCESSES.lock().entries.iter_mut().find(|e| e.info.pid == pid) {
+        f(entry);
+    }
+}
+
+fn kill_requested(pid: Pid) -> bool {
+    PROCESSES.lock().entries.iter().any(|e| e.info.pid == pid && e.kill_requested)
+}
+
+fn pause_requested(pid: Pid) -> bool {
+    PROCESSES.lock().entries.iter().any(|e| e.info.pid == pid && e.pause_requested)
+}
+
+fn set_status(pid: Pid, status: ProcessStatus) {
+    update(pid, |entry| {
+        if !matches!(entry.info.status, ProcessStatus::Exited(_)) {
+            entry.info.status = status;
+        }
+    });
+}
+
+fn fuel_budget(pid: Pid) -> Option<u64> {
+    PROCESSES.lock().entries.iter().find(|e| e.info.pid == pid).and_then(|e| e.info.fuel_budget)
+}
+
+/// Await `fut`, unless `pid` is killed first (`None`): a kill abandons
+/// whatever the process was waiting for, and it never resumes.
+async fn until_killed<F: Future + Unpin>(pid: Pid, mut fut: F) -> Option<F::Output> {
+    poll_fn(|cx| {
+        if kill_requested(pid) {
+            return Poll::Ready(None);
+        }
+        Pin::new(&mut fut).poll(cx).map(Some)
+    })
+    .await
+}
+
+/// Drive a process one slice per poll until it exits.
+async fn run(pid: Pid, instance: Arc<Mutex<WasmProcess>>) {
+    let name = String::from(instance.lock().name());
+    let code = loop {
+        if kill_requested(pid) {
+            serial_println!("[PROC] PID {} ('{}') killed.", pid, name);
+            break EXIT_KILLED;
+        }
+        if pause_requested(pid) {
+            set_status(pid, ProcessStatus::Paused);
+            serial_println!("[PROC] PID {} ('{}') paused.", pid, name);
+            while pause_requested(pid) && !kill_requested(pid) {
+                crate::p2p::yield_now().await;
+            }
+            set_status(pid, ProcessStatus::Running);
+            continue;
+        }
+        let (result, fuel) = {
+            let mut process = instance.lock();
+            process.set_fuel_budget(fuel_budget(pid));
+            let result = process.run_slice();
+            (result, process.fuel_used())
+        };
+        update(pid, |entry| entry.info.fuel_used = fuel);
+        match result {
+            SliceResult::Preempted => crate::p2p::yield_now().await,
+            SliceResult::Sleeping(deadline) => {
+                set_blocked(pid, true);
+                until_killed(pid, executor::sleep_until(deadline)).await;
+                set_blocked(pid, false);
+            }
+            SliceResult::Blocked(Wait::Message { cap, deadline }) => {
+                set_blocked(pid, true);
+                if let Some(result) = until_killed(pid, ipc::recv_until(cap, deadline)).await {
+                    instance.lock().receive_done(result);
+                }
+                set_blocked(pid, false);
+            }
+            SliceResult::Blocked(Wait::Any { mut set, deadline }) => {
+                set_blocked(pid, true);
+                if let Some(result) = until_killed(pid, set.wait_until(deadline)).await {
+                    instance.lock().wait_done(result);
+                }
+                set_blocked(pid, false);
+            }
+            SliceResult::Exited(code) => break code,
+            SliceResult::Failed(WasmError::QuotaExceeded) => {
+                serial_println!("[PROC] PID {} ('{}') exhausted its fuel budget.", pid, name);
+                break EXIT_QUOTA;
+            }
+            SliceResult::Failed(e) => {
+                serial_println!("[PROC] PID {} ('{}') trapped: {:?}", pid, name, e);
+                break EXIT_TRAPPED;
+            }
+        }
+    };
+    let fuel = instance.lock().fuel_used();
+    let output = instance.lock().output().contents();
+    crate::net_stack::close_process_sockets(&instance.lock().cspace().lock());
+    crate::services::unregister_owner(pid);
+    crate::shm::release_owner(pid);
+    crate::ipc::IPC_MANAGER.lock().release_owner(pid);
+    let mut exit_notify = None;
+    update(pid, |entry| {
+        entry.info.status = ProcessStatus::Exited(code);
+        entry.instance = None;
+        entry.final_output = output;
+        exit_notify = entry.exit_notify.take();
+    });
+    serial_println!("[PROC] PID {} exited with code {} ({} fuel).", pid, code, fuel);
+    if let Some(cap) = exit_notify {
+        let msg = Message::with_data2(CHILD_EXITED, pid, code as i64 as u64);
+        if let Err(e) = IPC_MANAGER.lock().send_with_cap(&cap, msg) {
+            serial_println!("[PROC] Could not notify parent of PID {}: {:?}", pid, e);
+        }
+    }
+}
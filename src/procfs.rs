@@ -0,0 +1,256 @@
+//! # /proc — Kernel State as Files
+//!
+//! A read-only synthetic filesystem that renders kernel state as text on
+//! every read. Shell tooling and WASI guests can inspect the system with
+//! ordinary file reads instead of a bespoke syscall per query.
+//!
+//! ## Layout
+//! | Path | Contents |
+//! |:---|:---|
+//! | `/proc/meminfo`    | Heap usage and free DMA frames |
+//! | `/proc/tasks`      | Live executor tasks and the admission limit |
+//! | `/proc/sockets`    | Every socket in the network stack with its state |
+//! | `/proc/net`        | eth0's address configuration, NIC traffic and recovery, P2P framing counters |
+//! | `/proc/firewall`   | Ingress firewall policy, rules and their hit counts |
+//! | `/proc/block`      | Per-disk I/O counts, errors, retries, latency |
+//! | `/proc/peers`      | P2P routing table and last observed endpoints |
+//! | `/proc/p2p`        | P2P dials, handshakes, RPCs by type and bytes per peer |
+//! | `/proc/wasm`       | WASM module cache hits, misses and size |
+//! | `/proc/shm`        | Shared memory regions, their owners and sizes |
+//! | `/proc/self/caps`  | The reading process's own capabilities |
+//!
+//! Files are generated on demand, so each read is a consistent snapshot of
+//! one subsystem but not across files.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use smoltcp::socket::Socket;
+use crate::addr_book::ADDRESS_BOOK;
+use crate::admission;
+use crate::block;
+use crate::config;
+use crate::firewall;
+use crate::interrupts;
+use crate::net_stack::{self, NETWORK_STACK};
+use crate::p2p::P2P_STATE;
+use crate::p2p_metrics;
+use crate::p2p_transport;
+use crate::shm;
+use crate::vfs::{FileSystem, ReadContext, VfsError};
+use crate::wasm_runtime;
+
+const ROOT_ENTRIES: &[&str] =
+    &["meminfo", "tasks", "sockets", "net", "firewall", "block", "peers", "p2p", "wasm", "shm", "self"];
+const SELF_ENTRIES: &[&str] = &["caps"];
+
+pub struct ProcFs;
+
+impl FileSystem for ProcFs {
+    fn read(&self, path: &str, ctx: &ReadContext) -> Result<Vec<u8>, VfsError> {
+        // Writing into a String never fails, so the fmt results are ignored.
+        let mut out = String::new();
+        let _ = match path {
+            "meminfo" => render_meminfo(&mut out),
+            "tasks" => render_tasks(&mut out),
+            "sockets" => render_sockets(&mut out),
+            "net" => render_net(&mut out),
+            "firewall" => render_firewall(&mut out),
+            "block" => render_block(&mut out),
+            "peers" => render_peers(&mut out),
+            "p2p" => render_p2p(&mut out),
+            "wasm" => render_wasm(&mut out),
+            "shm" => render_shm(&mut out),
+            "self/caps" => render_self_caps(&mut out, ctx),
+            "" | "self" => return Err(VfsError::NotADirectory),
+            _ => return Err(VfsError::NotFound),
+        };
+        Ok(out.into_bytes())
+    }
+
+    fn list(&self, path: &str) -> Result<Vec<String>, VfsError> {
+        let entries = match path {
+            "" => ROOT_ENTRIES,
+            "self" => SELF_ENTRIES,
+            p if self.read(p, &ReadContext::KERNEL).is_ok() => return Err(VfsError::NotADirectory),
+            _ => return Err(VfsError::NotFound),
+        };
+        Ok(entries.iter().map(|e| String::from(*e)).collect())
+    }
+}
+
+fn render_meminfo(out: &mut String) -> core::fmt::Result {
+    let u = admission::utilization();
+    writeln!(out, "heap_used:   {}", u.heap_used)?;
+    writeln!(out, "heap_total:  {}", u.heap_total)?;
+    writeln!(out, "heap_free:   {}", u.heap_total.saturating_sub(u.heap_used))?;
+    writeln!(out, "dma_frames_free: {}", u.frames_free)
+}
+
+fn render_tasks(out: &mut String) -> core::fmt::Result {
+    let u = admission::utilization();
+    writeln!(out, "tasks:     {}", u.tasks)?;
+    writeln!(out, "max_tasks: {}", u.max_tasks)?;
+    writeln!(out, "uptime_ms: {}", interrupts::uptime_ms())
+}
+
+fn render_sockets(out: &mut String) -> core::fmt::Result {
+    let guard = NETWORK_STACK.lock();
+    let stack = match guard.as_ref() {
+        Some(stack) => stack,
+        None => return writeln!(out, "network stack not initialized"),
+    };
+    writeln!(out, "{:<8} {:<6} {:<24} state", "handle", "type", "local")?;
+    for (handle, socket) in stack.sockets.iter().filter(|&(h, _)| !stack.socket_idle(h)) {
+        match socket {
+            Socket::Tcp(s) => {
+                let local = s.local_endpoint();
+                match local {
+                    Some(ep) => writeln!(out, "{:<8} {:<6} {:<24} {}", handle, "tcp", ep, s.state())?,
+                    None => writeln!(out, "{:<8} {:<6} {:<24} {}", handle, "tcp", "-", s.state())?,
+                }
+            }
+            Socket::Udp(s) => {
+                writeln!(out, "{:<8} {:<6} {:<24} {}", handle, "udp", s.endpoint(), if s.is_open() { "bound" } else { "closed" })?;
+            }
+            Socket::Dhcpv4(_) => writeln!(out, "{:<8} {:<6} {:<24} -", handle, "dhcp", "-")?,
+            #[allow(unreachable_patterns)]
+            _ => writeln!(out, "{:<8} {:<6} {:<24} -", handle, "other", "-")?,
+        }
+    }
+    writeln!(out, "total: {}/{}", stack.socket_count(), net_stack::max_sockets())
+}
+
+fn render_net(out: &mut String) -> core::fmt::Result {
+    if let Some(addr) = net_stack::address_config() {
+        writeln!(out, "address_source:   {:?}", addr.source)?;
+        match addr.address {
+            Some(address) => writeln!(out, "address:          {}", address)?,
+            None => writeln!(out, "address:          -")?,
+        }
+        match addr.gateway {
+            Some(gateway) => writeln!(out, "gateway:          {}", gateway)?,
+            None => writeln!(out, "gateway:          -")?,
+        }
+        match addr.dns {
+            Some(dns) => writeln!(out, "dns:              {}", dns)?,
+            None => writeln!(out, "dns:              -")?,
+        }
+    }
+    let nic = net_stack::net_stats().interface;
+    writeln!(out, "rx_packets:       {}", nic.rx_packets)?;
+    writeln!(out, "rx_bytes:         {}", nic.rx_bytes)?;
+    writeln!(out, "rx_dropped:       {}", nic.rx_dropped)?;
+    writeln!(out, "tx_packets:       {}", nic.tx_packets)?;
+    writeln!(out, "tx_bytes:         {}", nic.tx_bytes)?;
+    writeln!(out, "tx_dropped:       {}", nic.tx_dropped)?;
+    writeln!(out, "tx_queue_full:    {}", nic.tx_queue_full)?;
+    let stats = net_stack::recovery_stats();
+    writeln!(out, "wedges_detected:  {}", stats.wedges_detected)?;
+    writeln!(out, "resets_succeeded: {}", stats.resets_succeeded)?;
+    writeln!(out, "resets_failed:    {}", stats.resets_failed)?;
+    let framing = p2p_transport::framing_stats();
+    writeln!(out, "frames_oversized: {}", framing.oversized)?;
+    writeln!(out, "frame_timeouts:   {}", framing.timeouts)
+}
+
+fn render_firewall(out: &mut String) -> core::fmt::Result {
+    let (allowed, denied) = firewall::counters();
+    let default = if config::get_or("net.firewall.default", 1) != 0 { "allow" } else { "deny" };
+    writeln!(out, "default: {}", default)?;
+    writeln!(out, "allowed: {}", allowed)?;
+    writeln!(out, "denied:  {}", denied)?;
+    writeln!(out, "{:>3} {:>10}  rule", "#", "hits")?;
+    for (i, (rule, hits)) in firewall::rules().iter().enumerate() {
+        writeln!(out, "{:>3} {:>10}  {}", i, hits, rule)?;
+    }
+    Ok(())
+}
+
+fn render_block(out: &mut String) -> core::fmt::Result {
+    writeln!(out, "{:<8} {:>8} {:>8} {:>7} {:>7} {:>12} {:>12} health",
+        "device", "reads", "writes", "errors", "retries", "p50_cycles", "p99_cycles")?;
+    for (name, stats) in block::stats() {
+        writeln!(out, "{:<8} {:>8} {:>8} {:>7} {:>7} {:>12} {:>12} {}",
+            name,
+            stats.reads,
+            stats.writes,
+            stats.errors,
+            stats.retries,
+            stats.latency_percentile(500),
+            stats.latency_percentile(990),
+            if stats.degraded { "DEGRADED" } else { "ok" })?;
+    }
+    Ok(())
+}
+
+fn render_wasm(out: &mut String) -> core::fmt::Result {
+    let stats = wasm_runtime::module_cache_stats();
+    writeln!(out, "module_cache_hits:    {}", stats.hits)?;
+    writeln!(out, "module_cache_misses:  {}", stats.misses)?;
+    writeln!(out, "module_cache_entries: {}", stats.entries)
+}
+
+fn render_shm(out: &mut String) -> core::fmt::Result {
+    writeln!(out, "{:>6} {:>6} {:>10}", "id", "owner", "bytes")?;
+    for region in shm::list() {
+        writeln!(out, "{:>6} {:>6} {:>10}", region.id, region.owner, region.len)?;
+    }
+    Ok(())
+}
+
+fn render_peers(out: &mut String) -> core::fmt::Result {
+    let guard = P2P_STATE.lock();
+    let state = match guard.as_ref() {
+        Some(state) => state,
+        None => return writeln!(out, "p2p not initialized"),
+    };
+    writeln!(out, "local: {} {:?}", state.peer_id, state.node_id)?;
+    let now = interrupts::uptime_ms();
+    let book = ADDRESS_BOOK.lock();
+    for (idx, bucket) in state.routing_table.buckets.iter().enumerate() {
+        for peer in &bucket.peers {
+            match book.peer_endpoint(&peer.node_id, now).or(peer.endpoint) {
+                Some(ep) => writeln!(out, "bucket {:>3}: {} {:?} {}", idx, peer.peer_id_str, peer.node_id, ep)?,
+                None => writeln!(out, "bucket {:>3}: {} {:?} -", idx, peer.peer_id_str, peer.node_id)?,
+            }
+        }
+    }
+    Ok(())
+}
+
+fn render_p2p(out: &mut String) -> core::fmt::Result {
+    let metrics = p2p_metrics::snapshot();
+    writeln!(out, "dials:             {}", metrics.dials)?;
+    writeln!(out, "dials_succeeded:   {}", metrics.dials_succeeded)?;
+    writeln!(out, "handshakes:        {}", metrics.handshakes)?;
+    writeln!(out, "handshakes_failed: {}", metrics.handshakes_failed)?;
+    writeln!(out, "{:<14} {:>10} {:>10}", "rpc", "sent", "received")?;
+    for rpc in &metrics.rpcs {
+        writeln!(out, "{:<14} {:>10} {:>10}", rpc.name, rpc.sent, rpc.received)?;
+    }
+    writeln!(out, "{:<20} {:>12} {:>12}", "peer", "bytes_in", "bytes_out")?;
+    for peer in &metrics.peers {
+        writeln!(out, "{:<20} {:>12} {:>12}", alloc::format!("{:?}", peer.peer), peer.bytes_in, peer.bytes_out)?;
+    }
+    Ok(())
+}
+
+fn render_self_caps(out: &mut String, ctx: &ReadContext) -> core::fmt::Result {
+    let cspace = match ctx.cspace {
+        Some(cspace) => cspace,
+        None => return writeln!(out, "kernel: all capabilities"),
+    };
+    writeln!(out, "{:<5} {:<13} {:<6} resource", "slot", "type", "perms")?;
+    for (slot, cap) in cspace.iter() {
+        writeln!(
+            out,
+            "{:<5} {:<13} {:#06x} {}",
+            slot,
+            alloc::format!("{:?}", cap.cap_type),
+            cap.permissions.bits(),
+            cap.resource_id
+        )?;
+    }
+    Ok(())
+}
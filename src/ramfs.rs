@@ -0,0 +1,69 @@
+//! # RAM Filesystem
+//!
+//! A writable, flat, in-memory filesystem (mounted at `/tmp`). Files are
+//! whole-file blobs replaced on every write; there are no subdirectories.
+//! Contents live on the kernel heap and vanish on reboot.
+//!
+//! Note that the heap is a bump allocator: overwriting a file does not give
+//! the old contents back. `MAX_FILE_SIZE` and `MAX_FILES` keep a careless
+//! writer from exhausting it.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::vfs::{FileSystem, ReadContext, VfsError};
+
+/// Largest file accepted by `write`.
+pub const MAX_FILE_SIZE: usize = 1024 * 1024;
+/// Most files the filesystem will hold.
+const MAX_FILES: usize = 64;
+
+pub struct RamFs {
+    files: Mutex<Vec<(String, Vec<u8>)>>,
+}
+
+impl RamFs {
+    pub const fn new() -> Self {
+        RamFs { files: Mutex::new(Vec::new()) }
+    }
+}
+
+impl FileSystem for RamFs {
+    fn read(&self, path: &str, _ctx: &ReadContext) -> Result<Vec<u8>, VfsError> {
+        if path.is_empty() {
+            return Err(VfsError::NotADirectory);
+        }
+        self.files
+            .lock()
+            .iter()
+            .find(|(name, _)| name == path)
+            .map(|(_, data)| data.clone())
+            .ok_or(VfsError::NotFound)
+    }
+
+    fn list(&self, path: &str) -> Result<Vec<String>, VfsError> {
+        if !path.is_empty() {
+            return Err(VfsError::NotADirectory);
+        }
+        Ok(self.files.lock().iter().map(|(name, _)| name.clone()).collect())
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> Result<(), VfsError> {
+        if path.is_empty() || path.contains('/') {
+            return Err(VfsError::InvalidPath);
+        }
+        if data.len() > MAX_FILE_SIZE {
+            return Err(VfsError::NoSpace);
+        }
+        let mut files = self.files.lock();
+        if let Some((_, contents)) = files.iter_mut().find(|(name, _)| name == path) {
+            *contents = data.to_vec();
+            return Ok(());
+        }
+        if files.len() >= MAX_FILES {
+            return Err(VfsError::NoSpace);
+        }
+        files.push((String::from(path), data.to_vec()));
+        Ok(())
+    }
+}
@@ -1,28 +1,93 @@
-use getrandom::{register_custom_getrandom, Error};
+//! # Kernel RNG
+//!
+//! The single source of randomness for the kernel (`getrandom`, `/dev/random`)
+//! and for WASM guests (`env.random_get`), suitable for keys and nonces.
+//!
+//! ## Design
+//! A hash DRBG over SHA-256:
+//! - **Output**: block *i* of a request is `SHA-256(key ‖ counter ‖ i)`.
+//! - **Forward secrecy**: after every request the key is replaced by a hash
+//!   of itself, so a later state compromise doesn't reveal earlier output.
+//! - **Entropy**: the key is seeded on first use, and reseeded every
+//!   `RESEED_INTERVAL` requests, from RDRAND (when the CPU has it), the
+//!   TSC, and a pool fed with TSC samples by the timer interrupt.
+//!
+//! The interrupt handler only XORs into an atomic (`add_interrupt_sample`),
+//! so it never touches the DRBG lock.
+
 use core::sync::atomic::{AtomicU64, Ordering};
+use getrandom::{register_custom_getrandom, Error};
+use sha2::{Digest, Sha256};
+use spin::Mutex;
+use x86_64::instructions::random::RdRand;
+
+/// Requests served between reseeds from the entropy sources.
+const RESEED_INTERVAL: u64 = 1024;
+
+/// Interrupt timing samples, folded together.
+static INTERRUPT_POOL: AtomicU64 = AtomicU64::new(0);
+
+struct Drbg {
+    key: [u8; 32],
+    counter: u64,
+    seeded: bool,
+}
+
+static DRBG: Mutex<Drbg> = Mutex::new(Drbg { key: [0; 32], counter: 0, seeded: false });
+
+/// Fold a timing sample into the entropy pool. Cheap enough for interrupt
+/// handlers.
+pub fn add_interrupt_sample(sample: u64) {
+    let pool = INTERRUPT_POOL.load(Ordering::Relaxed);
+    INTERRUPT_POOL.store(pool.rotate_left(7) ^ sample, Ordering::Relaxed);
+}
 
-// Simple Xorshift RNG for PoC (NOT SECURE)
-static RHS_SEED: AtomicU64 = AtomicU64::new(0xCAFEBABE);
+/// Fill `buf` with cryptographically strong random bytes.
+pub fn fill(buf: &mut [u8]) {
+    let mut drbg = DRBG.lock();
+    if !drbg.seeded || drbg.counter.is_multiple_of(RESEED_INTERVAL) {
+        drbg.reseed();
+    }
+    drbg.counter += 1;
 
-fn next_u64() -> u64 {
-    let mut x = RHS_SEED.load(Ordering::Relaxed);
-    if x == 0 {
-        x = 0xCAFEBABE; // Avoid zero seed lock
+    for (i, chunk) in buf.chunks_mut(32).enumerate() {
+        let block = Sha256::new()
+            .chain_update(drbg.key)
+            .chain_update(drbg.counter.to_le_bytes())
+            .chain_update((i as u64).to_le_bytes())
+            .finalize();
+        chunk.copy_from_slice(&block[..chunk.len()]);
     }
-    x ^= x << 13;
-    x ^= x >> 7;
-    x ^= x << 17;
-    RHS_SEED.store(x, Ordering::Relaxed);
-    x
+
+    // Ratchet so this output can't be recomputed from a later state.
+    drbg.key = Sha256::new().chain_update(b"ratchet").chain_update(drbg.key).finalize().into();
 }
 
-pub fn custom_getrandom(buf: &mut [u8]) -> Result<(), Error> {
-    for chunk in buf.chunks_mut(8) {
-        let rand = next_u64();
-        let bytes = rand.to_le_bytes();
-        let len = chunk.len();
-        chunk.copy_from_slice(&bytes[..len]);
+impl Drbg {
+    /// Mix fresh entropy into the key.
+    fn reseed(&mut self) {
+        let mut hasher = Sha256::new().chain_update(self.key);
+        if let Some(rand) = RdRand::new() {
+            for _ in 0..8 {
+                if let Some(value) = rand.get_u64() {
+                    hasher.update(value.to_le_bytes());
+                }
+            }
+        }
+        hasher.update(INTERRUPT_POOL.load(Ordering::Relaxed).to_le_bytes());
+        hasher.update(crate::interrupts::get_ticks().to_le_bytes());
+        hasher.update(read_tsc().to_le_bytes());
+        self.key = hasher.finalize().into();
+        self.seeded = true;
     }
+}
+
+fn read_tsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+pub fn custom_getrandom(buf: &mut [u8]) -> Result<(), Error> {
+    fill(buf);
     Ok(())
 }
 
@@ -0,0 +1,514 @@
+//! # RTL8139 Driver
+//!
+//! The Realtek RTL8139 is the NIC emulators offer when virtio isn't there:
+//! QEMU's `-device rtl8139`, older hypervisors, nested setups. `init` finds
+//! it by PCI ID after the virtio NICs and adds it to the stack as a
+//! `NetDevice`; everything above the driver treats it like any other NIC.
+//!
+//! ## Receive
+//! The chip writes frames back to back into one ring of `RX_RING_LEN`
+//! bytes, each behind a 4-byte header (status, then the length including
+//! the CRC) and aligned to 4 bytes. With WRAP set a frame running past the
+//! end continues into the `RX_RING_SLACK` bytes after it instead of
+//! wrapping, so every frame is contiguous. The driver copies each frame
+//! out into a `FRAME_POOL` buffer and moves CAPR past it. A frame with a
+//! bad header means the ring is out of step, and the chip is restarted.
+//!
+//! ## Transmit
+//! Four descriptors, used in turn, each a buffer address (TSAD) and a
+//! status word (TSD). Writing the length to TSD hands the buffer to the
+//! chip; TOK, or an error bit, says it is done with it.
+//!
+//! ## Limits
+//! The chip only does 32-bit DMA, so its buffers must be below 4 GiB. No
+//! checksum offload, and the MTU is at most the Ethernet default. The
+//! receive filter is the 64-bit multicast hash in MAR0-7, plus AAP for
+//! promiscuous mode.
+
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
+use lazy_static::lazy_static;
+use smoltcp::phy::{Device, DeviceCapabilities, Medium, RxToken, TxToken};
+use smoltcp::time::Instant;
+use smoltcp::wire::EthernetAddress;
+use spin::Mutex;
+use x86_64::instructions::port::{Port, PortRead, PortWrite};
+use crate::interrupts;
+use crate::klog::Level;
+use crate::net_interface::{
+    self, ControlError, DmaBuffer, NetDevice, DEFAULT_MTU, MAX_MULTICAST_FILTER, MIN_MTU, RX_BYTES, RX_DROPPED,
+    RX_PACKETS, TX_BYTES, TX_DROPPED, TX_PACKETS, TX_QUEUE_FULL, WEDGE_TIMEOUT_MS,
+};
+use crate::net_stack::{self, NIC_IRQ};
+use crate::pci::{pci_read, pci_write_16, verify_device, PciFunction};
+use crate::{klog, serial_println};
+
+const VENDOR_REALTEK: u16 = 0x10ec;
+const DEVICE_RTL8139: u16 = 0x8139;
+
+// Registers, as offsets in the I/O BAR.
+const IDR0: u16 = 0x00;
+const MAR0: u16 = 0x08;
+const TSD0: u16 = 0x10;
+const TSAD0: u16 = 0x20;
+const RBSTART: u16 = 0x30;
+const CR: u16 = 0x37;
+const CAPR: u16 = 0x38;
+const IMR: u16 = 0x3c;
+const ISR: u16 = 0x3e;
+const TCR: u16 = 0x40;
+const RCR: u16 = 0x44;
+const CONFIG1: u16 = 0x52;
+
+const CR_RST: u8 = 0x10;
+const CR_RE: u8 = 0x08;
+const CR_TE: u8 = 0x04;
+/// The RX ring is empty.
+const CR_BUFE: u8 = 0x01;
+
+/// Receive OK, receive error, transmit OK, transmit error, RX overflow.
+const IMR_VALUE: u16 = 0x001f;
+
+/// Maximum DMA burst of 2048 bytes, standard interframe gap.
+const TCR_VALUE: u32 = 0x0300_0700;
+/// Clear an aborted transmission so the next one can go.
+const TCR_CLRABT: u32 = 0x01;
+
+const RCR_AAP: u32 = 0x01;
+const RCR_APM: u32 = 0x02;
+const RCR_AM: u32 = 0x04;
+const RCR_AB: u32 = 0x08;
+const RCR_WRAP: u32 = 0x80;
+/// Unlimited DMA burst, no RX FIFO threshold (whole frames), 8K ring.
+const RCR_VALUE: u32 = (7 << 13) | (7 << 8) | RCR_WRAP | RCR_AB | RCR_AM | RCR_APM;
+
+const TSD_TUN: u32 = 1 << 14;
+const TSD_TOK: u32 = 1 << 15;
+const TSD_TABT: u32 = 1 << 30;
+/// Start sending once 256 bytes are in the FIFO.
+const TSD_EARLY_THRESHOLD: u32 = (256 / 32) << 16;
+
+/// RX header status bit: the frame is good.
+const RX_ROK: u16 = 0x0001;
+/// Length the chip shows while it is still writing a frame.
+const RX_LEN_PENDING: u16 = 0xfff0;
+
+const TX_DESCRIPTORS: usize = 4;
+/// Bytes of ring the chip wraps at (RBLEN 8K), and the extra it needs.
+const RX_RING_LEN: usize = 8192;
+const RX_RING_SLACK: usize = 16 + 1536;
+/// Largest frame a TX descriptor takes, and the shortest Ethernet allows.
+const MAX_TX_FRAME: usize = 1792;
+const MIN_FRAME: usize = 60;
+/// Largest frame, CRC included, the ring can hold.
+const MAX_RX_FRAME: usize = 1536;
+/// Give up on a software reset after this long.
+const RESET_TIMEOUT_MS: u64 = 100;
+
+lazy_static! {
+    /// Buffers received frames are copied into.
+    static ref FRAME_POOL: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+}
+
+/// Add every RTL8139 on the PCI bus to the network stack.
+pub fn init() {
+    for bus in 0..255 {
+        for slot in 0..32 {
+            let header = match unsafe { verify_device(bus, slot) } {
+                Some(header) => header,
+                None => continue,
+            };
+            if header.vendor_id != VENDOR_REALTEK || header.device_id != DEVICE_RTL8139 {
+                continue;
+            }
+            serial_println!("[RTL8139] Found at {:02x}:{:02x}", bus, slot);
+            let bar0 = unsafe { pci_read(bus, slot, 0, 0x10) };
+            if bar0 & 1 != 1 {
+                serial_println!("[RTL8139] BAR0 is not I/O space; skipping.");
+                continue;
+            }
+            let command = unsafe { pci_read(bus, slot, 0, 0x04) } as u16;
+            unsafe { pci_write_16(bus, slot, 0, 0x04, command | 0x7) };
+
+            let function = PciFunction { bus, slot };
+            match function.interrupt_line() {
+                Some(line) if interrupts::route_irq(line, &NIC_IRQ) => {
+                    serial_println!("[RTL8139] Interrupts on IRQ {}.", line);
+                }
+                _ => { serial_println!("[RTL8139] No usable interrupt; polling only."); }
+            }
+            if let Some(device) = Rtl8139Device::new((bar0 & !0x3) as u16) {
+                let mac = device.mac.0;
+                net_stack::add_interface(NetDevice::Rtl8139(device), mac);
+            }
+        }
+    }
+}
+
+/// smoltcp Device implementation for an RTL8139.
+pub struct Rtl8139Device {
+    io_base: u16,
+    mac: EthernetAddress,
+    /// The RX ring, then one buffer per TX descriptor.
+    buffers: Vec<DmaBuffer>,
+    /// Offset in the RX ring of the next frame.
+    rx_offset: usize,
+    /// Descriptor the next frame goes out on.
+    tx_next: usize,
+    /// Oldest descriptor that may still be in flight.
+    tx_dirty: usize,
+    tx_pending: [bool; TX_DESCRIPTORS],
+    /// Last time the chip showed signs of life (TX completion, RX frame, or idle).
+    last_progress: Instant,
+    mtu: usize,
+    promiscuous: bool,
+    /// MAR0-7 while not promiscuous.
+    multicast: [u32; 2],
+    /// Frames for smoltcp that never crossed the wire (see `neighbor`).
+    injected: Vec<Vec<u8>>,
+}
+
+impl Rtl8139Device {
+    /// Reset and start the chip with its registers at `io_base`. `None` if
+    /// its buffers can't be had or it doesn't come out of reset.
+    pub fn new(io_base: u16) -> Option<Self> {
+        let mut buffers = Vec::with_capacity(1 + TX_DESCRIPTORS);
+        buffers.push(net_interface::pooled_buffer((RX_RING_LEN + RX_RING_SLACK).div_ceil(4096))?);
+        for _ in 0..TX_DESCRIPTORS {
+            buffers.push(net_interface::pooled_buffer(MAX_TX_FRAME.div_ceil(4096))?);
+        }
+        if buffers.iter().any(|buffer| buffer.phys_addr() + buffer.size() > u32::MAX as usize) {
+            serial_println!("[RTL8139] DMA memory above 4 GiB; the chip can't reach it.");
+            for buffer in buffers {
+                net_interface::release_buffer(buffer);
+            }
+            return None;
+        }
+        let mut device = Rtl8139Device {
+            io_base,
+            mac: EthernetAddress([0; 6]),
+            buffers,
+            rx_offset: 0,
+            tx_next: 0,
+            tx_dirty: 0,
+            tx_pending: [false; TX_DESCRIPTORS],
+            last_progress: Instant::ZERO,
+            mtu: DEFAULT_MTU,
+            promiscuous: false,
+            multicast: [u32::MAX; 2],
+            injected: Vec::new(),
+        };
+        let mut mac = [0; 6];
+        for (i, byte) in mac.iter_mut().enumerate() {
+            *byte = device.read(IDR0 + i as u16);
+        }
+        device.mac = EthernetAddress(mac);
+        serial_println!("[RTL8139] MAC Address: {}", device.mac);
+        if !device.restart() {
+            device.reset();
+            return None;
+        }
+        device.set_mtu(crate::config::get_or("net.mtu", DEFAULT_MTU as u64) as usize);
+        Some(device)
+    }
+
+    fn read<T: PortRead>(&self, register: u16) -> T {
+        unsafe { Port::<T>::new(self.io_base + register).read() }
+    }
+
+    fn write<T: PortWrite>(&self, register: u16, value: T) {
+        unsafe { Port::<T>::new(self.io_base + register).write(value) }
+    }
+
+    /// Software-reset the chip and start it again with empty rings and the
+    /// current receive filter. Frames in flight are lost. Returns false if
+    /// the chip doesn't come out of reset.
+    pub fn restart(&mut self) -> bool {
+        // Wake it from power-down, then reset.
+        self.write::<u8>(CONFIG1, 0);
+        self.write::<u8>(CR, CR_RST);
+        let deadline = interrupts::uptime_ms() + RESET_TIMEOUT_MS;
+        while self.read::<u8>(CR) & CR_RST != 0 {
+            if interrupts::uptime_ms() > deadline {
+                serial_println!("[RTL8139] Chip did not come out of reset.");
+                return false;
+            }
+            core::hint::spin_loop();
+        }
+        self.write::<u32>(RBSTART, self.buffers[0].phys_addr() as u32);
+        for slot in 0..TX_DESCRIPTORS {
+            self.write::<u32>(TSAD0 + 4 * slot as u16, self.buffers[1 + slot].phys_addr() as u32);
+        }
+        // TCR and RCR only take effect once RX and TX are enabled.
+        self.write::<u8>(CR, CR_RE | CR_TE);
+        self.write::<u32>(TCR, TCR_VALUE);
+        self.write_rx_filter();
+        self.write::<u16>(IMR, IMR_VALUE);
+        self.rx_offset = 0;
+        self.tx_next = 0;
+        self.tx_dirty = 0;
+        self.tx_pending = [false; TX_DESCRIPTORS];
+        true
+    }
+
+    /// Stop the chip and return its DMA buffers to the pool.
+    ///
+    /// The device is unusable afterwards; drop it and probe the NIC again.
+    pub fn reset(&mut self) {
+        self.write::<u16>(IMR, 0);
+        self.write::<u8>(CR, CR_RST);
+        for buffer in self.buffers.drain(..) {
+            net_interface::release_buffer(buffer);
+        }
+    }
+
+    /// Current IP MTU.
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// Use `requested` as the IP MTU, or the Ethernet default if it is
+    /// larger. Returns the MTU now in use.
+    pub fn set_mtu(&mut self, requested: usize) -> usize {
+        self.mtu = requested.clamp(MIN_MTU, DEFAULT_MTU);
+        serial_println!("[RTL8139] MTU {} (device allows {})", self.mtu, DEFAULT_MTU);
+        self.mtu
+    }
+
+    /// Number of TX descriptors handed to the chip and not yet completed.
+    pub fn tx_in_flight(&self) -> usize {
+        self.tx_pending.iter().filter(|&&pending| pending).count()
+    }
+
+    /// True if transmissions have been pending for longer than
+    /// `WEDGE_TIMEOUT_MS` without the chip completing any of them.
+    pub fn is_wedged(&self, now: Instant) -> bool {
+        self.tx_in_flight() > 0 && now.total_millis() - self.last_progress.total_millis() > WEDGE_TIMEOUT_MS
+    }
+
+    /// Deliver every frame on the link, not only those for this NIC.
+    pub fn set_promiscuous(&mut self, on: bool) -> Result<(), ControlError> {
+        self.promiscuous = on;
+        self.write_rx_filter();
+        Ok(())
+    }
+
+    /// Deliver multicast frames only for `groups` (Ethernet addresses), or
+    /// every multicast frame if there are more than `MAX_MULTICAST_FILTER`.
+    /// The hash filter lets some other groups through as well.
+    pub fn set_multicast_filter(&mut self, groups: &[[u8; 6]]) -> Result<(), ControlError> {
+        self.multicast = if groups.len() > MAX_MULTICAST_FILTER { [u32::MAX; 2] } else { [0; 2] };
+        for group in groups.iter().take(MAX_MULTICAST_FILTER) {
+            let bit = ethernet_crc(group) >> 26;
+            self.multicast[(bit >> 5) as usize] |= 1 << (bit & 31);
+        }
+        self.write_rx_filter();
+        Ok(())
+    }
+
+    fn write_rx_filter(&self) {
+        let (rcr, multicast) = match self.promiscuous {
+            true => (RCR_VALUE | RCR_AAP, [u32::MAX; 2]),
+            false => (RCR_VALUE, self.multicast),
+        };
+        self.write::<u32>(RCR, rcr);
+        self.write::<u32>(MAR0, multicast[0]);
+        self.write::<u32>(MAR0 + 4, multicast[1]);
+    }
+
+    /// Have the next `receive` return `frame`, as if it had arrived.
+    fn inject(&mut self, frame: &[u8]) {
+        let mut data = FRAME_POOL.lock().pop().unwrap_or_default();
+        data.clear();
+        data.extend_from_slice(frame);
+        self.injected.push(data);
+    }
+
+    /// Acknowledge the chip's interrupt; it raises the line again only
+    /// after that.
+    fn acknowledge(&self) {
+        let status: u16 = self.read(ISR);
+        if status != 0 {
+            self.write::<u16>(ISR, status);
+        }
+    }
+
+    /// Take descriptors the chip is done with back, in order.
+    fn poll_tx_completions(&mut self, now: Instant) {
+        while self.tx_pending[self.tx_dirty] {
+            let status: u32 = self.read(TSD0 + 4 * self.tx_dirty as u16);
+            if status & (TSD_TOK | TSD_TUN | TSD_TABT) == 0 {
+                break;
+            }
+            if status & TSD_TABT != 0 {
+                klog!(klog::NET, Level::Warn, "[RTL8139] Transmission aborted (TSD {:#010x})", status);
+                TX_DROPPED.fetch_add(1, Ordering::Relaxed);
+                self.write::<u32>(TCR, TCR_VALUE | TCR_CLRABT);
+            }
+            self.tx_pending[self.tx_dirty] = false;
+            self.tx_dirty = (self.tx_dirty + 1) % TX_DESCRIPTORS;
+            self.last_progress = now;
+        }
+        if self.tx_in_flight() == 0 {
+            // Nothing outstanding, so there is nothing to be stuck on.
+            self.last_progress = now;
+        }
+    }
+
+    /// Copy the next frame out of the RX ring. `None` when it is empty.
+    fn next_frame(&mut self) -> Option<Vec<u8>> {
+        if self.read::<u8>(CR) & CR_BUFE != 0 {
+            return None;
+        }
+        let offset = self.rx_offset;
+        let ring = self.buffers[0].as_mut_slice();
+        let status = u16::from_le_bytes([ring[offset], ring[offset + 1]]);
+        let len = u16::from_le_bytes([ring[offset + 2], ring[offset + 3]]);
+        if len == RX_LEN_PENDING {
+            return None;
+        }
+        let len = usize::from(len);
+        if status & RX_ROK == 0 || !(MIN_FRAME + 4..=MAX_RX_FRAME).contains(&len) {
+            klog!(klog::NET, Level::Error, "[RTL8139] Bad RX header {:#06x}/{} at {}; restarting", status, len, offset);
+            RX_DROPPED.fetch_add(1, Ordering::Relaxed);
+            self.restart();
+            return None;
+        }
+        let mut frame = FRAME_POOL.lock().pop().unwrap_or_default();
+        frame.clear();
+        // Without the CRC.
+        frame.extend_from_slice(&ring[offset + 4..offset + len]);
+        self.rx_offset = (offset + 4 + len).next_multiple_of(4) % RX_RING_LEN;
+        // CAPR trails the read offset by 16, a quirk of the chip.
+        self.write::<u16>(CAPR, self.rx_offset.wrapping_sub(16) as u16);
+        Some(frame)
+    }
+}
+
+/// The big-endian CRC-32 of an Ethernet address, whose top six bits pick
+/// its bit in the multicast hash.
+fn ethernet_crc(address: &[u8; 6]) -> u32 {
+    let mut crc = u32::MAX;
+    for &byte in address {
+        for bit in 0..8 {
+            let carry = (crc >> 31) ^ u32::from((byte >> bit) & 1);
+            crc <<= 1;
+            if carry != 0 {
+                crc ^= 0x04c1_1db7;
+            }
+        }
+    }
+    crc
+}
+
+/// A received frame, copied out of the ring.
+pub struct Rtl8139RxToken {
+    frame: Vec<u8>,
+}
+
+impl RxToken for Rtl8139RxToken {
+    fn consume<R, F>(mut self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        f(&mut self.frame)
+    }
+}
+
+impl Drop for Rtl8139RxToken {
+    fn drop(&mut self) {
+        FRAME_POOL.lock().push(core::mem::take(&mut self.frame));
+    }
+}
+
+pub struct Rtl8139TxToken<'a> {
+    device: &'a mut Rtl8139Device,
+}
+
+impl<'a> TxToken for Rtl8139TxToken<'a> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let device = self.device;
+        let slot = device.tx_next;
+        if device.tx_pending[slot] || len > MAX_TX_FRAME {
+            // Every descriptor is busy (a reply to a received frame), or
+            // the frame is too big: build it and drop it.
+            let mut scratch = FRAME_POOL.lock().pop().unwrap_or_default();
+            scratch.clear();
+            scratch.resize(len, 0);
+            let result = f(&mut scratch);
+            FRAME_POOL.lock().push(scratch);
+            if len <= MAX_TX_FRAME {
+                TX_QUEUE_FULL.fetch_add(1, Ordering::Relaxed);
+            }
+            TX_DROPPED.fetch_add(1, Ordering::Relaxed);
+            return result;
+        }
+
+        let buffer = device.buffers[1 + slot].as_mut_slice();
+        let result = f(&mut buffer[..len]);
+        let mut reply = [0; crate::neighbor::ARP_FRAME_LEN];
+        if crate::neighbor::static_reply(device.mac, &buffer[..len], &mut reply) {
+            // A static neighbor: answer smoltcp's ARP request ourselves.
+            device.inject(&reply);
+            return result;
+        }
+        net_interface::transmit_tap(&buffer[..len]);
+        klog!(klog::NET, Level::Trace, "[RTL8139 TX] {} bytes on descriptor {}", len, slot);
+
+        // The chip doesn't pad short frames itself.
+        let size = len.max(MIN_FRAME);
+        buffer[len..size].fill(0);
+        device.tx_pending[slot] = true;
+        device.tx_next = (slot + 1) % TX_DESCRIPTORS;
+        device.write::<u32>(TSD0 + 4 * slot as u16, TSD_EARLY_THRESHOLD | size as u32);
+        TX_PACKETS.fetch_add(1, Ordering::Relaxed);
+        TX_BYTES.fetch_add(len as u64, Ordering::Relaxed);
+        result
+    }
+}
+
+impl Device for Rtl8139Device {
+    type RxToken<'a> = Rtl8139RxToken;
+    type TxToken<'a> = Rtl8139TxToken<'a>;
+
+    fn receive(&mut self, timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        self.acknowledge();
+        self.poll_tx_completions(timestamp);
+        if let Some(frame) = self.injected.pop() {
+            return Some((Rtl8139RxToken { frame }, Rtl8139TxToken { device: self }));
+        }
+        loop {
+            let frame = self.next_frame()?;
+            self.last_progress = timestamp;
+            RX_PACKETS.fetch_add(1, Ordering::Relaxed);
+            RX_BYTES.fetch_add(frame.len() as u64, Ordering::Relaxed);
+            klog!(klog::NET, Level::Trace, "[RTL8139 RX] {} bytes", frame.len());
+            if !net_interface::receive_tap(self.mac, &frame) {
+                FRAME_POOL.lock().push(frame);
+                continue;
+            }
+            return Some((Rtl8139RxToken { frame }, Rtl8139TxToken { device: self }));
+        }
+    }
+
+    fn transmit(&mut self, timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        self.poll_tx_completions(timestamp);
+        if self.tx_pending[self.tx_next] {
+            TX_QUEUE_FULL.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(Rtl8139TxToken { device: self })
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        // smoltcp counts the Ethernet header in the MTU.
+        caps.max_transmission_unit = self.mtu + 14;
+        caps.max_burst_size = Some(1);
+        caps.medium = Medium::Ethernet;
+        caps
+    }
+}
@@ -21,6 +21,8 @@ use lazy_static::lazy_static;
 
 /// The standard I/O port address for COM1 (first serial port).
 const COM1_PORT: u16 = 0x3F8;
+/// The standard I/O port address for COM2 (the guest agent's fallback channel).
+const COM2_PORT: u16 = 0x2F8;
 
 lazy_static! {
     /// Global serial port instance, protected by a spinlock.
@@ -35,6 +37,31 @@ lazy_static! {
         serial_port.init();
         Mutex::new(serial_port)
     };
+
+    /// COM2, initialized on first use. Kept off the console so a host tool
+    /// can own it as a clean byte stream.
+    pub static ref SERIAL2: Mutex<SerialPort> = {
+        // SAFETY: Port 0x2F8 is the standard COM2 address; single instance.
+        let mut serial_port = unsafe { SerialPort::new(COM2_PORT) };
+        serial_port.init();
+        Mutex::new(serial_port)
+    };
+}
+
+/// A serial line exposed as a character device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialLine {
+    Com1,
+    Com2,
+}
+
+impl SerialLine {
+    fn port(self) -> (&'static Mutex<SerialPort>, u16) {
+        match self {
+            SerialLine::Com1 => (&SERIAL1, COM1_PORT),
+            SerialLine::Com2 => (&SERIAL2, COM2_PORT),
+        }
+    }
 }
 
 /// Internal print function. Use `serial_print!` or `serial_println!` instead.
@@ -52,6 +79,38 @@ pub fn _print(args: ::core::fmt::Arguments) {
     });
 }
 
+/// Write raw bytes to a serial line (used by the `/dev/ttyS*` devices).
+pub fn write_bytes(line: SerialLine, data: &[u8]) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut port = line.port().0.lock();
+        for &byte in data {
+            port.send_raw(byte);
+        }
+    });
+}
+
+/// Read one byte from a serial line if the UART has one ready; never blocks.
+pub fn try_read_byte(line: SerialLine) -> Option<u8> {
+    use x86_64::instructions::interrupts;
+    use x86_64::instructions::port::Port;
+
+    let (serial, base) = line.port();
+    interrupts::without_interrupts(|| {
+        // Hold the lock so we don't race a writer programming the UART.
+        let _port = serial.lock();
+        unsafe {
+            // Bit 0 of the line status register (base + 5) is "data ready".
+            if Port::<u8>::new(base + 5).read() & 1 != 0 {
+                Some(Port::<u8>::new(base).read())
+            } else {
+                None
+            }
+        }
+    })
+}
+
 /// Print to the serial console (no newline).
 #[macro_export]
 macro_rules! serial_print {
@@ -0,0 +1,137 @@
+//! # Service Registry
+//!
+//! Maps service names (`"net.echo"`, `"p2p.dht"`) to IPC endpoints, so
+//! processes find each other by name instead of by hardcoded endpoint
+//! slots.
+//!
+//! A provider registers an Endpoint capability it may delegate (`GRANT`).
+//! `lookup` hands each client a fresh capability derived from it with only
+//! `WRITE`: clients can send requests but can neither receive the
+//! provider's traffic nor pass the capability on. Registrations made by a
+//! process are dropped when it exits.
+//!
+//! Names are 1–`MAX_NAME_LEN` characters of `[a-z0-9._-]`. The first
+//! registration of a name wins until it is removed.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::capability::{Capability, CapabilityType, Permissions};
+use crate::ipc::{Message, IPC_MANAGER};
+use crate::process::Pid;
+
+/// Longest service name accepted.
+pub const MAX_NAME_LEN: usize = 64;
+
+/// Errors from registry operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceError {
+    /// The name is empty, too long or has characters outside `[a-z0-9._-]`.
+    InvalidName,
+    /// Another provider already registered this name.
+    AlreadyRegistered,
+    /// The capability is not an Endpoint capability with `GRANT`.
+    NotDelegable,
+    /// No service has this name.
+    NotFound,
+}
+
+/// A registry entry, as listed by `list`.
+#[derive(Debug, Clone)]
+pub struct ServiceInfo {
+    pub name: String,
+    /// PID of the registering process (0 for the kernel).
+    pub owner: Pid,
+    /// Resource id of the service's endpoint.
+    pub endpoint_id: u64,
+}
+
+struct Entry {
+    info: ServiceInfo,
+    cap: Capability,
+}
+
+lazy_static! {
+    static ref REGISTRY: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+}
+
+/// Publish `cap` under `name` on behalf of `owner`.
+pub fn register(name: &str, cap: Capability, owner: Pid) -> Result<(), ServiceError> {
+    if !valid_name(name) {
+        return Err(ServiceError::InvalidName);
+    }
+    if cap.cap_type != CapabilityType::Endpoint || !cap.permissions.contains(Permissions::GRANT) {
+        return Err(ServiceError::NotDelegable);
+    }
+    let mut registry = REGISTRY.lock();
+    if registry.iter().any(|e| e.info.name == name) {
+        return Err(ServiceError::AlreadyRegistered);
+    }
+    registry.push(Entry {
+        info: ServiceInfo { name: String::from(name), owner, endpoint_id: cap.resource_id },
+        cap,
+    });
+    Ok(())
+}
+
+/// A send-only capability to the service called `name`.
+pub fn lookup(name: &str) -> Result<Capability, ServiceError> {
+    REGISTRY
+        .lock()
+        .iter()
+        .find(|e| e.info.name == name)
+        .and_then(|e| e.cap.derive(Permissions::WRITE))
+        .ok_or(ServiceError::NotFound)
+}
+
+/// Remove `name`. Only its owner (or the kernel, as owner 0) may do so.
+pub fn unregister(name: &str, owner: Pid) -> Result<(), ServiceError> {
+    let mut registry = REGISTRY.lock();
+    let idx = registry
+        .iter()
+        .position(|e| e.info.name == name && (owner == 0 || e.info.owner == owner))
+        .ok_or(ServiceError::NotFound)?;
+    registry.swap_remove(idx);
+    Ok(())
+}
+
+/// Drop every registration made by `owner`. Called when a process exits.
+pub fn unregister_owner(owner: Pid) {
+    REGISTRY.lock().retain(|e| e.info.owner != owner);
+}
+
+/// Move every registration made by `from` to `to`, e.g. when `to` is a new
+/// version of the service replacing `from`.
+pub fn transfer_owner(from: Pid, to: Pid) {
+    for entry in REGISTRY.lock().iter_mut().filter(|e| e.info.owner == from) {
+        entry.info.owner = to;
+    }
+}
+
+/// Send `msg` to every registered service's endpoint, with the kernel's
+/// authority (the provider's capability need not carry `WRITE`). Returns
+/// how many endpoints took it.
+pub fn notify_all(msg: &Message) -> usize {
+    let caps: Vec<Capability> = REGISTRY.lock().iter().map(|e| e.cap.clone()).collect();
+    let manager = IPC_MANAGER.lock();
+    caps.iter()
+        .filter(|cap| {
+            manager
+                .resolve(cap, Permissions::NONE)
+                .and_then(|slot| manager.send(slot, msg.clone()))
+                .is_ok()
+        })
+        .count()
+}
+
+/// Every registered service.
+pub fn list() -> Vec<ServiceInfo> {
+    REGISTRY.lock().iter().map(|e| e.info.clone()).collect()
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name.bytes().all(|b| matches!(b, b'a'..=b'z' | b'0'..=b'9' | b'.' | b'_' | b'-'))
+}
@@ -0,0 +1,1000 @@
+//! # Kernel Debug Shell
+//!
+//! A line-oriented command prompt on the serial console (COM1) for poking at
+//! a running kernel. Subsystems add their own commands with `register`;
+//! `help` lists them.
+//!
+//! Input is polled from an executor task (never blocks the idle loop) and
+//! echoed back, with backspace support. Output goes through `serial_println!`
+//! and so interleaves with kernel logging.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::pin::pin;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::config;
+use crate::dns::RecordType;
+use crate::executor::{self, Task};
+use crate::addr_book::ADDRESS_BOOK;
+use crate::p2p_kademlia::{NodeId, K_BUCKET_SIZE};
+use crate::p2p_reputation::Subject;
+use crate::p2p_transport::Deadline;
+use crate::process::{self, ProcessStatus};
+use crate::ipc::{ENDPOINT_QUEUE_SIZE, IPC_MANAGER};
+use crate::serial::{self, SerialLine};
+use crate::supervisor::{self, RestartPolicy};
+use crate::upgrade::{self, UpgradeSpec};
+use crate::wasm_runtime::ProcessOptions;
+use crate::{block, dns, firewall, icmp, initrd, kv, mdns, neighbor, net_stack, p2p, p2p_blocks, p2p_conn, p2p_metrics, p2p_nat, p2p_pubsub, p2p_relay, p2p_reputation, p2p_rpc, p2p_store, pcap, serial_print, serial_println, services, shutdown, tls};
+
+/// Longest command line accepted; extra characters are ignored.
+const MAX_LINE: usize = 256;
+/// How long `ping` waits for a reply.
+const PING_TIMEOUT_MS: u64 = 2_000;
+/// How long `mdns discover` waits for answers.
+const MDNS_DISCOVER_MS: u64 = 2_000;
+
+/// A command handler; receives the arguments after the command name.
+pub type Handler = fn(&[&str]);
+
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    handler: Handler,
+}
+
+lazy_static! {
+    static ref COMMANDS: Mutex<Vec<Command>> = Mutex::new(Vec::new());
+}
+
+/// Add a command. A later registration with the same name replaces the
+/// earlier one.
+pub fn register(name: &'static str, help: &'static str, handler: Handler) {
+    let mut commands = COMMANDS.lock();
+    commands.retain(|c| c.name != name);
+    commands.push(Command { name, help, handler });
+}
+
+/// Register the built-in commands. Call once before spawning `shell_task`.
+pub fn init() {
+    register("help", "List available commands", cmd_help);
+    register("ipcstat", "Per-endpoint IPC counters and queue high-watermarks", cmd_ipcstat);
+    register("shutdown", "Flush state, close connections and power off", cmd_shutdown);
+    register("config", "config [get <key> | set <key> <value>] — kernel tunables", cmd_config);
+    register("ps", "Processes with their status, fuel use and memory limit", cmd_ps);
+    register("budget", "budget <pid> <fuel|none> — set a process's lifetime fuel budget", cmd_budget);
+    register("kv", "Key-value namespaces and their quota use", cmd_kv);
+    register("lsblk", "Disks and partitions with their size and partition type", cmd_lsblk);
+    register("supervised", "Supervised services, their current PIDs and restart counts", cmd_supervised);
+    register("upgrade", "upgrade <pid> <module> [fresh] — replace a service with an initrd module", cmd_upgrade);
+    register("dns", "dns [name | server <ip>...] — resolver cache, or look up a name", cmd_dns);
+    register("ping", "ping <host> — ICMP echo round-trip time", cmd_ping);
+    register("connect", "connect <host> <port> — try an outbound TCP connection", cmd_connect);
+    register("tls", "tls [pin <host> <key-hex> | <host> <port> [line]] — pinned keys, or try a TLS connection", cmd_tls);
+    register("mdns", "mdns [discover] — peers found on the local link, or ask for more", cmd_mdns);
+    register("netstat", "NIC frame counters and per-socket traffic", cmd_netstat);
+    register("ifconfig", "Network interfaces with their MAC, MTU and address", cmd_ifconfig);
+    register("arp", "arp [add <ip> <mac> | del <ip>]: neighbor cache and static entries", cmd_arp);
+    register("firewall", "firewall [allow|deny <tcp|udp|icmp|any> <port[-port]|*> [cidr] | del <n> | clear]", cmd_firewall);
+    register("pcap", "pcap [on | off | clear | dump] — packet capture state, or control it", cmd_pcap);
+    register("services", "Registered service names and their endpoints", cmd_services);
+    register("dial", "dial <host>:<port> — handshake with a P2P peer, retrying with backoff", cmd_dial);
+    register("conns", "Open P2P connections with their direction, queues and idle time", cmd_conns);
+    register("peers", "The P2P routing table by bucket, with occupancy, last-seen times and peer addresses", cmd_peers);
+    register("p2pstat", "P2P dials, handshakes, RPCs by type and bytes exchanged per peer", cmd_p2pstat);
+    register("identify", "identify <node-id-hex> — ask a P2P peer what it runs and where it sees this node from", cmd_identify);
+    register("nat", "nat [punch <node-id> <relay-node-id>] — addresses peers see this node at, or connect to a peer behind NAT through a relay", cmd_nat);
+    register("relay", "relay [on|off] — list the circuits this node relays for other peers, or turn relaying on or off", cmd_relay);
+    register("lookup", "lookup [<node-id-hex>] — find the P2P peers closest to a node (default: this one)", cmd_lookup);
+    register("dht", "dht [put <name> <value> | get <name>] — list DHT records held here, publish one or fetch one", cmd_dht);
+    register("blocks", "blocks [put <text> | get <hash> | providers <hash> | rm <hash>] — list blocks held here, store and provide an object, fetch one, find its providers or drop a block", cmd_blocks);
+    register("pubsub", "pubsub [pub <topic> <message> | sub <topic>] — list topics, publish, or print a topic's messages for a minute", cmd_pubsub);
+    register("reputation", "reputation [unban <node id | address>] — list peer penalty points, offenses and bans, or lift a ban", cmd_reputation);
+}
+
+/// Read and execute commands from the console forever.
+pub async fn shell_task() {
+    let mut line = String::new();
+    serial_print!("> ");
+    loop {
+        let byte = match serial::try_read_byte(SerialLine::Com1) {
+            Some(byte) => byte,
+            None => {
+                p2p::yield_now().await;
+                continue;
+            }
+        };
+        match byte {
+            b'\r' | b'\n' => {
+                serial_println!();
+                execute(&line);
+                line.clear();
+                serial_print!("> ");
+            }
+            // Backspace / DEL
+            0x08 | 0x7F => {
+                if line.pop().is_some() {
+                    serial_print!("\x08 \x08");
+                }
+            }
+            b if (b.is_ascii_graphic() || b == b' ') && line.len() < MAX_LINE => {
+                line.push(b as char);
+                serial_print!("{}", b as char);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Run one command line.
+pub fn execute(line: &str) {
+    let mut words = line.split_whitespace();
+    let name = match words.next() {
+        Some(name) => name,
+        None => return,
+    };
+    let args: Vec<&str> = words.collect();
+
+    // Copy the handler out so commands may themselves register commands.
+    let handler = COMMANDS.lock().iter().find(|c| c.name == name).map(|c| c.handler);
+    match handler {
+        Some(handler) => handler(&args),
+        None => {
+            serial_println!("Unknown command '{}'. Try 'help'.", name);
+        }
+    }
+}
+
+// ─── Built-in Commands ───────────────────────────────────────────────────────
+
+fn cmd_help(_args: &[&str]) {
+    for command in COMMANDS.lock().iter() {
+        serial_println!("  {:<12} {}", command.name, command.help);
+    }
+}
+
+fn cmd_ipcstat(_args: &[&str]) {
+    let stats = IPC_MANAGER.lock().all_stats();
+    serial_println!("Endpoint queue capacity: {}", ENDPOINT_QUEUE_SIZE);
+    serial_println!("{:>4} {:>6} {:>10} {:>10} {:>8} {:>7} {:>5}", "SLOT", "ID", "SENT", "RECEIVED", "DROPPED", "PENDING", "HIGH");
+    for (slot, id, s) in stats {
+        serial_println!(
+            "{:>4} {:>6} {:>10} {:>10} {:>8} {:>7} {:>5}",
+            slot, id, s.sent, s.received, s.dropped, s.pending, s.high_watermark
+        );
+    }
+}
+
+fn cmd_config(args: &[&str]) {
+    match args {
+        [] => {
+            for t in config::list() {
+                let mode = if t.read_only { "ro" } else if t.live { "live" } else { "" };
+                serial_println!(
+                    "  {:<30} {:>10} {:<4} [{}..={}, default {}] {}",
+                    t.key, t.value, mode, t.min, t.max, t.default, t.help
+                );
+            }
+        }
+        ["get", key] => match config::get(key) {
+            Some(value) => { serial_println!("{} = {}", key, value); }
+            None => { serial_println!("Unknown key '{}'.", key); }
+        },
+        ["set", key, value] => match value.parse::<u64>() {
+            Ok(value) => match config::set(key, value) {
+                Ok(()) => { serial_println!("{} = {}", key, value); }
+                Err(e) => { serial_println!("Cannot set {}: {:?}", key, e); }
+            },
+            Err(_) => { serial_println!("'{}' is not a non-negative integer.", value); }
+        },
+        _ => { serial_println!("Usage: config [get <key> | set <key> <value>]"); }
+    }
+}
+
+fn cmd_ps(_args: &[&str]) {
+    serial_println!(
+        "{:>5} {:>6} {:<20} {:<12} {:>14} {:>14} {:>8}",
+        "PID", "PARENT", "NAME", "STATUS", "FUEL", "BUDGET", "MEM KIB"
+    );
+    for p in process::list() {
+        let status = match p.status {
+            ProcessStatus::Running => String::from("running"),
+            ProcessStatus::Blocked => String::from("blocked"),
+            ProcessStatus::Paused => String::from("paused"),
+            ProcessStatus::Exited(code) => alloc::format!("exited({})", code),
+        };
+        let budget = match p.fuel_budget {
+            Some(budget) => alloc::format!("{}", budget),
+            None => String::from("-"),
+        };
+        serial_println!(
+            "{:>5} {:>6} {:<20} {:<12} {:>14} {:>14} {:>8}",
+            p.pid, p.parent, p.name, status, p.fuel_used, budget, p.memory_limit / 1024
+        );
+    }
+}
+
+fn cmd_budget(args: &[&str]) {
+    let (pid, budget) = match args {
+        [pid, budget] => (pid.parse::<u64>(), *budget),
+        _ => {
+            serial_println!("Usage: budget <pid> <fuel|none>");
+            return;
+        }
+    };
+    let budget = match budget {
+        "none" => Ok(None),
+        fuel => fuel.parse::<u64>().map(Some),
+    };
+    match (pid, budget) {
+        (Ok(pid), Ok(budget)) => match process::set_fuel_budget(pid, budget) {
+            Ok(()) => { serial_println!("PID {} budget set.", pid); }
+            Err(e) => { serial_println!("Cannot set budget: {:?}", e); }
+        },
+        _ => { serial_println!("Usage: budget <pid> <fuel|none>"); }
+    }
+}
+
+fn cmd_services(_args: &[&str]) {
+    serial_println!("{:<32} {:>6} {:>8}", "NAME", "OWNER", "ENDPOINT");
+    for service in services::list() {
+        serial_println!("{:<32} {:>6} {:>8}", service.name, service.owner, service.endpoint_id);
+    }
+}
+
+fn cmd_kv(_args: &[&str]) {
+    serial_println!("{:>4} {:<24} {:>12} {:>16}", "ID", "NAMESPACE", "KEYS", "BYTES");
+    for ns in kv::list() {
+        let keys = alloc::format!("{}/{}", ns.keys, ns.max_keys);
+        let bytes = alloc::format!("{}/{}", ns.bytes, ns.max_bytes);
+        serial_println!("{:>4} {:<24} {:>12} {:>16}", ns.id, ns.name, keys, bytes);
+    }
+}
+
+fn cmd_lsblk(_args: &[&str]) {
+    serial_println!("{:<12} {:>6} {:>12} {:>10} {}", "NAME", "BSIZE", "BLOCKS", "MIB", "TYPE");
+    for dev in block::list() {
+        serial_println!(
+            "{:<12} {:>6} {:>12} {:>10} {}",
+            dev.name,
+            dev.block_size,
+            dev.block_count,
+            dev.block_count * dev.block_size as u64 / (1024 * 1024),
+            dev.partition.map_or(String::from("disk"), |kind| kind.describe()),
+        );
+    }
+}
+
+fn cmd_supervised(_args: &[&str]) {
+    serial_println!("{:<24} {:>10} {:>6} {:<10} {:>8}", "NAME", "SUPERVISOR", "PID", "POLICY", "RESTARTS");
+    for child in supervisor::list() {
+        let policy = match child.policy {
+            RestartPolicy::Always => "always",
+            RestartPolicy::OnFailure => "on-failure",
+        };
+        serial_println!(
+            "{:<24} {:>10} {:>6} {:<10} {:>8}",
+            child.name, child.supervisor, child.pid, policy, child.restarts
+        );
+    }
+}
+
+fn cmd_upgrade(args: &[&str]) {
+    let (pid, module, keep_state) = match args {
+        [pid, module] => (pid.parse::<u64>(), *module, true),
+        [pid, module, "fresh"] => (pid.parse::<u64>(), *module, false),
+        _ => {
+            serial_println!("Usage: upgrade <pid> <module> [fresh]");
+            return;
+        }
+    };
+    let pid = match pid {
+        Ok(pid) => pid,
+        Err(_) => {
+            serial_println!("Usage: upgrade <pid> <module> [fresh]");
+            return;
+        }
+    };
+    let module = match initrd::find(module) {
+        Some(bytes) => bytes,
+        None => {
+            serial_println!("No module '{}' in the initrd.", module);
+            return;
+        }
+    };
+    let spec = UpgradeSpec { module, entry: "main", options: ProcessOptions::default(), keep_state };
+    match upgrade::upgrade(pid, spec) {
+        Ok(new_pid) => { serial_println!("PID {} replaced by PID {}.", pid, new_pid); }
+        Err(e) => { serial_println!("Cannot upgrade: {:?}", e); }
+    }
+}
+
+fn cmd_dns(args: &[&str]) {
+    match args {
+        [] => {
+            serial_println!("servers: {:?}", dns::servers());
+            let now = crate::interrupts::uptime_ms();
+            for entry in ADDRESS_BOOK.lock().names(now) {
+                serial_println!("{:<32} {:?} {:?}", entry.name, entry.record_type, entry.addrs);
+            }
+        }
+        ["server", servers @ ..] => {
+            let parsed: Option<Vec<_>> = servers.iter().map(|s| dns::parse_ipv4(s)).collect();
+            match parsed {
+                Some(servers) => {
+                    dns::set_servers(&servers);
+                    serial_println!("servers: {:?}", dns::servers());
+                }
+                None => { serial_println!("Usage: dns server <ip>..."); }
+            }
+        }
+        [name] => {
+            // Lookups wait on the network, so they run as their own task.
+            let name = String::from(*name);
+            executor::submit(Task::new(async move {
+                let a = dns::lookup(&name, RecordType::A).await;
+                let aaaa = dns::lookup(&name, RecordType::Aaaa).await;
+                serial_println!("{}: A {:?}, AAAA {:?}", name, a, aaaa);
+            }));
+        }
+        _ => { serial_println!("Usage: dns [name | server <ip>...]"); }
+    }
+}
+
+fn cmd_netstat(_args: &[&str]) {
+    let stats = net_stack::net_stats();
+    let nic = stats.interface;
+    serial_println!("rx: {} packets, {} bytes, {} dropped", nic.rx_packets, nic.rx_bytes, nic.rx_dropped);
+    serial_println!(
+        "tx: {} packets, {} bytes, {} dropped, queue full {} times",
+        nic.tx_packets, nic.tx_bytes, nic.tx_dropped, nic.tx_queue_full
+    );
+    let (retransmits, zero_windows) = crate::tcp_monitor::totals();
+    serial_println!("tcp: {} segments retransmitted, {} zero windows advertised", retransmits, zero_windows);
+    serial_println!(
+        "{:<8} {:>12} {:>12} {:>8} {:>7} {:>7} {:>7} {:>7} {:>7}",
+        "socket", "rx_bytes", "tx_bytes", "tx_full", "buffer", "rx_win", "tx_q", "retrans", "zerowin"
+    );
+    for (handle, socket) in stats.sockets {
+        serial_println!(
+            "{:<8} {:>12} {:>12} {:>8} {:>7} {:>7} {:>7} {:>7} {:>7}",
+            handle, socket.rx_bytes, socket.tx_bytes, socket.tx_full, socket.buffer, socket.rx_window,
+            socket.tx_queued, socket.retransmits, socket.zero_windows
+        );
+    }
+}
+
+fn cmd_ifconfig(_args: &[&str]) {
+    if let Some(hostname) = net_stack::hostname() {
+        serial_println!("hostname {}", hostname);
+    }
+    serial_println!("{:<6} {:<18} {:>5} {:<13} {:<19} {}", "name", "mac", "mtu", "source", "address", "gateway");
+    for interface in net_stack::interfaces() {
+        let address = &interface.address;
+        serial_println!(
+            "{:<6} {:<18} {:>5} {:<13} {:<19} {}",
+            alloc::format!("eth{}", interface.index),
+            alloc::format!("{}", interface.mac),
+            interface.mtu,
+            alloc::format!("{:?}", address.source),
+            address.address.map_or(String::from("-"), |cidr| alloc::format!("{}", cidr)),
+            address.gateway.map_or(String::from("-"), |gateway| alloc::format!("{}", gateway)),
+        );
+    }
+}
+
+fn cmd_arp(args: &[&str]) {
+    match args {
+        [] => {
+            let interfaces = net_stack::interfaces();
+            serial_println!("{} addresses failed to resolve", neighbor::failures());
+            serial_println!("{:<16} {:<18} {:<5} {}", "address", "mac", "nic", "origin");
+            for entry in neighbor::entries() {
+                let nic = match interfaces.iter().find(|i| i.mac == entry.nic) {
+                    Some(interface) => alloc::format!("eth{}", interface.index),
+                    None => String::from("*"),
+                };
+                let origin = match entry.origin {
+                    neighbor::Origin::Static => String::from("static"),
+                    neighbor::Origin::Learned(at) => {
+                        alloc::format!("learned {}s ago", crate::interrupts::uptime_ms().saturating_sub(at) / 1000)
+                    }
+                };
+                serial_println!("{:<16} {:<18} {:<5} {}", entry.address, entry.mac, nic, origin);
+            }
+        }
+        ["add", ip, mac] => {
+            let (Some(ip), Ok(mac)) = (dns::parse_ipv4(ip), mac.parse()) else {
+                serial_println!("Usage: arp add <ip> <mac>");
+                return;
+            };
+            if let Err(e) = neighbor::add_static(ip, mac) {
+                serial_println!("Cannot add entry: {:?}", e);
+            }
+        }
+        ["del", ip] => match dns::parse_ipv4(ip).map(neighbor::remove_static) {
+            Some(Ok(())) => {}
+            _ => { serial_println!("No static entry for {}.", ip); }
+        },
+        _ => { serial_println!("Usage: arp [add <ip> <mac> | del <ip>]"); }
+    }
+}
+
+fn cmd_firewall(args: &[&str]) {
+    match args {
+        [] => {
+            let (allowed, denied) = firewall::counters();
+            let default = if config::get_or("net.firewall.default", 1) != 0 { "allow" } else { "deny" };
+            serial_println!("default {}; {} packets allowed, {} denied", default, allowed, denied);
+            for (i, (rule, hits)) in firewall::rules().iter().enumerate() {
+                serial_println!("{:>3} {:>10}  {}", i, hits, rule);
+            }
+        }
+        ["del", n] => match n.parse().map(firewall::remove) {
+            Ok(Ok(rule)) => { serial_println!("Removed: {}", rule); }
+            _ => { serial_println!("No rule {}.", n); }
+        },
+        ["clear"] => firewall::clear(),
+        [action, protocol, ports, rest @ ..] if rest.len() <= 1 => {
+            let Some(rule) = parse_rule(action, protocol, ports, rest.first().copied()) else {
+                serial_println!("Usage: firewall allow|deny <tcp|udp|icmp|any> <port[-port]|*> [cidr]");
+                return;
+            };
+            match firewall::insert(usize::MAX, rule) {
+                Ok(i) => { serial_println!("{:>3}  {}", i, rule); }
+                Err(e) => { serial_println!("Cannot add rule: {:?}", e); }
+            }
+        }
+        _ => { serial_println!("Usage: firewall [allow|deny <proto> <ports> [cidr] | del <n> | clear]"); }
+    }
+}
+
+/// A firewall rule from its shell arguments; the source defaults to anywhere.
+fn parse_rule(action: &str, protocol: &str, ports: &str, source: Option<&str>) -> Option<firewall::Rule> {
+    let action = match action {
+        "allow" => firewall::Action::Allow,
+        "deny" => firewall::Action::Deny,
+        _ => return None,
+    };
+    let protocol = match protocol {
+        "any" => firewall::Protocol::Any,
+        "tcp" => firewall::Protocol::Tcp,
+        "udp" => firewall::Protocol::Udp,
+        "icmp" => firewall::Protocol::Icmp,
+        _ => return None,
+    };
+    let ports = match ports {
+        "*" => None,
+        ports => {
+            let (first, last) = ports.split_once('-').unwrap_or((ports, ports));
+            Some((first.parse().ok()?, last.parse().ok()?))
+        }
+    };
+    let source = source.unwrap_or("0.0.0.0/0").parse().ok()?;
+    Some(firewall::Rule { action, protocol, ports, source })
+}
+
+fn cmd_pcap(args: &[&str]) {
+    match args {
+        [] => {
+            let stats = pcap::stats();
+            serial_println!(
+                "capture {}: {} frames, {} dropped, {} bytes buffered",
+                if stats.enabled { "on" } else { "off" }, stats.captured, stats.dropped, stats.buffered
+            );
+        }
+        ["on"] | ["off"] => {
+            config::set("net.pcap", (args[0] == "on") as u64).ok();
+        }
+        ["clear"] => pcap::clear(),
+        ["dump"] => {
+            executor::submit(Task::new(async {
+                let len = pcap::dump_serial().await;
+                serial_println!("pcap: wrote {} bytes to COM2", len);
+            }));
+        }
+        _ => { serial_println!("Usage: pcap [on | off | clear | dump]"); }
+    }
+}
+
+fn cmd_mdns(args: &[&str]) {
+    let show = |peers: Vec<mdns::Peer>| {
+        for peer in peers {
+            serial_println!("{:<16} {:<24} {}", peer.instance, peer.endpoint, peer.peer_id);
+        }
+    };
+    match args {
+        [] => show(mdns::peers()),
+        ["discover"] => {
+            executor::submit(Task::new(async move { show(mdns::discover(MDNS_DISCOVER_MS).await) }));
+        }
+        _ => { serial_println!("Usage: mdns [discover]"); }
+    }
+}
+
+fn cmd_ping(args: &[&str]) {
+    let [host] = args else {
+        serial_println!("Usage: ping <host>");
+        return;
+    };
+    let host = String::from(*host);
+    executor::submit(Task::new(async move {
+        let addr = match dns::resolve(&host).await {
+            Ok(smoltcp::wire::IpAddress::Ipv4(addr)) => addr,
+            Err(e) => {
+                serial_println!("ping: cannot resolve {}: {:?}", host, e);
+                return;
+            }
+        };
+        match icmp::ping(addr, PING_TIMEOUT_MS).await {
+            Ok(rtt) => { serial_println!("ping {} ({}): {} ms", host, addr, rtt.total_millis()); }
+            Err(e) => { serial_println!("ping {} ({}): {:?}", host, addr, e); }
+        }
+    }));
+}
+
+fn cmd_connect(args: &[&str]) {
+    let (host, port) = match args {
+        [host, port] => match port.parse::<u16>() {
+            Ok(port) => (String::from(*host), port),
+            Err(_) => {
+                serial_println!("Usage: connect <host> <port>");
+                return;
+            }
+        },
+        _ => {
+            serial_println!("Usage: connect <host> <port>");
+            return;
+        }
+    };
+    executor::submit(Task::new(async move {
+        let addr = match dns::resolve(&host).await {
+            Ok(addr) => addr,
+            Err(e) => {
+                serial_println!("connect: cannot resolve {}: {:?}", host, e);
+                return;
+            }
+        };
+        let started = crate::interrupts::uptime_ms();
+        match net_stack::tcp_connect(addr, port).await {
+            Ok(handle) => {
+                let elapsed = crate::interrupts::uptime_ms() - started;
+                serial_println!("connect {}:{}: established in {} ms", addr, port, elapsed);
+                net_stack::tcp_close(handle);
+            }
+            Err(e) => { serial_println!("connect {}:{}: {:?}", addr, port, e); }
+        }
+    }));
+}
+
+fn cmd_tls(args: &[&str]) {
+    match args {
+        [] => {
+            for (host, key) in tls::pins() {
+                serial_print!("{:<32} ", host);
+                for byte in key {
+                    serial_print!("{:02x}", byte);
+                }
+                serial_println!();
+            }
+        }
+        ["pin", host, hex] => match parse_key(hex) {
+            Some(key) => tls::pin(host, key),
+            None => { serial_println!("tls: the key must be 64 hex digits (a raw Ed25519 public key)"); }
+        },
+        [host, port, line @ ..] => {
+            let Ok(port) = port.parse::<u16>() else {
+                serial_println!("Usage: tls <host> <port> [line]");
+                return;
+            };
+            let host = String::from(*host);
+            let line = line.join(" ");
+            executor::submit(Task::new(async move {
+                let started = crate::interrupts::uptime_ms();
+                let mut stream = match tls::tls_connect(&host, port).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        serial_println!("tls {}:{}: {:?}", host, port, e);
+                        return;
+                    }
+                };
+                let elapsed = crate::interrupts::uptime_ms() - started;
+                serial_println!("tls {}:{}: handshake done in {} ms", host, port, elapsed);
+                if !line.is_empty() {
+                    // Send the line and show the start of the reply.
+                    let mut reply = [0u8; 512];
+                    match stream.write_all(alloc::format!("{}\r\n", line).as_bytes()).await {
+                        Ok(()) => match stream.read(&mut reply).await {
+                            Ok(n) => { serial_println!("{}", String::from_utf8_lossy(&reply[..n])); }
+                            Err(e) => { serial_println!("tls {}:{}: {:?}", host, port, e); }
+                        },
+                        Err(e) => { serial_println!("tls {}:{}: {:?}", host, port, e); }
+                    }
+                }
+                stream.close();
+            }));
+        }
+        _ => { serial_println!("Usage: tls [pin <host> <key-hex> | <host> <port> [line]]"); }
+    }
+}
+
+/// A 32-byte key written as 64 hex digits.
+fn parse_key(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut key = [0; 32];
+    for (byte, pair) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(core::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(key)
+}
+
+fn cmd_dial(args: &[&str]) {
+    let [peer] = args else {
+        serial_println!("Usage: dial <host>:<port>");
+        return;
+    };
+    let peer = String::from(*peer);
+    executor::submit(Task::new(async move {
+        if p2p::bootstrap_peer(&peer).await {
+            serial_println!("dial: {} added", peer);
+        }
+    }));
+}
+
+fn cmd_conns(_args: &[&str]) {
+    let connections = p2p_conn::connections();
+    for conn in &connections {
+        serial_println!(
+            "{:?} {:?}: {} queued, {} in flight, idle {} ms",
+            conn.peer, conn.direction, conn.queued, conn.in_flight, conn.idle_ms
+        );
+        if let Some(relay) = conn.relay {
+            serial_println!("  through relay {:?}", relay);
+        }
+        let protocols: Vec<&str> = conn.protocols.iter().map(|protocol| protocol.name()).collect();
+        serial_println!("  {}", protocols.join(" "));
+    }
+    serial_println!("conns: {} open", connections.len());
+}
+
+fn cmd_peers(_args: &[&str]) {
+    let now = crate::interrupts::uptime_ms();
+    let buckets: Vec<_> = {
+        let state = p2p::P2P_STATE.lock();
+        let Some(state) = state.as_ref() else {
+            serial_println!("peers: P2P isn't running");
+            return;
+        };
+        serial_println!("local {} {:?}", state.peer_id, state.node_id);
+        let book = ADDRESS_BOOK.lock();
+        state
+            .routing_table
+            .buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, bucket)| !bucket.peers.is_empty() || bucket.candidate.is_some())
+            .map(|(idx, bucket)| {
+                let peers: Vec<_> = bucket
+                    .peers
+                    .iter()
+                    .map(|peer| {
+                        let endpoint = book.peer_endpoint(&peer.node_id, now).or(peer.endpoint);
+                        (peer.node_id, endpoint, peer.last_seen)
+                    })
+                    .collect();
+                (idx, peers, bucket.candidate.is_some(), bucket.last_refreshed)
+            })
+            .collect()
+    };
+    let mut total = 0;
+    for (idx, peers, candidate, last_refreshed) in &buckets {
+        let waiting = if *candidate { ", 1 waiting" } else { "" };
+        serial_println!(
+            "bucket {:>3}: {}/{} peers{}, refreshed {} s ago",
+            idx, peers.len(), K_BUCKET_SIZE, waiting, now.saturating_sub(*last_refreshed) / 1000
+        );
+        for (node_id, endpoint, last_seen) in peers {
+            let connected = if p2p_conn::is_connected(node_id) { ", connected" } else { "" };
+            match endpoint {
+                Some(endpoint) => {
+                    serial_println!(
+                        "  {:?} at {}, seen {} s ago{}",
+                        node_id, endpoint, now.saturating_sub(*last_seen) / 1000, connected
+                    );
+                }
+                None => {
+                    serial_println!(
+                        "  {:?} at no known address, seen {} s ago{}",
+                        node_id, now.saturating_sub(*last_seen) / 1000, connected
+                    );
+                }
+            }
+        }
+        total += peers.len();
+    }
+    serial_println!("peers: {} in {} buckets", total, buckets.len());
+}
+
+fn cmd_p2pstat(_args: &[&str]) {
+    let metrics = p2p_metrics::snapshot();
+    serial_println!("dials: {} attempted, {} succeeded", metrics.dials, metrics.dials_succeeded);
+    serial_println!("handshakes: {}, {} failed", metrics.handshakes, metrics.handshakes_failed);
+    serial_println!("{:<14} {:>10} {:>10}", "RPC", "SENT", "RECEIVED");
+    for rpc in &metrics.rpcs {
+        serial_println!("{:<14} {:>10} {:>10}", rpc.name, rpc.sent, rpc.received);
+    }
+    serial_println!("{:<20} {:>12} {:>12}", "PEER", "BYTES IN", "BYTES OUT");
+    for peer in &metrics.peers {
+        serial_println!("{:<20} {:>12} {:>12}", alloc::format!("{:?}", peer.peer), peer.bytes_in, peer.bytes_out);
+    }
+}
+
+fn cmd_identify(args: &[&str]) {
+    let Some(peer) = args.first().and_then(|hex| parse_key(hex)).map(NodeId::new) else {
+        serial_println!("Usage: identify <node-id-hex> (64 hex digits)");
+        return;
+    };
+    executor::submit(Task::new(async move {
+        match p2p_rpc::identify(peer).await {
+            Ok(identity) => {
+                serial_println!("{:?} runs {}", peer, identity.agent);
+                match identity.observed {
+                    Some(endpoint) => { serial_println!("  sees this node at {}", endpoint); }
+                    None => { serial_println!("  has no address for this node"); }
+                }
+            }
+            Err(e) => { serial_println!("identify: {:?} didn't answer: {:?}", peer, e); }
+        }
+    }));
+}
+
+fn cmd_nat(args: &[&str]) {
+    match args {
+        [] => {
+            let now = crate::interrupts::uptime_ms();
+            for observation in p2p_nat::observations() {
+                serial_println!(
+                    "{:?} sees this node at {} ({} s ago)",
+                    observation.reporter, observation.endpoint, now.saturating_sub(observation.at) / 1000
+                );
+            }
+            let reachability = p2p_nat::reachability();
+            match p2p_nat::external_address() {
+                Some(external) => { serial_println!("nat: {:?}, external address {}", reachability, external); }
+                None => { serial_println!("nat: {:?}", reachability); }
+            }
+        }
+        ["punch", target, relay] => {
+            let (Some(target), Some(relay)) = (parse_key(target).map(NodeId::new), parse_key(relay).map(NodeId::new))
+            else {
+                serial_println!("Usage: nat punch <node-id-hex> <relay-node-id-hex>");
+                return;
+            };
+            executor::submit(Task::new(async move {
+                match p2p_nat::connect_via(target, relay).await {
+                    Ok(()) => { serial_println!("nat: connected to {:?}", target); }
+                    Err(e) => { serial_println!("nat: no connection to {:?}: {:?}", target, e); }
+                }
+            }));
+        }
+        _ => { serial_println!("Usage: nat [punch <node-id-hex> <relay-node-id-hex>]"); }
+    }
+}
+
+fn cmd_relay(args: &[&str]) {
+    match args {
+        [] => {
+            let circuits = p2p_relay::circuits();
+            for c in &circuits {
+                let opening = if c.open { "" } else { " (opening)" };
+                serial_println!(
+                    "circuit {} {:?} -> {:?}{}: {} bytes forwarded, {} frames dropped, {} s old",
+                    c.id, c.origin, c.target, opening, c.forwarded, c.dropped, c.age_ms / 1000
+                );
+            }
+            let state = if p2p_relay::enabled() { "on" } else { "off" };
+            let reachability = p2p_nat::reachability();
+            serial_println!("relay: {}, {} circuits, this node is {:?}", state, circuits.len(), reachability);
+        }
+        ["on"] => p2p_relay::set_enabled(true),
+        ["off"] => p2p_relay::set_enabled(false),
+        _ => { serial_println!("Usage: relay [on|off]"); }
+    }
+}
+
+fn cmd_lookup(args: &[&str]) {
+    let target = match args {
+        [] => p2p::P2P_STATE.lock().as_ref().map(|state| state.node_id),
+        [hex] => parse_key(hex).map(NodeId::new),
+        _ => None,
+    };
+    let Some(target) = target else {
+        serial_println!("Usage: lookup [<node-id-hex>] (64 hex digits; needs P2P up)");
+        return;
+    };
+    executor::submit(Task::new(async move {
+        let found = p2p_rpc::lookup(target).await;
+        for contact in &found {
+            serial_println!("{:?} {}", contact.node_id, contact.endpoint);
+        }
+        serial_println!("lookup: {} peers", found.len());
+    }));
+}
+
+/// TTL of records and provider entries published from the shell.
+const DHT_SHELL_TTL_MS: u64 = 60 * 60 * 1000;
+
+fn cmd_dht(args: &[&str]) {
+    match args {
+        [] => {
+            let records = p2p_store::records(crate::interrupts::uptime_ms());
+            for record in &records {
+                serial_println!(
+                    "{:?} {} bytes from {:?}, {}s left",
+                    record.key, record.len, record.publisher, record.ttl_ms / 1000
+                );
+            }
+            serial_println!("dht: {} records", records.len());
+        }
+        ["put", name, value] => {
+            let (key, value) = (NodeId::from_data(name.as_bytes()), String::from(*value));
+            executor::submit(Task::new(async move {
+                match p2p_rpc::publish(key, value.as_bytes(), DHT_SHELL_TTL_MS).await {
+                    Ok(peers) => { serial_println!("dht: stored {:?} on {} peers", key, peers); }
+                    Err(e) => { serial_println!("dht: not stored: {:?}", e); }
+                }
+            }));
+        }
+        ["get", name] => {
+            let key = NodeId::from_data(name.as_bytes());
+            executor::submit(Task::new(async move {
+                match p2p_rpc::get(key).await {
+                    Some(value) => { serial_println!("{}", String::from_utf8_lossy(&value)); }
+                    None => { serial_println!("dht: {:?} not found", key); }
+                }
+            }));
+        }
+        _ => { serial_println!("Usage: dht [put <name> <value> | get <name>]"); }
+    }
+}
+
+fn cmd_blocks(args: &[&str]) {
+    match args {
+        [] => {
+            let blocks = p2p_blocks::blocks();
+            for block in &blocks {
+                serial_println!("{:?} {} bytes{}", block.hash, block.len, if block.pinned { ", pinned" } else { "" });
+            }
+            serial_println!("blocks: {} held", blocks.len());
+        }
+        ["put", text @ ..] if !text.is_empty() => match p2p_blocks::put_object(text.join(" ").as_bytes()) {
+            Ok(root) => {
+                serial_println!("blocks: stored as {:?}", root);
+                executor::submit(Task::new(async move {
+                    let peers = p2p_rpc::provide(root, DHT_SHELL_TTL_MS).await;
+                    serial_println!("blocks: {:?} provided to {} peers", root, peers);
+                }));
+            }
+            Err(e) => { serial_println!("blocks: not stored: {:?}", e); }
+        },
+        ["providers", hex] => {
+            let Some(hash) = parse_key(hex).map(NodeId::new) else {
+                serial_println!("Usage: blocks providers <hash> (64 hex digits)");
+                return;
+            };
+            executor::submit(Task::new(async move {
+                let providers = p2p_rpc::find_providers(hash).await;
+                for contact in &providers {
+                    serial_println!("{:?} {}", contact.node_id, contact.endpoint);
+                }
+                serial_println!("blocks: {} providers of {:?}", providers.len(), hash);
+            }));
+        }
+        ["get", hex] => {
+            let Some(root) = parse_key(hex).map(NodeId::new) else {
+                serial_println!("Usage: blocks get <hash> (64 hex digits)");
+                return;
+            };
+            executor::submit(Task::new(async move {
+                match p2p_rpc::fetch_object(root).await {
+                    Ok(object) => { serial_println!("{}", String::from_utf8_lossy(&object)); }
+                    Err(e) => { serial_println!("blocks: {:?} not fetched: {:?}", root, e); }
+                }
+            }));
+        }
+        ["rm", hex] => match parse_key(hex).map(NodeId::new) {
+            Some(hash) if p2p_blocks::remove(&hash) => { serial_println!("blocks: removed {:?}", hash); }
+            Some(hash) => { serial_println!("blocks: {:?} not held", hash); }
+            None => { serial_println!("Usage: blocks rm <hash> (64 hex digits)"); }
+        },
+        _ => { serial_println!("Usage: blocks [put <text> | get <hash> | providers <hash> | rm <hash>]"); }
+    }
+}
+
+/// How long `pubsub sub` prints a topic's messages.
+const PUBSUB_SHELL_LISTEN_MS: u64 = 60 * 1000;
+
+fn cmd_pubsub(args: &[&str]) {
+    match args {
+        [] => {
+            let topics = p2p_pubsub::topics();
+            for topic in &topics {
+                serial_println!(
+                    "{}: {} subscriptions, {} mesh peers, {} peers subscribed",
+                    topic.topic, topic.subscriptions, topic.mesh, topic.peers
+                );
+            }
+            serial_println!("pubsub: {} topics", topics.len());
+        }
+        ["pub", topic, message @ ..] if !message.is_empty() => {
+            let message = message.join(" ");
+            match p2p_pubsub::publish(topic, message.as_bytes()) {
+                Ok(peers) => { serial_println!("pubsub: sent to {} peers", peers); }
+                Err(e) => { serial_println!("pubsub: not published: {:?}", e); }
+            }
+        }
+        ["sub", topic] => {
+            let subscription = match p2p_pubsub::subscribe(topic) {
+                Ok(subscription) => subscription,
+                Err(e) => {
+                    serial_println!("pubsub: not subscribed: {:?}", e);
+                    return;
+                }
+            };
+            let deadline = Some(crate::interrupts::uptime_ms() + PUBSUB_SHELL_LISTEN_MS);
+            executor::submit(Task::new(async move {
+                while let Some(message) = (Deadline { inner: pin!(subscription.recv()), deadline }).await {
+                    serial_println!(
+                        "{} from {:?}: {}",
+                        message.topic, message.from, String::from_utf8_lossy(&message.data)
+                    );
+                }
+            }));
+        }
+        _ => { serial_println!("Usage: pubsub [pub <topic> <message> | sub <topic>]"); }
+    }
+}
+
+fn cmd_reputation(args: &[&str]) {
+    match args {
+        [] => {
+            let standings = p2p_reputation::standings();
+            for standing in &standings {
+                let ban = match standing.banned_ms {
+                    Some(ms) => alloc::format!(", banned for {} s", ms / 1000),
+                    None => String::new(),
+                };
+                serial_println!("{}: {} points{}", standing.subject, standing.points, ban);
+                for (offense, count) in standing.offenses.iter().filter(|(_, count)| *count > 0) {
+                    serial_println!("  {:?}: {}", offense, count);
+                }
+            }
+            let banned = standings.iter().filter(|s| s.banned_ms.is_some()).count();
+            serial_println!("reputation: {} tracked, {} banned", standings.len(), banned);
+        }
+        ["unban", who] => {
+            let subject = match (parse_key(who), dns::parse_ipv4(who)) {
+                (Some(id), _) => Subject::Peer(NodeId::new(id)),
+                (None, Some(addr)) => Subject::Address(addr.into()),
+                (None, None) => {
+                    serial_println!("Usage: reputation unban <node id (64 hex digits) | IPv4 address>");
+                    return;
+                }
+            };
+            if p2p_reputation::unban(subject) {
+                serial_println!("reputation: {} forgiven", subject);
+            } else {
+                serial_println!("reputation: {} isn't tracked", subject);
+            }
+        }
+        _ => { serial_println!("Usage: reputation [unban <node id | address>]"); }
+    }
+}
+
+fn cmd_shutdown(_args: &[&str]) {
+    serial_println!("Shutting down...");
+    shutdown::request();
+}
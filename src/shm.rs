@@ -0,0 +1,154 @@
+//! # Shared Memory Regions
+//!
+//! Lets cooperating processes exchange bulk data (packets, DHT values)
+//! without squeezing it through 8-word IPC messages.
+//!
+//! A `SharedRegion` is a kernel-owned, zero-filled byte buffer named by a
+//! `SharedMemory` capability (`resource_id` is the region id): READ to read
+//! it, WRITE to write it, GRANT to pass it on. The creator shares it by
+//! sending a derived capability as the `Payload::Shared` of an IPC message
+//! (`IpcManager::send_shared`); the receiver then reads and writes the same
+//! bytes.
+//!
+//! ## Why not map it into linear memory?
+//! A wasmi linear memory is one private, growable buffer per store, so two
+//! processes can't alias a range of it. Guests instead access a region at
+//! an offset through host calls (`env.shm_read` / `env.shm_write`): the
+//! data crosses the sandbox boundary once on each side and never passes
+//! through an IPC queue or an intermediate kernel copy.
+//!
+//! ## Lifetime
+//! A region belongs to the process that created it and is released when
+//! that process exits; capabilities other processes still hold then fail
+//! with `NotFound`. Region memory is admitted against the kernel heap like
+//! a spawn, a single region is at most `MAX_REGION_LEN` bytes, and a
+//! process holds at most `MAX_REGIONS_PER_OWNER` regions at once.
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::admission::{self, ResourceRequest};
+use crate::capability::{Capability, CapabilityType, Permissions};
+use crate::process::Pid;
+
+/// Largest region that may be created.
+pub const MAX_REGION_LEN: usize = 256 * 1024;
+
+/// Regions one process may own at once.
+pub const MAX_REGIONS_PER_OWNER: usize = 16;
+
+/// Errors from region operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShmError {
+    /// The length is zero or over `MAX_REGION_LEN`, or the heap can't
+    /// spare it.
+    TooLarge,
+    /// The capability isn't a SharedMemory capability, or its region has
+    /// been released.
+    NotFound,
+    /// The capability lacks the right the operation needs.
+    PermissionDenied,
+    /// The access extends past the end of the region.
+    OutOfRange,
+    /// The owner holds `MAX_REGIONS_PER_OWNER` regions already.
+    TooMany,
+}
+
+/// A block of memory several processes can read and write.
+pub struct SharedRegion {
+    id: u64,
+    owner: Pid,
+    data: Mutex<Vec<u8>>,
+}
+
+impl SharedRegion {
+    /// Size in bytes.
+    pub fn len(&self) -> usize {
+        self.data.lock().len()
+    }
+
+    /// Copy `bytes` into the region starting at `offset`.
+    pub fn write(&self, offset: usize, bytes: &[u8]) -> Result<(), ShmError> {
+        let mut data = self.data.lock();
+        let range = range(offset, bytes.len(), data.len())?;
+        data[range].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Run `f` on `len` bytes starting at `offset`, without copying them.
+    pub fn with_range<R>(&self, offset: usize, len: usize, f: impl FnOnce(&[u8]) -> R) -> Result<R, ShmError> {
+        let data = self.data.lock();
+        let range = range(offset, len, data.len())?;
+        Ok(f(&data[range]))
+    }
+}
+
+/// A region, as listed by `list`.
+#[derive(Debug, Clone, Copy)]
+pub struct RegionInfo {
+    pub id: u64,
+    pub owner: Pid,
+    pub len: usize,
+}
+
+static NEXT_REGION_ID: AtomicU64 = AtomicU64::new(1);
+
+lazy_static! {
+    static ref REGIONS: Mutex<Vec<Arc<SharedRegion>>> = Mutex::new(Vec::new());
+}
+
+/// Create a zero-filled region of `len` bytes owned by `owner`. Returns a
+/// capability with READ, WRITE and GRANT.
+pub fn create(len: usize, owner: Pid) -> Result<Capability, ShmError> {
+    if len == 0 || len > MAX_REGION_LEN {
+        return Err(ShmError::TooLarge);
+    }
+    let mut regions = REGIONS.lock();
+    if regions.iter().filter(|r| r.owner == owner).count() >= MAX_REGIONS_PER_OWNER {
+        return Err(ShmError::TooMany);
+    }
+    admission::admit(&ResourceRequest { heap_bytes: len, frames: 0, sockets: 0 }).map_err(|_| ShmError::TooLarge)?;
+    let id = NEXT_REGION_ID.fetch_add(1, Ordering::Relaxed);
+    regions.push(Arc::new(SharedRegion { id, owner, data: Mutex::new(vec![0; len]) }));
+    let rights = Permissions::READ.union(Permissions::WRITE).union(Permissions::GRANT);
+    Ok(Capability::new(CapabilityType::SharedMemory, rights, id))
+}
+
+/// The region `cap` names, if it carries `required`.
+pub fn resolve(cap: &Capability, required: Permissions) -> Result<Arc<SharedRegion>, ShmError> {
+    if cap.cap_type != CapabilityType::SharedMemory {
+        return Err(ShmError::NotFound);
+    }
+    if !cap.permissions.contains(required) {
+        return Err(ShmError::PermissionDenied);
+    }
+    REGIONS.lock().iter().find(|r| r.id == cap.resource_id).cloned().ok_or(ShmError::NotFound)
+}
+
+/// Release the region `cap` names, e.g. when its creator couldn't be given
+/// the capability.
+pub fn release(cap: &Capability) {
+    if cap.cap_type == CapabilityType::SharedMemory {
+        REGIONS.lock().retain(|r| r.id != cap.resource_id);
+    }
+}
+
+/// Release every region `owner` created. Called when a process exits.
+pub fn release_owner(owner: Pid) {
+    REGIONS.lock().retain(|r| r.owner != owner);
+}
+
+/// Every live region.
+pub fn list() -> Vec<RegionInfo> {
+    REGIONS.lock().iter().map(|r| RegionInfo { id: r.id, owner: r.owner, len: r.len() }).collect()
+}
+
+fn range(offset: usize, len: usize, size: usize) -> Result<core::ops::Range<usize>, ShmError> {
+    match offset.checked_add(len) {
+        Some(end) if end <= size => Ok(offset..end),
+        _ => Err(ShmError::OutOfRange),
+    }
+}
@@ -0,0 +1,156 @@
+//! # Shutdown Controller
+//!
+//! One ordered path from "power off requested" to "powered off", so that
+//! persistent state is never left half-written by an abrupt exit.
+//!
+//! ## Sequence (`run`)
+//! 1. **Stop accepting work** — `in_progress()` turns true; new WASM
+//!    processes are refused.
+//! 2. **Notify** — every registered service gets an `EVENT_SHUTDOWN`
+//!    message at `Priority::Urgent`, ahead of its queued requests, and
+//!    processes get `NOTICE_GRACE_MS` to exit on their own.
+//! 3. **Drain** — every remaining process is killed, and the executor keeps
+//!    running until they have all exited or `DRAIN_DEADLINE_MS` passes.
+//! 4. **Cancel tasks** — remaining tasks (listeners, shell, agent) are
+//!    dropped, which runs their drop guards while the network still exists.
+//! 5. **Close connections** — `net_stack::shutdown()` sends FINs to P2P and
+//!    TCP peers and resets the NIC; it runs as a task, and the executor is
+//!    driven until it finishes.
+//! 6. **Flush** — registered flush hooks (persistent stores) run, then every
+//!    block device's write cache is flushed.
+//! 7. **Power off.**
+//!
+//! Code running inside an executor task (the shell, the guest agent) must
+//! not drive the executor itself, so it calls `request`; the idle loop
+//! notices and calls `run`. The panic handler calls `emergency`, which only
+//! flushes disks, and only if it can do so without waiting on a lock.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use crate::executor::Task;
+use crate::ipc::{Message, Priority};
+use crate::process::{self, ProcessStatus};
+use crate::{block, executor, interrupts, net_stack, serial_println, services, EXECUTOR};
+
+/// Label of the notice services get when shutdown starts.
+pub const EVENT_SHUTDOWN: u64 = 0x5344_4E01;
+
+/// How long processes get to exit on their own after the notice.
+const NOTICE_GRACE_MS: u64 = 500;
+
+/// How long running processes get to exit after being killed.
+const DRAIN_DEADLINE_MS: u64 = 2_000;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+static IN_PROGRESS: AtomicBool = AtomicBool::new(false);
+static EMERGENCY: AtomicBool = AtomicBool::new(false);
+
+/// Called during the flush phase, before block devices are flushed.
+pub type FlushHook = fn();
+
+static FLUSH_HOOKS: Mutex<Vec<(&'static str, FlushHook)>> = Mutex::new(Vec::new());
+
+/// Register persistent state to write out during shutdown (e.g. a
+/// filesystem's dirty buffers, the config store).
+pub fn register_flush_hook(name: &'static str, hook: FlushHook) {
+    FLUSH_HOOKS.lock().push((name, hook));
+}
+
+/// True once shutdown has started; subsystems should refuse new work.
+pub fn in_progress() -> bool {
+    IN_PROGRESS.load(Ordering::Relaxed)
+}
+
+/// Ask for an orderly shutdown. Safe from inside tasks; the idle loop
+/// performs it on its next iteration.
+pub fn request() {
+    REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// True if `request` was called and `run` hasn't started yet.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::Relaxed) && !in_progress()
+}
+
+/// Run the full shutdown sequence and power off.
+///
+/// Must be called from the idle loop, not from inside an executor task.
+pub fn run() -> ! {
+    IN_PROGRESS.store(true, Ordering::Relaxed);
+    serial_println!("[SHUTDOWN] Stopping: no new work accepted.");
+
+    let notice = Message::new(EVENT_SHUTDOWN).with_priority(Priority::Urgent);
+    let notified = services::notify_all(&notice);
+    if notified > 0 {
+        serial_println!("[SHUTDOWN] Notified {} service(s).", notified);
+        drain(NOTICE_GRACE_MS);
+    }
+
+    // Drain processes.
+    for info in process::list() {
+        let _ = process::kill(info.pid);
+    }
+    drain(DRAIN_DEADLINE_MS);
+    if live_processes() > 0 {
+        serial_println!("[SHUTDOWN] {} process(es) still running at deadline.", live_processes());
+    }
+
+    let cancelled = EXECUTOR.lock().cancel_all();
+    serial_println!("[SHUTDOWN] Cancelled {} task(s); {} left.", cancelled, executor::task_count());
+
+    let closed = Arc::new(AtomicBool::new(false));
+    let done = closed.clone();
+    executor::submit(Task::new(async move {
+        net_stack::shutdown().await;
+        done.store(true, Ordering::Relaxed);
+    }));
+    while !closed.load(Ordering::Relaxed) {
+        EXECUTOR.lock().poll();
+        x86_64::instructions::hlt();
+    }
+
+    let hooks: Vec<(&'static str, FlushHook)> = FLUSH_HOOKS.lock().clone();
+    for (name, hook) in hooks {
+        serial_println!("[SHUTDOWN] Flushing {}...", name);
+        hook();
+    }
+    if let Err(e) = block::flush_all() {
+        serial_println!("[SHUTDOWN] Disk flush failed: {:?}", e);
+    }
+
+    serial_println!("[SHUTDOWN] Powering off.");
+    crate::exit_qemu(crate::QemuExitCode::Success);
+}
+
+/// Best-effort flush from the panic handler. Skips anything whose lock is
+/// held (the panic may have happened while holding it).
+pub fn emergency() {
+    if EMERGENCY.swap(true, Ordering::Relaxed) {
+        // Panicked during the emergency flush itself; don't try again.
+        return;
+    }
+    IN_PROGRESS.store(true, Ordering::Relaxed);
+    match block::try_flush_all() {
+        Some(Ok(())) => { serial_println!("[SHUTDOWN] Emergency disk flush done."); }
+        Some(Err(e)) => { serial_println!("[SHUTDOWN] Emergency disk flush failed: {:?}", e); }
+        None => { serial_println!("[SHUTDOWN] Block layer busy; skipping emergency flush."); }
+    }
+}
+
+/// Run the executor until every process has exited or `ms` pass.
+fn drain(ms: u64) {
+    let deadline = interrupts::uptime_ms() + ms;
+    while interrupts::uptime_ms() < deadline && live_processes() > 0 {
+        EXECUTOR.lock().poll();
+        x86_64::instructions::hlt();
+    }
+}
+
+fn live_processes() -> usize {
+    process::list()
+        .iter()
+        .filter(|p| !matches!(p.status, ProcessStatus::Exited(_)))
+        .count()
+}
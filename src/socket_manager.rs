@@ -0,0 +1,215 @@
+//! # Socket Manager
+//!
+//! Adds and removes TCP and UDP sockets in the network stack at runtime:
+//! for WASM processes (`NetworkStack::open_socket`, behind a capability)
+//! and for kernel tasks (`NetworkStack::create_socket`, `tcp_connect`),
+//! which hold the plain handle.
+//!
+//! ## Reclaiming buffers
+//! The kernel heap never frees, so dropping a socket would leak its
+//! buffers. A released socket instead stays in the socket set, idle, and
+//! is handed out again by the next `allocate` of the same kind: a UDP
+//! socket at once, a TCP socket once its FIN exchange has finished.
+//!
+//! ## TCP buffer sizes
+//! Each TCP socket gets the buffer size it asks for, per direction, rounded
+//! up to a power of two between `MIN_TCP_BUFFER` and `MAX_TCP_BUFFER`, and
+//! an idle socket is only reused for the same size. The receive buffer is
+//! the most a peer can send without waiting for an ACK, so with any latency
+//! it caps throughput: 4 KiB over 20 ms is 200 KB/s. Buffers past 64 KiB
+//! are announced with window scaling. UDP sockets all have the same size.
+//!
+//! ## Listeners
+//! A listening smoltcp socket serves a single connection. `listen` keeps a
+//! backlog of managed sockets in Listen state on one port instead: each
+//! incoming SYN is taken by one of them, `accept` hands out the ones that
+//! completed their handshake, and `fill_listeners` replaces them on the next
+//! poll. The kernel's HTTP, P2P and packet capture ports are served this way,
+//! each with its own TCP buffer size.
+//!
+//! The stack's other built-in sockets (DHCP, UDP echo, ICMP) are created
+//! once at init and are not managed here.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use smoltcp::iface::{SocketHandle, SocketSet};
+use smoltcp::socket::tcp::{self, Socket as TcpSocket, SocketBuffer as TcpSocketBuffer};
+use smoltcp::socket::udp::{self, PacketMetadata as UdpPacketMetadata, Socket as UdpSocket};
+use crate::net_stack::{configure_tcp, SocketKind};
+
+/// Smallest TCP buffer per direction, and the default of `net.tcp.buffer`.
+pub const MIN_TCP_BUFFER: usize = 4096;
+/// Largest TCP buffer per direction.
+pub const MAX_TCP_BUFFER: usize = 256 * 1024;
+/// Payload buffer size per direction of a managed UDP socket.
+const UDP_BUFFER: usize = 2048;
+/// Datagrams queued per direction of a managed UDP socket.
+const UDP_PACKETS: usize = 4;
+
+/// The TCP buffer size a socket asking for `requested` bytes gets.
+pub fn tcp_buffer_size(requested: usize) -> usize {
+    requested.clamp(MIN_TCP_BUFFER, MAX_TCP_BUFFER).next_power_of_two()
+}
+
+/// Heap one new managed socket costs, for admission control; `buffer` is
+/// the TCP buffer size asked for.
+pub fn socket_heap(kind: SocketKind, buffer: usize) -> usize {
+    match kind {
+        SocketKind::Tcp => 2 * tcp_buffer_size(buffer),
+        SocketKind::Udp => 2 * UDP_BUFFER,
+    }
+}
+
+/// Tracks which managed sockets are idle and which are winding down.
+pub struct SocketManager {
+    idle_tcp: Vec<SocketHandle>,
+    idle_udp: Vec<SocketHandle>,
+    /// Released TCP sockets still finishing their FIN exchange.
+    closing: Vec<SocketHandle>,
+    listeners: Vec<Listener>,
+}
+
+/// Sockets listening on one TCP port.
+struct Listener {
+    port: u16,
+    /// TCP buffer size of the sockets, as given to `allocate`.
+    buffer: usize,
+    /// Sockets in Listen or SynReceived state.
+    pending: Vec<SocketHandle>,
+}
+
+impl SocketManager {
+    pub fn new() -> Self {
+        SocketManager { idle_tcp: Vec::new(), idle_udp: Vec::new(), closing: Vec::new(), listeners: Vec::new() }
+    }
+
+    /// A fresh socket of `kind`: an idle one if there is one, otherwise a
+    /// newly allocated one. TCP sockets get `buffer` bytes per direction
+    /// (see `tcp_buffer_size`) and come configured from `net.tcp.*`.
+    pub fn allocate(&mut self, sockets: &mut SocketSet<'static>, kind: SocketKind, buffer: usize) -> SocketHandle {
+        match kind {
+            SocketKind::Tcp => {
+                let size = tcp_buffer_size(buffer);
+                let idle = self.idle_tcp.iter().position(|&h| sockets.get::<TcpSocket>(h).recv_capacity() == size);
+                let handle = match idle {
+                    Some(idx) => self.idle_tcp.swap_remove(idx),
+                    None => sockets.add(TcpSocket::new(
+                        TcpSocketBuffer::new(vec![0; size]),
+                        TcpSocketBuffer::new(vec![0; size]),
+                    )),
+                };
+                configure_tcp(sockets.get_mut::<TcpSocket>(handle));
+                handle
+            }
+            SocketKind::Udp => self.idle_udp.pop().unwrap_or_else(|| {
+                sockets.add(UdpSocket::new(
+                    udp::PacketBuffer::new(vec![UdpPacketMetadata::EMPTY; UDP_PACKETS], vec![0; UDP_BUFFER]),
+                    udp::PacketBuffer::new(vec![UdpPacketMetadata::EMPTY; UDP_PACKETS], vec![0; UDP_BUFFER]),
+                ))
+            }),
+        }
+    }
+
+    /// Close a managed socket. A TCP connection is closed gracefully (FIN)
+    /// and its socket becomes idle once `reap` sees it wound down.
+    pub fn release(&mut self, sockets: &mut SocketSet<'static>, handle: SocketHandle, kind: SocketKind) {
+        match kind {
+            SocketKind::Tcp => {
+                sockets.get_mut::<TcpSocket>(handle).close();
+                self.closing.push(handle);
+            }
+            SocketKind::Udp => {
+                sockets.get_mut::<UdpSocket>(handle).close();
+                self.idle_udp.push(handle);
+            }
+        }
+    }
+
+    /// Return wound-down TCP sockets to the idle pool. Called on every poll.
+    pub fn reap(&mut self, sockets: &mut SocketSet<'static>) {
+        let idle = &mut self.idle_tcp;
+        self.closing.retain(|&handle| {
+            let socket = sockets.get_mut::<TcpSocket>(handle);
+            match socket.state() {
+                tcp::State::Closed | tcp::State::TimeWait => {
+                    // Skip TIME-WAIT: the next user gets a new local port.
+                    socket.abort();
+                    idle.push(handle);
+                    false
+                }
+                _ => true,
+            }
+        });
+    }
+
+    // ─── Listeners ───────────────────────────────────────────────────
+
+    /// Accept connections on `port` from the next `fill_listeners` on, with
+    /// `buffer` bytes of TCP buffer per direction. Returns false if
+    /// something already listens there.
+    pub fn listen(&mut self, port: u16, buffer: usize) -> bool {
+        if self.listeners.iter().any(|l| l.port == port) {
+            return false;
+        }
+        self.listeners.push(Listener { port, buffer, pending: Vec::new() });
+        true
+    }
+
+    /// Give connections accepted on `port` from now on `buffer` bytes of
+    /// TCP buffer per direction. Sockets already listening keep theirs.
+    pub fn set_listen_buffer(&mut self, port: u16, buffer: usize) {
+        if let Some(listener) = self.listeners.iter_mut().find(|l| l.port == port) {
+            listener.buffer = buffer;
+        }
+    }
+
+    /// Take a connection that finished its handshake on `port`, if any.
+    /// The caller owns the socket from now on and `release`s it when done.
+    pub fn accept(&mut self, sockets: &mut SocketSet<'static>, port: u16) -> Option<SocketHandle> {
+        let listener = self.listeners.iter_mut().find(|l| l.port == port)?;
+        let idx = listener.pending.iter().position(|&handle| {
+            matches!(sockets.get::<TcpSocket>(handle).state(), tcp::State::Established | tcp::State::CloseWait)
+        })?;
+        Some(listener.pending.swap_remove(idx))
+    }
+
+    /// Bring every listener back to `backlog` listening sockets, adding at
+    /// most `room` sockets in total, and none the heap has no room for.
+    /// Called on every poll.
+    pub fn fill_listeners(&mut self, sockets: &mut SocketSet<'static>, backlog: usize, mut room: usize) {
+        for i in 0..self.listeners.len() {
+            let port = self.listeners[i].port;
+            let buffer = self.listeners[i].buffer;
+            // A handshake that was reset can leave a socket closed.
+            for &handle in &self.listeners[i].pending {
+                let socket = sockets.get_mut::<TcpSocket>(handle);
+                if socket.state() == tcp::State::Closed {
+                    socket.listen(port).ok();
+                }
+            }
+            while self.listeners[i].pending.len() < backlog && room > 0 {
+                let (used, total) = crate::heap_usage();
+                if total - used < socket_heap(SocketKind::Tcp, buffer) {
+                    break;
+                }
+                let handle = self.allocate(sockets, SocketKind::Tcp, buffer);
+                if sockets.get_mut::<TcpSocket>(handle).listen(port).is_err() {
+                    self.idle_tcp.push(handle);
+                    break;
+                }
+                self.listeners[i].pending.push(handle);
+                room -= 1;
+            }
+        }
+    }
+
+    /// Whether `handle` is a pooled socket nobody is using.
+    pub fn is_idle(&self, handle: SocketHandle) -> bool {
+        self.idle_tcp.contains(&handle) || self.idle_udp.contains(&handle)
+    }
+
+    /// Pooled sockets nobody is using.
+    pub fn idle_count(&self) -> usize {
+        self.idle_tcp.len() + self.idle_udp.len()
+    }
+}
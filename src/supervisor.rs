@@ -0,0 +1,248 @@
+//! # Supervision
+//!
+//! Keeps WASM services running: a supervisor (the kernel, or a parent
+//! process through `env.supervise`) hands over a `ChildSpec`, and a watcher
+//! task restarts the child whenever it exits, according to its policy.
+//!
+//! ## Policies
+//! - **Always**: restart after any exit, clean or not.
+//! - **OnFailure**: restart only after a non-zero exit code (a trap, a
+//!   kill, a quota stop or `env.exit(n != 0)`).
+//!
+//! Either way a child is restarted at most `max_restarts` times per
+//! `RESTART_WINDOW_MS`; one more exit inside the window and the watcher
+//! gives up. A restart that fails to start (admission may refuse it while
+//! the node is loaded) is retried after a backoff, from `RESTART_BACKOFF_MS`
+//! doubling up to `MAX_RESTART_BACKOFF_MS`; each attempt counts against the
+//! same budget. Supervision also ends when the supervising process exits.
+//!
+//! ## Notifications
+//! Every exit of a supervised child is reported to the supervisor's
+//! notification endpoint as a `process::CHILD_EXITED` message, with a
+//! third data word: the PID of the replacement, or 0 if the child was not
+//! restarted. Supervisors that ignore the third word see ordinary child
+//! exits.
+//!
+//! ## Upgrades
+//! `hand_over` tells a watcher that its child has been replaced by a new
+//! version (see `upgrade`): the old instance's exit is reported with the
+//! new PID as its replacement, without counting as a restart, and later
+//! restarts use the new module.
+//!
+//! Each instance starts with a fresh copy of the spec's CSpace, so
+//! capabilities a crashed instance acquired (sockets, service lookups) are
+//! not inherited by its replacement.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::capability::{CSpace, Capability};
+use crate::executor::{self, Task};
+use crate::ipc::{Message, IPC_MANAGER};
+use crate::process::{self, Pid, ProcessError, ProcessStatus, CHILD_EXITED};
+use crate::serial_println;
+use crate::wasm_runtime::ProcessOptions;
+
+/// Window over which `ChildSpec::max_restarts` is counted.
+pub const RESTART_WINDOW_MS: u64 = 60_000;
+
+/// Wait before retrying a restart that failed to start.
+const RESTART_BACKOFF_MS: u64 = 250;
+/// Longest wait between failed restart attempts.
+const MAX_RESTART_BACKOFF_MS: u64 = 8_000;
+
+/// When a supervised child is restarted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    /// After every exit.
+    Always,
+    /// After exits with a non-zero code.
+    OnFailure,
+}
+
+/// Everything needed to (re)start a supervised child.
+pub struct ChildSpec {
+    pub name: String,
+    pub module: &'static [u8],
+    pub entry: String,
+    /// Template for each instance's CSpace; see `CSpace::replicate`.
+    pub cspace: CSpace,
+    /// Memory limit, arguments and environment of every instance.
+    pub options: ProcessOptions,
+    pub policy: RestartPolicy,
+    /// Restarts allowed per `RESTART_WINDOW_MS` before giving up.
+    pub max_restarts: u32,
+}
+
+/// A supervised child, as listed by `list`.
+#[derive(Debug, Clone)]
+pub struct SupervisedInfo {
+    pub name: String,
+    /// The supervising process (0 for the kernel).
+    pub supervisor: Pid,
+    /// PID of the current instance.
+    pub pid: Pid,
+    pub policy: RestartPolicy,
+    /// Restarts since supervision began.
+    pub restarts: u64,
+}
+
+lazy_static! {
+    static ref SUPERVISED: Mutex<Vec<SupervisedInfo>> = Mutex::new(Vec::new());
+    /// Pending upgrades: `(old PID, new PID, new module)`.
+    static ref HANDOVERS: Mutex<Vec<(Pid, Pid, &'static [u8])>> = Mutex::new(Vec::new());
+}
+
+/// Start `spec` and keep it running on behalf of `supervisor`. Exits are
+/// reported through `notify` (an Endpoint capability with WRITE), if given.
+///
+/// Returns the PID of the first instance; a child that can't be started at
+/// all is not supervised.
+pub fn supervise(supervisor: Pid, spec: ChildSpec, notify: Option<Capability>) -> Result<Pid, ProcessError> {
+    let pid = start(supervisor, &spec)?;
+    SUPERVISED.lock().push(SupervisedInfo {
+        name: spec.name.clone(),
+        supervisor,
+        pid,
+        policy: spec.policy,
+        restarts: 0,
+    });
+    executor::submit(Task::new(watch(supervisor, spec, notify, pid)));
+    Ok(pid)
+}
+
+/// Every child currently under supervision.
+pub fn list() -> Vec<SupervisedInfo> {
+    SUPERVISED.lock().clone()
+}
+
+/// Record that the supervised child `old` is being replaced by `new`,
+/// running `module`. Returns false if `old` isn't supervised.
+pub fn hand_over(old: Pid, new: Pid, module: &'static [u8]) -> bool {
+    let mut supervised = SUPERVISED.lock();
+    let entry = match supervised.iter_mut().find(|e| e.pid == old) {
+        Some(entry) => entry,
+        None => return false,
+    };
+    entry.pid = new;
+    HANDOVERS.lock().push((old, new, module));
+    true
+}
+
+fn start(supervisor: Pid, spec: &ChildSpec) -> Result<Pid, ProcessError> {
+    process::spawn_child(
+        supervisor,
+        &spec.name,
+        spec.module,
+        &spec.entry,
+        spec.cspace.replicate(),
+        spec.options.clone(),
+        None,
+    )
+}
+
+/// Wait for each instance to exit and decide whether to replace it.
+async fn watch(supervisor: Pid, mut spec: ChildSpec, notify: Option<Capability>, mut pid: Pid) {
+    // Uptime of each restart inside the current window.
+    let mut recent: Vec<u64> = Vec::new();
+    loop {
+        let code = match process::wait(pid).await {
+            Ok(code) => code,
+            // Someone else reaped it; we can't tell how it ended.
+            Err(_) => break,
+        };
+        let wanted = match spec.policy {
+            RestartPolicy::Always => true,
+            RestartPolicy::OnFailure => code != 0,
+        };
+        let upgrade = take_handover(pid);
+        let replacement = if let Some((new_pid, module)) = upgrade {
+            spec.module = module;
+            Some(new_pid)
+        } else if !wanted || !supervisor_alive(supervisor) {
+            None
+        } else {
+            let new_pid = restart(supervisor, &spec, &mut recent).await;
+            if let Some(new_pid) = new_pid {
+                serial_println!(
+                    "[SUPERVISOR] '{}' (PID {}) exited with {}; restarted as PID {}.",
+                    spec.name, pid, code, new_pid
+                );
+            }
+            new_pid
+        };
+
+        if let Some(cap) = &notify {
+            let mut msg = Message::with_data2(CHILD_EXITED, pid, code as i64 as u64);
+            msg.data[2] = replacement.unwrap_or(0);
+            msg.length = 3;
+            if let Err(e) = IPC_MANAGER.lock().send_with_cap(cap, msg) {
+                serial_println!("[SUPERVISOR] Could not notify supervisor of PID {}: {:?}", pid, e);
+            }
+        }
+
+        let old_pid = pid;
+        match replacement {
+            Some(new_pid) => pid = new_pid,
+            None => break,
+        }
+        if upgrade.is_some() {
+            continue;
+        }
+        if let Some(entry) = SUPERVISED.lock().iter_mut().find(|e| e.pid == old_pid) {
+            entry.pid = pid;
+            entry.restarts += 1;
+        }
+    }
+    SUPERVISED.lock().retain(|e| e.pid != pid);
+}
+
+/// Start a new instance of `spec`, retrying failed starts with backoff.
+/// `recent` holds the uptime of each attempt in the current window; gives
+/// up once it reaches `max_restarts`, the supervisor exits or the node
+/// shuts down.
+async fn restart(supervisor: Pid, spec: &ChildSpec, recent: &mut Vec<u64>) -> Option<Pid> {
+    let mut backoff = RESTART_BACKOFF_MS;
+    loop {
+        let now = crate::interrupts::uptime_ms();
+        recent.retain(|&at| now.saturating_sub(at) < RESTART_WINDOW_MS);
+        if recent.len() >= spec.max_restarts as usize {
+            serial_println!(
+                "[SUPERVISOR] '{}' used {} restarts within {} ms; giving up.",
+                spec.name, recent.len(), RESTART_WINDOW_MS
+            );
+            return None;
+        }
+        recent.push(now);
+        match start(supervisor, spec) {
+            Ok(pid) => return Some(pid),
+            Err(e) => {
+                serial_println!(
+                    "[SUPERVISOR] '{}' could not be restarted: {:?}; retrying in {} ms.",
+                    spec.name, e, backoff
+                );
+            }
+        }
+        executor::sleep_ms(backoff).await;
+        backoff = (backoff * 2).min(MAX_RESTART_BACKOFF_MS);
+        if !supervisor_alive(supervisor) || crate::shutdown::in_progress() {
+            return None;
+        }
+    }
+}
+
+fn take_handover(old: Pid) -> Option<(Pid, &'static [u8])> {
+    let mut handovers = HANDOVERS.lock();
+    let idx = handovers.iter().position(|h| h.0 == old)?;
+    let (_, new, module) = handovers.swap_remove(idx);
+    Some((new, module))
+}
+
+fn supervisor_alive(supervisor: Pid) -> bool {
+    match process::get(supervisor) {
+        _ if supervisor == 0 => true,
+        Some(info) => !matches!(info.status, ProcessStatus::Exited(_)),
+        None => false,
+    }
+}
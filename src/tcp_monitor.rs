@@ -0,0 +1,108 @@
+//! # TCP Segment Monitor
+//!
+//! smoltcp keeps its retransmission timer and the windows it has seen to
+//! itself, so the driver shows every TCP segment it sends to this module
+//! and it keeps per-connection counters from what goes out:
+//!
+//! - **Retransmits**: segments carrying data whose sequence range was all
+//!   sent before. Loss, or a peer too slow to ACK within the RTO.
+//! - **Zero windows**: segments advertising a zero receive window, sent
+//!   when the local reader falls behind and the receive buffer fills.
+//!
+//! Connections are keyed by local port and remote endpoint; a SYN or
+//! SYN-ACK from the local side starts the counters of its key over. The
+//! table holds `MAX_FLOWS` connections and, when full, replaces them in
+//! turn. `netstat` shows the counters per socket, and the totals.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use smoltcp::wire::{EthernetFrame, EthernetProtocol, IpEndpoint, IpProtocol, Ipv4Packet, TcpPacket};
+use spin::Mutex;
+
+/// Connections tracked at a time.
+const MAX_FLOWS: usize = 64;
+
+/// One connection's counters.
+struct Flow {
+    local_port: u16,
+    remote: IpEndpoint,
+    /// Sequence number after the last byte sent so far.
+    next_seq: i32,
+    retransmits: u64,
+    zero_windows: u64,
+}
+
+lazy_static! {
+    static ref FLOWS: Mutex<Vec<Flow>> = Mutex::new(Vec::with_capacity(MAX_FLOWS));
+}
+
+/// Slot of the next flow to replace once the table is full.
+static NEXT_EVICT: AtomicU64 = AtomicU64::new(0);
+static RETRANSMITS: AtomicU64 = AtomicU64::new(0);
+static ZERO_WINDOWS: AtomicU64 = AtomicU64::new(0);
+
+/// Count an outgoing Ethernet frame if it is a TCP segment.
+pub fn observe(frame: &[u8]) {
+    let Ok(frame) = EthernetFrame::new_checked(frame) else { return };
+    if frame.ethertype() != EthernetProtocol::Ipv4 {
+        return;
+    }
+    let Ok(ip) = Ipv4Packet::new_checked(frame.payload()) else { return };
+    if ip.next_header() != IpProtocol::Tcp || ip.frag_offset() != 0 {
+        return;
+    }
+    let Ok(tcp) = TcpPacket::new_checked(ip.payload()) else { return };
+    let remote = IpEndpoint::new(ip.dst_addr().into(), tcp.dst_port());
+    let seq = tcp.seq_number().0;
+    let end = seq.wrapping_add(tcp.segment_len() as i32);
+
+    let mut flows = FLOWS.lock();
+    let idx = match flows.iter().position(|f| f.local_port == tcp.src_port() && f.remote == remote) {
+        Some(idx) => idx,
+        None => {
+            let flow = Flow { local_port: tcp.src_port(), remote, next_seq: seq, retransmits: 0, zero_windows: 0 };
+            if flows.len() < MAX_FLOWS {
+                flows.push(flow);
+                flows.len() - 1
+            } else {
+                let idx = (NEXT_EVICT.fetch_add(1, Ordering::Relaxed) % MAX_FLOWS as u64) as usize;
+                flows[idx] = flow;
+                idx
+            }
+        }
+    };
+    let flow = &mut flows[idx];
+    if tcp.syn() {
+        // A new connection, maybe reusing the key.
+        *flow = Flow { next_seq: end, retransmits: 0, zero_windows: 0, ..*flow };
+        return;
+    }
+    if !tcp.payload().is_empty() {
+        if end.wrapping_sub(flow.next_seq) <= 0 {
+            flow.retransmits += 1;
+            RETRANSMITS.fetch_add(1, Ordering::Relaxed);
+        } else {
+            flow.next_seq = end;
+        }
+    }
+    if tcp.window_len() == 0 && !tcp.rst() {
+        flow.zero_windows += 1;
+        ZERO_WINDOWS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counters of the connection from `local_port` to `remote`, if tracked:
+/// (retransmits, zero windows).
+pub fn flow(local_port: u16, remote: IpEndpoint) -> Option<(u64, u64)> {
+    FLOWS
+        .lock()
+        .iter()
+        .find(|f| f.local_port == local_port && f.remote == remote)
+        .map(|f| (f.retransmits, f.zero_windows))
+}
+
+/// Segments sent since boot: (retransmits, zero windows).
+pub fn totals() -> (u64, u64) {
+    (RETRANSMITS.load(Ordering::Relaxed), ZERO_WINDOWS.load(Ordering::Relaxed))
+}
@@ -0,0 +1,998 @@
+//! # TLS
+//!
+//! A TLS 1.3 client over the stack's TCP sockets, so module downloads and
+//! P2P bootstrap can run over authenticated, encrypted connections.
+//!
+//! ## Scope
+//! Exactly one configuration is offered: X25519 key exchange with
+//! TLS_CHACHA20_POLY1305_SHA256, no session resumption, no 0-RTT and no
+//! client certificates. A server that wants anything else (including a
+//! HelloRetryRequest) fails the handshake with `Unsupported`.
+//!
+//! ## Trust
+//! There is no X.509 path validation. A server is trusted if its leaf
+//! certificate carries an Ed25519 key pinned for the host name (`pin`) and
+//! its CertificateVerify signature checks out with that key. With
+//! `net.tls.allow_unpinned` set, hosts without a pin are still connected
+//! to, encrypted but unauthenticated, whatever their key type.
+//!
+//! ## Layers
+//! `TlsClient` is the protocol alone: it takes received bytes, hands out
+//! bytes to send and never touches a socket. `tls_connect` and `TlsStream`
+//! drive one over a kernel TCP connection; `start_session` and
+//! `with_process_session` drive one over a WASM process's socket, for the
+//! `env.tls_*` host functions.
+//!
+//! ## Buffers
+//! A session needs room for a full-size record in each direction. The
+//! kernel heap never frees, so a dropped client's buffers go to a spare
+//! list and the next client reuses them.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, Tag};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+use smoltcp::iface::SocketHandle;
+use smoltcp::socket::tcp::Socket as TcpSocket;
+use spin::Mutex;
+use x25519_dalek::{PublicKey, StaticSecret};
+use crate::admission::{self, ResourceRequest};
+use crate::capability::Capability;
+use crate::config;
+use crate::dns::{self, DnsError};
+use crate::interrupts::uptime_ms;
+use crate::net_stack::{self, ConnectError, SocketError, NETWORK_STACK};
+use crate::p2p::yield_now;
+
+const CONTENT_CHANGE_CIPHER_SPEC: u8 = 20;
+const CONTENT_ALERT: u8 = 21;
+const CONTENT_HANDSHAKE: u8 = 22;
+const CONTENT_APPLICATION_DATA: u8 = 23;
+
+const HS_CLIENT_HELLO: u8 = 1;
+const HS_SERVER_HELLO: u8 = 2;
+const HS_NEW_SESSION_TICKET: u8 = 4;
+const HS_ENCRYPTED_EXTENSIONS: u8 = 8;
+const HS_CERTIFICATE: u8 = 11;
+const HS_CERTIFICATE_REQUEST: u8 = 13;
+const HS_CERTIFICATE_VERIFY: u8 = 15;
+const HS_FINISHED: u8 = 20;
+const HS_KEY_UPDATE: u8 = 24;
+
+const EXT_SERVER_NAME: u16 = 0x0000;
+const EXT_SUPPORTED_GROUPS: u16 = 0x000a;
+const EXT_SIGNATURE_ALGORITHMS: u16 = 0x000d;
+const EXT_SUPPORTED_VERSIONS: u16 = 0x002b;
+const EXT_KEY_SHARE: u16 = 0x0033;
+
+const TLS13: u16 = 0x0304;
+const TLS_CHACHA20_POLY1305_SHA256: u16 = 0x1303;
+const GROUP_X25519: u16 = 0x001d;
+const SIG_ED25519: u16 = 0x0807;
+/// Offered so unpinned servers with common certificates can answer; only
+/// Ed25519 signatures are ever checked.
+const SIG_ECDSA_P256_SHA256: u16 = 0x0403;
+const SIG_RSA_PSS_SHA256: u16 = 0x0804;
+
+const ALERT_CLOSE_NOTIFY: u8 = 0;
+
+/// ServerHello.random of a HelloRetryRequest (RFC 8446, 4.1.3).
+const HRR_RANDOM: [u8; 32] = [
+    0xcf, 0x21, 0xad, 0x74, 0xe5, 0x9a, 0x61, 0x11, 0xbe, 0x1d, 0x8c, 0x02, 0x1e, 0x65, 0xb8, 0x91,
+    0xc2, 0xa2, 0x11, 0x16, 0x7a, 0xbb, 0x8c, 0x5e, 0x07, 0x9e, 0x09, 0xe2, 0xc8, 0xa8, 0x33, 0x9c,
+];
+/// DER prefix of an Ed25519 SubjectPublicKeyInfo, followed by the key.
+const ED25519_SPKI_PREFIX: [u8; 12] = [0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00];
+
+const RECORD_HEADER: usize = 5;
+const TAG_LEN: usize = 16;
+/// Largest record plaintext (2^14).
+const MAX_PLAINTEXT: usize = 16384;
+/// Largest record body accepted, ciphertext expansion included.
+const MAX_CIPHERTEXT: usize = MAX_PLAINTEXT + 256;
+/// Largest handshake message accepted (the server's certificate chain).
+const MAX_HANDSHAKE: usize = 16384;
+/// Plaintext bytes put in one outgoing record.
+const MAX_WRITE: usize = 4096;
+/// Outgoing ciphertext queued before `write` stops accepting data.
+const OUTGOING_CAPACITY: usize = 2 * (RECORD_HEADER + MAX_WRITE + 1 + TAG_LEN);
+/// Heap one new set of session buffers costs, for admission control.
+const SESSION_HEAP: usize = RECORD_HEADER + MAX_CIPHERTEXT + MAX_HANDSHAKE + MAX_PLAINTEXT + OUTGOING_CAPACITY;
+
+/// Errors from TLS connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsError {
+    /// The host name didn't resolve.
+    Dns(DnsError),
+    /// The TCP connection couldn't be opened.
+    Connect(ConnectError),
+    /// The process socket under the session failed.
+    Socket(SocketError),
+    /// The network stack is down.
+    NoNetwork,
+    /// Admission control refused the session's buffers.
+    NoResources,
+    /// The handshake took longer than `net.tls.handshake_timeout_ms`.
+    TimedOut,
+    /// The server wants a version, cipher suite or feature we don't offer.
+    Unsupported,
+    /// The host has no pinned key (and unpinned hosts aren't allowed), or
+    /// the server didn't prove possession of the pinned key.
+    Untrusted,
+    /// The server's Finished didn't match the handshake.
+    BadFinished,
+    /// A record failed to decrypt: corrupted or forged.
+    BadRecord,
+    /// A malformed or unexpected message.
+    Protocol,
+    /// The server sent a fatal alert with this description.
+    Alert(u8),
+    /// The connection ended without a close_notify, or is already closed.
+    Closed,
+}
+
+/// How a client authenticates the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trust {
+    /// The server must hold this Ed25519 key.
+    Pinned([u8; 32]),
+    /// Any server is accepted: the connection is encrypted only.
+    Unauthenticated,
+}
+
+lazy_static! {
+    static ref PINS: Mutex<Vec<(String, [u8; 32])>> = Mutex::new(Vec::new());
+    static ref SPARE_BUFFERS: Mutex<Vec<Buffers>> = Mutex::new(Vec::new());
+}
+
+/// Trust `host` only if it holds the Ed25519 public key `key`. Replaces an
+/// earlier pin for the same host.
+pub fn pin(host: &str, key: [u8; 32]) {
+    let mut pins = PINS.lock();
+    match pins.iter_mut().find(|(h, _)| h.eq_ignore_ascii_case(host)) {
+        Some(entry) => entry.1 = key,
+        None => pins.push((host.to_ascii_lowercase(), key)),
+    }
+}
+
+/// Every pinned host and its key.
+pub fn pins() -> Vec<(String, [u8; 32])> {
+    PINS.lock().clone()
+}
+
+/// How to authenticate `host`, or `None` if it mustn't be connected to.
+pub fn trust_for(host: &str) -> Option<Trust> {
+    let pinned = PINS.lock().iter().find(|(h, _)| h.eq_ignore_ascii_case(host)).map(|(_, key)| *key);
+    match pinned {
+        Some(key) => Some(Trust::Pinned(key)),
+        None if config::get_or("net.tls.allow_unpinned", 0) != 0 => Some(Trust::Unauthenticated),
+        None => None,
+    }
+}
+
+// ─── Key Schedule ────────────────────────────────────────────────────────────
+
+type Secret = [u8; 32];
+
+fn extract(salt: &[u8], ikm: &[u8]) -> Secret {
+    let (prk, _) = Hkdf::<Sha256>::extract(Some(salt), ikm);
+    let mut secret = [0; 32];
+    secret.copy_from_slice(&prk);
+    secret
+}
+
+/// HKDF-Expand-Label (RFC 8446, 7.1).
+fn expand_label(secret: &Secret, label: &[u8], context: &[u8], out: &mut [u8]) {
+    let hkdf = Hkdf::<Sha256>::from_prk(secret).expect("a secret is one SHA-256 output");
+    let length = (out.len() as u16).to_be_bytes();
+    let label_len = [(6 + label.len()) as u8];
+    let context_len = [context.len() as u8];
+    hkdf.expand_multi_info(&[&length, &label_len, b"tls13 ", label, &context_len, context], out)
+        .expect("labels are short");
+}
+
+fn derive_secret(secret: &Secret, label: &[u8], transcript_hash: &[u8]) -> Secret {
+    let mut derived = [0; 32];
+    expand_label(secret, label, transcript_hash, &mut derived);
+    derived
+}
+
+/// The HMAC a Finished message carries for `transcript_hash`.
+fn finished_mac(traffic_secret: &Secret, transcript_hash: &[u8]) -> Hmac<Sha256> {
+    let mut key = [0; 32];
+    expand_label(traffic_secret, b"finished", &[], &mut key);
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&key).expect("HMAC takes any key length");
+    mac.update(transcript_hash);
+    mac
+}
+
+/// AEAD state for one direction of the record layer.
+struct RecordKeys {
+    cipher: ChaCha20Poly1305,
+    iv: [u8; 12],
+    seq: u64,
+}
+
+impl RecordKeys {
+    fn new(traffic_secret: &Secret) -> Self {
+        let mut key = [0; 32];
+        let mut iv = [0; 12];
+        expand_label(traffic_secret, b"key", &[], &mut key);
+        expand_label(traffic_secret, b"iv", &[], &mut iv);
+        RecordKeys { cipher: ChaCha20Poly1305::new(Key::from_slice(&key)), iv, seq: 0 }
+    }
+
+    /// The nonce of the next record: the IV XORed with its sequence number.
+    fn next_nonce(&mut self) -> [u8; 12] {
+        let mut nonce = self.iv;
+        for (byte, seq) in nonce[4..].iter_mut().zip(self.seq.to_be_bytes()) {
+            *byte ^= seq;
+        }
+        self.seq += 1;
+        nonce
+    }
+
+    /// Append `data` as one protected record of `content_type`.
+    fn seal(&mut self, out: &mut Vec<u8>, content_type: u8, data: &[u8]) {
+        let start = out.len();
+        let len = (data.len() + 1 + TAG_LEN) as u16;
+        out.extend_from_slice(&[CONTENT_APPLICATION_DATA, 0x03, 0x03]);
+        out.extend_from_slice(&len.to_be_bytes());
+        out.extend_from_slice(data);
+        out.push(content_type);
+        let nonce = self.next_nonce();
+        let (header, body) = out[start..].split_at_mut(RECORD_HEADER);
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(Nonce::from_slice(&nonce), header, body)
+            .expect("records are far below the AEAD limit");
+        out.extend_from_slice(&tag);
+    }
+
+    /// Decrypt the protected `record` in place. Returns the inner content
+    /// type and where the content lies in `record`.
+    fn open(&mut self, record: &mut [u8]) -> Result<(u8, core::ops::Range<usize>), TlsError> {
+        let (header, body) = record.split_at_mut(RECORD_HEADER);
+        if body.len() < TAG_LEN + 1 {
+            return Err(TlsError::BadRecord);
+        }
+        let (ciphertext, tag) = body.split_at_mut(body.len() - TAG_LEN);
+        let nonce = self.next_nonce();
+        self.cipher
+            .decrypt_in_place_detached(Nonce::from_slice(&nonce), header, ciphertext, Tag::from_slice(tag))
+            .map_err(|_| TlsError::BadRecord)?;
+        // The content type is the last non-zero byte; zeros after it pad.
+        let end = ciphertext.iter().rposition(|&b| b != 0).ok_or(TlsError::Protocol)?;
+        Ok((ciphertext[end], RECORD_HEADER..RECORD_HEADER + end))
+    }
+}
+
+// ─── Client ──────────────────────────────────────────────────────────────────
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    WaitServerHello,
+    WaitEncryptedExtensions,
+    WaitCertificate,
+    WaitCertificateVerify,
+    WaitFinished,
+    Established,
+    /// The server sent close_notify.
+    Closed,
+    Failed(TlsError),
+}
+
+#[derive(Default)]
+struct Buffers {
+    /// Received bytes not yet processed as records.
+    incoming: Vec<u8>,
+    /// Handshake bytes not yet processed as messages.
+    handshake: Vec<u8>,
+    /// Decrypted application data not yet read.
+    plaintext: Vec<u8>,
+    /// Records not yet sent.
+    outgoing: Vec<u8>,
+}
+
+impl Buffers {
+    /// A spare set of buffers, or a new one if admission control allows.
+    fn take() -> Result<Self, TlsError> {
+        if let Some(buffers) = SPARE_BUFFERS.lock().pop() {
+            return Ok(buffers);
+        }
+        admission::admit(&ResourceRequest { heap_bytes: SESSION_HEAP, frames: 0, sockets: 0 })
+            .map_err(|_| TlsError::NoResources)?;
+        Ok(Buffers {
+            incoming: Vec::with_capacity(RECORD_HEADER + MAX_CIPHERTEXT),
+            handshake: Vec::with_capacity(MAX_HANDSHAKE),
+            plaintext: Vec::with_capacity(MAX_PLAINTEXT),
+            outgoing: Vec::with_capacity(OUTGOING_CAPACITY),
+        })
+    }
+}
+
+/// The client side of one TLS connection, without the transport.
+///
+/// Bytes from the server go in through `receive`, bytes for the server
+/// come out through `transmit`; `read` and `write` carry the application
+/// data once `is_established`.
+pub struct TlsClient {
+    state: State,
+    trust: Trust,
+    key_share: StaticSecret,
+    transcript: Sha256,
+    handshake_secret: Secret,
+    /// Current traffic secrets, for Finished and key updates.
+    client_secret: Secret,
+    server_secret: Secret,
+    /// Ed25519 key of the server's leaf certificate, if it has one.
+    server_key: Option<[u8; 32]>,
+    read_keys: Option<RecordKeys>,
+    write_keys: Option<RecordKeys>,
+    buffers: Buffers,
+}
+
+impl TlsClient {
+    /// Start a handshake with `server_name`: the ClientHello is queued for
+    /// `transmit` at once. `server_name` is sent as SNI unless it is an IP
+    /// address.
+    pub fn new(server_name: &str, trust: Trust) -> Result<Self, TlsError> {
+        let mut secret = [0; 32];
+        crate::random::fill(&mut secret);
+        let mut client = TlsClient {
+            state: State::WaitServerHello,
+            trust,
+            key_share: StaticSecret::from(secret),
+            transcript: Sha256::new(),
+            handshake_secret: [0; 32],
+            client_secret: [0; 32],
+            server_secret: [0; 32],
+            server_key: None,
+            read_keys: None,
+            write_keys: None,
+            buffers: Buffers::take()?,
+        };
+        let hello = client.client_hello(server_name);
+        client.transcript.update(&hello);
+        let out = &mut client.buffers.outgoing;
+        out.extend_from_slice(&[CONTENT_HANDSHAKE, 0x03, 0x01]);
+        out.extend_from_slice(&(hello.len() as u16).to_be_bytes());
+        out.extend_from_slice(&hello);
+        Ok(client)
+    }
+
+    fn client_hello(&self, server_name: &str) -> Vec<u8> {
+        let mut random = [0; 32];
+        crate::random::fill(&mut random);
+        let public = PublicKey::from(&self.key_share);
+
+        let mut extensions = Vec::new();
+        if dns::parse_ipv4(server_name).is_none() {
+            let name = server_name.as_bytes();
+            put_extension(&mut extensions, EXT_SERVER_NAME, &[
+                &((name.len() + 3) as u16).to_be_bytes(),
+                &[0],
+                &(name.len() as u16).to_be_bytes(),
+                name,
+            ]);
+        }
+        put_extension(&mut extensions, EXT_SUPPORTED_GROUPS, &[&2u16.to_be_bytes(), &GROUP_X25519.to_be_bytes()]);
+        put_extension(&mut extensions, EXT_SIGNATURE_ALGORITHMS, &[
+            &6u16.to_be_bytes(),
+            &SIG_ED25519.to_be_bytes(),
+            &SIG_ECDSA_P256_SHA256.to_be_bytes(),
+            &SIG_RSA_PSS_SHA256.to_be_bytes(),
+        ]);
+        put_extension(&mut extensions, EXT_SUPPORTED_VERSIONS, &[&[2], &TLS13.to_be_bytes()]);
+        put_extension(&mut extensions, EXT_KEY_SHARE, &[
+            &36u16.to_be_bytes(),
+            &GROUP_X25519.to_be_bytes(),
+            &32u16.to_be_bytes(),
+            public.as_bytes(),
+        ]);
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&[0x03, 0x03]);
+        body.extend_from_slice(&random);
+        body.push(0); // legacy_session_id
+        body.extend_from_slice(&2u16.to_be_bytes());
+        body.extend_from_slice(&TLS_CHACHA20_POLY1305_SHA256.to_be_bytes());
+        body.extend_from_slice(&[1, 0]); // null compression
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut message = Vec::with_capacity(4 + body.len());
+        message.push(HS_CLIENT_HELLO);
+        message.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        message.extend_from_slice(&body);
+        message
+    }
+
+    /// Whether the handshake has completed and application data can flow.
+    pub fn is_established(&self) -> bool {
+        self.state == State::Established
+    }
+
+    /// Whether the server has closed the connection with close_notify.
+    pub fn is_closed(&self) -> bool {
+        self.state == State::Closed
+    }
+
+    /// The error that ended the session, if one did.
+    pub fn failure(&self) -> Option<TlsError> {
+        match self.state {
+            State::Failed(e) => Some(e),
+            _ => None,
+        }
+    }
+
+    /// Hand queued records to `send`, which returns how many bytes it took
+    /// (0 when it can't take more now).
+    pub fn transmit(&mut self, mut send: impl FnMut(&[u8]) -> Result<usize, TlsError>) -> Result<(), TlsError> {
+        let out = &mut self.buffers.outgoing;
+        while !out.is_empty() {
+            let sent = send(out)?;
+            if sent == 0 {
+                break;
+            }
+            out.drain(..sent);
+        }
+        Ok(())
+    }
+
+    /// Fill the receive buffer with `recv`, which returns how many bytes it
+    /// wrote, and process every complete record.
+    pub fn receive(&mut self, recv: impl FnOnce(&mut [u8]) -> Result<usize, TlsError>) -> Result<(), TlsError> {
+        if let State::Failed(e) = self.state {
+            return Err(e);
+        }
+        let incoming = &mut self.buffers.incoming;
+        let start = incoming.len();
+        incoming.resize(RECORD_HEADER + MAX_CIPHERTEXT, 0);
+        let received = recv(&mut incoming[start..]);
+        incoming.truncate(start + *received.as_ref().unwrap_or(&0));
+        received?;
+        let result = self.process_records();
+        if let Err(e) = result {
+            self.state = State::Failed(e);
+        }
+        result
+    }
+
+    /// Copy decrypted application data into `buf`. Returns the count, 0 if
+    /// none is waiting.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let plaintext = &mut self.buffers.plaintext;
+        let n = buf.len().min(plaintext.len());
+        buf[..n].copy_from_slice(&plaintext[..n]);
+        plaintext.drain(..n);
+        if n > 0 {
+            // Records held back for lack of room may fit now.
+            if let Err(e) = self.process_records() {
+                self.state = State::Failed(e);
+            }
+        }
+        n
+    }
+
+    /// Encrypt application data for `transmit`. Returns how much of `data`
+    /// was taken: 0 while the handshake is running or the send queue is
+    /// full.
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, TlsError> {
+        match self.state {
+            State::Established => {}
+            State::Failed(e) => return Err(e),
+            State::Closed => return Err(TlsError::Closed),
+            _ => return Ok(0),
+        }
+        let room = OUTGOING_CAPACITY - self.buffers.outgoing.len();
+        if room <= RECORD_HEADER + 1 + TAG_LEN {
+            return Ok(0);
+        }
+        let n = data.len().min(MAX_WRITE).min(room - RECORD_HEADER - 1 - TAG_LEN);
+        let keys = self.write_keys.as_mut().expect("established sessions have keys");
+        keys.seal(&mut self.buffers.outgoing, CONTENT_APPLICATION_DATA, &data[..n]);
+        Ok(n)
+    }
+
+    /// Queue a close_notify alert. Write nothing after it.
+    pub fn close(&mut self) {
+        if let Some(keys) = self.write_keys.as_mut() {
+            keys.seal(&mut self.buffers.outgoing, CONTENT_ALERT, &[1, ALERT_CLOSE_NOTIFY]);
+        }
+    }
+
+    // ─── Records ─────────────────────────────────────────────────────
+
+    fn process_records(&mut self) -> Result<(), TlsError> {
+        loop {
+            let incoming = &mut self.buffers.incoming;
+            if incoming.len() < RECORD_HEADER {
+                return Ok(());
+            }
+            let content_type = incoming[0];
+            let len = u16::from_be_bytes([incoming[3], incoming[4]]) as usize;
+            if len > MAX_CIPHERTEXT {
+                return Err(TlsError::Protocol);
+            }
+            let end = RECORD_HEADER + len;
+            if incoming.len() < end {
+                return Ok(());
+            }
+            // Leave application data in place until `read` makes room.
+            if content_type == CONTENT_APPLICATION_DATA
+                && self.buffers.plaintext.len() + len.saturating_sub(TAG_LEN + 1) > MAX_PLAINTEXT
+            {
+                return Ok(());
+            }
+
+            match (content_type, self.read_keys.as_mut()) {
+                (CONTENT_CHANGE_CIPHER_SPEC, _) => {}
+                (CONTENT_ALERT, None) => {
+                    let description = *incoming.get(RECORD_HEADER + 1).ok_or(TlsError::Protocol)?;
+                    self.alert(description)?;
+                }
+                (CONTENT_HANDSHAKE, None) => {
+                    append(&mut self.buffers.handshake, &incoming[RECORD_HEADER..end])?;
+                }
+                (CONTENT_APPLICATION_DATA, Some(keys)) => {
+                    let (inner_type, content) = keys.open(&mut incoming[..end])?;
+                    let content = &incoming[content];
+                    if content.len() > MAX_PLAINTEXT {
+                        return Err(TlsError::Protocol);
+                    }
+                    match inner_type {
+                        CONTENT_HANDSHAKE => append(&mut self.buffers.handshake, content)?,
+                        CONTENT_APPLICATION_DATA if self.state == State::Established => {
+                            self.buffers.plaintext.extend_from_slice(content);
+                        }
+                        CONTENT_ALERT if content.len() == 2 => {
+                            let description = content[1];
+                            self.buffers.incoming.drain(..end);
+                            return self.alert(description);
+                        }
+                        _ => return Err(TlsError::Protocol),
+                    }
+                }
+                _ => return Err(TlsError::Protocol),
+            }
+            self.buffers.incoming.drain(..end);
+            self.process_handshake()?;
+        }
+    }
+
+    fn alert(&mut self, description: u8) -> Result<(), TlsError> {
+        if description == ALERT_CLOSE_NOTIFY {
+            self.state = State::Closed;
+            Ok(())
+        } else {
+            Err(TlsError::Alert(description))
+        }
+    }
+
+    // ─── Handshake ───────────────────────────────────────────────────
+
+    fn process_handshake(&mut self) -> Result<(), TlsError> {
+        loop {
+            let buffered = &self.buffers.handshake;
+            if buffered.len() < 4 {
+                return Ok(());
+            }
+            let len = u32::from_be_bytes([0, buffered[1], buffered[2], buffered[3]]) as usize;
+            if len > MAX_HANDSHAKE - 4 {
+                return Err(TlsError::Protocol);
+            }
+            if buffered.len() < 4 + len {
+                return Ok(());
+            }
+            let mut buffered = core::mem::take(&mut self.buffers.handshake);
+            let result = self.handle_message(&buffered[..4 + len]);
+            buffered.drain(..4 + len);
+            self.buffers.handshake = buffered;
+            result?;
+        }
+    }
+
+    /// Handle one handshake message, header included.
+    fn handle_message(&mut self, message: &[u8]) -> Result<(), TlsError> {
+        let body = &message[4..];
+        match (self.state, message[0]) {
+            (State::WaitServerHello, HS_SERVER_HELLO) => {
+                self.server_hello(body)?;
+                self.transcript.update(message);
+                self.handshake_keys();
+                self.state = State::WaitEncryptedExtensions;
+            }
+            (State::WaitEncryptedExtensions, HS_ENCRYPTED_EXTENSIONS) => {
+                self.transcript.update(message);
+                self.state = State::WaitCertificate;
+            }
+            (State::WaitCertificate, HS_CERTIFICATE_REQUEST) => return Err(TlsError::Unsupported),
+            (State::WaitCertificate, HS_CERTIFICATE) => {
+                self.certificate(body)?;
+                self.transcript.update(message);
+                self.state = State::WaitCertificateVerify;
+            }
+            (State::WaitCertificateVerify, HS_CERTIFICATE_VERIFY) => {
+                self.certificate_verify(body)?;
+                self.transcript.update(message);
+                self.state = State::WaitFinished;
+            }
+            (State::WaitFinished, HS_FINISHED) => {
+                finished_mac(&self.server_secret, &self.transcript.clone().finalize())
+                    .verify_slice(body)
+                    .map_err(|_| TlsError::BadFinished)?;
+                self.transcript.update(message);
+                self.finish();
+                self.state = State::Established;
+            }
+            (State::Established | State::Closed, HS_NEW_SESSION_TICKET) => {}
+            (State::Established | State::Closed, HS_KEY_UPDATE) => self.key_update(body)?,
+            _ => return Err(TlsError::Protocol),
+        }
+        Ok(())
+    }
+
+    /// Check the ServerHello and derive the shared secret from its key share.
+    fn server_hello(&mut self, body: &[u8]) -> Result<(), TlsError> {
+        let mut r = Reader::new(body);
+        r.take(2)?; // legacy_version
+        if r.take(32)? == HRR_RANDOM {
+            return Err(TlsError::Unsupported);
+        }
+        r.vec8()?; // legacy_session_id_echo
+        if r.u16()? != TLS_CHACHA20_POLY1305_SHA256 {
+            return Err(TlsError::Unsupported);
+        }
+        r.take(1)?; // legacy_compression_method
+
+        let mut version = 0;
+        let mut share = None;
+        let mut extensions = Reader::new(r.vec16()?);
+        while !extensions.is_empty() {
+            let ext_type = extensions.u16()?;
+            let mut data = Reader::new(extensions.vec16()?);
+            match ext_type {
+                EXT_SUPPORTED_VERSIONS => version = data.u16()?,
+                EXT_KEY_SHARE => {
+                    if data.u16()? != GROUP_X25519 {
+                        return Err(TlsError::Unsupported);
+                    }
+                    share = Some(data.vec16()?);
+                }
+                _ => {}
+            }
+        }
+        if version != TLS13 {
+            return Err(TlsError::Unsupported);
+        }
+        let share: [u8; 32] = share.and_then(|s| s.try_into().ok()).ok_or(TlsError::Protocol)?;
+        let shared = self.key_share.diffie_hellman(&PublicKey::from(share));
+        if !shared.was_contributory() {
+            return Err(TlsError::Protocol);
+        }
+        let early_secret = extract(&[0; 32], &[0; 32]);
+        let derived = derive_secret(&early_secret, b"derived", &Sha256::digest(b""));
+        self.handshake_secret = extract(&derived, shared.as_bytes());
+        Ok(())
+    }
+
+    /// Switch to the handshake traffic keys. Needs the transcript up to the
+    /// ServerHello.
+    fn handshake_keys(&mut self) {
+        let hash = self.transcript.clone().finalize();
+        self.client_secret = derive_secret(&self.handshake_secret, b"c hs traffic", &hash);
+        self.server_secret = derive_secret(&self.handshake_secret, b"s hs traffic", &hash);
+        self.read_keys = Some(RecordKeys::new(&self.server_secret));
+        self.write_keys = Some(RecordKeys::new(&self.client_secret));
+    }
+
+    /// Take the leaf certificate's key and check it against the pin.
+    fn certificate(&mut self, body: &[u8]) -> Result<(), TlsError> {
+        let mut r = Reader::new(body);
+        r.vec8()?; // certificate_request_context
+        let mut entries = Reader::new(r.vec24()?);
+        let leaf = entries.vec24()?;
+        self.server_key = leaf
+            .windows(ED25519_SPKI_PREFIX.len() + 32)
+            .find(|w| w.starts_with(&ED25519_SPKI_PREFIX))
+            .map(|w| w[ED25519_SPKI_PREFIX.len()..].try_into().expect("window holds a key"));
+        match self.trust {
+            Trust::Pinned(pin) if self.server_key != Some(pin) => Err(TlsError::Untrusted),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the server's signature over the transcript so far, which
+    /// proves it holds the certificate's key.
+    fn certificate_verify(&mut self, body: &[u8]) -> Result<(), TlsError> {
+        if self.trust == Trust::Unauthenticated {
+            return Ok(());
+        }
+        let mut r = Reader::new(body);
+        let scheme = r.u16()?;
+        let signature = r.vec16()?;
+        let key = self.server_key.ok_or(TlsError::Untrusted)?;
+        if scheme != SIG_ED25519 {
+            return Err(TlsError::Untrusted);
+        }
+
+        const CONTEXT: &[u8] = b"TLS 1.3, server CertificateVerify";
+        let mut content = [0x20; 64 + CONTEXT.len() + 1 + 32];
+        content[64..64 + CONTEXT.len()].copy_from_slice(CONTEXT);
+        content[64 + CONTEXT.len()] = 0;
+        content[64 + CONTEXT.len() + 1..].copy_from_slice(&self.transcript.clone().finalize());
+
+        let key = ed25519_dalek::VerifyingKey::from_bytes(&key).map_err(|_| TlsError::Untrusted)?;
+        let signature = ed25519_dalek::Signature::from_slice(signature).map_err(|_| TlsError::Untrusted)?;
+        key.verify_strict(&content, &signature).map_err(|_| TlsError::Untrusted)
+    }
+
+    /// After the server's Finished: send ours and switch to the application
+    /// traffic keys.
+    fn finish(&mut self) {
+        let hash = self.transcript.clone().finalize();
+        let mut message = [0; 4 + 32];
+        message[0] = HS_FINISHED;
+        message[3] = 32;
+        message[4..].copy_from_slice(&finished_mac(&self.client_secret, &hash).finalize().into_bytes());
+        let keys = self.write_keys.as_mut().expect("handshake keys are set");
+        keys.seal(&mut self.buffers.outgoing, CONTENT_HANDSHAKE, &message);
+
+        let derived = derive_secret(&self.handshake_secret, b"derived", &Sha256::digest(b""));
+        let master_secret = extract(&derived, &[0; 32]);
+        self.client_secret = derive_secret(&master_secret, b"c ap traffic", &hash);
+        self.server_secret = derive_secret(&master_secret, b"s ap traffic", &hash);
+        self.read_keys = Some(RecordKeys::new(&self.server_secret));
+        self.write_keys = Some(RecordKeys::new(&self.client_secret));
+    }
+
+    /// The server moved to new keys; follow it, and move ours too if asked.
+    fn key_update(&mut self, body: &[u8]) -> Result<(), TlsError> {
+        let update_requested = match body {
+            [0] => false,
+            [1] => true,
+            _ => return Err(TlsError::Protocol),
+        };
+        self.server_secret = derive_secret(&self.server_secret, b"traffic upd", &[]);
+        self.read_keys = Some(RecordKeys::new(&self.server_secret));
+        if update_requested && self.state == State::Established {
+            let keys = self.write_keys.as_mut().expect("established sessions have keys");
+            keys.seal(&mut self.buffers.outgoing, CONTENT_HANDSHAKE, &[HS_KEY_UPDATE, 0, 0, 1, 0]);
+            self.client_secret = derive_secret(&self.client_secret, b"traffic upd", &[]);
+            self.write_keys = Some(RecordKeys::new(&self.client_secret));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TlsClient {
+    fn drop(&mut self) {
+        let mut buffers = core::mem::take(&mut self.buffers);
+        buffers.incoming.clear();
+        buffers.handshake.clear();
+        buffers.plaintext.clear();
+        buffers.outgoing.clear();
+        SPARE_BUFFERS.lock().push(buffers);
+    }
+}
+
+/// Append handshake bytes, refusing more than one message can hold.
+fn append(handshake: &mut Vec<u8>, data: &[u8]) -> Result<(), TlsError> {
+    if handshake.len() + data.len() > MAX_HANDSHAKE {
+        return Err(TlsError::Protocol);
+    }
+    handshake.extend_from_slice(data);
+    Ok(())
+}
+
+fn put_extension(out: &mut Vec<u8>, ext_type: u16, parts: &[&[u8]]) {
+    let len: usize = parts.iter().map(|p| p.len()).sum();
+    out.extend_from_slice(&ext_type.to_be_bytes());
+    out.extend_from_slice(&(len as u16).to_be_bytes());
+    for part in parts {
+        out.extend_from_slice(part);
+    }
+}
+
+/// Cursor over a handshake message; every read fails with `Protocol` past
+/// the end.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], TlsError> {
+        if self.data.len() < n {
+            return Err(TlsError::Protocol);
+        }
+        let (head, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(head)
+    }
+
+    fn u16(&mut self) -> Result<u16, TlsError> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn vec8(&mut self) -> Result<&'a [u8], TlsError> {
+        let len = self.take(1)?[0] as usize;
+        self.take(len)
+    }
+
+    fn vec16(&mut self) -> Result<&'a [u8], TlsError> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+
+    fn vec24(&mut self) -> Result<&'a [u8], TlsError> {
+        let b = self.take(3)?;
+        self.take(u32::from_be_bytes([0, b[0], b[1], b[2]]) as usize)
+    }
+}
+
+// ─── Kernel Connections ──────────────────────────────────────────────────────
+
+/// A TLS connection over a kernel TCP socket, from `tls_connect`. Hand it
+/// back with `close` when done.
+pub struct TlsStream {
+    handle: SocketHandle,
+    client: TlsClient,
+}
+
+/// Connect to `port` on `host` and complete a TLS handshake, with `host`
+/// authenticated as `trust_for` says.
+pub async fn tls_connect(host: &str, port: u16) -> Result<TlsStream, TlsError> {
+    let trust = trust_for(host).ok_or(TlsError::Untrusted)?;
+    let addr = dns::resolve(host).await.map_err(TlsError::Dns)?;
+    let client = TlsClient::new(host, trust)?;
+    let handle = net_stack::tcp_connect(addr, port).await.map_err(TlsError::Connect)?;
+    let mut stream = TlsStream { handle, client };
+
+    let deadline = uptime_ms() + config::get_or("net.tls.handshake_timeout_ms", 10_000);
+    loop {
+        if let Err(e) = stream.pump() {
+            net_stack::tcp_close(stream.handle);
+            return Err(e);
+        }
+        if stream.client.is_established() {
+            return Ok(stream);
+        }
+        if stream.client.is_closed() || uptime_ms() >= deadline {
+            let closed = stream.client.is_closed();
+            net_stack::tcp_close(stream.handle);
+            return Err(if closed { TlsError::Closed } else { TlsError::TimedOut });
+        }
+        yield_now().await;
+    }
+}
+
+impl TlsStream {
+    /// Exchange whatever records are ready with the socket.
+    fn pump(&mut self) -> Result<(), TlsError> {
+        let mut stack = NETWORK_STACK.lock();
+        let stack = stack.as_mut().ok_or(TlsError::NoNetwork)?;
+        let socket = stack.sockets.get_mut::<TcpSocket>(self.handle);
+        let (mut sent, mut received) = (0, 0);
+        self.client.transmit(|data| {
+            let n = socket.send_slice(data).map_err(|_| TlsError::Closed)?;
+            sent += n;
+            Ok(n)
+        })?;
+        self.client.receive(|buf| {
+            received = socket.recv_slice(buf).map_err(|_| TlsError::Closed)?;
+            Ok(received)
+        })?;
+        // Receiving may have queued a reply (a key update).
+        self.client.transmit(|data| {
+            let n = socket.send_slice(data).map_err(|_| TlsError::Closed)?;
+            sent += n;
+            Ok(n)
+        })?;
+        stack.record_send(self.handle, sent, sent);
+        stack.record_recv(self.handle, received);
+        Ok(())
+    }
+
+    /// Send all of `data`.
+    pub async fn write_all(&mut self, mut data: &[u8]) -> Result<(), TlsError> {
+        while !data.is_empty() {
+            let n = self.client.write(data)?;
+            data = &data[n..];
+            self.pump()?;
+            if n == 0 {
+                yield_now().await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait for application data and read it into `buf`. Returns 0 once
+    /// the server has closed the connection.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, TlsError> {
+        loop {
+            let n = self.client.read(buf);
+            if n > 0 {
+                return Ok(n);
+            }
+            if self.client.is_closed() {
+                return Ok(0);
+            }
+            self.pump()?;
+            if self.client.is_closed() || !self.client.buffers.plaintext.is_empty() {
+                continue;
+            }
+            yield_now().await;
+        }
+    }
+
+    /// Send close_notify and close the TCP connection behind it.
+    pub fn close(mut self) {
+        self.client.close();
+        let _ = self.pump();
+        net_stack::tcp_close(self.handle);
+    }
+}
+
+// ─── Process Sessions ────────────────────────────────────────────────────────
+//
+// A WASM process layers TLS over a connected socket it holds a capability
+// for. The session is keyed by the socket's resource id and ends with the
+// socket (`end_session`).
+
+lazy_static! {
+    static ref SESSIONS: Mutex<Vec<(u64, TlsClient)>> = Mutex::new(Vec::new());
+}
+
+/// Start a handshake with `host` over the process socket `cap` names.
+pub fn start_session(cap: &Capability, host: &str) -> Result<(), TlsError> {
+    let trust = trust_for(host).ok_or(TlsError::Untrusted)?;
+    let mut sessions = SESSIONS.lock();
+    if sessions.iter().any(|(id, _)| *id == cap.resource_id) {
+        return Err(TlsError::Protocol);
+    }
+    let mut client = TlsClient::new(host, trust)?;
+    pump_process(cap, &mut client)?;
+    sessions.push((cap.resource_id, client));
+    Ok(())
+}
+
+/// Run `op` on the session over the socket `cap` names, after exchanging
+/// whatever records are ready with the socket.
+pub fn with_process_session<R>(cap: &Capability, op: impl FnOnce(&mut TlsClient) -> R) -> Result<R, TlsError> {
+    let mut sessions = SESSIONS.lock();
+    let (_, client) = sessions
+        .iter_mut()
+        .find(|(id, _)| *id == cap.resource_id)
+        .ok_or(TlsError::Socket(SocketError::NotFound))?;
+    pump_process(cap, client)?;
+    let result = op(client);
+    pump_process(cap, client)?;
+    Ok(result)
+}
+
+/// Drop the session over socket `socket_id`, if there is one. Call when
+/// the socket is closed.
+pub fn end_session(socket_id: u64) {
+    SESSIONS.lock().retain(|(id, _)| *id != socket_id);
+}
+
+fn pump_process(cap: &Capability, client: &mut TlsClient) -> Result<(), TlsError> {
+    let mut stack = NETWORK_STACK.lock();
+    let stack = stack.as_mut().ok_or(TlsError::NoNetwork)?;
+    client.transmit(|data| stack.send(cap, data).map_err(TlsError::Socket))?;
+    client.receive(|buf| stack.recv(cap, buf).map_err(TlsError::Socket))
+}
@@ -0,0 +1,88 @@
+//! # Live Upgrade
+//!
+//! Replaces a running WASM service with a new version of its module without
+//! taking its endpoints offline, e.g. when an update arrives over the P2P
+//! layer.
+//!
+//! ## Sequence
+//! 1. The new version is started under the old instance's name and parent,
+//!    with a copy of the old instance's capabilities in the same slots: its
+//!    endpoints (so queued and future requests reach the new version),
+//!    devices and, if `keep_state`, its key-value namespaces. Sockets and
+//!    shared regions die with the old instance and are not copied, and
+//!    neither is a scheduling context: the old instance stays bound to it
+//!    until it exits, so the new version runs best-effort.
+//! 2. Ownership of the old instance's endpoints, its service registrations
+//!    and its exit notification move to the new one. A supervised service's watcher follows the new PID
+//!    and restarts the new module from then on.
+//! 3. The old instance is killed.
+//!
+//! If the new version can't be loaded the old one keeps running untouched.
+//!
+//! ## State
+//! Key-value data stays in the store either way. Without `keep_state` the
+//! new version gets no Storage capabilities, so it starts empty-handed and
+//! the old data is still there for a rollback.
+
+use alloc::vec::Vec;
+use crate::capability::CapabilityType;
+use crate::ipc::IPC_MANAGER;
+use crate::process::{self, Pid, ProcessError, ProcessStatus};
+use crate::wasm_runtime::ProcessOptions;
+use crate::{serial_println, services, supervisor};
+
+/// The replacement for a running service.
+pub struct UpgradeSpec<'a> {
+    /// The new module. Kept for the supervisor's later restarts, hence
+    /// `'static` (leak a received buffer; the kernel heap never frees).
+    pub module: &'static [u8],
+    pub entry: &'a str,
+    /// Arguments and environment of the new version. A `memory_limit` of
+    /// `None` keeps the old instance's limit.
+    pub options: ProcessOptions,
+    /// Hand over the old instance's key-value namespaces.
+    pub keep_state: bool,
+}
+
+/// Replace process `pid` with `spec`. Returns the new version's PID.
+pub fn upgrade(pid: Pid, spec: UpgradeSpec) -> Result<Pid, ProcessError> {
+    let old = process::get(pid).ok_or(ProcessError::NoSuchProcess)?;
+    if let ProcessStatus::Exited(_) = old.status {
+        return Err(ProcessError::AlreadyExited);
+    }
+
+    let mut cspace = old.cspace.lock().replicate();
+    let dropped: Vec<usize> = cspace
+        .iter()
+        .filter(|(_, cap)| match cap.cap_type {
+            CapabilityType::Socket | CapabilityType::SharedMemory | CapabilityType::SchedContext => true,
+            CapabilityType::Storage => !spec.keep_state,
+            _ => false,
+        })
+        .map(|(slot, _)| slot)
+        .collect();
+    for slot in dropped {
+        cspace.revoke(slot);
+    }
+
+    let mut options = spec.options;
+    options.memory_limit = options.memory_limit.or(Some(old.memory_limit));
+    let new_pid = process::spawn_child(old.parent, &old.name, spec.module, spec.entry, cspace, options, None)?;
+
+    services::transfer_owner(pid, new_pid);
+    IPC_MANAGER.lock().transfer_owner(pid, new_pid);
+    process::transfer_exit_notify(pid, new_pid);
+    let supervised = supervisor::hand_over(pid, new_pid, spec.module);
+    // It can't have exited in between: nothing above yields.
+    let _ = process::kill(pid);
+
+    serial_println!(
+        "[UPGRADE] '{}' PID {} replaced by PID {}{}{}.",
+        old.name,
+        pid,
+        new_pid,
+        if spec.keep_state { ", state kept" } else { "" },
+        if supervised { ", still supervised" } else { "" }
+    );
+    Ok(new_pid)
+}
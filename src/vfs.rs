@@ -0,0 +1,167 @@
+//! # Virtual Filesystem (VFS)
+//!
+//! A minimal mount table that routes path lookups to filesystem drivers.
+//!
+//! ## Design
+//! Every filesystem implements [`FileSystem`] and is mounted at an absolute
+//! prefix (e.g., `/proc`). A lookup picks the mount with the longest
+//! matching prefix and hands the driver the remainder of the path, so
+//! drivers never see where they were mounted.
+//!
+//! ```text
+//!   read("/proc/meminfo")
+//!        │
+//!        ▼
+//!   ┌────────────┐   longest prefix   ┌────────────┐
+//!   │ Mount table│ ─────────────────▶ │ ProcFs     │ read("meminfo")
+//!   └────────────┘                    └────────────┘
+//! ```
+//!
+//! Device filesystems (`/dev`) additionally implement `open`, which returns
+//! a byte-stream handle after checking the caller's capabilities.
+//!
+//! Reads and writes are whole-file: drivers render the full contents into a
+//! `Vec<u8>`, and a write replaces a file. That matches the filesystems we
+//! have today — synthetic snapshots generated on demand and small RAM files.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::capability::{CSpace, Permissions};
+use crate::chardev::OpenDevice;
+
+lazy_static! {
+    pub static ref VFS: Mutex<Vfs> = Mutex::new(Vfs::new());
+}
+
+/// Errors returned by VFS operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VfsError {
+    /// No file or directory exists at the path.
+    NotFound,
+    /// The path names a directory where a file was expected, or vice versa.
+    NotADirectory,
+    /// A filesystem is already mounted at this prefix.
+    AlreadyMounted,
+    /// The path is not absolute.
+    InvalidPath,
+    /// The path names a device; use `open` instead of `read`.
+    IsADevice,
+    /// The path does not name a device.
+    NotADevice,
+    /// The caller lacks the capability for this operation.
+    PermissionDenied,
+    /// The filesystem does not support writing.
+    ReadOnly,
+    /// The filesystem is full or the file is too large.
+    NoSpace,
+}
+
+/// Who is performing a lookup. Lets drivers render per-caller views such as
+/// `/proc/self`.
+pub struct ReadContext<'a> {
+    /// The caller's capabilities, if the read comes from a process.
+    pub cspace: Option<&'a CSpace>,
+}
+
+impl ReadContext<'_> {
+    /// A lookup made by the kernel itself, not on behalf of a process.
+    pub const KERNEL: ReadContext<'static> = ReadContext { cspace: None };
+}
+
+/// A mountable filesystem driver. Paths are relative to the mount point,
+/// without a leading slash (`""` is the driver's root directory).
+pub trait FileSystem: Send {
+    /// Read the full contents of the file at `path`.
+    fn read(&self, path: &str, ctx: &ReadContext) -> Result<Vec<u8>, VfsError>;
+    /// List the entry names of the directory at `path`.
+    fn list(&self, path: &str) -> Result<Vec<String>, VfsError>;
+    /// Replace the contents of the file at `path`, creating it if needed.
+    fn write(&self, path: &str, data: &[u8]) -> Result<(), VfsError> {
+        let _ = (path, data);
+        Err(VfsError::ReadOnly)
+    }
+    /// Open the device at `path` for `permissions`. Only device filesystems
+    /// implement this; they are responsible for the capability check.
+    fn open(&self, path: &str, permissions: Permissions, ctx: &ReadContext) -> Result<OpenDevice, VfsError> {
+        let _ = (path, permissions, ctx);
+        Err(VfsError::NotADevice)
+    }
+}
+
+struct Mount {
+    prefix: String,
+    fs: Box<dyn FileSystem>,
+}
+
+pub struct Vfs {
+    mounts: Vec<Mount>,
+}
+
+impl Vfs {
+    pub const fn new() -> Self {
+        Vfs { mounts: Vec::new() }
+    }
+
+    /// Mount `fs` at the absolute path `prefix`.
+    pub fn mount(&mut self, prefix: &str, fs: Box<dyn FileSystem>) -> Result<(), VfsError> {
+        if !prefix.starts_with('/') {
+            return Err(VfsError::InvalidPath);
+        }
+        let prefix = prefix.trim_end_matches('/');
+        if self.mounts.iter().any(|m| m.prefix == prefix) {
+            return Err(VfsError::AlreadyMounted);
+        }
+        self.mounts.push(Mount { prefix: String::from(prefix), fs });
+        Ok(())
+    }
+
+    /// Read the file at the absolute `path`.
+    pub fn read(&self, path: &str, ctx: &ReadContext) -> Result<Vec<u8>, VfsError> {
+        let (fs, rest) = self.resolve(path)?;
+        fs.read(rest, ctx)
+    }
+
+    /// List the directory at the absolute `path`.
+    pub fn list(&self, path: &str) -> Result<Vec<String>, VfsError> {
+        let (fs, rest) = self.resolve(path)?;
+        fs.list(rest)
+    }
+
+    /// Open the device at the absolute `path` for `permissions`.
+    pub fn open(&self, path: &str, permissions: Permissions, ctx: &ReadContext) -> Result<OpenDevice, VfsError> {
+        let (fs, rest) = self.resolve(path)?;
+        fs.open(rest, permissions, ctx)
+    }
+
+    /// Replace the contents of the file at the absolute `path`.
+    pub fn write(&self, path: &str, data: &[u8]) -> Result<(), VfsError> {
+        let (fs, rest) = self.resolve(path)?;
+        fs.write(rest, data)
+    }
+
+    /// Find the mount with the longest prefix matching `path` and return it
+    /// together with the path relative to that mount.
+    fn resolve<'p>(&self, path: &'p str) -> Result<(&dyn FileSystem, &'p str), VfsError> {
+        if !path.starts_with('/') {
+            return Err(VfsError::InvalidPath);
+        }
+        let path = path.trim_end_matches('/');
+        self.mounts
+            .iter()
+            .filter_map(|m| {
+                let rest = path.strip_prefix(m.prefix.as_str())?;
+                // "/procfoo" must not match a mount at "/proc".
+                if rest.is_empty() || rest.starts_with('/') {
+                    Some((m, rest.trim_start_matches('/')))
+                } else {
+                    None
+                }
+            })
+            .max_by_key(|(m, _)| m.prefix.len())
+            .map(|(m, rest)| (&*m.fs, rest))
+            .ok_or(VfsError::NotFound)
+    }
+}
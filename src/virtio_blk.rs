@@ -0,0 +1,109 @@
+//! # VirtIO Block Driver
+//!
+//! Drives virtio-blk disks through `virtio_drivers`' `VirtIOBlk` and hands
+//! each one to the block layer as `diskN`, which scans it for partitions
+//! (see `block::register_disk`).
+//!
+//! ## Device (PCI ID 0x1001 transitional, 0x1042 modern)
+//! - One request queue; blocks are 512-byte sectors.
+//! - Requests are synchronous: the driver submits one and spins until the
+//!   device completes it, so no interrupts are routed.
+//! - `flush` sends `VIRTIO_BLK_T_FLUSH` when the device offers
+//!   `VIRTIO_BLK_F_FLUSH`. A device that doesn't has no volatile write
+//!   cache, so its completed writes are already durable.
+
+use alloc::format;
+use alloc::sync::Arc;
+use spin::Mutex;
+use virtio_drivers::device::blk::{VirtIOBlk, SECTOR_SIZE};
+use virtio_drivers::transport::Transport;
+use crate::block::{self, check_request, BlockDevice, BlockError};
+use crate::hal::VirtioHal;
+use crate::network::{self, LegacyTransport, VirtioLocation};
+use crate::serial_println;
+use crate::virtio_modern::ModernTransport;
+
+/// One virtio-blk disk.
+struct VirtioDisk<T: Transport> {
+    device: Mutex<VirtIOBlk<VirtioHal, T>>,
+    /// Capacity in sectors, read once at init.
+    sectors: u64,
+    read_only: bool,
+}
+
+impl<T: Transport + Send + 'static> VirtioDisk<T> {
+    /// Initialize the device behind `transport`.
+    fn probe(transport: T) -> Option<Arc<dyn BlockDevice>> {
+        match VirtIOBlk::<VirtioHal, T>::new(transport) {
+            Ok(device) => {
+                let sectors = device.capacity();
+                let read_only = device.readonly();
+                Some(Arc::new(VirtioDisk { device: Mutex::new(device), sectors, read_only }))
+            }
+            Err(e) => {
+                serial_println!("[BLK] Device init failed: {:?}", e);
+                None
+            }
+        }
+    }
+}
+
+impl<T: Transport + Send> BlockDevice for VirtioDisk<T> {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        if check_request(self, lba, buf.len())? == 0 {
+            return Ok(());
+        }
+        self.device.lock().read_blocks(lba as usize, buf).map_err(|_| BlockError::Io)
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        if check_request(self, lba, buf.len())? == 0 {
+            return Ok(());
+        }
+        self.device.lock().write_blocks(lba as usize, buf).map_err(|_| BlockError::Io)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.device.lock().flush().map_err(|_| BlockError::Io)
+    }
+}
+
+/// Find every virtio-blk disk and register it with the block layer.
+pub fn init() {
+    // 0x1001 is the transitional block device, 0x1042 the modern-only one.
+    let devices = network::find_devices(0x1001, 0x1042);
+    if devices.is_empty() {
+        serial_println!("[BLK] No VirtIO block device found.");
+        return;
+    }
+    for (index, (location, _)) in devices.into_iter().enumerate() {
+        let disk = match location {
+            VirtioLocation::Legacy(io_base) => VirtioDisk::probe(LegacyTransport::new(io_base)),
+            VirtioLocation::Modern(layout) => VirtioDisk::probe(ModernTransport::new(layout)),
+        };
+        let Some(disk) = disk else { continue };
+        let name = format!("disk{}", index);
+        serial_println!(
+            "[BLK] {}: {} sectors ({} MiB){}.",
+            name,
+            disk.block_count(),
+            disk.block_count() * SECTOR_SIZE as u64 / (1024 * 1024),
+            if location.is_modern() { "" } else { ", legacy interface" }
+        );
+        match block::register_disk(&name, disk) {
+            Ok(partitions) => { serial_println!("[BLK] {}: {} partition(s).", name, partitions); }
+            Err(e) => { serial_println!("[BLK] {}: not registered: {:?}", name, e); }
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! # VirtIO Console Driver
+//!
+//! Drives the first virtio-serial device through `virtio_drivers`'
+//! `VirtIOConsole` and registers its port 0 as `/dev/hvc0`, the guest
+//! agent's preferred channel.
+//!
+//! ## Device (PCI ID 0x1003 transitional, 0x1043 modern)
+//! - Multiport isn't negotiated, so only port 0 exists: in QEMU, the
+//!   `virtconsole` on the bus (`-device virtio-serial-pci -device
+//!   virtconsole,chardev=...`). Named `virtserialport`s need the control
+//!   queue and aren't reachable.
+//! - Reads poll the receive queue and never block. Writes spin until the
+//!   device has taken the bytes, like virtio-blk requests; no interrupts
+//!   are routed.
+
+use alloc::sync::Arc;
+use spin::Mutex;
+use virtio_drivers::device::console::VirtIOConsole;
+use virtio_drivers::transport::Transport;
+use crate::capability::DEVICE_VIRTIO_CONSOLE;
+use crate::chardev::{self, CharDevice};
+use crate::hal::VirtioHal;
+use crate::network::{self, LegacyTransport, VirtioLocation};
+use crate::serial_println;
+use crate::virtio_modern::ModernTransport;
+
+/// Port 0 of a virtio-serial device.
+struct VirtioConsole<T: Transport> {
+    device: Mutex<VirtIOConsole<VirtioHal, T>>,
+}
+
+impl<T: Transport + Send + 'static> VirtioConsole<T> {
+    /// Initialize the device behind `transport`.
+    fn probe(transport: T) -> Option<Arc<dyn CharDevice>> {
+        match VirtIOConsole::<VirtioHal, T>::new(transport) {
+            Ok(device) => Some(Arc::new(VirtioConsole { device: Mutex::new(device) })),
+            Err(e) => {
+                serial_println!("[VCON] Device init failed: {:?}", e);
+                None
+            }
+        }
+    }
+}
+
+impl<T: Transport + Send> CharDevice for VirtioConsole<T> {
+    fn read(&self, buf: &mut [u8]) -> usize {
+        let mut device = self.device.lock();
+        let mut n = 0;
+        while n < buf.len() {
+            match device.recv(true) {
+                Ok(Some(byte)) => {
+                    buf[n] = byte;
+                    n += 1;
+                }
+                _ => break,
+            }
+        }
+        n
+    }
+
+    fn write(&self, data: &[u8]) -> usize {
+        if data.is_empty() {
+            return 0;
+        }
+        match self.device.lock().send_bytes(data) {
+            Ok(()) => data.len(),
+            Err(_) => 0,
+        }
+    }
+}
+
+/// Find the first virtio-serial device and register it as `/dev/hvc0`.
+/// Call after `chardev::init`.
+pub fn init() {
+    // 0x1003 is the transitional console device, 0x1043 the modern-only one.
+    let Some((location, _)) = network::find_devices(0x1003, 0x1043).into_iter().next() else {
+        serial_println!("[VCON] No VirtIO console found.");
+        return;
+    };
+    let console = match location {
+        VirtioLocation::Legacy(io_base) => VirtioConsole::probe(LegacyTransport::new(io_base)),
+        VirtioLocation::Modern(layout) => VirtioConsole::probe(ModernTransport::new(layout)),
+    };
+    let Some(console) = console else { return };
+    match chardev::register("hvc0", DEVICE_VIRTIO_CONSOLE, console) {
+        Ok(()) => {
+            let legacy = if location.is_modern() { "" } else { " (legacy interface)" };
+            serial_println!("[VCON] Port 0 is /dev/hvc0{}.", legacy);
+        }
+        Err(e) => { serial_println!("[VCON] Not registered: {:?}", e); }
+    }
+}
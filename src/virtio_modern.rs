@@ -0,0 +1,325 @@
+//! # VirtIO Modern PCI Transport
+//!
+//! VirtIO 1.0+ devices don't have the fixed legacy register block in BAR0.
+//! Instead, vendor-specific PCI capabilities (ID 0x09) say in which memory
+//! BAR, and at which offset, each configuration structure lives:
+//!
+//! | `cfg_type` | Structure | Holds                                          |
+//! |------------|-----------|------------------------------------------------|
+//! | 1          | Common    | Features, status, queue selection and setup    |
+//! | 2          | Notify    | Doorbells, `notify_off_multiplier` apart       |
+//! | 3          | ISR       | Interrupt status (read to acknowledge)         |
+//! | 4          | Device    | Device-specific config (e.g. the MAC address)  |
+//!
+//! `probe` walks the capability list and resolves each structure to a
+//! virtual address through the HAL's physical memory mapping; the result,
+//! a `ModernLayout`, is `Copy` so a transport can be rebuilt from it when a
+//! device is reset. `network::find_devices` picks this transport whenever a
+//! device offers it, which is the only option for QEMU devices with
+//! `disable-legacy=on` and for most real hardware.
+//!
+//! Queues interrupt through the INTx line, or through one MSI-X vector
+//! each after `ModernLayout::use_msix_per_queue`.
+//!
+//! Registers are accessed with volatile loads and stores; 64-bit queue
+//! addresses are written as two 32-bit halves, low half first.
+
+use core::mem::size_of;
+use core::ptr;
+use bitflags::Flags;
+use virtio_drivers::transport::{DeviceStatus, DeviceType, Transport};
+use virtio_drivers::{Error, Hal, PhysAddr};
+use zerocopy::{FromBytes, Immutable, IntoBytes};
+use crate::hal::VirtioHal;
+use crate::pci::{PciFunction, CAP_ID_VENDOR};
+use crate::serial_println;
+
+const CAP_COMMON_CFG: u8 = 1;
+const CAP_NOTIFY_CFG: u8 = 2;
+const CAP_ISR_CFG: u8 = 3;
+const CAP_DEVICE_CFG: u8 = 4;
+
+/// `VIRTIO_F_VERSION_1`: the device follows the 1.0 spec. A modern-only
+/// device refuses FEATURES_OK without it.
+const VERSION_1: u64 = 1 << 32;
+/// `VIRTIO_F_INDIRECT_DESC` and `VIRTIO_F_EVENT_IDX`, masked out like the
+/// legacy transport does so the drivers use plain descriptors.
+const UNUSED_RING_FEATURES: u64 = (1 << 28) | (1 << 29);
+
+// Common configuration structure offsets
+const DEVICE_FEATURE_SELECT: usize = 0x00;
+const DEVICE_FEATURE: usize = 0x04;
+const DRIVER_FEATURE_SELECT: usize = 0x08;
+const DRIVER_FEATURE: usize = 0x0c;
+const DEVICE_STATUS: usize = 0x14;
+const CONFIG_GENERATION: usize = 0x15;
+const QUEUE_SELECT: usize = 0x16;
+const QUEUE_SIZE: usize = 0x18;
+const QUEUE_MSIX_VECTOR: usize = 0x1a;
+const QUEUE_ENABLE: usize = 0x1c;
+const QUEUE_NOTIFY_OFF: usize = 0x1e;
+const QUEUE_DESC: usize = 0x20;
+const QUEUE_DRIVER: usize = 0x28;
+const QUEUE_DEVICE: usize = 0x30;
+
+/// `queue_msix_vector` value for "no vector", also the device's answer when
+/// it can't use the one asked for.
+const NO_VECTOR: u16 = 0xffff;
+
+/// Where a modern device's configuration structures are mapped.
+#[derive(Debug, Clone, Copy)]
+pub struct ModernLayout {
+    device_type: DeviceType,
+    common: usize,
+    notify: usize,
+    notify_multiplier: u32,
+    isr: usize,
+    device: usize,
+    device_len: usize,
+    /// Route queue N's interrupts to MSI-X table entry N.
+    msix: bool,
+}
+
+impl ModernLayout {
+    /// From the next device setup on, have queue N interrupt through MSI-X
+    /// table entry N. The caller programs the table (`pci::MsiX`).
+    pub fn use_msix_per_queue(&mut self) {
+        self.msix = true;
+    }
+}
+
+/// Find the modern configuration structures of `function`. Returns `None`
+/// if the device type is unknown, the device doesn't offer the modern
+/// interface, or it puts a structure somewhere other than a memory BAR.
+pub fn probe(function: PciFunction, device_id: u16) -> Option<ModernLayout> {
+    let device_type = device_type(device_id)?;
+    let (mut common, mut notify, mut isr, mut device) = (None, None, None, None);
+    let mut notify_multiplier = 0;
+    for (id, cap) in function.capabilities() {
+        if id != CAP_ID_VENDOR {
+            continue;
+        }
+        let cfg_type = (function.capability_dword(cap, 0) >> 24) as u8;
+        // The first structure of each type is the preferred one.
+        let slot_for_type = match cfg_type {
+            CAP_COMMON_CFG => &mut common,
+            CAP_NOTIFY_CFG => &mut notify,
+            CAP_ISR_CFG => &mut isr,
+            CAP_DEVICE_CFG => &mut device,
+            _ => continue,
+        };
+        if slot_for_type.is_none() {
+            let bar = function.capability_dword(cap, 1) as u8;
+            let offset = function.capability_dword(cap, 2) as u64;
+            let length = function.capability_dword(cap, 3) as usize;
+            let phys = (function.bar(bar)? + offset) as usize;
+            let virt = unsafe { VirtioHal::mmio_phys_to_virt(phys, length) }.as_ptr() as usize;
+            *slot_for_type = Some((virt, length));
+            if cfg_type == CAP_NOTIFY_CFG {
+                notify_multiplier = function.capability_dword(cap, 4);
+            }
+        }
+    }
+
+    let (common, notify, isr) = (common?, notify?, isr?);
+    let (device, device_len) = device.unwrap_or((0, 0));
+    Some(ModernLayout {
+        device_type,
+        common: common.0,
+        notify: notify.0,
+        notify_multiplier,
+        isr: isr.0,
+        device,
+        device_len,
+        msix: false,
+    })
+}
+
+/// The device type behind a transitional (0x1000..) or modern (0x1040 +
+/// type) VirtIO PCI device ID. `None` if it isn't one.
+fn device_type(device_id: u16) -> Option<DeviceType> {
+    match device_id {
+        0x1000 => Some(DeviceType::Network),
+        0x1001 => Some(DeviceType::Block),
+        0x1002 => Some(DeviceType::MemoryBalloon),
+        0x1003 => Some(DeviceType::Console),
+        0x1004 => Some(DeviceType::ScsiHost),
+        0x1005 => Some(DeviceType::EntropySource),
+        0x1009 => Some(DeviceType::_9P),
+        0x1041.. => DeviceType::try_from(u32::from(device_id - 0x1040)).ok(),
+        _ => None,
+    }
+}
+
+/// A VirtIO 1.0+ device reached through its memory-mapped structures.
+pub struct ModernTransport {
+    layout: ModernLayout,
+}
+
+impl ModernTransport {
+    pub fn new(layout: ModernLayout) -> Self {
+        Self { layout }
+    }
+
+    fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { ptr::read_volatile((self.layout.common + offset) as *const T) }
+    }
+
+    fn write<T>(&mut self, offset: usize, value: T) {
+        unsafe { ptr::write_volatile((self.layout.common + offset) as *mut T, value) }
+    }
+
+    fn write_u64(&mut self, offset: usize, value: u64) {
+        self.write::<u32>(offset, value as u32);
+        self.write::<u32>(offset + 4, (value >> 32) as u32);
+    }
+}
+
+impl Transport for ModernTransport {
+    fn device_type(&self) -> DeviceType {
+        self.layout.device_type
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        self.write::<u32>(DEVICE_FEATURE_SELECT, 0);
+        let low = self.read::<u32>(DEVICE_FEATURE) as u64;
+        self.write::<u32>(DEVICE_FEATURE_SELECT, 1);
+        let high = self.read::<u32>(DEVICE_FEATURE) as u64;
+        (high << 32) | low
+    }
+
+    fn write_driver_features(&mut self, driver_features: u64) {
+        self.write::<u32>(DRIVER_FEATURE_SELECT, 0);
+        self.write::<u32>(DRIVER_FEATURE, driver_features as u32);
+        self.write::<u32>(DRIVER_FEATURE_SELECT, 1);
+        self.write::<u32>(DRIVER_FEATURE, (driver_features >> 32) as u32);
+    }
+
+    fn begin_init<F: Flags<Bits = u64> + core::ops::BitAnd<Output = F> + core::fmt::Debug>(
+        &mut self,
+        supported_features: F,
+    ) -> F {
+        self.set_status(DeviceStatus::empty());
+        self.set_status(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER);
+
+        let device_features = self.read_device_features();
+        let mut negotiated_features = F::from_bits_truncate(device_features) & supported_features;
+        negotiated_features.remove(F::from_bits_truncate(UNUSED_RING_FEATURES));
+        // The drivers only know the feature bits of their device; the
+        // transport adds VERSION_1 itself.
+        self.write_driver_features(negotiated_features.bits() | (device_features & VERSION_1));
+
+        self.set_status(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER | DeviceStatus::FEATURES_OK);
+        if !self.get_status().contains(DeviceStatus::FEATURES_OK) {
+            serial_println!("[VIRTIO] Device rejected features {:?}.", negotiated_features);
+        }
+        negotiated_features
+    }
+
+    fn finish_init(&mut self) {
+        self.set_status(
+            DeviceStatus::ACKNOWLEDGE
+                | DeviceStatus::DRIVER
+                | DeviceStatus::FEATURES_OK
+                | DeviceStatus::DRIVER_OK,
+        );
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u32 {
+        self.write::<u16>(QUEUE_SELECT, queue);
+        self.read::<u16>(QUEUE_SIZE) as u32
+    }
+
+    fn notify(&mut self, queue: u16) {
+        self.write::<u16>(QUEUE_SELECT, queue);
+        let offset = self.read::<u16>(QUEUE_NOTIFY_OFF) as usize * self.layout.notify_multiplier as usize;
+        unsafe { ptr::write_volatile((self.layout.notify + offset) as *mut u16, queue) }
+    }
+
+    fn get_status(&self) -> DeviceStatus {
+        DeviceStatus::from_bits_truncate(self.read::<u8>(DEVICE_STATUS).into())
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        self.write::<u8>(DEVICE_STATUS, status.bits() as u8);
+    }
+
+    fn set_guest_page_size(&mut self, _guest_page_size: u32) {
+        // Only the legacy interface has a page size.
+    }
+
+    fn requires_legacy_layout(&self) -> bool {
+        false
+    }
+
+    fn queue_set(
+        &mut self,
+        queue: u16,
+        size: u32,
+        descriptors: PhysAddr,
+        driver_area: PhysAddr,
+        device_area: PhysAddr,
+    ) {
+        self.write::<u16>(QUEUE_SELECT, queue);
+        self.write::<u16>(QUEUE_SIZE, size as u16);
+        self.write_u64(QUEUE_DESC, descriptors as u64);
+        self.write_u64(QUEUE_DRIVER, driver_area as u64);
+        self.write_u64(QUEUE_DEVICE, device_area as u64);
+        if self.layout.msix {
+            self.write::<u16>(QUEUE_MSIX_VECTOR, queue);
+            if self.read::<u16>(QUEUE_MSIX_VECTOR) == NO_VECTOR {
+                serial_println!("[VIRTIO] Queue {} has no MSI-X vector; it won't interrupt.", queue);
+            }
+        }
+        self.write::<u16>(QUEUE_ENABLE, 1);
+    }
+
+    fn queue_unset(&mut self, _queue: u16) {
+        // A no-op for the same reason as `LegacyTransport::queue_unset`; a
+        // modern queue can't be disabled without a reset anyway.
+    }
+
+    fn queue_used(&mut self, queue: u16) -> bool {
+        self.write::<u16>(QUEUE_SELECT, queue);
+        self.read::<u16>(QUEUE_ENABLE) == 1
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        // Reading ISR status resets it
+        let status = unsafe { ptr::read_volatile(self.layout.isr as *const u8) };
+        status & 1 != 0
+    }
+
+    fn read_config_generation(&self) -> u32 {
+        self.read::<u8>(CONFIG_GENERATION) as u32
+    }
+
+    fn read_config_space<T: FromBytes + IntoBytes>(&self, offset: usize) -> Result<T, Error> {
+        let type_size = size_of::<T>();
+        if self.layout.device == 0 {
+            return Err(Error::ConfigSpaceMissing);
+        }
+        let mut buffer = [0u8; 64];
+        if type_size > buffer.len() || offset + type_size > self.layout.device_len {
+            return Err(Error::ConfigSpaceTooSmall);
+        }
+        for (i, byte) in buffer[..type_size].iter_mut().enumerate() {
+            *byte = unsafe { ptr::read_volatile((self.layout.device + offset + i) as *const u8) };
+        }
+        T::read_from_bytes(&buffer[..type_size]).map_err(|_| Error::IoError)
+    }
+
+    fn write_config_space<T: IntoBytes + Immutable>(&mut self, offset: usize, value: T) -> Result<(), Error> {
+        let bytes = value.as_bytes();
+        if self.layout.device == 0 {
+            return Err(Error::ConfigSpaceMissing);
+        }
+        if offset + bytes.len() > self.layout.device_len {
+            return Err(Error::ConfigSpaceTooSmall);
+        }
+        for (i, &byte) in bytes.iter().enumerate() {
+            unsafe { ptr::write_volatile((self.layout.device + offset + i) as *mut u8, byte) };
+        }
+        Ok(())
+    }
+}
@@ -19,9 +19,30 @@
 //!   │  └────────────────────────────────────┘  │
 //!   │  ┌────────────────────────────────────┐  │
 //!   │  │   Host Functions (Syscalls)        │  │
-//!   │  │   - print(msg)                     │  │
-//!   │  │   - yield()                        │  │
-//!   │  │   - ipc_send() / ipc_recv()        │  │
+//!   │  │   - print(ptr, len) / print_char() │  │
+//!   │  │   - yield() (fuel-sliced)          │  │
+//!   │  │   - exit(code) / sleep_ms(ms)      │  │
+//!   │  │   - ipc_send() / ipc_recv[_wait]() │  │
+//!   │  │   - ipc_call() / ipc_reply()       │  │
+//!   │  │   - ipc_wait()                     │  │
+//!   │  │   - service_register() / _lookup() │  │
+//!   │  │   - service_unregister()           │  │
+//!   │  │   - spawn() / child_endpoint()     │  │
+//!   │  │   - supervise()                    │  │
+//!   │  │   - cap_list()                     │  │
+//!   │  │   - random_get()                   │  │
+//!   │  │   - clock_monotonic() / get_time() │  │
+//!   │  │   - get_ticks()                    │  │
+//!   │  │   - fs_read()                      │  │
+//!   │  │   - kv_put() / kv_get() / ...      │  │
+//!   │  │   - shm_create() / shm_read() / ...│  │
+//!   │  │   - dev_open() / dev_read() / ...  │  │
+//!   │  │   - blk_read() / blk_write() / ... │  │
+//!   │  │   - socket_create() / ...          │  │
+//!   │  │   - pubsub_subscribe() / ...       │  │
+//!   │  │   - dht_put() / dht_get() / ...    │  │
+//!   │  │   - channel_open() / ...           │  │
+//!   │  │   - WASI args_get() / environ_get()│  │
 //!   │  └────────────────────────────────────┘  │
 //!   ├──────────────────────────────────────────┤
 //!   │         Capability Check (CSpace)         │
@@ -33,15 +54,52 @@
 //! ## Security Model
 //! Each WASM process runs inside a sandbox with:
 //! - **Memory isolation**: WASM linear memory is separate from kernel memory.
+//! - **Memory limits**: linear memory is capped per process (1 MiB by
+//!   default), so one guest can't drain the heap the network stack shares.
 //! - **No direct hardware access**: All I/O goes through host functions (syscalls).
 //! - **Capability-gated syscalls**: Each host function checks the process's CSpace.
 
+use alloc::collections::VecDeque;
 use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::future::Future;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+use spin::Mutex;
+use wasmi::core::TrapCode;
+use wasmi::errors::ErrorKind;
 use wasmi::{
-    Caller, Engine, Linker, Module, Store,
+    AsContextMut, Caller, Config, Engine, Extern, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder,
+    TypedFunc, TypedResumableCall, TypedResumableInvocation, Val,
 };
-use crate::serial_println;
+use crate::admission::{self, InsufficientResources, ResourceRequest};
+use crate::block::{self, BlockDevice, BlockError};
+use crate::executor::{self, SchedError, Task};
+use crate::capability::{
+    CSpace, Capability, CapabilityType, Permissions, SharedCSpace, DEVICE_BLOCK, DEVICE_CLOCK, DEVICE_NETWORK,
+    DEVICE_NET_ADMIN, DEVICE_RNG,
+};
+use crate::firewall::{self, FirewallError};
+use crate::{config, serial_println, socket_manager};
+use crate::chardev::OpenDevice;
+use crate::ipc::{IpcError, Message, Payload, PendingCall, WaitSet, IPC_MANAGER};
+use crate::icmp::{self, PingError};
+use crate::kv::{self, KvError};
+use crate::p2p_channel::{self, Channel, ChannelError, Listener};
+use crate::p2p_kademlia::NodeId;
+use crate::p2p_pubsub::{self, PubsubError, Subscription};
+use crate::p2p_relay;
+use crate::p2p_rpc;
+use crate::p2p_store::{self, StoreError};
+use crate::services::{self, ServiceError};
+use crate::shm::{self, ShmError};
+use crate::supervisor::{self, ChildSpec, RestartPolicy};
+use crate::process::{self, ProcessError};
+use crate::net_stack::{self, SocketError, SocketKind, NETWORK_STACK};
+use crate::tls::{self, TlsError};
+use crate::vfs::{ReadContext, VfsError, VFS};
 
 // ─── Process State ───────────────────────────────────────────────────────────
 
@@ -52,8 +110,104 @@ use crate::serial_println;
 pub struct ProcessState {
     /// The process's name (for logging).
     pub name: String,
-    /// Collected output from `print` syscalls (captured for verification).
-    pub output: Vec<String>,
+    /// The tail of what the process printed, for the debugger and the
+    /// guest agent.
+    pub output: OutputLog,
+    /// The capabilities this process holds. Host functions check these.
+    /// Shared with the process table so the kernel can inspect or grant.
+    pub cspace: SharedCSpace,
+    /// Devices opened with `env.dev_open`, indexed by descriptor.
+    pub devices: Vec<Option<OpenDevice>>,
+    /// Topics subscribed to with `env.pubsub_subscribe`, indexed by
+    /// descriptor. Dropping the state unsubscribes.
+    pub subscriptions: Vec<Option<Subscription>>,
+    /// DHT requests started with `env.dht_put` / `env.dht_get`, indexed by
+    /// descriptor, until `env.dht_result` collects them.
+    pub dht_requests: Vec<Option<DhtRequest>>,
+    /// Listeners and channels from `env.channel_listen` / `_open` /
+    /// `_accept`, indexed by descriptor. Dropping the state closes them.
+    pub channels: Vec<Option<ChannelDescriptor>>,
+    /// Status the process exited with: 0 if the entry point returned,
+    /// otherwise the code passed to `env.exit`.
+    pub exit_code: i32,
+    /// Set by `env.sleep_ms`: uptime (ms) before which the process must not
+    /// be resumed.
+    pub wake_at: Option<u64>,
+    /// Set by a host call that blocks, e.g. `env.ipc_recv_wait`: what the
+    /// process waits for before it can be resumed.
+    pub wait: Option<Wait>,
+    /// Where the blocked receive delivers its message: `(buf_ptr, buf_len)`.
+    recv_buf: (i32, i32),
+    /// The `env.ipc_call` waiting for its reply. Dropping it closes the
+    /// reply endpoint.
+    call: Option<PendingCall>,
+    /// Where child exits are announced, once the process has spawned one:
+    /// the CSpace slot of the process's READ capability, and the kernel's
+    /// WRITE capability handed to each child's process table entry.
+    pub child_exits: Option<(usize, Capability)>,
+    /// The process's fuel limits.
+    pub quota: FuelQuota,
+    /// Fuel given to the store at the start of the current slice.
+    fuel_granted: u64,
+    /// Most bytes the process's linear memory may grow to.
+    pub memory_limit: usize,
+    /// wasmi's view of `memory_limit`, consulted on every `memory.grow`.
+    limits: StoreLimits,
+    /// Command-line arguments, `args[0]` being the process name.
+    pub args: Vec<String>,
+    /// Environment, as `KEY=VALUE` strings.
+    pub env: Vec<String>,
+}
+
+/// Bytes of printed output kept per process.
+pub const OUTPUT_LOG_LEN: usize = 2048;
+
+/// The last `OUTPUT_LOG_LEN` bytes a process printed. Output still goes to
+/// the serial console as it is printed; this is a copy.
+///
+/// A byte ring rather than a list of lines: once it has grown to the cap it
+/// never allocates again, which matters on a heap that never frees.
+#[derive(Default)]
+pub struct OutputLog {
+    bytes: VecDeque<u8>,
+    /// The last byte printed wasn't a newline.
+    mid_line: bool,
+}
+
+impl OutputLog {
+    /// Append `bytes`, dropping the oldest output beyond the limit.
+    pub fn push(&mut self, bytes: &[u8]) {
+        let bytes = &bytes[bytes.len().saturating_sub(OUTPUT_LOG_LEN)..];
+        let excess = (self.bytes.len() + bytes.len()).saturating_sub(OUTPUT_LOG_LEN);
+        self.bytes.drain(..excess);
+        self.bytes.extend(bytes);
+        if let Some(&last) = bytes.last() {
+            self.mid_line = last != b'\n';
+        }
+    }
+
+    /// The kept output as text.
+    pub fn contents(&self) -> String {
+        let (front, back) = self.bytes.as_slices();
+        let mut text = String::from_utf8_lossy(front).into_owned();
+        text.push_str(&String::from_utf8_lossy(back));
+        text
+    }
+}
+
+/// Settings chosen by whoever starts a process.
+#[derive(Debug, Clone, Default)]
+pub struct ProcessOptions {
+    /// Linear memory cap in bytes, or `None` for `proc.memory_limit`.
+    pub memory_limit: Option<usize>,
+    /// Arguments after `argv[0]`, which is always the process name.
+    pub args: Vec<String>,
+    /// Environment, as `KEY=VALUE` strings.
+    pub env: Vec<String>,
+    /// Fuel limits, or `None` for the defaults of the path that runs the
+    /// process: `FuelQuota::from_config()` in the process table,
+    /// `FuelQuota::unlimited()` in `execute_wasm`.
+    pub fuel: Option<FuelQuota>,
 }
 
 // ─── WASM Runtime ────────────────────────────────────────────────────────────
@@ -69,73 +223,596 @@ pub enum WasmError {
     EntryPointNotFound,
     /// Runtime error during execution (trap, out-of-bounds, etc.).
     ExecutionFailed,
+    /// Admission control rejected the spawn; carries current utilization.
+    InsufficientResources(InsufficientResources),
+    /// The kernel is shutting down and accepts no new processes.
+    ShuttingDown,
+    /// The process used up its lifetime fuel budget (`FuelQuota::total`).
+    QuotaExceeded,
+    /// The module's linear memory would outgrow the process's memory limit.
+    MemoryLimitExceeded,
+}
+
+// ─── Cooperative Scheduling ──────────────────────────────────────────────────
+//
+// Processes are scheduled cooperatively, not preempted. Every store runs
+// with fuel metering on, and a process is resumed in slices of
+// `FuelQuota::slice` fuel: once a slice's fuel is spent, the next *yield
+// point* (a host call returning no value — `print_char`, `print_newline`,
+// `yield`, `sleep_ms`) suspends the guest with a `Preempted` host error,
+// which wasmi turns into a resumable call. The executor task driving the
+// process then yields and resumes it on its next poll. A guest that makes
+// no such call keeps the CPU until it returns.
+//
+// wasmi 0.40 cannot resume a call that ran out of fuel, so fuel can't be
+// used to take the CPU away. It can only kill: with a kill limit
+// (`FuelQuota::uninterrupted`), a guest that burns that much fuel without
+// reaching a yield point traps with `OutOfFuel` and is killed. Processes
+// in the process table get `proc.max_uninterrupted_fuel` as their limit,
+// so a runaway loop can't hang the executor; `execute_wasm` sets none
+// unless the caller asks for one. Long computations should call
+// `env.yield()` now and then.
+//
+// A process may also have a lifetime budget (`FuelQuota::total`). A slice
+// is never granted more fuel than the budget has left, so a process that
+// exhausts it stops there, with `WasmError::QuotaExceeded`.
+//
+// Defaults come from the `proc.*` config keys and apply to processes
+// spawned after a change; the process table can adjust a running
+// process's budget.
+
+/// Default fuel a process may use per slice before it is suspended at its
+/// next yield point.
+pub const SLICE_FUEL: u64 = 100_000;
+/// Default fuel available between two yield points before a scheduled
+/// guest is killed.
+pub const MAX_UNINTERRUPTED_FUEL: u64 = 50_000_000;
+
+/// Default cap on a process's linear memory.
+pub const DEFAULT_MEMORY_LIMIT: usize = 1024 * 1024;
+
+/// Register the `proc.*` fuel and memory keys. Call once at boot.
+pub fn register_tunables() {
+    config::register("proc.slice_fuel", "Fuel per scheduling slice", SLICE_FUEL, 1_000, 100_000_000, None);
+    config::register(
+        "proc.max_uninterrupted_fuel",
+        "Fuel between yield points before a scheduled guest is killed",
+        MAX_UNINTERRUPTED_FUEL,
+        100_000,
+        10_000_000_000,
+        None,
+    );
+    config::register("proc.fuel_budget", "Lifetime fuel per process (0 = unlimited)", 0, 0, u64::MAX, None);
+    config::register(
+        "proc.memory_limit",
+        "Default linear memory cap per process, in bytes",
+        DEFAULT_MEMORY_LIMIT as u64,
+        64 * 1024,
+        64 * 1024 * 1024,
+        None,
+    );
+}
+
+/// The default linear memory cap, from `proc.memory_limit`.
+pub fn default_memory_limit() -> usize {
+    config::get_or("proc.memory_limit", DEFAULT_MEMORY_LIMIT as u64) as usize
+}
+
+/// Fuel limits of one process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FuelQuota {
+    /// Fuel per slice; the process is suspended at the first yield point
+    /// after using it.
+    pub slice: u64,
+    /// Fuel between yield points before the guest is killed, or `None` to
+    /// let it run until it yields.
+    pub uninterrupted: Option<u64>,
+    /// Lifetime budget, or `None` for unlimited.
+    pub total: Option<u64>,
+}
+
+impl FuelQuota {
+    /// The current defaults from the `proc.*` config keys.
+    pub fn from_config() -> Self {
+        let slice = config::get_or("proc.slice_fuel", SLICE_FUEL);
+        FuelQuota {
+            slice,
+            uninterrupted: Some(config::get_or("proc.max_uninterrupted_fuel", MAX_UNINTERRUPTED_FUEL).max(slice)),
+            total: match config::get_or("proc.fuel_budget", 0) {
+                0 => None,
+                total => Some(total),
+            },
+        }
+    }
+
+    /// The configured slice size with no kill limit and no budget.
+    pub fn unlimited() -> Self {
+        FuelQuota {
+            slice: config::get_or("proc.slice_fuel", SLICE_FUEL),
+            uninterrupted: None,
+            total: None,
+        }
+    }
+
+    /// Fuel to hand the store for one uninterrupted run.
+    fn grant(&self) -> u64 {
+        self.uninterrupted.unwrap_or(u64::MAX)
+    }
+}
+
+/// Host error used to suspend a guest at a yield point.
+#[derive(Debug)]
+struct Preempted;
+
+impl core::fmt::Display for Preempted {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("preempted")
+    }
+}
+
+impl wasmi::core::HostError for Preempted {}
+
+/// Suspend the guest if its slice is used up: a yield point. Call at the
+/// end of host functions that return no value.
+fn yield_point(caller: &Caller<'_, ProcessState>) -> Result<(), wasmi::Error> {
+    let state = caller.data();
+    let remaining = caller.get_fuel().unwrap_or(state.fuel_granted);
+    if state.fuel_granted.saturating_sub(remaining) >= state.quota.slice {
+        Err(wasmi::Error::host(Preempted))
+    } else {
+        Ok(())
+    }
+}
+
+/// Where a process is in its entry-point call.
+enum Execution {
+    NotStarted(TypedFunc<(), ()>),
+    Suspended(TypedResumableInvocation<()>),
+    Finished,
+}
+
+/// What a process blocked in a host call is waiting for.
+#[derive(Debug)]
+pub enum Wait {
+    /// A message on the endpoint `cap` names, until uptime reaches
+    /// `deadline` (if any). Report it with `WasmProcess::receive_done`.
+    Message { cap: Capability, deadline: Option<u64> },
+    /// A message on any endpoint in `set` (badged with its CSpace slot),
+    /// until `deadline`. Report it with `WasmProcess::wait_done`.
+    Any { set: WaitSet, deadline: Option<u64> },
+}
+
+/// Outcome of running one slice.
+#[derive(Debug)]
+pub enum SliceResult {
+    /// The slice ran out and the guest reached a yield point; call
+    /// `run_slice` again to continue.
+    Preempted,
+    /// The guest called `env.sleep_ms`; call `run_slice` again once uptime
+    /// reaches this many milliseconds.
+    Sleeping(u64),
+    /// The guest blocked in a host call; wait, report the outcome with the
+    /// method the `Wait` names, then call `run_slice` again.
+    Blocked(Wait),
+    /// The entry point returned (code 0) or the guest called `env.exit`.
+    Exited(i32),
+    /// The guest trapped (including running out of fuel).
+    Failed(WasmError),
+}
+
+/// A loaded WASM process that owns its store and instance and runs in
+/// fuel-bounded slices.
+pub struct WasmProcess {
+    store: Store<ProcessState>,
+    execution: Execution,
+    /// The module's exported linear memory, if it has one.
+    memory: Option<Memory>,
+    /// Total fuel consumed over the process's lifetime.
+    fuel_used: u64,
+    /// Return value of the host call the guest blocked in, once known.
+    resume_with: Option<Val>,
+}
+
+impl WasmProcess {
+    /// Admit, compile and instantiate a module, ready to run `entry_point`.
+    ///
+    /// The module's linear memory is capped at `options.memory_limit` bytes
+    /// (`proc.memory_limit` if `None`), and admission control must find
+    /// heap for the whole cap before anything is compiled. A module that
+    /// declares more initial memory fails to load; a `memory.grow` past the
+    /// cap traps. The
+    /// arguments and environment are readable through the WASI
+    /// `args_get` / `environ_get` calls.
+    ///
+    /// # Security
+    /// The WASM module can only interact with the kernel through explicitly
+    /// provided host functions. It cannot access kernel memory, hardware,
+    /// or other processes directly.
+    pub fn new(
+        name: &str,
+        wasm_bytes: &[u8],
+        entry_point: &str,
+        cspace: SharedCSpace,
+        options: ProcessOptions,
+    ) -> Result<Self, WasmError> {
+        serial_println!("[WASM] Loading process '{}'...", name);
+
+        // Step 0: Refuse up front if the node is shutting down or can't
+        // afford another process.
+        if crate::shutdown::in_progress() {
+            return Err(WasmError::ShuttingDown);
+        }
+        let memory_limit = options.memory_limit.unwrap_or_else(default_memory_limit);
+        admission::admit(&ResourceRequest::for_wasm(wasm_bytes.len(), memory_limit))
+            .map_err(WasmError::InsufficientResources)?;
+
+        // Steps 1–2: Get the compiled module for these bytes: from the
+        // module cache, or by validating, type-checking and compiling them
+        // on the shared engine (the interpreter core).
+        let engine: &Engine = &ENGINE;
+        let module = load_module(wasm_bytes)?;
+
+        // Step 3: Create a Store with our process state.
+        let quota = options.fuel.unwrap_or_else(FuelQuota::from_config);
+        let mut args = Vec::with_capacity(options.args.len() + 1);
+        args.push(String::from(name));
+        args.extend(options.args);
+        // The Store owns the WASM instance's memory and globals. Growth
+        // beyond the limit traps rather than returning -1, so a guest can't
+        // mistake the cap for a transient failure and retry.
+        let mut store = Store::new(
+            engine,
+            ProcessState {
+                name: String::from(name),
+                output: OutputLog::default(),
+                cspace,
+                devices: Vec::new(),
+                subscriptions: Vec::new(),
+                dht_requests: Vec::new(),
+                channels: Vec::new(),
+                exit_code: 0,
+                wake_at: None,
+                wait: None,
+                recv_buf: (0, 0),
+                call: None,
+                child_exits: None,
+                quota,
+                fuel_granted: quota.grant(),
+                memory_limit,
+                limits: StoreLimitsBuilder::new().memory_size(memory_limit).trap_on_grow_failure(true).build(),
+                args,
+                env: options.env,
+            },
+        );
+        store.limiter(|state| &mut state.limits);
+        store.set_fuel(quota.grant()).map_err(|_| WasmError::InstantiationFailed)?;
+
+        // Step 4: Set up the Linker with host functions (syscalls).
+        // These are the ONLY ways the WASM module can interact with the kernel.
+        let mut linker = <Linker<ProcessState>>::new(engine);
+        register_host_functions(&mut linker);
+
+        // Step 5: Instantiate the module — resolves imports against our host functions.
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .map_err(|e| match e.kind() {
+                ErrorKind::Memory(_) => WasmError::MemoryLimitExceeded,
+                _ => WasmError::InstantiationFailed,
+            })?
+            .start(&mut store)
+            .map_err(|_| WasmError::InstantiationFailed)?;
+        serial_println!("[WASM] Module instantiated.");
+
+        // Step 6: Find the entry point function.
+        let func = instance
+            .get_typed_func::<(), ()>(&store, entry_point)
+            .map_err(|_| WasmError::EntryPointNotFound)?;
+
+        let memory = instance.get_memory(&store, "memory");
+        Ok(WasmProcess { store, execution: Execution::NotStarted(func), memory, fuel_used: 0, resume_with: None })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.store.data().name
+    }
+
+    /// The process's capabilities.
+    pub fn cspace(&self) -> &SharedCSpace {
+        &self.store.data().cspace
+    }
+
+    /// Total fuel consumed so far.
+    pub fn fuel_used(&self) -> u64 {
+        self.fuel_used
+    }
+
+    /// Most bytes the process's linear memory may grow to.
+    pub fn memory_limit(&self) -> usize {
+        self.store.data().memory_limit
+    }
+
+    /// Current size of the linear memory in bytes (0 without one).
+    pub fn memory_size(&self) -> usize {
+        self.memory.map_or(0, |memory| memory.data(&self.store).len())
+    }
+
+    /// Run `f` on `len` bytes of linear memory starting at `addr`, or return
+    /// `None` if the range lies outside it.
+    pub fn with_memory<R>(&self, addr: usize, len: usize, f: impl FnOnce(&[u8]) -> R) -> Option<R> {
+        let data = self.memory?.data(&self.store);
+        data.get(addr..addr.checked_add(len)?).map(f)
+    }
+
+    /// What the process has printed recently.
+    pub fn output(&self) -> &OutputLog {
+        &self.store.data().output
+    }
+
+    /// The process's fuel limits.
+    pub fn quota(&self) -> FuelQuota {
+        self.store.data().quota
+    }
+
+    /// Change the lifetime fuel budget (`None` for unlimited). Takes effect
+    /// from the next slice.
+    pub fn set_fuel_budget(&mut self, total: Option<u64>) {
+        self.store.data_mut().quota.total = total;
+    }
+
+    /// Run the process until it yields, blocks, exits or traps.
+    pub fn run_slice(&mut self) -> SliceResult {
+        if let Execution::Finished = self.execution {
+            return SliceResult::Exited(self.store.data().exit_code);
+        }
+        let quota = self.store.data().quota;
+        let budget_left = match quota.total {
+            Some(total) if self.fuel_used >= total => return SliceResult::Failed(WasmError::QuotaExceeded),
+            Some(total) => total - self.fuel_used,
+            None => u64::MAX,
+        };
+        let grant = quota.grant().min(budget_left);
+        if self.store.set_fuel(grant).is_err() {
+            return SliceResult::Failed(WasmError::ExecutionFailed);
+        }
+        self.store.data_mut().fuel_granted = grant;
+        let result = match core::mem::replace(&mut self.execution, Execution::Finished) {
+            Execution::NotStarted(func) => {
+                serial_println!("[WASM] Starting '{}'...", self.store.data().name);
+                func.call_resumable(&mut self.store, ())
+            }
+            Execution::Suspended(invocation) => {
+                let results = self.resume_with.take();
+                invocation.resume(&mut self.store, results.as_slice())
+            }
+            Execution::Finished => return SliceResult::Exited(self.store.data().exit_code),
+        };
+        let remaining = self.store.get_fuel().unwrap_or(0);
+        self.fuel_used += grant - remaining;
+
+        // `env.exit` unwinds the guest with wasmi's i32 exit status, which
+        // arrives like any other host error: as a resumable call.
+        let exit_status = match &result {
+            Ok(TypedResumableCall::Resumable(invocation)) => invocation.host_error().i32_exit_status(),
+            Err(e) => e.i32_exit_status(),
+            Ok(TypedResumableCall::Finished(())) => Some(0),
+        };
+        if let Some(code) = exit_status {
+            self.store.data_mut().exit_code = code;
+            return SliceResult::Exited(code);
+        }
+        match result {
+            Ok(TypedResumableCall::Resumable(invocation)) => {
+                self.execution = Execution::Suspended(invocation);
+                let state = self.store.data_mut();
+                match (state.wake_at.take(), state.wait.take()) {
+                    (Some(deadline), _) => SliceResult::Sleeping(deadline),
+                    (None, Some(wait)) => SliceResult::Blocked(wait),
+                    (None, None) => SliceResult::Preempted,
+                }
+            }
+            // Running dry because of the budget (not the kill limit) is a
+            // quota stop, not a crash.
+            _ if grant < quota.grant() && remaining == 0 => SliceResult::Failed(WasmError::QuotaExceeded),
+            Err(e) if e.as_trap_code() == Some(TrapCode::GrowthOperationLimited) => {
+                SliceResult::Failed(WasmError::MemoryLimitExceeded)
+            }
+            _ => SliceResult::Failed(WasmError::ExecutionFailed),
+        }
+    }
+
+    /// Finish a `Wait::Message`: deliver the message to the guest (or the
+    /// error, e.g. `IpcError::TimedOut`) as the blocked call's result. For
+    /// `env.ipc_call` this also closes the reply endpoint.
+    pub fn receive_done(&mut self, result: Result<Message, IpcError>) {
+        let (buf_ptr, buf_len) = self.store.data().recv_buf;
+        let code = match result {
+            Ok(msg) => deliver_message(&mut self.store, self.memory, msg, buf_ptr, buf_len),
+            Err(e) => recv_error(e),
+        };
+        self.resume_with = Some(Val::I64(code));
+        self.store.data_mut().call = None;
+    }
+
+    /// Finish a `Wait::Any`: the blocked `env.ipc_wait` returns the slot
+    /// of the ready endpoint, or the error code.
+    pub fn wait_done(&mut self, result: Result<u64, IpcError>) {
+        let code = match result {
+            Ok(slot) => slot as i32,
+            Err(e) => recv_error(e) as i32,
+        };
+        self.resume_with = Some(Val::I32(code));
+    }
+
+    /// Give up the process and return its final state.
+    pub fn into_state(self) -> ProcessState {
+        self.store.into_data()
+    }
+}
+
+// ─── Module Cache ────────────────────────────────────────────────────────────
+//
+// Compiling a module (validation, type-checking, translation to wasmi's
+// internal bytecode) is the most expensive part of a spawn, and the same
+// bytes are spawned again and again: every supervisor restart, every
+// `env.spawn` of a helper. Compiled modules are therefore cached by the
+// SHA-256 of their bytes. A `Module` belongs to the engine that compiled
+// it, so all processes share one engine; each still gets its own store,
+// fuel and memory.
+//
+// The cache holds `MODULE_CACHE_SIZE` modules and evicts the least
+// recently used.
+
+/// Most compiled modules kept in the cache.
+const MODULE_CACHE_SIZE: usize = 16;
+
+lazy_static! {
+    /// The engine every process runs on, with fuel metering for scheduling.
+    static ref ENGINE: Engine = {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        Engine::new(&config)
+    };
+    static ref MODULE_CACHE: Mutex<ModuleCache> = Mutex::new(ModuleCache { entries: Vec::new(), clock: 0 });
+}
+
+static CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+static CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+
+struct ModuleCache {
+    /// (content hash, module, `clock` at last use)
+    entries: Vec<([u8; 32], Arc<Module>, u64)>,
+    clock: u64,
+}
+
+/// Module cache counters.
+#[derive(Debug, Clone, Copy)]
+pub struct ModuleCacheStats {
+    /// Spawns that reused a compiled module.
+    pub hits: u64,
+    /// Spawns that had to compile (including modules that failed to).
+    pub misses: u64,
+    /// Modules currently cached.
+    pub entries: usize,
+}
+
+pub fn module_cache_stats() -> ModuleCacheStats {
+    ModuleCacheStats {
+        hits: CACHE_HITS.load(Ordering::Relaxed),
+        misses: CACHE_MISSES.load(Ordering::Relaxed),
+        entries: MODULE_CACHE.lock().entries.len(),
+    }
+}
+
+/// The compiled module for `wasm_bytes`, from the cache if possible.
+fn load_module(wasm_bytes: &[u8]) -> Result<Arc<Module>, WasmError> {
+    let hash: [u8; 32] = Sha256::digest(wasm_bytes).into();
+    {
+        let mut cache = MODULE_CACHE.lock();
+        cache.clock += 1;
+        let now = cache.clock;
+        if let Some(entry) = cache.entries.iter_mut().find(|(h, _, _)| *h == hash) {
+            entry.2 = now;
+            CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+            serial_println!("[WASM] Module cache hit.");
+            return Ok(entry.1.clone());
+        }
+    }
+    CACHE_MISSES.fetch_add(1, Ordering::Relaxed);
+
+    // Compile without holding the cache lock.
+    let module = Arc::new(Module::new(&ENGINE, wasm_bytes).map_err(|_| WasmError::CompilationFailed)?);
+    serial_println!("[WASM] Module compiled successfully.");
+
+    let mut cache = MODULE_CACHE.lock();
+    if !cache.entries.iter().any(|(h, _, _)| *h == hash) {
+        if cache.entries.len() >= MODULE_CACHE_SIZE {
+            if let Some(lru) = cache.entries.iter().enumerate().min_by_key(|(_, e)| e.2).map(|(i, _)| i) {
+                cache.entries.swap_remove(lru);
+            }
+        }
+        let now = cache.clock;
+        cache.entries.push((hash, module.clone(), now));
+    }
+    Ok(module)
 }
 
-/// Load and execute a WASM binary inside a sandboxed process.
+/// Load and execute a WASM binary to completion on the calling thread.
+///
+/// For boot, before the executor runs: the guest's sleeps halt the CPU
+/// and nothing else is scheduled until it exits, so calling this from an
+/// executor task panics. Tasks start processes with `process::spawn` and
+/// await them instead. Unless `options.fuel` says otherwise the guest
+/// gets `FuelQuota::unlimited()`, so a long computation isn't killed for
+/// never yielding.
 ///
 /// # Arguments
 /// * `name` - Human-readable name for this process (for logging).
 /// * `wasm_bytes` - The raw `.wasm` binary bytecode.
 /// * `entry_point` - Name of the exported function to call (e.g., "main").
+/// * `cspace` - The capabilities granted to the process.
+/// * `options` - Memory limit, fuel limits, arguments and environment.
 ///
 /// # Returns
 /// The `ProcessState` after execution, containing any captured output.
-///
-/// # Security
-/// The WASM module can only interact with the kernel through explicitly
-/// provided host functions. It cannot access kernel memory, hardware,
-/// or other processes directly.
 pub fn execute_wasm(
     name: &str,
     wasm_bytes: &[u8],
     entry_point: &str,
+    cspace: CSpace,
+    options: ProcessOptions,
 ) -> Result<ProcessState, WasmError> {
-    serial_println!("[WASM] Loading process '{}'...", name);
-
-    // Step 1: Create the WASM engine (the interpreter core).
-    let engine = Engine::default();
-
-    // Step 2: Compile the WASM bytecode into an executable module.
-    // This validates the bytecode structure and type-checks all functions.
-    let module = Module::new(&engine, wasm_bytes)
-        .map_err(|_| WasmError::CompilationFailed)?;
-    serial_println!("[WASM] Module compiled successfully.");
-
-    // Step 3: Create a Store with our process state.
-    // The Store owns the WASM instance's memory and globals.
-    let mut store = Store::new(
-        &engine,
-        ProcessState {
-            name: String::from(name),
-            output: Vec::new(),
-        },
+    assert!(
+        crate::executor::current_context().is_none(),
+        "execute_wasm would stall the executor; use process::spawn"
     );
-
-    // Step 4: Set up the Linker with host functions (syscalls).
-    // These are the ONLY ways the WASM module can interact with the kernel.
-    let mut linker = <Linker<ProcessState>>::new(&engine);
-    register_host_functions(&mut linker);
-
-    // Step 5: Instantiate the module — resolves imports against our host functions.
-    let instance = linker
-        .instantiate(&mut store, &module)
-        .map_err(|_| WasmError::InstantiationFailed)?
-        .start(&mut store)
-        .map_err(|_| WasmError::InstantiationFailed)?;
-    serial_println!("[WASM] Module instantiated.");
-
-    // Step 6: Find and call the entry point function.
-    let func = instance
-        .get_typed_func::<(), ()>(&store, entry_point)
-        .map_err(|_| WasmError::EntryPointNotFound)?;
-
-    serial_println!("[WASM] Calling '{}'...", entry_point);
-    func.call(&mut store, ())
-        .map_err(|_| WasmError::ExecutionFailed)?;
-
-    serial_println!("[WASM] Process '{}' completed successfully.", name);
-
-    Ok(store.into_data())
+    let cspace = Arc::new(Mutex::new(cspace));
+    let options = ProcessOptions { fuel: Some(options.fuel.unwrap_or_else(FuelQuota::unlimited)), ..options };
+    let mut process = WasmProcess::new(name, wasm_bytes, entry_point, cspace, options)?;
+    loop {
+        match process.run_slice() {
+            SliceResult::Preempted => continue,
+            SliceResult::Sleeping(deadline) => {
+                // Nothing else runs on this path, so just wait for the clock.
+                while crate::interrupts::uptime_ms() < deadline {
+                    x86_64::instructions::hlt();
+                }
+            }
+            SliceResult::Blocked(wait) => {
+                // Nobody else runs to send one, so only the clock can end
+                // the wait.
+                let (Wait::Message { deadline, .. } | Wait::Any { deadline, .. }) = wait;
+                let Some(deadline) = deadline else {
+                    serial_println!("[WASM] Process '{}' waits forever with nothing else running.", name);
+                    net_stack::close_process_sockets(&process.store.data().cspace.lock());
+                    return Err(WasmError::ExecutionFailed);
+                };
+                while crate::interrupts::uptime_ms() < deadline {
+                    x86_64::instructions::hlt();
+                }
+                match wait {
+                    Wait::Message { cap, .. } => {
+                        let result = IPC_MANAGER.lock().receive_with_cap(&cap);
+                        process.receive_done(result.map_err(|e| match e {
+                            IpcError::QueueEmpty => IpcError::TimedOut,
+                            e => e,
+                        }));
+                    }
+                    Wait::Any { mut set, .. } => {
+                        let ready = set.poll(&IPC_MANAGER.lock());
+                        process.wait_done(ready.ok_or(IpcError::TimedOut));
+                    }
+                }
+            }
+            SliceResult::Exited(_) => break,
+            SliceResult::Failed(e) => {
+                net_stack::close_process_sockets(&process.store.data().cspace.lock());
+                return Err(e);
+            }
+        }
+    }
+    net_stack::close_process_sockets(&process.store.data().cspace.lock());
+    serial_println!("[WASM] Process '{}' exited with code {}.", name, process.store.data().exit_code);
+    Ok(process.into_state())
 }
 
 // ─── Host Functions (Syscalls) ───────────────────────────────────────────────
@@ -148,37 +825,99 @@ fn register_host_functions(linker: &mut Linker<ProcessState>) {
     // syscall: env.print_char(char_code: i32)
     // Prints a single character to the serial console.
     // This is the most basic output primitive — WASM modules use this
-    // to build up strings character by character.
+    // to build up strings character by character. Like all guest output,
+    // each console line is tagged `[name]` and kept in the output log.
     linker
         .func_wrap(
             "env",
             "print_char",
-            |_caller: Caller<'_, ProcessState>, char_code: i32| {
-                // Write a single character without newline.
-                // We use serial_println's underlying _print directly.
-                use core::fmt::Write;
-                use x86_64::instructions::interrupts;
-                interrupts::without_interrupts(|| {
-                    let c = char::from(char_code as u8);
-                    let mut serial = crate::serial::SERIAL1.lock();
-                    write!(serial, "{}", c).expect("serial write failed");
-                });
+            |mut caller: Caller<'_, ProcessState>, char_code: i32| -> Result<(), wasmi::Error> {
+                emit_output(caller.data_mut(), &[char_code as u8]);
+                yield_point(&caller)
             },
         )
         .expect("Failed to register print_char");
 
+    // syscall: env.print(ptr: i32, len: i32) -> i32
+    // Prints `len` bytes of guest memory starting at `ptr` to the serial
+    // console in one go. Returns `len`, or ERR_MEMORY_FAULT (printing
+    // nothing) if any part of the range lies outside linear memory.
+    linker
+        .func_wrap(
+            "env",
+            "print",
+            |mut caller: Caller<'_, ProcessState>, ptr: i32, len: i32| -> i32 {
+                let Some(memory) = caller.get_export("memory").and_then(Extern::into_memory) else {
+                    return ERR_MEMORY_FAULT;
+                };
+                let (data, state) = memory.data_and_store_mut(&mut caller);
+                let start = ptr as u32 as usize;
+                let Some(bytes) = usize::try_from(len).ok().and_then(|len| data.get(start..start.checked_add(len)?))
+                else {
+                    return ERR_MEMORY_FAULT;
+                };
+                emit_output(state, bytes);
+                len
+            },
+        )
+        .expect("Failed to register print");
+
     // syscall: env.print_newline()
     // Prints a newline to the serial console.
     linker
         .func_wrap(
             "env",
             "print_newline",
-            |_caller: Caller<'_, ProcessState>| {
-                serial_println!();
+            |mut caller: Caller<'_, ProcessState>| -> Result<(), wasmi::Error> {
+                emit_output(caller.data_mut(), b"\n");
+                yield_point(&caller)
             },
         )
         .expect("Failed to register print_newline");
 
+    // syscall: env.yield()
+    // Gives up the rest of the slice. Compute-heavy guests should call this
+    // periodically (see FuelQuota::uninterrupted); a yield point.
+    linker
+        .func_wrap(
+            "env",
+            "yield",
+            |_caller: Caller<'_, ProcessState>| -> Result<(), wasmi::Error> {
+                Err(wasmi::Error::host(Preempted))
+            },
+        )
+        .expect("Failed to register yield");
+
+    // syscall: env.exit(code: i32)
+    // Terminates the calling process immediately with `code` as its exit
+    // status (recorded in the process table). Never returns to the guest.
+    linker
+        .func_wrap(
+            "env",
+            "exit",
+            |_caller: Caller<'_, ProcessState>, code: i32| -> Result<(), wasmi::Error> {
+                Err(wasmi::Error::i32_exit(code))
+            },
+        )
+        .expect("Failed to register exit");
+
+    // syscall: env.sleep_ms(ms: i32)
+    // Suspends the process for at least `ms` milliseconds (10 ms timer
+    // resolution) without consuming CPU; other tasks run meanwhile.
+    // `ms <= 0` just yields.
+    linker
+        .func_wrap(
+            "env",
+            "sleep_ms",
+            |mut caller: Caller<'_, ProcessState>, ms: i32| -> Result<(), wasmi::Error> {
+                if ms > 0 {
+                    caller.data_mut().wake_at = Some(crate::interrupts::uptime_ms() + ms as u64);
+                }
+                Err(wasmi::Error::host(Preempted))
+            },
+        )
+        .expect("Failed to register sleep_ms");
+
     // syscall: env.get_os_version() -> i32
     // Returns the OS version as a single integer (major * 100 + minor).
     // Demonstrates a "query" syscall that returns data to the WASM module.
@@ -191,6 +930,2587 @@ fn register_host_functions(linker: &mut Linker<ProcessState>) {
             },
         )
         .expect("Failed to register get_os_version");
+
+    // syscall: env.cap_list(buf_ptr: i32, buf_len: i32) -> i32
+    // Describes the caller's own CSpace so service managers can verify
+    // what they were granted. Each entry is CAP_ENTRY_SIZE bytes:
+    //   [slot: u32][type: u32][permissions: u32][reserved: u32][resource_id: u64]
+    // (little-endian). As many entries as fit are written; the return value
+    // is the total number of capabilities, or a negative error code.
+    // Only processes holding a Thread capability with READ may call this.
+    linker
+        .func_wrap(
+            "env",
+            "cap_list",
+            |mut caller: Caller<'_, ProcessState>, buf_ptr: i32, buf_len: i32| -> i32 {
+                let shared = caller.data().cspace.clone();
+                let cspace = shared.lock();
+                if cspace.find(CapabilityType::Thread, Permissions::READ).is_none() {
+                    return ERR_PERMISSION_DENIED;
+                }
+
+                let total = cspace.len() as i32;
+                let fits = (buf_len.max(0) as usize) / CAP_ENTRY_SIZE;
+                let mut out = Vec::with_capacity(fits.min(cspace.len()) * CAP_ENTRY_SIZE);
+                for (slot, cap) in cspace.iter().take(fits) {
+                    out.extend_from_slice(&(slot as u32).to_le_bytes());
+                    out.extend_from_slice(&cap.cap_type.code().to_le_bytes());
+                    out.extend_from_slice(&cap.permissions.bits().to_le_bytes());
+                    out.extend_from_slice(&0u32.to_le_bytes());
+                    out.extend_from_slice(&cap.resource_id.to_le_bytes());
+                }
+                drop(cspace);
+
+                match write_guest_memory(&mut caller, buf_ptr, &out) {
+                    Ok(()) => total,
+                    Err(()) => ERR_MEMORY_FAULT,
+                }
+            },
+        )
+        .expect("Failed to register cap_list");
+
+    // syscall: env.random_get(buf_ptr: i32, buf_len: i32) -> i32
+    // Fills guest memory with bytes from the kernel's SHA-256 DRBG (see
+    // `random`), strong enough for keys and nonces. Requires a Device
+    // capability on DEVICE_RNG with READ. Returns 0 or a negative error code.
+    linker
+        .func_wrap(
+            "env",
+            "random_get",
+            |mut caller: Caller<'_, ProcessState>, buf_ptr: i32, buf_len: i32| -> i32 {
+                if !caller.data().cspace.lock().holds(CapabilityType::Device, DEVICE_RNG, Permissions::READ) {
+                    return ERR_PERMISSION_DENIED;
+                }
+
+                let mut chunk = [0u8; RANDOM_CHUNK];
+                let mut offset = 0usize;
+                let len = buf_len.max(0) as usize;
+                while offset < len {
+                    let n = (len - offset).min(RANDOM_CHUNK);
+                    crate::random::fill(&mut chunk[..n]);
+                    let ptr = (buf_ptr as u32 as usize + offset) as i32;
+                    if write_guest_memory(&mut caller, ptr, &chunk[..n]).is_err() {
+                        return ERR_MEMORY_FAULT;
+                    }
+                    offset += n;
+                }
+                0
+            },
+        )
+        .expect("Failed to register random_get");
+
+    // syscall: env.clock_monotonic() -> i64
+    // Milliseconds since boot from the kernel clock. Requires a Device
+    // capability on DEVICE_CLOCK with READ; returns -1 without it.
+    linker
+        .func_wrap(
+            "env",
+            "clock_monotonic",
+            |caller: Caller<'_, ProcessState>| -> i64 {
+                if !caller.data().cspace.lock().holds(CapabilityType::Device, DEVICE_CLOCK, Permissions::READ) {
+                    return ERR_PERMISSION_DENIED as i64;
+                }
+                crate::interrupts::uptime_ms() as i64
+            },
+        )
+        .expect("Failed to register clock_monotonic");
+
+    // syscall: env.get_time() -> i64
+    // Monotonic milliseconds since boot, for timeouts and rate limiting.
+    // Same value and capability check as `clock_monotonic`.
+    linker
+        .func_wrap(
+            "env",
+            "get_time",
+            |caller: Caller<'_, ProcessState>| -> i64 {
+                if !caller.data().cspace.lock().holds(CapabilityType::Device, DEVICE_CLOCK, Permissions::READ) {
+                    return ERR_PERMISSION_DENIED as i64;
+                }
+                crate::interrupts::uptime_ms() as i64
+            },
+        )
+        .expect("Failed to register get_time");
+
+    // syscall: env.get_ticks() -> i64
+    // Raw timer interrupts since boot. Finer-grained than `get_time`, but
+    // the tick rate depends on the platform, so use it only to order or
+    // difference events. Requires the same clock capability.
+    linker
+        .func_wrap(
+            "env",
+            "get_ticks",
+            |caller: Caller<'_, ProcessState>| -> i64 {
+                if !caller.data().cspace.lock().holds(CapabilityType::Device, DEVICE_CLOCK, Permissions::READ) {
+                    return ERR_PERMISSION_DENIED as i64;
+                }
+                crate::interrupts::get_ticks() as i64
+            },
+        )
+        .expect("Failed to register get_ticks");
+
+    // syscall: env.fs_read(path_ptr: i32, path_len: i32, buf_ptr: i32, buf_len: i32) -> i32
+    // Reads a file from the VFS into guest memory. As many bytes as fit are
+    // written; the return value is the full file size, or a negative error
+    // code. Only /proc is mounted today, and reading kernel state is
+    // introspection, so this requires a Thread capability with READ.
+    linker
+        .func_wrap(
+            "env",
+            "fs_read",
+            |mut caller: Caller<'_, ProcessState>, path_ptr: i32, path_len: i32, buf_ptr: i32, buf_len: i32| -> i32 {
+                if caller.data().cspace.lock().find(CapabilityType::Thread, Permissions::READ).is_none() {
+                    return ERR_PERMISSION_DENIED;
+                }
+                if path_len < 0 || path_len as usize > MAX_PATH_LEN {
+                    return ERR_MEMORY_FAULT;
+                }
+
+                let mut path_buf = [0u8; MAX_PATH_LEN];
+                let path_buf = &mut path_buf[..path_len as usize];
+                if read_guest_memory(&caller, path_ptr, path_buf).is_err() {
+                    return ERR_MEMORY_FAULT;
+                }
+                let path = match core::str::from_utf8(path_buf) {
+                    Ok(path) => path,
+                    Err(_) => return ERR_NOT_FOUND,
+                };
+
+                let shared = caller.data().cspace.clone();
+                let contents = {
+                    let cspace = shared.lock();
+                    let ctx = ReadContext { cspace: Some(&cspace) };
+                    match VFS.lock().read(path, &ctx) {
+                        Ok(contents) => contents,
+                        Err(_) => return ERR_NOT_FOUND,
+                    }
+                };
+
+                let n = contents.len().min(buf_len.max(0) as usize);
+                match write_guest_memory(&mut caller, buf_ptr, &contents[..n]) {
+                    Ok(()) => contents.len() as i32,
+                    Err(()) => ERR_MEMORY_FAULT,
+                }
+            },
+        )
+        .expect("Failed to register fs_read");
+
+    // syscall: env.dev_open(path_ptr: i32, path_len: i32, perms: i32) -> i32
+    // Opens a device under /dev. `perms` is a Permissions bit mask (READ,
+    // WRITE); the caller must hold a Device capability on that device with
+    // those permissions. Returns a descriptor or a negative error code.
+    linker
+        .func_wrap(
+            "env",
+            "dev_open",
+            |mut caller: Caller<'_, ProcessState>, path_ptr: i32, path_len: i32, perms: i32| -> i32 {
+                if path_len < 0 || path_len as usize > MAX_PATH_LEN {
+                    return ERR_MEMORY_FAULT;
+                }
+                let mut path_buf = [0u8; MAX_PATH_LEN];
+                let path_buf = &mut path_buf[..path_len as usize];
+                if read_guest_memory(&caller, path_ptr, path_buf).is_err() {
+                    return ERR_MEMORY_FAULT;
+                }
+                let path = match core::str::from_utf8(path_buf) {
+                    Ok(path) => path,
+                    Err(_) => return ERR_NOT_FOUND,
+                };
+                let permissions = Permissions::from_bits_truncate(perms as u32)
+                    .intersection(Permissions::READ.union(Permissions::WRITE));
+
+                let shared = caller.data().cspace.clone();
+                let device = {
+                    let cspace = shared.lock();
+                    let ctx = ReadContext { cspace: Some(&cspace) };
+                    match VFS.lock().open(path, permissions, &ctx) {
+                        Ok(device) => device,
+                        Err(VfsError::PermissionDenied) => return ERR_PERMISSION_DENIED,
+                        Err(_) => return ERR_NOT_FOUND,
+                    }
+                };
+
+                let devices = &mut caller.data_mut().devices;
+                match devices.iter().position(Option::is_none) {
+                    Some(fd) => {
+                        devices[fd] = Some(device);
+                        fd as i32
+                    }
+                    None => {
+                        devices.push(Some(device));
+                        (devices.len() - 1) as i32
+                    }
+                }
+            },
+        )
+        .expect("Failed to register dev_open");
+
+    // syscall: env.dev_read(fd: i32, buf_ptr: i32, buf_len: i32) -> i32
+    // Reads from an open device without blocking. Returns the number of
+    // bytes read (0 if none are ready) or a negative error code.
+    linker
+        .func_wrap(
+            "env",
+            "dev_read",
+            |mut caller: Caller<'_, ProcessState>, fd: i32, buf_ptr: i32, buf_len: i32| -> i32 {
+                let device = match open_device(&caller, fd) {
+                    Some(device) => device,
+                    None => return ERR_NOT_FOUND,
+                };
+                let mut chunk = [0u8; DEVICE_CHUNK];
+                let n = (buf_len.max(0) as usize).min(DEVICE_CHUNK);
+                let read = match device.read(&mut chunk[..n]) {
+                    Ok(read) => read,
+                    Err(_) => return ERR_PERMISSION_DENIED,
+                };
+                match write_guest_memory(&mut caller, buf_ptr, &chunk[..read]) {
+                    Ok(()) => read as i32,
+                    Err(()) => ERR_MEMORY_FAULT,
+                }
+            },
+        )
+        .expect("Failed to register dev_read");
+
+    // syscall: env.dev_write(fd: i32, buf_ptr: i32, buf_len: i32) -> i32
+    // Writes to an open device. Returns the number of bytes accepted or a
+    // negative error code.
+    linker
+        .func_wrap(
+            "env",
+            "dev_write",
+            |caller: Caller<'_, ProcessState>, fd: i32, buf_ptr: i32, buf_len: i32| -> i32 {
+                let device = match open_device(&caller, fd) {
+                    Some(device) => device,
+                    None => return ERR_NOT_FOUND,
+                };
+                let len = buf_len.max(0) as usize;
+                let mut chunk = [0u8; DEVICE_CHUNK];
+                let mut offset = 0usize;
+                while offset < len {
+                    let n = (len - offset).min(DEVICE_CHUNK);
+                    let ptr = (buf_ptr as u32 as usize + offset) as i32;
+                    if read_guest_memory(&caller, ptr, &mut chunk[..n]).is_err() {
+                        return ERR_MEMORY_FAULT;
+                    }
+                    match device.write(&chunk[..n]) {
+                        Ok(written) => {
+                            offset += written;
+                            if written < n {
+                                break;
+                            }
+                        }
+                        Err(_) => return ERR_PERMISSION_DENIED,
+                    }
+                }
+                offset as i32
+            },
+        )
+        .expect("Failed to register dev_write");
+
+    // syscall: env.dev_close(fd: i32) -> i32
+    // Closes a descriptor returned by `dev_open`. Returns 0 or ERR_NOT_FOUND.
+    linker
+        .func_wrap(
+            "env",
+            "dev_close",
+            |mut caller: Caller<'_, ProcessState>, fd: i32| -> i32 {
+                match caller.data_mut().devices.get_mut(fd as u32 as usize) {
+                    Some(slot @ Some(_)) => {
+                        *slot = None;
+                        0
+                    }
+                    _ => ERR_NOT_FOUND,
+                }
+            },
+        )
+        .expect("Failed to register dev_close");
+
+    // ── Block Devices ──
+
+    // syscall: env.blk_read(name_ptr: i32, name_len: i32, lba: i64, buf_ptr: i32, buf_len: i32) -> i32
+    // Reads `buf_len` bytes (a multiple of the block size, at most
+    // BLOCK_CHUNK) starting at block `lba` of the block device `name`
+    // (`disk0`, `disk0p1`, ...) into `buf_ptr`. Requires a Device
+    // capability on DEVICE_BLOCK with READ. Returns 0 or a negative error
+    // code (ERR_IO if the device failed).
+    linker
+        .func_wrap(
+            "env",
+            "blk_read",
+            |mut caller: Caller<'_, ProcessState>,
+             name_ptr: i32,
+             name_len: i32,
+             lba: i64,
+             buf_ptr: i32,
+             buf_len: i32|
+             -> i32 {
+                let device = match block_device(&caller, name_ptr, name_len, Permissions::READ) {
+                    Ok(device) => device,
+                    Err(code) => return code,
+                };
+                let len = match usize::try_from(buf_len) {
+                    Ok(len) if len <= BLOCK_CHUNK => len,
+                    _ => return ERR_INVALID,
+                };
+                let Some(memory) = caller.get_export("memory").and_then(Extern::into_memory) else {
+                    return ERR_MEMORY_FAULT;
+                };
+                let start = buf_ptr as u32 as usize;
+                let Some(buf) = memory.data_mut(&mut caller).get_mut(start..start + len) else {
+                    return ERR_MEMORY_FAULT;
+                };
+                match device.read_blocks(lba as u64, buf) {
+                    Ok(()) => 0,
+                    Err(e) => block_error(e),
+                }
+            },
+        )
+        .expect("Failed to register blk_read");
+
+    // syscall: env.blk_write(name_ptr: i32, name_len: i32, lba: i64, buf_ptr: i32, buf_len: i32) -> i32
+    // Writes `buf_len` bytes (a multiple of the block size, at most
+    // BLOCK_CHUNK) from `buf_ptr` starting at block `lba`, like
+    // `env.blk_read`. The data may sit in the device's write cache until
+    // `env.blk_flush`. Requires a Device capability on DEVICE_BLOCK with
+    // WRITE. Returns 0 or a negative error code.
+    linker
+        .func_wrap(
+            "env",
+            "blk_write",
+            |caller: Caller<'_, ProcessState>,
+             name_ptr: i32,
+             name_len: i32,
+             lba: i64,
+             buf_ptr: i32,
+             buf_len: i32|
+             -> i32 {
+                let device = match block_device(&caller, name_ptr, name_len, Permissions::WRITE) {
+                    Ok(device) => device,
+                    Err(code) => return code,
+                };
+                match usize::try_from(buf_len) {
+                    Ok(len) if len <= BLOCK_CHUNK => {}
+                    _ => return ERR_INVALID,
+                }
+                let data = match guest_slice(&caller, buf_ptr, buf_len) {
+                    Ok(data) => data,
+                    Err(()) => return ERR_MEMORY_FAULT,
+                };
+                match device.write_blocks(lba as u64, data) {
+                    Ok(()) => 0,
+                    Err(e) => block_error(e),
+                }
+            },
+        )
+        .expect("Failed to register blk_write");
+
+    // syscall: env.blk_flush(name_ptr: i32, name_len: i32) -> i32
+    // The `fsync` of the block layer: returns once every write to device
+    // `name` that completed before the call is on stable media (for a
+    // partition, the whole disk is flushed). Requires a Device capability
+    // on DEVICE_BLOCK with WRITE. Returns 0 or a negative error code.
+    linker
+        .func_wrap(
+            "env",
+            "blk_flush",
+            |caller: Caller<'_, ProcessState>, name_ptr: i32, name_len: i32| -> i32 {
+                let device = match block_device(&caller, name_ptr, name_len, Permissions::WRITE) {
+                    Ok(device) => device,
+                    Err(code) => return code,
+                };
+                match device.flush() {
+                    Ok(()) => 0,
+                    Err(e) => block_error(e),
+                }
+            },
+        )
+        .expect("Failed to register blk_flush");
+
+    // syscall: env.blk_subscribe(slot: i32) -> i32
+    // Subscribes the Endpoint capability in `slot` to disk health events:
+    // an EVENT_BLOCK_DEGRADED message (label 0x424C_4B01; data: errors,
+    // operations, retries) when a disk's error rate crosses the warning
+    // threshold. Requires a Device capability on DEVICE_BLOCK with READ.
+    // Returns 0 or a negative error code.
+    linker
+        .func_wrap(
+            "env",
+            "blk_subscribe",
+            |caller: Caller<'_, ProcessState>, slot: i32| -> i32 {
+                let cap = {
+                    let cspace = caller.data().cspace.lock();
+                    if !cspace.holds(CapabilityType::Device, DEVICE_BLOCK, Permissions::READ) {
+                        return ERR_PERMISSION_DENIED;
+                    }
+                    match cspace.get(slot as u32 as usize) {
+                        Some(cap) if cap.cap_type == CapabilityType::Endpoint => cap.clone(),
+                        _ => return ERR_NOT_FOUND,
+                    }
+                };
+                match block::subscribe_health(&cap) {
+                    Ok(()) => 0,
+                    Err(IpcError::PermissionDenied) => ERR_PERMISSION_DENIED,
+                    Err(_) => ERR_NOT_FOUND,
+                }
+            },
+        )
+        .expect("Failed to register blk_subscribe");
+
+    // ── Processes and IPC ──
+
+    // syscall: env.spawn(name_ptr: i32, name_len: i32) -> i32
+    // Starts the initrd module `name` (entry point `main`) as a child
+    // process. The child's CSpace is derived from the caller's: its own
+    // Thread capability plus diminished copies of everything the caller may
+    // delegate (see `CSpace::derive_child`). Requires a Thread capability
+    // with EXECUTE. Returns the child's PID or a negative error code.
+    //
+    // When the child exits, a `process::CHILD_EXITED` message (data: pid,
+    // exit code) arrives on the endpoint reported by `env.child_endpoint`.
+    linker
+        .func_wrap(
+            "env",
+            "spawn",
+            |mut caller: Caller<'_, ProcessState>, name_ptr: i32, name_len: i32| -> i32 {
+                let shared = caller.data().cspace.clone();
+                if !shared.lock().holds(CapabilityType::Thread, 0, Permissions::EXECUTE) {
+                    return ERR_PERMISSION_DENIED;
+                }
+                if name_len < 0 || name_len as usize > MAX_PATH_LEN {
+                    return ERR_MEMORY_FAULT;
+                }
+                let mut name_buf = [0u8; MAX_PATH_LEN];
+                let name_buf = &mut name_buf[..name_len as usize];
+                if read_guest_memory(&caller, name_ptr, name_buf).is_err() {
+                    return ERR_MEMORY_FAULT;
+                }
+                let name = match core::str::from_utf8(name_buf) {
+                    Ok(name) => name,
+                    Err(_) => return ERR_NOT_FOUND,
+                };
+                let module = match crate::initrd::find(name) {
+                    Some(module) => module,
+                    None => return ERR_NOT_FOUND,
+                };
+
+                let notify = match child_exit_notify(&mut caller) {
+                    Ok(cap) => cap,
+                    Err(code) => return code,
+                };
+
+                let options = child_options(caller.data());
+                let cspace = shared.lock().derive_child();
+                let parent = crate::executor::current_owner();
+                match process::spawn_child(parent, name, module, "main", cspace, options, Some(notify)) {
+                    Ok(pid) => pid as i32,
+                    Err(e) => spawn_error(e),
+                }
+            },
+        )
+        .expect("Failed to register spawn");
+
+    // syscall: env.supervise(name_ptr: i32, name_len: i32, policy: i32, max_restarts: i32) -> i32
+    // Like `env.spawn`, but the kernel restarts the child when it exits:
+    // after any exit (policy 0, always) or only after a non-zero exit code
+    // (policy 1, on failure), at most `max_restarts` times a minute; see
+    // `supervisor`. Each exit is reported on the `env.child_endpoint`
+    // endpoint as `CHILD_EXITED` with a third word, the replacement's PID
+    // (0 if none). Returns the first instance's PID or a negative error code.
+    linker
+        .func_wrap(
+            "env",
+            "supervise",
+            |mut caller: Caller<'_, ProcessState>,
+             name_ptr: i32,
+             name_len: i32,
+             policy: i32,
+             max_restarts: i32|
+             -> i32 {
+                let shared = caller.data().cspace.clone();
+                if !shared.lock().holds(CapabilityType::Thread, 0, Permissions::EXECUTE) {
+                    return ERR_PERMISSION_DENIED;
+                }
+                let policy = match policy {
+                    0 => RestartPolicy::Always,
+                    1 => RestartPolicy::OnFailure,
+                    _ => return ERR_INVALID,
+                };
+                let max_restarts = match u32::try_from(max_restarts) {
+                    Ok(max_restarts) => max_restarts,
+                    Err(_) => return ERR_INVALID,
+                };
+                let mut name_buf = [0u8; MAX_PATH_LEN];
+                let name = match read_guest_str(&caller, name_ptr, name_len, &mut name_buf) {
+                    Ok(name) => name,
+                    Err(code) => return code,
+                };
+                let module = match crate::initrd::find(name) {
+                    Some(module) => module,
+                    None => return ERR_NOT_FOUND,
+                };
+                let spec = ChildSpec {
+                    name: String::from(name),
+                    module,
+                    entry: String::from("main"),
+                    cspace: shared.lock().derive_child(),
+                    options: child_options(caller.data()),
+                    policy,
+                    max_restarts,
+                };
+                let notify = match child_exit_notify(&mut caller) {
+                    Ok(cap) => cap,
+                    Err(code) => return code,
+                };
+                match supervisor::supervise(crate::executor::current_owner(), spec, Some(notify)) {
+                    Ok(pid) => pid as i32,
+                    Err(e) => spawn_error(e),
+                }
+            },
+        )
+        .expect("Failed to register supervise");
+
+    // syscall: env.child_endpoint() -> i32
+    // CSpace slot of the endpoint child exits are announced on (receive
+    // with `env.ipc_recv`), or ERR_NOT_FOUND before the first `env.spawn`.
+    linker
+        .func_wrap(
+            "env",
+            "child_endpoint",
+            |caller: Caller<'_, ProcessState>| -> i32 {
+                match &caller.data().child_exits {
+                    Some((slot, _)) => *slot as i32,
+                    None => ERR_NOT_FOUND,
+                }
+            },
+        )
+        .expect("Failed to register child_endpoint");
+
+    // syscall: env.ipc_recv(slot: i32, buf_ptr: i32, buf_len: i32) -> i64
+    // Takes the next message from the endpoint whose capability (with READ)
+    // is in `slot`, without blocking. The message's data words are written
+    // to `buf_ptr` as little-endian u64s (as many as fit). Returns the
+    // message label, 0 if the queue is empty, or a negative error code.
+    //
+    // If the message carries a shared region (`env.shm_share`), its
+    // capability is installed in the caller's CSpace and the slot is
+    // appended as one more data word (u64::MAX if the CSpace is full).
+    // A request sent with `env.ipc_call` gets its reply capability (for
+    // `env.ipc_reply`) appended the same way, as the last word.
+    linker
+        .func_wrap(
+            "env",
+            "ipc_recv",
+            |mut caller: Caller<'_, ProcessState>, slot: i32, buf_ptr: i32, buf_len: i32| -> i64 {
+                let cap = match caller.data().cspace.lock().get(slot as u32 as usize) {
+                    Some(cap) if cap.cap_type == CapabilityType::Endpoint => cap.clone(),
+                    _ => return ERR_NOT_FOUND as i64,
+                };
+                let result = IPC_MANAGER.lock().receive_with_cap(&cap);
+                match result {
+                    Ok(msg) => {
+                        let memory = caller.get_export("memory").and_then(Extern::into_memory);
+                        deliver_message(&mut caller, memory, msg, buf_ptr, buf_len)
+                    }
+                    Err(IpcError::QueueEmpty) => 0,
+                    Err(e) => recv_error(e),
+                }
+            },
+        )
+        .expect("Failed to register ipc_recv");
+
+    // syscall: env.ipc_recv_wait(slot: i32, buf_ptr: i32, buf_len: i32, timeout_ms: i32) -> i64
+    // Like `env.ipc_recv`, but if the queue is empty the process blocks
+    // (without consuming CPU) until a message arrives or `timeout_ms`
+    // milliseconds pass, returning ERR_TIMED_OUT in the latter case. A
+    // negative timeout waits forever; 0 returns ERR_TIMED_OUT at once.
+    linker
+        .func_wrap(
+            "env",
+            "ipc_recv_wait",
+            |mut caller: Caller<'_, ProcessState>,
+             slot: i32,
+             buf_ptr: i32,
+             buf_len: i32,
+             timeout_ms: i32|
+             -> Result<i64, wasmi::Error> {
+                let cap = match caller.data().cspace.lock().get(slot as u32 as usize) {
+                    Some(cap) if cap.cap_type == CapabilityType::Endpoint => cap.clone(),
+                    _ => return Ok(ERR_NOT_FOUND as i64),
+                };
+                let result = IPC_MANAGER.lock().receive_with_cap(&cap);
+                match result {
+                    Ok(msg) => {
+                        let memory = caller.get_export("memory").and_then(Extern::into_memory);
+                        Ok(deliver_message(&mut caller, memory, msg, buf_ptr, buf_len))
+                    }
+                    Err(IpcError::QueueEmpty) if timeout_ms != 0 => {
+                        let deadline = u64::try_from(timeout_ms)
+                            .ok()
+                            .map(|ms| crate::interrupts::uptime_ms() + ms);
+                        let state = caller.data_mut();
+                        state.wait = Some(Wait::Message { cap, deadline });
+                        state.recv_buf = (buf_ptr, buf_len);
+                        Err(wasmi::Error::host(Preempted))
+                    }
+                    Err(IpcError::QueueEmpty) => Ok(ERR_TIMED_OUT as i64),
+                    Err(e) => Ok(recv_error(e)),
+                }
+            },
+        )
+        .expect("Failed to register ipc_recv_wait");
+
+    // syscall: env.ipc_wait(slots_ptr: i32, count: i32, timeout_ms: i32) -> i32
+    // Blocks until one of the `count` (at most MAX_WAIT_SLOTS) endpoints
+    // whose slots are listed at `slots_ptr` (little-endian u32s, each
+    // capability with READ) has a message, and returns that slot without
+    // dequeuing anything; receive from it with `env.ipc_recv`. Endpoints
+    // are checked round-robin. Returns ERR_TIMED_OUT after `timeout_ms`
+    // milliseconds (negative: never; 0: at once if none is ready), or
+    // ERR_NOT_FOUND once every endpoint is gone.
+    linker
+        .func_wrap(
+            "env",
+            "ipc_wait",
+            |mut caller: Caller<'_, ProcessState>,
+             slots_ptr: i32,
+             count: i32,
+             timeout_ms: i32|
+             -> Result<i32, wasmi::Error> {
+                let count = match usize::try_from(count) {
+                    Ok(count) if (1..=MAX_WAIT_SLOTS).contains(&count) => count,
+                    _ => return Ok(ERR_INVALID),
+                };
+                let mut bytes = [0u8; MAX_WAIT_SLOTS * 4];
+                if read_guest_memory(&caller, slots_ptr, &mut bytes[..count * 4]).is_err() {
+                    return Ok(ERR_MEMORY_FAULT);
+                }
+                let mut set = WaitSet::new();
+                {
+                    let cspace = caller.data().cspace.lock();
+                    let manager = IPC_MANAGER.lock();
+                    for chunk in bytes[..count * 4].chunks_exact(4) {
+                        let slot = u32::from_le_bytes(chunk.try_into().unwrap());
+                        let cap = match cspace.get(slot as usize) {
+                            Some(cap) if cap.cap_type == CapabilityType::Endpoint => cap.clone(),
+                            _ => return Ok(ERR_NOT_FOUND),
+                        };
+                        if let Err(e) = set.add(&manager, cap, slot as u64) {
+                            return Ok(recv_error(e) as i32);
+                        }
+                    }
+                    if let Some(slot) = set.poll(&manager) {
+                        return Ok(slot as i32);
+                    }
+                }
+                if timeout_ms == 0 {
+                    return Ok(ERR_TIMED_OUT);
+                }
+                let deadline = u64::try_from(timeout_ms).ok().map(|ms| crate::interrupts::uptime_ms() + ms);
+                caller.data_mut().wait = Some(Wait::Any { set, deadline });
+                Err(wasmi::Error::host(Preempted))
+            },
+        )
+        .expect("Failed to register ipc_wait");
+
+    // syscall: env.ipc_send(slot: i32, label: i64, buf_ptr: i32, words: i32) -> i32
+    // Sends a message with `label` and `words` (at most MAX_MESSAGE_WORDS)
+    // little-endian u64 data words read from `buf_ptr`, through the Endpoint
+    // capability (with WRITE) in `slot`. Returns 0, ERR_NO_RESOURCES if
+    // the receiver's queue is full, or another negative error code.
+    linker
+        .func_wrap(
+            "env",
+            "ipc_send",
+            |caller: Caller<'_, ProcessState>, slot: i32, label: i64, buf_ptr: i32, words: i32| -> i32 {
+                let cap = match caller.data().cspace.lock().get(slot as u32 as usize) {
+                    Some(cap) if cap.cap_type == CapabilityType::Endpoint => cap.clone(),
+                    _ => return ERR_NOT_FOUND,
+                };
+                let msg = match read_message(&caller, label, buf_ptr, words, crate::ipc::MAX_MESSAGE_WORDS) {
+                    Ok(msg) => msg,
+                    Err(code) => return code,
+                };
+                match IPC_MANAGER.lock().send_with_cap(&cap, msg) {
+                    Ok(()) => 0,
+                    Err(IpcError::QueueFull) => ERR_NO_RESOURCES,
+                    Err(IpcError::PermissionDenied) => ERR_PERMISSION_DENIED,
+                    Err(_) => ERR_NOT_FOUND,
+                }
+            },
+        )
+        .expect("Failed to register ipc_send");
+
+    // syscall: env.ipc_call(slot: i32, label: i64, buf_ptr: i32, words: i32, buf_len: i32, timeout_ms: i32) -> i64
+    // Sends a request like `env.ipc_send` (at most MAX_MESSAGE_WORDS - 1
+    // words, leaving room for the reply slot) and blocks until the server
+    // answers with `env.ipc_reply` or `timeout_ms` milliseconds pass
+    // (negative: forever). The reply is delivered like `env.ipc_recv`
+    // delivers a message, into `buf_ptr` / `buf_len`. Returns the reply's
+    // label, ERR_TIMED_OUT, ERR_NOT_FOUND if the server's endpoint went
+    // away, or another negative error code.
+    linker
+        .func_wrap(
+            "env",
+            "ipc_call",
+            |mut caller: Caller<'_, ProcessState>,
+             slot: i32,
+             label: i64,
+             buf_ptr: i32,
+             words: i32,
+             buf_len: i32,
+             timeout_ms: i32|
+             -> Result<i64, wasmi::Error> {
+                let cap = match caller.data().cspace.lock().get(slot as u32 as usize) {
+                    Some(cap) if cap.cap_type == CapabilityType::Endpoint => cap.clone(),
+                    _ => return Ok(ERR_NOT_FOUND as i64),
+                };
+                if timeout_ms == 0 {
+                    return Ok(ERR_INVALID as i64);
+                }
+                let msg = match read_message(&caller, label, buf_ptr, words, crate::ipc::MAX_MESSAGE_WORDS - 1) {
+                    Ok(msg) => msg,
+                    Err(code) => return Ok(code as i64),
+                };
+                let result = IPC_MANAGER.lock().call(&cap, msg);
+                let call = match result {
+                    Ok(call) => call,
+                    Err(IpcError::QueueFull) => return Ok(ERR_NO_RESOURCES as i64),
+                    Err(IpcError::PermissionDenied) => return Ok(ERR_PERMISSION_DENIED as i64),
+                    Err(_) => return Ok(ERR_NOT_FOUND as i64),
+                };
+                let deadline = u64::try_from(timeout_ms).ok().map(|ms| crate::interrupts::uptime_ms() + ms);
+                let state = caller.data_mut();
+                state.wait = Some(Wait::Message { cap: call.reply_cap(), deadline });
+                state.recv_buf = (buf_ptr, buf_len);
+                state.call = Some(call);
+                Err(wasmi::Error::host(Preempted))
+            },
+        )
+        .expect("Failed to register ipc_call");
+
+    // syscall: env.ipc_reply(reply_slot: i32, label: i64, buf_ptr: i32, words: i32) -> i32
+    // Answers an `env.ipc_call`: `reply_slot` is the reply capability
+    // `env.ipc_recv` delivered with the request. Each reply capability
+    // works once and leaves the caller's CSpace once used. Returns 0,
+    // ERR_NOT_FOUND if the caller stopped waiting, ERR_INVALID if the slot
+    // holds no unused reply capability, or another negative error code.
+    linker
+        .func_wrap(
+            "env",
+            "ipc_reply",
+            |caller: Caller<'_, ProcessState>, reply_slot: i32, label: i64, buf_ptr: i32, words: i32| -> i32 {
+                let reply_slot = reply_slot as u32 as usize;
+                let cap = match caller.data().cspace.lock().get(reply_slot) {
+                    Some(cap) if cap.cap_type == CapabilityType::Endpoint => cap.clone(),
+                    _ => return ERR_NOT_FOUND,
+                };
+                let msg = match read_message(&caller, label, buf_ptr, words, crate::ipc::MAX_MESSAGE_WORDS) {
+                    Ok(msg) => msg,
+                    Err(code) => return code,
+                };
+                let result = IPC_MANAGER.lock().reply(&cap, msg);
+                match result {
+                    Ok(()) | Err(IpcError::InvalidEndpoint) => {
+                        caller.data().cspace.lock().revoke(reply_slot);
+                    }
+                    Err(_) => {}
+                }
+                match result {
+                    Ok(()) => 0,
+                    Err(IpcError::InvalidEndpoint) => ERR_NOT_FOUND,
+                    Err(IpcError::PermissionDenied) => ERR_PERMISSION_DENIED,
+                    Err(_) => ERR_INVALID,
+                }
+            },
+        )
+        .expect("Failed to register ipc_reply");
+
+    // syscall: env.endpoint_create() -> i32
+    // Creates an IPC endpoint and puts a READ | WRITE | GRANT capability for
+    // it in the caller's CSpace, e.g. to publish with `service_register`.
+    // The endpoint is destroyed when the caller exits. Requires a Thread
+    // capability with EXECUTE. Returns the slot, or ERR_NO_RESOURCES once
+    // the caller owns ipc::MAX_ENDPOINTS_PER_OWNER endpoints.
+    linker
+        .func_wrap(
+            "env",
+            "endpoint_create",
+            |caller: Caller<'_, ProcessState>| -> i32 {
+                let shared = caller.data().cspace.clone();
+                if !shared.lock().holds(CapabilityType::Thread, 0, Permissions::EXECUTE) {
+                    return ERR_PERMISSION_DENIED;
+                }
+                let cap = {
+                    let mut ipc = IPC_MANAGER.lock();
+                    let endpoint = match ipc.create_endpoint(crate::executor::current_owner()) {
+                        Ok(endpoint) => endpoint,
+                        Err(_) => return ERR_NO_RESOURCES,
+                    };
+                    let rights = Permissions::READ.union(Permissions::WRITE).union(Permissions::GRANT);
+                    match ipc.endpoint_capability(endpoint, rights) {
+                        Ok(cap) => cap,
+                        Err(_) => return ERR_NO_RESOURCES,
+                    }
+                };
+                let slot = shared.lock().insert(cap.clone());
+                match slot {
+                    Some(slot) => slot as i32,
+                    None => {
+                        destroy_endpoint(&cap);
+                        ERR_NO_RESOURCES
+                    }
+                }
+            },
+        )
+        .expect("Failed to register endpoint_create");
+
+    // syscall: env.service_register(name_ptr: i32, name_len: i32, slot: i32) -> i32
+    // Publishes the Endpoint capability in `slot` (which must carry GRANT)
+    // under a service name; see `services`. The name is released when the
+    // process exits. At shutdown the endpoint gets an EVENT_SHUTDOWN
+    // message (label 0x5344_4E01) ahead of queued requests; the process
+    // then has a moment to exit before it is killed. Returns 0 or a
+    // negative error code (ERR_INVALID for a bad or already-taken name).
+    linker
+        .func_wrap(
+            "env",
+            "service_register",
+            |caller: Caller<'_, ProcessState>, name_ptr: i32, name_len: i32, slot: i32| -> i32 {
+                let mut name_buf = [0u8; MAX_PATH_LEN];
+                let name = match read_guest_str(&caller, name_ptr, name_len, &mut name_buf) {
+                    Ok(name) => name,
+                    Err(code) => return code,
+                };
+                let cap = match caller.data().cspace.lock().get(slot as u32 as usize) {
+                    Some(cap) => cap.clone(),
+                    None => return ERR_NOT_FOUND,
+                };
+                match services::register(name, cap, crate::executor::current_owner()) {
+                    Ok(()) => 0,
+                    Err(ServiceError::NotDelegable) => ERR_PERMISSION_DENIED,
+                    Err(_) => ERR_INVALID,
+                }
+            },
+        )
+        .expect("Failed to register service_register");
+
+    // syscall: env.service_lookup(name_ptr: i32, name_len: i32) -> i32
+    // Finds a service by name and puts a send-only (WRITE) capability for
+    // its endpoint in the caller's CSpace. Returns the slot, ERR_NOT_FOUND
+    // if no such service is registered, or another negative error code.
+    linker
+        .func_wrap(
+            "env",
+            "service_lookup",
+            |caller: Caller<'_, ProcessState>, name_ptr: i32, name_len: i32| -> i32 {
+                let mut name_buf = [0u8; MAX_PATH_LEN];
+                let name = match read_guest_str(&caller, name_ptr, name_len, &mut name_buf) {
+                    Ok(name) => name,
+                    Err(code) => return code,
+                };
+                let cap = match services::lookup(name) {
+                    Ok(cap) => cap,
+                    Err(_) => return ERR_NOT_FOUND,
+                };
+                let slot = caller.data().cspace.lock().insert(cap);
+                match slot {
+                    Some(slot) => slot as i32,
+                    None => ERR_NO_RESOURCES,
+                }
+            },
+        )
+        .expect("Failed to register service_lookup");
+
+    // syscall: env.service_unregister(name_ptr: i32, name_len: i32) -> i32
+    // Withdraws a name the caller registered, e.g. before handing its work
+    // to another service. Callers that already looked it up keep their
+    // capability. Returns 0, or ERR_NOT_FOUND if the caller owns no
+    // service by that name.
+    linker
+        .func_wrap(
+            "env",
+            "service_unregister",
+            |caller: Caller<'_, ProcessState>, name_ptr: i32, name_len: i32| -> i32 {
+                let mut name_buf = [0u8; MAX_PATH_LEN];
+                let name = match read_guest_str(&caller, name_ptr, name_len, &mut name_buf) {
+                    Ok(name) => name,
+                    Err(code) => return code,
+                };
+                match services::unregister(name, crate::executor::current_owner()) {
+                    Ok(()) => 0,
+                    Err(_) => ERR_NOT_FOUND,
+                }
+            },
+        )
+        .expect("Failed to register service_unregister");
+
+    // ── Shared memory ──
+    // A region is named by the CSpace slot of its SharedMemory capability;
+    // see `shm`. Offsets and lengths are in bytes.
+
+    // syscall: env.shm_create(len: i32) -> i32
+    // Creates a zero-filled region of `len` bytes (at most
+    // shm::MAX_REGION_LEN), released when the caller exits. Requires a
+    // Thread capability with EXECUTE. Returns the slot of a capability with
+    // READ, WRITE and GRANT, or ERR_NO_RESOURCES (also once the caller owns
+    // shm::MAX_REGIONS_PER_OWNER regions).
+    linker
+        .func_wrap(
+            "env",
+            "shm_create",
+            |caller: Caller<'_, ProcessState>, len: i32| -> i32 {
+                let shared = caller.data().cspace.clone();
+                if !shared.lock().holds(CapabilityType::Thread, 0, Permissions::EXECUTE) {
+                    return ERR_PERMISSION_DENIED;
+                }
+                let len = match usize::try_from(len) {
+                    Ok(len) => len,
+                    Err(_) => return ERR_INVALID,
+                };
+                let cap = match shm::create(len, crate::executor::current_owner()) {
+                    Ok(cap) => cap,
+                    Err(_) => return ERR_NO_RESOURCES,
+                };
+                let slot = shared.lock().insert(cap.clone());
+                match slot {
+                    Some(slot) => slot as i32,
+                    None => {
+                        shm::release(&cap);
+                        ERR_NO_RESOURCES
+                    }
+                }
+            },
+        )
+        .expect("Failed to register shm_create");
+
+    // syscall: env.shm_read(slot: i32, offset: i32, buf_ptr: i32, len: i32) -> i32
+    // Copies `len` bytes at `offset` in the region into guest memory.
+    // Requires READ. Returns 0 or a negative error code (ERR_INVALID if the
+    // range extends past the region).
+    linker
+        .func_wrap(
+            "env",
+            "shm_read",
+            |mut caller: Caller<'_, ProcessState>, slot: i32, offset: i32, buf_ptr: i32, len: i32| -> i32 {
+                let region = match shm_region(&caller, slot, Permissions::READ) {
+                    Ok(region) => region,
+                    Err(code) => return code,
+                };
+                let (offset, len) = match (usize::try_from(offset), usize::try_from(len)) {
+                    (Ok(offset), Ok(len)) => (offset, len),
+                    _ => return ERR_INVALID,
+                };
+                let result = region.with_range(offset, len, |bytes| write_guest_memory(&mut caller, buf_ptr, bytes));
+                match result {
+                    Ok(Ok(())) => 0,
+                    Ok(Err(())) => ERR_MEMORY_FAULT,
+                    Err(e) => shm_error(e),
+                }
+            },
+        )
+        .expect("Failed to register shm_read");
+
+    // syscall: env.shm_write(slot: i32, offset: i32, buf_ptr: i32, len: i32) -> i32
+    // Copies `len` bytes of guest memory into the region at `offset`.
+    // Requires WRITE. Returns 0 or a negative error code.
+    linker
+        .func_wrap(
+            "env",
+            "shm_write",
+            |caller: Caller<'_, ProcessState>, slot: i32, offset: i32, buf_ptr: i32, len: i32| -> i32 {
+                let region = match shm_region(&caller, slot, Permissions::WRITE) {
+                    Ok(region) => region,
+                    Err(code) => return code,
+                };
+                let offset = match usize::try_from(offset) {
+                    Ok(offset) => offset,
+                    Err(_) => return ERR_INVALID,
+                };
+                let bytes = match guest_slice(&caller, buf_ptr, len) {
+                    Ok(bytes) => bytes,
+                    Err(()) => return ERR_MEMORY_FAULT,
+                };
+                match region.write(offset, bytes) {
+                    Ok(()) => 0,
+                    Err(e) => shm_error(e),
+                }
+            },
+        )
+        .expect("Failed to register shm_write");
+
+    // syscall: env.shm_share(slot: i32, endpoint_slot: i32, label: i64, rights: i32) -> i32
+    // Sends a message with `label` whose payload is a capability to the
+    // region, carrying at most `rights` (READ / WRITE / GRANT bits), through
+    // the Endpoint capability (with WRITE) in `endpoint_slot`. The region
+    // capability in `slot` must carry GRANT. The receiver finds the new
+    // capability's slot as the last data word from `env.ipc_recv`.
+    linker
+        .func_wrap(
+            "env",
+            "shm_share",
+            |caller: Caller<'_, ProcessState>, slot: i32, endpoint_slot: i32, label: i64, rights: i32| -> i32 {
+                let (region, endpoint) = {
+                    let cspace = caller.data().cspace.lock();
+                    match (cspace.get(slot as u32 as usize), cspace.get(endpoint_slot as u32 as usize)) {
+                        (Some(region), Some(endpoint)) => (region.clone(), endpoint.clone()),
+                        _ => return ERR_NOT_FOUND,
+                    }
+                };
+                if let Err(e) = shm::resolve(&region, Permissions::GRANT) {
+                    return shm_error(e);
+                }
+                let rights = Permissions::from_bits_truncate(rights as u32)
+                    .intersection(Permissions::READ.union(Permissions::WRITE).union(Permissions::GRANT));
+                let msg = Message::new(label as u64);
+                match IPC_MANAGER.lock().send_shared(&endpoint, msg, &region, rights) {
+                    Ok(()) => 0,
+                    Err(IpcError::QueueFull) => ERR_NO_RESOURCES,
+                    Err(IpcError::PermissionDenied) => ERR_PERMISSION_DENIED,
+                    Err(_) => ERR_INVALID,
+                }
+            },
+        )
+        .expect("Failed to register shm_share");
+
+    // ── Key-value store ──
+    // Each call uses the namespace of the first Storage capability in the
+    // caller's CSpace carrying the needed right (READ to get, WRITE to put
+    // or delete); see `kv`. Keys and values are arbitrary bytes.
+
+    // syscall: env.kv_put(key_ptr: i32, key_len: i32, val_ptr: i32, val_len: i32) -> i32
+    // Stores a value, replacing any previous one. Returns 0, ERR_INVALID for
+    // an empty or oversized key or value, ERR_NO_RESOURCES if the
+    // namespace's quota is used up, or another negative error code.
+    linker
+        .func_wrap(
+            "env",
+            "kv_put",
+            |caller: Caller<'_, ProcessState>, key_ptr: i32, key_len: i32, val_ptr: i32, val_len: i32| -> i32 {
+                let ns = match kv_namespace(&caller, Permissions::WRITE) {
+                    Ok(ns) => ns,
+                    Err(code) => return code,
+                };
+                let key = guest_slice(&caller, key_ptr, key_len);
+                let value = guest_slice(&caller, val_ptr, val_len);
+                let (key, value) = match (key, value) {
+                    (Ok(key), Ok(value)) => (key, value),
+                    _ => return ERR_MEMORY_FAULT,
+                };
+                kv_result(kv::put(ns, key, value).map(|()| 0))
+            },
+        )
+        .expect("Failed to register kv_put");
+
+    // syscall: env.kv_get(key_ptr: i32, key_len: i32, buf_ptr: i32, buf_len: i32) -> i32
+    // Copies as much of the value as fits into the buffer and returns its
+    // full length (like `fs_read`), or ERR_NOT_FOUND if the key is absent.
+    linker
+        .func_wrap(
+            "env",
+            "kv_get",
+            |mut caller: Caller<'_, ProcessState>, key_ptr: i32, key_len: i32, buf_ptr: i32, buf_len: i32| -> i32 {
+                let ns = match kv_namespace(&caller, Permissions::READ) {
+                    Ok(ns) => ns,
+                    Err(code) => return code,
+                };
+                let key_len = match usize::try_from(key_len) {
+                    Ok(len) if len <= kv::MAX_KEY_LEN => len,
+                    _ => return ERR_INVALID,
+                };
+                let mut key = [0u8; kv::MAX_KEY_LEN];
+                if read_guest_memory(&caller, key_ptr, &mut key[..key_len]).is_err() {
+                    return ERR_MEMORY_FAULT;
+                }
+                kv_result(kv::get(ns, &key[..key_len], |value| {
+                    let n = value.len().min(buf_len.max(0) as usize);
+                    match write_guest_memory(&mut caller, buf_ptr, &value[..n]) {
+                        Ok(()) => value.len() as i32,
+                        Err(()) => ERR_MEMORY_FAULT,
+                    }
+                }))
+            },
+        )
+        .expect("Failed to register kv_get");
+
+    // syscall: env.kv_delete(key_ptr: i32, key_len: i32) -> i32
+    // Removes a key. Returns 0 or ERR_NOT_FOUND.
+    linker
+        .func_wrap(
+            "env",
+            "kv_delete",
+            |caller: Caller<'_, ProcessState>, key_ptr: i32, key_len: i32| -> i32 {
+                let ns = match kv_namespace(&caller, Permissions::WRITE) {
+                    Ok(ns) => ns,
+                    Err(code) => return code,
+                };
+                let key = match guest_slice(&caller, key_ptr, key_len) {
+                    Ok(key) => key,
+                    Err(()) => return ERR_MEMORY_FAULT,
+                };
+                kv_result(kv::delete(ns, key).map(|()| 0))
+            },
+        )
+        .expect("Failed to register kv_delete");
+
+    // ── Sockets ──
+    // A socket is named by the CSpace slot of its Socket capability, so it
+    // can be delegated like any other capability. All calls are
+    // non-blocking; guests poll (calling `env.yield` in between).
+
+    // syscall: env.socket_create(kind: i32) -> i32
+    // Creates a TCP (kind 0) or UDP (kind 1) socket. Requires a Device
+    // capability on DEVICE_NETWORK; the new Socket capability carries that
+    // capability's CONNECT / LISTEN / SEND / RECV bits. Returns the slot of
+    // the Socket capability or a negative error code.
+    linker
+        .func_wrap(
+            "env",
+            "socket_create",
+            |caller: Caller<'_, ProcessState>, kind: i32| -> i32 {
+                let kind = match kind {
+                    0 => SocketKind::Tcp,
+                    1 => SocketKind::Udp,
+                    _ => return ERR_INVALID,
+                };
+                let socket_rights = Permissions::CONNECT
+                    .union(Permissions::LISTEN)
+                    .union(Permissions::SEND)
+                    .union(Permissions::RECV);
+                let shared = caller.data().cspace.clone();
+                let rights = shared
+                    .lock()
+                    .iter()
+                    .filter(|(_, cap)| cap.cap_type == CapabilityType::Device && cap.resource_id == DEVICE_NETWORK)
+                    .fold(Permissions::NONE, |acc, (_, cap)| acc.union(cap.permissions))
+                    .intersection(socket_rights);
+                if rights.bits() == 0 {
+                    return ERR_PERMISSION_DENIED;
+                }
+                let heap_bytes = socket_manager::socket_heap(kind, net_stack::tcp_buffer());
+                let request = ResourceRequest { heap_bytes, frames: 0, sockets: 1 };
+                if admission::admit(&request).is_err() {
+                    return ERR_NO_RESOURCES;
+                }
+
+                let cap = match NETWORK_STACK.lock().as_mut() {
+                    Some(stack) => stack.open_socket(kind, rights),
+                    None => return ERR_NO_RESOURCES,
+                };
+                let id = cap.resource_id;
+                let slot = shared.lock().insert(cap);
+                match slot {
+                    Some(slot) => slot as i32,
+                    None => {
+                        if let Some(stack) = NETWORK_STACK.lock().as_mut() {
+                            let _ = stack.close_socket(id);
+                        }
+                        ERR_NO_RESOURCES
+                    }
+                }
+            },
+        )
+        .expect("Failed to register socket_create");
+
+    // syscall: env.socket_connect(slot: i32, ipv4: i32, port: i32) -> i32
+    // Starts a TCP connection (poll `socket_send` / `socket_recv` for the
+    // outcome) or sets a UDP socket's destination. `ipv4` is the address as
+    // a big-endian integer (10.0.2.2 = 0x0A000202). Requires CONNECT.
+    linker
+        .func_wrap(
+            "env",
+            "socket_connect",
+            |caller: Caller<'_, ProcessState>, slot: i32, ipv4: i32, port: i32| -> i32 {
+                let cap = match socket_cap(&caller, slot) {
+                    Ok(cap) => cap,
+                    Err(code) => return code,
+                };
+                let port = match u16::try_from(port) {
+                    Ok(port) if port != 0 => port,
+                    _ => return ERR_INVALID,
+                };
+                let addr = smoltcp::wire::Ipv4Address::from_bytes(&(ipv4 as u32).to_be_bytes());
+                let remote = smoltcp::wire::IpEndpoint::new(addr.into(), port);
+                with_socket_stack(|stack| stack.connect(&cap, remote).map(|()| 0))
+            },
+        )
+        .expect("Failed to register socket_connect");
+
+    // syscall: env.socket_listen(slot: i32, port: i32) -> i32
+    // Listens on a TCP port (for one connection) or binds a UDP socket.
+    // Requires LISTEN.
+    linker
+        .func_wrap(
+            "env",
+            "socket_listen",
+            |caller: Caller<'_, ProcessState>, slot: i32, port: i32| -> i32 {
+                let cap = match socket_cap(&caller, slot) {
+                    Ok(cap) => cap,
+                    Err(code) => return code,
+                };
+                let port = match u16::try_from(port) {
+                    Ok(port) if port != 0 => port,
+                    _ => return ERR_INVALID,
+                };
+                with_socket_stack(|stack| stack.listen(&cap, port).map(|()| 0))
+            },
+        )
+        .expect("Failed to register socket_listen");
+
+    // syscall: env.socket_bind_interface(slot: i32, index: i32) -> i32
+    // Ties the socket to interface `eth<index>`: a later `socket_listen` or
+    // `socket_connect` uses that interface's address and fails with
+    // ERR_NOT_FOUND while it has none.
+    linker
+        .func_wrap(
+            "env",
+            "socket_bind_interface",
+            |caller: Caller<'_, ProcessState>, slot: i32, index: i32| -> i32 {
+                let cap = match socket_cap(&caller, slot) {
+                    Ok(cap) => cap,
+                    Err(code) => return code,
+                };
+                let index = match usize::try_from(index) {
+                    Ok(index) => index,
+                    Err(_) => return ERR_INVALID,
+                };
+                with_socket_stack(|stack| stack.bind_interface(&cap, index).map(|()| 0))
+            },
+        )
+        .expect("Failed to register socket_bind_interface");
+
+    // syscall: env.socket_set_buffer(slot: i32, bytes: i32) -> i32
+    // Sizes a TCP socket's send and receive buffers before it connects or
+    // listens; `bytes` is rounded up to a power of two from 4 KiB to
+    // 256 KiB. Returns the size set, ERR_INVALID for a UDP socket or one
+    // already in use, or ERR_NO_RESOURCES.
+    linker
+        .func_wrap(
+            "env",
+            "socket_set_buffer",
+            |caller: Caller<'_, ProcessState>, slot: i32, bytes: i32| -> i32 {
+                let cap = match socket_cap(&caller, slot) {
+                    Ok(cap) => cap,
+                    Err(code) => return code,
+                };
+                let bytes = match usize::try_from(bytes) {
+                    Ok(bytes) => bytes,
+                    Err(_) => return ERR_INVALID,
+                };
+                let heap_bytes = socket_manager::socket_heap(SocketKind::Tcp, bytes);
+                if admission::admit(&ResourceRequest { heap_bytes, frames: 0, sockets: 0 }).is_err() {
+                    return ERR_NO_RESOURCES;
+                }
+                with_socket_stack(|stack| stack.set_buffer_size(&cap, bytes).map(|size| size as i32))
+            },
+        )
+        .expect("Failed to register socket_set_buffer");
+
+    // syscall: env.socket_send(slot: i32, buf_ptr: i32, buf_len: i32) -> i32
+    // Queues bytes for sending. Returns how many were accepted (0 if the
+    // send buffer is full) or a negative error code. Requires SEND.
+    linker
+        .func_wrap(
+            "env",
+            "socket_send",
+            |caller: Caller<'_, ProcessState>, slot: i32, buf_ptr: i32, buf_len: i32| -> i32 {
+                let cap = match socket_cap(&caller, slot) {
+                    Ok(cap) => cap,
+                    Err(code) => return code,
+                };
+                let data = match guest_slice(&caller, buf_ptr, buf_len) {
+                    Ok(data) => data,
+                    Err(()) => return ERR_MEMORY_FAULT,
+                };
+                with_socket_stack(|stack| stack.send(&cap, data).map(|n| n as i32))
+            },
+        )
+        .expect("Failed to register socket_send");
+
+    // syscall: env.socket_recv(slot: i32, buf_ptr: i32, buf_len: i32) -> i32
+    // Reads up to SOCKET_CHUNK bytes. Returns the count (0 if nothing has
+    // arrived), ERR_CLOSED once a TCP peer has closed and all its data has
+    // been read, or another negative error code. Requires RECV.
+    linker
+        .func_wrap(
+            "env",
+            "socket_recv",
+            |mut caller: Caller<'_, ProcessState>, slot: i32, buf_ptr: i32, buf_len: i32| -> i32 {
+                let cap = match socket_cap(&caller, slot) {
+                    Ok(cap) => cap,
+                    Err(code) => return code,
+                };
+                let mut chunk = [0u8; SOCKET_CHUNK];
+                let n = (buf_len.max(0) as usize).min(SOCKET_CHUNK);
+                let read = with_socket_stack(|stack| stack.recv(&cap, &mut chunk[..n]).map(|n| n as i32));
+                if read <= 0 {
+                    return read;
+                }
+                match write_guest_memory(&mut caller, buf_ptr, &chunk[..read as usize]) {
+                    Ok(()) => read,
+                    Err(()) => ERR_MEMORY_FAULT,
+                }
+            },
+        )
+        .expect("Failed to register socket_recv");
+
+    // syscall: env.socket_send_to(slot: i32, buf_ptr: i32, buf_len: i32, ipv4: i32, port: i32) -> i32
+    // Queues one datagram to `ipv4`:`port` on a UDP socket, binding it to
+    // an ephemeral port first if neither `socket_listen` nor
+    // `socket_connect` has. Returns `buf_len` once queued, 0 if the send
+    // buffer is full, or a negative error code (ERR_INVALID for a datagram
+    // larger than the buffer). Requires SEND.
+    linker
+        .func_wrap(
+            "env",
+            "socket_send_to",
+            |caller: Caller<'_, ProcessState>, slot: i32, buf_ptr: i32, buf_len: i32, ipv4: i32, port: i32| -> i32 {
+                let cap = match socket_cap(&caller, slot) {
+                    Ok(cap) => cap,
+                    Err(code) => return code,
+                };
+                let port = match u16::try_from(port) {
+                    Ok(port) if port != 0 => port,
+                    _ => return ERR_INVALID,
+                };
+                let data = match guest_slice(&caller, buf_ptr, buf_len) {
+                    Ok(data) => data,
+                    Err(()) => return ERR_MEMORY_FAULT,
+                };
+                let addr = smoltcp::wire::Ipv4Address::from_bytes(&(ipv4 as u32).to_be_bytes());
+                let remote = smoltcp::wire::IpEndpoint::new(addr.into(), port);
+                with_socket_stack(|stack| {
+                    stack.send_to(&cap, data, remote).map(|queued| if queued { data.len() as i32 } else { 0 })
+                })
+            },
+        )
+        .expect("Failed to register socket_send_to");
+
+    // syscall: env.socket_recv_from(slot: i32, buf_ptr: i32, buf_len: i32, from_ptr: i32) -> i32
+    // Reads one datagram from a UDP socket, truncated to `buf_len` and
+    // SOCKET_CHUNK bytes, and writes its sender to `from_ptr` as two
+    // little-endian i32s: the address (in `socket_connect`'s form) and the
+    // port. Returns the length read, 0 if nothing has arrived (an empty
+    // datagram also reads as 0, with the sender written), or a negative
+    // error code. Requires RECV.
+    linker
+        .func_wrap(
+            "env",
+            "socket_recv_from",
+            |mut caller: Caller<'_, ProcessState>, slot: i32, buf_ptr: i32, buf_len: i32, from_ptr: i32| -> i32 {
+                let cap = match socket_cap(&caller, slot) {
+                    Ok(cap) => cap,
+                    Err(code) => return code,
+                };
+                let mut chunk = [0u8; SOCKET_CHUNK];
+                let n = (buf_len.max(0) as usize).min(SOCKET_CHUNK);
+                let mut sender = None;
+                let read = with_socket_stack(|stack| {
+                    let received = stack.recv_from(&cap, &mut chunk[..n])?;
+                    sender = received.map(|(_, from)| from);
+                    Ok(received.map_or(0, |(len, _)| len as i32))
+                });
+                let Some(from) = sender else { return read };
+                let smoltcp::wire::IpAddress::Ipv4(addr) = from.addr;
+                let mut from_bytes = [0u8; 8];
+                from_bytes[..4].copy_from_slice(&u32::from_be_bytes(addr.0).to_le_bytes());
+                from_bytes[4..].copy_from_slice(&u32::from(from.port).to_le_bytes());
+                match write_guest_memory(&mut caller, from_ptr, &from_bytes)
+                    .and_then(|()| write_guest_memory(&mut caller, buf_ptr, &chunk[..read as usize]))
+                {
+                    Ok(()) => read,
+                    Err(()) => ERR_MEMORY_FAULT,
+                }
+            },
+        )
+        .expect("Failed to register socket_recv_from");
+
+    // syscall: env.socket_close(slot: i32) -> i32
+    // Closes the socket, revoking its capability from every holder.
+    // Returns 0 or a negative error code.
+    linker
+        .func_wrap(
+            "env",
+            "socket_close",
+            |caller: Caller<'_, ProcessState>, slot: i32| -> i32 {
+                let cap = match socket_cap(&caller, slot) {
+                    Ok(cap) => cap,
+                    Err(code) => return code,
+                };
+                caller.data().cspace.lock().revoke(slot as usize);
+                tls::end_session(cap.resource_id);
+                with_socket_stack(|stack| stack.close_socket(cap.resource_id).map(|()| 0))
+            },
+        )
+        .expect("Failed to register socket_close");
+
+    // syscall: env.tls_start(slot: i32, host_ptr: i32, host_len: i32) -> i32
+    // Starts a TLS handshake with `host` over a connected TCP socket, which
+    // needs SEND and RECV. The host must have a pinned key unless
+    // `net.tls.allow_unpinned` is set (ERR_PERMISSION_DENIED otherwise).
+    // Poll `tls_send` / `tls_recv` for the outcome. Returns 0 or a negative
+    // error code.
+    linker
+        .func_wrap(
+            "env",
+            "tls_start",
+            |caller: Caller<'_, ProcessState>, slot: i32, host_ptr: i32, host_len: i32| -> i32 {
+                let cap = match socket_cap(&caller, slot) {
+                    Ok(cap) => cap,
+                    Err(code) => return code,
+                };
+                if !cap.permissions.contains(Permissions::SEND.union(Permissions::RECV)) {
+                    return ERR_PERMISSION_DENIED;
+                }
+                let host = match guest_slice(&caller, host_ptr, host_len) {
+                    Ok(host) => host,
+                    Err(()) => return ERR_MEMORY_FAULT,
+                };
+                let host = match core::str::from_utf8(host) {
+                    Ok(host) if !host.is_empty() => host,
+                    _ => return ERR_INVALID,
+                };
+                match tls::start_session(&cap, host) {
+                    Ok(()) => 0,
+                    Err(e) => tls_error_code(e),
+                }
+            },
+        )
+        .expect("Failed to register tls_start");
+
+    // syscall: env.tls_send(slot: i32, buf_ptr: i32, buf_len: i32) -> i32
+    // Encrypts and queues bytes on the socket's TLS session. Returns how
+    // many were accepted (0 while the handshake runs or the queue is full)
+    // or a negative error code, e.g. ERR_PERMISSION_DENIED if the server
+    // failed authentication.
+    linker
+        .func_wrap(
+            "env",
+            "tls_send",
+            |caller: Caller<'_, ProcessState>, slot: i32, buf_ptr: i32, buf_len: i32| -> i32 {
+                let cap = match socket_cap(&caller, slot) {
+                    Ok(cap) => cap,
+                    Err(code) => return code,
+                };
+                let data = match guest_slice(&caller, buf_ptr, buf_len) {
+                    Ok(data) => data,
+                    Err(()) => return ERR_MEMORY_FAULT,
+                };
+                match tls::with_process_session(&cap, |client| client.write(data)) {
+                    Ok(Ok(n)) => n as i32,
+                    Ok(Err(e)) | Err(e) => tls_error_code(e),
+                }
+            },
+        )
+        .expect("Failed to register tls_send");
+
+    // syscall: env.tls_recv(slot: i32, buf_ptr: i32, buf_len: i32) -> i32
+    // Reads up to SOCKET_CHUNK decrypted bytes from the socket's TLS
+    // session. Returns the count (0 if nothing has arrived), ERR_CLOSED once
+    // the server has closed the session and all its data has been read, or
+    // another negative error code.
+    linker
+        .func_wrap(
+            "env",
+            "tls_recv",
+            |mut caller: Caller<'_, ProcessState>, slot: i32, buf_ptr: i32, buf_len: i32| -> i32 {
+                let cap = match socket_cap(&caller, slot) {
+                    Ok(cap) => cap,
+                    Err(code) => return code,
+                };
+                let mut chunk = [0u8; SOCKET_CHUNK];
+                let n = (buf_len.max(0) as usize).min(SOCKET_CHUNK);
+                let read = tls::with_process_session(&cap, |client| match client.read(&mut chunk[..n]) {
+                    0 if client.is_closed() => Err(TlsError::Closed),
+                    0 => client.failure().map_or(Ok(0), Err),
+                    read => Ok(read),
+                });
+                let read = match read {
+                    Ok(Ok(read)) => read,
+                    Ok(Err(e)) | Err(e) => return tls_error_code(e),
+                };
+                match write_guest_memory(&mut caller, buf_ptr, &chunk[..read]) {
+                    Ok(()) => read as i32,
+                    Err(()) => ERR_MEMORY_FAULT,
+                }
+            },
+        )
+        .expect("Failed to register tls_recv");
+
+    // syscall: env.ping_send(ipv4: i32, timeout_ms: i32) -> i32
+    // Sends an ICMP echo request to `ipv4` (big-endian, as for
+    // `socket_connect`). Requires a Device capability on DEVICE_NETWORK with
+    // SEND. Returns a sequence number for `ping_result`, or a negative error
+    // code.
+    linker
+        .func_wrap(
+            "env",
+            "ping_send",
+            |caller: Caller<'_, ProcessState>, ipv4: i32, timeout_ms: i32| -> i32 {
+                let allowed = caller.data().cspace.lock().iter().any(|(_, cap)| {
+                    cap.cap_type == CapabilityType::Device
+                        && cap.resource_id == DEVICE_NETWORK
+                        && cap.permissions.contains(Permissions::SEND)
+                });
+                if !allowed {
+                    return ERR_PERMISSION_DENIED;
+                }
+                let Ok(timeout_ms) = u64::try_from(timeout_ms) else { return ERR_INVALID };
+                let addr = smoltcp::wire::Ipv4Address::from_bytes(&(ipv4 as u32).to_be_bytes());
+                match icmp::send(addr, timeout_ms) {
+                    Ok(seq) => i32::from(seq),
+                    Err(_) => ERR_NO_RESOURCES,
+                }
+            },
+        )
+        .expect("Failed to register ping_send");
+
+    // syscall: env.ping_result(seq: i32) -> i32
+    // Outcome of a `ping_send`: the round-trip time in milliseconds,
+    // ERR_WOULD_BLOCK while no reply has arrived yet, ERR_TIMED_OUT, or
+    // ERR_NOT_FOUND for an unknown or already collected sequence number.
+    linker
+        .func_wrap(
+            "env",
+            "ping_result",
+            |_caller: Caller<'_, ProcessState>, seq: i32| -> i32 {
+                let Ok(seq) = u16::try_from(seq) else { return ERR_NOT_FOUND };
+                match icmp::result(seq) {
+                    None => ERR_WOULD_BLOCK,
+                    Some(Ok(rtt_ms)) => rtt_ms.min(i32::MAX as u64) as i32,
+                    Some(Err(PingError::TimedOut)) => ERR_TIMED_OUT,
+                    Some(Err(_)) => ERR_NOT_FOUND,
+                }
+            },
+        )
+        .expect("Failed to register ping_result");
+
+    // syscall: env.net_subscribe(slot: i32) -> i32
+    // Subscribes the endpoint in `slot` (with READ) to network events: an
+    // EVENT_NET_ADDRESS message (data: interface index, address as for
+    // `socket_connect` or 0 once unconfigured, prefix length) whenever an
+    // interface's address changes, and an EVENT_NET_NEIGHBOR_FAILED message
+    // (data: interface index, address) when ARP gets no answer. Requires a
+    // Device capability on DEVICE_NETWORK. Returns 0 or a negative error
+    // code.
+    linker
+        .func_wrap(
+            "env",
+            "net_subscribe",
+            |caller: Caller<'_, ProcessState>, slot: i32| -> i32 {
+                let cap = {
+                    let cspace = caller.data().cspace.lock();
+                    if !cspace.holds(CapabilityType::Device, DEVICE_NETWORK, Permissions::NONE) {
+                        return ERR_PERMISSION_DENIED;
+                    }
+                    match cspace.get(slot as u32 as usize) {
+                        Some(cap) if cap.cap_type == CapabilityType::Endpoint => cap.clone(),
+                        _ => return ERR_NOT_FOUND,
+                    }
+                };
+                match net_stack::subscribe_events(&cap) {
+                    Ok(()) => 0,
+                    Err(IpcError::PermissionDenied) => ERR_PERMISSION_DENIED,
+                    Err(_) => ERR_NOT_FOUND,
+                }
+            },
+        )
+        .expect("Failed to register net_subscribe");
+
+    // syscall: env.firewall_insert(position: i32, action: i32, protocol: i32,
+    //                              ports: i32, source_ipv4: i32, prefix_len: i32) -> i32
+    // Inserts an ingress firewall rule at `position` (appended if past the
+    // end) and returns where it went. `action` is 0 = allow, 1 = deny;
+    // `protocol` 0 = any, 1 = TCP, 2 = UDP, 3 = ICMP; `ports` is
+    // `first << 16 | last` or -1 for any; the source is a big-endian address
+    // and prefix length. Requires a Device capability on DEVICE_NET_ADMIN
+    // with WRITE.
+    linker
+        .func_wrap(
+            "env",
+            "firewall_insert",
+            |caller: Caller<'_, ProcessState>,
+             position: i32,
+             action: i32,
+             protocol: i32,
+             ports: i32,
+             source_ipv4: i32,
+             prefix_len: i32|
+             -> i32 {
+                if !caller.data().cspace.lock().holds(CapabilityType::Device, DEVICE_NET_ADMIN, Permissions::WRITE) {
+                    return ERR_PERMISSION_DENIED;
+                }
+                let action = match action {
+                    0 => firewall::Action::Allow,
+                    1 => firewall::Action::Deny,
+                    _ => return ERR_INVALID,
+                };
+                let protocol = match protocol {
+                    0 => firewall::Protocol::Any,
+                    1 => firewall::Protocol::Tcp,
+                    2 => firewall::Protocol::Udp,
+                    3 => firewall::Protocol::Icmp,
+                    _ => return ERR_INVALID,
+                };
+                let ports = match ports {
+                    -1 => None,
+                    ports => Some(((ports as u32 >> 16) as u16, ports as u32 as u16)),
+                };
+                let Ok(prefix_len) = u8::try_from(prefix_len) else { return ERR_INVALID };
+                if prefix_len > 32 {
+                    return ERR_INVALID;
+                }
+                let address = smoltcp::wire::Ipv4Address::from_bytes(&(source_ipv4 as u32).to_be_bytes());
+                let source = smoltcp::wire::Ipv4Cidr::new(address, prefix_len);
+                let position = usize::try_from(position).unwrap_or(usize::MAX);
+                match firewall::insert(position, firewall::Rule { action, protocol, ports, source }) {
+                    Ok(position) => position as i32,
+                    Err(FirewallError::TableFull) => ERR_NO_RESOURCES,
+                    Err(_) => ERR_INVALID,
+                }
+            },
+        )
+        .expect("Failed to register firewall_insert");
+
+    // syscall: env.firewall_remove(position: i32) -> i32
+    // Removes the ingress firewall rule at `position`. Requires a Device
+    // capability on DEVICE_NET_ADMIN with WRITE.
+    linker
+        .func_wrap(
+            "env",
+            "firewall_remove",
+            |caller: Caller<'_, ProcessState>, position: i32| -> i32 {
+                if !caller.data().cspace.lock().holds(CapabilityType::Device, DEVICE_NET_ADMIN, Permissions::WRITE) {
+                    return ERR_PERMISSION_DENIED;
+                }
+                let Ok(position) = usize::try_from(position) else { return ERR_NOT_FOUND };
+                match firewall::remove(position) {
+                    Ok(_) => 0,
+                    Err(_) => ERR_NOT_FOUND,
+                }
+            },
+        )
+        .expect("Failed to register firewall_remove");
+
+    // syscall: env.p2p_relay_enable(on: i32) -> i32
+    // Turns relaying for other P2P peers on (nonzero) or off (see
+    // `p2p_relay`). Requires a Device capability on DEVICE_NET_ADMIN with
+    // WRITE. Returns 0.
+    linker
+        .func_wrap(
+            "env",
+            "p2p_relay_enable",
+            |caller: Caller<'_, ProcessState>, on: i32| -> i32 {
+                if !caller.data().cspace.lock().holds(CapabilityType::Device, DEVICE_NET_ADMIN, Permissions::WRITE) {
+                    return ERR_PERMISSION_DENIED;
+                }
+                p2p_relay::set_enabled(on != 0);
+                0
+            },
+        )
+        .expect("Failed to register p2p_relay_enable");
+
+    // ── Pubsub ──
+    // Topics shared with other nodes over the P2P layer (see `p2p_pubsub`).
+    // A subscription is named by a descriptor, like an open device; it ends
+    // with `pubsub_unsubscribe` or when the process exits.
+
+    // syscall: env.pubsub_subscribe(topic_ptr: i32, topic_len: i32) -> i32
+    // Subscribes to a topic. Requires a Device capability on DEVICE_NETWORK
+    // with RECV. Returns a descriptor, ERR_NO_RESOURCES if the process holds
+    // MAX_SUBSCRIPTIONS already, or another negative error code.
+    linker
+        .func_wrap(
+            "env",
+            "pubsub_subscribe",
+            |mut caller: Caller<'_, ProcessState>, topic_ptr: i32, topic_len: i32| -> i32 {
+                if !caller.data().cspace.lock().holds(CapabilityType::Device, DEVICE_NETWORK, Permissions::RECV) {
+                    return ERR_PERMISSION_DENIED;
+                }
+                let mut topic_buf = [0u8; MAX_PATH_LEN];
+                let topic = match read_guest_str(&caller, topic_ptr, topic_len, &mut topic_buf) {
+                    Ok(topic) => topic,
+                    Err(code) => return code,
+                };
+                let subscriptions = &caller.data().subscriptions;
+                let fd = match subscriptions.iter().position(Option::is_none) {
+                    Some(fd) => fd,
+                    None if subscriptions.len() < MAX_SUBSCRIPTIONS => subscriptions.len(),
+                    None => return ERR_NO_RESOURCES,
+                };
+                let subscription = match p2p_pubsub::subscribe(topic) {
+                    Ok(subscription) => subscription,
+                    Err(e) => return pubsub_error(e),
+                };
+                let subscriptions = &mut caller.data_mut().subscriptions;
+                if fd == subscriptions.len() {
+                    subscriptions.push(None);
+                }
+                subscriptions[fd] = Some(subscription);
+                fd as i32
+            },
+        )
+        .expect("Failed to register pubsub_subscribe");
+
+    // syscall: env.pubsub_recv(fd: i32, buf_ptr: i32, buf_len: i32) -> i32
+    // Takes the oldest message waiting on a subscription without blocking.
+    // Copies as much of it as fits into the buffer and returns its full
+    // length; the rest is lost. Returns ERR_WOULD_BLOCK if none is waiting.
+    linker
+        .func_wrap(
+            "env",
+            "pubsub_recv",
+            |mut caller: Caller<'_, ProcessState>, fd: i32, buf_ptr: i32, buf_len: i32| -> i32 {
+                let message = match caller.data().subscriptions.get(fd as u32 as usize) {
+                    Some(Some(subscription)) => subscription.try_recv(),
+                    _ => return ERR_NOT_FOUND,
+                };
+                let Some(message) = message else { return ERR_WOULD_BLOCK };
+                let n = message.data.len().min(buf_len.max(0) as usize);
+                match write_guest_memory(&mut caller, buf_ptr, &message.data[..n]) {
+                    Ok(()) => message.data.len() as i32,
+                    Err(()) => ERR_MEMORY_FAULT,
+                }
+            },
+        )
+        .expect("Failed to register pubsub_recv");
+
+    // syscall: env.pubsub_unsubscribe(fd: i32) -> i32
+    // Ends a subscription. Returns 0 or ERR_NOT_FOUND.
+    linker
+        .func_wrap(
+            "env",
+            "pubsub_unsubscribe",
+            |mut caller: Caller<'_, ProcessState>, fd: i32| -> i32 {
+                match caller.data_mut().subscriptions.get_mut(fd as u32 as usize) {
+                    Some(slot @ Some(_)) => {
+                        *slot = None;
+                        0
+                    }
+                    _ => ERR_NOT_FOUND,
+                }
+            },
+        )
+        .expect("Failed to register pubsub_unsubscribe");
+
+    // syscall: env.pubsub_publish(topic_ptr: i32, topic_len: i32, data_ptr: i32, data_len: i32) -> i32
+    // Publishes a message on a topic, to this node's subscribers and the
+    // topic's peers. Requires a Device capability on DEVICE_NETWORK with
+    // SEND. Returns how many peers it was sent to or a negative error code.
+    linker
+        .func_wrap(
+            "env",
+            "pubsub_publish",
+            |caller: Caller<'_, ProcessState>, topic_ptr: i32, topic_len: i32, data_ptr: i32, data_len: i32| -> i32 {
+                if !caller.data().cspace.lock().holds(CapabilityType::Device, DEVICE_NETWORK, Permissions::SEND) {
+                    return ERR_PERMISSION_DENIED;
+                }
+                let mut topic_buf = [0u8; MAX_PATH_LEN];
+                let topic = match read_guest_str(&caller, topic_ptr, topic_len, &mut topic_buf) {
+                    Ok(topic) => topic,
+                    Err(code) => return code,
+                };
+                let Ok(data) = guest_slice(&caller, data_ptr, data_len) else { return ERR_MEMORY_FAULT };
+                match p2p_pubsub::publish(topic, data) {
+                    Ok(peers) => peers as i32,
+                    Err(e) => pubsub_error(e),
+                }
+            },
+        )
+        .expect("Failed to register pubsub_publish");
+
+    // ── Channels ──
+    // Byte streams to processes on other nodes, over the P2P layer (see
+    // `p2p_channel`). Listeners and channels are named by descriptors, like
+    // pubsub subscriptions; closing one, or exiting, ends it. All calls are
+    // non-blocking, like the socket calls.
+
+    // syscall: env.channel_listen(service_ptr: i32, service_len: i32) -> i32
+    // Accepts channels opened to `service` on this node. Requires a Device
+    // capability on DEVICE_NETWORK with LISTEN. Returns a descriptor, or a
+    // negative error code (ERR_INVALID for a bad or already-taken name).
+    linker
+        .func_wrap(
+            "env",
+            "channel_listen",
+            |mut caller: Caller<'_, ProcessState>, service_ptr: i32, service_len: i32| -> i32 {
+                if !caller.data().cspace.lock().holds(CapabilityType::Device, DEVICE_NETWORK, Permissions::LISTEN) {
+                    return ERR_PERMISSION_DENIED;
+                }
+                let mut service_buf = [0u8; MAX_PATH_LEN];
+                let service = match read_guest_str(&caller, service_ptr, service_len, &mut service_buf) {
+                    Ok(service) => service,
+                    Err(code) => return code,
+                };
+                match p2p_channel::listen(service) {
+                    Ok(listener) => add_channel(&mut caller, ChannelDescriptor::Listener(listener)),
+                    Err(e) => channel_error(e),
+                }
+            },
+        )
+        .expect("Failed to register channel_listen");
+
+    // syscall: env.channel_accept(fd: i32) -> i32
+    // Takes the oldest channel opened to a listener's service. Returns the
+    // channel's descriptor, ERR_WOULD_BLOCK if none is waiting, or another
+    // negative error code.
+    linker
+        .func_wrap(
+            "env",
+            "channel_accept",
+            |mut caller: Caller<'_, ProcessState>, fd: i32| -> i32 {
+                let channel = match caller.data().channels.get(fd as u32 as usize) {
+                    Some(Some(ChannelDescriptor::Listener(listener))) => listener.accept(),
+                    _ => return ERR_NOT_FOUND,
+                };
+                match channel {
+                    Some(channel) => add_channel(&mut caller, ChannelDescriptor::Channel(channel)),
+                    None => ERR_WOULD_BLOCK,
+                }
+            },
+        )
+        .expect("Failed to register channel_accept");
+
+    // syscall: env.channel_open(node_ptr: i32, service_ptr: i32, service_len: i32) -> i32
+    // Opens a channel to `service` on the node whose 32-byte ID is at
+    // `node_ptr`, connecting to it if needed. Requires a Device capability
+    // on DEVICE_NETWORK with CONNECT. Returns a descriptor at once; the
+    // channel carries nothing until the node accepts it (poll
+    // `channel_send` / `channel_recv` for the outcome).
+    linker
+        .func_wrap(
+            "env",
+            "channel_open",
+            |mut caller: Caller<'_, ProcessState>, node_ptr: i32, service_ptr: i32, service_len: i32| -> i32 {
+                if !caller.data().cspace.lock().holds(CapabilityType::Device, DEVICE_NETWORK, Permissions::CONNECT) {
+                    return ERR_PERMISSION_DENIED;
+                }
+                let mut node = [0u8; 32];
+                if read_guest_memory(&caller, node_ptr, &mut node).is_err() {
+                    return ERR_MEMORY_FAULT;
+                }
+                let mut service_buf = [0u8; MAX_PATH_LEN];
+                let service = match read_guest_str(&caller, service_ptr, service_len, &mut service_buf) {
+                    Ok(service) => service,
+                    Err(code) => return code,
+                };
+                match p2p_channel::open(NodeId::new(node), service) {
+                    Ok(channel) => add_channel(&mut caller, ChannelDescriptor::Channel(channel)),
+                    Err(e) => channel_error(e),
+                }
+            },
+        )
+        .expect("Failed to register channel_open");
+
+    // syscall: env.channel_send(fd: i32, buf_ptr: i32, buf_len: i32) -> i32
+    // Queues up to `p2p_channel::MAX_CHUNK` bytes for the other end.
+    // Returns how many were taken (0 while the channel is opening or the
+    // connection is backed up), or a negative error code: ERR_NOT_FOUND if
+    // nothing listens under the service, ERR_TIMED_OUT if the node couldn't
+    // be reached, ERR_CLOSED once either end has closed the channel.
+    linker
+        .func_wrap(
+            "env",
+            "channel_send",
+            |caller: Caller<'_, ProcessState>, fd: i32, buf_ptr: i32, buf_len: i32| -> i32 {
+                let Some(Some(ChannelDescriptor::Channel(channel))) = caller.data().channels.get(fd as u32 as usize)
+                else {
+                    return ERR_NOT_FOUND;
+                };
+                let data = match guest_slice(&caller, buf_ptr, buf_len) {
+                    Ok(data) => data,
+                    Err(()) => return ERR_MEMORY_FAULT,
+                };
+                match channel.send(data) {
+                    Ok(n) => n as i32,
+                    Err(e) => channel_error(e),
+                }
+            },
+        )
+        .expect("Failed to register channel_send");
+
+    // syscall: env.channel_recv(fd: i32, buf_ptr: i32, buf_len: i32) -> i32
+    // Reads up to SOCKET_CHUNK bytes. Returns the count (0 if nothing has
+    // arrived), or once the channel has closed and everything before has
+    // been read, the error `channel_send` would return.
+    linker
+        .func_wrap(
+            "env",
+            "channel_recv",
+            |mut caller: Caller<'_, ProcessState>, fd: i32, buf_ptr: i32, buf_len: i32| -> i32 {
+                let mut chunk = [0u8; SOCKET_CHUNK];
+                let n = (buf_len.max(0) as usize).min(SOCKET_CHUNK);
+                let read = match caller.data().channels.get(fd as u32 as usize) {
+                    Some(Some(ChannelDescriptor::Channel(channel))) => channel.recv(&mut chunk[..n]),
+                    _ => return ERR_NOT_FOUND,
+                };
+                match read {
+                    Ok(0) => 0,
+                    Ok(read) => match write_guest_memory(&mut caller, buf_ptr, &chunk[..read]) {
+                        Ok(()) => read as i32,
+                        Err(()) => ERR_MEMORY_FAULT,
+                    },
+                    Err(e) => channel_error(e),
+                }
+            },
+        )
+        .expect("Failed to register channel_recv");
+
+    // syscall: env.channel_close(fd: i32) -> i32
+    // Closes a channel, or stops a listener (closing the channels it
+    // hadn't accepted). Returns 0 or ERR_NOT_FOUND.
+    linker
+        .func_wrap(
+            "env",
+            "channel_close",
+            |mut caller: Caller<'_, ProcessState>, fd: i32| -> i32 {
+                match caller.data_mut().channels.get_mut(fd as u32 as usize) {
+                    Some(slot @ Some(_)) => {
+                        *slot = None;
+                        0
+                    }
+                    _ => ERR_NOT_FOUND,
+                }
+            },
+        )
+        .expect("Failed to register channel_close");
+
+    // ── DHT ──
+    // Records shared with other nodes through the DHT (see `p2p_rpc`),
+    // under the SHA-256 of a key the guest names. A walk across the
+    // network takes a while, so `dht_put` and `dht_get` start a request
+    // and return its descriptor, and `dht_result` collects the outcome.
+
+    // syscall: env.dht_put(key_ptr: i32, key_len: i32, value_ptr: i32, value_len: i32, ttl_secs: i32) -> i32
+    // Publishes a record signed by this node, here and on the nodes closest
+    // to its key. Requires a Device capability on DEVICE_NETWORK with SEND.
+    // Returns a request descriptor or a negative error code.
+    linker
+        .func_wrap(
+            "env",
+            "dht_put",
+            |mut caller: Caller<'_, ProcessState>,
+             key_ptr: i32,
+             key_len: i32,
+             value_ptr: i32,
+             value_len: i32,
+             ttl_secs: i32|
+             -> i32 {
+                if !caller.data().cspace.lock().holds(CapabilityType::Device, DEVICE_NETWORK, Permissions::SEND) {
+                    return ERR_PERMISSION_DENIED;
+                }
+                let Ok(ttl_secs) = u64::try_from(ttl_secs) else { return ERR_INVALID };
+                let Ok(key) = guest_slice(&caller, key_ptr, key_len).map(NodeId::from_data) else {
+                    return ERR_MEMORY_FAULT;
+                };
+                let Ok(value) = guest_slice(&caller, value_ptr, value_len).map(<[u8]>::to_vec) else {
+                    return ERR_MEMORY_FAULT;
+                };
+                if value.len() > p2p_store::MAX_VALUE_LEN {
+                    return ERR_INVALID;
+                }
+                start_dht_request(&mut caller, async move {
+                    DhtOutcome::Stored(p2p_rpc::publish(key, &value, ttl_secs * 1000).await)
+                })
+            },
+        )
+        .expect("Failed to register dht_put");
+
+    // syscall: env.dht_get(key_ptr: i32, key_len: i32) -> i32
+    // Looks a record up, here first and then across the network. Requires
+    // a Device capability on DEVICE_NETWORK with RECV. Returns a request
+    // descriptor or a negative error code.
+    linker
+        .func_wrap(
+            "env",
+            "dht_get",
+            |mut caller: Caller<'_, ProcessState>, key_ptr: i32, key_len: i32| -> i32 {
+                if !caller.data().cspace.lock().holds(CapabilityType::Device, DEVICE_NETWORK, Permissions::RECV) {
+                    return ERR_PERMISSION_DENIED;
+                }
+                let Ok(key) = guest_slice(&caller, key_ptr, key_len).map(NodeId::from_data) else {
+                    return ERR_MEMORY_FAULT;
+                };
+                start_dht_request(&mut caller, async move { DhtOutcome::Found(p2p_rpc::get(key).await) })
+            },
+        )
+        .expect("Failed to register dht_get");
+
+    // syscall: env.dht_result(fd: i32, buf_ptr: i32, buf_len: i32) -> i32
+    // Outcome of a DHT request, which frees its descriptor:
+    // ERR_WOULD_BLOCK while it is still running; for `dht_put`, how many
+    // peers took the record; for `dht_get`, the value's full length, with
+    // as much of it as fits copied into the buffer, or ERR_NOT_FOUND.
+    linker
+        .func_wrap(
+            "env",
+            "dht_result",
+            |mut caller: Caller<'_, ProcessState>, fd: i32, buf_ptr: i32, buf_len: i32| -> i32 {
+                let outcome = match caller.data_mut().dht_requests.get_mut(fd as u32 as usize) {
+                    Some(slot @ Some(_)) => {
+                        let Some(outcome) = slot.as_ref().and_then(|request| request.lock().take()) else {
+                            return ERR_WOULD_BLOCK;
+                        };
+                        *slot = None;
+                        outcome
+                    }
+                    _ => return ERR_NOT_FOUND,
+                };
+                match outcome {
+                    DhtOutcome::Stored(Ok(peers)) => peers.min(i32::MAX as usize) as i32,
+                    DhtOutcome::Stored(Err(e)) => store_error(e),
+                    DhtOutcome::Found(None) => ERR_NOT_FOUND,
+                    DhtOutcome::Found(Some(value)) => {
+                        let n = value.len().min(buf_len.max(0) as usize);
+                        match write_guest_memory(&mut caller, buf_ptr, &value[..n]) {
+                            Ok(()) => value.len() as i32,
+                            Err(()) => ERR_MEMORY_FAULT,
+                        }
+                    }
+                }
+            },
+        )
+        .expect("Failed to register dht_result");
+
+    // ── WASI ──
+    // The `wasi_snapshot_preview1` calls for arguments and environment,
+    // with WASI's ABI: they return an errno (0 on success) and lay strings
+    // out as in C — an array of u32 pointers plus a buffer of NUL-terminated
+    // strings — so standard toolchains' `std::env::args()` work unchanged.
+
+    // syscall: wasi_snapshot_preview1.args_sizes_get(argc_ptr: i32, buf_size_ptr: i32) -> i32
+    linker
+        .func_wrap(
+            WASI_MODULE,
+            "args_sizes_get",
+            |mut caller: Caller<'_, ProcessState>, argc_ptr: i32, buf_size_ptr: i32| -> i32 {
+                let (count, size) = wasi_sizes(&caller.data().args);
+                wasi_write_sizes(&mut caller, count, size, argc_ptr, buf_size_ptr)
+            },
+        )
+        .expect("Failed to register args_sizes_get");
+
+    // syscall: wasi_snapshot_preview1.args_get(argv_ptr: i32, argv_buf_ptr: i32) -> i32
+    linker
+        .func_wrap(
+            WASI_MODULE,
+            "args_get",
+            |mut caller: Caller<'_, ProcessState>, argv_ptr: i32, argv_buf_ptr: i32| -> i32 {
+                wasi_write_strings(&mut caller, |state| &state.args, argv_ptr, argv_buf_ptr)
+            },
+        )
+        .expect("Failed to register args_get");
+
+    // syscall: wasi_snapshot_preview1.environ_sizes_get(count_ptr: i32, buf_size_ptr: i32) -> i32
+    linker
+        .func_wrap(
+            WASI_MODULE,
+            "environ_sizes_get",
+            |mut caller: Caller<'_, ProcessState>, count_ptr: i32, buf_size_ptr: i32| -> i32 {
+                let (count, size) = wasi_sizes(&caller.data().env);
+                wasi_write_sizes(&mut caller, count, size, count_ptr, buf_size_ptr)
+            },
+        )
+        .expect("Failed to register environ_sizes_get");
+
+    // syscall: wasi_snapshot_preview1.environ_get(environ_ptr: i32, environ_buf_ptr: i32) -> i32
+    linker
+        .func_wrap(
+            WASI_MODULE,
+            "environ_get",
+            |mut caller: Caller<'_, ProcessState>, environ_ptr: i32, environ_buf_ptr: i32| -> i32 {
+                wasi_write_strings(&mut caller, |state| &state.env, environ_ptr, environ_buf_ptr)
+            },
+        )
+        .expect("Failed to register environ_get");
+}
+
+/// Import module name of the WASI calls.
+const WASI_MODULE: &str = "wasi_snapshot_preview1";
+/// WASI errno: success.
+const WASI_ESUCCESS: i32 = 0;
+/// WASI errno: bad address.
+const WASI_EFAULT: i32 = 21;
+
+/// Number of strings and the buffer size their NUL-terminated copies need.
+fn wasi_sizes(strings: &[String]) -> (usize, usize) {
+    (strings.len(), strings.iter().map(|s| s.len() + 1).sum())
+}
+
+/// Store a `*_sizes_get` result as two little-endian u32s.
+fn wasi_write_sizes(
+    caller: &mut Caller<'_, ProcessState>,
+    count: usize,
+    size: usize,
+    count_ptr: i32,
+    size_ptr: i32,
+) -> i32 {
+    let ok = write_guest_memory(caller, count_ptr, &(count as u32).to_le_bytes()).is_ok()
+        && write_guest_memory(caller, size_ptr, &(size as u32).to_le_bytes()).is_ok();
+    if ok { WASI_ESUCCESS } else { WASI_EFAULT }
+}
+
+/// Lay out the strings `select` picks from the process state the way
+/// `args_get` / `environ_get` do: pointers at `ptrs_ptr`, bytes at `buf_ptr`.
+fn wasi_write_strings(
+    caller: &mut Caller<'_, ProcessState>,
+    select: fn(&ProcessState) -> &Vec<String>,
+    ptrs_ptr: i32,
+    buf_ptr: i32,
+) -> i32 {
+    let memory = match caller.get_export("memory").and_then(Extern::into_memory) {
+        Some(memory) => memory,
+        None => return WASI_EFAULT,
+    };
+    let (data, state) = memory.data_and_store_mut(&mut *caller);
+    let mut ptr = ptrs_ptr as u32 as usize;
+    let mut buf = buf_ptr as u32 as usize;
+    for string in select(state) {
+        let (Some(ptr_end), Some(buf_end)) = (ptr.checked_add(4), buf.checked_add(string.len() + 1)) else {
+            return WASI_EFAULT;
+        };
+        if ptr_end > data.len() || buf_end > data.len() {
+            return WASI_EFAULT;
+        }
+        data[ptr..ptr_end].copy_from_slice(&(buf as u32).to_le_bytes());
+        data[buf..buf_end - 1].copy_from_slice(string.as_bytes());
+        data[buf_end - 1] = 0;
+        ptr = ptr_end;
+        buf = buf_end;
+    }
+    WASI_ESUCCESS
+}
+
+/// Largest chunk `env.random_get` generates at once (kept on the stack so
+/// guests can't drain the kernel heap by asking for huge buffers).
+const RANDOM_CHUNK: usize = 256;
+
+/// Size in bytes of one entry written by `env.cap_list`.
+const CAP_ENTRY_SIZE: usize = 24;
+
+/// Host function error: the caller lacks the required capability.
+const ERR_PERMISSION_DENIED: i32 = -1;
+/// Host function error: a guest pointer/length fell outside linear memory.
+const ERR_MEMORY_FAULT: i32 = -2;
+/// Host function error: the named object does not exist.
+const ERR_NOT_FOUND: i32 = -3;
+/// Host function error: the connection was closed by the peer.
+const ERR_CLOSED: i32 = -4;
+/// Host function error: bad argument, or the object is in the wrong state.
+const ERR_INVALID: i32 = -5;
+/// Host function error: a kernel limit was reached (or the subsystem is down).
+const ERR_NO_RESOURCES: i32 = -6;
+/// Host function error: the operation hasn't completed yet; ask again later.
+const ERR_WOULD_BLOCK: i32 = -7;
+/// Host function error: the operation's deadline passed.
+const ERR_TIMED_OUT: i32 = -8;
+/// Host function error: the device reported an I/O error.
+const ERR_IO: i32 = -9;
+
+/// Most endpoints one `env.ipc_wait` waits on (slot list copied onto the
+/// stack).
+const MAX_WAIT_SLOTS: usize = 16;
+
+/// Longest path `env.fs_read` / `env.dev_open` accept (copied onto the stack).
+const MAX_PATH_LEN: usize = 256;
+
+/// Largest transfer `env.dev_read` makes per call, and the chunk size
+/// `env.dev_write` copies through (kept on the stack, like `RANDOM_CHUNK`).
+const DEVICE_CHUNK: usize = 256;
+
+/// Largest transfer `env.blk_read` / `env.blk_write` make per call (the
+/// device is driven synchronously, so this bounds the time spent in one).
+const BLOCK_CHUNK: usize = 64 * 1024;
+
+/// Largest transfer `env.socket_recv` makes per call (one Ethernet MTU,
+/// kept on the stack like `RANDOM_CHUNK`).
+const SOCKET_CHUNK: usize = 1536;
+
+/// Look up the block device named at `name_ptr`, checking that the caller
+/// holds a Device capability on DEVICE_BLOCK with `permissions`.
+fn block_device(
+    caller: &Caller<'_, ProcessState>,
+    name_ptr: i32,
+    name_len: i32,
+    permissions: Permissions,
+) -> Result<Arc<dyn BlockDevice>, i32> {
+    if !caller.data().cspace.lock().holds(CapabilityType::Device, DEVICE_BLOCK, permissions) {
+        return Err(ERR_PERMISSION_DENIED);
+    }
+    let mut name_buf = [0u8; MAX_PATH_LEN];
+    let name = read_guest_str(caller, name_ptr, name_len, &mut name_buf)?;
+    block::get(name).map_err(block_error)
+}
+
+fn block_error(e: BlockError) -> i32 {
+    match e {
+        BlockError::NoSuchDevice => ERR_NOT_FOUND,
+        BlockError::ReadOnly => ERR_PERMISSION_DENIED,
+        BlockError::Io => ERR_IO,
+        BlockError::OutOfRange | BlockError::UnalignedBuffer | BlockError::AlreadyRegistered => ERR_INVALID,
+    }
+}
+
+/// Look up the Socket capability in `slot` of the caller's CSpace.
+fn socket_cap(caller: &Caller<'_, ProcessState>, slot: i32) -> Result<Capability, i32> {
+    match caller.data().cspace.lock().get(slot as u32 as usize) {
+        Some(cap) if cap.cap_type == CapabilityType::Socket => Ok(cap.clone()),
+        _ => Err(ERR_NOT_FOUND),
+    }
+}
+
+/// The WRITE capability child exits are announced through, creating the
+/// caller's child-exit endpoint (and its READ capability in the caller's
+/// CSpace) on first use.
+fn child_exit_notify(caller: &mut Caller<'_, ProcessState>) -> Result<Capability, i32> {
+    if let Some((_, cap)) = &caller.data().child_exits {
+        return Ok(cap.clone());
+    }
+    let (read_cap, write_cap) = {
+        let mut ipc = IPC_MANAGER.lock();
+        let endpoint = ipc.create_endpoint(crate::executor::current_owner()).map_err(|_| ERR_NO_RESOURCES)?;
+        match (
+            ipc.endpoint_capability(endpoint, Permissions::READ),
+            ipc.endpoint_capability(endpoint, Permissions::WRITE),
+        ) {
+            (Ok(read_cap), Ok(write_cap)) => (read_cap, write_cap),
+            _ => return Err(ERR_NO_RESOURCES),
+        }
+    };
+    let Some(slot) = caller.data().cspace.lock().insert(read_cap.clone()) else {
+        destroy_endpoint(&read_cap);
+        return Err(ERR_NO_RESOURCES);
+    };
+    caller.data_mut().child_exits = Some((slot, write_cap.clone()));
+    Ok(write_cap)
+}
+
+/// Destroy the endpoint `cap` names, one the caller couldn't be given.
+fn destroy_endpoint(cap: &Capability) {
+    let mut ipc = IPC_MANAGER.lock();
+    if let Ok(slot) = ipc.resolve(cap, Permissions::NONE) {
+        let _ = ipc.destroy_endpoint(slot);
+    }
+}
+
+/// Options for a process's child: its memory limit, so spawning can't be
+/// used to escape it, and its environment. Arguments are not inherited;
+/// the child gets the process table's fuel defaults.
+fn child_options(parent: &ProcessState) -> ProcessOptions {
+    ProcessOptions { memory_limit: Some(parent.memory_limit), args: Vec::new(), env: parent.env.clone(), fuel: None }
+}
+
+/// Map a failed spawn to a host function return value.
+fn spawn_error(e: ProcessError) -> i32 {
+    match e {
+        ProcessError::TableFull
+        | ProcessError::Sched(SchedError::AlreadyBound)
+        | ProcessError::Load(WasmError::InsufficientResources(_))
+        | ProcessError::Load(WasmError::ShuttingDown) => ERR_NO_RESOURCES,
+        _ => ERR_INVALID,
+    }
+}
+
+/// Resolve the SharedMemory capability in `slot` to its region.
+fn shm_region(
+    caller: &Caller<'_, ProcessState>,
+    slot: i32,
+    required: Permissions,
+) -> Result<Arc<shm::SharedRegion>, i32> {
+    let cap = caller.data().cspace.lock().get(slot as u32 as usize).cloned().ok_or(ERR_NOT_FOUND)?;
+    shm::resolve(&cap, required).map_err(shm_error)
+}
+
+/// Map a shared-memory error to a host function return value.
+fn shm_error(e: ShmError) -> i32 {
+    match e {
+        ShmError::NotFound => ERR_NOT_FOUND,
+        ShmError::PermissionDenied => ERR_PERMISSION_DENIED,
+        ShmError::OutOfRange => ERR_INVALID,
+        ShmError::TooLarge | ShmError::TooMany => ERR_NO_RESOURCES,
+    }
+}
+
+/// The namespace of the caller's first Storage capability with `required`.
+fn kv_namespace(caller: &Caller<'_, ProcessState>, required: Permissions) -> Result<kv::Namespace, i32> {
+    caller
+        .data()
+        .cspace
+        .lock()
+        .iter()
+        .find(|(_, cap)| cap.cap_type == CapabilityType::Storage && cap.permissions.contains(required))
+        .map(|(_, cap)| cap.resource_id)
+        .ok_or(ERR_PERMISSION_DENIED)
+}
+
+/// Map a key-value store result to a host function return value.
+fn kv_result(result: Result<i32, KvError>) -> i32 {
+    match result {
+        Ok(value) => value,
+        Err(KvError::NotFound) | Err(KvError::NoSuchNamespace) => ERR_NOT_FOUND,
+        Err(KvError::TooLarge) => ERR_INVALID,
+        Err(KvError::QuotaExceeded) => ERR_NO_RESOURCES,
+    }
+}
+
+/// Pubsub subscriptions a process may hold at once. Every one queues its
+/// own copy of each message on its topic.
+const MAX_SUBSCRIPTIONS: usize = 8;
+
+/// Map a pubsub error to a host function return value.
+fn pubsub_error(error: PubsubError) -> i32 {
+    match error {
+        PubsubError::BadTopic | PubsubError::TooLarge => ERR_INVALID,
+        PubsubError::TooManyTopics | PubsubError::NotRunning => ERR_NO_RESOURCES,
+    }
+}
+
+/// Listeners and channels a process may have open at once.
+const MAX_CHANNELS: usize = 32;
+
+/// What a channel descriptor names.
+pub enum ChannelDescriptor {
+    /// From `env.channel_listen`.
+    Listener(Listener),
+    /// From `env.channel_open` or `env.channel_accept`.
+    Channel(Channel),
+}
+
+/// Give the caller a descriptor for `descriptor`, or ERR_NO_RESOURCES if
+/// it has `MAX_CHANNELS` open already.
+fn add_channel(caller: &mut Caller<'_, ProcessState>, descriptor: ChannelDescriptor) -> i32 {
+    let channels = &mut caller.data_mut().channels;
+    match channels.iter().position(Option::is_none) {
+        Some(fd) => {
+            channels[fd] = Some(descriptor);
+            fd as i32
+        }
+        None if channels.len() < MAX_CHANNELS => {
+            channels.push(Some(descriptor));
+            (channels.len() - 1) as i32
+        }
+        None => ERR_NO_RESOURCES,
+    }
+}
+
+/// Map a channel error to a host function return value.
+fn channel_error(error: ChannelError) -> i32 {
+    match error {
+        ChannelError::BadService | ChannelError::InUse => ERR_INVALID,
+        ChannelError::NotRunning => ERR_NO_RESOURCES,
+        ChannelError::Unreachable => ERR_TIMED_OUT,
+        ChannelError::Refused => ERR_NOT_FOUND,
+        ChannelError::Closed | ChannelError::Overrun => ERR_CLOSED,
+    }
+}
+
+/// DHT requests a process may have outstanding at once.
+const MAX_DHT_REQUESTS: usize = 16;
+
+/// The outcome of a guest's DHT request, once it has finished.
+pub type DhtRequest = Arc<Mutex<Option<DhtOutcome>>>;
+
+pub enum DhtOutcome {
+    /// From `dht_put`: how many peers took the record.
+    Stored(Result<usize, StoreError>),
+    /// From `dht_get`: the value, if one was found.
+    Found(Option<Vec<u8>>),
+}
+
+/// Run `request` on the executor and give the caller a descriptor for its
+/// outcome, or a negative error code.
+fn start_dht_request(
+    caller: &mut Caller<'_, ProcessState>,
+    request: impl Future<Output = DhtOutcome> + Send + 'static,
+) -> i32 {
+    let requests = &mut caller.data_mut().dht_requests;
+    let fd = match requests.iter().position(Option::is_none) {
+        Some(fd) => fd,
+        None if requests.len() < MAX_DHT_REQUESTS => {
+            requests.push(None);
+            requests.len() - 1
+        }
+        None => return ERR_NO_RESOURCES,
+    };
+    let outcome: DhtRequest = Arc::new(Mutex::new(None));
+    requests[fd] = Some(outcome.clone());
+    executor::submit(Task::new(async move {
+        let result = request.await;
+        *outcome.lock() = Some(result);
+    }));
+    fd as i32
+}
+
+/// Map a DHT store error to a host function return value.
+fn store_error(error: StoreError) -> i32 {
+    match error {
+        StoreError::QuotaExceeded | StoreError::Full => ERR_NO_RESOURCES,
+        StoreError::TooLarge | StoreError::BadSignature | StoreError::NoAddress => ERR_INVALID,
+    }
+}
+
+/// Run a socket operation against the network stack and map the result to
+/// a host function return value.
+fn with_socket_stack(op: impl FnOnce(&mut net_stack::NetworkStack) -> Result<i32, SocketError>) -> i32 {
+    match NETWORK_STACK.lock().as_mut() {
+        Some(stack) => match op(stack) {
+            Ok(value) => value,
+            Err(SocketError::NotFound) | Err(SocketError::NoInterface) => ERR_NOT_FOUND,
+            Err(SocketError::PermissionDenied) => ERR_PERMISSION_DENIED,
+            Err(SocketError::InvalidState) | Err(SocketError::TooLarge) => ERR_INVALID,
+            Err(SocketError::Closed) => ERR_CLOSED,
+        },
+        None => ERR_NO_RESOURCES,
+    }
+}
+
+/// Map a TLS failure to a host function return value.
+fn tls_error_code(error: TlsError) -> i32 {
+    match error {
+        TlsError::Socket(SocketError::NotFound) => ERR_NOT_FOUND,
+        TlsError::Socket(SocketError::PermissionDenied) | TlsError::Untrusted => ERR_PERMISSION_DENIED,
+        TlsError::Socket(SocketError::Closed) | TlsError::Closed => ERR_CLOSED,
+        TlsError::NoNetwork | TlsError::NoResources => ERR_NO_RESOURCES,
+        TlsError::TimedOut => ERR_TIMED_OUT,
+        _ => ERR_INVALID,
+    }
+}
+
+/// Hand a received message to the guest, as `env.ipc_recv` describes:
+/// install a shared region's and a reply capability and append their
+/// slots, write the data words to `buf_ptr`, and return the label.
+fn deliver_message(
+    mut ctx: impl AsContextMut<Data = ProcessState>,
+    memory: Option<Memory>,
+    mut msg: Message,
+    buf_ptr: i32,
+    buf_len: i32,
+) -> i64 {
+    let region = match msg.payload.take() {
+        Some(Payload::Shared { region }) => Some(region),
+        _ => None,
+    };
+    for cap in region.into_iter().chain(msg.reply.take()) {
+        if msg.length < crate::ipc::MAX_MESSAGE_WORDS {
+            let slot = ctx.as_context().data().cspace.lock().insert(cap);
+            msg.data[msg.length] = slot.map_or(u64::MAX, |slot| slot as u64);
+            msg.length += 1;
+        }
+    }
+    let words = (buf_len.max(0) as usize / 8).min(msg.length);
+    let mut out = [0u8; 8 * crate::ipc::MAX_MESSAGE_WORDS];
+    for (i, word) in msg.data[..words].iter().enumerate() {
+        out[i * 8..i * 8 + 8].copy_from_slice(&word.to_le_bytes());
+    }
+    let written = memory.map(|memory| memory.write(&mut ctx, buf_ptr as u32 as usize, &out[..words * 8]));
+    match written {
+        Some(Ok(())) => msg.label as i64,
+        _ => ERR_MEMORY_FAULT as i64,
+    }
+}
+
+/// Build a message from `words` (at most `max_words`) little-endian u64s
+/// at `buf_ptr`, or fail with the host call's error code.
+fn read_message(
+    caller: &Caller<'_, ProcessState>,
+    label: i64,
+    buf_ptr: i32,
+    words: i32,
+    max_words: usize,
+) -> Result<Message, i32> {
+    let words = match usize::try_from(words) {
+        Ok(words) if words <= max_words => words,
+        _ => return Err(ERR_INVALID),
+    };
+    let mut bytes = [0u8; 8 * crate::ipc::MAX_MESSAGE_WORDS];
+    read_guest_memory(caller, buf_ptr, &mut bytes[..words * 8]).map_err(|()| ERR_MEMORY_FAULT)?;
+    let mut msg = Message::new(label as u64);
+    for (i, word) in msg.data[..words].iter_mut().enumerate() {
+        *word = u64::from_le_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap());
+    }
+    msg.length = words;
+    Ok(msg)
+}
+
+/// The `env.ipc_recv*` result for a failed receive.
+fn recv_error(e: IpcError) -> i64 {
+    match e {
+        IpcError::PermissionDenied => ERR_PERMISSION_DENIED as i64,
+        IpcError::TimedOut => ERR_TIMED_OUT as i64,
+        _ => ERR_NOT_FOUND as i64,
+    }
+}
+
+/// Copy `data` into the guest's exported linear memory at `ptr`.
+///
+/// Fails if the module exports no memory or the range is out of bounds.
+fn write_guest_memory(caller: &mut Caller<'_, ProcessState>, ptr: i32, data: &[u8]) -> Result<(), ()> {
+    let memory = caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or(())?;
+    memory.write(caller, ptr as u32 as usize, data).map_err(|_| ())
+}
+
+/// Look up an open device descriptor. Clones the handle so the caller can
+/// borrow guest memory mutably while using it.
+fn open_device(caller: &Caller<'_, ProcessState>, fd: i32) -> Option<OpenDevice> {
+    caller.data().devices.get(fd as u32 as usize)?.clone()
+}
+
+/// Borrow `len` bytes of the guest's linear memory starting at `ptr`,
+/// without copying.
+///
+/// Fails if the module exports no memory, `len` is negative, or the range
+/// extends past the end of memory. The borrow ends before the guest runs
+/// again, so the memory can't grow or move underneath it.
+fn guest_slice<'a>(caller: &'a Caller<'_, ProcessState>, ptr: i32, len: i32) -> Result<&'a [u8], ()> {
+    let memory = caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or(())?;
+    let start = ptr as u32 as usize;
+    let len = usize::try_from(len).map_err(|_| ())?;
+    let end = start.checked_add(len).ok_or(())?;
+    memory.data(caller).get(start..end).ok_or(())
+}
+
+/// Print guest output on the console, each line tagged with the process
+/// name, and keep a copy in its output log.
+fn emit_output(state: &mut ProcessState, bytes: &[u8]) {
+    use crate::serial::{write_bytes, SerialLine};
+    for line in bytes.split_inclusive(|&b| b == b'\n') {
+        if !state.output.mid_line {
+            write_bytes(SerialLine::Com1, b"[");
+            write_bytes(SerialLine::Com1, state.name.as_bytes());
+            write_bytes(SerialLine::Com1, b"] ");
+        }
+        write_bytes(SerialLine::Com1, line);
+        state.output.push(line);
+    }
+}
+
+/// Copy a UTF-8 string of `len` bytes out of guest memory into `buf`.
+/// Errors are host function return codes.
+fn read_guest_str<'a>(
+    caller: &Caller<'_, ProcessState>,
+    ptr: i32,
+    len: i32,
+    buf: &'a mut [u8; MAX_PATH_LEN],
+) -> Result<&'a str, i32> {
+    let len = match usize::try_from(len) {
+        Ok(len) if len <= MAX_PATH_LEN => len,
+        _ => return Err(ERR_MEMORY_FAULT),
+    };
+    read_guest_memory(caller, ptr, &mut buf[..len]).map_err(|()| ERR_MEMORY_FAULT)?;
+    core::str::from_utf8(&buf[..len]).map_err(|_| ERR_INVALID)
+}
+
+/// Copy `buf.len()` bytes out of the guest's linear memory at `ptr`.
+fn read_guest_memory(caller: &Caller<'_, ProcessState>, ptr: i32, buf: &mut [u8]) -> Result<(), ()> {
+    let memory = caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or(())?;
+    memory.read(caller, ptr as u32 as usize, buf).map_err(|_| ())
 }
 
 // ─── Embedded WASM Bytecode ──────────────────────────────────────────────────